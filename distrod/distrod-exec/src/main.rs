@@ -1,14 +1,20 @@
 use anyhow::{Context, Result};
-use libs::cli_ui::LoggerInitializer;
+use libs::cli_ui::{LogFormat, LoggerInitializer};
+use libs::container::HostPath;
+use libs::daemon::{DaemonClient, DaemonRequest};
 use libs::distro::{self, Distro, DistroLauncher};
-use libs::distrod_config::DistrodConfig;
+use libs::distrod_config::{self, DistrodConfig};
+use libs::error::{self, DistroError};
+use libs::exec_broker::{self, ExecRequest};
 use libs::multifork::set_noninheritable_sig_ign;
 use std::ffi::{CString, OsStr, OsString};
 use std::os::unix::prelude::OsStrExt;
+use std::os::unix::process::CommandExt;
 use std::path::Path;
+use std::process::{Command, Stdio};
 use structopt::StructOpt;
 
-use libs::passwd::get_real_credential;
+use libs::passwd::{get_real_credential, Credential};
 
 /// Distrod-exec is a small helper command to allow a non-root user to run programs under the systemd container.
 /// It implements the subset features of distrod's exec subcommand, but has the setuid bit set.
@@ -35,7 +41,7 @@ fn main() {
     init_logger(&opts);
 
     if let Err(err) = run(opts) {
-        log::error!("{:?}", err);
+        std::process::exit(error::report(&err));
     }
 }
 
@@ -53,11 +59,27 @@ fn init_logger(opts: &Opts) {
     logger_initializer.with_kmsg(true);
     if let Some(kmsg_log_level) = opts.kmsg_log_level.as_ref().cloned().or_else(|| {
         distrod_config
+            .as_ref()
             .ok()
-            .and_then(|ref config| config.distrod.kmsg_log_level.clone())
+            .and_then(|config| config.distrod.kmsg_log_level.clone())
     }) {
         logger_initializer.with_kmsg_log_level(kmsg_log_level);
     }
+    if let Ok(ref config) = distrod_config {
+        if let Some(ref log_file_level) = config.distrod.log_file_level {
+            let format = config
+                .distrod
+                .log_file_format
+                .as_ref()
+                .and_then(|format| format.parse::<LogFormat>().ok())
+                .unwrap_or_default();
+            logger_initializer.with_log_file(
+                distrod_config::get_log_file_path(),
+                log_file_level.clone(),
+                format,
+            );
+        }
+    }
     logger_initializer.init("Distrod".to_owned());
 }
 
@@ -105,12 +127,40 @@ where
     let inner = || -> Result<()> {
         let cred = get_real_credential().with_context(|| "Failed to get the real credential.")?;
 
-        let distro = match DistroLauncher::get_running_distro()
+        // The broker can't give the command the terminal of this process as the controlling
+        // terminal, so it's used only when the command isn't interactive.
+        if !nix::unistd::isatty(0).unwrap_or(false) {
+            if let Some(status) =
+                exec_command_by_broker(command.as_ref(), arg0.as_ref(), args, &cred).unwrap_or_else(
+                    |e| {
+                        log::debug!("Failed to request the exec broker. {:?}", e);
+                        None
+                    },
+                )
+            {
+                std::process::exit(status as i32)
+            }
+        }
+
+        let mut distro = match DistroLauncher::get_running_distro()
             .with_context(|| "Failed to get the running distro.")?
         {
             Some(distro) => distro,
-            None => launch_distro()?,
+            None => match start_distro_by_daemon()? {
+                Some(distro) => distro,
+                None => launch_distro()?,
+            },
         };
+        let rootfs = HostPath::new(distro.get_rootfs())?;
+        if let Some(filter) = distro::get_configured_seccomp_filter(&rootfs)
+            .with_context(|| "Failed to get the seccomp profile of the distro.")?
+        {
+            distro.with_seccomp_filter(filter);
+        }
+        distro.with_dropped_exec_capabilities(
+            distro::get_configured_exec_capability_drops(&rootfs)
+                .with_context(|| "Failed to get the capabilities to drop.")?,
+        );
 
         log::debug!("Executing a command in the distro.");
         set_noninheritable_sig_ign();
@@ -122,6 +172,8 @@ where
             Some(&cred),
         )?;
         cred.drop_privilege();
+        // The command gets the signals of the terminal by itself in the process group.
+        waiter.forward_signals(false)?;
         let status = waiter.wait();
         std::process::exit(status as i32)
     };
@@ -133,8 +185,90 @@ where
     Ok(())
 }
 
+/// Runs the command by the exec broker of the running distro, which saves entering the
+/// namespaces of the distro. Returns None if the broker isn't running.
+fn exec_command_by_broker<S1, S2>(
+    command: &Path,
+    arg0: S1,
+    args: &[S2],
+    cred: &Credential,
+) -> Result<Option<u32>>
+where
+    S1: AsRef<OsStr>,
+    S2: AsRef<OsStr>,
+{
+    let request = ExecRequest {
+        command: command.as_os_str().to_owned(),
+        args: args.iter().map(|arg| arg.as_ref().to_owned()).collect(),
+        arg0: Some(arg0.as_ref().to_owned()),
+        working_directory: Some(
+            std::env::current_dir().with_context(|| "Failed to get the current dir.")?,
+        ),
+        envs: collect_secure_envs(),
+        uid: Some(cred.uid.as_raw()),
+    };
+    let waiter = exec_broker::request_exec(None, &request)?;
+    waiter
+        .map(|mut waiter| {
+            log::debug!("Executing a command by the exec broker.");
+            // The command isn't in the process group of this process.
+            waiter.forward_signals(true)?;
+            Ok(waiter.wait())
+        })
+        .transpose()
+}
+
+/// The variables the dynamic loader of glibc removes from the environment of a setuid program,
+/// in addition to the ones starting with "LD_".
+static UNSECURE_ENV_NAMES: &[&str] = &[
+    "GCONV_PATH",
+    "GETCONF_DIR",
+    "GLIBC_TUNABLES",
+    "HOSTALIASES",
+    "LOCALDOMAIN",
+    "LOCPATH",
+    "MALLOC_TRACE",
+    "NIS_PATH",
+    "NLSPATH",
+    "RESOLV_HOST_CONF",
+    "RES_OPTIONS",
+    "TMPDIR",
+    "TZDIR",
+];
+
+/// Returns the environment for the broker, without the variables which can change what a
+/// privileged program does. The command run directly inherits the environment the loader has
+/// cleaned up for this setuid program, and the broker must not get more than that, whichever
+/// libc this is built with.
+fn collect_secure_envs() -> Vec<(OsString, OsString)> {
+    std::env::vars_os()
+        .filter(|(key, _)| {
+            let key = key.as_bytes();
+            !key.starts_with(b"LD_")
+                && !UNSECURE_ENV_NAMES.iter().any(|name| name.as_bytes() == key)
+        })
+        .collect()
+}
+
+/// Lets `distrod daemon` start the default distro if the daemon is running, so that the daemon
+/// owns it. Returns None if the daemon isn't running.
+fn start_distro_by_daemon() -> Result<Option<Distro>> {
+    let mut client = match DaemonClient::connect()? {
+        Some(client) => client,
+        None => return Ok(None),
+    };
+    log::debug!("Starting the distro by the daemon.");
+    client.request(&DaemonRequest::Start { name: None })?;
+    let distro = DistroLauncher::get_running_distro()
+        .with_context(|| "Failed to get the running distro.")?
+        .ok_or_else(|| DistroError::NotRunning { name: None })?;
+    Ok(Some(distro))
+}
+
 fn launch_distro() -> Result<Distro> {
     delay_init_launch();
+    // Take it before the launch so that WSLENV of the distro doesn't have it.
+    let autostart_plan = take_autostart_plan();
     log::debug!("starting /init from distrod-exec");
 
     let mut distro_launcher =
@@ -145,9 +279,53 @@ fn launch_distro() -> Result<Distro> {
     let distro = distro_launcher
         .launch()
         .with_context(|| "Failed to launch the distro.")?;
+    if let Some(plan) = autostart_plan {
+        if let Err(e) = spawn_autostart_distros(&plan) {
+            log::warn!("Failed to start the autostart distros. {:?}", e);
+        }
+    }
     Ok(distro)
 }
 
+static DISTROD_AUTOSTART_DISTROS_ENV_NAME: &str = "DISTROD_AUTOSTART_DISTROS";
+
+/// Returns the named distros which the autostart task asks to start after the default distro.
+fn take_autostart_plan() -> Option<String> {
+    let plan = std::env::var(DISTROD_AUTOSTART_DISTROS_ENV_NAME).ok()?;
+    std::env::remove_var(DISTROD_AUTOSTART_DISTROS_ENV_NAME);
+    strip_wslenv(DISTROD_AUTOSTART_DISTROS_ENV_NAME);
+    if plan.is_empty() {
+        return None;
+    }
+    Some(plan)
+}
+
+/// Starts the distros by `distrod autostart` in the background, since waiting for them to boot
+/// would keep the autostart task from exiting.
+fn spawn_autostart_distros(plan: &str) -> Result<()> {
+    log::debug!("Starting the autostart distros: {}", plan);
+    let mut distrod = Command::new(distrod_config::get_distrod_bin_path());
+    distrod
+        .args(&["autostart", "--plan", plan])
+        .stdin(Stdio::null())
+        .stdout(Stdio::null())
+        .stderr(Stdio::null());
+    unsafe {
+        distrod.pre_exec(|| {
+            // distrod requires the real uid to be the root. `distrod autostart` only starts the
+            // distros listed in the Distrod config, whatever the plan given by the caller is.
+            nix::unistd::setuid(nix::unistd::Uid::from_raw(0))
+                .map_err(|e| std::io::Error::new(std::io::ErrorKind::Other, e))?;
+            nix::unistd::setsid().map_err(|e| std::io::Error::new(std::io::ErrorKind::Other, e))?;
+            Ok(())
+        });
+    }
+    distrod
+        .spawn()
+        .with_context(|| "Failed to spawn distrod autostart.")?;
+    Ok(())
+}
+
 static DISTROD_EXEC_DELAY_ENV_NAME: &str = "DISTROD_EXEC_INIT_LAUNCH_DELAY";
 
 /// On some distros, starting Systemd during WSL's /init being initialized on Windows startup
@@ -169,6 +347,15 @@ fn delay_init_launch() {
             return;
         }
     };
+    // The delay in the distro config takes precedence over the one the autostart task sets.
+    let delay_sec = match get_configured_delay_sec() {
+        Ok(Some(configured_delay_sec)) => configured_delay_sec,
+        Ok(None) => delay_sec,
+        Err(e) => {
+            log::warn!("Failed to get the delay from the distro config. {:?}", e);
+            delay_sec
+        }
+    };
 
     log::debug!(
         "Delaying launching init by {}sec. {:?}",
@@ -177,14 +364,22 @@ fn delay_init_launch() {
     );
     std::thread::sleep(std::time::Duration::from_secs(delay_sec as u64));
 
-    strip_wslenv_for_distod_exec_delay();
+    strip_wslenv(DISTROD_EXEC_DELAY_ENV_NAME);
     log::debug!("delay finished {:?}", std::time::Instant::now());
 }
 
-fn strip_wslenv_for_distod_exec_delay() {
+fn get_configured_delay_sec() -> Result<Option<u32>> {
+    let config = DistrodConfig::get().with_context(|| "Failed to get the Distrod config.")?;
+    let rootfs = HostPath::new(&config.distrod.default_distro_image)?;
+    Ok(distro::get_distro_config(&rootfs)?.autostart.delay_sec)
+}
+
+/// Removes the variable the autostart task has appended to WSLENV. The variables are removed
+/// in the reverse order of being appended.
+fn strip_wslenv(env_name: &str) {
     let inner = || -> Result<()> {
         let wslenv = std::env::var("WSLENV")?;
-        if let Some(stripped) = wslenv.strip_suffix(&format!(":{}", DISTROD_EXEC_DELAY_ENV_NAME)) {
+        if let Some(stripped) = wslenv.strip_suffix(&format!(":{}", env_name)) {
             std::env::set_var("WSLENV", stripped);
         }
         Ok(())
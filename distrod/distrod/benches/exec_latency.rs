@@ -0,0 +1,55 @@
+//! Measures the overhead of `distrod exec` with and without the exec broker.
+//! Run it as root while a distro is running, such as by
+//! `sudo -E cargo bench --bench exec_latency -- --distro ubuntu`.
+
+use std::process::{Command, Stdio};
+use std::time::{Duration, Instant};
+
+const N_ITERATIONS: usize = 100;
+
+fn main() {
+    let args: Vec<String> = std::env::args().collect();
+    let distro = args
+        .iter()
+        .position(|arg| arg == "--distro")
+        .and_then(|i| args.get(i + 1).cloned());
+
+    for use_broker in &[true, false] {
+        let mut samples = vec![];
+        for _ in 0..N_ITERATIONS {
+            let mut exec = Command::new(env!("CARGO_BIN_EXE_distrod"));
+            exec.arg("exec");
+            if let Some(ref distro) = distro {
+                exec.args(&["--distro", distro]);
+            }
+            if !use_broker {
+                exec.arg("--no-broker");
+            }
+            exec.args(&["--", "true"])
+                .stdin(Stdio::null())
+                .stdout(Stdio::null());
+            let started_at = Instant::now();
+            let status = exec.status().expect("distrod can be executed");
+            samples.push(started_at.elapsed());
+            if !status.success() {
+                eprintln!("distrod exec failed. Is a distro running? {}", status);
+                std::process::exit(1);
+            }
+        }
+        report(if *use_broker { "broker" } else { "no-broker" }, samples);
+    }
+}
+
+fn report(name: &str, mut samples: Vec<Duration>) {
+    samples.sort();
+    let mean = samples.iter().sum::<Duration>() / samples.len() as u32;
+    let percentile = |p: usize| samples[(samples.len() - 1) * p / 100];
+    println!(
+        "{:>10}: mean {:>8.2?}, p50 {:>8.2?}, p95 {:>8.2?}, max {:>8.2?}",
+        name,
+        mean,
+        percentile(50),
+        percentile(95),
+        samples[samples.len() - 1]
+    );
+}
@@ -0,0 +1,16 @@
+$ErrorActionPreference = "Stop"
+$Launcher = '{{LAUNCHER_PATH}}'
+$WorkDir = Join-Path $env:TEMP ("distrod-update-" + [guid]::NewGuid())
+Expand-Archive -LiteralPath '{{ZIP_PATH}}' -DestinationPath $WorkDir
+$NewLauncher = Get-ChildItem -LiteralPath $WorkDir -Recurse -Filter "*.exe" | Select-Object -First 1
+If ($null -eq $NewLauncher) {
+	throw "No launcher is found in the downloaded archive."
+}
+# A running exe cannot be overwritten, but can be renamed.
+If (Test-Path -LiteralPath "$Launcher.old") {
+	Remove-Item -LiteralPath "$Launcher.old" -Force
+}
+Move-Item -LiteralPath $Launcher -Destination "$Launcher.old"
+Move-Item -LiteralPath $NewLauncher.FullName -Destination $Launcher
+Remove-Item -LiteralPath $WorkDir -Recurse -Force
+echo "The launcher has been updated."
@@ -0,0 +1,226 @@
+use anyhow::{anyhow, bail, Context, Result};
+use std::fs;
+use std::path::{Path, PathBuf};
+use structopt::StructOpt;
+
+use libs::distrod_config;
+use libs::windows_alias::{validate_alias_name, WindowsAlias};
+use libs::wsl_interop;
+
+use crate::output::{self, OutputFormat, Table, OUTPUT_FORMATS};
+
+/// The directory of the shims under %LOCALAPPDATA% of Windows.
+const DEFAULT_ALIAS_DIR: &str = r"Distrod\aliases";
+
+#[derive(Debug, StructOpt)]
+pub enum AliasOpts {
+    /// Make a command of Windows which runs a Linux program in the distro, such as `code`.
+    Add(AliasAddOpts),
+    /// Remove a command made by `distrod alias add`.
+    Remove(AliasRemoveOpts),
+    /// List the commands made by `distrod alias add`.
+    List(AliasListOpts),
+}
+
+#[derive(Debug, StructOpt)]
+#[structopt(rename_all = "kebab")]
+pub struct AliasDirOpts {
+    /// The Windows directory of the shims. Defaults to %LOCALAPPDATA%\Distrod\aliases.
+    #[structopt(long)]
+    dir: Option<String>,
+}
+
+#[derive(Debug, StructOpt)]
+#[structopt(rename_all = "kebab")]
+pub struct AliasAddOpts {
+    /// The name of the command on Windows.
+    name: String,
+
+    /// The arguments given to the program before the ones given to the command.
+    #[structopt(last = true)]
+    args: Vec<String>,
+
+    /// The Linux program to run. Defaults to the name, which is looked up in the PATH of the user.
+    #[structopt(short, long)]
+    command: Option<String>,
+
+    /// The user to run the program as, in the login environment of the user. Defaults to root.
+    #[structopt(short, long)]
+    user: Option<String>,
+
+    /// The name of the distro to run the program in. Defaults to the default distro.
+    #[structopt(long)]
+    distro: Option<String>,
+
+    /// Close the console of the command right away, for the programs with windows.
+    #[structopt(long)]
+    gui: bool,
+
+    /// Replace the alias of the name if it exists.
+    #[structopt(short, long)]
+    force: bool,
+
+    #[structopt(flatten)]
+    dir: AliasDirOpts,
+}
+
+#[derive(Debug, StructOpt)]
+#[structopt(rename_all = "kebab")]
+pub struct AliasRemoveOpts {
+    name: String,
+
+    #[structopt(flatten)]
+    dir: AliasDirOpts,
+}
+
+#[derive(Debug, StructOpt)]
+#[structopt(rename_all = "kebab")]
+pub struct AliasListOpts {
+    /// The output format. Defaults to the one of --output, or table.
+    #[structopt(long, possible_values = OUTPUT_FORMATS)]
+    format: Option<OutputFormat>,
+
+    #[structopt(flatten)]
+    dir: AliasDirOpts,
+}
+
+pub fn run_alias_command(opts: AliasOpts) -> Result<()> {
+    match opts {
+        AliasOpts::Add(add_opts) => add_alias(add_opts),
+        AliasOpts::Remove(remove_opts) => remove_alias(remove_opts),
+        AliasOpts::List(list_opts) => list_aliases(list_opts),
+    }
+}
+
+fn add_alias(opts: AliasAddOpts) -> Result<()> {
+    validate_alias_name(&opts.name)?;
+    let alias_dir = get_alias_dir(&opts.dir)?;
+    let shim_path = get_shim_path(&alias_dir, &opts.name);
+    if read_alias(&shim_path, &opts.name)?.is_some() {
+        if !opts.force {
+            bail!(
+                "The alias '{}' already exists. Pass --force to replace it.",
+                &opts.name
+            );
+        }
+    } else if shim_path.exists() {
+        bail!(
+            "{:?} exists, and it's not made by Distrod. Choose another name.",
+            &shim_path
+        );
+    }
+
+    let command = opts.command.unwrap_or_else(|| opts.name.clone());
+    let alias = WindowsAlias {
+        name: opts.name,
+        command,
+        wsl_distro: wsl_interop::get_distro_name()
+            .with_context(|| "Failed to get the name of the WSL distro.")?,
+        distro: opts.distro,
+        user: opts.user,
+        args: opts.args,
+        gui: opts.gui,
+    };
+    let distrod_path = format!("{}/distrod", distrod_config::get_distrod_bin_dir_path());
+    fs::create_dir_all(&alias_dir)
+        .with_context(|| format!("Failed to create {:?}.", &alias_dir))?;
+    fs::write(&shim_path, alias.to_cmd_script(&distrod_path)?)
+        .with_context(|| format!("Failed to write {:?}.", &shim_path))?;
+    log::info!(
+        "Added {}.",
+        wsl_interop::wsl_path_to_windows_path(&shim_path)?
+    );
+    if !is_in_path(&alias_dir) {
+        log::info!(
+            "Add {} to PATH of Windows to run '{}' from PowerShell.",
+            wsl_interop::wsl_path_to_windows_path(&alias_dir)?,
+            &alias.name
+        );
+    }
+    Ok(())
+}
+
+fn remove_alias(opts: AliasRemoveOpts) -> Result<()> {
+    validate_alias_name(&opts.name)?;
+    let alias_dir = get_alias_dir(&opts.dir)?;
+    let shim_path = get_shim_path(&alias_dir, &opts.name);
+    // Don't remove the files which Distrod didn't make.
+    if read_alias(&shim_path, &opts.name)?.is_none() {
+        bail!("The alias '{}' doesn't exist.", &opts.name);
+    }
+    fs::remove_file(&shim_path).with_context(|| format!("Failed to remove {:?}.", &shim_path))
+}
+
+fn list_aliases(opts: AliasListOpts) -> Result<()> {
+    let alias_dir = get_alias_dir(&opts.dir)?;
+    let mut aliases = vec![];
+    if alias_dir.exists() {
+        for entry in
+            fs::read_dir(&alias_dir).with_context(|| format!("Failed to read {:?}.", &alias_dir))?
+        {
+            let path = entry?.path();
+            let name = match path.file_name().and_then(|name| name.to_str()) {
+                Some(name) => match name.strip_suffix(".cmd") {
+                    Some(name) => name.to_owned(),
+                    None => continue,
+                },
+                None => continue,
+            };
+            match read_alias(&path, &name) {
+                Ok(Some(alias)) => aliases.push(alias),
+                Ok(None) => {}
+                Err(e) => log::warn!("{:?}", e),
+            }
+        }
+    }
+    aliases.sort_by(|a, b| a.name.cmp(&b.name));
+
+    let mut table = Table::new(&["NAME", "DISTRO", "USER", "COMMAND", "GUI"]);
+    for alias in aliases {
+        let command = std::iter::once(alias.command)
+            .chain(alias.args)
+            .collect::<Vec<_>>()
+            .join(" ");
+        table.add_row(vec![
+            alias.name,
+            alias.distro.unwrap_or_default(),
+            alias.user.unwrap_or_else(|| "root".to_owned()),
+            command,
+            alias.gui.to_string(),
+        ]);
+    }
+    table.print(output::resolve_format(opts.format))
+}
+
+fn get_alias_dir(opts: &AliasDirOpts) -> Result<PathBuf> {
+    let windows_dir = match opts.dir {
+        Some(ref dir) => dir.clone(),
+        None => format!(
+            r"{}\{}",
+            wsl_interop::get_windows_env_var("LOCALAPPDATA")?,
+            DEFAULT_ALIAS_DIR
+        ),
+    };
+    wsl_interop::windows_path_to_wsl_path(&windows_dir)?
+        .ok_or_else(|| anyhow!("'{}' is not a Windows path with a drive.", &windows_dir))
+}
+
+fn get_shim_path(alias_dir: &Path, name: &str) -> PathBuf {
+    alias_dir.join(format!("{}.cmd", name))
+}
+
+fn read_alias(shim_path: &Path, name: &str) -> Result<Option<WindowsAlias>> {
+    if !shim_path.exists() {
+        return Ok(None);
+    }
+    let script = fs::read_to_string(shim_path)
+        .with_context(|| format!("Failed to read {:?}.", shim_path))?;
+    WindowsAlias::from_cmd_script(name, &script)
+}
+
+/// WSL appends PATH of Windows to the one of Linux, translating the directories.
+fn is_in_path(dir: &Path) -> bool {
+    std::env::var_os("PATH").map_or(false, |path| {
+        std::env::split_paths(&path).any(|entry| entry == dir)
+    })
+}
@@ -0,0 +1,128 @@
+use anyhow::{bail, Context, Result};
+use libs::auto_update::{self, AutoUpdateResult, AUTO_UPDATE_TIMER};
+use libs::container::HostPath;
+use libs::distro::{self, DistroLauncher};
+use libs::distro_config::validate_timer_schedule;
+use libs::init_system::InitSystem;
+use std::process::Command;
+use structopt::StructOpt;
+
+#[derive(Debug, StructOpt)]
+pub enum AutoUpdateOpts {
+    /// Update the packages of the distro on a schedule by distrod-auto-update.timer.
+    Enable(AutoUpdateEnableOpts),
+    /// Stop updating the packages automatically.
+    Disable(AutoUpdateDisableOpts),
+    /// Update the packages by the package manager of the distro now, and record the result for
+    /// `distrod status`. This is run by distrod-auto-update.service in the distro.
+    Run,
+}
+
+#[derive(Debug, StructOpt)]
+#[structopt(rename_all = "kebab")]
+pub struct AutoUpdateEnableOpts {
+    /// When to update, in the OnCalendar= format of systemd.timer, such as "daily" or
+    /// "Sun 03:00". Defaults to the one in the distro config, which is weekly by default.
+    #[structopt(long)]
+    schedule: Option<String>,
+
+    #[structopt(long)]
+    distro: Option<String>,
+}
+
+#[derive(Debug, StructOpt)]
+#[structopt(rename_all = "kebab")]
+pub struct AutoUpdateDisableOpts {
+    #[structopt(long)]
+    distro: Option<String>,
+}
+
+pub fn run_auto_update_command(opts: AutoUpdateOpts) -> Result<()> {
+    match opts {
+        AutoUpdateOpts::Enable(enable_opts) => {
+            if let Some(ref schedule) = enable_opts.schedule {
+                validate_timer_schedule(schedule)?;
+            }
+            set_auto_update(
+                enable_opts.distro.as_deref(),
+                true,
+                enable_opts.schedule.as_deref(),
+            )
+        }
+        AutoUpdateOpts::Disable(disable_opts) => {
+            set_auto_update(disable_opts.distro.as_deref(), false, None)
+        }
+        AutoUpdateOpts::Run => run_update(),
+    }
+}
+
+fn set_auto_update(name: Option<&str>, enabled: bool, schedule: Option<&str>) -> Result<()> {
+    let rootfs = distro::get_distro_rootfs(name)?;
+    let init_system = InitSystem::detect(&rootfs);
+    if init_system != InitSystem::Systemd {
+        bail!(
+            "The init of the distro is {}, which doesn't have systemd timers.",
+            init_system.name()
+        );
+    }
+    let mut config = distro::get_distro_config(&rootfs)
+        .with_context(|| "Failed to read the config of the distro.")?;
+    config.auto_update.enabled = enabled;
+    if let Some(schedule) = schedule {
+        config.auto_update.schedule = schedule.to_owned();
+    }
+    distro::set_distro_config(&rootfs, &config)
+        .with_context(|| "Failed to save the config of the distro.")?;
+    auto_update::apply_auto_update_config(&rootfs, &config.auto_update)?;
+
+    // The timer of a stopped distro is picked up by systemd on the next start.
+    if let Some(distro) = DistroLauncher::get_running_distro_by_name(name)
+        .with_context(|| "Failed to get the running distro.")?
+    {
+        let action = if enabled { "restart" } else { "stop" };
+        for args in &[vec!["daemon-reload"], vec![action, AUTO_UPDATE_TIMER]] {
+            let (exit_code, _) = distro
+                .exec_command_output("systemctl", args)
+                .with_context(|| format!("Failed to run systemctl {}.", args[0]))?;
+            if exit_code != 0 {
+                bail!("systemctl {} exited with {}.", args[0], exit_code);
+            }
+        }
+    }
+    if enabled {
+        log::info!(
+            "The packages will be updated on the schedule '{}'. See the last result by \
+             `distrod status`.",
+            &config.auto_update.schedule
+        );
+    } else {
+        log::info!("The packages will no longer be updated automatically.");
+    }
+    Ok(())
+}
+
+fn run_update() -> Result<()> {
+    let rootfs = HostPath::new("/")?;
+    let (package_manager, command) = match auto_update::detect_package_manager(&rootfs)? {
+        Some(package_manager) => package_manager,
+        None => bail!("No known package manager is found in the distro."),
+    };
+    log::info!("Updating the packages by {}.", package_manager);
+    let status = Command::new("/bin/sh")
+        .args(&["-c", command])
+        .status()
+        .with_context(|| format!("Failed to run '{}'.", command))?;
+    let result = AutoUpdateResult {
+        package_manager: package_manager.to_owned(),
+        finished_at: chrono::Local::now().to_rfc3339_opts(chrono::SecondsFormat::Secs, true),
+        exit_code: status.code(),
+    };
+    if let Err(e) = result.save(&rootfs) {
+        log::warn!("Failed to record the result of the update. {:?}", e);
+    }
+    if !result.succeeded() {
+        bail!("The update {}.", result.describe());
+    }
+    log::info!("The packages are up to date.");
+    Ok(())
+}
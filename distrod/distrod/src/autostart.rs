@@ -1,17 +1,152 @@
+use std::collections::HashSet;
+use std::time::Duration;
 use std::{io::Write, os::unix::prelude::PermissionsExt, path::Path, process::Command};
 
 use anyhow::{anyhow, bail, Context, Result};
 use regex::Regex;
+use structopt::StructOpt;
 use tempfile::NamedTempFile;
 
+use libs::autostart;
+use libs::distro::DistroLauncher;
+use libs::distrod_config::{self, AutostartDistroConfig, DistrodConfig};
 use libs::template::Template;
 use libs::wsl_interop;
 
-pub fn enable_autostart_on_windows_boot(distro_name: &str) -> Result<()> {
+use crate::{ResourceLimitOpts, StartOpts};
+
+#[derive(Debug, StructOpt)]
+#[structopt(rename_all = "kebab")]
+pub struct AutostartOpts {
+    /// The distros to start and their dependencies, which the autostart task gives.
+    /// Defaults to [autostart] of the Distrod config.
+    #[structopt(long)]
+    plan: Option<String>,
+
+    /// Keep running instead of starting the distros, so that the WSL session of the autostart
+    /// task keeps WSL from shutting down the VM. The task runs this when `keep_alive` of
+    /// [autostart] is on, which doesn't need the root.
+    #[structopt(long, conflicts_with = "plan")]
+    keep_alive: bool,
+}
+
+impl AutostartOpts {
+    pub fn is_keep_alive(&self) -> bool {
+        self.keep_alive
+    }
+}
+
+/// How often the keep-alive session wakes up, which doesn't matter to WSL as long as it runs.
+const KEEP_ALIVE_INTERVAL: Duration = Duration::from_secs(3600);
+
+/// Starts the named distros one by one, waiting for the ones in `after` of each distro to finish
+/// booting. A distro which fails to start doesn't stop the others, except the ones after it.
+pub fn start_autostart_distros(opts: AutostartOpts) -> Result<()> {
+    if opts.keep_alive {
+        keep_alive();
+    }
+    let config = DistrodConfig::get().with_context(|| "Failed to get the Distrod config.")?;
+    let configured = &config.autostart.distros;
+    let plan = match opts.plan {
+        Some(ref plan) => autostart::parse_plan(plan)
+            .with_context(|| format!("Invalid autostart plan: '{}'.", plan))?,
+        None => autostart::plan_autostart(configured)
+            .with_context(|| "Invalid [autostart] in the Distrod config.")?,
+    };
+    let ready_timeout = Duration::from_secs(config.autostart.ready_timeout_sec);
+
+    let mut failed = HashSet::new();
+    for distro in plan {
+        // The plan may come from the environment, so only start the distros the root allows.
+        if !configured.iter().any(|allowed| allowed.name == distro.name) {
+            log::warn!("'{}' is not in [autostart]. Skipping it.", &distro.name);
+            failed.insert(distro.name);
+            continue;
+        }
+        if let Err(e) = start_after_dependencies(&distro, &failed, ready_timeout) {
+            log::warn!("Failed to autostart '{}'. {:?}", &distro.name, e);
+            failed.insert(distro.name);
+        }
+    }
+    if !failed.is_empty() {
+        bail!("Failed to autostart {:?}.", failed);
+    }
+    Ok(())
+}
+
+fn start_after_dependencies(
+    distro: &AutostartDistroConfig,
+    failed: &HashSet<String>,
+    ready_timeout: Duration,
+) -> Result<()> {
+    for dep in &distro.after {
+        if failed.contains(dep) {
+            bail!("'{}' has failed to start.", dep);
+        }
+        wait_until_ready(dep, ready_timeout)?;
+    }
+    if DistroLauncher::get_running_distro_by_name(Some(&distro.name))?.is_some() {
+        log::debug!("'{}' is already running.", &distro.name);
+        return Ok(());
+    }
+    log::info!("Starting '{}'.", &distro.name);
+    crate::launch_distro(StartOpts {
+        rootfs: None,
+        distro: Some(distro.name.clone()),
+        target: None,
+        ephemeral: false,
+        read_only: false,
+        mount: vec![],
+        dns: vec![],
+        dns_search: vec![],
+        limits: ResourceLimitOpts::default(),
+        no_wizard: true,
+    })
+}
+
+/// Waits for the running distro to finish booting.
+pub fn wait_until_ready(name: &str, ready_timeout: Duration) -> Result<()> {
+    let distro = DistroLauncher::get_running_distro_by_name(Some(name))?
+        .ok_or_else(|| anyhow!("'{}' is not running.", name))?;
+    log::debug!("Waiting for '{}' to finish booting.", name);
+    if !distro.wait_until_ready(ready_timeout)? {
+        bail!(
+            "'{}' didn't finish booting in {} seconds.",
+            name,
+            ready_timeout.as_secs()
+        );
+    }
+    Ok(())
+}
+
+/// Holds the WSL session of the autostart task until WSL shuts down. WSL shuts down the VM a
+/// while after the last session from Windows ends, even if services are running in the distros.
+fn keep_alive() -> ! {
+    log::info!("Keeping WSL running.");
+    loop {
+        std::thread::sleep(KEEP_ALIVE_INTERVAL);
+    }
+}
+
+/// Returns the order of the named distros which the autostart task will start, which is fixed
+/// when the task is registered.
+pub fn get_autostart_plan() -> Result<String> {
+    let config = DistrodConfig::get().with_context(|| "Failed to get the Distrod config.")?;
+    let plan = autostart::plan_autostart(&config.autostart.distros)
+        .with_context(|| "Invalid [autostart] in the Distrod config.")?;
+    Ok(autostart::encode_plan(&plan))
+}
+
+pub fn enable_autostart_on_windows_boot(
+    distro_name: &str,
+    autostart_plan: &str,
+    keep_alive: bool,
+) -> Result<()> {
     let c = wsl_interop::get_wsl_drive_path("C")?.ok_or_else(|| anyhow!("C drive not found."))?;
 
     let user_name = get_user_name(&c)?;
-    let (_task_xml, task_xml_win_path) = generate_task_xml(&user_name, distro_name)?;
+    let (_task_xml, task_xml_win_path) =
+        generate_task_xml(&user_name, distro_name, autostart_plan, keep_alive)?;
     let sched_ps_cont = generate_schedule_posh_command(&user_name, &task_xml_win_path, distro_name);
 
     let mut powershell =
@@ -65,12 +200,32 @@ fn get_user_name(drive_path: &Path) -> Result<String> {
         .to_string())
 }
 
-fn generate_task_xml(user_name: &str, distro_name: &str) -> Result<(NamedTempFile, String)> {
+fn generate_task_xml(
+    user_name: &str,
+    distro_name: &str,
+    autostart_plan: &str,
+    keep_alive: bool,
+) -> Result<(NamedTempFile, String)> {
     let bytes = include_bytes!("../resources/distrod_autostart.xml");
     let mut task_xml = Template::new(String::from_utf8_lossy(bytes).into_owned());
+    // The task ends with the session, so the keep-alive session mustn't hit the time limit.
+    let (wsl_command, execution_time_limit) = if keep_alive {
+        (
+            format!(
+                "{} autostart --keep-alive",
+                distrod_config::get_distrod_bin_path()
+            ),
+            "PT0S",
+        )
+    } else {
+        ("exit".to_owned(), "PT72H")
+    };
     task_xml
         .assign("USER_NAME", user_name)
         .assign("DISTRO_NAME", distro_name)
+        .assign("AUTOSTART_DISTROS", autostart_plan)
+        .assign("WSL_COMMAND", &wsl_command)
+        .assign("EXECUTION_TIME_LIMIT", execution_time_limit)
         .assign("TASK_NAME", &format!("StartDistrod_{}", &distro_name));
     let mut task_xml_file = NamedTempFile::new().with_context(|| "Failed to create temp file.")?;
 
@@ -0,0 +1,227 @@
+use anyhow::{anyhow, bail, Context, Result};
+use libs::container::{ContainerPath, HostPath};
+use libs::distro::{Distro, DistroLauncher};
+use libs::distro_registry::{self, DistroInstance};
+use libs::distrod_config::DistrodConfig;
+use libs::mount_info::get_mount_entries;
+use libs::rootfs_dir::RootfsDir;
+use libs::rootfs_image;
+use std::path::{Path, PathBuf};
+use std::process::Command;
+use structopt::StructOpt;
+
+use crate::snapshot;
+
+#[derive(Debug, StructOpt)]
+#[structopt(rename_all = "kebab")]
+pub struct CompactOpts {
+    /// The distro to compact. Defaults to all the named distros.
+    #[structopt(long)]
+    distro: Option<String>,
+
+    /// The number of the newest snapshots of each distro to keep. Defaults to `keep` of the
+    /// [snapshot] section of the Distrod config, or all of them.
+    #[structopt(long)]
+    keep_snapshots: Option<usize>,
+
+    /// Don't clear the caches of the package managers.
+    #[structopt(long)]
+    keep_package_caches: bool,
+}
+
+/// A package manager, whose cache of the downloaded packages `distrod compact` clears.
+struct PackageManager {
+    /// The program by which the package manager is detected, in the rootfs.
+    program: &'static str,
+    /// The command which clears the cache, which runs in the distro while it's running.
+    clean_command: &'static [&'static str],
+    /// The files under these directories are removed from outside while the distro is stopped.
+    cache_dirs: &'static [&'static str],
+}
+
+const PACKAGE_MANAGERS: &[PackageManager] = &[
+    PackageManager {
+        program: "/usr/bin/apt-get",
+        clean_command: &["/usr/bin/apt-get", "clean"],
+        cache_dirs: &["/var/cache/apt/archives"],
+    },
+    PackageManager {
+        program: "/usr/bin/dnf",
+        clean_command: &["/usr/bin/dnf", "clean", "all"],
+        cache_dirs: &["/var/cache/dnf"],
+    },
+    PackageManager {
+        program: "/usr/bin/yum",
+        clean_command: &["/usr/bin/yum", "clean", "all"],
+        cache_dirs: &["/var/cache/yum"],
+    },
+    PackageManager {
+        program: "/usr/bin/pacman",
+        clean_command: &["/usr/bin/pacman", "-Scc", "--noconfirm"],
+        cache_dirs: &["/var/cache/pacman/pkg"],
+    },
+    PackageManager {
+        program: "/usr/bin/zypper",
+        clean_command: &["/usr/bin/zypper", "--non-interactive", "clean", "--all"],
+        cache_dirs: &["/var/cache/zypp/packages"],
+    },
+    PackageManager {
+        program: "/sbin/apk",
+        clean_command: &["/sbin/apk", "cache", "clean"],
+        cache_dirs: &["/var/cache/apk"],
+    },
+];
+
+/// Reclaims the disk space the distros don't need, continuing to the next step and the next
+/// distro when a step fails.
+pub fn compact_distros(opts: CompactOpts) -> Result<()> {
+    let instances = match opts.distro {
+        Some(ref name) => vec![DistroInstance::get(name)?],
+        None => distro_registry::list_instances().with_context(|| "Failed to list the distros.")?,
+    };
+    let keep_snapshots = match opts.keep_snapshots {
+        Some(keep) => Some(keep),
+        None => {
+            DistrodConfig::get()
+                .with_context(|| "Failed to get the Distrod config.")?
+                .snapshot
+                .keep
+        }
+    };
+    let mut n_failures = 0;
+    for instance in &instances {
+        log::info!("Compacting {}...", &instance.name);
+        if let Err(e) = compact_distro(instance, keep_snapshots, !opts.keep_package_caches) {
+            log::warn!("Failed to compact {}. {:?}", &instance.name, e);
+            n_failures += 1;
+        }
+    }
+    if n_failures > 0 {
+        bail!("Failed to compact {} of the distros.", n_failures);
+    }
+    Ok(())
+}
+
+fn compact_distro(
+    instance: &DistroInstance,
+    keep_snapshots: Option<usize>,
+    clears_package_caches: bool,
+) -> Result<()> {
+    // A disk image is mounted so that it can be trimmed, which punches holes in the image
+    // file for the blocks its file system has freed.
+    rootfs_image::mount_image(&instance.rootfs)?;
+    let mut succeeded = true;
+    if clears_package_caches {
+        if let Err(e) = clear_package_caches(instance) {
+            log::warn!("Failed to clear the package caches. {:?}", e);
+            succeeded = false;
+        }
+    }
+    if let Some(keep) = keep_snapshots {
+        match snapshot::prune_snapshots(instance, keep) {
+            Ok(deleted) => {
+                for name in deleted {
+                    log::info!("Deleted the snapshot {}.", name);
+                }
+            }
+            Err(e) => {
+                log::warn!("Failed to delete the old snapshots. {:?}", e);
+                succeeded = false;
+            }
+        }
+    }
+    // The deleted files above are trimmed together.
+    if let Err(e) = trim(&instance.rootfs) {
+        log::warn!("Failed to trim the file system of the rootfs. {:?}", e);
+        succeeded = false;
+    }
+    if !succeeded {
+        bail!("Some steps have failed.");
+    }
+    Ok(())
+}
+
+fn clear_package_caches(instance: &DistroInstance) -> Result<()> {
+    let rootfs = HostPath::new(&instance.rootfs)?;
+    let running_distro = DistroLauncher::get_running_distro_by_name(Some(&instance.name))
+        .with_context(|| "Failed to get the running distro.")?;
+    for package_manager in PACKAGE_MANAGERS {
+        if !ContainerPath::new(package_manager.program)?
+            .to_host_path(&rootfs)
+            .exists()
+        {
+            continue;
+        }
+        log::info!("Clearing the cache of {}...", package_manager.program);
+        match running_distro {
+            // The package manager knows its cache best, and holds its lock while it runs.
+            Some(ref distro) => run_clean_command(distro, package_manager)?,
+            None => {
+                for dir in package_manager.cache_dirs {
+                    // The cache directories are opened without following symlinks, which
+                    // would point out of the rootfs.
+                    if let Some(dir) = RootfsDir::open(&rootfs, &ContainerPath::new(dir)?)? {
+                        remove_files_under(&dir)?;
+                    }
+                }
+            }
+        }
+    }
+    Ok(())
+}
+
+fn run_clean_command(distro: &Distro, package_manager: &PackageManager) -> Result<()> {
+    let (command, args) = package_manager
+        .clean_command
+        .split_first()
+        .expect("[BUG] a clean command is empty.");
+    let (exit_code, _) = distro.exec_command_output(command, args)?;
+    if exit_code != 0 {
+        bail!("{} exited with {}.", command, exit_code);
+    }
+    Ok(())
+}
+
+/// Removes the files in the directory and its subdirectories but keeps the directories, which
+/// the package managers expect to exist. A symlink is removed itself.
+fn remove_files_under(dir: &RootfsDir) -> Result<()> {
+    for (name, is_dir) in dir.entries()? {
+        if is_dir {
+            if let Some(subdir) = dir.open_dir(&name)? {
+                remove_files_under(&subdir)?;
+            }
+        } else if name != "lock" {
+            dir.remove_file(&name)?;
+        }
+    }
+    Ok(())
+}
+
+/// Trims the file system which has the rootfs, so that WSL or the disk image can give the
+/// freed blocks back to Windows.
+fn trim(rootfs: &Path) -> Result<()> {
+    let mount_point = get_mount_point(rootfs)?;
+    log::info!("Trimming {:?}...", &mount_point);
+    let status = Command::new("fstrim")
+        .arg("-v")
+        .arg(&mount_point)
+        .status()
+        .with_context(|| "Failed to run fstrim.")?;
+    if !status.success() {
+        bail!("fstrim exited with {}.", status);
+    }
+    Ok(())
+}
+
+/// Returns the mount point of the file system which has the path.
+fn get_mount_point(path: &Path) -> Result<PathBuf> {
+    let path = path
+        .canonicalize()
+        .with_context(|| format!("Failed to canonicalize {:?}.", path))?;
+    get_mount_entries()?
+        .into_iter()
+        .map(|entry| entry.path)
+        .filter(|mount_point| path.starts_with(mount_point))
+        .max_by_key(|mount_point| mount_point.components().count())
+        .ok_or_else(|| anyhow!("No file system is mounted on {:?}.", &path))
+}
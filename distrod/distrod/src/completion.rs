@@ -0,0 +1,53 @@
+use anyhow::{Context, Result};
+use structopt::clap::Shell;
+use structopt::StructOpt;
+
+use libs::completion::{self, CompletionShell, DynamicValues, COMPLETION_SHELLS};
+use libs::distrod_config;
+
+use crate::Opts;
+
+#[derive(Debug, StructOpt)]
+#[structopt(rename_all = "kebab")]
+pub struct CompletionOpts {
+    /// The shell to print the completion script for.
+    #[structopt(possible_values = COMPLETION_SHELLS)]
+    shell: CompletionShell,
+}
+
+/// Prints the completion script, which completes the names of the distros of `distrod create`
+/// after --distro by `distrod list`.
+pub fn print_completion(opts: CompletionOpts) -> Result<()> {
+    let clap_shell = match opts.shell {
+        CompletionShell::Bash => Shell::Bash,
+        CompletionShell::Zsh => Shell::Zsh,
+        CompletionShell::Fish => Shell::Fish,
+        CompletionShell::PowerShell => Shell::PowerShell,
+    };
+    let mut script = vec![];
+    Opts::clap().gen_completions_to("distrod", clap_shell, &mut script);
+    let script = String::from_utf8(script)
+        .with_context(|| "The generated completion script is not UTF-8.")?;
+
+    let distrod_path = format!("{}/distrod", distrod_config::get_distrod_bin_dir_path());
+    let list_command = match opts.shell {
+        CompletionShell::PowerShell => format!(
+            "& '{}' list --format tsv 2>$null | Select-Object -Skip 1 | \
+             ForEach-Object {{ ($_ -split \"`t\")[0] }}",
+            distrod_path
+        ),
+        _ => format!(
+            "{} list --format tsv 2>/dev/null | tail -n +2 | cut -f1",
+            distrod_path
+        ),
+    };
+    let values = DynamicValues {
+        options: &["--distro"],
+        command: &list_command,
+    };
+    print!(
+        "{}",
+        completion::add_dynamic_values(&script, opts.shell, "distrod", &values)
+    );
+    Ok(())
+}
@@ -0,0 +1,215 @@
+use anyhow::{bail, Context, Result};
+use libs::cancellation;
+use libs::compose::{ComposeDistro, ComposeFile, DEFAULT_COMPOSE_FILE_NAME};
+use libs::container::HostPath;
+use libs::container_org_image::parse_image_reference;
+use libs::distro::{self, DistroLauncher};
+use libs::distro_registry::DistroInstance;
+use libs::distrod_config;
+use libs::error::DistroError;
+use libs::port_forward::PortForwardRules;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+use structopt::StructOpt;
+
+use distrod_core::{CancellationToken, CreateOptions, ImageSource};
+
+use crate::{ResourceLimitOpts, StartOpts, StopOpts};
+
+#[derive(Debug, StructOpt)]
+pub enum ComposeOpts {
+    /// Create the distros of the compose file which don't exist yet, forward their ports, and
+    /// start them in the order of their dependencies.
+    Up(ComposeUpOpts),
+    /// Stop the distros of the compose file in the reverse order, and stop forwarding their ports.
+    Down(ComposeDownOpts),
+}
+
+#[derive(Debug, StructOpt)]
+#[structopt(rename_all = "kebab")]
+pub struct ComposeUpOpts {
+    /// The compose file. Defaults to distrod-compose.toml in the current directory.
+    #[structopt(short, long)]
+    file: Option<PathBuf>,
+}
+
+#[derive(Debug, StructOpt)]
+#[structopt(rename_all = "kebab")]
+pub struct ComposeDownOpts {
+    /// The compose file. Defaults to distrod-compose.toml in the current directory.
+    #[structopt(short, long)]
+    file: Option<PathBuf>,
+
+    /// Kill the processes of the distros right away without powering off systemd.
+    #[structopt(short = "9", long)]
+    sigkill: bool,
+
+    /// The seconds to wait for systemd of each distro to power off before killing the processes.
+    #[structopt(short, long)]
+    timeout: Option<u64>,
+}
+
+pub fn run_compose_command(opts: ComposeOpts) -> Result<()> {
+    match opts {
+        ComposeOpts::Up(up_opts) => compose_up(up_opts),
+        ComposeOpts::Down(down_opts) => compose_down(down_opts),
+    }
+}
+
+fn load_compose_file(file: Option<&Path>) -> Result<ComposeFile> {
+    ComposeFile::load(file.unwrap_or_else(|| Path::new(DEFAULT_COMPOSE_FILE_NAME)))
+}
+
+/// Brings up the distros one by one, waiting for the ones in `after` of each distro to finish
+/// booting. It stops at the first distro which fails, leaving the ones before it running.
+fn compose_up(opts: ComposeUpOpts) -> Result<()> {
+    let compose = load_compose_file(opts.file.as_deref())?;
+    let ready_timeout = Duration::from_secs(compose.ready_timeout_sec);
+    for distro in compose.plan_start_order()? {
+        bring_up_distro(distro, ready_timeout)
+            .with_context(|| format!("Failed to bring up '{}'.", &distro.name))?;
+    }
+    log::info!("All the distros are up.");
+    Ok(())
+}
+
+fn bring_up_distro(distro: &ComposeDistro, ready_timeout: Duration) -> Result<()> {
+    let rootfs = match DistroInstance::get(&distro.name) {
+        Ok(instance) => HostPath::new(instance.rootfs)?,
+        Err(e) if matches!(e.downcast_ref(), Some(DistroError::NotFound { .. })) => {
+            create_compose_distro(distro)?
+        }
+        Err(e) => return Err(e),
+    };
+    apply_config(distro, &rootfs)?;
+    add_port_forwards(distro)?;
+
+    for dep in &distro.after {
+        crate::autostart::wait_until_ready(dep, ready_timeout)?;
+    }
+    if DistroLauncher::get_running_distro_by_name(Some(&distro.name))?.is_some() {
+        log::info!("'{}' is already running.", &distro.name);
+        return Ok(());
+    }
+    log::info!("Starting '{}'.", &distro.name);
+    crate::launch_distro(StartOpts {
+        rootfs: None,
+        distro: Some(distro.name.clone()),
+        target: None,
+        ephemeral: false,
+        read_only: false,
+        mount: distro.mounts.clone(),
+        dns: vec![],
+        dns_search: vec![],
+        limits: ResourceLimitOpts::default(),
+        no_wizard: true,
+    })
+}
+
+#[tokio::main]
+async fn create_compose_distro(distro: &ComposeDistro) -> Result<HostPath> {
+    // Read the spec before downloading the image, so that a broken spec doesn't waste it.
+    let spec = distro.load_provision_spec()?;
+    log::info!("Creating '{}' from {}.", &distro.name, &distro.image);
+    let mut create_opts = CreateOptions::new(ImageSource::ContainerOrg(parse_image_reference(
+        &distro.image,
+    )?));
+    create_opts.with_name(&distro.name).with_progress_bar();
+    let cancel = CancellationToken::new();
+    cancellation::run_on_ctrl_c({
+        let cancel = cancel.clone();
+        move || cancel.cancel()
+    });
+    let created = distrod_core::create(&create_opts, &cancel).await?;
+    let rootfs = HostPath::new(created.rootfs)?;
+    if let Some(spec) = spec {
+        crate::provision_distro(&rootfs, &spec).with_context(|| {
+            format!("'{}' is created, but failed to provision it.", &distro.name)
+        })?;
+    }
+    Ok(rootfs)
+}
+
+/// Sets the keys of `config` of the distro, which take effect from the next start if it's
+/// already running.
+fn apply_config(distro: &ComposeDistro, rootfs: &HostPath) -> Result<()> {
+    let values = distro.get_config_values()?;
+    if values.is_empty() {
+        return Ok(());
+    }
+    let mut config = distro::get_distro_config(rootfs)?;
+    for (key, value) in &values {
+        config.set_value(key, value)?;
+    }
+    distro::set_distro_config(rootfs, &config)
+        .with_context(|| "Failed to save the config of the distro.")
+}
+
+fn add_port_forwards(distro: &ComposeDistro) -> Result<()> {
+    let rules_path = distrod_config::get_port_forward_rules_path();
+    let mut rules = PortForwardRules::open(rules_path)?;
+    let mut changed = false;
+    for rule in distro.get_port_forward_rules()? {
+        if rules.forwards.contains(&rule) {
+            continue;
+        }
+        let listen = rule.listen_socket_address();
+        rules
+            .add(rule)
+            .with_context(|| format!("Failed to forward {}.", listen))?;
+        log::info!("Forwarding {} to '{}'.", listen, &distro.name);
+        changed = true;
+    }
+    if changed {
+        rules.save(rules_path)?;
+    }
+    Ok(())
+}
+
+/// Stops the distros in the reverse order of `up`, so that no distro stops before the ones which
+/// depend on it. A distro which fails to stop doesn't stop the others.
+fn compose_down(opts: ComposeDownOpts) -> Result<()> {
+    let compose = load_compose_file(opts.file.as_deref())?;
+    let mut plan = compose.plan_start_order()?;
+    plan.reverse();
+    let mut failed = vec![];
+    for distro in plan {
+        if let Err(e) = bring_down_distro(distro, &opts) {
+            log::warn!("Failed to bring down '{}'. {:?}", &distro.name, e);
+            failed.push(distro.name.as_str());
+        }
+    }
+    if !failed.is_empty() {
+        bail!("Failed to bring down {:?}.", failed);
+    }
+    log::info!("All the distros are down.");
+    Ok(())
+}
+
+fn bring_down_distro(distro: &ComposeDistro, opts: &ComposeDownOpts) -> Result<()> {
+    remove_port_forwards(distro)?;
+    if DistroLauncher::get_running_distro_by_name(Some(&distro.name))?.is_none() {
+        log::debug!("'{}' is not running.", &distro.name);
+        return Ok(());
+    }
+    log::info!("Stopping '{}'.", &distro.name);
+    crate::stop_distro(StopOpts {
+        sigkill: opts.sigkill,
+        timeout: opts.timeout,
+        distro: Some(distro.name.clone()),
+    })
+}
+
+/// Removes only the forwards which are the same as the ones of the compose file, leaving the ones
+/// changed by `distrod port` since `up`.
+fn remove_port_forwards(distro: &ComposeDistro) -> Result<()> {
+    let rules_path = distrod_config::get_port_forward_rules_path();
+    let mut rules = PortForwardRules::open(rules_path)?;
+    let compose_rules = distro.get_port_forward_rules()?;
+    let count = rules.forwards.len();
+    rules.forwards.retain(|rule| !compose_rules.contains(rule));
+    if rules.forwards.len() != count {
+        rules.save(rules_path)?;
+    }
+    Ok(())
+}
@@ -0,0 +1,74 @@
+use anyhow::{bail, Context, Result};
+use libs::distro;
+use structopt::StructOpt;
+
+#[derive(Debug, StructOpt)]
+pub enum ConfigOpts {
+    /// Show the value of a key in /etc/distrod/distrod.toml of the distro, such as network.share_resolv_conf.
+    Get(ConfigGetOpts),
+    /// Set the value of a key in /etc/distrod/distrod.toml of the distro. It takes effect from the next start.
+    Set(ConfigSetOpts),
+}
+
+#[derive(Debug, StructOpt)]
+#[structopt(rename_all = "kebab")]
+pub struct ConfigGetOpts {
+    /// The key such as `autostart.delay_sec` or `env.EDITOR`. The whole config is shown if omitted.
+    key: Option<String>,
+
+    #[structopt(long)]
+    distro: Option<String>,
+}
+
+#[derive(Debug, StructOpt)]
+#[structopt(rename_all = "kebab")]
+pub struct ConfigSetOpts {
+    /// The key such as `autostart.delay_sec` or `env.EDITOR`.
+    key: String,
+
+    /// The new value. Give a list as comma-separated values, such as `a.service,b.service`.
+    value: String,
+
+    #[structopt(long)]
+    distro: Option<String>,
+}
+
+pub fn run_config_command(opts: ConfigOpts) -> Result<()> {
+    match opts {
+        ConfigOpts::Get(get_opts) => get_config(get_opts),
+        ConfigOpts::Set(set_opts) => set_config(set_opts),
+    }
+}
+
+fn get_config(opts: ConfigGetOpts) -> Result<()> {
+    let rootfs = distro::get_distro_rootfs(opts.distro.as_deref())?;
+    let config = distro::get_distro_config(&rootfs)?;
+    let key = match opts.key {
+        Some(key) => key,
+        None => {
+            print!("{}", config.to_toml_string()?);
+            return Ok(());
+        }
+    };
+    match config.get_value(&key)? {
+        Some(toml::Value::String(value)) => println!("{}", value),
+        Some(value @ toml::Value::Table(_)) => print!("{}", toml::to_string(&value)?),
+        Some(value) => println!("{}", value),
+        None => bail!("'{}' is not set.", &key),
+    }
+    Ok(())
+}
+
+fn set_config(opts: ConfigSetOpts) -> Result<()> {
+    let rootfs = distro::get_distro_rootfs(opts.distro.as_deref())?;
+    let mut config = distro::get_distro_config(&rootfs)?;
+    config.set_value(&opts.key, &opts.value)?;
+    distro::set_distro_config(&rootfs, &config)
+        .with_context(|| "Failed to save the config of the distro.")?;
+    log::info!(
+        "{} has been set to {}. It takes effect from the next start.",
+        &opts.key,
+        &opts.value
+    );
+    Ok(())
+}
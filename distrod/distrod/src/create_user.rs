@@ -0,0 +1,216 @@
+use anyhow::{bail, Context, Result};
+use libs::cli_ui;
+use libs::container::{ContainerPath, HostPath};
+use libs::distro;
+use libs::distro_config::validate_user_name;
+use libs::provision::UserSpec;
+use libs::wsl_conf::WslConf;
+use std::fs;
+use std::os::unix::fs::PermissionsExt;
+use std::process::Command;
+
+/// The groups whose members can use sudo, in the order of preference.
+const ADMIN_GROUPS: [&str; 2] = ["sudo", "wheel"];
+const SUDOERS_FILE_PATH: &str = "/etc/sudoers.d/distrod-default-user";
+const MAX_PASSWORD_ATTEMPTS: usize = 3;
+
+/// Creates the default user of a new distro with the admin group, and makes it the user
+/// `distrod shell` and WSL log in as. With `passwordless_sudo`, sudo doesn't ask the password.
+pub fn create_default_user(
+    rootfs: &HostPath,
+    name: &str,
+    uid: Option<u32>,
+    prompts_password: bool,
+    passwordless_sudo: bool,
+) -> Result<()> {
+    validate_user_name(name)?;
+    if prompts_password && !cli_ui::is_interactive() {
+        bail!(
+            "Can't prompt for the password since Distrod runs non-interactively. \
+             Drop --password, and set it by `passwd` later."
+        );
+    }
+    let admin_group = find_admin_group(rootfs)?;
+    add_user(rootfs, name, uid, admin_group)
+        .with_context(|| format!("Failed to add the user {}.", name))?;
+    allow_sudo(rootfs, name, passwordless_sudo)
+        .with_context(|| "Failed to allow the user to use sudo.")?;
+
+    if prompts_password {
+        set_password(rootfs, name)?;
+    } else if !passwordless_sudo {
+        log::info!(
+            "The password of {} is not set. Set it by `passwd` in the distro to use sudo.",
+            name
+        );
+    }
+
+    let mut config = distro::get_distro_config(rootfs)?;
+    config.user.default = Some(name.to_owned());
+    distro::set_distro_config(rootfs, &config)
+        .with_context(|| "Failed to save the default user in the config of the distro.")?;
+    let mut wsl_conf = WslConf::open(ContainerPath::new("/etc/wsl.conf")?.to_host_path(rootfs))?;
+    wsl_conf.set("user", "default", name);
+    wsl_conf.write()?;
+    Ok(())
+}
+
+/// Asks the password of the user by passwd, again when the two inputs don't match, up to
+/// `MAX_PASSWORD_ATTEMPTS` times.
+fn set_password(rootfs: &HostPath, name: &str) -> Result<()> {
+    for _ in 0..MAX_PASSWORD_ATTEMPTS {
+        let status = chroot_command(rootfs, "passwd")
+            .arg(name)
+            .status()
+            .with_context(|| "Failed to run passwd.")?;
+        if status.success() {
+            return Ok(());
+        }
+    }
+    bail!(
+        "Failed to set the password of {} in {} attempts. Set it by `passwd` in the distro.",
+        name,
+        MAX_PASSWORD_ATTEMPTS
+    );
+}
+
+/// Creates a user of the provisioning spec, and adds it to its groups which exist in the distro.
+pub fn create_provisioned_user(rootfs: &HostPath, user: &UserSpec) -> Result<()> {
+    if user.default {
+        create_default_user(rootfs, &user.name, user.uid, false, user.passwordless_sudo)?;
+    } else {
+        let is_admin = user.admin || user.passwordless_sudo;
+        let admin_group = if is_admin {
+            find_admin_group(rootfs)?
+        } else {
+            None
+        };
+        add_user(rootfs, &user.name, user.uid, admin_group)
+            .with_context(|| format!("Failed to add the user {}.", &user.name))?;
+        if is_admin {
+            allow_sudo(rootfs, &user.name, user.passwordless_sudo)
+                .with_context(|| "Failed to allow the user to use sudo.")?;
+        }
+    }
+    for group in &user.groups {
+        if !has_group(rootfs, group)? {
+            log::warn!(
+                "The group {} doesn't exist in the distro. {} is not added to it.",
+                group,
+                &user.name
+            );
+            continue;
+        }
+        add_user_to_group(rootfs, &user.name, group)
+            .with_context(|| format!("Failed to add {} to {}.", &user.name, group))?;
+    }
+    Ok(())
+}
+
+fn find_admin_group(rootfs: &HostPath) -> Result<Option<&'static str>> {
+    let groups = read_group_names(rootfs)?;
+    Ok(ADMIN_GROUPS
+        .iter()
+        .copied()
+        .find(|admin_group| groups.iter().any(|group| group == admin_group)))
+}
+
+fn has_group(rootfs: &HostPath, name: &str) -> Result<bool> {
+    Ok(read_group_names(rootfs)?.iter().any(|group| group == name))
+}
+
+fn read_group_names(rootfs: &HostPath) -> Result<Vec<String>> {
+    let group_path = ContainerPath::new("/etc/group")?.to_host_path(rootfs);
+    let groups = fs::read_to_string(group_path.as_path())
+        .with_context(|| format!("Failed to read {:?}.", &group_path))?;
+    Ok(groups
+        .lines()
+        .filter_map(|line| line.split(':').next())
+        .map(|name| name.to_owned())
+        .collect())
+}
+
+fn add_user(rootfs: &HostPath, name: &str, uid: Option<u32>, group: Option<&str>) -> Result<()> {
+    let shell = if ContainerPath::new("/bin/bash")?
+        .to_host_path(rootfs)
+        .exists()
+    {
+        "/bin/bash"
+    } else {
+        "/bin/sh"
+    };
+    let mut has_useradd = false;
+    for path in &["/usr/sbin/useradd", "/sbin/useradd"] {
+        has_useradd |= ContainerPath::new(path)?.to_host_path(rootfs).exists();
+    }
+    if has_useradd {
+        let mut useradd = chroot_command(rootfs, "useradd");
+        useradd.args(&["-m", "-s", shell]);
+        if let Some(uid) = uid {
+            useradd.args(&["-u", &uid.to_string()]);
+        }
+        if let Some(group) = group {
+            useradd.args(&["-G", group]);
+        }
+        return run(useradd.arg(name));
+    }
+
+    // Distros based on BusyBox such as Alpine have only adduser.
+    let mut adduser = chroot_command(rootfs, "adduser");
+    adduser.args(&["-D", "-s", shell]);
+    if let Some(uid) = uid {
+        adduser.args(&["-u", &uid.to_string()]);
+    }
+    run(adduser.arg(name))?;
+    if let Some(group) = group {
+        run(chroot_command(rootfs, "addgroup").args(&[name, group]))?;
+    }
+    Ok(())
+}
+
+fn add_user_to_group(rootfs: &HostPath, name: &str, group: &str) -> Result<()> {
+    let mut has_usermod = false;
+    for path in &["/usr/sbin/usermod", "/sbin/usermod"] {
+        has_usermod |= ContainerPath::new(path)?.to_host_path(rootfs).exists();
+    }
+    if has_usermod {
+        return run(chroot_command(rootfs, "usermod").args(&["-aG", group, name]));
+    }
+    run(chroot_command(rootfs, "addgroup").args(&[name, group]))
+}
+
+fn allow_sudo(rootfs: &HostPath, name: &str, passwordless: bool) -> Result<()> {
+    let sudoers_path = ContainerPath::new(SUDOERS_FILE_PATH)?.to_host_path(rootfs);
+    let sudoers_dir = sudoers_path.parent().expect("the path has a parent");
+    if !sudoers_dir.exists() {
+        log::info!("sudo is not installed in the distro.");
+        return Ok(());
+    }
+    let rule = if passwordless {
+        format!("{} ALL=(ALL:ALL) NOPASSWD: ALL\n", name)
+    } else {
+        format!("{} ALL=(ALL:ALL) ALL\n", name)
+    };
+    fs::write(sudoers_path.as_path(), rule)
+        .with_context(|| format!("Failed to write {:?}.", &sudoers_path))?;
+    // sudo ignores the files which others can write to.
+    fs::set_permissions(sudoers_path.as_path(), fs::Permissions::from_mode(0o440))
+        .with_context(|| format!("Failed to set the permission of {:?}.", &sudoers_path))?;
+    Ok(())
+}
+
+fn chroot_command(rootfs: &HostPath, command: &str) -> Command {
+    let mut chroot = Command::new("chroot");
+    chroot.arg(rootfs.as_path()).arg(command);
+    chroot
+}
+
+fn run(command: &mut Command) -> Result<()> {
+    let status = command
+        .status()
+        .with_context(|| format!("Failed to run {:?}.", &command))?;
+    if !status.success() {
+        bail!("{:?} exited with {}.", &command, status);
+    }
+    Ok(())
+}
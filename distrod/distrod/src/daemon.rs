@@ -0,0 +1,323 @@
+use anyhow::{anyhow, bail, Context, Result};
+use libs::container::HostPath;
+use libs::daemon::{
+    self, DaemonClient, DaemonDistroState, DaemonEvent, DaemonRequest, DaemonResponse,
+};
+use libs::distro::{get_daemon_socket_path, DistroLauncher};
+use libs::distro_registry;
+use libs::multifork::reap_children;
+use nix::poll::{PollFd, PollFlags};
+use nix::sys::socket::sockopt::PeerCredentials;
+use std::collections::BTreeMap;
+use std::fs;
+use std::os::unix::fs::PermissionsExt;
+use std::os::unix::io::AsRawFd;
+use std::os::unix::net::{UnixListener, UnixStream};
+use std::process::{Command, Stdio};
+use std::sync::{Arc, Mutex, PoisonError, TryLockError};
+use std::time::Duration;
+use structopt::StructOpt;
+
+/// How often the daemon looks at the states of the distros while no request comes, which is
+/// how soon it tells the subscribers about the changes made without it.
+const STATE_CHECK_INTERVAL_MSEC: i32 = 1000;
+/// How long a client can keep its thread waiting for its next request or for reading an event.
+const CLIENT_TIMEOUT: Duration = Duration::from_secs(5);
+
+#[derive(Debug, StructOpt)]
+pub struct DaemonOpts {
+    #[structopt(subcommand)]
+    command: Option<DaemonCommand>,
+}
+
+#[derive(Debug, StructOpt)]
+pub enum DaemonCommand {
+    /// Run the daemon in the foreground. This is the default.
+    Run,
+    /// Print the events of the running daemon as JSON lines, such as a distro starting.
+    Events,
+    /// Start or stop a distro by the request on stdin, and write the response to stdout. The
+    /// daemon runs this for each start and stop, since forking the init of a distro from the
+    /// daemon, which has the threads of its clients, isn't safe.
+    #[structopt(setting = structopt::clap::AppSettings::Hidden)]
+    Handle,
+}
+
+pub fn run_daemon_command(opts: DaemonOpts) -> Result<()> {
+    match opts.command.unwrap_or(DaemonCommand::Run) {
+        DaemonCommand::Run => Daemon::bind()?.run(),
+        DaemonCommand::Events => print_events(),
+        DaemonCommand::Handle => handle_request_from_stdin(),
+    }
+}
+
+/// The init pids of the running distros by their names, where None is the default distro.
+type DistroStates = BTreeMap<Option<String>, u32>;
+
+/// A long-running process which starts and stops the distros for its clients, such as the CLI
+/// and distrod-exec, and tells the subscribers when a distro starts or stops.
+struct Daemon {
+    listener: UnixListener,
+    socket_path: HostPath,
+    shared: Arc<SharedState>,
+    states: DistroStates,
+}
+
+/// What the threads serving the clients share with the daemon.
+#[derive(Default)]
+struct SharedState {
+    subscribers: Mutex<Vec<UnixStream>>,
+    /// Held while a distro starts or stops by `distrod daemon handle`, which also keeps the
+    /// daemon from reaping the process the operation waits for. The operations run one at a time.
+    operation: Mutex<()>,
+}
+
+impl Daemon {
+    fn bind() -> Result<Daemon> {
+        let socket_path = get_daemon_socket_path()?;
+        if socket_path.exists() {
+            if UnixStream::connect(socket_path.as_path()).is_ok() {
+                bail!("The daemon is already running.");
+            }
+            fs::remove_file(socket_path.as_path())
+                .with_context(|| format!("Failed to remove the stale {:?}.", &socket_path))?;
+        }
+        let listener = UnixListener::bind(socket_path.as_path())
+            .with_context(|| format!("Failed to bind {:?}.", &socket_path))?;
+        fs::set_permissions(socket_path.as_path(), fs::Permissions::from_mode(0o600))
+            .with_context(|| format!("Failed to set the permission of {:?}.", &socket_path))?;
+        Ok(Daemon {
+            listener,
+            socket_path,
+            shared: Arc::new(SharedState::default()),
+            states: get_distro_states()?,
+        })
+    }
+
+    /// Serves the clients until it fails.
+    fn run(&mut self) -> Result<()> {
+        log::info!("The daemon is listening on {:?}.", &self.socket_path);
+        loop {
+            // A thread starting or stopping a distro waits for its child by itself.
+            match self.shared.operation.try_lock() {
+                Ok(_operation) => reap_children(),
+                Err(TryLockError::Poisoned(operation)) => {
+                    let _operation = operation.into_inner();
+                    reap_children();
+                }
+                Err(TryLockError::WouldBlock) => {}
+            }
+            let mut fds = [PollFd::new(self.listener.as_raw_fd(), PollFlags::POLLIN)];
+            match nix::poll::poll(&mut fds, STATE_CHECK_INTERVAL_MSEC) {
+                Ok(0) => {
+                    self.check_distro_states();
+                    continue;
+                }
+                Ok(_) => {}
+                Err(nix::Error::Sys(nix::errno::Errno::EINTR)) => continue,
+                Err(e) => return Err(e).with_context(|| "Failed to poll the socket."),
+            }
+            let (stream, _) = match self.listener.accept() {
+                Ok(accepted) => accepted,
+                Err(e) => {
+                    log::warn!("Failed to accept a connection. {:?}", e);
+                    continue;
+                }
+            };
+            // Each client has its own thread, so that starting or stopping a distro, which may
+            // take long, doesn't keep the other clients waiting.
+            let shared = self.shared.clone();
+            std::thread::spawn(move || {
+                if let Err(e) = serve(&shared, stream) {
+                    log::warn!("Failed to serve a client. {:?}", e);
+                }
+            });
+            self.check_distro_states();
+        }
+    }
+
+    fn check_distro_states(&mut self) {
+        let states = match get_distro_states() {
+            Ok(states) => states,
+            Err(e) => {
+                log::warn!("Failed to get the states of the distros. {:?}", e);
+                return;
+            }
+        };
+        let mut events = vec![];
+        for (name, init_pid) in &self.states {
+            if states.get(name) != Some(init_pid) {
+                events.push(DaemonEvent::Stopped { name: name.clone() });
+            }
+        }
+        for (name, init_pid) in &states {
+            if self.states.get(name) != Some(init_pid) {
+                events.push(DaemonEvent::Started {
+                    name: name.clone(),
+                    init_pid: *init_pid,
+                });
+            }
+        }
+        self.states = states;
+        for event in events {
+            log::info!("{:?}", &event);
+            daemon::publish_event(&self.shared.subscribers, event);
+        }
+    }
+}
+
+impl Drop for Daemon {
+    fn drop(&mut self) {
+        let _ = fs::remove_file(self.socket_path.as_path());
+    }
+}
+
+fn serve(shared: &SharedState, stream: UnixStream) -> Result<()> {
+    let peer = nix::sys::socket::getsockopt(stream.as_raw_fd(), PeerCredentials)
+        .with_context(|| "Failed to get the credential of the peer.")?;
+    if peer.uid() != 0 {
+        bail!("A non-root user (uid {}) connected.", peer.uid());
+    }
+    stream.set_read_timeout(Some(CLIENT_TIMEOUT))?;
+    stream.set_write_timeout(Some(CLIENT_TIMEOUT))?;
+    daemon::serve_client(stream, &shared.subscribers, |request| match request {
+        DaemonRequest::List => Ok(DaemonResponse::Distros {
+            distros: list_distro_states()?,
+        }),
+        DaemonRequest::Start { .. } | DaemonRequest::Stop { .. } => {
+            // A panicked operation leaves nothing the next one relies on.
+            let _operation = shared
+                .operation
+                .lock()
+                .unwrap_or_else(PoisonError::into_inner);
+            run_handler_process(request)
+        }
+        DaemonRequest::Subscribe => bail!("[BUG] a subscription is not a request to handle."),
+    })
+}
+
+/// Runs `distrod daemon handle` for the request, whose logs go to the ones of the daemon.
+fn run_handler_process(request: &DaemonRequest) -> Result<DaemonResponse> {
+    let mut handler = Command::new(crate::get_current_exe()?)
+        .args(&["daemon", "handle"])
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .spawn()
+        .with_context(|| "Failed to run distrod daemon handle.")?;
+    let response = (|| {
+        let mut stdin = handler
+            .stdin
+            .take()
+            .expect("[BUG] the stdin of the handler is piped.");
+        daemon::write_message(&mut stdin, request)?;
+        drop(stdin);
+        let mut stdout = handler
+            .stdout
+            .take()
+            .expect("[BUG] the stdout of the handler is piped.");
+        daemon::read_message::<_, DaemonResponse>(&mut stdout)
+    })();
+    let status = handler
+        .wait()
+        .with_context(|| "Failed to wait for distrod daemon handle.")?;
+    match response? {
+        Some(response) => Ok(response),
+        None => bail!(
+            "distrod daemon handle exited with {} without a response.",
+            status
+        ),
+    }
+}
+
+fn handle_request_from_stdin() -> Result<()> {
+    let request = daemon::read_message::<_, DaemonRequest>(&mut std::io::stdin())?
+        .ok_or_else(|| anyhow!("No request is given on stdin."))?;
+    let response = handle_request(&request).unwrap_or_else(|e| {
+        log::warn!("Failed to handle {:?}. {:?}", &request, &e);
+        daemon::to_error_response(&e)
+    });
+    daemon::write_message(&mut std::io::stdout(), &response)
+}
+
+fn handle_request(request: &DaemonRequest) -> Result<DaemonResponse> {
+    match request {
+        DaemonRequest::Start { name } => {
+            let mut start_opts = distrod_core::StartOptions::new();
+            if let Some(ref name) = name {
+                start_opts.with_name(name);
+            }
+            start_opts.with_distrod_bin(crate::get_current_exe()?);
+            let started =
+                distrod_core::start(&start_opts, &distrod_core::CancellationToken::new())?;
+            Ok(DaemonResponse::Started {
+                name: name.clone(),
+                init_pid: started.init_pid,
+            })
+        }
+        DaemonRequest::Stop {
+            name,
+            sigkill,
+            timeout_sec,
+        } => {
+            distrod_core::stop(&crate::build_stop_options(
+                name.as_deref(),
+                *sigkill,
+                *timeout_sec,
+            )?)?;
+            Ok(DaemonResponse::Stopped { name: name.clone() })
+        }
+        _ => bail!(
+            "[BUG] {:?} is not handled by distrod daemon handle.",
+            request
+        ),
+    }
+}
+
+fn get_distro_states() -> Result<DistroStates> {
+    let mut names = vec![None];
+    names.extend(
+        distro_registry::list_instances()
+            .with_context(|| "Failed to list the distros.")?
+            .into_iter()
+            .map(|instance| Some(instance.name)),
+    );
+    let mut states = DistroStates::new();
+    for name in names {
+        if let Some(distro) = DistroLauncher::get_running_distro_by_name(name.as_deref())
+            .with_context(|| "Failed to get the running distro.")?
+        {
+            states.insert(name, distro.get_init_pid());
+        }
+    }
+    Ok(states)
+}
+
+fn list_distro_states() -> Result<Vec<DaemonDistroState>> {
+    let mut distros = vec![];
+    for instance in
+        distro_registry::list_instances().with_context(|| "Failed to list the distros.")?
+    {
+        let init_pid = DistroLauncher::get_running_distro_by_name(Some(&instance.name))
+            .with_context(|| format!("Failed to get the state of {}.", &instance.name))?
+            .map(|distro| distro.get_init_pid());
+        distros.push(DaemonDistroState {
+            name: instance.name,
+            rootfs: instance.rootfs,
+            init_pid,
+        });
+    }
+    Ok(distros)
+}
+
+fn print_events() -> Result<()> {
+    let mut client = match DaemonClient::connect()? {
+        Some(client) => client,
+        None => bail!("The daemon is not running. Start it by `sudo distrod daemon`."),
+    };
+    client.subscribe()?;
+    while let Some(event) = client.next_event()? {
+        println!("{}", serde_json::to_string(&event)?);
+    }
+    log::info!("The daemon has exited.");
+    Ok(())
+}
@@ -0,0 +1,123 @@
+use anyhow::{Context, Result};
+use indicatif::HumanBytes;
+use libs::container::{ContainerPath, HostPath};
+use libs::disk_usage::get_disk_usage;
+use libs::distro_registry::{self, DistroInstance};
+use libs::rootfs_image;
+use std::os::unix::fs::MetadataExt;
+use std::path::Path;
+use structopt::StructOpt;
+
+use crate::output::{self, OutputFormat, Table, OUTPUT_FORMATS};
+use crate::snapshot;
+
+#[derive(Debug, StructOpt)]
+#[structopt(rename_all = "kebab")]
+pub struct DfOpts {
+    /// Show only the distro of the name.
+    #[structopt(long)]
+    distro: Option<String>,
+
+    /// The output format. Defaults to the one of --output, or table. The sizes are in bytes
+    /// except in the table.
+    #[structopt(long, possible_values = OUTPUT_FORMATS)]
+    format: Option<OutputFormat>,
+}
+
+/// The bytes a distro takes on the disk.
+struct DistroUsage {
+    name: String,
+    /// The rootfs directory, or its disk image if it has one.
+    rootfs: u64,
+    /// /var/cache of the rootfs, such as the packages the package manager downloaded. It's a
+    /// part of `rootfs`.
+    cache: Option<u64>,
+    snapshots: u64,
+}
+
+pub fn show_disk_usage(opts: DfOpts) -> Result<()> {
+    let format = output::resolve_format(opts.format);
+    let instances = match opts.distro {
+        Some(ref name) => vec![DistroInstance::get(name)?],
+        None => distro_registry::list_instances().with_context(|| "Failed to list the distros.")?,
+    };
+    let mut usages = vec![];
+    for instance in &instances {
+        usages.push(
+            get_distro_usage(instance)
+                .with_context(|| format!("Failed to get the disk usage of {}.", &instance.name))?,
+        );
+    }
+
+    let format_bytes = |bytes: u64| {
+        if format.is_human_readable() {
+            HumanBytes(bytes).to_string()
+        } else {
+            bytes.to_string()
+        }
+    };
+    let mut table = Table::new(&["NAME", "ROOTFS", "CACHE", "SNAPSHOTS", "TOTAL"]);
+    for usage in &usages {
+        table.add_row(vec![
+            usage.name.clone(),
+            format_bytes(usage.rootfs),
+            usage.cache.map_or_else(String::new, format_bytes),
+            format_bytes(usage.snapshots),
+            format_bytes(usage.rootfs + usage.snapshots),
+        ]);
+    }
+    // The total row is only for humans, as in `distrod port usage`.
+    if format.is_human_readable() && usages.len() > 1 {
+        let sum = |f: fn(&DistroUsage) -> u64| usages.iter().map(f).sum::<u64>();
+        let (rootfs, snapshots) = (sum(|u| u.rootfs), sum(|u| u.snapshots));
+        table.add_row(vec![
+            "TOTAL".to_owned(),
+            format_bytes(rootfs),
+            format_bytes(sum(|u| u.cache.unwrap_or(0))),
+            format_bytes(snapshots),
+            format_bytes(rootfs + snapshots),
+        ]);
+    }
+    table
+        .align_right(1)
+        .align_right(2)
+        .align_right(3)
+        .align_right(4);
+    table.print(format)
+}
+
+fn get_distro_usage(instance: &DistroInstance) -> Result<DistroUsage> {
+    let image = rootfs_image::get_image_path(&instance.rootfs)?;
+    let rootfs = if image.is_file() {
+        // The blocks allocated to the sparse image, which is what it takes from the disk.
+        get_allocated_bytes(&image)?
+    } else {
+        get_disk_usage(&instance.rootfs)?
+    };
+    // The cache of a disk image can be seen only while it's mounted.
+    let cache_dir =
+        ContainerPath::new("/var/cache")?.to_host_path(&HostPath::new(&instance.rootfs)?);
+    let cache = if cache_dir.is_dir() {
+        Some(get_disk_usage(cache_dir.as_path())?)
+    } else {
+        None
+    };
+    let snapshots_dir = snapshot::get_snapshots_dir(instance)?;
+    let snapshots = if snapshots_dir.is_dir() {
+        get_disk_usage(&snapshots_dir)?
+    } else {
+        0
+    };
+    Ok(DistroUsage {
+        name: instance.name.clone(),
+        rootfs,
+        cache,
+        snapshots,
+    })
+}
+
+fn get_allocated_bytes(path: &Path) -> Result<u64> {
+    let metadata =
+        std::fs::metadata(path).with_context(|| format!("Failed to stat {:?}.", path))?;
+    Ok(metadata.blocks() * 512)
+}
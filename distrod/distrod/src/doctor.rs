@@ -0,0 +1,232 @@
+use anyhow::{bail, Context, Result};
+use libs::distro::{self, Distro, DistroLauncher, WSL_GPU_DEVICE_PATH, WSL_GPU_LIB_DIR_PATH};
+use std::path::Path;
+use structopt::StructOpt;
+
+use crate::output::{self, OutputFormat, Table};
+
+/// The libraries WSL puts in /usr/lib/wsl/lib for CUDA and DirectML.
+const WSL_GPU_LIBS: &[&str] = &["libcuda.so.1", "libd3d12.so", "libdxcore.so"];
+
+#[derive(Debug, StructOpt)]
+#[structopt(rename_all = "kebab")]
+pub struct DoctorOpts {
+    /// Check whether CUDA and DirectML can use the GPU in the distro.
+    #[structopt(long)]
+    gpu: bool,
+
+    #[structopt(long)]
+    distro: Option<String>,
+
+    /// The output format. Defaults to the one of --output, or table.
+    #[structopt(long, possible_values = output::OUTPUT_FORMATS)]
+    format: Option<OutputFormat>,
+}
+
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub enum CheckResult {
+    Ok,
+    Failed,
+    Skipped,
+}
+
+impl CheckResult {
+    pub fn from_bool(ok: bool) -> Self {
+        if ok {
+            CheckResult::Ok
+        } else {
+            CheckResult::Failed
+        }
+    }
+
+    fn name(&self) -> &'static str {
+        match self {
+            CheckResult::Ok => "ok",
+            CheckResult::Failed => "failed",
+            CheckResult::Skipped => "skipped",
+        }
+    }
+}
+
+pub struct Check {
+    pub name: &'static str,
+    pub result: CheckResult,
+    pub detail: String,
+    /// What the user can do to fix the failure.
+    pub remedy: Option<String>,
+}
+
+/// Runs the checks and prints the results. Exits with 1 if any of them fails.
+pub fn run_doctor(opts: DoctorOpts) -> Result<()> {
+    let mut checks = vec![];
+    if opts.gpu {
+        checks.extend(check_gpu(opts.distro.as_deref())?);
+    }
+    if checks.is_empty() {
+        bail!("Specify what to check, such as --gpu.");
+    }
+    print_checks(&checks, output::resolve_format(opts.format))
+}
+
+/// Prints the results, and the remedies of the failures for humans. Exits with 1 if any of
+/// them fails.
+pub fn print_checks(checks: &[Check], format: OutputFormat) -> Result<()> {
+    let mut table = Table::new(&["CHECK", "RESULT", "DETAIL"]);
+    for check in checks {
+        table.add_row(vec![
+            check.name.to_owned(),
+            check.result.name().to_owned(),
+            check.detail.clone(),
+        ]);
+    }
+    table.print(format)?;
+    let failures: Vec<&Check> = checks
+        .iter()
+        .filter(|check| check.result == CheckResult::Failed)
+        .collect();
+    if failures.is_empty() {
+        return Ok(());
+    }
+    if format.is_human_readable() {
+        println!();
+        println!("To fix the failures:");
+        for (i, check) in failures.iter().enumerate() {
+            if let Some(ref remedy) = check.remedy {
+                println!("  {}. {}: {}", i + 1, check.name, remedy);
+            }
+        }
+    }
+    std::process::exit(1);
+}
+
+pub fn check_gpu(name: Option<&str>) -> Result<Vec<Check>> {
+    let mut checks = vec![];
+    let has_device = Path::new(WSL_GPU_DEVICE_PATH).exists();
+    checks.push(Check {
+        name: "GPU device on WSL",
+        result: CheckResult::from_bool(has_device),
+        detail: if has_device {
+            WSL_GPU_DEVICE_PATH.to_owned()
+        } else {
+            format!("{} doesn't exist.", WSL_GPU_DEVICE_PATH)
+        },
+        remedy: Some(
+            "The GPU needs WSL2 and a GPU driver of Windows which supports WSL. Install the latest \
+             driver from the vendor of the GPU, and run `wsl --update` on Windows."
+                .to_owned(),
+        ),
+    });
+    let host_libs = find_gpu_libs(Path::new(WSL_GPU_LIB_DIR_PATH));
+    checks.push(Check {
+        name: "GPU libraries on WSL",
+        result: CheckResult::from_bool(!host_libs.is_empty()),
+        detail: describe_gpu_libs(&host_libs),
+        remedy: Some(
+            "WSL puts the libraries there from the GPU driver of Windows. Update the driver."
+                .to_owned(),
+        ),
+    });
+
+    let rootfs = distro::get_distro_rootfs(name)?;
+    let passthrough = distro::get_distro_config(&rootfs)
+        .with_context(|| "Failed to read the config of the distro.")?
+        .gpu
+        .passthrough;
+    checks.push(Check {
+        name: "GPU passthrough",
+        result: CheckResult::from_bool(passthrough),
+        detail: if passthrough {
+            "enabled".to_owned()
+        } else {
+            "disabled by passthrough of [gpu] in /etc/distrod/distrod.toml".to_owned()
+        },
+        remedy: Some(
+            "Run `distrod config set gpu.passthrough true`, or `distrod gpu setup`, and restart \
+             the distro."
+                .to_owned(),
+        ),
+    });
+
+    let distro = DistroLauncher::get_running_distro_by_name(name)
+        .with_context(|| "Failed to get the running distro.")?;
+    match distro {
+        Some(distro) => checks.extend(check_gpu_in_distro(&distro)?),
+        None => {
+            for name in &[
+                "GPU device in the distro",
+                "GPU libraries in the distro",
+                "Dynamic linker",
+            ] {
+                checks.push(Check {
+                    name: *name,
+                    result: CheckResult::Skipped,
+                    detail: "The distro is not running.".to_owned(),
+                    remedy: None,
+                });
+            }
+        }
+    }
+    Ok(checks)
+}
+
+fn check_gpu_in_distro(distro: &Distro) -> Result<Vec<Check>> {
+    let mut checks = vec![];
+    let (exit_code, _) = distro.exec_command_output("test", &["-c", WSL_GPU_DEVICE_PATH])?;
+    checks.push(Check {
+        name: "GPU device in the distro",
+        result: CheckResult::from_bool(exit_code == 0),
+        detail: if exit_code == 0 {
+            WSL_GPU_DEVICE_PATH.to_owned()
+        } else {
+            format!("{} is not in the distro.", WSL_GPU_DEVICE_PATH)
+        },
+        remedy: Some("Restart the distro after enabling the GPU passthrough.".to_owned()),
+    });
+
+    // The directory in the rootfs on the disk is only a mountpoint, so look at it from inside.
+    let (_, output) = distro.exec_command_output("ls", &["-1", WSL_GPU_LIB_DIR_PATH])?;
+    let distro_libs: Vec<String> = output
+        .lines()
+        .filter(|file| WSL_GPU_LIBS.contains(file))
+        .map(|file| file.to_owned())
+        .collect();
+    checks.push(Check {
+        name: "GPU libraries in the distro",
+        result: CheckResult::from_bool(!distro_libs.is_empty()),
+        detail: describe_gpu_libs(&distro_libs),
+        remedy: Some("Restart the distro after enabling the GPU passthrough.".to_owned()),
+    });
+
+    let (exit_code, output) = distro.exec_command_output("/sbin/ldconfig", &["-p"])?;
+    let linked = exit_code == 0
+        && output
+            .lines()
+            .any(|line| line.contains(&format!("{}/", WSL_GPU_LIB_DIR_PATH)));
+    checks.push(Check {
+        name: "Dynamic linker",
+        result: CheckResult::from_bool(linked),
+        detail: if linked {
+            format!("{} is in ld.so.cache.", WSL_GPU_LIB_DIR_PATH)
+        } else {
+            format!("{} is not in ld.so.cache.", WSL_GPU_LIB_DIR_PATH)
+        },
+        remedy: Some("Run `distrod gpu setup`, or restart the distro.".to_owned()),
+    });
+    Ok(checks)
+}
+
+fn find_gpu_libs(dir: &Path) -> Vec<String> {
+    WSL_GPU_LIBS
+        .iter()
+        .filter(|lib| dir.join(lib).exists())
+        .map(|lib| lib.to_string())
+        .collect()
+}
+
+fn describe_gpu_libs(libs: &[String]) -> String {
+    if libs.is_empty() {
+        format!("No GPU libraries in {}.", WSL_GPU_LIB_DIR_PATH)
+    } else {
+        libs.join(", ")
+    }
+}
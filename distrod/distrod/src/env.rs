@@ -0,0 +1,105 @@
+use anyhow::{Context, Result};
+use structopt::StructOpt;
+
+use libs::wsl_interop;
+use libs::wslenv::{self, WslenvEntry};
+
+use crate::output::{self, OutputFormat, Table, OUTPUT_FORMATS};
+
+#[derive(Debug, StructOpt)]
+pub enum EnvOpts {
+    /// Pass an environment variable between Windows and the distro by adding NAME[/FLAGS] to WSLENV of the Windows user.
+    Share(EnvShareOpts),
+    /// Stop passing an environment variable by removing it from WSLENV of the Windows user.
+    Unshare(EnvUnshareOpts),
+    /// List the variables in WSLENV of the Windows user and their values in this session.
+    List(EnvListOpts),
+}
+
+#[derive(Debug, StructOpt)]
+#[structopt(rename_all = "kebab")]
+pub struct EnvShareOpts {
+    /// The name and the flags of WSLENV, such as GOPATH/l. p translates a path, l translates a
+    /// list of paths, u passes it only from Windows, and w passes it only to Windows.
+    variable: String,
+}
+
+#[derive(Debug, StructOpt)]
+#[structopt(rename_all = "kebab")]
+pub struct EnvUnshareOpts {
+    name: String,
+}
+
+#[derive(Debug, StructOpt)]
+#[structopt(rename_all = "kebab")]
+pub struct EnvListOpts {
+    /// The output format. Defaults to the one of --output, or table.
+    #[structopt(long, possible_values = OUTPUT_FORMATS)]
+    format: Option<OutputFormat>,
+}
+
+pub fn run_env_command(opts: EnvOpts) -> Result<()> {
+    match opts {
+        EnvOpts::Share(share_opts) => {
+            let entry = WslenvEntry::parse(&share_opts.variable)?;
+            let mut entries = read_wslenv()?;
+            let description = entry.to_string();
+            if !wslenv::share(&mut entries, entry) {
+                log::info!("{} is already in WSLENV.", description);
+                return Ok(());
+            }
+            write_wslenv(&entries)?;
+            log::info!("Added {} to WSLENV.", description);
+            log_restart_needed();
+        }
+        EnvOpts::Unshare(unshare_opts) => {
+            let mut entries = read_wslenv()?;
+            if !wslenv::unshare(&mut entries, &unshare_opts.name) {
+                log::info!("{} is not in WSLENV.", &unshare_opts.name);
+                return Ok(());
+            }
+            write_wslenv(&entries)?;
+            log::info!("Removed {} from WSLENV.", &unshare_opts.name);
+            log_restart_needed();
+        }
+        EnvOpts::List(list_opts) => {
+            let mut table = Table::new(&["NAME", "FLAGS", "DESCRIPTION", "VALUE"]);
+            for entry in read_wslenv()? {
+                let value = std::env::var(&entry.name).unwrap_or_default();
+                table.add_row(vec![
+                    entry.name.clone(),
+                    entry.flags.clone(),
+                    entry.describe_flags(),
+                    value,
+                ]);
+            }
+            table.print(output::resolve_format(list_opts.format))?;
+        }
+    }
+    Ok(())
+}
+
+fn read_wslenv() -> Result<Vec<WslenvEntry>> {
+    let value = wsl_interop::get_windows_user_env_var("WSLENV")
+        .with_context(|| "Failed to read WSLENV of the Windows user.")?;
+    Ok(wslenv::parse_wslenv(&value.unwrap_or_default()))
+}
+
+fn write_wslenv(entries: &[WslenvEntry]) -> Result<()> {
+    let value = wslenv::format_wslenv(entries);
+    let value = if value.is_empty() {
+        None
+    } else {
+        Some(value.as_str())
+    };
+    wsl_interop::set_windows_user_env_var("WSLENV", value)
+        .with_context(|| "Failed to write WSLENV of the Windows user.")
+}
+
+fn log_restart_needed() {
+    // The running terminals keep the old environment of the Windows user.
+    log::info!(
+        "Open a new terminal on Windows to apply it. The sessions not from Windows, such as \
+         ssh, get the shared variables on the next start of the distro."
+    );
+}
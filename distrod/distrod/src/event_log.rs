@@ -0,0 +1,87 @@
+use anyhow::{anyhow, bail, Context, Result};
+use std::io::{BufRead, BufReader, Write};
+use std::process::{Command, Stdio};
+use structopt::StructOpt;
+
+use libs::distrod_config::{self, DistrodConfig};
+use libs::event_log::EventLogEntry;
+use libs::wsl_interop;
+
+#[derive(Debug, StructOpt)]
+#[structopt(rename_all = "kebab")]
+pub struct EventLogOpts {
+    /// Print the entries instead of writing them to the Windows Event Log.
+    #[structopt(long)]
+    dry_run: bool,
+}
+
+/// Follows the journal and writes the entries of [event_log] in distrod.toml to the Windows
+/// Event Log by portproxy.exe. This returns only when journalctl or portproxy.exe exits.
+pub fn run_event_log(opts: EventLogOpts) -> Result<()> {
+    let config = DistrodConfig::get().with_context(|| "Failed to get the Distrod config.")?;
+    let config = config.event_log.clone();
+    if config.max_priority > 7 {
+        bail!(
+            "max_priority of [event_log] must be from 0 to 7, but it's {}.",
+            config.max_priority
+        );
+    }
+    let distro_name = wsl_interop::get_distro_name().unwrap_or_else(|_| "WSL".to_owned());
+
+    let mut journalctl = Command::new("journalctl");
+    journalctl
+        .args(&["--follow", "--lines=0", "--output=json"])
+        .arg(format!("--priority={}", config.max_priority));
+    for unit in &config.units {
+        journalctl.arg(format!("--unit={}", unit));
+    }
+    let mut journalctl = journalctl
+        .stdout(Stdio::piped())
+        .spawn()
+        .with_context(|| "Failed to run journalctl.")?;
+    let journal = BufReader::new(
+        journalctl
+            .stdout
+            .take()
+            .expect("[BUG] the stdout of journalctl is piped."),
+    );
+
+    let mut portproxy = if opts.dry_run {
+        None
+    } else {
+        Some(
+            Command::new(format!(
+                "{}/portproxy.exe",
+                distrod_config::get_distrod_bin_dir_path()
+            ))
+            .args(&["event-log", "--source", &config.source])
+            .stdin(Stdio::piped())
+            .spawn()
+            .with_context(|| "Failed to run portproxy.exe.")?,
+        )
+    };
+    log::info!(
+        "Forwarding the journal entries of priority {} or higher to the Windows Event Log.",
+        config.max_priority
+    );
+    for line in journal.lines() {
+        let line = line.with_context(|| "Failed to read the output of journalctl.")?;
+        let entry = match EventLogEntry::from_journal_json(&line, &distro_name) {
+            Some(entry) => entry,
+            None => continue,
+        };
+        match portproxy {
+            Some(ref mut portproxy) => portproxy
+                .stdin
+                .as_mut()
+                .expect("[BUG] the stdin of portproxy.exe is piped.")
+                .write_all(entry.to_json_line()?.as_bytes())
+                .with_context(|| "Failed to pass the entry to portproxy.exe.")?,
+            None => println!("{:?}: {}", entry.level, entry.description()),
+        }
+    }
+    let status = journalctl
+        .wait()
+        .with_context(|| "Failed to wait for journalctl.")?;
+    Err(anyhow!("journalctl exited with {}.", status))
+}
@@ -0,0 +1,85 @@
+use anyhow::{Context, Result};
+use libs::envfile::{read_env_arg_file, EnvArg};
+use std::ffi::OsString;
+use std::path::PathBuf;
+use structopt::StructOpt;
+
+/// The PATH of the command when it starts from a clean environment without PATH given.
+const CLEAN_ENV_PATH: &str = "/usr/local/sbin:/usr/local/bin:/usr/sbin:/usr/bin:/sbin:/bin";
+
+#[derive(Clone, Debug, Default, StructOpt)]
+#[structopt(rename_all = "kebab")]
+pub struct ExecEnvOpts {
+    /// Set an environment variable by KEY=VALUE, or pass the variable of the caller by KEY.
+    /// Applied after --env-file.
+    #[structopt(short, long, number_of_values = 1)]
+    env: Vec<String>,
+
+    /// Read environment variables from a file of KEY=VALUE or KEY lines.
+    #[structopt(long, number_of_values = 1)]
+    env_file: Vec<PathBuf>,
+
+    /// Start from an empty environment instead of the environment of the caller.
+    /// Only PATH is set to the default unless it's given.
+    #[structopt(long)]
+    clean_env: bool,
+}
+
+impl ExecEnvOpts {
+    pub fn is_clean_env(&self) -> bool {
+        self.clean_env
+    }
+
+    /// Returns the variables given by --env-file and --env in the order they are applied.
+    /// Passed-through variables which the caller doesn't have are skipped.
+    pub fn get_assignments(&self) -> Result<Vec<(OsString, OsString)>> {
+        let mut args = vec![];
+        for path in &self.env_file {
+            args.extend(read_env_arg_file(path)?);
+        }
+        for env in &self.env {
+            args.push(EnvArg::parse(env).with_context(|| format!("Invalid --env '{}'.", env))?);
+        }
+        Ok(args
+            .into_iter()
+            .filter_map(|arg| match arg {
+                EnvArg::Set(key, value) => Some((key.into(), value.into())),
+                EnvArg::PassThrough(key) => {
+                    std::env::var_os(&key).map(|value| (OsString::from(key), value))
+                }
+            })
+            .collect())
+    }
+
+    /// Returns the whole environment of the command.
+    pub fn build_envs(&self) -> Result<Vec<(OsString, OsString)>> {
+        let mut envs: Vec<(OsString, OsString)> = if self.clean_env {
+            vec![("PATH".into(), CLEAN_ENV_PATH.into())]
+        } else {
+            std::env::vars_os().collect()
+        };
+        for (key, value) in self.get_assignments()? {
+            envs.retain(|(existing, _)| existing != &key);
+            envs.push((key, value));
+        }
+        Ok(envs)
+    }
+
+    /// Replaces the environment of this process with the one of the command, so that the command
+    /// inherits it. Call this right before spawning the command.
+    pub fn apply_to_current_process(&self) -> Result<()> {
+        if !self.clean_env && self.env.is_empty() && self.env_file.is_empty() {
+            return Ok(());
+        }
+        let envs = self.build_envs()?;
+        for (key, _) in std::env::vars_os() {
+            if !envs.iter().any(|(k, _)| k == &key) {
+                std::env::remove_var(key);
+            }
+        }
+        for (key, value) in envs {
+            std::env::set_var(key, value);
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,245 @@
+use anyhow::{Context, Result};
+use libs::container::{ContainerPath, HostPath};
+use libs::cuda_version::{
+    self, parse_cuda_toolkit_version, parse_nvidia_smi_cuda_version, CudaCompatibility, CudaVersion,
+};
+use libs::distro::{self, Distro, DistroLauncher, WSL_GPU_LIB_DIR_PATH};
+use libs::rootfs_dir;
+use std::fs;
+use std::process::Command;
+use structopt::StructOpt;
+
+use crate::doctor::{self, Check, CheckResult};
+use crate::output::{self, OutputFormat, OUTPUT_FORMATS};
+
+/// The toolkit `distrod gpu setup` checks, which NVIDIA installs in /usr/local/cuda.
+const CUDA_TOOLKIT_DIR_PATH: &str = "/usr/local/cuda";
+const ROCM_VERSION_FILE_PATH: &str = "/opt/rocm/.info/version";
+
+#[derive(Debug, StructOpt)]
+pub enum GpuOpts {
+    /// Set up the distro for CUDA and ROCm by the GPU of WSL, check the versions of the driver and the libraries, and print how to fix the problems.
+    Setup(GpuSetupOpts),
+}
+
+#[derive(Debug, StructOpt)]
+#[structopt(rename_all = "kebab")]
+pub struct GpuSetupOpts {
+    #[structopt(long)]
+    distro: Option<String>,
+
+    /// The output format of the checks. Defaults to the one of --output, or table.
+    #[structopt(long, possible_values = OUTPUT_FORMATS)]
+    format: Option<OutputFormat>,
+}
+
+pub fn run_gpu_command(opts: GpuOpts) -> Result<()> {
+    match opts {
+        GpuOpts::Setup(setup_opts) => set_up_gpu(setup_opts),
+    }
+}
+
+/// Enables the passthrough and the library path of the GPU drivers, and then runs the checks
+/// of `distrod doctor --gpu` with the ones of the versions.
+fn set_up_gpu(opts: GpuSetupOpts) -> Result<()> {
+    let name = opts.distro.as_deref();
+    let rootfs = distro::get_distro_rootfs(name)?;
+    let running_distro = DistroLauncher::get_running_distro_by_name(name)
+        .with_context(|| "Failed to get the running distro.")?;
+
+    let mut config = distro::get_distro_config(&rootfs)?;
+    if !config.gpu.passthrough {
+        config.gpu.passthrough = true;
+        distro::set_distro_config(&rootfs, &config)
+            .with_context(|| "Failed to save the config of the distro.")?;
+        log::info!("Enabled the GPU passthrough. It takes effect from the next start.");
+    }
+    if distro::write_wsl_gpu_ld_conf(&rootfs)? {
+        if let Some(ref distro) = running_distro {
+            distro
+                .update_ld_cache()
+                .with_context(|| "Failed to update ld.so.cache of the distro.")?;
+        }
+    } else {
+        log::warn!(
+            "The distro doesn't have /etc/ld.so.conf.d. The drivers in {} need a glibc based distro.",
+            WSL_GPU_LIB_DIR_PATH
+        );
+    }
+
+    let mut checks = doctor::check_gpu(name)?;
+    checks.extend(check_cuda(&rootfs, running_distro.as_ref())?);
+    checks.push(check_rocm(&rootfs)?);
+    doctor::print_checks(&checks, output::resolve_format(opts.format))
+}
+
+fn check_cuda(rootfs: &HostPath, distro: Option<&Distro>) -> Result<Vec<Check>> {
+    let mut checks = vec![];
+    let driver = get_driver_cuda_version();
+    checks.push(Check {
+        name: "CUDA driver",
+        result: if driver.is_some() {
+            CheckResult::Ok
+        } else {
+            CheckResult::Skipped
+        },
+        detail: match driver {
+            Some(version) => format!("supports CUDA {} or older", version),
+            None => "No NVIDIA GPU, or nvidia-smi of WSL failed.".to_owned(),
+        },
+        remedy: None,
+    });
+
+    let toolkit = get_cuda_toolkit_version(rootfs)?;
+    let (result, detail, remedy) = match (driver, toolkit) {
+        (_, None) => (
+            CheckResult::Skipped,
+            format!("No CUDA toolkit in {}.", CUDA_TOOLKIT_DIR_PATH),
+            None,
+        ),
+        (None, Some(toolkit)) => (
+            CheckResult::Skipped,
+            format!("CUDA {}, but no CUDA driver.", toolkit),
+            None,
+        ),
+        (Some(driver), Some(toolkit)) => {
+            match cuda_version::get_cuda_compatibility(driver, toolkit) {
+                CudaCompatibility::Full => (CheckResult::Ok, format!("CUDA {}", toolkit), None),
+                CudaCompatibility::MinorVersion => (
+                    CheckResult::Ok,
+                    format!(
+                        "CUDA {} runs by the minor version compatibility with the driver of CUDA {}, \
+                         without the features of CUDA {} such as compiling PTX at runtime.",
+                        toolkit, driver, toolkit
+                    ),
+                    None,
+                ),
+                CudaCompatibility::Incompatible => (
+                    CheckResult::Failed,
+                    format!(
+                        "CUDA {} doesn't run on the driver of CUDA {}.",
+                        toolkit, driver
+                    ),
+                    Some(format!(
+                        "Update the NVIDIA driver of Windows to one for CUDA {}, or install the \
+                         toolkit of CUDA {} for WSL.",
+                        toolkit, driver
+                    )),
+                ),
+            }
+        }
+    };
+    checks.push(Check {
+        name: "CUDA toolkit",
+        result,
+        detail,
+        remedy,
+    });
+
+    checks.push(match distro {
+        Some(distro) => check_libcuda_shadowing(distro)?,
+        None => Check {
+            name: "libcuda of WSL",
+            result: CheckResult::Skipped,
+            detail: "The distro is not running.".to_owned(),
+            remedy: None,
+        },
+    });
+    Ok(checks)
+}
+
+/// Runs nvidia-smi of WSL, which comes with the driver of Windows.
+fn get_driver_cuda_version() -> Option<CudaVersion> {
+    let output = Command::new(format!("{}/nvidia-smi", WSL_GPU_LIB_DIR_PATH))
+        .output()
+        .ok()?;
+    if !output.status.success() {
+        return None;
+    }
+    parse_nvidia_smi_cuda_version(&String::from_utf8_lossy(&output.stdout))
+}
+
+fn get_cuda_toolkit_version(rootfs: &HostPath) -> Result<Option<CudaVersion>> {
+    let read = |file: &str| -> Result<Option<String>> {
+        // /usr/local/cuda is usually a symlink, whose target is in the rootfs.
+        let path = rootfs_dir::resolve_path(
+            rootfs,
+            &ContainerPath::new(format!("{}/{}", CUDA_TOOLKIT_DIR_PATH, file))?,
+        )?;
+        if !path.exists() {
+            return Ok(None);
+        }
+        Ok(Some(
+            fs::read_to_string(path.as_path())
+                .with_context(|| format!("Failed to read {:?}.", &path))?,
+        ))
+    };
+    let version_json = read("version.json")?;
+    let version_txt = read("version.txt")?;
+    Ok(parse_cuda_toolkit_version(
+        version_json.as_deref(),
+        version_txt.as_deref(),
+    ))
+}
+
+/// The NVIDIA driver packages of Linux install their own libcuda.so.1, which can't use the GPU
+/// of WSL but hides the one of WSL from CUDA if the dynamic linker finds it first.
+fn check_libcuda_shadowing(distro: &Distro) -> Result<Check> {
+    let (exit_code, output) = distro.exec_command_output("/sbin/ldconfig", &["-p"])?;
+    let libcudas: Vec<&str> = if exit_code == 0 {
+        output
+            .lines()
+            .filter(|line| line.trim_start().starts_with("libcuda.so"))
+            .filter_map(|line| line.rsplit("=> ").next())
+            .collect()
+    } else {
+        vec![]
+    };
+    let others: Vec<&str> = libcudas
+        .iter()
+        .copied()
+        .filter(|path| !path.starts_with(&format!("{}/", WSL_GPU_LIB_DIR_PATH)))
+        .collect();
+    Ok(Check {
+        name: "libcuda of WSL",
+        result: CheckResult::from_bool(others.is_empty()),
+        detail: if others.is_empty() {
+            "No other libcuda hides the one of WSL.".to_owned()
+        } else {
+            format!("{} hide the one of WSL.", others.join(", "))
+        },
+        remedy: Some(
+            "Uninstall the NVIDIA driver packages of Linux, such as libnvidia-compute or \
+             cuda-drivers, and install the toolkit only, such as cuda-toolkit of the WSL-Ubuntu \
+             repository of NVIDIA."
+                .to_owned(),
+        ),
+    })
+}
+
+fn check_rocm(rootfs: &HostPath) -> Result<Check> {
+    let version_path =
+        rootfs_dir::resolve_path(rootfs, &ContainerPath::new(ROCM_VERSION_FILE_PATH)?)?;
+    if !version_path.exists() {
+        return Ok(Check {
+            name: "ROCm",
+            result: CheckResult::Skipped,
+            detail: "ROCm is not installed.".to_owned(),
+            remedy: None,
+        });
+    }
+    let version = fs::read_to_string(version_path.as_path())
+        .with_context(|| format!("Failed to read {:?}.", &version_path))?;
+    // The build of ROCm for Linux doesn't know /dev/dxg, but can't be told from the one for WSL
+    // by its files, so only the way to install it is shown.
+    Ok(Check {
+        name: "ROCm",
+        result: CheckResult::Ok,
+        detail: format!(
+            "ROCm {}. It needs the build for WSL, which `amdgpu-install --usecase=wsl,rocm \
+             --no-dkms` installs.",
+            version.trim()
+        ),
+        remedy: None,
+    })
+}
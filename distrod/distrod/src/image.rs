@@ -0,0 +1,42 @@
+use anyhow::{Context, Result};
+use structopt::StructOpt;
+
+use libs::container_org_image::{get_container_org_image_url, list_container_org_rootfs_images};
+
+use crate::output::{self, OutputFormat, Table, OUTPUT_FORMATS};
+
+#[derive(Debug, StructOpt)]
+pub enum ImageOpts {
+    /// List the latest images of linuxcontainers.org for the architecture of this machine.
+    List(ImageListOpts),
+}
+
+#[derive(Debug, StructOpt)]
+#[structopt(rename_all = "kebab")]
+pub struct ImageListOpts {
+    /// The output format. Defaults to the one of --output, or table.
+    #[structopt(long, possible_values = OUTPUT_FORMATS)]
+    format: Option<OutputFormat>,
+}
+
+pub fn run_image_command(opts: ImageOpts) -> Result<()> {
+    match opts {
+        ImageOpts::List(list_opts) => list_images(list_opts),
+    }
+}
+
+#[tokio::main]
+async fn list_images(opts: ImageListOpts) -> Result<()> {
+    let images = list_container_org_rootfs_images()
+        .await
+        .with_context(|| "Failed to list the images of linuxcontainers.org.")?;
+    let mut table = Table::new(&["DISTRO", "RELEASE", "URL"]);
+    for image in &images {
+        table.add_row(vec![
+            image.distro.clone(),
+            image.release.clone(),
+            get_container_org_image_url(image),
+        ]);
+    }
+    table.print(output::resolve_format(opts.format))
+}
@@ -0,0 +1,110 @@
+use anyhow::{bail, Context, Result};
+use libs::container::ContainerPath;
+use libs::distro::{self, DistroLauncher};
+use libs::multifork::set_noninheritable_sig_ign;
+use std::ffi::OsString;
+use std::process::Command;
+use structopt::StructOpt;
+
+const JOURNAL_DIR_PATH: &str = "/var/log/journal";
+
+#[derive(Debug, StructOpt)]
+#[structopt(rename_all = "kebab")]
+pub struct LogsOpts {
+    /// Show only the logs of the unit, such as ssh.service. Can be given multiple times.
+    #[structopt(short, long)]
+    unit: Vec<String>,
+
+    /// Show the logs of a boot, such as 0 for the current boot and -1 for the previous one.
+    #[structopt(short, long)]
+    boot: Option<String>,
+
+    /// Keep showing new logs until interrupted. This requires the distro to be running.
+    #[structopt(short, long)]
+    follow: bool,
+
+    /// Show only the last N lines.
+    #[structopt(short = "n", long)]
+    lines: Option<u32>,
+
+    /// Show the logs since the time, such as "2021-10-01 12:00" or "1 hour ago".
+    #[structopt(long)]
+    since: Option<String>,
+
+    /// The output format of journalctl. The default one has unambiguous timestamps which
+    /// can be compared with the Event Viewer of Windows.
+    #[structopt(short, long, default_value = "short-iso")]
+    output: String,
+
+    #[structopt(long)]
+    distro: Option<String>,
+}
+
+/// Shows the journal of the distro by journalctl in the distro. When the distro is not running,
+/// the journal files left in the rootfs are read by journalctl of this WSL distro instead.
+pub fn show_logs(opts: LogsOpts) -> Result<()> {
+    let args = build_journalctl_args(&opts);
+    let running_distro = DistroLauncher::get_running_distro_by_name(opts.distro.as_deref())
+        .with_context(|| "Failed to get the running distro.")?;
+    let status = match running_distro {
+        Some(distro) => {
+            // Let Ctrl-C in the follow mode stop only journalctl.
+            set_noninheritable_sig_ign();
+            let mut waiter =
+                distro.exec_command("journalctl", &args, None::<&str>, None::<&str>, None)?;
+            waiter.wait()
+        }
+        None => show_logs_of_stopped_distro(&opts, args)?,
+    };
+    if status != 0 {
+        std::process::exit(status as i32);
+    }
+    Ok(())
+}
+
+fn show_logs_of_stopped_distro(opts: &LogsOpts, mut args: Vec<OsString>) -> Result<u32> {
+    if opts.follow {
+        bail!("The distro is not running. --follow is available only while it's running.");
+    }
+    let rootfs = distro::get_distro_rootfs(opts.distro.as_deref())?;
+    let journal_dir = ContainerPath::new(JOURNAL_DIR_PATH)?.to_host_path(&rootfs);
+    if !journal_dir.exists() {
+        bail!(
+            "The distro is not running, and it has no persistent journal at {}.",
+            JOURNAL_DIR_PATH
+        );
+    }
+    args.insert(0, OsString::from("--directory"));
+    args.insert(1, journal_dir.as_os_str().to_owned());
+    let status = Command::new("journalctl")
+        .args(&args)
+        .status()
+        .with_context(|| {
+            "Failed to run journalctl. Start the distro to read the logs by journalctl in it."
+        })?;
+    Ok(status.code().unwrap_or(1) as u32)
+}
+
+fn build_journalctl_args(opts: &LogsOpts) -> Vec<OsString> {
+    // The pager doesn't work well when the output goes through wsl.exe.
+    let mut args: Vec<OsString> = vec![
+        "--no-pager".into(),
+        format!("--output={}", &opts.output).into(),
+    ];
+    for unit in &opts.unit {
+        args.push(format!("--unit={}", unit).into());
+    }
+    if let Some(ref boot) = opts.boot {
+        args.push(format!("--boot={}", boot).into());
+    }
+    if opts.follow {
+        args.push("--follow".into());
+    }
+    if let Some(lines) = opts.lines {
+        args.push(format!("--lines={}", lines).into());
+    }
+    if let Some(ref since) = opts.since {
+        args.push(format!("--since={}", since).into());
+    }
+    args
+}
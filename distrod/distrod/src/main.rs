@@ -1,37 +1,98 @@
 use anyhow::{anyhow, bail, Context, Result};
-use libs::cli_ui::{build_progress_bar, choose_from_list, init_logger, prompt_path};
+use libs::cancellation;
+use libs::cgroup::parse_cpus;
+use libs::cli_ui::{
+    self, choose_from_list, init_logger, prompt_path, LogFormat, LoggerInitializer, LOG_FORMATS,
+};
 use libs::container::{ContainerPath, HostPath};
+use libs::daemon::{DaemonClient, DaemonRequest};
 use libs::distrod_config::{self, DistrodConfig};
+use libs::error::{self, DistroError};
+use libs::etc_guard::EtcGuard;
+use libs::exec_broker::{self, ExecBroker, ExecRequest};
+use libs::image_fetcher_plugin;
 use libs::local_image::LocalDistroImage;
-use libs::multifork::set_noninheritable_sig_ign;
+use libs::multifork::{set_noninheritable_sig_ign, Waiter};
+use libs::resolved;
+use libs::seccomp::SeccompProfile;
+use libs::userns;
 use nix::unistd::{Gid, Uid};
 use std::ffi::{CString, OsString};
-use std::fs::File;
-use std::io::{stdin, Cursor, Read};
+use std::io::stdin;
 use std::os::unix::prelude::OsStrExt;
-use std::path::Path;
+use std::path::PathBuf;
+use std::time::Duration;
 use structopt::StructOpt;
-use xz2::read::XzDecoder;
 
+use distrod_core::{CancellationToken, CreateOptions, ImageSource, StartOptions, StopOptions};
 use libs::command_alias::CommandAlias;
 use libs::container_org_image::ContainerOrgImageList;
 use libs::distro::{self, DistroLauncher};
-use libs::distro_image::{
-    self, download_file_with_progress, DistroImage, DistroImageFetcher, DistroImageFetcherGen,
-    DistroImageFile,
-};
+use libs::distro_config::{parse_memory_size, validate_hostname};
+use libs::distro_image::{self, DistroImageFetcher, DistroImageFetcherGen, DistroImageFile};
+use libs::distro_registry;
 use libs::passwd::{self, get_credential_from_passwd_file, Credential};
+use libs::provision::ProvisionSpec;
+use libs::pty::Pty;
 use libs::wsl_interop;
 
+mod alias;
+mod auto_update;
 mod autostart;
+mod compact;
+mod completion;
+mod compose;
+mod config;
+mod create_user;
+mod daemon;
+mod df;
+mod doctor;
+mod env;
+mod event_log;
+mod exec_env;
+mod gpu;
+mod image;
+mod logs;
+mod mdns;
+mod migrate;
+mod monitor;
+mod output;
+mod port;
+mod ps;
+mod relay;
+mod run;
+mod self_update;
+mod shell;
 mod shell_hook;
+mod snapshot;
+mod status;
+mod target;
+mod time_sync;
+mod uninstall;
+mod unit;
+mod wizard;
+mod wslconf;
 
 #[derive(Debug, StructOpt)]
 #[structopt(name = "distrod")]
 pub struct Opts {
     /// Log level in the env_logger format. Simple levels: trace, debug, info(default), warn, error.
+    /// The level of each module can follow, such as "info,libs::distro=debug".
     #[structopt(short, long)]
     pub log_level: Option<String>,
+    /// The format of the logs to stderr. json writes an object per line.
+    #[structopt(long, default_value = "text", possible_values = LOG_FORMATS)]
+    pub log_format: LogFormat,
+    /// The format of the results of the commands which print them, such as list, status, port
+    /// list, and image list. The key names of tsv and json are stable. --format of each command
+    /// takes precedence. The logs go to stderr in any format. Give it before the command, since
+    /// `distrod logs --output` is the format of journalctl.
+    #[structopt(long, possible_values = output::OUTPUT_FORMATS)]
+    pub output: Option<output::OutputFormat>,
+    /// Never prompt, such as in CI pipelines. The prompts take their defaults, or fail if they
+    /// have none. It's also the case when stdin is not a terminal.
+    #[structopt(long, global = true)]
+    pub non_interactive: bool,
     #[structopt(subcommand)]
     pub command: Subcommand,
 }
@@ -43,7 +104,75 @@ pub enum Subcommand {
     Create(CreateOpts),
     Start(StartOpts),
     Exec(ExecOpts),
+    /// Run a command in a throwaway distro of an image, such as `distrod run ubuntu:22.04 -- make test`. The image is downloaded on the first use, and the distro and all the changes in it are discarded when the command exits.
+    Run(run::RunOpts),
+    /// Bring up or down the named distros declared in a compose file together, such as a db distro and an app distro, in the order of their dependencies.
+    Compose(compose::ComposeOpts),
+    /// Start the named distros in [autostart] of the Distrod config in order. This is run by the autostart task on Windows startup.
+    Autostart(autostart::AutostartOpts),
+    /// Keep the namespaces of the running distro open and run the commands of `distrod exec` in them, so that exec starts faster. This is started by `distrod start`.
+    ExecBroker(ExecBrokerOpts),
+    /// Keep the name servers of the distro in sync with /etc/resolv.conf of WSL. This is started by `distrod start` if the distro uses systemd-resolved or `network.repair_vpn_dns` is on.
+    ResolvedSync(ResolvedSyncOpts),
+    /// Start the distro if needed, and log in to it by the login shell of the user.
+    Shell(shell::ShellOpts),
     Stop(StopOpts),
+    /// Run a long-running process which starts and stops the distros for the CLI and distrod-exec through a Unix socket, and tells its subscribers when a distro starts or stops.
+    Daemon(daemon::DaemonOpts),
+    /// Stop the distro cleanly and start it again with the same options, such as after editing systemd units or the distro config.
+    Restart(RestartOpts),
+    Status(StatusOpts),
+    List(ListOpts),
+    /// Show the processes running in the distro with their users and systemd units, read from the /proc of the distro.
+    Ps(ps::PsOpts),
+    /// Show how much of the disk each named distro takes with its snapshots, such as to find the one filling up the virtual disk of WSL.
+    Df(df::DfOpts),
+    /// Reclaim the disk space of the named distros by clearing the caches of the package managers, deleting the old snapshots, and trimming the file system.
+    Compact(compact::CompactOpts),
+    Port(port::PortOpts),
+    Target(target::TargetOpts),
+    /// Mask or unmask the systemd units of the distro on every start, or list the ones Distrod masks and disables.
+    Unit(unit::UnitOpts),
+    /// Update the packages of the distro by its package manager on a schedule, such as apt or dnf.
+    AutoUpdate(auto_update::AutoUpdateOpts),
+    /// Show the journal of the distro, optionally filtered by unit and boot.
+    Logs(logs::LogsOpts),
+    /// Show or change the config of the distro.
+    Config(config::ConfigOpts),
+    /// Update Distrod to the latest release.
+    SelfUpdate(self_update::SelfUpdateOpts),
+    /// Keep the files in /etc configured in distrod.toml from being overwritten by WSL. This is run by distrod-etc-guard.service.
+    EtcGuard(EtcGuardOpts),
+    /// Alert on the processes which look like malware or cryptominers by the heuristics in distrod.toml. This is run by distrod-monitor.service.
+    Monitor(monitor::MonitorOpts),
+    /// Forward the journal entries configured in [event_log] of distrod.toml to the Windows Event Log. This is run by distrod-event-log.service.
+    EventLog(event_log::EventLogOpts),
+    /// Answer the mDNS queries for <hostname>.local with the address of WSL, so that Windows finds the distro by name. This is run by distrod-mdns.service.
+    Mdns(mdns::MdnsOpts),
+    /// Correct the clock when it drifts from the one of Windows, such as after Windows sleeps. This is run by distrod-time-sync.service.
+    TimeSync(time_sync::TimeSyncOpts),
+    /// Relay a Unix socket in the distro and a named pipe of Windows, such as the Docker socket or ssh-agent.
+    Relay(relay::RelayOpts),
+    /// Move the distro to the built-in systemd support of WSL, and stop using Distrod as the init.
+    MigrateToNative(migrate::MigrateToNativeOpts),
+    /// Check the setup of WSL and the distro for the features which depend on it, such as the GPU.
+    Doctor(doctor::DoctorOpts),
+    /// Set up the distro for CUDA and ROCm by the GPU of WSL.
+    Gpu(gpu::GpuOpts),
+    /// Make commands of Windows which run Linux programs in the distro, such as `code` from PowerShell.
+    Alias(alias::AliasOpts),
+    /// Print the completion script of the shell, such as `distrod completion bash`.
+    Completion(completion::CompletionOpts),
+    /// List the images `distrod create` can download.
+    Image(image::ImageOpts),
+    /// Save, restore, and delete the snapshots of the rootfs of a named distro. They are instant btrfs snapshots if the rootfs is a btrfs subvolume.
+    Snapshot(snapshot::SnapshotOpts),
+    /// Copy a named distro into a new one.
+    Clone(snapshot::CloneOpts),
+    /// Read and edit /etc/wsl.conf of WSL without breaking it, keeping the comments.
+    Wslconf(wslconf::WslconfOpts),
+    /// Share environment variables between Windows and the distro by WSLENV of the Windows user.
+    Env(env::EnvOpts),
 }
 
 #[derive(Debug, StructOpt)]
@@ -51,6 +180,64 @@ pub enum Subcommand {
 pub struct StartOpts {
     #[structopt(short, long)]
     rootfs: Option<OsString>,
+
+    /// The name of the distro to start. Multiple distros can run at the same time if they have different names.
+    #[structopt(long)]
+    distro: Option<String>,
+
+    /// The systemd target to boot into this time, such as multi-user.target.
+    /// Defaults to the one `distrod target get` shows.
+    #[structopt(long)]
+    target: Option<String>,
+
+    /// Discard all the changes made in the rootfs when the distro stops. The changes are kept
+    /// on memory while it runs.
+    #[structopt(long)]
+    ephemeral: bool,
+
+    /// Mount the rootfs read-only except for /var, /home, /tmp, and /run, as `read_only` of the
+    /// [rootfs] section of /etc/distrod/distrod.toml of the distro does.
+    #[structopt(long)]
+    read_only: bool,
+
+    /// Bind-mount a directory or file into the distro by SOURCE:TARGET[:ro|:rw], such as
+    /// /mnt/c/data:/data:ro. Added to [[mounts]] of /etc/distrod/distrod.toml of the distro.
+    #[structopt(long, number_of_values = 1)]
+    mount: Vec<String>,
+
+    /// The IP address of a name server to use in place of the ones of WSL this time. Takes
+    /// precedence over `nameservers` of the [network] section of /etc/distrod/distrod.toml.
+    #[structopt(long, number_of_values = 1)]
+    dns: Vec<String>,
+
+    /// A search domain with the name servers of --dns.
+    #[structopt(long, number_of_values = 1, requires = "dns")]
+    dns_search: Vec<String>,
+
+    #[structopt(flatten)]
+    limits: ResourceLimitOpts,
+
+    /// Don't run the setup wizard even if this is the first start of a distro made by
+    /// `distrod create`, such as in scripts. The wizard runs on the next interactive start.
+    #[structopt(long)]
+    no_wizard: bool,
+}
+
+/// The limits which take precedence over [resources] of /etc/distrod/distrod.toml of the distro.
+#[derive(Clone, Debug, Default, StructOpt)]
+#[structopt(rename_all = "kebab")]
+pub struct ResourceLimitOpts {
+    /// The memory limit of the distro, such as 4G.
+    #[structopt(long)]
+    memory: Option<String>,
+
+    /// The number of CPUs the distro can use up, such as 1.5.
+    #[structopt(long, parse(try_from_str = parse_cpus))]
+    cpus: Option<f64>,
+
+    /// The maximum number of processes and threads in the distro.
+    #[structopt(long)]
+    pids_limit: Option<u64>,
 }
 
 #[derive(Clone, Debug, StructOpt)]
@@ -62,26 +249,129 @@ pub struct ExecOpts {
     #[structopt(short, long)]
     arg0: Option<OsString>,
 
+    /// The name or the uid of the user to run the command as. The command runs in the login
+    /// environment of the user, with $HOME and the working directory set to the home directory.
     #[structopt(short, long)]
     user: Option<String>,
 
-    #[structopt(short = "i", long)]
+    /// The uid to run the command with, keeping the current environment.
+    #[structopt(short = "i", long, conflicts_with = "user")]
     uid: Option<u32>,
 
-    #[structopt(short, long)]
+    /// The working directory of the command. A Windows path such as `C:\src\proj` is translated
+    /// into the path where WSL mounts it. Defaults to the root directory.
+    #[structopt(short, long, visible_alias = "workdir")]
     working_directory: Option<OsString>,
 
     #[structopt(short, long)]
     rootfs: Option<OsString>,
+
+    /// The name of the distro to execute the command in.
+    #[structopt(long)]
+    distro: Option<String>,
+
+    /// Don't ask the exec broker of the distro to run the command.
+    #[structopt(long)]
+    no_broker: bool,
+
+    /// Apply a seccomp profile in the Docker format to the command, in place of the one in
+    /// [seccomp] of /etc/distrod/distrod.toml of the distro.
+    #[structopt(long)]
+    seccomp_profile: Option<PathBuf>,
+
+    /// Run the command on a new pseudo terminal, relaying the terminal of this process in the
+    /// raw mode with its window size. This is the default when stdin and stdout are terminals.
+    #[structopt(short, long)]
+    tty: bool,
+
+    /// Run the command with the stdio of this process as it is, without a pseudo terminal.
+    #[structopt(short = "T", long, conflicts_with = "tty")]
+    no_tty: bool,
+
+    #[structopt(flatten)]
+    env: exec_env::ExecEnvOpts,
+}
+
+impl ExecOpts {
+    fn uses_tty(&self) -> bool {
+        if self.no_tty {
+            return false;
+        }
+        self.tty
+            || (nix::unistd::isatty(0).unwrap_or(false) && nix::unistd::isatty(1).unwrap_or(false))
+    }
+}
+
+#[derive(Debug, StructOpt)]
+#[structopt(rename_all = "kebab")]
+pub struct ExecBrokerOpts {
+    /// The name of the distro to serve.
+    #[structopt(long)]
+    distro: Option<String>,
+}
+
+#[derive(Debug, StructOpt)]
+#[structopt(rename_all = "kebab")]
+pub struct ResolvedSyncOpts {
+    /// The name of the distro to keep in sync.
+    #[structopt(long)]
+    distro: Option<String>,
 }
 
 #[derive(Debug, StructOpt)]
 #[structopt(rename_all = "kebab")]
 pub struct StopOpts {
+    /// Kill the processes of the distro right away without powering off systemd.
     #[structopt(short = "9", long)]
     sigkill: bool,
+
+    /// The seconds to wait for systemd to power off before killing the processes.
+    /// Defaults to `systemd.stop_timeout_sec` of the distro config, or 30.
+    #[structopt(short, long)]
+    timeout: Option<u64>,
+
+    /// The name of the distro to stop.
+    #[structopt(long)]
+    distro: Option<String>,
+}
+
+#[derive(Debug, StructOpt)]
+#[structopt(rename_all = "kebab")]
+pub struct RestartOpts {
+    /// The seconds to wait for systemd to power off before killing the processes.
+    /// Defaults to `systemd.stop_timeout_sec` of the distro config, or 30.
+    #[structopt(short, long)]
+    timeout: Option<u64>,
+
+    /// The name of the distro to restart.
+    #[structopt(long)]
+    distro: Option<String>,
 }
 
+#[derive(Debug, StructOpt)]
+#[structopt(rename_all = "kebab")]
+pub struct StatusOpts {
+    /// The name of the distro to show the status of.
+    #[structopt(long)]
+    distro: Option<String>,
+
+    /// The output format. Defaults to the one of --output, or table.
+    #[structopt(long, possible_values = output::OUTPUT_FORMATS)]
+    format: Option<output::OutputFormat>,
+}
+
+#[derive(Debug, StructOpt)]
+#[structopt(rename_all = "kebab")]
+pub struct ListOpts {
+    /// The output format. Defaults to the one of --output, or table.
+    #[structopt(long, possible_values = output::OUTPUT_FORMATS)]
+    format: Option<output::OutputFormat>,
+}
+
+#[derive(Debug, StructOpt)]
+#[structopt(rename_all = "kebab")]
+pub struct EtcGuardOpts {}
+
 #[derive(Debug, StructOpt)]
 #[structopt(rename_all = "kebab")]
 pub struct CreateOpts {
@@ -89,6 +379,52 @@ pub struct CreateOpts {
     install_dir: Option<OsString>,
     #[structopt(short = "i", long)]
     image_path: Option<OsString>,
+    /// Take the image from an image fetcher plugin in /opt/distrod/image-fetchers by
+    /// PLUGIN/PATH, such as corp-mirror/ubuntu/jammy, without prompts. The path can be short to
+    /// take the defaults of the plugin.
+    #[structopt(long, conflicts_with = "image-path")]
+    image_plugin: Option<String>,
+    /// The name of the new distro, which `--distro` of the other commands takes. Defaults to the image name.
+    #[structopt(short, long)]
+    name: Option<String>,
+    /// A glob pattern of the paths in the image not to extract, such as "/usr/share/doc/**".
+    /// Added to `exclude` of the [extract] section of the Distrod config.
+    #[structopt(long)]
+    exclude: Vec<String>,
+    /// A glob pattern of the paths to extract even if they match an exclude pattern.
+    /// Added to `include` of the [extract] section of the Distrod config.
+    #[structopt(long)]
+    include: Vec<String>,
+    /// Create a user with the admin group, such as sudo or wheel, and make it the default login user.
+    #[structopt(long)]
+    user: Option<String>,
+    /// The uid of the user given by --user.
+    #[structopt(long, requires = "user")]
+    uid: Option<u32>,
+    /// Prompt for the password of the user given by --user.
+    #[structopt(long, requires = "user")]
+    password: bool,
+    /// Let the user given by --user use sudo without the password.
+    #[structopt(long, requires = "user")]
+    passwordless_sudo: bool,
+    /// The hostname of the new distro, instead of the name of the Windows machine.
+    /// Saved as `hostname` of the [network] section of /etc/distrod/distrod.toml of the distro.
+    #[structopt(long)]
+    hostname: Option<String>,
+    /// Store the rootfs in an ext4 disk image of the size, such as 20G, instead of in the directory.
+    /// The image is a sparse file next to the directory, and the files in the rootfs can't take
+    /// more than the size.
+    #[structopt(long)]
+    image_size: Option<String>,
+    /// Provision the new distro by a spec in TOML, which lists the packages to install, the users
+    /// to create, the files to write, the services to enable, and the commands to run. The
+    /// packages, the services, and the commands are set up on the first boot.
+    #[structopt(long)]
+    provision: Option<PathBuf>,
+    /// Don't run the setup wizard on the first start of the new distro. It doesn't run for
+    /// the distros provisioned by --provision either.
+    #[structopt(long)]
+    no_wizard: bool,
 }
 
 #[derive(Debug, StructOpt)]
@@ -98,32 +434,87 @@ pub struct EnableOpts {
     start_on_windows_boot: bool,
     #[structopt(short, long)]
     do_full_initialization: bool,
+    /// Create a user with the admin group, such as sudo or wheel, and make it the default login
+    /// user of the distro and WSL. This is used by the launcher on installation.
+    #[structopt(long)]
+    user: Option<String>,
+    /// Prompt for the password of the user given by --user.
+    #[structopt(long, requires = "user")]
+    password: bool,
+    /// Let the user given by --user use sudo without the password.
+    #[structopt(long, requires = "user")]
+    passwordless_sudo: bool,
 }
 
 #[derive(Debug, StructOpt)]
 #[structopt(rename_all = "kebab")]
-pub struct DisableOpts {}
+pub struct DisableOpts {
+    /// Also undo the other changes Distrod has made to the distro and Windows, such as the files in
+    /// /etc, /etc/wsl.conf, and the profile of Windows Terminal, and check the distro boots
+    /// without Distrod.
+    #[structopt(long)]
+    purge: bool,
+
+    /// Only show what --purge will do.
+    #[structopt(long, requires = "purge")]
+    dry_run: bool,
+}
+
+fn init_distrod_logger(opts: &Opts) {
+    let mut logger_initializer = LoggerInitializer::default();
+    logger_initializer.with_format(opts.log_format);
+    let distrod_config = DistrodConfig::get().ok();
+    if let Some(log_level) = opts.log_level.as_ref().cloned().or_else(|| {
+        distrod_config
+            .as_ref()
+            .and_then(|config| config.distrod.log_level.clone())
+    }) {
+        logger_initializer.with_log_level(log_level);
+    }
+    let log_file_level = distrod_config
+        .as_ref()
+        .and_then(|config| config.distrod.log_file_level.clone())
+        .or_else(|| match opts.command {
+            // Nobody sees stderr of the autostart on Windows startup. The keep-alive session
+            // runs as the default user, who can't write the file.
+            Subcommand::Autostart(ref autostart_opts) if !autostart_opts.is_keep_alive() => {
+                Some("info".to_owned())
+            }
+            _ => None,
+        });
+    if let Some(log_file_level) = log_file_level {
+        let format = distrod_config
+            .as_ref()
+            .and_then(|config| config.distrod.log_file_format.as_ref())
+            .and_then(|format| format.parse::<LogFormat>().ok())
+            .unwrap_or_default();
+        logger_initializer.with_log_file(
+            distrod_config::get_log_file_path(),
+            log_file_level,
+            format,
+        );
+    }
+    logger_initializer.init("Distrod".to_owned());
+}
 
 fn main() {
     if is_executed_as_alias() {
         init_logger("Distrod".to_owned(), None);
         if let Err(err) = run_as_command_alias() {
-            log::error!("{:?}", err);
+            std::process::exit(error::report(&err));
         }
         return;
     }
 
     let opts = Opts::from_args();
-    let log_level = opts.log_level.as_ref().cloned().or_else(|| {
-        DistrodConfig::get()
-            .ok()
-            .and_then(|config| config.distrod.log_level.clone())
-    });
-    init_logger("Distrod".to_owned(), log_level);
+    init_distrod_logger(&opts);
+    cli_ui::set_non_interactive(opts.non_interactive);
+    if let Some(format) = opts.output {
+        output::set_global_format(format);
+    }
 
     if let Err(err) = run(opts) {
-        log::error!("{:?}", err);
-        std::process::exit(1);
+        std::process::exit(error::report(&err));
     }
 }
 
@@ -168,7 +559,7 @@ fn run_as_command_alias() -> Result<()> {
 
 fn run(opts: Opts) -> Result<()> {
     if !nix::unistd::getuid().is_root() {
-        bail!("Distrod needs the root permission.");
+        prepare_rootless_mode(&opts.command)?;
     }
 
     match opts.command {
@@ -187,36 +578,237 @@ fn run(opts: Opts) -> Result<()> {
         Subcommand::Exec(exec_opts) => {
             exec_command(exec_opts)?;
         }
+        Subcommand::Run(run_opts) => {
+            run::run_ephemeral_distro(run_opts)?;
+        }
+        Subcommand::Compose(compose_opts) => {
+            compose::run_compose_command(compose_opts)?;
+        }
+        Subcommand::Autostart(autostart_opts) => {
+            autostart::start_autostart_distros(autostart_opts)?;
+        }
+        Subcommand::ExecBroker(exec_broker_opts) => {
+            run_exec_broker(exec_broker_opts)?;
+        }
+        Subcommand::ResolvedSync(resolved_sync_opts) => {
+            sync_resolved_nameservers(resolved_sync_opts)?;
+        }
+        Subcommand::Shell(shell_opts) => {
+            shell::run_shell(shell_opts)?;
+        }
         Subcommand::Stop(stop_opts) => {
             stop_distro(stop_opts)?;
         }
+        Subcommand::Daemon(daemon_opts) => {
+            daemon::run_daemon_command(daemon_opts)?;
+        }
+        Subcommand::Restart(restart_opts) => {
+            restart_distro(restart_opts)?;
+        }
+        Subcommand::Status(status_opts) => {
+            status::show_status(
+                status_opts.distro.as_deref(),
+                output::resolve_format(status_opts.format),
+            )?;
+        }
+        Subcommand::List(list_opts) => {
+            status::list_distros(output::resolve_format(list_opts.format))?;
+        }
+        Subcommand::Ps(ps_opts) => {
+            ps::show_processes(ps_opts)?;
+        }
+        Subcommand::Df(df_opts) => {
+            df::show_disk_usage(df_opts)?;
+        }
+        Subcommand::Compact(compact_opts) => {
+            compact::compact_distros(compact_opts)?;
+        }
+        Subcommand::Port(port_opts) => {
+            port::run_port_command(port_opts)?;
+        }
+        Subcommand::Target(target_opts) => {
+            target::run_target_command(target_opts)?;
+        }
+        Subcommand::Unit(unit_opts) => {
+            unit::run_unit_command(unit_opts)?;
+        }
+        Subcommand::AutoUpdate(auto_update_opts) => {
+            auto_update::run_auto_update_command(auto_update_opts)?;
+        }
+        Subcommand::Logs(logs_opts) => {
+            logs::show_logs(logs_opts)?;
+        }
+        Subcommand::Config(config_opts) => {
+            config::run_config_command(config_opts)?;
+        }
+        Subcommand::SelfUpdate(self_update_opts) => {
+            self_update::self_update(self_update_opts)?;
+        }
+        Subcommand::EtcGuard(etc_guard_opts) => {
+            guard_etc_files(etc_guard_opts)?;
+        }
+        Subcommand::Monitor(monitor_opts) => {
+            monitor::run_monitor(monitor_opts)?;
+        }
+        Subcommand::EventLog(event_log_opts) => {
+            event_log::run_event_log(event_log_opts)?;
+        }
+        Subcommand::Mdns(mdns_opts) => {
+            mdns::run_mdns(mdns_opts)?;
+        }
+        Subcommand::TimeSync(time_sync_opts) => {
+            time_sync::run_time_sync(time_sync_opts)?;
+        }
+        Subcommand::Relay(relay_opts) => {
+            relay::run_relay_command(relay_opts)?;
+        }
+        Subcommand::MigrateToNative(migrate_opts) => {
+            migrate::migrate_to_native(migrate_opts)?;
+        }
+        Subcommand::Doctor(doctor_opts) => {
+            doctor::run_doctor(doctor_opts)?;
+        }
+        Subcommand::Gpu(gpu_opts) => {
+            gpu::run_gpu_command(gpu_opts)?;
+        }
+        Subcommand::Alias(alias_opts) => {
+            alias::run_alias_command(alias_opts)?;
+        }
+        Subcommand::Completion(completion_opts) => {
+            completion::print_completion(completion_opts)?;
+        }
+        Subcommand::Image(image_opts) => {
+            image::run_image_command(image_opts)?;
+        }
+        Subcommand::Snapshot(snapshot_opts) => {
+            snapshot::run_snapshot_command(snapshot_opts)?;
+        }
+        Subcommand::Clone(clone_opts) => {
+            snapshot::clone_distro(clone_opts)?;
+        }
+        Subcommand::Wslconf(wslconf_opts) => {
+            wslconf::run_wslconf_command(wslconf_opts)?;
+        }
+        Subcommand::Env(env_opts) => {
+            env::run_env_command(env_opts)?;
+        }
     }
     Ok(())
 }
 
+/// Lets a non-root user create and run distros of their own in user namespaces. The other
+/// commands still need the root permission.
+fn prepare_rootless_mode(command: &Subcommand) -> Result<()> {
+    let creates_distro = match command {
+        Subcommand::Create(_) => true,
+        Subcommand::Start(_)
+        | Subcommand::Exec(_)
+        | Subcommand::Shell(_)
+        | Subcommand::Stop(_)
+        | Subcommand::Restart(_)
+        | Subcommand::Status(_)
+        | Subcommand::List(_)
+        | Subcommand::Df(_)
+        | Subcommand::Wslconf(_)
+        | Subcommand::Env(_)
+        | Subcommand::Alias(_)
+        | Subcommand::Completion(_)
+        | Subcommand::Image(_) => false,
+        // The autostart task runs it as the default user of WSL.
+        Subcommand::Autostart(ref autostart_opts) if autostart_opts.is_keep_alive() => false,
+        _ => bail!("Distrod needs the root permission."),
+    };
+    userns::enable_rootless_mode();
+    log::debug!("Running in the rootless mode.");
+    // The others enter the namespaces when they launch a distro, since a running distro is
+    // entered by its own user namespace.
+    if creates_distro {
+        enter_rootless_namespaces()?;
+    }
+    Ok(())
+}
+
+fn enter_rootless_namespaces() -> Result<()> {
+    userns::enter_rootless_namespaces().with_context(|| {
+        "Distrod needs the root permission, or a kernel which lets a non-root user make \
+         user namespaces."
+    })
+}
+
 fn enable_wsl_exec_hook(opts: EnableOpts) -> Result<()> {
+    // The user is created first, so that a failure leaves the distro as it was.
+    if let Some(ref user) = opts.user {
+        log::info!("Creating the user {}...", user);
+        create_user::create_default_user(
+            &HostPath::new("/")?,
+            user,
+            None,
+            opts.password,
+            opts.passwordless_sudo,
+        )
+        .with_context(|| format!("Failed to create the user {}.", user))?;
+    }
+    if let Err(e) = uninstall::back_up_wsl_conf() {
+        log::warn!("Failed to back up /etc/wsl.conf. {:?}", e);
+    }
     distro::initialize_distro_rootfs(HostPath::new("/")?, opts.do_full_initialization)
         .with_context(|| "Failed to initialize the rootfs.")?;
     shell_hook::enable_default_shell_hook()
         .with_context(|| "Failed to enable the hook to the default shell.")?;
     log::info!("Distrod has been enabled. Now your shell will start under systemd.");
     if opts.start_on_windows_boot {
-        log::info!(
-            "Enabling atuomatic startup of Distrod. UAC dialog will appear because scheduling\n\
-             a task requires the admin privilege. Please hit enter to proceed."
-        );
+        register_autostart_task()?;
+    }
+    Ok(())
+}
+
+/// Registers the task which starts Distrod and the distros in [autostart] on Windows startup, or
+/// updates it with the current [autostart].
+fn register_autostart_task() -> Result<()> {
+    log::info!(
+        "Enabling atuomatic startup of Distrod. UAC dialog will appear because scheduling\n\
+         a task requires the admin privilege. Please hit enter to proceed."
+    );
+    let autostart_plan = autostart::get_autostart_plan()?;
+    let keep_alive = DistrodConfig::get()
+        .with_context(|| "Failed to get the Distrod config.")?
+        .autostart
+        .keep_alive;
+    if cli_ui::is_interactive() {
         let mut buf = String::new();
         let _ = stdin().read_line(&mut buf);
-        autostart::enable_autostart_on_windows_boot(
-            &wsl_interop::get_distro_name().with_context(|| "Failed to get the distro name.")?,
-        )
-        .with_context(|| "Failed to enable the autostart on Windows boot.")?;
-        log::info!("Distrod will now start automatically on Windows startup.");
+    }
+    autostart::enable_autostart_on_windows_boot(
+        &wsl_interop::get_distro_name().with_context(|| "Failed to get the distro name.")?,
+        &autostart_plan,
+        keep_alive,
+    )
+    .with_context(|| "Failed to enable the autostart on Windows boot.")?;
+    log::info!("Distrod will now start automatically on Windows startup.");
+    if !autostart_plan.is_empty() {
+        log::info!("The distros {} will start after it.", &autostart_plan);
+    }
+    if keep_alive {
+        log::info!("The autostart task will keep WSL running.");
     }
     Ok(())
 }
 
-fn disable_wsl_exec_hook(_opts: DisableOpts) -> Result<()> {
+fn disable_wsl_exec_hook(opts: DisableOpts) -> Result<()> {
+    if opts.purge {
+        return uninstall::purge_distrod(opts.dry_run);
+    }
+    disable_default_hooks()?;
+    log::info!("Distrod has been disabled. Now systemd will not start automatically.");
+    if let Err(e) = autostart::disable_autostart_on_windows_boot(
+        &wsl_interop::get_distro_name().with_context(|| "Failed to get the distro name.")?,
+    ) {
+        log::warn!("Failed to disable the autostart on Windows boot.: {:?}", e);
+    }
+    Ok(())
+}
+
+fn disable_default_hooks() -> Result<()> {
     shell_hook::disable_default_shell_hook()
         .with_context(|| "Failed to disable the hook to the default shell.")?;
     if let Err(e) = distro::cleanup_distro_rootfs(HostPath::new("/")?) {
@@ -225,145 +817,380 @@ fn disable_wsl_exec_hook(_opts: DisableOpts) -> Result<()> {
             e
         );
     }
-    log::info!("Distrod has been disabled. Now systemd will not start automatically.");
-    if let Err(e) = autostart::disable_autostart_on_windows_boot(
-        &wsl_interop::get_distro_name().with_context(|| "Failed to get the distro name.")?,
-    ) {
-        log::warn!("Failed to disable the autostart on Windows boot.: {:?}", e);
-    }
     Ok(())
 }
 
 #[tokio::main]
 async fn create_distro(opts: CreateOpts) -> Result<()> {
-    let image = match opts.image_path {
-        None => {
+    if let Some(ref name) = opts.name {
+        distro_registry::validate_instance_name(name)?;
+    }
+    if let Some(ref hostname) = opts.hostname {
+        validate_hostname(hostname)?;
+    }
+    let provision_spec = opts
+        .provision
+        .as_ref()
+        .map(ProvisionSpec::load)
+        .transpose()?;
+    let (image, image_name) = match (opts.image_path, opts.image_plugin) {
+        (None, Some(plugin_path)) => {
+            let (plugin, path) = match plugin_path.find('/') {
+                Some(pos) => (&plugin_path[..pos], &plugin_path[pos + 1..]),
+                None => (plugin_path.as_str(), ""),
+            };
+            let source = ImageSource::Plugin {
+                plugin: plugin.to_owned(),
+                path: path.to_owned(),
+            };
+            (source, None)
+        }
+        (None, None) => {
             let local_image_fetcher =
                 || Ok(Box::new(LocalDistroImage::new(&prompt_path)) as Box<dyn DistroImageFetcher>);
             let container_org_image_fetcher =
                 || Ok(Box::new(ContainerOrgImageList::default()) as Box<dyn DistroImageFetcher>);
-            let fetchers = vec![
+            let mut fetchers = distro_image::with_registered_fetchers(vec![
                 Box::new(local_image_fetcher) as DistroImageFetcherGen,
                 Box::new(container_org_image_fetcher) as DistroImageFetcherGen,
-            ];
-            distro_image::fetch_image(fetchers, &choose_from_list, 1)
-                .await
-                .with_context(|| "Failed to fetch the image list.")?
-        }
-        Some(path) => {
-            let name = format!(
-                "local-{}",
-                Path::new(&path)
-                    .file_stem()
-                    .ok_or_else(|| anyhow!("image {:?} should be a file.", &path))?
-                    .to_string_lossy()
-                    .replace(".tar", "")
+            ]);
+            fetchers.extend(
+                image_fetcher_plugin::get_plugin_fetchers(
+                    distrod_config::get_image_fetcher_plugins_dir(),
+                )
+                .with_context(|| "Failed to list the image fetcher plugins.")?,
             );
-            DistroImage {
-                image: DistroImageFile::Local(path),
-                name,
-            }
+            let image = distro_image::fetch_image(fetchers, &choose_from_list, 1)
+                .await
+                .with_context(|| "Failed to fetch the image list.")?;
+            let source = match image.image {
+                DistroImageFile::Local(path) => ImageSource::LocalFile(path.into()),
+                DistroImageFile::Url(url) => ImageSource::Url(url),
+            };
+            (source, Some(image.name))
         }
+        // The core names it after the file.
+        (Some(path), _) => (ImageSource::LocalFile(path.into()), None),
     };
 
-    let image_name = image.name;
-    let tar_xz = match image.image {
-        DistroImageFile::Local(path) => Box::new(
-            File::open(&path)
-                .with_context(|| format!("Failed to open the distro image file: {:?}.", &path))?,
-        ) as Box<dyn Read>,
-        DistroImageFile::Url(url) => {
-            log::info!("Downloading '{}'...", url);
-            let mut bytes = vec![];
-            download_file_with_progress(&url, build_progress_bar, &mut bytes).await?;
-            log::info!("Download done.");
-            Box::new(Cursor::new(bytes)) as Box<dyn Read>
-        }
-    };
+    let mut create_opts = CreateOptions::new(image);
+    if let Some(name) = opts.name.as_deref().or_else(|| image_name.as_deref()) {
+        create_opts.with_name(name);
+    }
+    create_opts.with_progress_bar();
+    if let Some(ref install_dir) = opts.install_dir {
+        create_opts.with_install_dir(install_dir);
+    }
+    for pattern in &opts.exclude {
+        create_opts.with_exclude(pattern);
+    }
+    for pattern in &opts.include {
+        create_opts.with_include(pattern);
+    }
+    if let Some(ref hostname) = opts.hostname {
+        create_opts.with_hostname(hostname);
+    }
+    if let Some(ref image_size) = opts.image_size {
+        create_opts.with_image_size(parse_memory_size(image_size)?);
+    }
 
-    log::info!("Unpacking...");
-    let install_dir = match opts.install_dir {
-        Some(install_dir) => install_dir,
-        None => {
-            let def_install_path =
-                DistrodConfig::get().with_context(|| "Failed to get the Distrod config.")?;
-            def_install_path
-                .distrod
-                .distro_images_dir
-                .join(&image_name)
-                .into()
+    let cancel = CancellationToken::new();
+    cancellation::run_on_ctrl_c({
+        let cancel = cancel.clone();
+        move || cancel.cancel()
+    });
+    let created = distrod_core::create(&create_opts, &cancel).await?;
+    let image_name = created.name;
+    let rootfs = HostPath::new(created.rootfs)?;
+    if let Some(ref user) = opts.user {
+        log::info!("Creating the user {}...", user);
+        create_user::create_default_user(
+            &rootfs,
+            user,
+            opts.uid,
+            opts.password,
+            opts.passwordless_sudo,
+        )
+        .with_context(|| {
+            format!(
+                "{} is created, but failed to create the user {}.",
+                &image_name, user
+            )
+        })?;
+        log::info!("{} is the default user of {}.", user, &image_name);
+    }
+    if let Some(spec) = provision_spec {
+        provision_distro(&rootfs, &spec).with_context(|| {
+            format!(
+                "{} is created, but failed to provision it by {:?}.",
+                &image_name,
+                opts.provision.as_ref().expect("the spec is given")
+            )
+        })?;
+    } else if !opts.no_wizard {
+        if let Err(e) = wizard::put_wizard_marker(&rootfs) {
+            log::warn!(
+                "Failed to mark {} for the setup wizard. {:?}",
+                &image_name,
+                e
+            );
         }
-    };
-    let install_dir = Path::new(&install_dir);
-    if !install_dir.exists() {
-        std::fs::create_dir_all(&install_dir)
-            .with_context(|| format!("Failed to make a directory: {:?}.", &install_dir))?;
-    }
-    let tar = XzDecoder::new(tar_xz);
-    let mut archive = tar::Archive::new(tar);
-    archive.set_preserve_permissions(true);
-    archive.set_unpack_xattrs(true);
-    archive
-        .unpack(&install_dir)
-        .with_context(|| format!("Failed to unpack the image to '{:?}'.", &install_dir))?;
-
-    distro::initialize_distro_rootfs(
-        HostPath::new(&install_dir.canonicalize().with_context(|| {
-            format!("Failed to get the canonicalized path of {:?}", &install_dir)
-        })?)?,
-        true,
-    )
-    .with_context(|| "Failed to initialize the rootfs.")?;
+    }
+    Ok(())
+}
 
-    log::info!("{} is created at {:?}", &image_name, install_dir);
+/// Creates the users and writes the files of the spec, and leaves the rest to the first boot.
+fn provision_distro(rootfs: &HostPath, spec: &ProvisionSpec) -> Result<()> {
+    for user in &spec.users {
+        log::info!("Creating the user {}...", &user.name);
+        create_user::create_provisioned_user(rootfs, user)
+            .with_context(|| format!("Failed to create the user {}.", &user.name))?;
+    }
+    spec.write_files(rootfs)?;
+    spec.put_first_boot_hook(rootfs)
+        .with_context(|| "Failed to put the hook for the first boot.")?;
+    if spec.build_first_boot_script().is_some() {
+        log::info!(
+            "The packages, the services, and the commands will be set up on the first start."
+        );
+    }
     Ok(())
 }
 
 fn launch_distro(opts: StartOpts) -> Result<()> {
-    if distro::is_inside_running_distro()
-        || DistroLauncher::get_running_distro()
-            .with_context(|| "Failed to see if there's a running distro.")?
-            .is_some()
-    {
-        bail!("There is already a running distro.");
+    if !opts.no_wizard && opts.rootfs.is_none() {
+        wizard::run_first_start_wizard(opts.distro.as_deref())
+            .with_context(|| "Failed to run the setup wizard.")?;
     }
-    let mut distro_launcher = DistroLauncher::new()?;
-    if let Some(rootfs) = opts.rootfs {
-        distro_launcher
-            .with_rootfs(&rootfs)
-            .with_context(|| format!("Failed to set {:?} to the rootfs of the distro.", &rootfs))?;
-    } else {
-        distro_launcher
-            .from_default_distro()
-            .with_context(|| "Failed to get the default distro.")?;
+    // The daemon takes only the name, so the other options are applied by starting it here.
+    let takes_only_name = opts.rootfs.is_none()
+        && opts.target.is_none()
+        && !opts.ephemeral
+        && !opts.read_only
+        && opts.mount.is_empty()
+        && opts.dns.is_empty()
+        && opts.limits.memory.is_none()
+        && opts.limits.cpus.is_none()
+        && opts.limits.pids_limit.is_none();
+    if takes_only_name {
+        if let Some(mut client) = DaemonClient::connect()? {
+            log::debug!("Starting the distro by the daemon.");
+            client.request(&DaemonRequest::Start {
+                name: opts.distro.clone(),
+            })?;
+            return Ok(());
+        }
+    }
+    let mut start_opts = StartOptions::new();
+    if let Some(ref name) = opts.distro {
+        start_opts.with_name(name);
+    }
+    if let Some(ref rootfs) = opts.rootfs {
+        start_opts.with_rootfs(rootfs);
     }
-    distro_launcher
-        .launch()
-        .with_context(|| "Failed to launch the distro.")?;
+    if let Some(ref target) = opts.target {
+        start_opts.with_target(target);
+    }
+    if opts.ephemeral {
+        start_opts.with_ephemeral();
+    }
+    if opts.read_only {
+        start_opts.with_read_only_rootfs();
+    }
+    for mount in &opts.mount {
+        start_opts.with_mount(mount);
+    }
+    if !opts.dns.is_empty() {
+        start_opts.with_nameservers(&opts.dns, &opts.dns_search);
+    }
+    if let Some(ref memory) = opts.limits.memory {
+        start_opts.with_memory_limit(parse_memory_size(memory)?);
+    }
+    if let Some(cpus) = opts.limits.cpus {
+        start_opts.with_cpu_limit(cpus);
+    }
+    if let Some(pids) = opts.limits.pids_limit {
+        start_opts.with_pids_limit(pids);
+    }
+    start_opts.with_distrod_bin(get_current_exe()?);
+    let cancel = CancellationToken::new();
+    cancel.cancel_on_sigint()?;
+    distrod_core::start(&start_opts, &cancel)?;
     Ok(())
 }
 
+fn get_current_exe() -> Result<PathBuf> {
+    std::env::current_exe().with_context(|| "Failed to get the path to distrod.")
+}
+
+fn run_exec_broker(opts: ExecBrokerOpts) -> Result<()> {
+    let distro = DistroLauncher::get_running_distro_by_name(opts.distro.as_deref())
+        .with_context(|| "Failed to get the running distro.")?
+        .ok_or_else(|| DistroError::NotRunning {
+            name: opts.distro.clone(),
+        })?;
+    let mut broker =
+        ExecBroker::bind(&distro).with_context(|| "Failed to start the exec broker.")?;
+    broker.run()
+}
+
+fn sync_resolved_nameservers(opts: ResolvedSyncOpts) -> Result<()> {
+    let distro = DistroLauncher::get_running_distro_by_name(opts.distro.as_deref())
+        .with_context(|| "Failed to get the running distro.")?
+        .ok_or_else(|| DistroError::NotRunning {
+            name: opts.distro.clone(),
+        })?;
+    let distro_config = distro::get_distro_config(&HostPath::new(distro.get_rootfs())?)
+        .with_context(|| "Failed to read the config of the distro.")?;
+    resolved::sync_nameservers(&distro, distro_config.network.repair_vpn_dns)
+}
+
 fn exec_command(opts: ExecOpts) -> Result<()> {
-    let distro = DistroLauncher::get_running_distro()
+    let status = run_exec_command(opts)?;
+    std::process::exit(status as i32)
+}
+
+/// Runs the command of exec in the distro, starting the distro if needed, and returns the exit
+/// code of the command. The signals to this process are ignored or forwarded from then on.
+fn run_exec_command(mut opts: ExecOpts) -> Result<u32> {
+    let translated_wd = match opts.working_directory.as_ref().and_then(|wd| wd.to_str()) {
+        Some(wd) => wsl_interop::windows_path_to_wsl_path(wd)
+            .with_context(|| format!("Failed to translate the Windows path {}.", wd))?,
+        None => None,
+    };
+    if let Some(wsl_path) = translated_wd {
+        log::debug!("The working directory is translated into {:?}.", &wsl_path);
+        opts.working_directory = Some(wsl_path.into_os_string());
+    }
+    // A command run by the broker isn't in the session of this process, so it cannot use the
+    // terminal of this process as the controlling terminal. Use the broker only when the command
+    // isn't interactive, such as when it's run by tools.
+    // The broker applies only the seccomp profile of the distro config.
+    if !opts.no_broker
+        && opts.user.is_none()
+        && opts.seccomp_profile.is_none()
+        && !opts.tty
+        && !nix::unistd::isatty(0).unwrap_or(false)
+    {
+        let request = ExecRequest {
+            command: opts.command.clone(),
+            args: opts.args.iter().map(OsString::from).collect(),
+            arg0: opts.arg0.clone(),
+            working_directory: opts.working_directory.as_ref().map(PathBuf::from),
+            envs: opts.env.build_envs()?,
+            uid: opts.uid,
+        };
+        match exec_broker::request_exec(opts.distro.as_deref(), &request) {
+            Ok(Some(mut waiter)) => {
+                log::debug!("Executing a command by the exec broker.");
+                // The command isn't in the process group of this process, so forward the signals
+                // of the terminal as well. It still gets SIGHUP if this process is killed by
+                // SIGKILL and closes the connection.
+                waiter.forward_signals(true)?;
+                return Ok(waiter.wait());
+            }
+            Ok(None) => {}
+            Err(e) => log::debug!("Failed to request the exec broker. {:?}", e),
+        }
+    }
+    let distro = DistroLauncher::get_running_distro_by_name(opts.distro.as_deref())
         .with_context(|| "Failed to get the running distro.")?;
     if distro.is_none() {
-        if let Some(ref rootfs) = opts.rootfs {
+        if opts.rootfs.is_some() || opts.distro.is_some() {
             launch_distro(StartOpts {
-                rootfs: Some(rootfs.clone()),
+                rootfs: opts.rootfs.clone(),
+                distro: opts.distro.clone(),
+                target: None,
+                ephemeral: false,
+                read_only: false,
+                mount: vec![],
+                dns: vec![],
+                dns_search: vec![],
+                limits: ResourceLimitOpts::default(),
+                no_wizard: true,
             })?;
-            return exec_command(opts);
+            return run_exec_command(opts);
         }
-        bail!("No distro is currently running.");
+        return Err(DistroError::NotRunning { name: None }.into());
     }
-    let distro = distro.unwrap();
+    let mut distro = distro.unwrap();
+    let seccomp_filter = match opts.seccomp_profile {
+        Some(ref path) => Some(
+            SeccompProfile::load(path)?
+                .compile()
+                .with_context(|| format!("Failed to compile the seccomp profile {:?}.", path))?,
+        ),
+        None => distro::get_configured_seccomp_filter(&HostPath::new(distro.get_rootfs())?)
+            .with_context(|| "Failed to get the seccomp profile of the distro.")?,
+    };
+    if let Some(filter) = seccomp_filter {
+        distro.with_seccomp_filter(filter);
+    }
+    distro.with_dropped_exec_capabilities(
+        distro::get_configured_exec_capability_drops(&HostPath::new(distro.get_rootfs())?)
+            .with_context(|| "Failed to get the capabilities to drop.")?,
+    );
+
+    let pty = if opts.uses_tty() {
+        let pty = Pty::open()?;
+        distro.with_terminal(pty.try_clone_slave()?);
+        Some(pty)
+    } else {
+        None
+    };
 
     let passwd_path =
         ContainerPath::new("/etc/passwd")?.to_host_path(&HostPath::new(distro.get_rootfs())?);
+    if let Some(user) = opts.user.as_deref() {
+        if opts.arg0.is_some() {
+            bail!("--arg0 cannot be used with --user.");
+        }
+        if opts.env.is_clean_env() {
+            bail!(
+                "--clean-env cannot be used with --user, which starts from the login environment."
+            );
+        }
+        let assignments = opts.env.get_assignments()?;
+        let mut passwd_file = passwd::PasswdFile::open(passwd_path.as_path())
+            .with_context(|| format!("Failed to open the passwd file. {:?}", &passwd_path))?;
+        let user_name = resolve_user_name(&mut passwd_file, user)?;
+        // su sets up the environment of a login session by PAM, and the shell runs the command
+        // in place of itself, so that the arguments are passed as they are.
+        let script = if opts.working_directory.is_some() {
+            r#"cd -- "$1" && shift && exec "$@""#
+        } else {
+            r#"exec "$@""#
+        };
+        let mut su_args: Vec<OsString> = vec![
+            "-".into(),
+            user_name.into(),
+            "-c".into(),
+            script.into(),
+            "distrod-exec".into(),
+        ];
+        su_args.extend(opts.working_directory);
+        if !assignments.is_empty() {
+            su_args.push("env".into());
+            su_args.extend(assignments.into_iter().map(|(key, value)| {
+                let mut assignment = key;
+                assignment.push("=");
+                assignment.push(value);
+                assignment
+            }));
+        }
+        su_args.push(opts.command);
+        su_args.extend(opts.args.into_iter().map(OsString::from));
+
+        log::debug!("Executing a command in the distro as {}.", user);
+        set_noninheritable_sig_ign();
+        let mut waiter = distro.exec_command("su", &su_args, None::<&str>, None::<&str>, None)?;
+        return wait_exec_command(pty, &mut waiter);
+    }
     let cred = opts
         .uid
         .map(|uid| {
             Ok(
-                get_credential_from_passwd_file(opts.user.as_ref(), Some(uid), &passwd_path)
+                get_credential_from_passwd_file(None, Some(uid), &passwd_path)
                     .with_context(|| format!("Failed to open the passwd file. {:?}", &passwd_path))?
                     .unwrap_or(Credential {
                         uid: Uid::from_raw(uid),
@@ -375,6 +1202,10 @@ fn exec_command(opts: ExecOpts) -> Result<()> {
         .map_or(Ok(None), |v: Result<_>| v.map(Some))
         .with_context(|| "Failed to get credentail.")?;
 
+    opts.env.apply_to_current_process()?;
+    if opts.env.is_clean_env() {
+        distro.with_clean_env();
+    }
     log::debug!("Executing a command in the distro.");
     set_noninheritable_sig_ign();
     let mut waiter = distro.exec_command(
@@ -387,17 +1218,88 @@ fn exec_command(opts: ExecOpts) -> Result<()> {
     if let Some(cred) = cred {
         cred.drop_privilege();
     }
-    let status = waiter.wait();
-    std::process::exit(status as i32)
+    wait_exec_command(pty, &mut waiter)
+}
+
+/// Waits for the command of exec, relaying its pty to the terminal of this process if it has one.
+/// The signals to this process are forwarded to the command, and the signals of the terminal are
+/// forwarded only to a command on a pty, which is in a session of its own.
+fn wait_exec_command(pty: Option<Pty>, waiter: &mut Waiter) -> Result<u32> {
+    waiter.forward_signals(pty.is_some())?;
+    match pty {
+        Some(pty) => pty.relay(waiter),
+        None => Ok(waiter.wait()),
+    }
+}
+
+/// Returns the name of the user given by the name or the uid.
+fn resolve_user_name(passwd_file: &mut passwd::PasswdFile, user: &str) -> Result<String> {
+    if let Some(entry) = passwd_file.get_ent_by_name(user)? {
+        return Ok(entry.name.to_owned());
+    }
+    if let Ok(uid) = user.parse::<u32>() {
+        if let Some(entry) = passwd_file.get_ent_by_uid(uid)? {
+            return Ok(entry.name.to_owned());
+        }
+    }
+    bail!("The user '{}' doesn't exist in the distro.", user);
+}
+
+fn guard_etc_files(_opts: EtcGuardOpts) -> Result<()> {
+    let config = DistrodConfig::get().with_context(|| "Failed to get the Distrod config.")?;
+    let files = config.etc_guard.files.clone();
+    if files.is_empty() {
+        log::info!("No file is configured to be guarded in [etc_guard] of distrod.toml.");
+        return Ok(());
+    }
+    for file in &files {
+        log::info!("Guarding {:?} with {:?}.", &file.path, &file.policy);
+    }
+    let mut guard = EtcGuard::new(files).with_context(|| "Failed to start the guard.")?;
+    guard.run()
 }
 
 fn stop_distro(opts: StopOpts) -> Result<()> {
-    let distro = DistroLauncher::get_running_distro()
-        .with_context(|| "Failed to get the running distro.")?;
-    if distro.is_none() {
-        bail!("No distro is currently running.");
+    if let Some(mut client) = DaemonClient::connect()? {
+        log::debug!("Stopping the distro by the daemon.");
+        client.request(&DaemonRequest::Stop {
+            name: opts.distro.clone(),
+            sigkill: opts.sigkill,
+            timeout_sec: opts.timeout,
+        })?;
+        return Ok(());
     }
-    let distro = distro.unwrap();
-    log::debug!("Executing a command in the distro.");
-    distro.stop(opts.sigkill)
+    distrod_core::stop(&build_stop_options(
+        opts.distro.as_deref(),
+        opts.sigkill,
+        opts.timeout,
+    )?)
+}
+
+fn restart_distro(opts: RestartOpts) -> Result<()> {
+    distrod_core::restart(&build_stop_options(
+        opts.distro.as_deref(),
+        false,
+        opts.timeout,
+    )?)?;
+    Ok(())
+}
+
+fn build_stop_options(
+    name: Option<&str>,
+    sigkill: bool,
+    timeout: Option<u64>,
+) -> Result<StopOptions> {
+    let mut stop_opts = StopOptions::new();
+    if let Some(name) = name {
+        stop_opts.with_name(name);
+    }
+    if sigkill {
+        stop_opts.with_sigkill();
+    }
+    if let Some(timeout) = timeout {
+        stop_opts.with_timeout(Duration::from_secs(timeout));
+    }
+    stop_opts.with_distrod_bin(get_current_exe()?);
+    Ok(stop_opts)
 }
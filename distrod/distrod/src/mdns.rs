@@ -0,0 +1,156 @@
+use anyhow::{bail, Context, Result};
+use nix::sys::socket::{self, sockopt, AddressFamily, InetAddr, SockAddr, SockFlag, SockType};
+use std::net::{IpAddr, Ipv4Addr, SocketAddr, UdpSocket};
+use std::os::unix::io::FromRawFd;
+use structopt::StructOpt;
+
+use libs::container::HostPath;
+use libs::distro;
+use libs::distro_config::validate_hostname;
+use libs::mdns::{self, MdnsQuery, MDNS_GROUP, MDNS_PORT};
+
+#[derive(Debug, StructOpt)]
+#[structopt(rename_all = "kebab")]
+pub struct MdnsOpts {
+    /// The names to answer for, without .local. Defaults to `network.mdns_names` of the distro
+    /// config, or the hostname of the distro.
+    #[structopt(long = "name")]
+    names: Vec<String>,
+}
+
+/// Answers the mDNS queries for <name>.local with the address of WSL which the querier reaches,
+/// such as the one of eth0 for Windows. This never returns unless it fails.
+pub fn run_mdns(opts: MdnsOpts) -> Result<()> {
+    let names: Vec<String> = get_names(opts)?
+        .iter()
+        .map(|name| format!("{}.local", name))
+        .collect();
+    let socket = bind_mdns_socket()?;
+    log::info!("Answering the mDNS queries for {}.", names.join(", "));
+    let mut buf = vec![0; 9000];
+    loop {
+        let (n, querier) = socket
+            .recv_from(&mut buf)
+            .with_context(|| "Failed to receive an mDNS packet.")?;
+        let query = match MdnsQuery::parse(&buf[..n]) {
+            Some(query) => query,
+            None => continue,
+        };
+        for question in &query.questions {
+            let name = match names.iter().find(|name| question.asks_ipv4_of(name)) {
+                Some(name) => name,
+                None => continue,
+            };
+            let addr = match get_local_addr_to(&querier) {
+                Ok(addr) => addr,
+                Err(e) => {
+                    log::warn!("Failed to answer {}. {:?}", querier, e);
+                    continue;
+                }
+            };
+            log::debug!("Answering {} to {} with {}.", name, querier, addr);
+            // A querier on another port than 5353 is a plain DNS resolver, which takes only
+            // unicast responses.
+            let legacy_query = if querier.port() != MDNS_PORT {
+                Some((query.id, question))
+            } else {
+                None
+            };
+            let response = mdns::build_a_response(name, addr, legacy_query);
+            let dest = if legacy_query.is_some() || question.unicast_response {
+                querier
+            } else {
+                SocketAddr::from((MDNS_GROUP, MDNS_PORT))
+            };
+            if let Err(e) = socket.send_to(&response, dest) {
+                log::warn!("Failed to send the mDNS response to {}. {:?}", dest, e);
+            }
+        }
+    }
+}
+
+fn get_names(opts: MdnsOpts) -> Result<Vec<String>> {
+    let names = if !opts.names.is_empty() {
+        opts.names
+    } else {
+        // This runs in the distro, whose root is the rootfs.
+        let config = distro::get_distro_config(&HostPath::new("/")?)
+            .with_context(|| "Failed to read the config of the distro.")?;
+        if !config.network.mdns_names.is_empty() {
+            config.network.mdns_names
+        } else {
+            let hostname = std::fs::read_to_string("/proc/sys/kernel/hostname")
+                .with_context(|| "Failed to get the hostname.")?;
+            vec![hostname.trim().to_owned()]
+        }
+    };
+    names
+        .into_iter()
+        .map(|name| {
+            let name = name.trim_end_matches(".local").to_owned();
+            validate_hostname(&name)?;
+            Ok(name)
+        })
+        .collect()
+}
+
+/// Binds the mDNS port shared with the other responders, such as avahi-daemon, and joins the
+/// group on every interface other than the loopback.
+fn bind_mdns_socket() -> Result<UdpSocket> {
+    let fd = socket::socket(
+        AddressFamily::Inet,
+        SockType::Datagram,
+        SockFlag::SOCK_CLOEXEC,
+        None,
+    )
+    .with_context(|| "Failed to create a UDP socket.")?;
+    // Own the fd first so that it's closed on errors.
+    let socket = unsafe { UdpSocket::from_raw_fd(fd) };
+    socket::setsockopt(fd, sockopt::ReuseAddr, &true)
+        .with_context(|| "Failed to set SO_REUSEADDR.")?;
+    let addr = SocketAddr::from((Ipv4Addr::UNSPECIFIED, MDNS_PORT));
+    socket::bind(fd, &SockAddr::new_inet(InetAddr::from_std(&addr)))
+        .with_context(|| format!("Failed to bind {}.", addr))?;
+
+    let mut joined = 0;
+    for interface_addr in collect_interface_addrs()? {
+        match socket.join_multicast_v4(&MDNS_GROUP, &interface_addr) {
+            Ok(_) => joined += 1,
+            Err(e) => log::debug!(
+                "Failed to join the mDNS group on {}. {:?}",
+                interface_addr,
+                e
+            ),
+        }
+    }
+    if joined == 0 {
+        bail!("Failed to join the mDNS group on any interface.");
+    }
+    Ok(socket)
+}
+
+fn collect_interface_addrs() -> Result<Vec<Ipv4Addr>> {
+    let addrs = nix::ifaddrs::getifaddrs().with_context(|| "Failed to get the interfaces.")?;
+    Ok(addrs
+        .filter_map(|iaddr| match iaddr.address {
+            Some(SockAddr::Inet(addr @ InetAddr::V4(_))) => match addr.to_std().ip() {
+                IpAddr::V4(ip) if !ip.is_loopback() => Some(ip),
+                _ => None,
+            },
+            _ => None,
+        })
+        .collect())
+}
+
+/// Returns the local address of the route to the querier, which is the address it reaches WSL by.
+fn get_local_addr_to(querier: &SocketAddr) -> Result<Ipv4Addr> {
+    let probe = UdpSocket::bind((Ipv4Addr::UNSPECIFIED, 0))
+        .with_context(|| "Failed to create a UDP socket.")?;
+    probe
+        .connect(querier)
+        .with_context(|| format!("No route to {}.", querier))?;
+    match probe.local_addr()?.ip() {
+        IpAddr::V4(addr) => Ok(addr),
+        IpAddr::V6(addr) => bail!("{} is not an IPv4 address.", addr),
+    }
+}
@@ -0,0 +1,236 @@
+use anyhow::{Context, Result};
+use libs::container::HostPath;
+use libs::distro;
+use libs::distrod_config;
+use libs::managed_file::{ConflictPolicy, ManagedFileUpdater};
+use libs::wsl_conf::WslConf;
+use libs::wsl_interop;
+use std::fs;
+use std::os::unix::fs::symlink;
+use std::path::{Path, PathBuf};
+use structopt::StructOpt;
+
+use crate::autostart;
+use crate::shell_hook;
+
+const WSL_ENV_PROFILE_PATH: &str = "/etc/profile.d/distrod-wsl-env.sh";
+/// The units which run portproxy.exe, which needs WSL_INTEROP.
+const PORT_FORWARD_UNITS: &[&str] = &["portproxy.service", "distrod-port-watch.service"];
+const WSL_INTEROP_DROP_IN_NAME: &str = "distrod-wsl-interop.conf";
+
+#[derive(Debug, StructOpt)]
+#[structopt(rename_all = "kebab")]
+pub struct MigrateToNativeOpts {
+    /// Only show what will be done.
+    #[structopt(long)]
+    dry_run: bool,
+}
+
+/// Hands the distro over to the built-in systemd support of WSL.
+/// Systemd is started by WSL instead of Distrod, but the units and the commands Distrod provides
+/// keep working, since they are moved from /run, which only Distrod mounts, to /etc.
+pub fn migrate_to_native(opts: MigrateToNativeOpts) -> Result<()> {
+    let dry_run = opts.dry_run;
+    enable_native_systemd(dry_run).with_context(|| "Failed to update /etc/wsl.conf.")?;
+    install_run_files_to_etc(dry_run)
+        .with_context(|| "Failed to install Distrod's units to /etc.")?;
+    install_wsl_env_profile(dry_run)
+        .with_context(|| "Failed to install the profile script for the WSL env vars.")?;
+    keep_port_forwards(dry_run).with_context(|| "Failed to keep the port forwards.")?;
+    remove_autostart_task(dry_run);
+
+    log::info!("Disabling Distrod as the init.");
+    if !dry_run {
+        shell_hook::disable_default_shell_hook()
+            .with_context(|| "Failed to disable the hook to the default shell.")?;
+        // WSL sets the WSL env vars by itself. The ones written by Distrod will be stale.
+        if let Err(e) = distro::cleanup_distro_rootfs(HostPath::new("/")?) {
+            log::warn!(
+                "Failed to clean up the rootfs. Some garbage might not be removed.: {:?}",
+                e
+            );
+        }
+    }
+
+    if dry_run {
+        log::info!("This is a dry run. Nothing has been changed.");
+        return Ok(());
+    }
+    log::info!(
+        "The migration is complete. Run `wsl.exe --shutdown` on Windows and restart the distro \
+         to use the built-in systemd support of WSL."
+    );
+    Ok(())
+}
+
+fn enable_native_systemd(dry_run: bool) -> Result<()> {
+    let mut wsl_conf = WslConf::open("/etc/wsl.conf")?;
+    if wsl_conf.get("boot", "systemd").as_deref() == Some("true") {
+        log::info!("/etc/wsl.conf already enables systemd.");
+        return Ok(());
+    }
+    log::info!("Setting `systemd = true` in [boot] of /etc/wsl.conf.");
+    if dry_run {
+        return Ok(());
+    }
+    wsl_conf.set("boot", "systemd", "true");
+    wsl_conf.write()
+}
+
+/// Copies the files Distrod mounts to /run, such as portproxy.service, to the same paths under /etc.
+/// The units the user enabled are linked from /etc/systemd/system/*.wants to /run, so they are
+/// re-linked to the copies in /etc. The units already copied by the last migration are updated
+/// keeping the user's changes.
+fn install_run_files_to_etc(dry_run: bool) -> Result<()> {
+    let run_overlay_dir = Path::new(distrod_config::get_distrod_run_overlay_dir());
+    let updater = ManagedFileUpdater::for_rootfs(&HostPath::new("/")?, ConflictPolicy::Ask)?;
+    for path in glob::glob(&format!("{}/**/*", run_overlay_dir.to_string_lossy()))
+        .with_context(|| "glob failed.")?
+    {
+        let path = path?;
+        if !path.is_file() {
+            continue;
+        }
+        let rel_path = path.strip_prefix(run_overlay_dir).with_context(|| {
+            format!("[BUG] {:?} should starts with {:?}", &path, run_overlay_dir)
+        })?;
+        let run_path = Path::new("/run").join(rel_path);
+        let etc_path = Path::new("/etc").join(rel_path);
+        let is_unit = rel_path.starts_with("systemd/system");
+        if etc_path.exists() && !is_unit {
+            log::info!("{:?} already exists. Skipping it.", &etc_path);
+            continue;
+        }
+        log::info!("Copying {:?} to {:?}.", &path, &etc_path);
+        if !dry_run {
+            if let Some(dir) = etc_path.parent() {
+                fs::create_dir_all(dir).with_context(|| format!("Failed to create {:?}.", dir))?;
+            }
+            if is_unit {
+                updater
+                    .update(&Path::new("etc").join(rel_path), &path, &etc_path)
+                    .with_context(|| format!("Failed to update {:?}.", &etc_path))?;
+            } else {
+                fs::copy(&path, &etc_path)
+                    .with_context(|| format!("Failed to copy {:?} to {:?}.", &path, &etc_path))?;
+            }
+        }
+        for link in find_links_to(&run_path)? {
+            log::info!("Re-linking {:?} to {:?}.", &link, &etc_path);
+            if dry_run {
+                continue;
+            }
+            fs::remove_file(&link).with_context(|| format!("Failed to remove {:?}.", &link))?;
+            symlink(&etc_path, &link)
+                .with_context(|| format!("Failed to link {:?} to {:?}.", &link, &etc_path))?;
+        }
+    }
+    Ok(())
+}
+
+fn find_links_to(target: &Path) -> Result<Vec<PathBuf>> {
+    let mut links = vec![];
+    for link in glob::glob("/etc/systemd/system/*.wants/*").with_context(|| "glob failed.")? {
+        let link = link?;
+        match fs::read_link(&link) {
+            Ok(link_to) if link_to == target => links.push(link),
+            _ => {}
+        }
+    }
+    Ok(links)
+}
+
+/// Distrod puts the WSL env vars such as WSL_INTEROP into /etc/environment so that sessions
+/// like sudo or ssh can run .exe files. With the native systemd, they are set only in the sessions
+/// WSL starts, so this script finds them for the other sessions.
+fn install_wsl_env_profile(dry_run: bool) -> Result<()> {
+    log::info!("Installing {}.", WSL_ENV_PROFILE_PATH);
+    if dry_run {
+        return Ok(());
+    }
+    let script = format!(
+        "# Installed by `distrod migrate-to-native`.\n\
+         if [ -z \"$WSL_INTEROP\" ]; then\n\
+         \x20   for socket in $(ls -t /run/WSL/*_interop 2> /dev/null); do\n\
+         \x20       export WSL_INTEROP=\"$socket\"\n\
+         \x20       break\n\
+         \x20   done\n\
+         fi\n\
+         case \":$PATH:\" in\n\
+         \x20   *:{bin_dir}:*) ;;\n\
+         \x20   *) export PATH=\"{bin_dir}:$PATH\" ;;\n\
+         esac\n",
+        bin_dir = distrod_config::get_distrod_bin_dir_path()
+    );
+    // The user's changes to the script installed by the last migration are kept.
+    ManagedFileUpdater::for_rootfs(&HostPath::new("/")?, ConflictPolicy::Ask)?
+        .update_content(
+            Path::new(WSL_ENV_PROFILE_PATH.trim_start_matches('/')),
+            script.as_bytes(),
+            Path::new(WSL_ENV_PROFILE_PATH),
+        )
+        .with_context(|| format!("Failed to update {}.", WSL_ENV_PROFILE_PATH))
+}
+
+/// The units which run portproxy.exe read WSL_INTEROP from /etc/environment, where only Distrod
+/// puts it. Each enabled one gets a drop-in with the socket WSL's init serves for systemd, so that
+/// the forwards in /opt/distrod/conf keep working.
+fn keep_port_forwards(dry_run: bool) -> Result<()> {
+    for unit in PORT_FORWARD_UNITS {
+        if !is_unit_enabled(unit)? {
+            continue;
+        }
+        let drop_in_path = Path::new("/etc/systemd/system")
+            .join(format!("{}.d", unit))
+            .join(WSL_INTEROP_DROP_IN_NAME);
+        if drop_in_path.exists() {
+            log::info!("{:?} already exists. Skipping it.", &drop_in_path);
+            continue;
+        }
+        log::info!(
+            "Installing {:?} to keep the port forwards of {}.",
+            &drop_in_path,
+            unit
+        );
+        if dry_run {
+            continue;
+        }
+        if let Some(dir) = drop_in_path.parent() {
+            fs::create_dir_all(dir).with_context(|| format!("Failed to create {:?}.", dir))?;
+        }
+        fs::write(
+            &drop_in_path,
+            "# Installed by `distrod migrate-to-native`.\n\
+             [Service]\n\
+             Environment=WSL_INTEROP=/run/WSL/1_interop\n",
+        )
+        .with_context(|| format!("Failed to write {:?}.", &drop_in_path))?;
+    }
+    Ok(())
+}
+
+fn is_unit_enabled(unit: &str) -> Result<bool> {
+    let pattern = format!("/etc/systemd/system/*.wants/{}", unit);
+    Ok(glob::glob(&pattern)
+        .with_context(|| "glob failed.")?
+        .any(|link| link.is_ok()))
+}
+
+/// The autostart task starts Distrod and the distros in [autostart], which don't run without
+/// Distrod as the init, so it's removed. A failure only warns, since the migration is done anyway.
+fn remove_autostart_task(dry_run: bool) {
+    log::info!("Removing the autostart task of Distrod on Windows if it's scheduled.");
+    if dry_run {
+        return;
+    }
+    let result = wsl_interop::get_distro_name()
+        .with_context(|| "Failed to get the distro name.")
+        .and_then(|name| autostart::disable_autostart_on_windows_boot(&name));
+    if let Err(e) = result {
+        log::warn!(
+            "Failed to remove the autostart task. Remove StartWSL_* in Task Scheduler of Windows \
+             by yourself. {:?}",
+            e
+        );
+    }
+}
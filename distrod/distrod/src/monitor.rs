@@ -0,0 +1,71 @@
+use anyhow::{anyhow, Context, Result};
+use std::process::Command;
+use structopt::StructOpt;
+
+use libs::distrod_config::DistrodConfig;
+use libs::threat_monitor::{Alert, ThreatMonitor};
+use libs::wsl_interop;
+
+#[derive(Debug, StructOpt)]
+#[structopt(rename_all = "kebab")]
+pub struct MonitorOpts {
+    /// Only log the alerts, without showing Windows notifications.
+    #[structopt(long)]
+    no_notify: bool,
+}
+
+pub fn run_monitor(opts: MonitorOpts) -> Result<()> {
+    let config = DistrodConfig::get().with_context(|| "Failed to get the Distrod config.")?;
+    let config = config.monitor.clone();
+    let notify_windows = config.notify_windows && !opts.no_notify;
+    log::info!(
+        "Monitoring the processes in {:?} and the outbound connections.",
+        &config.watched_dirs
+    );
+    let mut monitor = ThreatMonitor::new(config).with_context(|| "Failed to start the monitor.")?;
+    monitor.run(|alert| {
+        log::warn!("Suspicious process: {}", alert);
+        if notify_windows {
+            if let Err(e) = notify_on_windows(alert) {
+                log::warn!("Failed to show the Windows notification. {:?}", e);
+            }
+        }
+    })
+}
+
+fn notify_on_windows(alert: &Alert) -> Result<()> {
+    let c = wsl_interop::get_wsl_drive_path("C")?.ok_or_else(|| anyhow!("C drive not found."))?;
+    let distro_name = wsl_interop::get_distro_name().unwrap_or_else(|_| "WSL".to_owned());
+    let mut powershell =
+        Command::new(c.join("Windows/System32/WindowsPowerShell/v1.0/powershell.exe"));
+    powershell
+        .arg("-Command")
+        .arg(generate_notification_posh_command(
+            &format!("Distrod: suspicious process in {}", distro_name),
+            &alert.to_string(),
+        ));
+    let mut powershell = powershell
+        .spawn()
+        .with_context(|| "Failed to execute Powershell.")?;
+    // The balloon stays while Powershell runs, so don't block the monitor on it.
+    std::thread::spawn(move || powershell.wait());
+    Ok(())
+}
+
+fn generate_notification_posh_command(title: &str, message: &str) -> String {
+    format!(
+        "Add-Type -AssemblyName System.Windows.Forms; \
+         $n = New-Object System.Windows.Forms.NotifyIcon; \
+         $n.Icon = [System.Drawing.SystemIcons]::Warning; \
+         $n.Visible = $true; \
+         $n.ShowBalloonTip(30000, '{}', '{}', 'Warning'); \
+         Start-Sleep -Seconds 30; \
+         $n.Dispose()",
+        escape_posh_string(title),
+        escape_posh_string(message)
+    )
+}
+
+fn escape_posh_string(s: &str) -> String {
+    s.replace('\'', "''")
+}
@@ -0,0 +1,206 @@
+use anyhow::{bail, Result};
+use once_cell::sync::OnceCell;
+use serde_json::{Map, Value};
+use std::str::FromStr;
+
+pub const OUTPUT_FORMATS: &[&str] = &["table", "tsv", "json"];
+
+/// The format of the results the commands print.
+/// `table` is for humans, and `tsv` and `json` are for scripts such as PowerShell's ConvertFrom-Csv.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum OutputFormat {
+    Table,
+    Tsv,
+    Json,
+}
+
+impl FromStr for OutputFormat {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "table" => Ok(OutputFormat::Table),
+            "tsv" => Ok(OutputFormat::Tsv),
+            "json" => Ok(OutputFormat::Json),
+            _ => bail!("Unknown output format: '{}'.", s),
+        }
+    }
+}
+
+impl OutputFormat {
+    /// Whether the output is read by humans, so values can be shown in a friendly form such as "1.5 GiB".
+    pub fn is_human_readable(&self) -> bool {
+        *self == OutputFormat::Table
+    }
+}
+
+static GLOBAL_FORMAT: OnceCell<OutputFormat> = OnceCell::new();
+
+/// Sets the format given by the global `--output`, which the commands use without `--format`.
+pub fn set_global_format(format: OutputFormat) {
+    let _ = GLOBAL_FORMAT.set(format);
+}
+
+/// Returns the format given by `--format` of the command, or else by `--output`, or else `table`.
+pub fn resolve_format(format: Option<OutputFormat>) -> OutputFormat {
+    format
+        .or_else(|| GLOBAL_FORMAT.get().copied())
+        .unwrap_or(OutputFormat::Table)
+}
+
+/// Rows with a fixed set of columns, such as the list of distros.
+pub struct Table {
+    columns: Vec<&'static str>,
+    right_aligned: Vec<bool>,
+    rows: Vec<Vec<String>>,
+}
+
+impl Table {
+    pub fn new(columns: &[&'static str]) -> Table {
+        Table {
+            columns: columns.to_vec(),
+            right_aligned: vec![false; columns.len()],
+            rows: vec![],
+        }
+    }
+
+    pub fn align_right(&mut self, column: usize) -> &mut Self {
+        self.right_aligned[column] = true;
+        self
+    }
+
+    pub fn add_row(&mut self, row: Vec<String>) -> &mut Self {
+        debug_assert_eq!(self.columns.len(), row.len());
+        self.rows.push(row);
+        self
+    }
+
+    pub fn print(&self, format: OutputFormat) -> Result<()> {
+        match format {
+            OutputFormat::Table => self.print_table(),
+            OutputFormat::Tsv => {
+                let keys: Vec<_> = self.columns.iter().map(|column| to_key(column)).collect();
+                println!("{}", keys.join("\t"));
+                for row in &self.rows {
+                    let values: Vec<_> = row.iter().map(|value| escape_tsv(value)).collect();
+                    println!("{}", values.join("\t"));
+                }
+            }
+            OutputFormat::Json => {
+                let rows: Vec<_> = self
+                    .rows
+                    .iter()
+                    .map(|row| {
+                        let object: Map<_, _> = self
+                            .columns
+                            .iter()
+                            .zip(row)
+                            .map(|(column, value)| (to_key(column), Value::from(value.as_str())))
+                            .collect();
+                        Value::Object(object)
+                    })
+                    .collect();
+                println!("{}", serde_json::to_string_pretty(&rows)?);
+            }
+        }
+        Ok(())
+    }
+
+    fn print_table(&self) {
+        let widths: Vec<_> = self
+            .columns
+            .iter()
+            .enumerate()
+            .map(|(i, column)| {
+                self.rows
+                    .iter()
+                    .map(|row| row[i].chars().count())
+                    .chain(std::iter::once(column.chars().count()))
+                    .max()
+                    .unwrap_or(0)
+            })
+            .collect();
+        let format_line = |values: Vec<&str>| {
+            let cells: Vec<_> = values
+                .into_iter()
+                .enumerate()
+                .map(|(i, value)| {
+                    if self.right_aligned[i] {
+                        format!("{:>width$}", value, width = widths[i])
+                    } else {
+                        format!("{:<width$}", value, width = widths[i])
+                    }
+                })
+                .collect();
+            cells.join("  ").trim_end().to_owned()
+        };
+        println!("{}", format_line(self.columns.clone()));
+        for row in &self.rows {
+            println!("{}", format_line(row.iter().map(|s| s.as_str()).collect()));
+        }
+    }
+}
+
+/// A set of named values of a single object, such as the status of a distro.
+#[derive(Default)]
+pub struct Record {
+    fields: Vec<(&'static str, Option<String>)>,
+}
+
+impl Record {
+    pub fn new() -> Record {
+        Record::default()
+    }
+
+    /// Adds a field. A field without a value is omitted in the table format, but kept empty
+    /// in the other formats so that the schema is always the same.
+    pub fn add_field(&mut self, label: &'static str, value: Option<String>) -> &mut Self {
+        self.fields.push((label, value));
+        self
+    }
+
+    pub fn print(&self, format: OutputFormat) -> Result<()> {
+        match format {
+            OutputFormat::Table => {
+                for (label, value) in &self.fields {
+                    if let Some(value) = value {
+                        println!("{}: {}", label, value);
+                    }
+                }
+            }
+            OutputFormat::Tsv => {
+                let keys: Vec<_> = self.fields.iter().map(|(label, _)| to_key(label)).collect();
+                let values: Vec<_> = self
+                    .fields
+                    .iter()
+                    .map(|(_, value)| escape_tsv(value.as_deref().unwrap_or("")))
+                    .collect();
+                println!("{}", keys.join("\t"));
+                println!("{}", values.join("\t"));
+            }
+            OutputFormat::Json => {
+                let object: Map<_, _> = self
+                    .fields
+                    .iter()
+                    .map(|(label, value)| {
+                        (
+                            to_key(label),
+                            value.as_deref().map_or(Value::Null, Value::from),
+                        )
+                    })
+                    .collect();
+                println!("{}", serde_json::to_string_pretty(&Value::Object(object))?);
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Converts a label such as "Init PID" to a key for scripts such as "init_pid".
+fn to_key(label: &str) -> String {
+    label.to_lowercase().replace(' ', "_")
+}
+
+fn escape_tsv(value: &str) -> String {
+    value.replace(&['\t', '\n', '\r'][..], " ")
+}
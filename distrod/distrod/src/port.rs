@@ -0,0 +1,431 @@
+use anyhow::{anyhow, bail, Context, Result};
+use indicatif::HumanBytes;
+use libs::distrod_config::{self, DistrodConfig};
+use libs::port_forward::{self, PortForwardCommand, PortForwardRule, PortForwardRules, Protocol};
+use libs::port_usage::{self, PortUsage, PortUsageStats};
+use libs::wsl_interop;
+use std::collections::BTreeSet;
+use std::io::Write;
+use std::net::IpAddr;
+use std::path::Path;
+use std::process::{Child, Command, Stdio};
+use std::time::Duration;
+use structopt::StructOpt;
+
+use crate::output::{self, OutputFormat, Table, OUTPUT_FORMATS};
+
+#[derive(Debug, StructOpt)]
+pub enum PortOpts {
+    /// Show the bytes transferred through the forwarded ports.
+    Usage(PortUsageOpts),
+    /// Forward the ports which the services in WSL listen on from Windows as they come and go,
+    /// like the localhost forwarding of WSL.
+    Watch(PortWatchOpts),
+    /// Forward a port from Windows by the rules file, which portproxy.service applies within a
+    /// few seconds.
+    Add(PortAddOpts),
+    /// Stop forwarding a port from Windows.
+    Remove(PortRemoveOpts),
+    /// List the forwards portproxy.service keeps.
+    List(PortListOpts),
+}
+
+#[derive(Debug, StructOpt)]
+#[structopt(rename_all = "kebab")]
+pub struct PortUsageOpts {
+    /// Show the usage of each port in the current month, instead of the monthly summaries.
+    #[structopt(short, long)]
+    month: bool,
+
+    /// The output format. Defaults to the one of --output, or table.
+    #[structopt(long, possible_values = OUTPUT_FORMATS)]
+    format: Option<OutputFormat>,
+}
+
+#[derive(Debug, StructOpt)]
+#[structopt(rename_all = "kebab")]
+pub struct PortWatchOpts {
+    /// Print the changes of the forwards instead of running portproxy.exe.
+    #[structopt(long)]
+    dry_run: bool,
+}
+
+#[derive(Debug, StructOpt)]
+#[structopt(rename_all = "kebab")]
+pub struct PortAddOpts {
+    /// The port on Windows to listen on.
+    listen_port: u16,
+
+    /// The port to forward to. Defaults to the listen port.
+    #[structopt(long)]
+    to: Option<u16>,
+
+    /// The address to forward to. Defaults to the address of eth0 of WSL.
+    #[structopt(long)]
+    dest_address: Option<String>,
+
+    /// The address of the interface on Windows to listen on, such as 127.0.0.1.
+    #[structopt(long, default_value = "0.0.0.0")]
+    listen_address: String,
+
+    /// Forward UDP instead of TCP.
+    #[structopt(long)]
+    udp: bool,
+}
+
+#[derive(Debug, StructOpt)]
+#[structopt(rename_all = "kebab")]
+pub struct PortRemoveOpts {
+    listen_port: u16,
+
+    /// Remove only the forward on this address. Defaults to all the forwards of the port.
+    #[structopt(long)]
+    listen_address: Option<String>,
+
+    /// Remove the UDP forward instead of the TCP one.
+    #[structopt(long)]
+    udp: bool,
+}
+
+#[derive(Debug, StructOpt)]
+#[structopt(rename_all = "kebab")]
+pub struct PortListOpts {
+    /// The output format. Defaults to the one of --output, or table.
+    #[structopt(long, possible_values = OUTPUT_FORMATS)]
+    format: Option<OutputFormat>,
+}
+
+pub fn run_port_command(opts: PortOpts) -> Result<()> {
+    match opts {
+        PortOpts::Usage(usage_opts) => show_port_usage(usage_opts),
+        PortOpts::Watch(watch_opts) => watch_ports(watch_opts),
+        PortOpts::Add(add_opts) => add_forward(add_opts),
+        PortOpts::Remove(remove_opts) => remove_forward(remove_opts),
+        PortOpts::List(list_opts) => list_forwards(list_opts),
+    }
+}
+
+fn add_forward(opts: PortAddOpts) -> Result<()> {
+    let rule = PortForwardRule {
+        listen_port: opts.listen_port,
+        listen_address: opts.listen_address,
+        dest_address: opts.dest_address,
+        dest_port: opts.to,
+        protocol: if opts.udp {
+            Protocol::Udp
+        } else {
+            Protocol::Tcp
+        },
+    };
+    let forwarded_by_tcp4_ports = rule.protocol == Protocol::Tcp
+        && rule.listen_address == "0.0.0.0"
+        && read_tcp4_ports()?.contains(&rule.listen_port);
+    if forwarded_by_tcp4_ports {
+        bail!(
+            "{} is already forwarded by {}.",
+            rule.listen_port,
+            distrod_config::get_tcp4_ports_path()
+        );
+    }
+    let rules_path = distrod_config::get_port_forward_rules_path();
+    let mut rules = PortForwardRules::open(rules_path)?;
+    let listen = rule.listen_socket_address();
+    rules.add(rule)?;
+    rules.save(rules_path)?;
+    log::info!(
+        "Added the forward of {}. portproxy.service applies it within a few seconds.",
+        listen
+    );
+    Ok(())
+}
+
+fn remove_forward(opts: PortRemoveOpts) -> Result<()> {
+    let protocol = if opts.udp {
+        Protocol::Udp
+    } else {
+        Protocol::Tcp
+    };
+    let rules_path = distrod_config::get_port_forward_rules_path();
+    let mut rules = PortForwardRules::open(rules_path)?;
+    let removed = rules.remove(opts.listen_port, opts.listen_address.as_deref(), protocol);
+    if !removed.is_empty() {
+        rules.save(rules_path)?;
+        for rule in &removed {
+            log::info!("Removed the forward of {}.", rule.listen_socket_address());
+        }
+    }
+
+    let mut tcp4_ports = read_tcp4_ports()?;
+    let in_tcp4_ports = protocol == Protocol::Tcp
+        && opts
+            .listen_address
+            .as_deref()
+            .map_or(true, |address| address == "0.0.0.0")
+        && tcp4_ports.contains(&opts.listen_port);
+    if in_tcp4_ports {
+        tcp4_ports.retain(|port| *port != opts.listen_port);
+        write_tcp4_ports(&tcp4_ports)?;
+        log::info!(
+            "Removed {} from {}. Restart portproxy.service to apply it.",
+            opts.listen_port,
+            distrod_config::get_tcp4_ports_path()
+        );
+    } else if removed.is_empty() {
+        bail!("{} is not forwarded.", opts.listen_port);
+    }
+    Ok(())
+}
+
+fn list_forwards(opts: PortListOpts) -> Result<()> {
+    let mut table = Table::new(&["LISTEN", "DESTINATION", "PROTOCOL", "SOURCE"]);
+    for port in read_tcp4_ports()? {
+        table.add_row(vec![
+            format!("0.0.0.0:{}", port),
+            format!("WSL:{}", port),
+            "tcp".to_owned(),
+            "tcp4_ports".to_owned(),
+        ]);
+    }
+    let rules = PortForwardRules::open(distrod_config::get_port_forward_rules_path())?;
+    for rule in &rules.forwards {
+        table.add_row(vec![
+            rule.listen_socket_address(),
+            rule.dest_socket_address("WSL"),
+            rule.protocol.name().to_lowercase(),
+            "rules".to_owned(),
+        ]);
+    }
+    table.print(output::resolve_format(opts.format))
+}
+
+fn show_port_usage(opts: PortUsageOpts) -> Result<()> {
+    let format = output::resolve_format(opts.format);
+    let stats_path = distrod_config::get_port_usage_stats_path();
+    let stats =
+        PortUsageStats::open(stats_path).with_context(|| "Failed to open the port usage stats.")?;
+
+    let mut table = if opts.month {
+        let month = port_usage::get_current_month();
+        let mut table = Table::new(&["PORT", "IN", "OUT", "TOTAL"]);
+        if let Some(ports) = stats.get_month(&month) {
+            for (port, usage) in ports {
+                table.add_row(usage_row(port.to_string(), usage, format));
+            }
+        }
+        // The total row is only for humans. Scripts can sum up the rows.
+        if format.is_human_readable() {
+            table.add_row(usage_row(
+                "TOTAL".to_owned(),
+                &stats.get_month_total(&month),
+                format,
+            ));
+        }
+        table
+    } else {
+        let mut table = Table::new(&["MONTH", "IN", "OUT", "TOTAL"]);
+        for month in stats.months.keys() {
+            table.add_row(usage_row(
+                month.clone(),
+                &stats.get_month_total(month),
+                format,
+            ));
+        }
+        table
+    };
+    table.align_right(1).align_right(2).align_right(3);
+    table.print(format)
+}
+
+fn usage_row(key: String, usage: &PortUsage, format: OutputFormat) -> Vec<String> {
+    let format_bytes = |bytes: u64| {
+        if format.is_human_readable() {
+            HumanBytes(bytes).to_string()
+        } else {
+            bytes.to_string()
+        }
+    };
+    vec![
+        key,
+        format_bytes(usage.bytes_in),
+        format_bytes(usage.bytes_out),
+        format_bytes(usage.total()),
+    ]
+}
+
+/// Keeps portproxy.exe forwarding the listening ports other than the excluded ones and the ones
+/// portproxy.service listens on. portproxy.exe is started when there's a port to forward, and
+/// exits by itself when no connection has been made through the forwards for `idle_exit_sec`.
+/// It's started again when a port is listened on. This never returns unless portproxy.exe fails
+/// or it fails.
+fn watch_ports(opts: PortWatchOpts) -> Result<()> {
+    let config = DistrodConfig::get().with_context(|| "Failed to get the Distrod config.")?;
+    let config = config.port_discovery.clone();
+    if config.interval_sec == 0 {
+        bail!("interval_sec of [port_discovery] must be 1 or more.");
+    }
+    if config.listen_address.parse::<IpAddr>().is_err() {
+        bail!(
+            "Invalid listen_address of [port_discovery]: {}",
+            config.listen_address
+        );
+    }
+    let mut excluded_ports: BTreeSet<u16> = config.excluded_ports.iter().copied().collect();
+    excluded_ports.extend(read_static_ports()?);
+
+    let mut portproxy: Option<Child> = None;
+    log::info!("Watching the listening ports to forward them from Windows.");
+    let mut forwarded_ports = BTreeSet::new();
+    loop {
+        if let Some(ref mut running) = portproxy {
+            if let Some(status) = running
+                .try_wait()
+                .with_context(|| "Failed to wait for portproxy.exe.")?
+            {
+                if !status.success() {
+                    return Err(anyhow!("portproxy.exe exited with {}.", status));
+                }
+                log::info!(
+                    "portproxy.exe exited since no connection has been made for {} seconds.",
+                    config.idle_exit_sec
+                );
+                // forwarded_ports is kept, so that it's started again only by a new port.
+                portproxy = None;
+            }
+        }
+        let ports: BTreeSet<u16> = port_forward::collect_listening_tcp_ports()?
+            .difference(&excluded_ports)
+            .copied()
+            .collect();
+        if !opts.dry_run
+            && portproxy.is_none()
+            && ports.difference(&forwarded_ports).next().is_some()
+        {
+            log::info!("Starting portproxy.exe.");
+            portproxy = Some(spawn_dynamic_portproxy(
+                &config.listen_address,
+                config.idle_exit_sec,
+            )?);
+            // The new portproxy.exe forwards nothing yet.
+            forwarded_ports.clear();
+        }
+        for command in port_forward::diff_ports(&forwarded_ports, &ports) {
+            match portproxy {
+                Some(ref mut portproxy) => portproxy
+                    .stdin
+                    .as_mut()
+                    .expect("[BUG] the stdin of portproxy.exe is piped.")
+                    .write_all(command.to_line().as_bytes())
+                    .with_context(|| "Failed to pass the command to portproxy.exe.")?,
+                None if opts.dry_run => match command {
+                    PortForwardCommand::Add(port) => println!("Forward {}", port),
+                    PortForwardCommand::Remove(port) => println!("Stop forwarding {}", port),
+                },
+                None => {}
+            }
+        }
+        forwarded_ports = ports;
+        std::thread::sleep(Duration::from_secs(config.interval_sec));
+    }
+}
+
+fn spawn_dynamic_portproxy(listen_address: &str, idle_exit_sec: u64) -> Result<Child> {
+    let output = Command::new(format!(
+        "{}/portproxy",
+        distrod_config::get_distrod_bin_dir_path()
+    ))
+    .args(&["show", "ipv4"])
+    .output()
+    .with_context(|| "Failed to run portproxy show ipv4.")?;
+    if !output.status.success() {
+        bail!(
+            "portproxy show ipv4 exited with error. stderr: {}",
+            String::from_utf8_lossy(&output.stderr)
+        );
+    }
+    let dest_addr = String::from_utf8_lossy(&output.stdout).trim().to_owned();
+    // The usage stats are written by the Windows process, so pass their path in the Windows format.
+    let usage_stats_path = format!(
+        "{}\\port_usage.json",
+        wsl_interop::wsl_path_to_windows_path(Path::new(distrod_config::get_distrod_var_dir()))?
+    );
+    std::fs::create_dir_all(distrod_config::get_distrod_var_dir())
+        .with_context(|| "Failed to create the var directory of Distrod.")?;
+    let mut portproxy = Command::new(format!(
+        "{}/portproxy.exe",
+        distrod_config::get_distrod_bin_dir_path()
+    ));
+    portproxy.args(&[
+        "proxy",
+        &dest_addr,
+        "--dynamic",
+        "--dynamic-listen-address",
+        listen_address,
+        "--usage-stats",
+        &usage_stats_path,
+    ]);
+    if idle_exit_sec != 0 {
+        portproxy.args(&["--idle-exit-sec", &idle_exit_sec.to_string()]);
+    }
+    // The forwards found by the watch follow [firewall] of the rules file as well.
+    let rules = PortForwardRules::open(distrod_config::get_port_forward_rules_path())?;
+    let firewall_remote_addresses = rules
+        .firewall
+        .as_ref()
+        .and_then(|firewall| firewall.get_enabled_remote_addresses());
+    if let Some(remote_addresses) = firewall_remote_addresses {
+        portproxy.arg("--firewall");
+        for address in remote_addresses {
+            portproxy.args(&["--firewall-remote-address", &address]);
+        }
+    }
+    portproxy
+        .stdin(Stdio::piped())
+        .spawn()
+        .with_context(|| "Failed to run portproxy.exe.")
+}
+
+/// Returns the TCP ports on Windows which portproxy.service listens on by tcp4_ports and the
+/// rules file.
+fn read_static_ports() -> Result<Vec<u16>> {
+    let mut ports = read_tcp4_ports()?;
+    let rules = PortForwardRules::open(distrod_config::get_port_forward_rules_path())?;
+    ports.extend(
+        rules
+            .forwards
+            .iter()
+            .filter(|rule| rule.protocol == Protocol::Tcp)
+            .map(|rule| rule.listen_port),
+    );
+    Ok(ports)
+}
+
+/// Reads the ports in tcp4_ports, leaving out 0, which the default file has as a placeholder.
+fn read_tcp4_ports() -> Result<Vec<u16>> {
+    let path = distrod_config::get_tcp4_ports_path();
+    let content = match std::fs::read_to_string(path) {
+        Ok(content) => content,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => String::new(),
+        Err(e) => return Err(e).with_context(|| format!("Failed to read {}.", path)),
+    };
+    let ports = content
+        .split_whitespace()
+        .map(|port| {
+            port.parse::<u16>()
+                .with_context(|| format!("Invalid port in {}: {}", path, port))
+        })
+        .collect::<Result<Vec<_>>>()?;
+    Ok(ports.into_iter().filter(|port| *port != 0).collect())
+}
+
+fn write_tcp4_ports(ports: &[u16]) -> Result<()> {
+    let path = distrod_config::get_tcp4_ports_path();
+    // portproxy.service takes at least one port, and skips 0.
+    let content = if ports.is_empty() {
+        "0".to_owned()
+    } else {
+        let ports: Vec<String> = ports.iter().map(|port| port.to_string()).collect();
+        ports.join(" ")
+    };
+    std::fs::write(path, format!("{}\n", content))
+        .with_context(|| format!("Failed to write {}.", path))
+}
@@ -0,0 +1,60 @@
+use anyhow::{Context, Result};
+use libs::distro::DistroLauncher;
+use libs::error::DistroError;
+use libs::process_list;
+use std::path::Path;
+use structopt::StructOpt;
+
+use crate::output::{self, OutputFormat, Table, OUTPUT_FORMATS};
+
+#[derive(Debug, StructOpt)]
+#[structopt(rename_all = "kebab")]
+pub struct PsOpts {
+    #[structopt(long)]
+    distro: Option<String>,
+
+    /// Show only the processes of the systemd unit, such as nginx.service.
+    #[structopt(long)]
+    unit: Option<String>,
+
+    /// The output format. Defaults to the one of --output, or table.
+    #[structopt(long, possible_values = OUTPUT_FORMATS)]
+    format: Option<OutputFormat>,
+}
+
+pub fn show_processes(opts: PsOpts) -> Result<()> {
+    let format = output::resolve_format(opts.format);
+    let distro = DistroLauncher::get_running_distro_by_name(opts.distro.as_deref())
+        .with_context(|| "Failed to get the running distro.")?
+        .ok_or_else(|| DistroError::NotRunning {
+            name: opts.distro.clone(),
+        })?;
+    // The /proc mounted in the distro shows only its processes, with the PIDs seen in it.
+    let processes =
+        process_list::list_processes(distro.get_path_in_running_distro(Path::new("/proc")))
+            .with_context(|| "Failed to list the processes of the distro.")?;
+    let user_names =
+        process_list::get_user_names(distro.get_path_in_running_distro(Path::new("/etc/passwd")))
+            .unwrap_or_else(|e| {
+                log::warn!("Failed to read /etc/passwd of the distro. {:?}", e);
+                Default::default()
+            });
+
+    let mut table = Table::new(&["PID", "USER", "UNIT", "COMMAND"]);
+    for process in processes {
+        if opts.unit.is_some() && process.unit != opts.unit {
+            continue;
+        }
+        table.add_row(vec![
+            process.pid.to_string(),
+            user_names
+                .get(&process.uid)
+                .cloned()
+                .unwrap_or_else(|| process.uid.to_string()),
+            process.unit.unwrap_or_default(),
+            process.command,
+        ]);
+    }
+    table.align_right(0);
+    table.print(format)
+}
@@ -0,0 +1,234 @@
+use anyhow::{anyhow, bail, Context, Result};
+use std::fs::{self, Permissions};
+use std::os::unix::fs::PermissionsExt;
+use std::os::unix::net::{UnixListener, UnixStream};
+use std::path::{Path, PathBuf};
+use std::process::{Command, Stdio};
+use structopt::StructOpt;
+
+use libs::distro::DistroLauncher;
+use libs::distrod_config;
+use libs::socket_relay::{relay_streams, remove_stale_socket};
+use libs::wsl_interop;
+
+#[derive(Debug, StructOpt)]
+pub enum RelayOpts {
+    /// Expose a named pipe of Windows as a Unix socket in the distro, such as
+    /// \\.\pipe\docker_engine as /var/run/docker.sock.
+    PipeToSocket(PipeToSocketOpts),
+    /// Expose an Assuan socket of Windows as a Unix socket in the distro, such as the one of
+    /// gpg-agent of Gpg4win, which listens on a TCP port instead of a named pipe.
+    AssuanToSocket(AssuanToSocketOpts),
+    /// Expose a Unix socket in the distro as a named pipe of Windows, such as the one of
+    /// ssh-agent.
+    SocketToPipe(SocketToPipeOpts),
+    /// Relay stdin and stdout to a Unix socket in the running distro. This is run by
+    /// portproxy.exe on WSL for each client of the named pipe of socket-to-pipe.
+    Connect(RelayConnectOpts),
+}
+
+#[derive(Debug, StructOpt)]
+#[structopt(rename_all = "kebab")]
+pub struct PipeToSocketOpts {
+    /// The named pipe, such as \\.\pipe\docker_engine.
+    #[structopt(long)]
+    pipe: String,
+
+    /// The Unix socket to create.
+    #[structopt(long)]
+    socket: PathBuf,
+
+    /// The permission of the socket in octal.
+    #[structopt(long, default_value = "660")]
+    socket_mode: String,
+}
+
+#[derive(Debug, StructOpt)]
+#[structopt(rename_all = "kebab")]
+pub struct AssuanToSocketOpts {
+    /// The Assuan socket file on Windows, such as
+    /// C:\Users\you\AppData\Local\gnupg\S.gpg-agent, which `gpgconf --list-dirs agent-socket`
+    /// shows.
+    #[structopt(long)]
+    assuan_file: String,
+
+    /// The Unix socket to create.
+    #[structopt(long)]
+    socket: PathBuf,
+
+    /// The permission of the socket in octal.
+    #[structopt(long, default_value = "600")]
+    socket_mode: String,
+}
+
+#[derive(Debug, StructOpt)]
+#[structopt(rename_all = "kebab")]
+pub struct SocketToPipeOpts {
+    /// The absolute path of the Unix socket in the distro.
+    #[structopt(long)]
+    socket: PathBuf,
+
+    /// The named pipe to create, such as \\.\pipe\openssh-ssh-agent.
+    #[structopt(long)]
+    pipe: String,
+
+    /// The distro which has the socket. Defaults to the default distro.
+    #[structopt(long)]
+    distro: Option<String>,
+}
+
+#[derive(Debug, StructOpt)]
+#[structopt(rename_all = "kebab")]
+pub struct RelayConnectOpts {
+    /// The absolute path of the Unix socket in the distro.
+    socket: PathBuf,
+
+    #[structopt(long)]
+    distro: Option<String>,
+}
+
+pub fn run_relay_command(opts: RelayOpts) -> Result<()> {
+    match opts {
+        RelayOpts::PipeToSocket(pipe_to_socket_opts) => relay_pipe_to_socket(pipe_to_socket_opts),
+        RelayOpts::AssuanToSocket(assuan_to_socket_opts) => {
+            relay_assuan_to_socket(assuan_to_socket_opts)
+        }
+        RelayOpts::SocketToPipe(socket_to_pipe_opts) => relay_socket_to_pipe(socket_to_pipe_opts),
+        RelayOpts::Connect(connect_opts) => relay_connect(connect_opts),
+    }
+}
+
+/// Listens on the socket, and connects each client to the pipe by a portproxy.exe of its own.
+/// This never returns unless it fails.
+fn relay_pipe_to_socket(opts: PipeToSocketOpts) -> Result<()> {
+    serve_socket_by_portproxy(
+        &opts.socket,
+        &opts.socket_mode,
+        &opts.pipe,
+        vec!["pipe-connect".to_owned(), opts.pipe.clone()],
+    )
+}
+
+/// Listens on the socket, and connects each client to the Assuan socket by a portproxy.exe of its
+/// own, which reads the port and the nonce from the file each time, since they change when the
+/// server restarts. This never returns unless it fails.
+fn relay_assuan_to_socket(opts: AssuanToSocketOpts) -> Result<()> {
+    serve_socket_by_portproxy(
+        &opts.socket,
+        &opts.socket_mode,
+        &opts.assuan_file,
+        vec!["assuan-connect".to_owned(), opts.assuan_file.clone()],
+    )
+}
+
+/// Listens on the socket, and relays each client to the stdin and stdout of a new portproxy.exe
+/// run with `portproxy_args`, which connects to `target` on Windows.
+fn serve_socket_by_portproxy(
+    socket: &Path,
+    socket_mode: &str,
+    target: &str,
+    portproxy_args: Vec<String>,
+) -> Result<()> {
+    let mode = u32::from_str_radix(socket_mode, 8)
+        .with_context(|| format!("Invalid socket mode: {}", socket_mode))?;
+    remove_stale_socket(socket)?;
+    let listener =
+        UnixListener::bind(socket).with_context(|| format!("Failed to bind {:?}.", socket))?;
+    fs::set_permissions(socket, Permissions::from_mode(mode))
+        .with_context(|| format!("Failed to change the mode of {:?}.", socket))?;
+    log::info!("Relaying {:?} to {}.", socket, target);
+    for stream in listener.incoming() {
+        let stream = stream.with_context(|| format!("Failed to accept on {:?}.", socket))?;
+        let target = target.to_owned();
+        let portproxy_args = portproxy_args.clone();
+        std::thread::spawn(move || {
+            if let Err(e) = relay_to_portproxy(stream, &portproxy_args) {
+                log::warn!("The relay to {} failed. {:?}", &target, e);
+            }
+        });
+    }
+    Ok(())
+}
+
+fn relay_to_portproxy(stream: UnixStream, portproxy_args: &[String]) -> Result<()> {
+    let mut portproxy = Command::new(format!(
+        "{}/portproxy.exe",
+        distrod_config::get_distrod_bin_dir_path()
+    ))
+    .args(portproxy_args)
+    .stdin(Stdio::piped())
+    .stdout(Stdio::piped())
+    .spawn()
+    .with_context(|| "Failed to run portproxy.exe.")?;
+    let stdin = portproxy
+        .stdin
+        .take()
+        .expect("[BUG] the stdin of portproxy.exe is piped.");
+    let stdout = portproxy
+        .stdout
+        .take()
+        .expect("[BUG] the stdout of portproxy.exe is piped.");
+    let result = relay_streams(stream, stdin, stdout);
+    let status = portproxy
+        .wait()
+        .with_context(|| "Failed to wait for portproxy.exe.")?;
+    if !status.success() {
+        bail!("portproxy.exe exited with {}.", status);
+    }
+    result
+}
+
+/// Creates the pipe by portproxy.exe, which runs `distrod relay connect` on WSL for each client.
+/// This never returns unless portproxy.exe exits.
+fn relay_socket_to_pipe(opts: SocketToPipeOpts) -> Result<()> {
+    if !opts.socket.is_absolute() {
+        bail!("The socket must be an absolute path in the distro.");
+    }
+    let wsl_distro_name = wsl_interop::get_distro_name()?;
+    let socket = opts
+        .socket
+        .to_str()
+        .ok_or_else(|| anyhow!("The socket path is not UTF-8: {:?}", &opts.socket))?;
+    let distrod_path = format!("{}/distrod", distrod_config::get_distrod_bin_dir_path());
+    let mut connect_command = vec![
+        "wsl.exe",
+        "-d",
+        wsl_distro_name.as_str(),
+        "-u",
+        "root",
+        "-e",
+        distrod_path.as_str(),
+        "relay",
+        "connect",
+        socket,
+    ];
+    if let Some(ref distro) = opts.distro {
+        connect_command.extend(&["--distro", distro.as_str()]);
+    }
+    log::info!("Relaying {} to {:?}.", &opts.pipe, &opts.socket);
+    let status = Command::new(format!(
+        "{}/portproxy.exe",
+        distrod_config::get_distrod_bin_dir_path()
+    ))
+    .args(&["pipe-serve", &opts.pipe, "--"])
+    .args(&connect_command)
+    .status()
+    .with_context(|| "Failed to run portproxy.exe.")?;
+    Err(anyhow!("portproxy.exe exited with {}.", status))
+}
+
+/// Connects to the socket through the root of the init of the distro, since this runs on WSL
+/// outside the namespaces of the distro.
+fn relay_connect(opts: RelayConnectOpts) -> Result<()> {
+    let socket = opts
+        .socket
+        .strip_prefix("/")
+        .with_context(|| "The socket must be an absolute path in the distro.")?;
+    let distro = DistroLauncher::get_running_distro_by_name(opts.distro.as_deref())
+        .with_context(|| "Failed to get the running distro.")?
+        .ok_or_else(|| anyhow!("The distro is not running."))?;
+    let path = PathBuf::from(format!("/proc/{}/root", distro.get_init_pid())).join(socket);
+    let stream =
+        UnixStream::connect(&path).with_context(|| format!("Failed to connect to {:?}.", &path))?;
+    relay_streams(stream, std::io::stdout(), std::io::stdin())
+}
@@ -0,0 +1,225 @@
+use anyhow::{Context, Result};
+use libs::cancellation;
+use libs::container_org_image::parse_image_reference;
+use libs::distro::DistroLauncher;
+use libs::distro_registry;
+use libs::rootfs_storage;
+use nix::errno::Errno;
+use nix::fcntl::{flock, FlockArg};
+use std::ffi::OsString;
+use std::fs::{File, OpenOptions};
+use std::os::unix::io::AsRawFd;
+use std::path::{Path, PathBuf};
+use structopt::StructOpt;
+
+use distrod_core::{CancellationToken, CreateOptions, ImageSource, StartOptions};
+
+use crate::{build_stop_options, exec_env, get_current_exe, ExecOpts};
+
+/// The directory under the directory of the named distros where the images of `distrod run` are
+/// kept. `distrod list` doesn't show it, since a distro name never starts with '.'.
+const RUN_IMAGES_DIR_NAME: &str = ".run-images";
+
+#[derive(Debug, StructOpt)]
+#[structopt(rename_all = "kebab")]
+pub struct RunOpts {
+    /// The image of linuxcontainers.org, such as ubuntu:22.04, debian/bookworm, or archlinux.
+    image: String,
+
+    /// The command to run. Defaults to `/bin/sh -l`.
+    command: Option<OsString>,
+    args: Vec<String>,
+
+    /// Download the image again even if it has been used before.
+    #[structopt(long)]
+    pull: bool,
+
+    /// The name or the uid of the user to run the command as, in the login environment of the
+    /// user.
+    #[structopt(short, long)]
+    user: Option<String>,
+
+    /// The working directory of the command. A Windows path such as `C:\src\proj` is translated
+    /// into the path where WSL mounts it. Defaults to the root directory.
+    #[structopt(short, long, visible_alias = "workdir")]
+    working_directory: Option<OsString>,
+
+    /// Bind-mount a directory or file into the distro by SOURCE:TARGET[:ro|:rw], such as
+    /// /mnt/c/src/proj:/src, as `distrod start --mount` does.
+    #[structopt(long, number_of_values = 1)]
+    mount: Vec<String>,
+
+    /// Don't wait for systemd of the distro to finish booting before running the command.
+    #[structopt(long)]
+    no_wait: bool,
+
+    /// Run the command on a new pseudo terminal, as `distrod exec --tty` does.
+    #[structopt(short, long)]
+    tty: bool,
+
+    /// Run the command with the stdio of this process as it is, without a pseudo terminal.
+    #[structopt(short = "T", long, conflicts_with = "tty")]
+    no_tty: bool,
+
+    #[structopt(flatten)]
+    env: exec_env::ExecEnvOpts,
+}
+
+/// Starts a throwaway distro of the image, runs the command in it, and stops it, which
+/// discards all the changes the command has made. Exits with the exit code of the command.
+pub fn run_ephemeral_distro(opts: RunOpts) -> Result<()> {
+    // The lock is kept until the process exits, after the distro has stopped.
+    let (rootfs, _image_lock) = get_run_image(&opts.image, opts.pull)?;
+    let name = format!("run-{}", std::process::id());
+    let mut start_opts = StartOptions::new();
+    start_opts
+        .with_name(&name)
+        .with_rootfs(&rootfs)
+        .with_ephemeral();
+    for mount in &opts.mount {
+        start_opts.with_mount(mount);
+    }
+    start_opts.with_distrod_bin(get_current_exe()?);
+    distrod_core::start(&start_opts, &CancellationToken::new())
+        .with_context(|| format!("Failed to start {:?} as {}.", &rootfs, &name))?;
+
+    let result = run_command(&name, opts);
+    if let Err(e) = stop_ephemeral_distro(&name) {
+        log::warn!("Failed to stop {}. {:?}", &name, e);
+    }
+    std::process::exit(result? as i32)
+}
+
+fn run_command(name: &str, opts: RunOpts) -> Result<u32> {
+    if !opts.no_wait {
+        let distro = DistroLauncher::get_running_distro_by_name(Some(name))
+            .with_context(|| "Failed to get the running distro.")?
+            .with_context(|| format!("{} stopped right after it started.", name))?;
+        crate::shell::wait_until_booted(&distro)?;
+    }
+    let (command, args) = match opts.command {
+        Some(command) => (command, opts.args),
+        None => ("/bin/sh".into(), vec!["-l".to_owned()]),
+    };
+    crate::run_exec_command(ExecOpts {
+        command,
+        args,
+        arg0: None,
+        user: opts.user,
+        uid: None,
+        working_directory: opts.working_directory,
+        rootfs: None,
+        distro: Some(name.to_owned()),
+        no_broker: false,
+        seccomp_profile: None,
+        tty: opts.tty,
+        no_tty: opts.no_tty,
+        env: opts.env,
+    })
+}
+
+fn stop_ephemeral_distro(name: &str) -> Result<()> {
+    // Nothing in the distro is worth a clean shutdown, since the changes are thrown away.
+    let stop_opts = build_stop_options(Some(name), true, None)?;
+    distrod_core::stop(&stop_opts)?;
+    log::debug!("{} has been stopped and discarded.", name);
+    Ok(())
+}
+
+/// Returns the rootfs of the image, downloading and unpacking it if it hasn't been used yet or
+/// `pull` is given, and the lock which keeps the other runs from replacing it while it's used.
+#[tokio::main]
+async fn get_run_image(reference: &str, pull: bool) -> Result<(PathBuf, ImageLock)> {
+    let path = parse_image_reference(reference)?;
+    let image_name = path.replace('/', "-");
+    let images_dir = distro_registry::get_instances_dir()?.join(RUN_IMAGES_DIR_NAME);
+    std::fs::create_dir_all(&images_dir)
+        .with_context(|| format!("Failed to make a directory: {:?}.", &images_dir))?;
+    let rootfs = images_dir.join(&image_name);
+    let lock = ImageLock::open(&images_dir, &image_name)?;
+    if !pull {
+        lock.lock_shared()?;
+        if rootfs.exists() {
+            log::debug!("Using the image at {:?}.", &rootfs);
+            return Ok((rootfs, lock));
+        }
+    }
+    lock.lock_exclusive()?;
+    // Another run may have downloaded it while this one waited for the lock.
+    if rootfs.exists() && !pull {
+        lock.lock_shared()?;
+        return Ok((rootfs, lock));
+    }
+
+    // The image is unpacked next to it and renamed, so that an interrupted download never
+    // leaves an incomplete image in place.
+    let new_rootfs = images_dir.join(format!(".{}.new", &image_name));
+    if new_rootfs.exists() {
+        rootfs_storage::remove_rootfs_dir(&new_rootfs)
+            .with_context(|| format!("Failed to remove {:?}.", &new_rootfs))?;
+    }
+    let mut create_opts = CreateOptions::new(ImageSource::ContainerOrg(path.clone()));
+    create_opts
+        .with_name(&image_name)
+        .with_install_dir(&new_rootfs)
+        .with_progress_bar();
+    let cancel = CancellationToken::new();
+    cancellation::run_on_ctrl_c({
+        let cancel = cancel.clone();
+        move || cancel.cancel()
+    });
+    distrod_core::create(&create_opts, &cancel).await?;
+    replace_rootfs(&new_rootfs, &rootfs)?;
+    // Replacing it again is blocked until this run ends, while the other runs can use it.
+    lock.lock_shared()?;
+    Ok((rootfs, lock))
+}
+
+/// The flock of an image of `distrod run`. The runs share it while they use the image, and a
+/// download takes it exclusively, since it replaces the image and unpacks it in the staging
+/// directory which the downloads of the image share.
+struct ImageLock {
+    file: File,
+}
+
+impl ImageLock {
+    fn open(images_dir: &Path, image_name: &str) -> Result<ImageLock> {
+        let path = images_dir.join(format!(".{}.lock", image_name));
+        let file = OpenOptions::new()
+            .create(true)
+            .write(true)
+            .open(&path)
+            .with_context(|| format!("Failed to open {:?}.", &path))?;
+        Ok(ImageLock { file })
+    }
+
+    fn lock_shared(&self) -> Result<()> {
+        self.lock(FlockArg::LockSharedNonblock, FlockArg::LockShared)
+    }
+
+    /// Converting a shared lock to the exclusive one isn't atomic, so check the image again
+    /// after this.
+    fn lock_exclusive(&self) -> Result<()> {
+        self.lock(FlockArg::LockExclusiveNonblock, FlockArg::LockExclusive)
+    }
+
+    fn lock(&self, nonblocking: FlockArg, blocking: FlockArg) -> Result<()> {
+        match flock(self.file.as_raw_fd(), nonblocking) {
+            Err(nix::Error::Sys(Errno::EWOULDBLOCK)) => {
+                log::info!("Waiting for the other runs of the image...");
+                flock(self.file.as_raw_fd(), blocking)
+            }
+            result => result,
+        }
+        .with_context(|| "Failed to lock the image.")
+    }
+}
+
+fn replace_rootfs(new_rootfs: &Path, rootfs: &Path) -> Result<()> {
+    if rootfs.exists() {
+        rootfs_storage::remove_rootfs_dir(rootfs)
+            .with_context(|| format!("Failed to remove the old image at {:?}.", rootfs))?;
+    }
+    std::fs::rename(new_rootfs, rootfs)
+        .with_context(|| format!("Failed to rename {:?} to {:?}.", new_rootfs, rootfs))
+}
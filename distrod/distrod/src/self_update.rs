@@ -0,0 +1,374 @@
+use anyhow::{anyhow, bail, Context, Result};
+use flate2::read::GzDecoder;
+use libs::arch::Arch;
+use libs::cancellation::{self, CancellationToken};
+use libs::cli_ui::build_progress_bar;
+use libs::distro_image::download_file_with_progress;
+use libs::distrod_config;
+use libs::managed_file::{ConflictPolicy, ManagedFileUpdater};
+use libs::template::Template;
+use libs::wsl_interop;
+use serde::Deserialize;
+use std::fs::{self, File};
+use std::io::{BufWriter, Write};
+use std::path::{Path, PathBuf};
+use std::process::Command;
+use structopt::StructOpt;
+
+const LATEST_RELEASE_API_URL: &str =
+    "https://api.github.com/repos/nullpo-head/wsl-distrod/releases/latest";
+
+#[derive(Debug, StructOpt)]
+#[structopt(rename_all = "kebab")]
+pub struct SelfUpdateOpts {
+    /// Only check if a new version is available.
+    #[structopt(long)]
+    check: bool,
+
+    /// Update even if the installed version is the latest.
+    #[structopt(long)]
+    force: bool,
+
+    /// The Windows path of distrod_wsl_launcher.exe to update as well. e.g. 'C:\Users\you\distrod_wsl_launcher.exe'
+    #[structopt(long)]
+    launcher_path: Option<String>,
+
+    /// Keep your version of the config files you have changed, instead of asking which to take.
+    /// The new versions are saved with the .distrod-new suffix.
+    #[structopt(long, conflicts_with = "theirs")]
+    ours: bool,
+
+    /// Take the new version of the config files you have changed, instead of asking which to take.
+    /// Your versions are saved with the .distrod-old suffix.
+    #[structopt(long)]
+    theirs: bool,
+}
+
+#[derive(Debug, Deserialize)]
+struct Release {
+    tag_name: String,
+    assets: Vec<ReleaseAsset>,
+}
+
+#[derive(Debug, Deserialize)]
+struct ReleaseAsset {
+    name: String,
+    browser_download_url: String,
+}
+
+#[tokio::main]
+pub async fn self_update(opts: SelfUpdateOpts) -> Result<()> {
+    let release = fetch_latest_release()
+        .await
+        .with_context(|| "Failed to get the latest release.")?;
+    let latest_version = parse_version(&release.tag_name)?;
+    let current_version = parse_version(env!("CARGO_PKG_VERSION"))?;
+    if latest_version <= current_version && !opts.force {
+        log::info!(
+            "Distrod is up to date. (version {})",
+            env!("CARGO_PKG_VERSION")
+        );
+        return Ok(());
+    }
+    log::info!(
+        "Distrod {} is available. The installed version is {}.",
+        &release.tag_name,
+        env!("CARGO_PKG_VERSION")
+    );
+    if opts.check {
+        return Ok(());
+    }
+
+    let cancel = CancellationToken::new();
+    cancellation::cancel_on_ctrl_c(&cancel);
+
+    // Make the work dir next to the install dir so that the new files can be renamed into it.
+    let distrod_root = Path::new(distrod_config::get_distrod_root_dir());
+    let work_dir = tempfile::Builder::new()
+        .prefix(".distrod-update")
+        .tempdir_in(distrod_root.parent().unwrap_or_else(|| Path::new("/")))
+        .with_context(|| "Failed to create a work directory.")?;
+
+    // Download and verify everything before replacing anything.
+    let linux_archive =
+        download_verified_asset(&release, get_linux_asset_name(), work_dir.path(), &cancel).await?;
+    let launcher_archive = match opts.launcher_path {
+        Some(_) => Some(
+            download_verified_asset(
+                &release,
+                &get_launcher_asset_name(),
+                work_dir.path(),
+                &cancel,
+            )
+            .await?,
+        ),
+        None => None,
+    };
+
+    log::info!("Installing the new Distrod...");
+    let staging_dir = work_dir.path().join("opt_distrod");
+    unpack_tar_gz(&linux_archive, &staging_dir, &cancel)
+        .with_context(|| format!("Failed to unpack {:?}.", &linux_archive))?;
+    cancel.check()?;
+    let policy = match (opts.ours, opts.theirs) {
+        (true, _) => ConflictPolicy::Ours,
+        (_, true) => ConflictPolicy::Theirs,
+        _ => ConflictPolicy::Ask,
+    };
+    swap_in_new_files(
+        &staging_dir,
+        distrod_root,
+        Path::new(""),
+        &ManagedFileUpdater::new(policy),
+    )
+    .with_context(|| "Failed to replace the files of Distrod.")?;
+    run_post_update_script()?;
+
+    if let (Some(launcher_path), Some(launcher_archive)) = (opts.launcher_path, launcher_archive) {
+        log::info!("Updating the launcher at {}...", &launcher_path);
+        update_launcher(&launcher_archive, &launcher_path)
+            .with_context(|| "Failed to update the launcher.")?;
+    }
+    log::info!("Distrod has been updated to {}!", &release.tag_name);
+    Ok(())
+}
+
+async fn fetch_latest_release() -> Result<Release> {
+    // GitHub API rejects requests without User-Agent.
+    let client = reqwest::Client::builder()
+        .user_agent(concat!("distrod/", env!("CARGO_PKG_VERSION")))
+        .build()?;
+    let body = client
+        .get(LATEST_RELEASE_API_URL)
+        .send()
+        .await
+        .with_context(|| format!("Failed to get {}.", LATEST_RELEASE_API_URL))?
+        .error_for_status()?
+        .text()
+        .await?;
+    serde_json::from_str(&body).with_context(|| "Failed to parse the release information.")
+}
+
+fn parse_version(version: &str) -> Result<(u32, u32, u32)> {
+    let numbers: Vec<u32> = version
+        .trim_start_matches('v')
+        .split('.')
+        .map(|n| n.parse::<u32>())
+        .collect::<std::result::Result<_, _>>()
+        .with_context(|| format!("Invalid version: '{}'.", version))?;
+    match numbers.as_slice() {
+        [major, minor, patch] => Ok((*major, *minor, *patch)),
+        _ => bail!("Invalid version: '{}'.", version),
+    }
+}
+
+/// The asset of x86_64 keeps the name it had before the releases for aarch64.
+fn get_linux_asset_name() -> &'static str {
+    match Arch::current() {
+        Arch::X86_64 => "opt_distrod.tar.gz",
+        Arch::Aarch64 => "opt_distrod-aarch64.tar.gz",
+    }
+}
+
+fn get_launcher_asset_name() -> String {
+    format!("distrod_wsl_launcher-{}.zip", Arch::current().name())
+}
+
+async fn download_verified_asset(
+    release: &Release,
+    asset_name: &str,
+    work_dir: &Path,
+    cancel: &CancellationToken,
+) -> Result<PathBuf> {
+    let find_asset = |name: &str| {
+        release
+            .assets
+            .iter()
+            .find(|asset| asset.name == name)
+            .ok_or_else(|| {
+                anyhow!(
+                    "{} is not found in the release {}.",
+                    name,
+                    &release.tag_name
+                )
+            })
+    };
+    let asset = find_asset(asset_name)?;
+    let checksum_asset = find_asset(&format!("{}.sha256", asset_name))
+        .with_context(|| "Refusing to install a file that cannot be verified.")?;
+
+    let mut checksum = vec![];
+    download_file_with_progress(
+        &checksum_asset.browser_download_url,
+        |_| indicatif::ProgressBar::hidden(),
+        &mut checksum,
+        cancel,
+    )
+    .await
+    .with_context(|| format!("Failed to download the checksum of {}.", asset_name))?;
+    let expected_checksum = String::from_utf8_lossy(&checksum)
+        .split_whitespace()
+        .next()
+        .ok_or_else(|| anyhow!("The checksum file of {} is empty.", asset_name))?
+        .to_lowercase();
+
+    log::info!("Downloading {}...", asset_name);
+    let path = work_dir.join(asset_name);
+    let mut file = BufWriter::new(
+        File::create(&path).with_context(|| format!("Failed to create {:?}.", &path))?,
+    );
+    download_file_with_progress(
+        &asset.browser_download_url,
+        build_progress_bar,
+        &mut file,
+        cancel,
+    )
+    .await?;
+    file.flush()
+        .with_context(|| format!("Failed to write {:?}.", &path))?;
+    drop(file);
+
+    let actual_checksum = compute_sha256(&path)?;
+    if actual_checksum != expected_checksum {
+        bail!(
+            "The checksum of {} doesn't match. expected: {}, actual: {}",
+            asset_name,
+            expected_checksum,
+            actual_checksum
+        );
+    }
+    log::debug!("Verified the checksum of {}.", asset_name);
+    Ok(path)
+}
+
+fn compute_sha256(path: &Path) -> Result<String> {
+    let mut sha256sum = Command::new("sha256sum");
+    sha256sum.arg(path);
+    let output = sha256sum
+        .output()
+        .with_context(|| "Failed to execute sha256sum.")?;
+    if !output.status.success() {
+        bail!(
+            "sha256sum exited with error. stderr: {}",
+            String::from_utf8_lossy(&output.stderr)
+        );
+    }
+    Ok(String::from_utf8_lossy(&output.stdout)
+        .split_whitespace()
+        .next()
+        .ok_or_else(|| anyhow!("sha256sum has written nothing."))?
+        .to_lowercase())
+}
+
+fn unpack_tar_gz(archive_path: &Path, dest: &Path, cancel: &CancellationToken) -> Result<()> {
+    let archive_file =
+        File::open(archive_path).with_context(|| format!("Failed to open {:?}.", archive_path))?;
+    let mut archive = tar::Archive::new(GzDecoder::new(archive_file));
+    archive.set_preserve_permissions(true);
+    fs::create_dir_all(dest).with_context(|| format!("Failed to create {:?}.", dest))?;
+    for entry in archive.entries()? {
+        cancel.check()?;
+        let mut entry = entry?;
+        entry
+            .unpack_in(dest)
+            .with_context(|| format!("Failed to unpack {:?}.", entry.path()))?;
+    }
+    Ok(())
+}
+
+/// Moves the files in `new_dir` into `dest_dir` one by one by rename, so that running binaries
+/// are replaced safely. The user's configuration files in conf/ and the units in
+/// run/systemd/system/ are updated by `updater`, which keeps the user's changes.
+fn swap_in_new_files(
+    new_dir: &Path,
+    dest_dir: &Path,
+    rel_path: &Path,
+    updater: &ManagedFileUpdater,
+) -> Result<()> {
+    let new_path = new_dir.join(rel_path);
+    for entry in
+        fs::read_dir(&new_path).with_context(|| format!("Failed to read {:?}.", &new_path))?
+    {
+        let entry = entry?;
+        let rel_entry_path = rel_path.join(entry.file_name());
+        let dest_path = dest_dir.join(&rel_entry_path);
+        if rel_entry_path.starts_with("var/baseline") {
+            // The baselines are updated by the updater along with the files.
+            continue;
+        }
+        if entry.file_type()?.is_dir() {
+            fs::create_dir_all(&dest_path)
+                .with_context(|| format!("Failed to create {:?}.", &dest_path))?;
+            swap_in_new_files(new_dir, dest_dir, &rel_entry_path, updater)?;
+            continue;
+        }
+        if rel_entry_path.starts_with("conf") || rel_entry_path.starts_with("run/systemd/system") {
+            updater
+                .update(&rel_entry_path, &entry.path(), &dest_path)
+                .with_context(|| format!("Failed to update {:?}.", &dest_path))?;
+            continue;
+        }
+        fs::rename(entry.path(), &dest_path)
+            .with_context(|| format!("Failed to rename {:?} to {:?}.", entry.path(), &dest_path))?;
+    }
+    Ok(())
+}
+
+fn run_post_update_script() -> Result<()> {
+    let post_update =
+        Path::new(distrod_config::get_distrod_root_dir()).join("misc/distrod-post-update");
+    if !post_update.exists() {
+        return Ok(());
+    }
+    log::info!("Running post-update actions...");
+    let status = Command::new(&post_update)
+        .status()
+        .with_context(|| format!("Failed to run {:?}.", &post_update))?;
+    if !status.success() {
+        bail!("{:?} exited with error. {}", &post_update, status);
+    }
+    Ok(())
+}
+
+fn update_launcher(archive: &Path, launcher_path: &str) -> Result<()> {
+    let c = wsl_interop::get_wsl_drive_path("C")?.ok_or_else(|| anyhow!("C drive not found."))?;
+
+    let mut wslpath = Command::new("/bin/wslpath");
+    wslpath.args(&["-w".as_ref(), archive.as_os_str()]);
+    let archive_win_path = wslpath
+        .output()
+        .with_context(|| format!("Failed to execute wslpath -w {:?}", archive))?;
+    if !archive_win_path.status.success() {
+        bail!(
+            "wslpath -w {:?} exited with error. stderr: {}",
+            archive,
+            String::from_utf8_lossy(&archive_win_path.stderr)
+        );
+    }
+    let archive_win_path = String::from_utf8_lossy(&archive_win_path.stdout)
+        .trim()
+        .to_owned();
+
+    let bytes = include_bytes!("../resources/update_launcher.ps1");
+    let mut update_ps = Template::new(String::from_utf8_lossy(bytes).into_owned());
+    // The paths are put in single-quoted strings, where PowerShell expands nothing such as `$`.
+    update_ps
+        .assign("LAUNCHER_PATH", &escape_posh_string(launcher_path))
+        .assign("ZIP_PATH", &escape_posh_string(&archive_win_path));
+
+    let mut powershell =
+        Command::new(c.join("Windows/System32/WindowsPowerShell/v1.0/powershell.exe"));
+    log::trace!("powershell command:\n{}", update_ps.render());
+    powershell.arg("-Command").arg(update_ps.render());
+    let status = powershell
+        .status()
+        .with_context(|| "Failed to execute Powershell.")?;
+    if !status.success() {
+        bail!("Powershell failed. {}", status);
+    }
+    Ok(())
+}
+
+fn escape_posh_string(s: &str) -> String {
+    s.replace('\'', "''")
+}
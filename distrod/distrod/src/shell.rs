@@ -0,0 +1,105 @@
+use anyhow::{bail, Context, Result};
+use libs::container::{ContainerPath, HostPath};
+use libs::distro::{self, Distro, DistroLauncher};
+use libs::init_system::InitSystem;
+use libs::multifork::set_noninheritable_sig_ign;
+use libs::passwd::PasswdFile;
+use std::time::Duration;
+use structopt::StructOpt;
+
+use crate::{launch_distro, ResourceLimitOpts, StartOpts};
+
+/// systemctl can wait for systemd by itself, but the other inits are polled up to this time.
+const OTHER_INIT_READY_TIMEOUT: Duration = Duration::from_secs(60);
+
+#[derive(Debug, StructOpt)]
+#[structopt(rename_all = "kebab")]
+pub struct ShellOpts {
+    /// The user to log in as. Defaults to `user.default` of the distro config, the user who runs
+    /// distrod by sudo, or root.
+    #[structopt(short, long)]
+    user: Option<String>,
+
+    #[structopt(long)]
+    distro: Option<String>,
+}
+
+/// Starts the distro if it's not running, waits for systemd to finish booting, and runs
+/// the login shell of the user in the home directory.
+pub fn run_shell(opts: ShellOpts) -> Result<()> {
+    let running_distro = DistroLauncher::get_running_distro_by_name(opts.distro.as_deref())
+        .with_context(|| "Failed to get the running distro.")?;
+    let distro = match running_distro {
+        Some(distro) => distro,
+        None => {
+            launch_distro(StartOpts {
+                rootfs: None,
+                distro: opts.distro.clone(),
+                target: None,
+                ephemeral: false,
+                read_only: false,
+                mount: vec![],
+                dns: vec![],
+                dns_search: vec![],
+                limits: ResourceLimitOpts::default(),
+                no_wizard: false,
+            })?;
+            DistroLauncher::get_running_distro_by_name(opts.distro.as_deref())
+                .with_context(|| "Failed to get the running distro.")?
+                .with_context(|| "The distro stopped right after it started.")?
+        }
+    };
+
+    let rootfs = HostPath::new(distro.get_rootfs())?;
+    let default_user = distro::get_distro_config(&rootfs)
+        .with_context(|| "Failed to read the config of the distro.")?
+        .user
+        .default;
+    let user = opts
+        .user
+        .or(default_user)
+        .or_else(|| std::env::var("SUDO_USER").ok())
+        .unwrap_or_else(|| "root".to_owned());
+    let passwd_path = ContainerPath::new("/etc/passwd")?.to_host_path(&rootfs);
+    let mut passwd_file = PasswdFile::open(passwd_path.as_path())
+        .with_context(|| format!("Failed to open {:?}.", &passwd_path))?;
+    if passwd_file.get_ent_by_name(&user)?.is_none() {
+        bail!("The user '{}' doesn't exist in the distro.", &user);
+    }
+
+    wait_until_booted(&distro)?;
+
+    // su sets up the supplementary groups and the environment of a login shell, which loads
+    // the WSL environment variables by the profile script of Distrod.
+    set_noninheritable_sig_ign();
+    let mut waiter = distro.exec_command(
+        "su",
+        &["-", user.as_str()],
+        None::<&str>,
+        None::<&str>,
+        None,
+    )?;
+    let status = waiter.wait();
+    std::process::exit(status as i32)
+}
+
+/// Waits for the init of the distro to finish booting. It only warns if the init fails or
+/// doesn't finish in time, since the distro is still usable.
+pub fn wait_until_booted(distro: &Distro) -> Result<()> {
+    let init_system = distro.get_init_system()?;
+    log::debug!("Waiting for {} to finish booting.", init_system.name());
+    if init_system == InitSystem::Systemd {
+        match distro.exec_command_output("systemctl", &["is-system-running", "--wait"]) {
+            // "degraded" is not an error here.
+            Ok((_, state)) => log::debug!("The system state: {}", state.trim()),
+            Err(e) => log::warn!("Failed to wait for systemd. {:?}", e),
+        }
+    } else {
+        match distro.wait_until_ready(OTHER_INIT_READY_TIMEOUT) {
+            Ok(true) => {}
+            Ok(false) => log::warn!("{} didn't finish booting in time.", init_system.name()),
+            Err(e) => log::warn!("Failed to wait for {}. {:?}", init_system.name(), e),
+        }
+    }
+    Ok(())
+}
@@ -43,6 +43,47 @@ pub fn disable_default_shell_hook() -> Result<()> {
     Ok(())
 }
 
+/// Returns the users whose login shell `enable_default_shell_hook` has hooked, with the shells
+/// `disable_default_shell_hook` restores.
+pub fn list_default_shell_hooks() -> Result<Vec<(String, String)>> {
+    let mut passwd_file = PasswdFile::open("/etc/passwd")?;
+    let mut hooks = vec![];
+    for entry in passwd_file.entries() {
+        let entry = match entry {
+            Ok(entry) => entry,
+            // Such as the empty last line.
+            Err(_) => continue,
+        };
+        if !CommandAlias::is_alias(entry.shell) {
+            continue;
+        }
+        let alias = CommandAlias::open_from_link(entry.shell)?;
+        hooks.push((
+            entry.name.to_owned(),
+            alias.get_source_path().to_string_lossy().to_string(),
+        ));
+    }
+    Ok(hooks)
+}
+
+/// Removes the alias shells `enable_default_shell_hook` added to /etc/shells. Returns the removed
+/// lines.
+pub fn unregister_alias_shells_from_system(dry_run: bool) -> Result<Vec<String>> {
+    let shells =
+        std::fs::read_to_string("/etc/shells").with_context(|| "Failed to read /etc/shells")?;
+    let (aliases, kept): (Vec<&str>, Vec<&str>) = shells
+        .lines()
+        .partition(|line| CommandAlias::is_alias(line.trim()));
+    let aliases: Vec<String> = aliases.into_iter().map(|line| line.to_owned()).collect();
+    if aliases.is_empty() || dry_run {
+        return Ok(aliases);
+    }
+    let mut new_shells = kept.join("\n");
+    new_shells.push('\n');
+    std::fs::write("/etc/shells", new_shells).with_context(|| "Failed to write /etc/shells")?;
+    Ok(aliases)
+}
+
 fn register_shells_to_system(mut shell_paths: HashSet<String>) -> Result<()> {
     {
         let mut open_opts = std::fs::OpenOptions::new();
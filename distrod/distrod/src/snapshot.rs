@@ -0,0 +1,230 @@
+use anyhow::{bail, Context, Result};
+use std::path::PathBuf;
+use structopt::StructOpt;
+
+use libs::distro::DistroLauncher;
+use libs::distro_registry::{self, DistroInstance};
+use libs::error::DistroError;
+use libs::rootfs_image;
+use libs::rootfs_storage::{self, RootfsStorage};
+
+use crate::output::{self, OutputFormat, Table, OUTPUT_FORMATS};
+
+/// The directory of the snapshots under the directory of the named distros. It starts with '.',
+/// so that it's never taken for a distro.
+const SNAPSHOTS_DIR_NAME: &str = ".snapshots";
+
+#[derive(Debug, StructOpt)]
+pub enum SnapshotOpts {
+    /// Save the rootfs of the distro as a snapshot. A running distro is saved as of the moment,
+    /// as if it lost the power then.
+    Create(SnapshotCreateOpts),
+    List(SnapshotListOpts),
+    /// Replace the rootfs of the stopped distro with a copy of the snapshot, which is kept.
+    Restore(SnapshotNameOpts),
+    Delete(SnapshotNameOpts),
+}
+
+#[derive(Debug, StructOpt)]
+#[structopt(rename_all = "kebab")]
+pub struct SnapshotCreateOpts {
+    /// The name of the snapshot. Defaults to the current time, such as 20220402-153000.
+    name: Option<String>,
+
+    #[structopt(long)]
+    distro: String,
+}
+
+#[derive(Debug, StructOpt)]
+#[structopt(rename_all = "kebab")]
+pub struct SnapshotListOpts {
+    #[structopt(long)]
+    distro: String,
+
+    /// The output format. Defaults to the one of --output, or table.
+    #[structopt(long, possible_values = OUTPUT_FORMATS)]
+    format: Option<OutputFormat>,
+}
+
+#[derive(Debug, StructOpt)]
+#[structopt(rename_all = "kebab")]
+pub struct SnapshotNameOpts {
+    name: String,
+
+    #[structopt(long)]
+    distro: String,
+}
+
+#[derive(Debug, StructOpt)]
+#[structopt(rename_all = "kebab")]
+pub struct CloneOpts {
+    /// The distro to copy. A running distro is copied as of the moment, as if it lost the power
+    /// then.
+    source: String,
+
+    /// The name of the new distro.
+    name: String,
+}
+
+pub fn run_snapshot_command(opts: SnapshotOpts) -> Result<()> {
+    match opts {
+        SnapshotOpts::Create(create_opts) => create_snapshot(create_opts),
+        SnapshotOpts::List(list_opts) => list_snapshots(list_opts),
+        SnapshotOpts::Restore(restore_opts) => restore_snapshot(restore_opts),
+        SnapshotOpts::Delete(delete_opts) => delete_snapshot(delete_opts),
+    }
+}
+
+/// Copies a distro into a new one, which is instant if the distro is a btrfs subvolume.
+pub fn clone_distro(opts: CloneOpts) -> Result<()> {
+    let source = DistroInstance::get(&opts.source)?;
+    distro_registry::validate_instance_name(&opts.name)?;
+    let dest = distro_registry::get_instances_dir()?.join(&opts.name);
+    // A rootfs in a disk image is copied from the mounted image into a directory.
+    rootfs_image::mount_image(&source.rootfs)?;
+    log::info!("Copying {} to {}...", &opts.source, &opts.name);
+    let storage = rootfs_storage::copy_rootfs(&source.rootfs, &dest)
+        .with_context(|| format!("Failed to copy {} to {}.", &opts.source, &opts.name))?;
+    log::info!(
+        "{} is created at {:?} as {}.",
+        &opts.name,
+        &dest,
+        describe(storage)
+    );
+    Ok(())
+}
+
+fn create_snapshot(opts: SnapshotCreateOpts) -> Result<()> {
+    let distro = DistroInstance::get(&opts.distro)?;
+    let name = opts
+        .name
+        .unwrap_or_else(|| chrono::Local::now().format("%Y%m%d-%H%M%S").to_string());
+    let snapshot = get_snapshot_path(&distro, &name)?;
+    rootfs_image::mount_image(&distro.rootfs)?;
+    let storage = rootfs_storage::copy_rootfs(&distro.rootfs, &snapshot)
+        .with_context(|| format!("Failed to save the snapshot {}.", &name))?;
+    log::info!("Saved the snapshot {} as {}.", &name, describe(storage));
+    Ok(())
+}
+
+fn list_snapshots(opts: SnapshotListOpts) -> Result<()> {
+    let distro = DistroInstance::get(&opts.distro)?;
+    let mut table = Table::new(&["NAME", "STORAGE"]);
+    for (name, path) in read_snapshots(&distro)? {
+        table.add_row(vec![name, describe(RootfsStorage::of(path)?).to_owned()]);
+    }
+    table.print(output::resolve_format(opts.format))
+}
+
+/// Deletes the snapshots of the distro except the newest `keep` ones, and returns the names of
+/// the deleted ones.
+pub fn prune_snapshots(distro: &DistroInstance, keep: usize) -> Result<Vec<String>> {
+    let mut snapshots = vec![];
+    for (name, path) in read_snapshots(distro)? {
+        let modified = std::fs::metadata(&path)
+            .and_then(|metadata| metadata.modified())
+            .with_context(|| format!("Failed to stat {:?}.", &path))?;
+        snapshots.push((modified, name, path));
+    }
+    snapshots.sort_by(|a, b| b.0.cmp(&a.0));
+    let mut deleted = vec![];
+    for (_, name, path) in snapshots.into_iter().skip(keep) {
+        rootfs_storage::remove_rootfs_dir(&path)
+            .with_context(|| format!("Failed to delete the snapshot {}.", &name))?;
+        deleted.push(name);
+    }
+    Ok(deleted)
+}
+
+/// Returns the names and the paths of the snapshots of the distro sorted by the name.
+fn read_snapshots(distro: &DistroInstance) -> Result<Vec<(String, PathBuf)>> {
+    let dir = get_snapshots_dir(distro)?;
+    let mut snapshots = vec![];
+    if !dir.exists() {
+        return Ok(snapshots);
+    }
+    for entry in std::fs::read_dir(&dir).with_context(|| format!("Failed to read {:?}.", &dir))? {
+        let entry = entry.with_context(|| format!("Failed to read {:?}.", &dir))?;
+        if entry.path().is_dir() {
+            snapshots.push((
+                entry.file_name().to_string_lossy().into_owned(),
+                entry.path(),
+            ));
+        }
+    }
+    snapshots.sort();
+    Ok(snapshots)
+}
+
+fn restore_snapshot(opts: SnapshotNameOpts) -> Result<()> {
+    let distro = DistroInstance::get(&opts.distro)?;
+    if DistroLauncher::get_running_distro_by_name(Some(&distro.name))?.is_some() {
+        return Err(DistroError::AlreadyRunning {
+            name: Some(distro.name),
+        })
+        .with_context(|| "Stop the distro before restoring a snapshot.");
+    }
+    if rootfs_image::has_image(&distro.rootfs)? {
+        bail!(
+            "{} is stored in a disk image, which can't be restored from a snapshot.",
+            &distro.name
+        );
+    }
+    let snapshot = get_existing_snapshot_path(&distro, &opts.name)?;
+    // Copy the snapshot next to the rootfs first, so that a failure leaves the rootfs as it was.
+    let restoring = distro
+        .rootfs
+        .with_file_name(format!(".{}.restoring", &distro.name));
+    if restoring.exists() {
+        rootfs_storage::remove_rootfs_dir(&restoring)?;
+    }
+    rootfs_storage::copy_rootfs(&snapshot, &restoring)
+        .with_context(|| format!("Failed to copy the snapshot {}.", &opts.name))?;
+    rootfs_storage::remove_rootfs_dir(&distro.rootfs)
+        .with_context(|| "Failed to remove the current rootfs.")?;
+    std::fs::rename(&restoring, &distro.rootfs).with_context(|| {
+        format!(
+            "Failed to move the restored rootfs {:?} to {:?}.",
+            &restoring, &distro.rootfs
+        )
+    })?;
+    log::info!("Restored {} to the snapshot {}.", &distro.name, &opts.name);
+    Ok(())
+}
+
+fn delete_snapshot(opts: SnapshotNameOpts) -> Result<()> {
+    let distro = DistroInstance::get(&opts.distro)?;
+    let snapshot = get_existing_snapshot_path(&distro, &opts.name)?;
+    rootfs_storage::remove_rootfs_dir(&snapshot)
+        .with_context(|| format!("Failed to delete the snapshot {}.", &opts.name))?;
+    log::info!("Deleted the snapshot {}.", &opts.name);
+    Ok(())
+}
+
+/// The directory of the snapshots of the distro, which may not exist.
+pub fn get_snapshots_dir(distro: &DistroInstance) -> Result<PathBuf> {
+    Ok(distro_registry::get_instances_dir()?
+        .join(SNAPSHOTS_DIR_NAME)
+        .join(&distro.name))
+}
+
+fn get_snapshot_path(distro: &DistroInstance, name: &str) -> Result<PathBuf> {
+    distro_registry::validate_instance_name(name)
+        .with_context(|| format!("Invalid snapshot name: '{}'.", name))?;
+    Ok(get_snapshots_dir(distro)?.join(name))
+}
+
+fn get_existing_snapshot_path(distro: &DistroInstance, name: &str) -> Result<PathBuf> {
+    let path = get_snapshot_path(distro, name)?;
+    if !path.is_dir() {
+        bail!("{} has no snapshot named '{}'.", &distro.name, name);
+    }
+    Ok(path)
+}
+
+fn describe(storage: RootfsStorage) -> &'static str {
+    match storage {
+        RootfsStorage::BtrfsSubvolume => "a btrfs subvolume",
+        RootfsStorage::Directory => "a directory",
+    }
+}
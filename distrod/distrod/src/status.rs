@@ -0,0 +1,147 @@
+use anyhow::{bail, Context, Result};
+use distrod_core::DistroState;
+use libs::auto_update::AutoUpdateResult;
+use libs::container::HostPath;
+use libs::distro::{self, Distro, DistroLauncher};
+use libs::init_system::InitSystem;
+use nix::sys::socket::SockAddr;
+
+use crate::output::{OutputFormat, Record, Table};
+
+pub fn show_status(name: Option<&str>, format: OutputFormat) -> Result<()> {
+    let distro = DistroLauncher::get_running_distro_by_name(name)
+        .with_context(|| "Failed to get the running distro.")?;
+    let mut record = Record::new();
+    let distro = match distro {
+        None => {
+            record
+                .add_field("Status", Some("Stopped".to_owned()))
+                .add_field("Name", name.map(|name| name.to_owned()))
+                .add_field("Rootfs", None)
+                .add_field("Ephemeral", None)
+                .add_field("Init PID", None)
+                .add_field("Init", None)
+                .add_field("Systemd", None)
+                .add_field("Failed units", None)
+                .add_field("IP address", None)
+                .add_field("Auto update", None);
+            return record.print(format);
+        }
+        Some(distro) => distro,
+    };
+
+    let init_system = distro.get_init_system()?;
+    let (system_state, n_failed_units) = if init_system == InitSystem::Systemd {
+        let system_state =
+            get_system_state(&distro).with_context(|| "Failed to get the state of systemd.")?;
+        let n_failed_units =
+            count_failed_units(&distro).with_context(|| "Failed to get the failed units.")?;
+        (Some(system_state), Some(n_failed_units.to_string()))
+    } else {
+        (None, None)
+    };
+    let ip_addrs = get_ipv4_addrs().with_context(|| "Failed to get the IP addresses.")?;
+    record
+        .add_field("Status", Some("Running".to_owned()))
+        .add_field("Name", distro.get_name().map(|name| name.to_owned()))
+        .add_field(
+            "Rootfs",
+            Some(distro.get_rootfs().to_string_lossy().into_owned()),
+        )
+        .add_field(
+            "Ephemeral",
+            Some(if distro.is_ephemeral() { "yes" } else { "no" }.to_owned()),
+        )
+        .add_field("Init PID", Some(distro.get_init_pid().to_string()))
+        .add_field("Init", Some(init_system.name().to_owned()))
+        .add_field("Systemd", system_state)
+        .add_field("Failed units", n_failed_units)
+        .add_field(
+            "IP address",
+            Some(if ip_addrs.is_empty() {
+                "none".to_owned()
+            } else {
+                ip_addrs.join(", ")
+            }),
+        )
+        .add_field("Auto update", describe_auto_update(&distro));
+    record.print(format)
+}
+
+pub fn list_distros(format: OutputFormat) -> Result<()> {
+    let mut table = Table::new(&["NAME", "STATE", "ROOTFS"]);
+    for distro in distrod_core::list()? {
+        table.add_row(vec![
+            distro.name,
+            match distro.state {
+                DistroState::Running => "Running",
+                _ => "Stopped",
+            }
+            .to_owned(),
+            distro.rootfs.to_string_lossy().into_owned(),
+        ]);
+    }
+    table.print(format)
+}
+
+fn get_system_state(distro: &Distro) -> Result<String> {
+    // is-system-running exits with non-zero unless the state is "running", but it still prints
+    // the state such as "starting" or "degraded".
+    let (_, output) = distro.exec_command_output("systemctl", &["is-system-running"])?;
+    let state = output.trim();
+    if state.is_empty() {
+        return Ok("unknown".to_owned());
+    }
+    Ok(state.to_owned())
+}
+
+fn count_failed_units(distro: &Distro) -> Result<usize> {
+    let (exit_code, output) = distro.exec_command_output(
+        "systemctl",
+        &["list-units", "--state=failed", "--no-legend", "--plain"],
+    )?;
+    if exit_code != 0 {
+        bail!("systemctl list-units exited with {}.", exit_code);
+    }
+    Ok(output
+        .lines()
+        .filter(|line| !line.trim().is_empty())
+        .count())
+}
+
+/// Describes the result of the last automatic update, or whether it's enabled if it hasn't run.
+fn describe_auto_update(distro: &Distro) -> Option<String> {
+    let rootfs = HostPath::new(distro.get_rootfs()).ok()?;
+    let enabled = match distro::get_distro_config(&rootfs) {
+        Ok(config) => config.auto_update.enabled,
+        Err(e) => {
+            log::debug!("Failed to read the config of the distro. {:?}", e);
+            return None;
+        }
+    };
+    match AutoUpdateResult::load(&rootfs) {
+        Ok(Some(result)) if enabled => Some(result.describe()),
+        Ok(Some(result)) => Some(format!("disabled (last {})", result.describe())),
+        Ok(None) if enabled => Some("not run yet".to_owned()),
+        Ok(None) => Some("disabled".to_owned()),
+        Err(e) => {
+            log::debug!("Failed to read the result of the last update. {:?}", e);
+            None
+        }
+    }
+}
+
+fn get_ipv4_addrs() -> Result<Vec<String>> {
+    // The distro shares the network namespace with WSL, so the addresses of WSL are the
+    // addresses of the distro.
+    let addrs = nix::ifaddrs::getifaddrs().with_context(|| "getifaddrs failed.")?;
+    Ok(addrs
+        .filter(|ifaddr| ifaddr.interface_name != "lo")
+        .filter_map(|ifaddr| match ifaddr.address {
+            Some(SockAddr::Inet(addr)) if addr.ip().to_std().is_ipv4() => {
+                Some(format!("{} ({})", addr.ip(), ifaddr.interface_name))
+            }
+            _ => None,
+        })
+        .collect())
+}
@@ -0,0 +1,86 @@
+use anyhow::{bail, Context, Result};
+use libs::distro::{self, DistroLauncher};
+use libs::init_system::InitSystem;
+use structopt::StructOpt;
+
+#[derive(Debug, StructOpt)]
+pub enum TargetOpts {
+    /// Show the systemd target the distro boots into.
+    Get(TargetGetOpts),
+    /// Set the systemd target the distro boots into.
+    Set(TargetSetOpts),
+}
+
+#[derive(Debug, StructOpt)]
+#[structopt(rename_all = "kebab")]
+pub struct TargetGetOpts {
+    #[structopt(long)]
+    distro: Option<String>,
+}
+
+#[derive(Debug, StructOpt)]
+#[structopt(rename_all = "kebab")]
+pub struct TargetSetOpts {
+    /// A systemd target such as multi-user.target or distrod-session.target.
+    target: String,
+
+    #[structopt(long)]
+    distro: Option<String>,
+
+    /// Also switch the running distro to the target by `systemctl isolate`.
+    #[structopt(long)]
+    now: bool,
+}
+
+pub fn run_target_command(opts: TargetOpts) -> Result<()> {
+    match opts {
+        TargetOpts::Get(get_opts) => {
+            let rootfs = distro::get_distro_rootfs(get_opts.distro.as_deref())?;
+            println!("{}", distro::get_default_target(&rootfs)?);
+        }
+        TargetOpts::Set(set_opts) => set_target(set_opts)?,
+    }
+    Ok(())
+}
+
+fn set_target(opts: TargetSetOpts) -> Result<()> {
+    let rootfs = distro::get_distro_rootfs(opts.distro.as_deref())?;
+    let init_system = InitSystem::detect(&rootfs);
+    if init_system != InitSystem::Systemd {
+        bail!(
+            "The init of the distro is {}, which doesn't have systemd targets.",
+            init_system.name()
+        );
+    }
+    distro::set_default_target(&rootfs, &opts.target)
+        .with_context(|| format!("Failed to set the default target to {}.", &opts.target))?;
+    log::info!(
+        "The distro will boot into {} from the next start.",
+        &opts.target
+    );
+
+    if !opts.now {
+        return Ok(());
+    }
+    let running_distro = DistroLauncher::get_running_distro_by_name(opts.distro.as_deref())
+        .with_context(|| "Failed to get the running distro.")?;
+    let running_distro = match running_distro {
+        Some(distro) => distro,
+        None => {
+            log::info!("The distro is not running. It will boot into the target on start.");
+            return Ok(());
+        }
+    };
+    let (exit_code, _) = running_distro
+        .exec_command_output("systemctl", &["isolate", opts.target.as_str()])
+        .with_context(|| "Failed to run systemctl isolate.")?;
+    if exit_code != 0 {
+        bail!(
+            "systemctl isolate {} exited with {}.",
+            &opts.target,
+            exit_code
+        );
+    }
+    log::info!("Switched the running distro to {}.", &opts.target);
+    Ok(())
+}
@@ -0,0 +1,103 @@
+use anyhow::{bail, Context, Result};
+use std::time::Duration;
+use structopt::StructOpt;
+
+use libs::distrod_config::{DistrodConfig, TimeSyncConfig};
+use libs::time_sync::{self, ClockOffset};
+
+/// How long an NTP client is given to step the clock before Distrod steps it by itself.
+const TIME_SERVICE_GRACE_PERIOD: Duration = Duration::from_secs(10);
+
+#[derive(Debug, StructOpt)]
+#[structopt(rename_all = "kebab")]
+pub struct TimeSyncOpts {
+    /// Check the clock once and exit, instead of checking it every interval_sec.
+    #[structopt(long)]
+    once: bool,
+
+    /// Only log the offsets, without correcting the clock.
+    #[structopt(long)]
+    dry_run: bool,
+}
+
+/// Checks the clock against the one of Windows every interval_sec of [time_sync] in
+/// distrod.toml, and corrects it when it's off by more than max_offset_sec. The NTP client of
+/// the distro is asked first if it runs, since it would otherwise fight the correction.
+pub fn run_time_sync(opts: TimeSyncOpts) -> Result<()> {
+    let config = DistrodConfig::get().with_context(|| "Failed to get the Distrod config.")?;
+    let config = config.time_sync.clone();
+    // This is false for NaN as well.
+    let is_max_offset_positive = config.max_offset_sec > 0.0;
+    if !is_max_offset_positive || config.interval_sec == 0 {
+        bail!(
+            "max_offset_sec and interval_sec of [time_sync] must be positive, but they're {} \
+             and {}.",
+            config.max_offset_sec,
+            config.interval_sec
+        );
+    }
+    if opts.once {
+        return sync_clock(&config, opts.dry_run);
+    }
+    log::info!(
+        "Correcting the clock when it's off by more than {} seconds.",
+        config.max_offset_sec
+    );
+    loop {
+        if let Err(e) = sync_clock(&config, opts.dry_run) {
+            log::warn!("Failed to correct the clock. {:?}", e);
+        }
+        std::thread::sleep(Duration::from_secs(config.interval_sec));
+    }
+}
+
+fn sync_clock(config: &TimeSyncConfig, dry_run: bool) -> Result<()> {
+    let offset = time_sync::get_clock_offset()?;
+    if !is_off(&offset, config) {
+        log::debug!(
+            "The clock is off by {:.1} seconds from the {}.",
+            offset.seconds,
+            offset.reference
+        );
+        return Ok(());
+    }
+    log::info!(
+        "The clock is off by {:.1} seconds from the {}.",
+        offset.seconds,
+        offset.reference
+    );
+    if dry_run {
+        return Ok(());
+    }
+
+    let offset = match time_sync::find_active_time_service() {
+        None => offset,
+        Some(service) => {
+            log::info!("Asking {} to correct the clock.", service);
+            if let Err(e) = time_sync::trigger_time_service(service) {
+                log::warn!("Failed to ask {}. {:?}", service, e);
+            }
+            std::thread::sleep(TIME_SERVICE_GRACE_PERIOD);
+            let offset = time_sync::get_clock_offset()?;
+            if !is_off(&offset, config) {
+                log::info!("{} has corrected the clock.", service);
+                return Ok(());
+            }
+            // It may have no reachable server, such as while a VPN is connecting.
+            log::info!("{} hasn't corrected the clock.", service);
+            offset
+        }
+    };
+    time_sync::step_clock(offset.seconds)
+        .with_context(|| format!("Failed to step the clock by {:.1} seconds.", offset.seconds))?;
+    log::info!(
+        "Stepped the clock by {:.1} seconds to the {}.",
+        offset.seconds,
+        offset.reference
+    );
+    Ok(())
+}
+
+fn is_off(offset: &ClockOffset, config: &TimeSyncConfig) -> bool {
+    offset.seconds.abs() > config.max_offset_sec
+}
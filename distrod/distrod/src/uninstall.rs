@@ -0,0 +1,266 @@
+use anyhow::{bail, Context, Result};
+use libs::command_alias::CommandAlias;
+use libs::distrod_config;
+use libs::managed_file::ROOTFS_BASELINE_DIR;
+use libs::passwd::PasswdView;
+use libs::terminal_profile::FRAGMENT_APP_NAME;
+use libs::wsl_conf::{WslConf, WSL_CONF_PATH};
+use libs::wsl_interop;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use crate::{autostart, shell_hook};
+
+/// The files Distrod writes into /etc, which do nothing without Distrod.
+const INJECTED_FILES: &[&str] = &[
+    "/etc/profile.d/distrod-user-wsl-envs.sh",
+    "/etc/profile.d/distrod-wsl-env.sh",
+];
+
+const IFCFG_ETH0_PATH: &str = "/etc/sysconfig/network-scripts/ifcfg-eth0";
+const DISABLED_IFCFG_ETH0_PATH: &str =
+    "/etc/sysconfig/network-scripts/disabled-by-distrod.ifcfg-eth0";
+
+/// The keys of /etc/wsl.conf which Distrod and its launcher change on enabling, such as
+/// `boot.systemd` unset by `distrod_wsl_launcher migrate`.
+const RESTORED_WSL_CONF_KEYS: &[(&str, &str)] = &[("boot", "systemd"), ("boot", "command")];
+
+/// Keeps a copy of /etc/wsl.conf as it was before Distrod was enabled for the first time.
+pub fn back_up_wsl_conf() -> Result<()> {
+    let backup_path = Path::new(distrod_config::get_wsl_conf_backup_path());
+    if backup_path.exists() {
+        return Ok(());
+    }
+    let wsl_conf = Path::new(WSL_CONF_PATH);
+    if !wsl_conf.exists() {
+        // An empty backup tells the purge that the keys were not set.
+        return fs::write(backup_path, "")
+            .with_context(|| format!("Failed to create {:?}.", backup_path));
+    }
+    fs::copy(wsl_conf, backup_path)
+        .with_context(|| format!("Failed to copy {:?} to {:?}.", wsl_conf, backup_path))?;
+    Ok(())
+}
+
+/// Undoes what `distrod enable` and the launcher have done to the distro and Windows, so that
+/// the distro boots under WSL as it did before Distrod, and checks it does at the end.
+/// The units Distrod disabled or masked are left as they are, since they are harmless without
+/// systemd and incompatible with WSL even with the built-in systemd of WSL.
+pub fn purge_distrod(dry_run: bool) -> Result<()> {
+    for (user, shell) in shell_hook::list_default_shell_hooks()
+        .with_context(|| "Failed to find the login shells to restore.")?
+    {
+        log::info!(
+            "Restoring the login shell of {} to {} in /etc/passwd.",
+            user,
+            shell
+        );
+    }
+    log::info!("Removing the variables Distrod has put in the system environment.");
+    if !dry_run {
+        crate::disable_default_hooks()?;
+    }
+    for shell in shell_hook::unregister_alias_shells_from_system(dry_run)
+        .with_context(|| "Failed to clean up /etc/shells.")?
+    {
+        log::info!("Removing {} from /etc/shells.", shell);
+    }
+    remove_injected_files(dry_run).with_context(|| "Failed to remove the files in /etc.")?;
+    remove_links_to_distrod_units(dry_run)
+        .with_context(|| "Failed to remove the links to Distrod's units.")?;
+    restore_network_scripts(dry_run)
+        .with_context(|| format!("Failed to restore {}.", IFCFG_ETH0_PATH))?;
+    restore_wsl_conf(dry_run).with_context(|| format!("Failed to restore {}.", WSL_CONF_PATH))?;
+
+    let distro_name =
+        wsl_interop::get_distro_name().with_context(|| "Failed to get the distro name.")?;
+    log::info!("Deleting the task to start Distrod on Windows boot.");
+    if !dry_run {
+        if let Err(e) = autostart::disable_autostart_on_windows_boot(&distro_name) {
+            log::warn!("Failed to disable the autostart on Windows boot.: {:?}", e);
+        }
+    }
+    match get_terminal_fragment_path(&distro_name) {
+        Ok(Some(path)) => {
+            log::info!("Removing the profile of Windows Terminal {:?}.", &path);
+            if !dry_run {
+                fs::remove_file(&path).with_context(|| format!("Failed to remove {:?}.", &path))?;
+            }
+        }
+        Ok(None) => {}
+        Err(e) => log::warn!("Failed to find the profile of Windows Terminal. {:?}", e),
+    }
+
+    if dry_run {
+        log::info!("This is a dry run. Nothing has been changed.");
+        return Ok(());
+    }
+    let problems = find_boot_problems()?;
+    if !problems.is_empty() {
+        bail!(
+            "Distrod has been removed, but the distro may not boot under WSL.\n{}",
+            problems.join("\n")
+        );
+    }
+    log::info!(
+        "Distrod has been removed from the distro. Run `wsl.exe --terminate {}` on Windows to \
+         boot it without Distrod. Delete /opt/distrod if you don't need it anymore.",
+        &distro_name
+    );
+    Ok(())
+}
+
+fn remove_injected_files(dry_run: bool) -> Result<()> {
+    for path in INJECTED_FILES {
+        if !Path::new(path).exists() {
+            continue;
+        }
+        log::info!("Removing {}.", path);
+        if !dry_run {
+            fs::remove_file(path).with_context(|| format!("Failed to remove {}.", path))?;
+        }
+    }
+    // Without the files, the baselines would tell that the user has deleted them, which keeps
+    // them from being installed when Distrod is enabled again.
+    if Path::new(ROOTFS_BASELINE_DIR).exists() {
+        log::info!("Removing {}.", ROOTFS_BASELINE_DIR);
+        if !dry_run {
+            fs::remove_dir_all(ROOTFS_BASELINE_DIR)
+                .with_context(|| format!("Failed to remove {}.", ROOTFS_BASELINE_DIR))?;
+        }
+    }
+    Ok(())
+}
+
+/// Removes the links of the enabled units which point to the files Distrod mounts on /run, such
+/// as portproxy.service, which will be dangling without Distrod.
+fn remove_links_to_distrod_units(dry_run: bool) -> Result<()> {
+    let run_overlay_dir = Path::new(distrod_config::get_distrod_run_overlay_dir());
+    for link in glob::glob("/etc/systemd/system/*.wants/*").with_context(|| "glob failed.")? {
+        let link = link?;
+        let target = match fs::read_link(&link) {
+            Ok(target) => target,
+            Err(_) => continue,
+        };
+        let rel_path = match target.strip_prefix("/run") {
+            Ok(rel_path) => rel_path,
+            Err(_) => continue,
+        };
+        if !run_overlay_dir.join(rel_path).exists() {
+            continue;
+        }
+        log::info!("Removing {:?}, which links to {:?}.", &link, &target);
+        if !dry_run {
+            fs::remove_file(&link).with_context(|| format!("Failed to remove {:?}.", &link))?;
+        }
+    }
+    Ok(())
+}
+
+/// Puts back the network-scripts config which `distrod enable` renamed, unless a new one has
+/// been made since then.
+fn restore_network_scripts(dry_run: bool) -> Result<()> {
+    if !Path::new(DISABLED_IFCFG_ETH0_PATH).exists() || Path::new(IFCFG_ETH0_PATH).exists() {
+        return Ok(());
+    }
+    log::info!(
+        "Moving {} back to {}.",
+        DISABLED_IFCFG_ETH0_PATH,
+        IFCFG_ETH0_PATH
+    );
+    if !dry_run {
+        fs::rename(DISABLED_IFCFG_ETH0_PATH, IFCFG_ETH0_PATH)?;
+    }
+    Ok(())
+}
+
+/// Sets the boot keys of /etc/wsl.conf back to the ones before Distrod was enabled. The other
+/// keys, such as `user.default`, are kept, since the user may have changed them since then.
+fn restore_wsl_conf(dry_run: bool) -> Result<()> {
+    let backup_path = distrod_config::get_wsl_conf_backup_path();
+    if !Path::new(backup_path).exists() {
+        log::info!(
+            "{} was not backed up when Distrod was enabled. Leaving it as it is.",
+            WSL_CONF_PATH
+        );
+        return Ok(());
+    }
+    let backup = WslConf::open(backup_path)?;
+    let mut wsl_conf = WslConf::open(WSL_CONF_PATH)?;
+    let mut changed = false;
+    for (section, key) in RESTORED_WSL_CONF_KEYS {
+        let original = backup.get(section, key);
+        if wsl_conf.get(section, key) == original {
+            continue;
+        }
+        match original {
+            Some(value) => {
+                log::info!("Setting {}.{} back to '{}'.", section, key, &value);
+                wsl_conf.set(section, key, &value);
+            }
+            None => {
+                log::info!("Unsetting {}.{}.", section, key);
+                wsl_conf.unset(section, key);
+            }
+        }
+        changed = true;
+    }
+    if changed && !dry_run {
+        wsl_conf.write()?;
+    }
+    Ok(())
+}
+
+fn get_terminal_fragment_path(distro_name: &str) -> Result<Option<PathBuf>> {
+    let local_app_data = wsl_interop::get_windows_env_var("LOCALAPPDATA")?;
+    let local_app_data = match wsl_interop::windows_path_to_wsl_path(&local_app_data)? {
+        Some(path) => path,
+        None => return Ok(None),
+    };
+    let path = local_app_data
+        .join("Microsoft/Windows Terminal/Fragments")
+        .join(FRAGMENT_APP_NAME)
+        .join(format!("{}.json", distro_name));
+    Ok(if path.exists() { Some(path) } else { None })
+}
+
+/// Checks what WSL needs to boot the distro and log in to it without Distrod.
+fn find_boot_problems() -> Result<Vec<String>> {
+    let mut problems = vec![];
+    let passwd =
+        fs::read_to_string("/etc/passwd").with_context(|| "Failed to read /etc/passwd.")?;
+    for line in passwd.lines().filter(|line| !line.is_empty()) {
+        let entry = PasswdView::deserialize(line)?;
+        if CommandAlias::is_alias(entry.shell) {
+            problems.push(format!(
+                "The login shell of {} is still Distrod's {}.",
+                entry.name, entry.shell
+            ));
+        } else if !entry.shell.is_empty() && !Path::new(entry.shell).exists() {
+            problems.push(format!(
+                "The login shell of {}, {}, doesn't exist.",
+                entry.name, entry.shell
+            ));
+        }
+    }
+
+    let wsl_conf = WslConf::open(WSL_CONF_PATH)?;
+    problems.extend(wsl_conf.find_problems());
+    // WSL runs /sbin/init only if it starts systemd by itself.
+    if wsl_conf.get("boot", "systemd").as_deref() == Some("true")
+        && fs::metadata("/sbin/init").is_err()
+    {
+        problems.push(
+            "boot.systemd of /etc/wsl.conf is true, but /sbin/init doesn't exist.".to_owned(),
+        );
+    }
+
+    let environment = fs::read_to_string("/etc/environment").unwrap_or_default();
+    if environment.contains(distrod_config::get_distrod_bin_dir_path()) {
+        problems.push(format!(
+            "/etc/environment still has {}.",
+            distrod_config::get_distrod_bin_dir_path()
+        ));
+    }
+    Ok(problems)
+}
@@ -0,0 +1,148 @@
+use anyhow::{bail, Context, Result};
+use libs::container::HostPath;
+use libs::distro::{self, DistroLauncher};
+use libs::distro_config::validate_unit_name;
+use libs::init_system::InitSystem;
+use libs::systemdunit::SystemdUnitDisabler;
+use structopt::StructOpt;
+
+use crate::output::{self, OutputFormat, Table};
+
+#[derive(Debug, StructOpt)]
+pub enum UnitOpts {
+    /// Mask the units every time the distro starts, such as the ones which hang the boot.
+    Mask(UnitMaskOpts),
+    /// Stop masking the units, including the ones Distrod masks by default.
+    Unmask(UnitMaskOpts),
+    /// List the units Distrod masks on start and disables on create.
+    List(UnitListOpts),
+}
+
+#[derive(Debug, StructOpt)]
+#[structopt(rename_all = "kebab")]
+pub struct UnitMaskOpts {
+    /// The units such as snapd.service or NetworkManager-wait-online.service.
+    #[structopt(required = true)]
+    units: Vec<String>,
+
+    #[structopt(long)]
+    distro: Option<String>,
+}
+
+#[derive(Debug, StructOpt)]
+#[structopt(rename_all = "kebab")]
+pub struct UnitListOpts {
+    #[structopt(long)]
+    distro: Option<String>,
+
+    /// The output format. Defaults to the one of --output, or table.
+    #[structopt(long, possible_values = output::OUTPUT_FORMATS)]
+    format: Option<OutputFormat>,
+}
+
+pub fn run_unit_command(opts: UnitOpts) -> Result<()> {
+    match opts {
+        UnitOpts::Mask(mask_opts) => change_masks(mask_opts, true),
+        UnitOpts::Unmask(unmask_opts) => change_masks(unmask_opts, false),
+        UnitOpts::List(list_opts) => list_units(list_opts),
+    }
+}
+
+fn change_masks(opts: UnitMaskOpts, masks: bool) -> Result<()> {
+    for unit in &opts.units {
+        validate_unit_name(unit)?;
+    }
+    let rootfs = distro::get_distro_rootfs(opts.distro.as_deref())?;
+    let init_system = InitSystem::detect(&rootfs);
+    if init_system != InitSystem::Systemd {
+        bail!(
+            "The init of the distro is {}, which doesn't have systemd units.",
+            init_system.name()
+        );
+    }
+    let mut config = distro::get_distro_config(&rootfs)
+        .with_context(|| "Failed to read the config of the distro.")?;
+    let masked_units = &mut config.systemd.masked_units;
+    for unit in &opts.units {
+        let position = masked_units.iter().position(|masked| masked == unit);
+        match (masks, position) {
+            (true, None) => masked_units.push(unit.clone()),
+            (false, Some(position)) => {
+                masked_units.remove(position);
+            }
+            _ => {}
+        }
+    }
+    distro::set_distro_config(&rootfs, &config)
+        .with_context(|| "Failed to save the config of the distro.")?;
+
+    let running_distro = DistroLauncher::get_running_distro_by_name(opts.distro.as_deref())
+        .with_context(|| "Failed to get the running distro.")?;
+    match running_distro {
+        // systemctl reloads systemd as well, and sees the rootfs of an ephemeral distro.
+        Some(distro) => {
+            let command = if masks { "mask" } else { "unmask" };
+            let mut args = vec![command];
+            args.extend(opts.units.iter().map(|unit| unit.as_str()));
+            let (exit_code, _) = distro
+                .exec_command_output("systemctl", &args)
+                .with_context(|| format!("Failed to run systemctl {}.", command))?;
+            if exit_code != 0 {
+                bail!("systemctl {} exited with {}.", command, exit_code);
+            }
+        }
+        None => {
+            for unit in &opts.units {
+                let disabler = SystemdUnitDisabler::new(rootfs.as_path(), unit);
+                if masks {
+                    disabler.mask()
+                } else {
+                    disabler.unmask()
+                }
+                .with_context(|| format!("Failed to change the mask of {}.", unit))?;
+            }
+        }
+    }
+    if masks {
+        log::info!("Masked {}.", opts.units.join(", "));
+    } else {
+        log::info!(
+            "Unmasked {}. Enable them by systemctl in the distro if needed.",
+            opts.units.join(", ")
+        );
+    }
+    Ok(())
+}
+
+fn list_units(opts: UnitListOpts) -> Result<()> {
+    let rootfs = distro::get_distro_rootfs(opts.distro.as_deref())?;
+    let config = distro::get_distro_config(&rootfs)
+        .with_context(|| "Failed to read the config of the distro.")?;
+    let mut table = Table::new(&["UNIT", "ACTION", "MASKED"]);
+    for unit in &config.systemd.masked_units {
+        table.add_row(vec![
+            unit.clone(),
+            "mask on start".to_owned(),
+            describe_mask(&rootfs, unit),
+        ]);
+    }
+    for unit in &config.systemd.disabled_units {
+        table.add_row(vec![
+            unit.clone(),
+            "disable on create".to_owned(),
+            describe_mask(&rootfs, unit),
+        ]);
+    }
+    table.print(output::resolve_format(opts.format))
+}
+
+fn describe_mask(rootfs: &HostPath, unit: &str) -> String {
+    match SystemdUnitDisabler::new(rootfs.as_path(), unit).is_masked() {
+        Ok(true) => "yes".to_owned(),
+        Ok(false) => "no".to_owned(),
+        Err(e) => {
+            log::debug!("Failed to see if {} is masked. {:?}", unit, e);
+            "unknown".to_owned()
+        }
+    }
+}
@@ -0,0 +1,203 @@
+use anyhow::{Context, Result};
+use libs::cli_ui;
+use libs::container::{ContainerPath, HostPath};
+use libs::distro::{self, DistroLauncher};
+use libs::distro_config::DistroConfig;
+use libs::distrod_config::{AutostartDistroConfig, DistrodConfig};
+use libs::passwd::{Passwd, PasswdFile};
+use libs::userns;
+use std::fs;
+use std::os::unix::fs::symlink;
+
+use crate::create_user;
+
+/// The file `distrod create` puts in a new distro, which tells `distrod start` to run the wizard.
+/// It's removed once the wizard finishes.
+const WIZARD_MARKER_PATH: &str = "/etc/distrod/first-start-wizard";
+const PORT_WATCH_WANTS_LINK_PATH: &str =
+    "/etc/systemd/system/multi-user.target.wants/distrod-port-watch.service";
+
+/// Marks a new distro to run the wizard on its first interactive start.
+pub fn put_wizard_marker(rootfs: &HostPath) -> Result<()> {
+    let marker_path = ContainerPath::new(WIZARD_MARKER_PATH)?.to_host_path(rootfs);
+    if let Some(dir) = marker_path.parent() {
+        fs::create_dir_all(dir).with_context(|| format!("Failed to create {:?}.", dir))?;
+    }
+    fs::write(marker_path.as_path(), "")
+        .with_context(|| format!("Failed to write {:?}.", &marker_path))
+}
+
+/// Asks how to set up the distro if it starts for the first time after `distrod create`. Each step
+/// is skipped if it's already set up, such as by `distrod create --user`. The wizard is left for
+/// the next start if nobody can answer it.
+pub fn run_first_start_wizard(name: Option<&str>) -> Result<()> {
+    if DistroLauncher::get_running_distro_by_name(name)
+        .with_context(|| "Failed to get the running distro.")?
+        .is_some()
+    {
+        return Ok(());
+    }
+    let rootfs = distro::get_distro_rootfs(name)?;
+    let marker_path = ContainerPath::new(WIZARD_MARKER_PATH)?.to_host_path(&rootfs);
+    if !marker_path.exists() || !cli_ui::is_interactive() || userns::is_rootless_mode() {
+        return Ok(());
+    }
+    log::info!(
+        "This is the first start of the distro. Answer a few questions to set it up, or press \
+         enter to skip each of them. Give --no-wizard to `distrod start` not to be asked."
+    );
+
+    set_up_default_user(&rootfs)?;
+    let mut config = distro::get_distro_config(&rootfs)
+        .with_context(|| "Failed to read the config of the distro.")?;
+    if !config.locale.sync_windows_timezone
+        && cli_ui::prompt_yes_no("Use the time zone of Windows in the distro?")?
+    {
+        config.locale.sync_windows_timezone = true;
+        distro::set_distro_config(&rootfs, &config)
+            .with_context(|| "Failed to save the config of the distro.")?;
+    }
+    set_up_default_shell(&rootfs, &config)?;
+    if let Some(name) = name {
+        set_up_autostart(name)?;
+    }
+    set_up_port_watch(&rootfs)?;
+
+    fs::remove_file(marker_path.as_path())
+        .with_context(|| format!("Failed to remove {:?}.", &marker_path))?;
+    log::info!("The distro is set up. Change it later by `distrod config`.");
+    Ok(())
+}
+
+fn set_up_default_user(rootfs: &HostPath) -> Result<()> {
+    let config = distro::get_distro_config(rootfs)
+        .with_context(|| "Failed to read the config of the distro.")?;
+    if config.user.default.is_some() {
+        return Ok(());
+    }
+    let name = cli_ui::prompt_string(
+        "Create the default user, who can use sudo? Leave it empty to use only root.",
+        "the user name",
+        None,
+    )?;
+    if name.is_empty() {
+        return Ok(());
+    }
+    create_user::create_default_user(rootfs, &name, None, true, false)
+        .with_context(|| format!("Failed to create the user {}.", &name))?;
+    log::info!("{} is the default user of the distro.", &name);
+    Ok(())
+}
+
+fn set_up_default_shell(rootfs: &HostPath, config: &DistroConfig) -> Result<()> {
+    let user = config.user.default.as_deref().unwrap_or("root");
+    let passwd_path = ContainerPath::new("/etc/passwd")?.to_host_path(rootfs);
+    let mut passwd_file = PasswdFile::open(passwd_path.as_path())?;
+    let current_shell = match passwd_file.get_ent_by_name(user)? {
+        Some(entry) => entry.shell.to_owned(),
+        None => return Ok(()),
+    };
+    let shells = list_login_shells(rootfs)?;
+    if shells.len() < 2 {
+        return Ok(());
+    }
+    let shell = cli_ui::prompt_string(
+        &format!(
+            "Choose the login shell of {} from: {}",
+            user,
+            shells.join(", ")
+        ),
+        "the shell",
+        Some(&current_shell),
+    )?;
+    let shell = if shell.is_empty() {
+        current_shell.clone()
+    } else {
+        shell
+    };
+    if shell == current_shell {
+        return Ok(());
+    }
+    if !shells.contains(&shell) {
+        log::warn!(
+            "{} is not in /etc/shells of the distro. The login shell is not changed.",
+            &shell
+        );
+        return Ok(());
+    }
+    passwd_file.update(&mut |passwd| {
+        if passwd.name != user {
+            return Ok(None);
+        }
+        let mut new_passwd = Passwd::from_view(passwd);
+        new_passwd.shell = shell.clone();
+        Ok(Some(new_passwd))
+    })?;
+    log::info!("The login shell of {} is {}.", user, &shell);
+    Ok(())
+}
+
+/// Lists the shells in /etc/shells of the distro which exist in it.
+fn list_login_shells(rootfs: &HostPath) -> Result<Vec<String>> {
+    let shells_path = ContainerPath::new("/etc/shells")?.to_host_path(rootfs);
+    let shells = match fs::read_to_string(shells_path.as_path()) {
+        Ok(shells) => shells,
+        Err(_) => return Ok(vec![]),
+    };
+    let mut result: Vec<String> = vec![];
+    for line in shells.lines().map(|line| line.trim()) {
+        if !line.starts_with('/') || result.iter().any(|shell| shell == line) {
+            continue;
+        }
+        if ContainerPath::new(line)?.to_host_path(rootfs).exists() {
+            result.push(line.to_owned());
+        }
+    }
+    Ok(result)
+}
+
+fn set_up_autostart(name: &str) -> Result<()> {
+    let mut distrod_config = DistrodConfig::get()
+        .with_context(|| "Failed to get the Distrod config.")?
+        .as_ref()
+        .clone();
+    if distrod_config
+        .autostart
+        .distros
+        .iter()
+        .any(|distro| distro.name == name)
+    {
+        return Ok(());
+    }
+    if !cli_ui::prompt_yes_no(&format!("Start {} on Windows startup?", name))? {
+        return Ok(());
+    }
+    distrod_config
+        .autostart
+        .distros
+        .push(AutostartDistroConfig {
+            name: name.to_owned(),
+            after: vec![],
+        });
+    distrod_config.update()?;
+    crate::register_autostart_task()
+}
+
+fn set_up_port_watch(rootfs: &HostPath) -> Result<()> {
+    let link_path = ContainerPath::new(PORT_WATCH_WANTS_LINK_PATH)?.to_host_path(rootfs);
+    if fs::symlink_metadata(link_path.as_path()).is_ok() {
+        return Ok(());
+    }
+    if !cli_ui::prompt_yes_no(
+        "Forward the ports the services listen on to Windows automatically by \
+         distrod-port-watch.service?",
+    )? {
+        return Ok(());
+    }
+    if let Some(dir) = link_path.parent() {
+        fs::create_dir_all(dir).with_context(|| format!("Failed to create {:?}.", dir))?;
+    }
+    symlink("/run/systemd/system/distrod-port-watch.service", &link_path)
+        .with_context(|| format!("Failed to create {:?}.", &link_path))?;
+    Ok(())
+}
@@ -0,0 +1,113 @@
+use anyhow::{anyhow, bail, Context, Result};
+use libs::wsl_conf::{self, WslConf, WSL_CONF_PATH};
+use structopt::StructOpt;
+
+#[derive(Debug, StructOpt)]
+pub enum WslconfOpts {
+    /// Print the value of a key such as boot.systemd. Nothing is printed if it's not set.
+    Get(WslconfGetOpts),
+    /// Set the value of a key such as boot.systemd, keeping the other lines and the comments.
+    Set(WslconfSetOpts),
+    /// Remove a key such as boot.command.
+    Unset(WslconfGetOpts),
+    /// Check the lines which WSL ignores or fails to read, such as a misspelled key.
+    Check,
+}
+
+#[derive(Debug, StructOpt)]
+#[structopt(rename_all = "kebab")]
+pub struct WslconfGetOpts {
+    /// The section and the key joined by '.', such as boot.systemd.
+    key: String,
+}
+
+#[derive(Debug, StructOpt)]
+#[structopt(rename_all = "kebab")]
+pub struct WslconfSetOpts {
+    /// The section and the key joined by '.', such as boot.systemd.
+    key: String,
+
+    value: String,
+
+    /// Set a key WSL doesn't know, such as one of a newer WSL.
+    #[structopt(long)]
+    force: bool,
+}
+
+pub fn run_wslconf_command(opts: WslconfOpts) -> Result<()> {
+    match opts {
+        WslconfOpts::Get(get_opts) => {
+            let (section, key) = parse_key(&get_opts.key)?;
+            if let Some(value) = open()?.get(section, key) {
+                println!("{}", value);
+            }
+        }
+        WslconfOpts::Set(set_opts) => {
+            let (section, key) = parse_key(&set_opts.key)?;
+            if !wsl_conf::is_known_key(section, key) && !set_opts.force {
+                bail!(
+                    "WSL doesn't read {}. Check the spelling, or give --force to set it anyway.",
+                    &set_opts.key
+                );
+            }
+            wsl_conf::validate_value(section, key, &set_opts.value)?;
+            let mut conf = open()?;
+            conf.set(section, key, &set_opts.value);
+            conf.write()?;
+            log_restart_needed();
+        }
+        WslconfOpts::Unset(unset_opts) => {
+            let (section, key) = parse_key(&unset_opts.key)?;
+            let mut conf = open()?;
+            if !conf.unset(section, key) {
+                log::info!("{} is not set.", &unset_opts.key);
+                return Ok(());
+            }
+            conf.write()?;
+            log_restart_needed();
+        }
+        WslconfOpts::Check => {
+            let problems = open()?.find_problems();
+            for problem in &problems {
+                println!("{}: {}", WSL_CONF_PATH, problem);
+            }
+            if !problems.is_empty() {
+                bail!("{} has {} problems.", WSL_CONF_PATH, problems.len());
+            }
+            log::info!("{} has no problem.", WSL_CONF_PATH);
+        }
+    }
+    Ok(())
+}
+
+fn open() -> Result<WslConf> {
+    WslConf::open(WSL_CONF_PATH).with_context(|| format!("Failed to open {}.", WSL_CONF_PATH))
+}
+
+/// Splits "boot.systemd" into the section and the key. The key can't contain '.', but a section
+/// such as a custom one could. They are validated even with --force, which only allows the keys
+/// WSL doesn't know.
+fn parse_key(key: &str) -> Result<(&str, &str)> {
+    let (section, key) = key.rsplit_once('.').ok_or_else(|| {
+        anyhow!(
+            "'{}' should be a section and a key such as boot.systemd.",
+            key
+        )
+    })?;
+    if section.is_empty() || key.is_empty() {
+        bail!(
+            "'{}.{}' should be a section and a key such as boot.systemd.",
+            section,
+            key
+        );
+    }
+    wsl_conf::validate_key(section, key)?;
+    Ok((section, key))
+}
+
+fn log_restart_needed() {
+    log::info!(
+        "Updated {}. Run `wsl --shutdown` on Windows to apply it to WSL.",
+        WSL_CONF_PATH
+    );
+}
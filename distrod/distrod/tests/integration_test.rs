@@ -2,6 +2,7 @@ use std::{fs::File, io::BufWriter, path::PathBuf, process::Command, time::Durati
 
 use anyhow::{Context, Result};
 use libs::{
+    cancellation::CancellationToken,
     cli_ui::build_progress_bar,
     container_org_image::fetch_container_org_image,
     distro_image::{
@@ -35,6 +36,31 @@ fn test_init_is_sytemd() {
     assert!(String::from_utf8_lossy(&output.stdout).contains("(systemd)"));
 }
 
+#[test]
+fn test_status_reports_running_distro() {
+    let mut status = DISTROD_SETUP.new_command();
+    status.arg("status");
+    let output = status.output().unwrap();
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    assert!(stdout.contains("Status: Running"), "{}", stdout);
+    assert!(stdout.contains("Systemd: "), "{}", stdout);
+}
+
+#[test]
+fn test_status_in_tsv() {
+    let mut status = DISTROD_SETUP.new_command();
+    status.args(&["status", "--format", "tsv"]);
+    let output = status.output().unwrap();
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    let lines: Vec<_> = stdout.lines().collect();
+    assert_eq!(2, lines.len(), "{}", stdout);
+    assert_eq!(
+        "status\tname\trootfs\tinit_pid\tsystemd\tfailed_units\tip_address",
+        lines[0]
+    );
+    assert!(lines[1].starts_with("Running\t"), "{}", stdout);
+}
+
 #[test]
 fn test_no_systemd_unit_is_failing() {
     let query_systemctl = || -> std::process::Output {
@@ -416,6 +442,90 @@ fn test_distrod_is_in_path() {
     assert!(output.contains("/opt/distrod/bin"));
 }
 
+#[test]
+fn test_exec_with_and_without_broker() {
+    for no_broker in &[false, true] {
+        let mut exit = DISTROD_SETUP.new_command();
+        exit.arg("exec");
+        if *no_broker {
+            exit.arg("--no-broker");
+        }
+        exit.args(&["--", "sh", "-c", "read line; echo $line; exit 3"]);
+        exit.stdin(std::process::Stdio::piped())
+            .stdout(std::process::Stdio::piped());
+        let mut child = exit.spawn().unwrap();
+        std::io::Write::write_all(child.stdin.as_mut().unwrap(), b"foo\n").unwrap();
+        let output = child.wait_with_output().unwrap();
+        assert_eq!(Some(3), output.status.code());
+        assert_eq!("foo\n", String::from_utf8_lossy(&output.stdout));
+    }
+}
+
+#[test]
+fn test_exec_env_flags() {
+    let mut env_file = tempfile::NamedTempFile::new().unwrap();
+    std::io::Write::write_all(&mut env_file, b"# comment\nFROM_FILE=1\nOVERRIDDEN=file\n").unwrap();
+
+    let mut env = DISTROD_SETUP.new_command();
+    env.env("PASSED", "yes").env("NOT_PASSED", "yes");
+    env.args(&["exec", "--clean-env", "--env-file"])
+        .arg(env_file.path())
+        .args(&["-e", "OVERRIDDEN=arg", "-e", "PASSED", "--", "env"]);
+    let output = env.output().unwrap();
+    let mut lines: Vec<_> = String::from_utf8_lossy(&output.stdout)
+        .lines()
+        .map(|line| line.to_owned())
+        .collect();
+    lines.sort();
+    assert_eq!(
+        vec![
+            "FROM_FILE=1",
+            "OVERRIDDEN=arg",
+            "PASSED=yes",
+            "PATH=/usr/local/sbin:/usr/local/bin:/usr/sbin:/usr/bin:/sbin:/bin",
+        ],
+        lines
+    );
+}
+
+#[test]
+fn test_exec_as_another_user() {
+    let mut create_user = DISTROD_SETUP.new_command();
+    create_user.args(&[
+        "exec",
+        "--",
+        "useradd",
+        "-m",
+        "-u",
+        "1234",
+        "exec_user",
+        "--shell",
+        "/bin/bash",
+    ]);
+    assert!(create_user.status().unwrap().success());
+
+    for user in &["exec_user", "1234"] {
+        let mut echo_home = DISTROD_SETUP.new_command();
+        echo_home.args(&[
+            "exec",
+            "--user",
+            user,
+            "--",
+            "sh",
+            "-c",
+            "echo $(id -un) $HOME $(pwd)",
+        ]);
+        let output = echo_home.output().unwrap();
+        let output = String::from_utf8_lossy(&output.stdout);
+        assert_eq!("exec_user /home/exec_user /home/exec_user", output.trim());
+    }
+
+    let mut pwd = DISTROD_SETUP.new_command();
+    pwd.args(&["exec", "-u", "exec_user", "-w", "/tmp", "--", "pwd"]);
+    let output = pwd.output().unwrap();
+    assert_eq!("/tmp", String::from_utf8_lossy(&output.stdout).trim());
+}
+
 #[tokio::test]
 async fn test_distro_download_url_is_live() {
     let distro_image =
@@ -499,9 +609,14 @@ async fn setup_distro_image(distro_name: &str) -> PathBuf {
         }
         DistroImageFile::Url(url) => {
             log::info!("Downloading '{}'...", url);
-            download_file_with_progress(&url, build_progress_bar, &mut tar_xz)
-                .await
-                .unwrap();
+            download_file_with_progress(
+                &url,
+                build_progress_bar,
+                &mut tar_xz,
+                &CancellationToken::new(),
+            )
+            .await
+            .unwrap();
             log::info!("Download done.");
         }
     }
@@ -0,0 +1,50 @@
+use anyhow::Result;
+use libs::cancellation;
+
+/// A token which cancels `create` or `start` from another thread, such as a Ctrl-C handler.
+/// `create` stops at a point where it can remove the incomplete rootfs, and `start` stops before
+/// it launches the container. Both fail with `Cancelled`.
+#[derive(Clone, Debug, Default)]
+pub struct CancellationToken {
+    inner: cancellation::CancellationToken,
+}
+
+impl CancellationToken {
+    pub fn new() -> Self {
+        CancellationToken::default()
+    }
+
+    pub fn cancel(&self) {
+        self.inner.cancel();
+    }
+
+    pub fn is_cancelled(&self) -> bool {
+        self.inner.is_cancelled()
+    }
+
+    /// Cancels the token on the first SIGINT, such as by Ctrl-C, and exits the process on the
+    /// second one. This sets a signal handler rather than starting a thread, since `start` forks
+    /// the init of the distro, which isn't safe in a process with threads.
+    pub fn cancel_on_sigint(&self) -> Result<()> {
+        cancellation::cancel_on_sigint(&self.inner)
+    }
+
+    pub(crate) fn as_libs_token(&self) -> &cancellation::CancellationToken {
+        &self.inner
+    }
+}
+
+#[cfg(test)]
+mod test_cancellation {
+    use super::*;
+
+    #[test]
+    fn test_cancel() {
+        let token = CancellationToken::new();
+        let cloned = token.clone();
+        assert!(!token.is_cancelled());
+        cloned.cancel();
+        assert!(token.is_cancelled());
+        assert!(token.as_libs_token().check().is_err());
+    }
+}
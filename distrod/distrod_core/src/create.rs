@@ -0,0 +1,307 @@
+use anyhow::{anyhow, bail, Context, Result};
+use libs::arch;
+use libs::cancellation;
+use libs::cli_ui::build_progress_bar;
+use libs::container::HostPath;
+use libs::container_org_image::fetch_container_org_image;
+use libs::distro;
+use libs::distro_config::validate_hostname;
+use libs::distro_image::{self, download_file_with_progress, DistroImage, DistroImageFile};
+use libs::distro_registry;
+use libs::distrod_config::{self, DistrodConfig};
+use libs::error::ImageError;
+use libs::extract;
+use libs::image_fetcher_plugin;
+use libs::rootfs_image;
+use libs::rootfs_storage;
+use std::fs::File;
+use std::io::{Cursor, Read};
+use std::path::{Path, PathBuf};
+
+use crate::cancellation::CancellationToken;
+
+/// Where the image of a new distro comes from.
+#[derive(Clone, Debug)]
+#[non_exhaustive]
+pub enum ImageSource {
+    /// A local rootfs image in .tar.xz.
+    LocalFile(PathBuf),
+    /// The URL of a rootfs image in .tar.xz. It needs a name by `CreateOptions::with_name`.
+    Url(String),
+    /// An image of linuxcontainers.org such as "ubuntu/jammy". The release can be omitted, as in
+    /// "ubuntu", to take the default one.
+    ContainerOrg(String),
+    /// An image listed by the image fetcher plugin of the name, at the path such as
+    /// "ubuntu/jammy". The path can be short, as in `ContainerOrg`, to take the defaults.
+    Plugin { plugin: String, path: String },
+}
+
+#[derive(Clone, Debug)]
+pub struct CreateOptions {
+    image: ImageSource,
+    name: Option<String>,
+    install_dir: Option<PathBuf>,
+    exclude: Vec<String>,
+    include: Vec<String>,
+    hostname: Option<String>,
+    image_size: Option<u64>,
+    shows_progress: bool,
+}
+
+impl CreateOptions {
+    pub fn new(image: ImageSource) -> CreateOptions {
+        CreateOptions {
+            image,
+            name: None,
+            install_dir: None,
+            exclude: vec![],
+            include: vec![],
+            hostname: None,
+            image_size: None,
+            shows_progress: false,
+        }
+    }
+
+    /// The name of the new distro. Defaults to the name of the image, such as "ubuntu-jammy".
+    pub fn with_name(&mut self, name: &str) -> &mut Self {
+        self.name = Some(name.to_owned());
+        self
+    }
+
+    /// The directory of the rootfs. Defaults to the one of the name under `distro_images_dir`
+    /// of the Distrod config.
+    pub fn with_install_dir<P: AsRef<Path>>(&mut self, install_dir: P) -> &mut Self {
+        self.install_dir = Some(install_dir.as_ref().to_owned());
+        self
+    }
+
+    /// A glob pattern of the paths in the image not to extract, such as "/usr/share/doc/**",
+    /// in addition to the [extract] section of the Distrod config.
+    pub fn with_exclude(&mut self, pattern: &str) -> &mut Self {
+        self.exclude.push(pattern.to_owned());
+        self
+    }
+
+    /// A glob pattern of the paths to extract even if they match an exclude pattern.
+    pub fn with_include(&mut self, pattern: &str) -> &mut Self {
+        self.include.push(pattern.to_owned());
+        self
+    }
+
+    /// The hostname of the new distro, instead of the name of the Windows machine.
+    pub fn with_hostname(&mut self, hostname: &str) -> &mut Self {
+        self.hostname = Some(hostname.to_owned());
+        self
+    }
+
+    /// Stores the rootfs in an ext4 disk image of the size in bytes, which is mounted on the
+    /// directory of the rootfs, instead of in the directory itself. The install directory must
+    /// not exist yet.
+    pub fn with_image_size(&mut self, bytes: u64) -> &mut Self {
+        self.image_size = Some(bytes);
+        self
+    }
+
+    /// Shows the progress bar of the download on the terminal.
+    pub fn with_progress_bar(&mut self) -> &mut Self {
+        self.shows_progress = true;
+        self
+    }
+}
+
+/// The distro made by `create`.
+#[derive(Clone, Debug)]
+#[non_exhaustive]
+pub struct CreatedDistro {
+    pub name: String,
+    pub rootfs: PathBuf,
+}
+
+/// Downloads and unpacks the image, and initializes the rootfs for Distrod. The incomplete
+/// rootfs is removed if it fails or `cancel` is cancelled.
+pub async fn create(opts: &CreateOptions, cancel: &CancellationToken) -> Result<CreatedDistro> {
+    let cancel = cancel.as_libs_token();
+    if let Some(ref name) = opts.name {
+        distro_registry::validate_instance_name(name)?;
+    }
+    if let Some(ref hostname) = opts.hostname {
+        validate_hostname(hostname)?;
+    }
+    let image = match opts.image {
+        ImageSource::LocalFile(ref path) => DistroImage {
+            name: format!(
+                "local-{}",
+                path.file_stem()
+                    .ok_or_else(|| anyhow!("image {:?} should be a file.", path))?
+                    .to_string_lossy()
+                    .replace(".tar", "")
+            ),
+            image: DistroImageFile::Local(path.clone().into_os_string()),
+        },
+        ImageSource::Url(ref url) => DistroImage {
+            name: opts
+                .name
+                .clone()
+                .ok_or_else(|| anyhow!("A name is needed for the image of {}.", url))?,
+            image: DistroImageFile::Url(url.clone()),
+        },
+        ImageSource::ContainerOrg(ref path) => {
+            fetch_container_org_image(&distro_image::choose_by_path(path))
+                .await
+                .with_context(|| format!("Failed to get the image of {}.", path))?
+        }
+        ImageSource::Plugin {
+            ref plugin,
+            ref path,
+        } => {
+            let found = image_fetcher_plugin::list_plugins(
+                distrod_config::get_image_fetcher_plugins_dir(),
+            )?
+            .into_iter()
+            .find(|candidate| &candidate.name == plugin)
+            .ok_or_else(|| ImageError::NotFound {
+                name: plugin.clone(),
+                list_item_kind: "the image fetcher plugins".to_owned(),
+            })?;
+            image_fetcher_plugin::fetch_plugin_image(found, path)
+                .await
+                .with_context(|| format!("Failed to get the image of {} by {}.", path, plugin))?
+        }
+    };
+
+    let image_name = opts.name.clone().unwrap_or(image.name);
+    let tar_xz = match image.image {
+        DistroImageFile::Local(path) => Box::new(
+            File::open(&path)
+                .with_context(|| format!("Failed to open the distro image file: {:?}.", &path))?,
+        ) as Box<dyn Read + Send>,
+        DistroImageFile::Url(url) => {
+            log::info!("Downloading '{}'...", url);
+            let mut bytes = vec![];
+            if opts.shows_progress {
+                download_file_with_progress(&url, build_progress_bar, &mut bytes, cancel).await?;
+            } else {
+                download_file_with_progress(
+                    &url,
+                    |_| indicatif::ProgressBar::hidden(),
+                    &mut bytes,
+                    cancel,
+                )
+                .await?;
+            }
+            log::info!("Download done.");
+            Box::new(Cursor::new(bytes)) as Box<dyn Read + Send>
+        }
+    };
+
+    log::info!("Unpacking...");
+    let config = DistrodConfig::get().with_context(|| "Failed to get the Distrod config.")?;
+    let install_dir = match opts.install_dir {
+        Some(ref install_dir) => install_dir.clone(),
+        None => distro_registry::get_instances_dir()?.join(&image_name),
+    };
+    let mut exclude = config.extract.exclude.clone();
+    exclude.extend(opts.exclude.iter().cloned());
+    let mut include = config.extract.include.clone();
+    include.extend(opts.include.iter().cloned());
+    let filter = extract::ExtractFilter::new(&exclude, &include)
+        .with_context(|| "Failed to parse the extract filter.")?;
+    let creates_install_dir = !install_dir.exists();
+    if opts.image_size.is_some() && !creates_install_dir {
+        bail!(
+            "{:?} already exists. The rootfs in a disk image needs a new directory.",
+            &install_dir
+        );
+    }
+    if creates_install_dir {
+        rootfs_storage::create_rootfs_dir(&install_dir)
+            .with_context(|| format!("Failed to make a directory: {:?}.", &install_dir))?;
+    }
+    if let Some(size) = opts.image_size {
+        log::info!(
+            "Making a disk image of {} MiB for the rootfs...",
+            size >> 20
+        );
+        if let Err(e) = rootfs_image::create_image(&install_dir, size) {
+            remove_incomplete_rootfs(&install_dir);
+            return Err(e).with_context(|| "Failed to make the disk image of the rootfs.");
+        }
+    }
+    if let Err(e) = unpack_and_initialize_rootfs(tar_xz, &install_dir, &filter, cancel) {
+        if creates_install_dir {
+            remove_incomplete_rootfs(&install_dir);
+        }
+        if cancel.is_cancelled() {
+            return Err(anyhow::Error::new(cancellation::Cancelled)
+                .context(format!("Creating {} has been cancelled.", &image_name)));
+        }
+        return Err(e);
+    }
+
+    log::info!("{} is created at {:?}", &image_name, &install_dir);
+
+    let rootfs_path = install_dir
+        .canonicalize()
+        .with_context(|| format!("Failed to get the canonicalized path of {:?}", &install_dir))?;
+    if let Some(ref hostname) = opts.hostname {
+        set_hostname_of_new_distro(&HostPath::new(&rootfs_path)?, hostname).with_context(|| {
+            format!(
+                "{} is created, but failed to set the hostname to {}.",
+                &image_name, hostname
+            )
+        })?;
+    }
+    Ok(CreatedDistro {
+        name: image_name,
+        rootfs: rootfs_path,
+    })
+}
+
+/// Removes the rootfs made by `create` and its disk image if it has one.
+fn remove_incomplete_rootfs(install_dir: &Path) {
+    log::info!("Removing the incomplete rootfs at {:?}...", install_dir);
+    let result = rootfs_image::get_image_path(install_dir).and_then(|image| {
+        if image.exists() {
+            rootfs_image::unmount_image(install_dir)?;
+            std::fs::remove_file(&image)
+                .with_context(|| format!("Failed to remove {:?}.", &image))?;
+        }
+        rootfs_storage::remove_rootfs_dir(install_dir)
+    });
+    if let Err(e) = result {
+        log::warn!("Failed to remove {:?}. {:?}", install_dir, e);
+    }
+}
+
+fn set_hostname_of_new_distro(rootfs: &HostPath, hostname: &str) -> Result<()> {
+    let mut distro_config = distro::get_distro_config(rootfs)?;
+    distro_config.network.hostname = Some(hostname.to_owned());
+    distro::set_distro_config(rootfs, &distro_config)?;
+    // The distro does it on every start as well, but this lets the files be right beforehand.
+    distro::set_hostname(rootfs, hostname)
+}
+
+fn unpack_and_initialize_rootfs<R: Read + Send + 'static>(
+    tar_xz: R,
+    install_dir: &Path,
+    filter: &extract::ExtractFilter,
+    cancel: &cancellation::CancellationToken,
+) -> Result<()> {
+    let skipped_paths = extract::unpack_image(tar_xz, install_dir, filter, cancel)
+        .with_context(|| format!("Failed to unpack the image to '{:?}'.", &install_dir))?;
+
+    cancel.check()?;
+    let rootfs =
+        HostPath::new(&install_dir.canonicalize().with_context(|| {
+            format!("Failed to get the canonicalized path of {:?}", &install_dir)
+        })?)?;
+    arch::check_rootfs_arch(&rootfs)?;
+    if !filter.is_empty() {
+        log::info!("Skipped {} paths in the image.", skipped_paths.len());
+        extract::write_skipped_paths_manifest(&rootfs, &skipped_paths)
+            .with_context(|| "Failed to write the list of the skipped paths.")?;
+    }
+    distro::initialize_distro_rootfs(rootfs, true)
+        .with_context(|| "Failed to initialize the rootfs.")?;
+    Ok(())
+}
@@ -0,0 +1,544 @@
+use anyhow::{bail, Context, Result};
+use libs::cgroup::ResourceLimits;
+use libs::container::{ContainerPath, HostPath};
+use libs::distro::{self, Distro, DistroLauncher};
+use libs::distro_config::MountConfig;
+use libs::distro_registry;
+use libs::distrod_config;
+use libs::error::DistroError;
+use libs::exec_broker;
+use libs::passwd::{Credential, IdCredential, PasswdFile};
+use libs::resolved::{self, NameServers};
+use libs::userns;
+use std::ffi::{OsStr, OsString};
+use std::os::unix::process::CommandExt;
+use std::path::{Path, PathBuf};
+use std::process::{Command, Stdio};
+use std::time::Duration;
+
+use crate::cancellation::CancellationToken;
+
+/// Whether a distro is running.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+#[non_exhaustive]
+pub enum DistroState {
+    Running,
+    Stopped,
+}
+
+/// A distro made by `create` or `distrod create`.
+#[derive(Clone, Debug)]
+#[non_exhaustive]
+pub struct DistroSummary {
+    pub name: String,
+    pub rootfs: PathBuf,
+    pub state: DistroState,
+}
+
+/// Lists the distros made by `create` or `distrod create`, sorted by the name.
+pub fn list() -> Result<Vec<DistroSummary>> {
+    let instances =
+        distro_registry::list_instances().with_context(|| "Failed to list the distros.")?;
+    let mut distros = vec![];
+    for instance in instances {
+        let is_running = DistroLauncher::get_running_distro_by_name(Some(&instance.name))
+            .with_context(|| format!("Failed to get the state of {}.", &instance.name))?
+            .is_some();
+        distros.push(DistroSummary {
+            name: instance.name,
+            rootfs: instance.rootfs,
+            state: if is_running {
+                DistroState::Running
+            } else {
+                DistroState::Stopped
+            },
+        });
+    }
+    Ok(distros)
+}
+
+/// The distro launched by `start`.
+#[derive(Clone, Debug)]
+#[non_exhaustive]
+pub struct StartedDistro {
+    /// The name, which is None for a distro launched only by the rootfs.
+    pub name: Option<String>,
+    pub rootfs: PathBuf,
+    /// The pid of the init process of the distro, such as systemd, outside the container.
+    pub init_pid: u32,
+}
+
+#[derive(Clone, Debug, Default)]
+pub struct StartOptions {
+    name: Option<String>,
+    rootfs: Option<PathBuf>,
+    target: Option<String>,
+    ephemeral: bool,
+    read_only: bool,
+    mounts: Vec<String>,
+    nameservers: Vec<String>,
+    search_domains: Vec<String>,
+    limits: ResourceLimits,
+    distrod_bin: Option<PathBuf>,
+}
+
+impl StartOptions {
+    /// The options which start the default distro.
+    pub fn new() -> StartOptions {
+        StartOptions::default()
+    }
+
+    /// Starts the distro of the name. With `with_rootfs`, it's the name given to the rootfs.
+    pub fn with_name(&mut self, name: &str) -> &mut Self {
+        self.name = Some(name.to_owned());
+        self
+    }
+
+    /// Starts the rootfs of the directory instead of a distro made by `create`.
+    pub fn with_rootfs<P: AsRef<Path>>(&mut self, rootfs: P) -> &mut Self {
+        self.rootfs = Some(rootfs.as_ref().to_owned());
+        self
+    }
+
+    /// Boots into the target of systemd, such as "multi-user.target".
+    pub fn with_target(&mut self, target: &str) -> &mut Self {
+        self.target = Some(target.to_owned());
+        self
+    }
+
+    /// Throws away the changes to the rootfs when the distro stops.
+    pub fn with_ephemeral(&mut self) -> &mut Self {
+        self.ephemeral = true;
+        self
+    }
+
+    pub fn with_read_only_rootfs(&mut self) -> &mut Self {
+        self.read_only = true;
+        self
+    }
+
+    /// Bind-mounts a path into the distro, such as "/mnt/c/data:/data:ro" as
+    /// `distrod start --mount` takes.
+    pub fn with_mount(&mut self, mount: &str) -> &mut Self {
+        self.mounts.push(mount.to_owned());
+        self
+    }
+
+    /// Uses the name servers in the distro instead of the ones of WSL.
+    pub fn with_nameservers<S: AsRef<str>>(
+        &mut self,
+        nameservers: &[S],
+        search_domains: &[S],
+    ) -> &mut Self {
+        self.nameservers = nameservers.iter().map(|s| s.as_ref().to_owned()).collect();
+        self.search_domains = search_domains
+            .iter()
+            .map(|s| s.as_ref().to_owned())
+            .collect();
+        self
+    }
+
+    pub fn with_memory_limit(&mut self, bytes: u64) -> &mut Self {
+        self.limits.memory_bytes = Some(bytes);
+        self
+    }
+
+    /// Limits the number of the CPUs the distro can use up, such as 1.5.
+    pub fn with_cpu_limit(&mut self, cpus: f64) -> &mut Self {
+        self.limits.cpus = Some(cpus);
+        self
+    }
+
+    pub fn with_pids_limit(&mut self, pids: u64) -> &mut Self {
+        self.limits.pids = Some(pids);
+        self
+    }
+
+    /// The distrod command which serves the distro in the background while it runs, such as by
+    /// the exec broker. Defaults to the installed one.
+    pub fn with_distrod_bin<P: AsRef<Path>>(&mut self, path: P) -> &mut Self {
+        self.distrod_bin = Some(path.as_ref().to_owned());
+        self
+    }
+}
+
+/// Launches a distro, and returns once its init process has started. If `cancel` is cancelled
+/// before the container is launched, it fails with `Cancelled` without launching it.
+pub fn start(opts: &StartOptions, cancel: &CancellationToken) -> Result<StartedDistro> {
+    if let Some(ref name) = opts.name {
+        distro_registry::validate_instance_name(name)?;
+    }
+    if distro::is_inside_running_distro()
+        || DistroLauncher::get_running_distro_by_name(opts.name.as_deref())
+            .with_context(|| "Failed to see if there's a running distro.")?
+            .is_some()
+    {
+        return Err(DistroError::AlreadyRunning {
+            name: opts.name.clone(),
+        }
+        .into());
+    }
+    if userns::is_rootless_mode() {
+        enter_rootless_namespaces()?;
+    }
+    let mut distro_launcher = DistroLauncher::new()?;
+    if let Some(ref name) = opts.name {
+        if opts.rootfs.is_some() {
+            distro_launcher.with_name(name)?;
+        } else {
+            distro_launcher
+                .from_named_distro(name)
+                .with_context(|| format!("Failed to get the distro '{}'.", name))?;
+        }
+    }
+    if let Some(ref rootfs) = opts.rootfs {
+        distro_launcher
+            .with_rootfs(rootfs)
+            .with_context(|| format!("Failed to set {:?} to the rootfs of the distro.", rootfs))?;
+    } else if opts.name.is_none() {
+        distro_launcher
+            .from_default_distro()
+            .with_context(|| "Failed to get the default distro.")?;
+    }
+    if let Some(ref target) = opts.target {
+        distro_launcher.with_target(target)?;
+    }
+    if opts.ephemeral {
+        distro_launcher.with_ephemeral();
+    }
+    if opts.read_only {
+        distro_launcher.with_read_only_rootfs();
+    }
+    for mount in &opts.mounts {
+        distro_launcher.with_bind_mount(MountConfig::parse(mount)?)?;
+    }
+    if !opts.nameservers.is_empty() {
+        distro_launcher.with_nameservers(NameServers {
+            nameservers: opts.nameservers.clone(),
+            search_domains: opts.search_domains.clone(),
+        })?;
+    }
+    distro_launcher.with_resource_limits(opts.limits);
+    distro_launcher.with_cancellation_token(cancel.as_libs_token());
+    let distro = distro_launcher
+        .launch()
+        .with_context(|| "Failed to launch the distro.")?;
+    spawn_distro_daemons(&distro, &get_distrod_bin(opts.distrod_bin.as_deref()))?;
+    Ok(StartedDistro {
+        name: distro.get_name().map(|name| name.to_owned()),
+        rootfs: distro.get_rootfs().to_owned(),
+        init_pid: distro.get_init_pid(),
+    })
+}
+
+#[derive(Clone, Debug, Default)]
+pub struct StopOptions {
+    name: Option<String>,
+    sigkill: bool,
+    timeout: Option<Duration>,
+    distrod_bin: Option<PathBuf>,
+}
+
+impl StopOptions {
+    /// The options which stop the running distro.
+    pub fn new() -> StopOptions {
+        StopOptions::default()
+    }
+
+    pub fn with_name(&mut self, name: &str) -> &mut Self {
+        self.name = Some(name.to_owned());
+        self
+    }
+
+    /// Kills the processes right away instead of shutting down the init process.
+    pub fn with_sigkill(&mut self) -> &mut Self {
+        self.sigkill = true;
+        self
+    }
+
+    /// How long to wait for the shutdown before killing the processes.
+    pub fn with_timeout(&mut self, timeout: Duration) -> &mut Self {
+        self.timeout = Some(timeout);
+        self
+    }
+
+    /// The distrod command which serves the distro restarted by `restart`.
+    /// Defaults to the installed one.
+    pub fn with_distrod_bin<P: AsRef<Path>>(&mut self, path: P) -> &mut Self {
+        self.distrod_bin = Some(path.as_ref().to_owned());
+        self
+    }
+}
+
+/// Stops the running distro.
+pub fn stop(opts: &StopOptions) -> Result<()> {
+    let distro = get_running_distro(opts.name.as_deref())?;
+    distro.stop(opts.sigkill, opts.timeout)
+}
+
+/// Stops the running distro, and starts it again with the same options.
+pub fn restart(opts: &StopOptions) -> Result<StartedDistro> {
+    let distro = get_running_distro(opts.name.as_deref())?;
+    let name = distro.get_name().map(|name| name.to_owned());
+    let rootfs = distro.get_rootfs().to_owned();
+    let limits = *distro.get_resource_limits();
+    let target = distro.get_target().map(|target| target.to_owned());
+    let ephemeral = distro.is_ephemeral();
+    let read_only = distro.is_read_only_by_launcher();
+    let bind_mounts = distro.get_bind_mounts().to_vec();
+    let nameservers = distro.get_nameservers().cloned();
+    distro
+        .stop(opts.sigkill, opts.timeout)
+        .with_context(|| "Failed to stop the distro.")?;
+
+    // The broker of the stopped distro notices it within a few seconds.
+    if !exec_broker::wait_for_broker_exit(name.as_deref(), Duration::from_secs(5))? {
+        log::warn!("The exec broker of the stopped distro is still running.");
+    }
+    if userns::is_rootless_mode() {
+        enter_rootless_namespaces()?;
+    }
+    let mut distro_launcher = DistroLauncher::new()?;
+    if let Some(ref name) = name {
+        distro_launcher.with_name(name)?;
+    }
+    distro_launcher
+        .with_rootfs(&rootfs)
+        .with_context(|| format!("Failed to set {:?} to the rootfs of the distro.", &rootfs))?;
+    if let Some(ref target) = target {
+        distro_launcher.with_target(target)?;
+    }
+    if ephemeral {
+        distro_launcher.with_ephemeral();
+    }
+    if read_only {
+        distro_launcher.with_read_only_rootfs();
+    }
+    for mount in bind_mounts {
+        distro_launcher.with_bind_mount(mount)?;
+    }
+    if let Some(nameservers) = nameservers {
+        distro_launcher.with_nameservers(nameservers)?;
+    }
+    distro_launcher.with_resource_limits(limits);
+    let distro = distro_launcher
+        .launch()
+        .with_context(|| "Failed to launch the distro.")?;
+    spawn_distro_daemons(&distro, &get_distrod_bin(opts.distrod_bin.as_deref()))?;
+    Ok(StartedDistro {
+        name: distro.get_name().map(|name| name.to_owned()),
+        rootfs: distro.get_rootfs().to_owned(),
+        init_pid: distro.get_init_pid(),
+    })
+}
+
+#[derive(Clone, Debug)]
+pub struct ExecOptions {
+    name: Option<String>,
+    command: OsString,
+    args: Vec<OsString>,
+    working_directory: Option<PathBuf>,
+    uid: Option<u32>,
+}
+
+impl ExecOptions {
+    /// The options which run the command in the running distro as root.
+    pub fn new<S: AsRef<OsStr>, T: AsRef<OsStr>>(command: S, args: &[T]) -> ExecOptions {
+        ExecOptions {
+            name: None,
+            command: command.as_ref().to_owned(),
+            args: args.iter().map(|arg| arg.as_ref().to_owned()).collect(),
+            working_directory: None,
+            uid: None,
+        }
+    }
+
+    pub fn with_name(&mut self, name: &str) -> &mut Self {
+        self.name = Some(name.to_owned());
+        self
+    }
+
+    /// The working directory in the distro.
+    pub fn with_working_directory<P: AsRef<Path>>(&mut self, path: P) -> &mut Self {
+        self.working_directory = Some(path.as_ref().to_owned());
+        self
+    }
+
+    /// Runs the command as the user of the uid, with the groups in /etc/passwd of the distro. `exec`
+    /// fails if the uid isn't there.
+    pub fn with_uid(&mut self, uid: u32) -> &mut Self {
+        self.uid = Some(uid);
+        self
+    }
+}
+
+/// The result of the command run by `exec`.
+#[derive(Clone, Debug)]
+#[non_exhaustive]
+pub struct ExecResult {
+    pub exit_code: u32,
+}
+
+/// Runs a command in the running distro with the stdio of this process, and waits for it.
+/// The seccomp profile and the dropped capabilities of the distro config apply as they do to
+/// `distrod exec`.
+pub fn exec(opts: &ExecOptions) -> Result<ExecResult> {
+    let mut distro = get_running_distro(opts.name.as_deref())?;
+    let rootfs = HostPath::new(distro.get_rootfs())?;
+    if let Some(filter) = distro::get_configured_seccomp_filter(&rootfs)
+        .with_context(|| "Failed to get the seccomp profile of the distro.")?
+    {
+        distro.with_seccomp_filter(filter);
+    }
+    distro.with_dropped_exec_capabilities(
+        distro::get_configured_exec_capability_drops(&rootfs)
+            .with_context(|| "Failed to get the capabilities to drop.")?,
+    );
+
+    let cred = match opts.uid {
+        Some(uid) => Some(get_credential(
+            &ContainerPath::new("/etc/passwd")?.to_host_path(&rootfs),
+            uid,
+        )?),
+        None => None,
+    };
+    let mut waiter = distro.exec_command(
+        &opts.command,
+        &opts.args,
+        opts.working_directory.as_ref(),
+        None::<&OsStr>,
+        cred.as_ref(),
+    )?;
+    Ok(ExecResult {
+        exit_code: waiter.wait(),
+    })
+}
+
+/// Returns the credential of the uid by the passwd file of the distro. It fails if the uid isn't
+/// there, rather than running the command by a guessed group.
+fn get_credential(passwd_path: &Path, uid: u32) -> Result<Credential> {
+    let mut passwd_file = PasswdFile::open(passwd_path)
+        .with_context(|| "Failed to open the passwd file of the distro.")?;
+    if passwd_file.get_ent_by_uid(uid)?.is_none() {
+        bail!("The uid {} is not in /etc/passwd of the distro.", uid);
+    }
+    Credential::from_user(IdCredential::Uid(uid), &mut passwd_file)
+}
+
+fn get_running_distro(name: Option<&str>) -> Result<Distro> {
+    // The name makes the path of the run info file.
+    if let Some(name) = name {
+        distro_registry::validate_instance_name(name)?;
+    }
+    DistroLauncher::get_running_distro_by_name(name)
+        .with_context(|| "Failed to get the running distro.")?
+        .ok_or_else(|| {
+            DistroError::NotRunning {
+                name: name.map(|name| name.to_owned()),
+            }
+            .into()
+        })
+}
+
+fn get_distrod_bin(distrod_bin: Option<&Path>) -> PathBuf {
+    distrod_bin.map_or_else(
+        || PathBuf::from(distrod_config::get_distrod_bin_path()),
+        |path| path.to_owned(),
+    )
+}
+
+fn enter_rootless_namespaces() -> Result<()> {
+    userns::enter_rootless_namespaces().with_context(|| {
+        "Distrod needs the root permission, or a kernel which lets a non-root user make \
+         user namespaces."
+    })
+}
+
+/// Starts the processes which serve the distro from outside while it runs.
+fn spawn_distro_daemons(distro: &Distro, distrod_bin: &Path) -> Result<()> {
+    // The daemons run as root, and a rootless client is outside the user namespace.
+    if userns::is_rootless_mode() {
+        return Ok(());
+    }
+    if let Err(e) = spawn_distro_daemon(distrod_bin, "exec-broker", distro.get_name()) {
+        log::warn!("Failed to start the exec broker. {:?}", e);
+    }
+    let rootfs = HostPath::new(distro.get_rootfs())?;
+    let distro_config = distro::get_distro_config(&rootfs)
+        .with_context(|| "Failed to read the config of the distro.")?;
+    let custom_nameservers = distro.get_custom_nameservers(&distro_config);
+    if resolved::syncs_nameservers(&rootfs, &distro_config, custom_nameservers.as_ref()) {
+        if let Err(e) = spawn_distro_daemon(distrod_bin, "resolved-sync", distro.get_name()) {
+            log::warn!("Failed to start the sync of the name servers. {:?}", e);
+        }
+    }
+    Ok(())
+}
+
+/// Starts the subcommand which serves the distro in the background until the distro stops.
+fn spawn_distro_daemon(
+    distrod_bin: &Path,
+    subcommand: &str,
+    distro_name: Option<&str>,
+) -> Result<()> {
+    let mut daemon = Command::new(distrod_bin);
+    daemon.arg(subcommand);
+    if let Some(name) = distro_name {
+        daemon.args(&["--distro", name]);
+    }
+    daemon
+        .stdin(Stdio::null())
+        .stdout(Stdio::null())
+        .stderr(Stdio::null());
+    unsafe {
+        daemon.pre_exec(|| {
+            nix::unistd::setsid().map_err(|e| std::io::Error::new(std::io::ErrorKind::Other, e))?;
+            Ok(())
+        });
+    }
+    daemon
+        .spawn()
+        .with_context(|| format!("Failed to spawn distrod {}.", subcommand))?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod test_distro {
+    use super::*;
+    use libs::error::find_coded_error;
+    use std::io::Write;
+
+    #[test]
+    fn test_get_credential() {
+        let mut passwd = tempfile::NamedTempFile::new().unwrap();
+        passwd
+            .write_all(
+                b"root:x:0:0:root:/root:/bin/bash\n\
+                  alice:x:1000:1001:,,,:/home/alice:/bin/bash\n",
+            )
+            .unwrap();
+        let cred = get_credential(passwd.path(), 1000).unwrap();
+        assert_eq!(1000, cred.uid.as_raw());
+        assert_eq!(1001, cred.gid.as_raw());
+        assert!(get_credential(passwd.path(), 1002).is_err());
+    }
+
+    #[test]
+    fn test_invalid_name_is_rejected() {
+        let assert_invalid_name = |err: anyhow::Error| {
+            assert_eq!("E104", find_coded_error(&err).unwrap().code());
+        };
+        assert_invalid_name(
+            start(
+                StartOptions::new().with_name("../ubuntu"),
+                &CancellationToken::new(),
+            )
+            .unwrap_err(),
+        );
+        assert_invalid_name(stop(StopOptions::new().with_name("../ubuntu")).unwrap_err());
+        assert_invalid_name(restart(StopOptions::new().with_name("../ubuntu")).unwrap_err());
+        assert_invalid_name(
+            exec(ExecOptions::new("true", &[] as &[&str]).with_name("../ubuntu")).unwrap_err(),
+        );
+    }
+}
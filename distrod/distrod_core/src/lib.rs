@@ -0,0 +1,48 @@
+//! The library API of Distrod, for the programs which manage the distros of Distrod without
+//! running the distrod command, such as GUI frontends and provisioning tools.
+//!
+//! ```no_run
+//! # fn main() -> distrod_core::Result<()> {
+//! let mut opts = distrod_core::StartOptions::new();
+//! opts.with_name("ubuntu");
+//! distrod_core::start(&opts, &distrod_core::CancellationToken::new())?;
+//!
+//! let result = distrod_core::exec(&distrod_core::ExecOptions::new("uname", &["-a"]))?;
+//! println!("exited with {}", result.exit_code);
+//! # Ok(())
+//! # }
+//! ```
+//!
+//! The items of this crate follow semantic versioning. The `libs` crate which implements them is
+//! internal to Distrod, and it changes without notice, so none of its types appear here except
+//! the ones re-exported below. The options are set by the builder methods, and the results and
+//! the errors are `#[non_exhaustive]`, so that new options, fields, and errors don't break the
+//! programs using them.
+//!
+//! The errors are `anyhow::Error`s. The ones with a stable code, such as a distro not running,
+//! are found by `find_coded_error`, or by downcasting them to `DistroError`, `ImageError`,
+//! `ContainerError`, or `Cancelled`.
+//!
+//! The functions need the root permission as the distrod command does, and they work only on
+//! Linux in WSL.
+
+#[cfg(target_os = "linux")]
+mod cancellation;
+#[cfg(target_os = "linux")]
+mod create;
+#[cfg(target_os = "linux")]
+mod distro;
+
+pub use anyhow::{Error, Result};
+pub use libs::cancellation::Cancelled;
+pub use libs::error::{find_coded_error, CodedError, ContainerError, DistroError, ImageError};
+
+#[cfg(target_os = "linux")]
+pub use cancellation::CancellationToken;
+#[cfg(target_os = "linux")]
+pub use create::{create, CreateOptions, CreatedDistro, ImageSource};
+#[cfg(target_os = "linux")]
+pub use distro::{
+    exec, list, restart, start, stop, DistroState, DistroSummary, ExecOptions, ExecResult,
+    StartOptions, StartedDistro, StopOptions,
+};
@@ -0,0 +1,57 @@
+use libs::cancellation::Cancelled;
+use libs::error::{self, exit_code};
+use thiserror::Error;
+
+/// The failures the launcher exits with a code of its own for, so that provisioning scripts
+/// can tell them apart. They are attached to the errors by `anyhow::Context::context`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Error)]
+pub enum LauncherFailure {
+    /// An option is invalid, such as a malformed distro name.
+    #[error("An option is invalid.")]
+    InvalidOption,
+    /// A distro of the name is already registered.
+    #[error("The distro is already registered.")]
+    AlreadyRegistered,
+    /// The image couldn't be chosen, downloaded, or unpacked.
+    #[error("Failed to get the distro image.")]
+    Image,
+    /// WSL failed to register the distro.
+    #[error("Failed to register the distro.")]
+    Registration,
+    /// Adding the user or enabling Distrod in the registered distro failed.
+    #[error("Failed to set up the registered distro.")]
+    SetUp,
+    /// Ctrl-C cancelled the installation.
+    #[error("The installation has been cancelled.")]
+    Cancelled,
+}
+
+impl LauncherFailure {
+    pub fn exit_code(&self) -> i32 {
+        match self {
+            LauncherFailure::InvalidOption => exit_code::INVALID_OPTION,
+            LauncherFailure::AlreadyRegistered => exit_code::ALREADY_REGISTERED,
+            LauncherFailure::Image => exit_code::LAUNCHER_IMAGE,
+            LauncherFailure::Registration => exit_code::REGISTRATION,
+            LauncherFailure::SetUp => exit_code::SET_UP,
+            LauncherFailure::Cancelled => exit_code::CANCELLED,
+        }
+    }
+}
+
+/// Returns the exit code for `err`. The errors without a `LauncherFailure` exit with the code of
+/// their `libs::error::CodedError`, or with 1.
+pub fn get_exit_code(err: &anyhow::Error) -> i32 {
+    // A step cancelled by Ctrl-C fails with the failure of the step attached outside, which
+    // must not hide the cancellation.
+    if err.downcast_ref::<Cancelled>().is_some() {
+        return exit_code::CANCELLED;
+    }
+    if let Some(failure) = err.downcast_ref::<LauncherFailure>() {
+        return failure.exit_code();
+    }
+    if let Some(coded) = error::find_coded_error(err) {
+        return coded.exit_code();
+    }
+    1
+}
@@ -1,28 +1,42 @@
 use anyhow::{anyhow, bail, Context, Result};
+use failure::LauncherFailure;
 use flate2::read::GzDecoder;
 use flate2::write::GzEncoder;
+use libs::cancellation::{self, CancellationToken};
 use libs::cli_ui::{self, build_progress_bar};
-use libs::cli_ui::{init_logger, prompt_string};
-use libs::container_org_image::ContainerOrgImageList;
+use libs::cli_ui::{init_logger, prompt_string, prompt_yes_no};
+use libs::completion::{self, CompletionShell, DynamicValues, COMPLETION_SHELLS};
+use libs::container_org_image::{fetch_container_org_image, ContainerOrgImageList};
+use libs::distro_config::{validate_user_name, DistroConfig, DISTRO_CONFIG_PATH};
 use libs::distro_image::{
-    self, download_file_with_progress, DistroImageFetcher, DistroImageFetcherGen, DistroImageFile,
+    self, download_file_with_progress, DistroImage, DistroImageFetcher, DistroImageFetcherGen,
+    DistroImageFile,
 };
 use libs::distrod_config;
 use libs::local_image::LocalDistroImage;
+use libs::port_forward::{PortForwardRule, Protocol};
+use libs::terminal_profile::TerminalProfile;
+use paths::LauncherPaths;
 use std::ffi::OsStr;
 use std::fs::File;
 use std::io::{self, BufReader, BufWriter, Cursor, Read};
 use std::path::{Path, PathBuf};
 use std::process::Command;
+use structopt::clap::Shell;
 use structopt::StructOpt;
-use tempfile::tempdir;
 use tempfile::TempDir;
 use xz2::read::XzDecoder;
 
+mod failure;
+mod migrate;
+mod paths;
 mod tar_helper;
 mod wsl;
 
 static DISTRO_NAME: &str = "Distrod";
+/// Prints the names of the distros registered to WSL, one per line. `wsl --list` prints UTF-16.
+static LIST_DISTROS_POSH_COMMAND: &str = "(Get-ChildItem \
+    HKCU:\\Software\\Microsoft\\Windows\\CurrentVersion\\Lxss | Get-ItemProperty).DistributionName";
 
 #[derive(Debug, StructOpt)]
 #[structopt(name = "distrod-install", rename_all = "kebab")]
@@ -30,8 +44,18 @@ pub struct Opts {
     /// Log level in the env_logger format. Simple levels: trace, debug, info(default), warn, error.
     #[structopt(short, long)]
     pub log_level: Option<String>,
-    #[structopt(short, long)]
+    /// The name of the distro in WSL, such as Distrod-Ubuntu, so that multiple distros can be
+    /// installed side by side. Defaults to Distrod. `install` asks it if it's not given.
+    #[structopt(short, long, visible_alias = "name")]
     pub distro_name: Option<String>,
+    /// Keep the distros and the files of the launcher in the DistrodData directory beside the
+    /// launcher instead of %LocalAppData%. This is turned on automatically once DistrodData exists.
+    #[structopt(long)]
+    pub portable: bool,
+    /// Never prompt, such as in provisioning scripts. The prompts take their defaults, or fail if
+    /// they have none. It's also the case when stdin is not a terminal.
+    #[structopt(long, global = true)]
+    pub non_interactive: bool,
     #[structopt(subcommand)]
     pub command: Option<Subcommand>,
 }
@@ -41,6 +65,16 @@ pub enum Subcommand {
     Install(InstallOpts),
     Run(RunOpts),
     Config(ConfigOpts),
+    Delete(DeleteOpts),
+    /// Move the virtual disk (ext4.vhdx) of the installed distro into another directory, such as
+    /// one on a larger drive.
+    Move(MoveOpts),
+    /// Copy a distro registered to WSL, such as Ubuntu from Microsoft Store, into a new distro
+    /// with Distrod, which is named by --name and has the same default user. Defaults to
+    /// Distrod-<the source>.
+    Migrate(migrate::MigrateOpts),
+    /// Print the completion script of the shell, such as `completion powershell`.
+    Completion(CompletionOpts),
 }
 
 #[derive(Debug, StructOpt)]
@@ -48,6 +82,35 @@ pub enum Subcommand {
 pub struct InstallOpts {
     #[structopt(long)]
     root: bool,
+
+    /// Install without any prompts, such as from provisioning scripts. What the options don't
+    /// give is the default, such as the Ubuntu image, and only root is set up without
+    /// --default-user.
+    #[structopt(short, long)]
+    yes: bool,
+
+    /// The image to install without the prompt. Either a local .tar.xz file, or the path of
+    /// a linuxcontainers.org image such as ubuntu/jammy. The version can be omitted, as in ubuntu.
+    #[structopt(long)]
+    image: Option<String>,
+
+    /// The Linux user to add and make the default without the prompt. With --yes, its
+    /// password is not set.
+    #[structopt(long, conflicts_with = "root")]
+    default_user: Option<String>,
+
+    /// Let the default user use sudo without the password, without the prompt.
+    #[structopt(long, conflicts_with = "root")]
+    passwordless_sudo: bool,
+
+    /// Don't add the profile of the distro to the dropdown of Windows Terminal.
+    #[structopt(long)]
+    no_terminal_profile: bool,
+
+    /// The directory to install the virtual disk (ext4.vhdx) of the distro into, such as
+    /// D:\WSL\Distrod. Defaults to %LocalAppData%\<distro name>.
+    #[structopt(long)]
+    install_dir: Option<PathBuf>,
 }
 
 #[derive(Debug, StructOpt)]
@@ -61,42 +124,139 @@ pub struct RunOpts {
 pub struct ConfigOpts {
     #[structopt(long)]
     default_user: Option<String>,
+
+    /// A TOML file on Windows to install as /etc/distrod/distrod.toml of the distro.
+    #[structopt(long)]
+    distro_config: Option<PathBuf>,
+}
+
+#[derive(Debug, StructOpt)]
+#[structopt(rename_all = "kebab")]
+pub struct MoveOpts {
+    /// The directory to move the virtual disk into, such as D:\WSL\Distrod.
+    install_dir: PathBuf,
+}
+
+#[derive(Debug, StructOpt)]
+#[structopt(rename_all = "kebab")]
+pub struct CompletionOpts {
+    /// The shell to print the completion script for.
+    #[structopt(possible_values = COMPLETION_SHELLS)]
+    shell: CompletionShell,
+}
+
+#[derive(Debug, StructOpt)]
+#[structopt(rename_all = "kebab")]
+pub struct DeleteOpts {
+    /// Delete without the confirmation prompt.
+    #[structopt(short, long)]
+    yes: bool,
 }
 
 fn main() {
     let opts = Opts::from_args();
     init_logger("Distrod".to_owned(), opts.log_level.clone());
+    cli_ui::set_non_interactive(opts.non_interactive);
 
     if let Err(err) = run(opts) {
         log::error!("{:?}", err);
-        std::process::exit(1);
+        std::process::exit(failure::get_exit_code(&err));
     }
 }
 
 fn run(opts: Opts) -> Result<()> {
-    let distro_name = opts.distro_name.unwrap_or_else(|| DISTRO_NAME.to_owned());
+    if let Some(Subcommand::Completion(completion_opts)) = opts.command {
+        return print_completion(completion_opts);
+    }
+    let prompts_name = matches!(
+        opts.command,
+        Some(Subcommand::Install(ref install_opts)) if !install_opts.yes
+    );
+    let distro_name = match opts.distro_name {
+        Some(distro_name) => distro_name,
+        None if prompts_name => prompt_distro_name()?,
+        None => match opts.command {
+            Some(Subcommand::Migrate(ref migrate_opts)) => {
+                migrate::get_default_distro_name(migrate_opts)
+            }
+            _ => DISTRO_NAME.to_owned(),
+        },
+    };
+    let paths = LauncherPaths::new(opts.portable)
+        .with_context(|| "Failed to get the directories of the launcher.")?;
     match opts.command {
         None => {
             let run_opts = RunOpts { cmd: vec![] };
-            run_distro(&distro_name, run_opts)?;
+            run_distro(&distro_name, run_opts, &paths)?;
         }
         Some(Subcommand::Run(run_opts)) => {
-            run_distro(&distro_name, run_opts)?;
+            run_distro(&distro_name, run_opts, &paths)?;
         }
         Some(Subcommand::Install(install_opts)) => {
-            install_distro(&distro_name, install_opts)?;
+            install_distro(&distro_name, install_opts, &paths)?;
         }
         Some(Subcommand::Config(config_opts)) => {
-            config_distro(&distro_name, config_opts)?;
+            config_distro(&distro_name, config_opts, &paths)?;
+        }
+        Some(Subcommand::Delete(delete_opts)) => {
+            delete_distro(&distro_name, delete_opts, &paths)?;
+        }
+        Some(Subcommand::Move(move_opts)) => {
+            move_distro(&distro_name, move_opts)?;
         }
+        Some(Subcommand::Migrate(migrate_opts)) => {
+            migrate::migrate_distro(&distro_name, migrate_opts, &paths)?;
+        }
+        Some(Subcommand::Completion(_)) => unreachable!("[BUG] completion is handled above."),
     }
     Ok(())
 }
 
-fn run_distro(distro_name: &str, opts: RunOpts) -> Result<()> {
+/// Prints the completion script, which completes the names of the distros registered to WSL
+/// after --distro-name.
+fn print_completion(opts: CompletionOpts) -> Result<()> {
+    let clap_shell = match opts.shell {
+        CompletionShell::Bash => Shell::Bash,
+        CompletionShell::Zsh => Shell::Zsh,
+        CompletionShell::Fish => Shell::Fish,
+        CompletionShell::PowerShell => Shell::PowerShell,
+    };
+    let bin_name = "distrod_wsl_launcher";
+    let mut script = vec![];
+    Opts::clap().gen_completions_to(bin_name, clap_shell, &mut script);
+    let script = String::from_utf8(script)
+        .with_context(|| "The generated completion script is not UTF-8.")?;
+
+    let list_command = match opts.shell {
+        CompletionShell::PowerShell => LIST_DISTROS_POSH_COMMAND.to_owned(),
+        _ => format!(
+            "powershell.exe -NoProfile -Command '{}' | tr -d '\\r'",
+            LIST_DISTROS_POSH_COMMAND
+        ),
+    };
+    let values = DynamicValues {
+        options: &["--distro-name", "--name", "-d"],
+        command: &list_command,
+    };
+    print!(
+        "{}",
+        completion::add_dynamic_values(&script, opts.shell, bin_name, &values)
+    );
+    Ok(())
+}
+
+fn run_distro(distro_name: &str, opts: RunOpts, paths: &LauncherPaths) -> Result<()> {
     if !unsafe { wsl::is_distribution_registered(distro_name) } {
-        let install_opts = InstallOpts { root: false };
-        return install_distro(distro_name, install_opts);
+        let install_opts = InstallOpts {
+            root: false,
+            yes: false,
+            image: None,
+            default_user: None,
+            passwordless_sudo: false,
+            no_terminal_profile: false,
+            install_dir: None,
+        };
+        return install_distro(distro_name, install_opts, paths);
     }
 
     let mut command = wsl::WslCommand::new(opts.cmd.get(0), distro_name);
@@ -109,7 +269,7 @@ fn run_distro(distro_name: &str, opts: RunOpts) -> Result<()> {
     Ok(())
 }
 
-fn config_distro(distro_name: &str, opts: ConfigOpts) -> Result<()> {
+fn config_distro(distro_name: &str, opts: ConfigOpts, paths: &LauncherPaths) -> Result<()> {
     if let Some(ref default_user) = opts.default_user {
         let uid = match default_user.parse::<u32>() {
             Ok(uid) => uid,
@@ -120,14 +280,342 @@ fn config_distro(distro_name: &str, opts: ConfigOpts) -> Result<()> {
             wsl::set_distribution_default_user(distro_name, uid)
                 .with_context(|| "Failed to set the default user")?;
         }
+        // Keep the profile of Windows Terminal logging in as the default user.
+        let has_terminal_profile = paths
+            .get_terminal_fragment_path(distro_name)
+            .map_or(false, |path| path.exists());
+        if has_terminal_profile {
+            if let Err(e) = install_terminal_profile(distro_name, default_user, paths) {
+                log::warn!("Failed to update the profile of Windows Terminal. {:?}", e);
+            }
+        }
+    }
+    if let Some(ref distro_config) = opts.distro_config {
+        install_distro_config(distro_name, distro_config)
+            .with_context(|| format!("Failed to install {:?}.", distro_config))?;
     }
     log::info!("Configuration done.");
 
     Ok(())
 }
 
+fn install_distro_config(distro_name: &str, config_path: &Path) -> Result<()> {
+    let cont = std::fs::read_to_string(config_path)
+        .with_context(|| format!("Failed to read {:?}.", config_path))?;
+    // Validate it here, since an invalid config makes the distro fail to start.
+    DistroConfig::from_toml_str(&cont)?;
+
+    let mut install = wsl::WslCommand::new(Some("/bin/sh"), distro_name);
+    install.user("root").arg("-c").arg(format!(
+        "mkdir -p \"$(dirname {path})\" && cat > {path}",
+        path = DISTRO_CONFIG_PATH
+    ));
+    let status = install
+        .status_with_input(cont.as_bytes())
+        .with_context(|| "Failed to write the config into the distro.")?;
+    if status != 0 {
+        bail!("Writing the config exited with error code {}.", status);
+    }
+    log::info!("The config has been installed. It takes effect when the distro starts next time.");
+    Ok(())
+}
+
+fn delete_distro(distro_name: &str, opts: DeleteOpts, paths: &LauncherPaths) -> Result<()> {
+    if !unsafe { wsl::is_distribution_registered(distro_name) } {
+        bail!("{} is not registered.", distro_name);
+    }
+    if !opts.yes
+        && !prompt_yes_no(&format!(
+            "{} and all the files in it will be deleted. Are you sure?",
+            distro_name
+        ))?
+    {
+        log::info!("Deletion has been cancelled.");
+        return Ok(());
+    }
+
+    // The forwards are kept in the distro, so they are read before it's gone.
+    log::info!("Removing the port forwards of {}...", distro_name);
+    let forwards = query_port_forwards(distro_name).unwrap_or_else(|e| {
+        log::warn!(
+            "Failed to get the port forwards. Their firewall rules might remain. {:?}",
+            e
+        );
+        vec![]
+    });
+    // Stopping the distro stops portproxy.service, which closes the forwarded ports.
+    log::info!("Stopping {}...", distro_name);
+    let mut distrod_stop =
+        wsl::WslCommand::new(Some(distrod_config::get_distrod_bin_path()), distro_name);
+    distrod_stop.user("root").arg("stop");
+    match distrod_stop.status() {
+        Ok(0) => {}
+        Ok(status) => log::debug!("`distrod stop` exited with {}.", status),
+        Err(e) => log::warn!("Failed to run `distrod stop`. {:?}", e),
+    }
+    remove_firewall_rules(&forwards);
+
+    // `distrod disable` removes the autostart task on the Windows side, which would otherwise
+    // keep trying to start the deleted distro.
+    log::info!("Disabling Distrod in {}...", distro_name);
+    let mut distrod_disable =
+        wsl::WslCommand::new(Some(distrod_config::get_distrod_bin_path()), distro_name);
+    distrod_disable.arg("disable");
+    match distrod_disable.status() {
+        Ok(0) => {}
+        Ok(status) => log::warn!(
+            "`distrod disable` exited with {}. The autostart task might remain.",
+            status
+        ),
+        Err(e) => log::warn!(
+            "Failed to run `distrod disable`. The autostart task might remain. {:?}",
+            e
+        ),
+    }
+
+    // Get it before the unregistration removes it from the registry.
+    let base_path = query_distro_base_path(distro_name).unwrap_or_else(|e| {
+        log::debug!("Failed to get the install directory. {:?}", e);
+        None
+    });
+    log::info!("Unregistering {}. This may take a while...", distro_name);
+    unsafe {
+        wsl::unregister_distribution(distro_name)
+            .with_context(|| format!("Failed to unregister {}.", distro_name))?;
+    }
+
+    remove_terminal_profile(distro_name, paths);
+
+    // The directory `wsl --import` installed the distro to is left after the unregistration.
+    // A directory given by --install-dir is removed only if it's empty, since it may be shared.
+    let default_install_dir = paths.get_install_dir(distro_name);
+    match base_path {
+        Some(base_path) if base_path != default_install_dir => {
+            match std::fs::remove_dir(&base_path) {
+                Ok(_) => log::info!("Removed {:?}.", &base_path),
+                Err(e) => log::debug!("Left {:?}. {:?}", &base_path, e),
+            }
+        }
+        _ => {
+            if !is_installed_by_wsl_api(distro_name, paths) && default_install_dir.exists() {
+                match std::fs::remove_dir_all(&default_install_dir) {
+                    Ok(_) => log::info!("Removed {:?}.", &default_install_dir),
+                    Err(e) => log::warn!("Failed to remove {:?}. {:?}", &default_install_dir, e),
+                }
+            }
+        }
+    }
+    remove_cached_images(paths);
+    log::info!("{} has been deleted.", distro_name);
+    Ok(())
+}
+
+/// Lists the forwards portproxy.service of the distro keeps, by `distrod port list`.
+fn query_port_forwards(distro_name: &str) -> Result<Vec<PortForwardRule>> {
+    let mut port_list =
+        wsl::WslCommand::new(Some(distrod_config::get_distrod_bin_path()), distro_name);
+    port_list
+        .user("root")
+        .args(["port", "list", "--format", "tsv"]);
+    let output = port_list
+        .output()
+        .with_context(|| "Failed to run `distrod port list`.")?;
+    if output.status != 0 {
+        bail!("`distrod port list` exited with {}.", output.status);
+    }
+    let list = String::from_utf8(output.stdout)
+        .with_context(|| "The output of `distrod port list` is invalid utf-8.")?;
+    // The first line has the keys.
+    list.lines()
+        .skip(1)
+        .filter(|line| !line.is_empty())
+        .map(parse_port_forward_row)
+        .collect()
+}
+
+/// Parses a row of `distrod port list --format tsv`, such as "0.0.0.0:80\tWSL:80\ttcp\trules".
+fn parse_port_forward_row(row: &str) -> Result<PortForwardRule> {
+    let fields: Vec<&str> = row.split('\t').collect();
+    if fields.len() != 4 {
+        bail!("Unexpected row of `distrod port list`: '{}'", row);
+    }
+    let (listen_address, listen_port) = fields[0]
+        .rsplit_once(':')
+        .ok_or_else(|| anyhow!("Invalid listen address: '{}'", fields[0]))?;
+    let protocol = match fields[2] {
+        "tcp" => Protocol::Tcp,
+        "udp" => Protocol::Udp,
+        protocol => bail!("Unknown protocol: '{}'", protocol),
+    };
+    Ok(PortForwardRule {
+        listen_port: listen_port
+            .parse()
+            .with_context(|| format!("Invalid listen port: '{}'", listen_port))?,
+        listen_address: listen_address
+            .trim_start_matches('[')
+            .trim_end_matches(']')
+            .to_owned(),
+        dest_address: None,
+        dest_port: None,
+        protocol,
+    })
+}
+
+/// Removes the firewall rules portproxy.exe has added for the forwards, which stay on Windows if
+/// it was killed before it removed them. Removing a rule which doesn't exist is not an error.
+fn remove_firewall_rules(forwards: &[PortForwardRule]) {
+    for forward in forwards {
+        let status = Command::new("powershell.exe")
+            .args([
+                "-NoProfile",
+                "-NonInteractive",
+                "-Command",
+                &forward.build_delete_firewall_rule_command(),
+            ])
+            .status();
+        match status {
+            Ok(status) if status.success() => {
+                log::info!(
+                    "Removed the firewall rule '{}'.",
+                    forward.firewall_rule_name()
+                )
+            }
+            Ok(status) => log::warn!(
+                "Failed to remove the firewall rule '{}'. powershell.exe exited with {}.",
+                forward.firewall_rule_name(),
+                status
+            ),
+            Err(e) => log::warn!(
+                "Failed to remove the firewall rule '{}'. {:?}",
+                forward.firewall_rule_name(),
+                e
+            ),
+        }
+    }
+}
+
+/// Removes the image files an interrupted installation left in the portable mode. The ones of an
+/// installation still running are in use, and fail to be removed.
+fn remove_cached_images(paths: &LauncherPaths) {
+    let cache_dir = match paths.get_cache_dir() {
+        Some(cache_dir) if cache_dir.exists() => cache_dir,
+        _ => return,
+    };
+    match std::fs::remove_dir_all(&cache_dir) {
+        Ok(_) => log::info!("Removed the cached images in {:?}.", &cache_dir),
+        Err(e) => log::debug!("Left {:?}. {:?}", &cache_dir, e),
+    }
+}
+
+fn move_distro(distro_name: &str, opts: MoveOpts) -> Result<()> {
+    if !unsafe { wsl::is_distribution_registered(distro_name) } {
+        bail!("{} is not registered.", distro_name);
+    }
+    let install_dir = prepare_install_dir(&opts.install_dir)?;
+    // The virtual disk can't be moved while the distro is running.
+    log::info!("Stopping {}...", distro_name);
+    let status = Command::new("wsl.exe")
+        .args(["--terminate", distro_name])
+        .status()
+        .with_context(|| "Failed to launch wsl.exe command.")?;
+    if !status.success() {
+        bail!("Failed to stop {}.", distro_name);
+    }
+    log::info!(
+        "Moving {} into {:?}. This may take a while...",
+        distro_name,
+        &install_dir
+    );
+    let status = Command::new("wsl.exe")
+        .args(["--manage", distro_name, "--move"])
+        .arg(&install_dir)
+        .status()
+        .with_context(|| "Failed to launch wsl.exe command.")?;
+    if !status.success() {
+        bail!(
+            "Failed: wsl --manage {} --move {:?}. Moving needs WSL 2.3.26 or later. Update it by `wsl --update`.",
+            distro_name,
+            &install_dir
+        );
+    }
+    log::info!("{} has been moved into {:?}.", distro_name, &install_dir);
+    Ok(())
+}
+
+/// Makes `install_dir` absolute, and checks that no other distro is installed in it.
+fn prepare_install_dir(install_dir: &Path) -> Result<PathBuf> {
+    let install_dir = if install_dir.is_absolute() {
+        install_dir.to_owned()
+    } else {
+        std::env::current_dir()
+            .with_context(|| "Failed to get the current directory.")?
+            .join(install_dir)
+    };
+    if install_dir.join("ext4.vhdx").exists() {
+        bail!(
+            "{:?} already has ext4.vhdx of another distro. Choose another directory.",
+            &install_dir
+        );
+    }
+    Ok(install_dir)
+}
+
+/// Gets the directory of the virtual disk of the distro from the registry of WSL.
+fn query_distro_base_path(distro_name: &str) -> Result<Option<PathBuf>> {
+    let script = format!(
+        "Get-ChildItem HKCU:\\Software\\Microsoft\\Windows\\CurrentVersion\\Lxss | Get-ItemProperty | \
+         Where-Object {{ $_.DistributionName -eq '{}' }} | Select-Object -ExpandProperty BasePath",
+        escape_posh_string(distro_name)
+    );
+    let output = Command::new("powershell.exe")
+        .args(["-NoProfile", "-NonInteractive", "-Command", &script])
+        .output()
+        .with_context(|| "Failed to launch powershell.exe.")?;
+    if !output.status.success() {
+        bail!("powershell.exe exited with {}.", output.status);
+    }
+    let base_path = String::from_utf8(output.stdout)
+        .with_context(|| "The output of powershell.exe is invalid utf-8.")?;
+    let base_path = base_path.trim();
+    let base_path = base_path.strip_prefix(r"\\?\").unwrap_or(base_path);
+    if base_path.is_empty() {
+        return Ok(None);
+    }
+    Ok(Some(PathBuf::from(base_path)))
+}
+
+/// Escapes a string for a single-quoted string of PowerShell, which takes the typographic single
+/// quotes as the quote as well.
+fn escape_posh_string(s: &str) -> String {
+    let mut escaped = String::with_capacity(s.len());
+    for c in s.chars() {
+        if matches!(c, '\'' | '\u{2018}' | '\u{2019}' | '\u{201a}' | '\u{201b}') {
+            escaped.push(c);
+        }
+        escaped.push(c);
+    }
+    escaped
+}
+
 #[tokio::main]
-async fn install_distro(distro_name: &str, opts: InstallOpts) -> Result<()> {
+async fn install_distro(distro_name: &str, opts: InstallOpts, paths: &LauncherPaths) -> Result<()> {
+    validate_distro_name(distro_name).context(LauncherFailure::InvalidOption)?;
+    if let Some(ref default_user) = opts.default_user {
+        validate_user_name(default_user).context(LauncherFailure::InvalidOption)?;
+    }
+    if unsafe { wsl::is_distribution_registered(distro_name) } {
+        return Err(anyhow!(
+            "{} is already registered. Choose another name by --name.",
+            distro_name
+        ))
+        .context(LauncherFailure::AlreadyRegistered);
+    }
+    let install_dir = match opts.install_dir {
+        Some(ref install_dir) => {
+            Some(prepare_install_dir(install_dir).context(LauncherFailure::InvalidOption)?)
+        }
+        None => None,
+    };
     println!(
         r"
         ██████╗ ██╗███████╗████████╗██████╗  ██████╗ ██████╗ 
@@ -145,16 +633,26 @@ You can install a local .tar.xz, or download an image from linuxcontainers.org.
   BTW, you can run Systemd with distrod, so you can try LXC/LXD with distrod!
 ================================================================================="
     );
-    let container_org_root_tarxz = fetch_distro_image()
+    let image = choose_distro_image(&opts)
+        .await
+        .with_context(|| "Failed to choose a distro image.")
+        .context(LauncherFailure::Image)?;
+
+    let cancel = CancellationToken::new();
+    cancellation::cancel_on_ctrl_c(&cancel);
+
+    let container_org_root_tarxz = open_distro_image(image, &cancel)
         .await
-        .with_context(|| "Failed to fetch a distro image.")?;
+        .with_context(|| "Failed to fetch a distro image.")
+        .context(LauncherFailure::Image)?;
     let container_org_tar = tar::Archive::new(XzDecoder::new(container_org_root_tarxz));
 
     log::info!(
         "Unpacking and merging the given rootfs to the distrod rootfs. This may take a while..."
     );
-    let tmp_dir = tempdir().with_context(|| "Failed to create a tempdir")?;
-    let install_targz_path = merge_tar_archive(&tmp_dir, container_org_tar)?;
+    let tmp_dir = paths.create_work_dir()?;
+    let install_targz_path =
+        merge_tar_archive(&tmp_dir, container_org_tar, &cancel).context(LauncherFailure::Image)?;
     if let Ok(rootfs_save_path) = std::env::var("SAVE_ROOTFS") {
         log::info!(
             "Copying the rootfs to the specified path. {:?}",
@@ -168,29 +666,145 @@ You can install a local .tar.xz, or download an image from linuxcontainers.org.
         })?;
     }
 
+    cancel.check()?;
     log::info!("Now Windows is installing the new distribution. This may take a while...");
-    register_distribution(distro_name, &install_targz_path)
-        .with_context(|| "Failed to register the distribution.")?;
+    register_distribution(
+        distro_name,
+        &install_targz_path,
+        install_dir.as_deref(),
+        paths,
+    )
+    .with_context(|| "Failed to register the distribution.")
+    .context(LauncherFailure::Registration)?;
     log::info!("Done!");
 
-    let uid = if !opts.root {
-        let user_name = prompt_string("Please input the new Linux user name. This doesn't have to be the same as your Windows user name.", "user name", None)?;
-        let uid = add_user(distro_name, &user_name);
-        if let Err(ref e) = uid {
-            log::warn!(
-                "Adding a user failed, but you can try adding a new user as the root after installation. {:?}",
-                e
-            );
+    let user_name = match set_up_registered_distribution(distro_name, &opts, &cancel) {
+        Ok(user_name) => user_name,
+        Err(e) if cancel.is_cancelled() => {
+            // Roll back the registration so that a half-initialized distro isn't left behind.
+            log::info!("Unregistering {}...", distro_name);
+            if let Err(e) = unsafe { wsl::unregister_distribution(distro_name) } {
+                log::warn!("Failed to unregister {}. {:?}", distro_name, e);
+            }
+            return Err(e).context(LauncherFailure::Cancelled);
         }
-        uid.unwrap_or(0)
-    } else {
-        0
+        Err(e) => return Err(e).context(LauncherFailure::SetUp),
+    };
+    if !opts.no_terminal_profile {
+        if let Err(e) = install_terminal_profile(distro_name, &user_name, paths) {
+            log::warn!("Failed to add the profile to Windows Terminal. {:?}", e);
+        }
+    }
+
+    log::info!("Installation of Distrod is now complete.");
+    if distro_name != DISTRO_NAME {
+        log::info!(
+            "Run it later by `wsl -d {name}` or `distrod_wsl_launcher --name {name}`.",
+            name = distro_name
+        );
+    }
+    if opts.yes || !cli_ui::is_interactive() {
+        return Ok(());
+    }
+    let _ = wsl::WslCommand::new::<String, _>(None, distro_name)
+        .status()
+        .with_context(|| "Failed to initialize the rootfs image inside WSL.")?;
+
+    log::info!("Hit enter to exit.");
+    let mut s = String::new();
+    let _ = io::stdin().read_line(&mut s);
+
+    Ok(())
+}
+
+fn prompt_distro_name() -> Result<String> {
+    loop {
+        let name = prompt_string(
+            "Please input the name of the new distro in WSL, such as Distrod-Ubuntu.",
+            "distro name",
+            Some(DISTRO_NAME),
+        )?;
+        let name = if name.is_empty() {
+            DISTRO_NAME.to_owned()
+        } else {
+            name
+        };
+        match validate_distro_name(&name) {
+            Ok(_) => return Ok(name),
+            Err(e) => log::error!("{}", e),
+        }
+    }
+}
+
+/// Asks the name of the default user. Returns None if it's left empty to use only root.
+fn prompt_user_name() -> Result<Option<String>> {
+    loop {
+        let name = prompt_string("Please input the new Linux user name. This doesn't have to be the same as your Windows user name. Leave it empty to use only root.", "user name", None)?;
+        if name.is_empty() {
+            return Ok(None);
+        }
+        match validate_user_name(&name) {
+            Ok(_) => return Ok(Some(name)),
+            Err(e) => log::error!("{}", e),
+        }
+    }
+}
+
+/// Accepts the names `wsl --import` accepts, which are also safe as a directory name.
+fn validate_distro_name(name: &str) -> Result<()> {
+    let is_valid = !name.is_empty()
+        && name
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_' || c == '.')
+        && !name.starts_with('.');
+    if !is_valid {
+        bail!(
+            "Invalid distro name: '{}'. Use letters, digits, '-', '_', and '.'.",
+            name
+        );
+    }
+    Ok(())
+}
+
+/// Adds the user and enables Distrod in the registered distro. Returns the name of the default user.
+fn set_up_registered_distribution(
+    distro_name: &str,
+    opts: &InstallOpts,
+    cancel: &CancellationToken,
+) -> Result<String> {
+    let user_name = match opts.default_user {
+        Some(ref user_name) => Some(user_name.clone()),
+        // Only root is set up by default.
+        None if opts.root || opts.yes || !cli_ui::is_interactive() => None,
+        None => prompt_user_name()?,
+    };
+    let passwordless_sudo = match user_name {
+        Some(ref user_name) if !opts.passwordless_sudo && !opts.yes && cli_ui::is_interactive() => {
+            prompt_yes_no(&format!(
+                "Do you want {} to use sudo without the password?",
+                user_name
+            ))?
+        }
+        Some(_) => opts.passwordless_sudo,
+        None => false,
     };
 
+    cancel.check()?;
     log::info!("Initializing the new Distrod distribution. This may take a while...");
     let mut distrod_enable =
         wsl::WslCommand::new(Some(distrod_config::get_distrod_bin_path()), distro_name);
     distrod_enable.args(["enable", "-d"]);
+    // `distrod enable` creates the user, and makes it the default in /etc/wsl.conf and the
+    // config of the distro.
+    if let Some(ref user_name) = user_name {
+        distrod_enable.args(["--user", user_name]);
+        if !opts.yes && cli_ui::is_interactive() {
+            distrod_enable.arg("--password");
+        }
+        if passwordless_sudo {
+            distrod_enable.arg("--passwordless-sudo");
+        }
+    }
     let exit_code = distrod_enable
         .status()
         .with_context(|| "Failed to initialize the rootfs image inside WSL.")?;
@@ -202,6 +816,19 @@ You can install a local .tar.xz, or download an image from linuxcontainers.org.
         );
     }
 
+    let (uid, user_name) = match user_name {
+        Some(user_name) => {
+            log::info!(
+                "Querying the generated uid. This may take some time depending on your machine."
+            );
+            let uid = query_uid(distro_name, &user_name)
+                .with_context(|| format!("Failed to get the uid of {}.", &user_name))?;
+            (uid, user_name)
+        }
+        None => (0, "root".to_owned()),
+    };
+
+    cancel.check()?;
     if uid != 0 {
         // This should be done after enable, because this changes the default user from root.
         log::info!("Setting the default user to uid: {}", uid);
@@ -213,31 +840,118 @@ You can install a local .tar.xz, or download an image from linuxcontainers.org.
             log::info!("You can configure the default user later by `distrod_wsl_launcher config --default-user USER_NAME`");
         }
     }
+    Ok(user_name)
+}
 
-    log::info!("Installation of Distrod is now complete.");
-    let _ = wsl::WslCommand::new::<String, _>(None, distro_name)
-        .status()
-        .with_context(|| "Failed to initialize the rootfs image inside WSL.")?;
+/// Writes the Windows Terminal fragment with the profile which logs in to the distro as `user`,
+/// so that the distro shows up in the dropdown of Windows Terminal.
+fn install_terminal_profile(distro_name: &str, user: &str, paths: &LauncherPaths) -> Result<()> {
+    let fragment_path = match paths.get_terminal_fragment_path(distro_name) {
+        Some(path) => path,
+        None => {
+            log::debug!("The profile of Windows Terminal is not added in the portable mode.");
+            return Ok(());
+        }
+    };
+    let entry = query_passwd_entry(distro_name, user)
+        .with_context(|| format!("Failed to get the passwd entry of {}.", user))?;
+    let mut profile = TerminalProfile::new(
+        distro_name,
+        distrod_config::get_distrod_bin_path(),
+        &entry.name,
+        &entry.home,
+        &entry.shell,
+    );
+    if let Ok(exe_path) = std::env::current_exe() {
+        profile.with_icon(exe_path.to_string_lossy().into_owned());
+    }
+    let fragment_dir = fragment_path
+        .parent()
+        .ok_or_else(|| anyhow!("[BUG] the fragment path has a parent."))?;
+    std::fs::create_dir_all(fragment_dir)
+        .with_context(|| format!("Failed to create {:?}.", fragment_dir))?;
+    std::fs::write(&fragment_path, profile.to_fragment_json()?)
+        .with_context(|| format!("Failed to write {:?}.", &fragment_path))?;
+    log::info!(
+        "{} has been added to the profiles of Windows Terminal.",
+        distro_name
+    );
+    Ok(())
+}
 
-    log::info!("Hit enter to exit.");
-    let mut s = String::new();
-    let _ = io::stdin().read_line(&mut s);
+fn remove_terminal_profile(distro_name: &str, paths: &LauncherPaths) {
+    let fragment_path = match paths.get_terminal_fragment_path(distro_name) {
+        Some(path) if path.exists() => path,
+        _ => return,
+    };
+    match std::fs::remove_file(&fragment_path) {
+        Ok(_) => log::info!("Removed the Windows Terminal profile."),
+        Err(e) => log::warn!("Failed to remove {:?}. {:?}", &fragment_path, e),
+    }
+}
 
-    Ok(())
+struct PasswdEntry {
+    name: String,
+    home: String,
+    shell: String,
 }
 
-async fn fetch_distro_image() -> Result<Box<dyn Read>> {
+/// Gets the entry of `user`, which is a name or a uid, from /etc/passwd of the distro.
+fn query_passwd_entry(distro_name: &str, user: &str) -> Result<PasswdEntry> {
+    let mut getent = wsl::WslCommand::new(Some("getent"), distro_name);
+    getent.args(["passwd", user]);
+    let output = getent
+        .output()
+        .with_context(|| "Failed to spawn getent command.")?;
+    if output.status != 0 {
+        bail!("'getent passwd' exited with error code. {}", output.status);
+    }
+    let line = String::from_utf8(output.stdout)
+        .with_context(|| "The output of getent command is invalid utf-8.")?;
+    let fields: Vec<&str> = line.trim().split(':').collect();
+    if fields.len() != 7 {
+        bail!("getent command has written an unexpected data: '{}'", line);
+    }
+    let shell = if fields[6].is_empty() {
+        "/bin/sh"
+    } else {
+        fields[6]
+    };
+    Ok(PasswdEntry {
+        name: fields[0].to_owned(),
+        home: fields[5].to_owned(),
+        shell: shell.to_owned(),
+    })
+}
+
+async fn choose_distro_image(opts: &InstallOpts) -> Result<DistroImage> {
+    if opts.image.is_some() || opts.yes {
+        let image = opts.image.as_deref().unwrap_or("");
+        if Path::new(image).is_file() {
+            return Ok(DistroImage {
+                name: image.to_owned(),
+                image: DistroImageFile::Local(image.into()),
+            });
+        }
+        return fetch_container_org_image(&distro_image::choose_by_path(image)).await;
+    }
     let local_image_fetcher =
         || Ok(Box::new(LocalDistroImage::new(&cli_ui::prompt_path)) as Box<dyn DistroImageFetcher>);
     let container_org_image_fetcher =
         || Ok(Box::new(ContainerOrgImageList::default()) as Box<dyn DistroImageFetcher>);
-    let fetchers = vec![
+    let fetchers = distro_image::with_registered_fetchers(vec![
         Box::new(local_image_fetcher) as DistroImageFetcherGen,
         Box::new(container_org_image_fetcher) as DistroImageFetcherGen,
-    ];
-    let image = distro_image::fetch_image(fetchers, &cli_ui::choose_from_list, 1)
+    ]);
+    distro_image::fetch_image(fetchers, &cli_ui::choose_from_list, 1)
         .await
-        .with_context(|| "Failed to fetch the image list.")?;
+        .with_context(|| "Failed to fetch the image list.")
+}
+
+async fn open_distro_image(
+    image: DistroImage,
+    cancel: &CancellationToken,
+) -> Result<Box<dyn Read>> {
     match image.image {
         DistroImageFile::Local(path) => {
             let file =
@@ -247,14 +961,18 @@ async fn fetch_distro_image() -> Result<Box<dyn Read>> {
         DistroImageFile::Url(url) => {
             log::info!("Downloading '{}'...", url);
             let mut bytes = vec![];
-            download_file_with_progress(&url, build_progress_bar, &mut bytes).await?;
+            download_file_with_progress(&url, build_progress_bar, &mut bytes, cancel).await?;
             log::info!("Download done.");
             Ok(Box::new(Cursor::new(bytes)) as Box<dyn Read>)
         }
     }
 }
 
-fn merge_tar_archive<R: Read>(work_dir: &TempDir, mut rootfs: tar::Archive<R>) -> Result<PathBuf> {
+fn merge_tar_archive<R: Read>(
+    work_dir: &TempDir,
+    mut rootfs: tar::Archive<R>,
+    cancel: &CancellationToken,
+) -> Result<PathBuf> {
     let distrod_targz = std::include_bytes!("../resources/distrod_root.tar.gz");
     let mut distrod_tar = tar::Archive::new(GzDecoder::new(std::io::Cursor::new(distrod_targz)));
 
@@ -270,30 +988,38 @@ fn merge_tar_archive<R: Read>(work_dir: &TempDir, mut rootfs: tar::Archive<R>) -
         &mut builder,
         &mut rootfs,
         vec!["/etc/resolv.conf"],
+        cancel,
     )
     .with_context(|| "Failed to merge the given image.")?;
-    tar_helper::append_tar_archive::<_, _, _, &str>(&mut builder, &mut distrod_tar, vec![])
+    tar_helper::append_tar_archive::<_, _, _, &str>(&mut builder, &mut distrod_tar, vec![], cancel)
         .with_context(|| "Failed to merge the given image.")?;
     builder.finish()?;
     drop(builder); // So that we can close the install_targz file.
     Ok(install_targz_path)
 }
 
-fn register_distribution<P: AsRef<Path>>(distro_name: &str, tar_gz_filename: P) -> Result<()> {
-    // Install the distro by WSL API only when this app is a Windows Store app and --distro-name is not given.
-    if distro_name == DISTRO_NAME && is_windows_store_app() {
+fn register_distribution<P: AsRef<Path>>(
+    distro_name: &str,
+    tar_gz_filename: P,
+    install_dir: Option<&Path>,
+    paths: &LauncherPaths,
+) -> Result<()> {
+    if install_dir.is_none() && is_installed_by_wsl_api(distro_name, paths) {
         unsafe {
             wsl::register_distribution(distro_name, tar_gz_filename)
                 .with_context(|| "Failed to register the distribution.")
         }
     } else {
         // Otherwise, use wsl.exe --import to install the distro for flexibility.
+        let install_dir = install_dir
+            .map(|dir| dir.to_owned())
+            .unwrap_or_else(|| paths.get_install_dir(distro_name));
         let mut cmd = Command::new("cmd.exe");
         cmd.arg("/C")
             .arg("wsl")
             .arg("--import")
             .arg(distro_name)
-            .arg(format!("%LocalAppData%\\{}", distro_name))
+            .arg(&install_dir)
             .arg(tar_gz_filename.as_ref());
         let mut child = cmd
             .spawn()
@@ -303,21 +1029,24 @@ fn register_distribution<P: AsRef<Path>>(distro_name: &str, tar_gz_filename: P)
             .with_context(|| "Failed to wait for wsl.exe command.")?;
         if !status.success() {
             bail!(
-                "Failed: cmd.exe /C wsl --import {} {} {:#?}",
+                "Failed: cmd.exe /C wsl --import {} {:?} {:#?}",
                 distro_name,
-                format!("%LocalAppData%\\{}", distro_name),
+                &install_dir,
                 tar_gz_filename.as_ref()
             );
         }
-        log::info!(
-            "{} is installed in %LocalAppData%\\{}",
-            distro_name,
-            distro_name
-        );
+        log::info!("{} is installed in {:?}", distro_name, &install_dir);
         Ok(())
     }
 }
 
+/// Whether the distro is installed by the WSL API, which decides the install directory by itself.
+/// This is only when this app is a Windows Store app and neither --distro-name nor --install-dir
+/// is given.
+fn is_installed_by_wsl_api(distro_name: &str, paths: &LauncherPaths) -> bool {
+    distro_name == DISTRO_NAME && !paths.is_portable() && is_windows_store_app()
+}
+
 fn is_windows_store_app() -> bool {
     let inner = || -> Result<bool> {
         let mut self_path =
@@ -329,36 +1058,6 @@ fn is_windows_store_app() -> bool {
     inner().unwrap_or(false)
 }
 
-fn add_user(distro_name: &str, user_name: &str) -> Result<u32> {
-    let mut user_add = wsl::WslCommand::new(Some("/bin/sh"), distro_name);
-    user_add.arg("-c");
-    user_add.arg(format!(
-        "if ! command -v useradd > /dev/null; then \
-             echo Error: no 'useradd' command found. exiting.; \
-             exit 1; \
-         fi; \
-         useradd -m --shell /bin/bash '{}' && \
-         if ! command -v passwd > /dev/null; then \
-             echo  no 'passwd' command found. exiting.; \
-             exit 1; \
-         fi; \
-         while ! passwd {}; do : ; done && \
-         echo '{} ALL=(ALL:ALL) ALL' >> /etc/sudoers",
-        user_name, user_name, user_name
-    ));
-    let status = user_add
-        .status()
-        .with_context(|| "Failed to invoke user_add")?;
-    if status != 0 {
-        bail!(
-            "The commands to add a user exited with error code {}",
-            status
-        );
-    }
-    log::info!("Querying the generated uid. This may take some time depending on your machine.");
-    query_uid(distro_name, user_name)
-}
-
 fn query_uid(distro_name: &str, user_name: &str) -> Result<u32> {
     let mut id = wsl::WslCommand::new(Some("id"), distro_name);
     id.arg("-u");
@@ -0,0 +1,261 @@
+use anyhow::{anyhow, bail, Context, Result};
+use libs::cancellation::{self, CancellationToken};
+use libs::cli_ui::prompt_yes_no;
+use libs::distrod_config;
+use std::fs::File;
+use std::io::BufReader;
+use std::path::{Path, PathBuf};
+use std::process::Command;
+use structopt::StructOpt;
+
+use crate::failure::LauncherFailure;
+use crate::paths::LauncherPaths;
+use crate::wsl;
+
+#[derive(Debug, StructOpt)]
+#[structopt(rename_all = "kebab")]
+pub struct MigrateOpts {
+    /// The registered distro to migrate, such as Ubuntu from Microsoft Store. It's kept as it is
+    /// unless --unregister-source is given.
+    #[structopt(long)]
+    from: String,
+
+    /// Migrate without the confirmation prompt.
+    #[structopt(short, long)]
+    yes: bool,
+
+    /// Unregister the source distro once the migration succeeds, which deletes all the files
+    /// in it.
+    #[structopt(long)]
+    unregister_source: bool,
+
+    /// Don't add the profile of the new distro to the dropdown of Windows Terminal.
+    #[structopt(long)]
+    no_terminal_profile: bool,
+
+    /// The directory to install the virtual disk (ext4.vhdx) of the new distro into. Defaults to
+    /// %LocalAppData%\<distro name>.
+    #[structopt(long)]
+    install_dir: Option<PathBuf>,
+}
+
+/// The name of the new distro when --name is not given, such as Distrod-Ubuntu.
+pub fn get_default_distro_name(opts: &MigrateOpts) -> String {
+    format!("Distrod-{}", &opts.from)
+}
+
+/// Copies a registered distro into a new distro with Distrod, which is made from the export of
+/// the source with Distrod merged into it, and has the same default user.
+pub fn migrate_distro(distro_name: &str, opts: MigrateOpts, paths: &LauncherPaths) -> Result<()> {
+    crate::validate_distro_name(distro_name).context(LauncherFailure::InvalidOption)?;
+    if !unsafe { wsl::is_distribution_registered(opts.from.as_str()) } {
+        return Err(anyhow!("{} is not registered.", &opts.from))
+            .context(LauncherFailure::InvalidOption);
+    }
+    if unsafe { wsl::is_distribution_registered(distro_name) } {
+        return Err(anyhow!(
+            "{} is already registered. Choose another name by --name.",
+            distro_name
+        ))
+        .context(LauncherFailure::AlreadyRegistered);
+    }
+    let install_dir = match opts.install_dir {
+        Some(ref install_dir) => {
+            Some(crate::prepare_install_dir(install_dir).context(LauncherFailure::InvalidOption)?)
+        }
+        None => None,
+    };
+    let default_user = query_default_user(&opts.from)
+        .with_context(|| format!("Failed to get the default user of {}.", &opts.from))?;
+    if !opts.yes {
+        let consequence = if opts.unregister_source {
+            "and then unregistered with all the files in it"
+        } else {
+            "and kept as it is"
+        };
+        let message = format!(
+            "{from} will be stopped and copied into a new distro {name} with Distrod, {consequence}. \
+             Continue?",
+            from = &opts.from,
+            name = distro_name,
+            consequence = consequence
+        );
+        if !prompt_yes_no(&message)? {
+            log::info!("Migration has been cancelled.");
+            return Ok(());
+        }
+    }
+
+    let cancel = CancellationToken::new();
+    cancellation::cancel_on_ctrl_c(&cancel);
+    let work_dir = paths.create_work_dir()?;
+    let export_path = work_dir.path().join("export.tar");
+    export_distribution(&opts.from, &export_path, &cancel).context(LauncherFailure::Image)?;
+
+    cancel.check()?;
+    log::info!("Merging Distrod into the export. This may take a while...");
+    let export = tar::Archive::new(BufReader::new(
+        File::open(&export_path).with_context(|| format!("Failed to open {:?}.", &export_path))?,
+    ));
+    let install_targz_path =
+        crate::merge_tar_archive(&work_dir, export, &cancel).context(LauncherFailure::Image)?;
+    // The export can be as large as the distro, so don't keep it until the import finishes.
+    if let Err(e) = std::fs::remove_file(&export_path) {
+        log::debug!("Failed to remove {:?}. {:?}", &export_path, e);
+    }
+
+    cancel.check()?;
+    log::info!(
+        "Now Windows is installing {}. This may take a while...",
+        distro_name
+    );
+    crate::register_distribution(
+        distro_name,
+        &install_targz_path,
+        install_dir.as_deref(),
+        paths,
+    )
+    .with_context(|| "Failed to register the distribution.")
+    .context(LauncherFailure::Registration)?;
+
+    if let Err(e) = set_up_migrated_distribution(distro_name, default_user.as_deref(), &cancel) {
+        // Roll back the registration, since the source is left as it was and can be migrated again.
+        log::info!("Unregistering {}...", distro_name);
+        if let Err(e) = unsafe { wsl::unregister_distribution(distro_name) } {
+            log::warn!("Failed to unregister {}. {:?}", distro_name, e);
+        }
+        let failure = if cancel.is_cancelled() {
+            LauncherFailure::Cancelled
+        } else {
+            LauncherFailure::SetUp
+        };
+        return Err(e).context(failure);
+    }
+    if !opts.no_terminal_profile {
+        let user = default_user.as_deref().unwrap_or("root");
+        if let Err(e) = crate::install_terminal_profile(distro_name, user, paths) {
+            log::warn!("Failed to add the profile to Windows Terminal. {:?}", e);
+        }
+    }
+
+    if opts.unregister_source {
+        log::info!("Unregistering {}. This may take a while...", &opts.from);
+        unsafe {
+            wsl::unregister_distribution(opts.from.as_str())
+                .with_context(|| format!("Failed to unregister {}.", &opts.from))?;
+        }
+    }
+    log::info!(
+        "{} has been migrated into {}. Run it by `wsl -d {name}` or `distrod_wsl_launcher --name {name}`.",
+        &opts.from,
+        distro_name,
+        name = distro_name
+    );
+    if !opts.unregister_source {
+        log::info!(
+            "{} is kept. Unregister it by `wsl --unregister {}` once you no longer need it.",
+            &opts.from,
+            &opts.from
+        );
+    }
+    Ok(())
+}
+
+/// Returns the user WSL logs in to the distro as, or None if it's root.
+fn query_default_user(distro_name: &str) -> Result<Option<String>> {
+    let output = wsl::WslCommand::new(Some("id"), distro_name)
+        .arg("-un")
+        .output()
+        .with_context(|| "Failed to spawn id command.")?;
+    if output.status != 0 {
+        bail!("'id -un' exited with error code. {}", output.status);
+    }
+    let user = String::from_utf8(output.stdout)
+        .with_context(|| "The output of id command is invalid utf-8.")?;
+    let user = user.trim();
+    if user.is_empty() || user == "root" {
+        return Ok(None);
+    }
+    Ok(Some(user.to_owned()))
+}
+
+/// Exports the distro by wsl.exe. Ctrl-C reaches wsl.exe as well and makes it fail, so its
+/// failures are reported as the cancellation once `cancel` is cancelled.
+fn export_distribution(
+    distro_name: &str,
+    export_path: &Path,
+    cancel: &CancellationToken,
+) -> Result<()> {
+    // The files can change while the distro runs, so stop it to export a consistent copy.
+    log::info!("Stopping {}...", distro_name);
+    let status = Command::new("wsl.exe")
+        .args(["--terminate", distro_name])
+        .status()
+        .with_context(|| "Failed to launch wsl.exe command.")?;
+    if !status.success() {
+        cancel.check()?;
+        bail!("Failed to stop {}.", distro_name);
+    }
+    log::info!("Exporting {}. This may take a while...", distro_name);
+    let status = Command::new("wsl.exe")
+        .args(["--export", distro_name])
+        .arg(export_path)
+        .status()
+        .with_context(|| "Failed to launch wsl.exe command.")?;
+    if !status.success() {
+        cancel.check()?;
+        bail!("Failed: wsl --export {} {:?}", distro_name, export_path);
+    }
+    Ok(())
+}
+
+/// Enables Distrod in the imported distro, and brings the default user over, which WSL keeps
+/// outside the distro and doesn't export.
+fn set_up_migrated_distribution(
+    distro_name: &str,
+    default_user: Option<&str>,
+    cancel: &CancellationToken,
+) -> Result<()> {
+    cancel.check()?;
+    log::info!("Enabling Distrod in {}...", distro_name);
+    run_distrod(distro_name, &["enable", "-d"])?;
+
+    // Systemd started by WSL and the one started by Distrod can't run together.
+    let output = wsl::WslCommand::new(Some(distrod_config::get_distrod_bin_path()), distro_name)
+        .user("root")
+        .args(["wslconf", "get", "boot.systemd"])
+        .output()
+        .with_context(|| "Failed to read /etc/wsl.conf.")?;
+    if String::from_utf8_lossy(&output.stdout).trim() == "true" {
+        log::info!("Turning off the built-in systemd support of WSL in /etc/wsl.conf, since Distrod starts systemd instead.");
+        run_distrod(distro_name, &["wslconf", "unset", "boot.systemd"])?;
+    }
+
+    let user = match default_user {
+        Some(user) => user,
+        None => return Ok(()),
+    };
+    cancel.check()?;
+    log::info!("Making {} the default user.", user);
+    run_distrod(distro_name, &["wslconf", "set", "user.default", user])?;
+    let uid = crate::query_uid(distro_name, user)
+        .with_context(|| format!("Failed to get the uid of {}.", user))?;
+    if let Err(e) = crate::set_default_user(distro_name, uid) {
+        // /etc/wsl.conf makes it the default user anyway.
+        log::warn!("Failed to set the default user by the WSL API. {:?}", e);
+    }
+    Ok(())
+}
+
+fn run_distrod(distro_name: &str, args: &[&str]) -> Result<()> {
+    let mut distrod =
+        wsl::WslCommand::new(Some(distrod_config::get_distrod_bin_path()), distro_name);
+    distrod.user("root").args(args);
+    let exit_code = distrod
+        .status()
+        .with_context(|| format!("Failed to run {:?}.", &distrod))?;
+    if exit_code != 0 {
+        bail!("{:?} exited with {}.", &distrod, exit_code);
+    }
+    Ok(())
+}
@@ -0,0 +1,92 @@
+use anyhow::{anyhow, Context, Result};
+use libs::terminal_profile::FRAGMENT_APP_NAME;
+use std::path::{Path, PathBuf};
+use tempfile::TempDir;
+
+/// The name of the directory beside the launcher which holds the data in the portable mode.
+static PORTABLE_DATA_DIR_NAME: &str = "DistrodData";
+
+/// The locations on Windows where the launcher keeps the distros and its files.
+/// In the portable mode, everything is kept in the DistrodData directory beside the launcher,
+/// such as on a USB drive, instead of %LocalAppData% and the temp directory.
+#[derive(Debug, Clone)]
+pub struct LauncherPaths {
+    data_dir: PathBuf,
+    portable: bool,
+}
+
+impl LauncherPaths {
+    /// The portable mode is used if `portable` is true, or the DistrodData directory already
+    /// exists beside the launcher, so that a portable installation keeps being portable.
+    pub fn new(portable: bool) -> Result<LauncherPaths> {
+        let portable_data_dir = get_portable_data_dir()?;
+        if portable || portable_data_dir.exists() {
+            std::fs::create_dir_all(&portable_data_dir)
+                .with_context(|| format!("Failed to create {:?}.", &portable_data_dir))?;
+            log::debug!("Using the portable data dir {:?}.", &portable_data_dir);
+            return Ok(LauncherPaths {
+                data_dir: portable_data_dir,
+                portable: true,
+            });
+        }
+        let local_app_data = std::env::var_os("LocalAppData")
+            .ok_or_else(|| anyhow!("%LocalAppData% is not set."))?;
+        Ok(LauncherPaths {
+            data_dir: PathBuf::from(local_app_data),
+            portable: false,
+        })
+    }
+
+    pub fn is_portable(&self) -> bool {
+        self.portable
+    }
+
+    /// Returns the directory the virtual disk of the distro is installed into.
+    pub fn get_install_dir(&self, distro_name: &str) -> PathBuf {
+        self.data_dir.join(distro_name)
+    }
+
+    /// Returns the file of the Windows Terminal fragment with the profile of the distro.
+    /// This is None in the portable mode, which leaves nothing in %LocalAppData%.
+    pub fn get_terminal_fragment_path(&self, distro_name: &str) -> Option<PathBuf> {
+        if self.portable {
+            return None;
+        }
+        Some(
+            self.data_dir
+                .join(r"Microsoft\Windows Terminal\Fragments")
+                .join(FRAGMENT_APP_NAME)
+                .join(format!("{}.json", distro_name)),
+        )
+    }
+
+    /// Returns the directory in DistrodData which keeps the image files during the installation.
+    /// This is None out of the portable mode, which uses the temp directory of the system.
+    pub fn get_cache_dir(&self) -> Option<PathBuf> {
+        if !self.portable {
+            return None;
+        }
+        Some(self.data_dir.join("cache"))
+    }
+
+    /// Creates a temporary directory for the image files, which is removed when it's dropped.
+    pub fn create_work_dir(&self) -> Result<TempDir> {
+        let cache_dir = match self.get_cache_dir() {
+            Some(cache_dir) => cache_dir,
+            None => return tempfile::tempdir().with_context(|| "Failed to create a tempdir."),
+        };
+        std::fs::create_dir_all(&cache_dir)
+            .with_context(|| format!("Failed to create {:?}.", &cache_dir))?;
+        tempfile::tempdir_in(&cache_dir)
+            .with_context(|| format!("Failed to create a tempdir in {:?}.", &cache_dir))
+    }
+}
+
+fn get_portable_data_dir() -> Result<PathBuf> {
+    let exe_path =
+        std::env::current_exe().with_context(|| "Failed to get the current exe path.")?;
+    Ok(exe_path
+        .parent()
+        .unwrap_or_else(|| Path::new("."))
+        .join(PORTABLE_DATA_DIR_NAME))
+}
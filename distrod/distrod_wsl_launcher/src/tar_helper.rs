@@ -1,4 +1,5 @@
 use anyhow::{Context, Result};
+use libs::cancellation::CancellationToken;
 use std::collections::HashSet;
 use std::ffi::OsString;
 use std::io::{Cursor, Read};
@@ -9,6 +10,7 @@ pub fn append_tar_archive<W, R, I, P>(
     builder: &mut tar::Builder<W>,
     archive: &mut tar::Archive<R>,
     exclusion: I,
+    cancel: &CancellationToken,
 ) -> Result<()>
 where
     W: std::io::Write,
@@ -27,6 +29,7 @@ where
         .with_context(|| "Failed to open the archive")?;
 
     for entry in entries {
+        cancel.check()?;
         let mut entry = entry.with_context(|| "An archive entry is an error.")?;
 
         let path = entry
@@ -1,5 +1,6 @@
 use std::{
     ffi::{OsStr, OsString},
+    io::Write,
     path::Path,
 };
 
@@ -32,6 +33,16 @@ where
     WslRegisterDistribution(distributionname, path).with_context(|| err)
 }
 
+pub unsafe fn unregister_distribution<'a, Param0: IntoParam<'a, PWSTR> + std::fmt::Debug>(
+    distributionname: Param0,
+) -> Result<()> {
+    let err = format!(
+        "WslUnregisterDistribution failed. distro: {:?}",
+        &distributionname
+    );
+    WslUnregisterDistribution(distributionname).with_context(|| err)
+}
+
 pub unsafe fn set_distribution_default_user<'a, Param0: IntoParam<'a, PWSTR> + std::fmt::Debug>(
     distributionname: Param0,
     defaultuid: u32,
@@ -51,6 +62,7 @@ pub struct WslCommand {
     distribution_name: OsString,
     command: Option<OsString>,
     args: Vec<OsString>,
+    user: Option<OsString>,
 }
 
 pub struct WslCommandOutput {
@@ -68,9 +80,15 @@ impl WslCommand {
             distribution_name: distribution_name.as_ref().to_owned(),
             command: command.map(|s| s.as_ref().to_owned()),
             args: vec![],
+            user: None,
         }
     }
 
+    pub fn user<S: AsRef<OsStr>>(&mut self, user: S) -> &mut Self {
+        self.user = Some(user.as_ref().to_owned());
+        self
+    }
+
     pub fn arg<S: AsRef<OsStr>>(&mut self, arg: S) -> &mut Self {
         self.args.push(arg.as_ref().to_owned());
         self
@@ -98,6 +116,27 @@ impl WslCommand {
             .ok_or_else(|| anyhow!("Failed to get the exit code."))
     }
 
+    /// Runs the command with the given bytes as its stdin.
+    pub fn status_with_input(&mut self, input: &[u8]) -> Result<i32> {
+        let mut child = self
+            .gen_command()
+            .stdin(std::process::Stdio::piped())
+            .spawn()
+            .with_context(|| format!("Failed to spawn {:?}", &self))?;
+        child
+            .stdin
+            .take()
+            .ok_or_else(|| anyhow!("Failed to get the stdin of {:?}", &self))?
+            .write_all(input)
+            .with_context(|| format!("Failed to write to the stdin of {:?}", &self))?;
+        let status = child
+            .wait()
+            .with_context(|| format!("Failed to wait for {:?}", &self))?;
+        status
+            .code()
+            .ok_or_else(|| anyhow!("Failed to get the exit code."))
+    }
+
     pub fn output(&mut self) -> Result<WslCommandOutput> {
         // Use wsl command instead of winapi for now, since it seems more robust way to avoid
         // strange crash on Windows 11.
@@ -122,6 +161,10 @@ impl WslCommand {
         let mut command = std::process::Command::new("wsl");
         command.arg("-d");
         command.arg(&self.distribution_name);
+        if let Some(ref user) = self.user {
+            command.arg("-u");
+            command.arg(user);
+        }
         if let Some(ref command_name) = self.command {
             command.arg("--");
             command.arg(command_name);
@@ -0,0 +1,194 @@
+use anyhow::{Context, Result};
+use std::io::Read;
+use std::path::Path;
+
+use crate::error::ContainerError;
+
+const ELF_MAGIC: &[u8; 4] = b"\x7fELF";
+/// The offset of e_machine, which is the same for 32-bit and 64-bit ELF.
+const ELF_MACHINE_OFFSET: usize = 18;
+const EM_X86_64: u16 = 62;
+const EM_AARCH64: u16 = 183;
+
+/// The binaries which almost every rootfs has, to see the architecture of a rootfs by.
+const ROOTFS_PROBE_PATHS: &[&str] = &[
+    "usr/bin/env",
+    "bin/env",
+    "bin/busybox",
+    "usr/lib/systemd/systemd",
+    "lib/systemd/systemd",
+    "usr/bin/ls",
+    "bin/ls",
+];
+
+#[cfg(target_arch = "x86_64")]
+const CURRENT_ARCH: Arch = Arch::X86_64;
+#[cfg(target_arch = "aarch64")]
+const CURRENT_ARCH: Arch = Arch::Aarch64;
+
+/// The CPU architectures Distrod is built for.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Arch {
+    X86_64,
+    Aarch64,
+}
+
+impl Arch {
+    /// Returns the architecture this binary is built for, which is the one of WSL, since
+    /// WSL on ARM64 Windows runs only ARM64 Linux binaries.
+    pub fn current() -> Arch {
+        CURRENT_ARCH
+    }
+
+    /// Returns the name `uname -m` shows, which the release assets are named by.
+    pub fn name(&self) -> &'static str {
+        match self {
+            Arch::X86_64 => "x86_64",
+            Arch::Aarch64 => "aarch64",
+        }
+    }
+
+    /// Returns the name of the images of linuxcontainers.org for the architecture.
+    pub fn image_arch_name(&self) -> &'static str {
+        match self {
+            Arch::X86_64 => "amd64",
+            Arch::Aarch64 => "arm64",
+        }
+    }
+
+    fn elf_machine(&self) -> u16 {
+        match self {
+            Arch::X86_64 => EM_X86_64,
+            Arch::Aarch64 => EM_AARCH64,
+        }
+    }
+}
+
+/// The architecture of an ELF file, which can be one Distrod isn't built for, such as armhf.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ElfArch(u16);
+
+impl ElfArch {
+    pub fn is(&self, arch: Arch) -> bool {
+        self.0 == arch.elf_machine()
+    }
+
+    pub fn name(&self) -> String {
+        match self.0 {
+            EM_X86_64 => "x86_64".to_owned(),
+            EM_AARCH64 => "aarch64".to_owned(),
+            3 => "i386".to_owned(),
+            40 => "arm".to_owned(),
+            other => format!("the ELF machine {}", other),
+        }
+    }
+}
+
+/// Reads the architecture from the header of an ELF file. Returns None if it's not an ELF file.
+pub fn detect_elf_arch<P: AsRef<Path>>(path: P) -> Result<Option<ElfArch>> {
+    let path = path.as_ref();
+    let mut header = [0u8; ELF_MACHINE_OFFSET + 2];
+    let mut file =
+        std::fs::File::open(path).with_context(|| format!("Failed to open {:?}.", path))?;
+    if file.read_exact(&mut header).is_err() || &header[0..4] != ELF_MAGIC {
+        return Ok(None);
+    }
+    // x86_64 and aarch64 binaries are little endian.
+    Ok(Some(ElfArch(u16::from_le_bytes([
+        header[ELF_MACHINE_OFFSET],
+        header[ELF_MACHINE_OFFSET + 1],
+    ]))))
+}
+
+/// Returns the architecture of the binaries in the rootfs, or None if no known binary is found.
+/// Symlinks are skipped since they may point outside the rootfs, e.g. to /bin/busybox of WSL.
+pub fn detect_rootfs_arch<P: AsRef<Path>>(rootfs: P) -> Result<Option<ElfArch>> {
+    for probe in ROOTFS_PROBE_PATHS {
+        let path = rootfs.as_ref().join(probe);
+        match std::fs::symlink_metadata(&path) {
+            Ok(metadata) if metadata.file_type().is_file() => {}
+            _ => continue,
+        }
+        if let Some(arch) = detect_elf_arch(&path)? {
+            return Ok(Some(arch));
+        }
+    }
+    Ok(None)
+}
+
+/// Fails if the binaries in the rootfs are not for the architecture of WSL, e.g. an amd64 image
+/// on ARM64 Windows, which otherwise fails later with an obscure "Exec format error".
+pub fn check_rootfs_arch<P: AsRef<Path>>(rootfs: P) -> Result<()> {
+    let rootfs = rootfs.as_ref();
+    let arch = match detect_rootfs_arch(rootfs)
+        .with_context(|| format!("Failed to detect the architecture of {:?}.", rootfs))?
+    {
+        Some(arch) => arch,
+        None => {
+            log::debug!("The architecture of {:?} is unknown.", rootfs);
+            return Ok(());
+        }
+    };
+    if !arch.is(Arch::current()) {
+        return Err(ContainerError::ArchMismatch {
+            rootfs: rootfs.to_owned(),
+            rootfs_arch: arch.name().to_owned(),
+            machine_arch: Arch::current().name().to_owned(),
+            image_arch: Arch::current().image_arch_name().to_owned(),
+        }
+        .into());
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod test_arch {
+    use super::*;
+    use std::io::Write;
+
+    fn write_elf_header(machine: u16) -> tempfile::NamedTempFile {
+        let mut file = tempfile::NamedTempFile::new().unwrap();
+        let mut header = vec![0u8; 64];
+        header[0..4].copy_from_slice(ELF_MAGIC);
+        header[ELF_MACHINE_OFFSET..ELF_MACHINE_OFFSET + 2].copy_from_slice(&machine.to_le_bytes());
+        file.write_all(&header).unwrap();
+        file
+    }
+
+    #[test]
+    fn test_detect_elf_arch() {
+        let x86_64 = detect_elf_arch(write_elf_header(EM_X86_64).path())
+            .unwrap()
+            .unwrap();
+        assert!(x86_64.is(Arch::X86_64));
+        assert!(!x86_64.is(Arch::Aarch64));
+        let armhf = detect_elf_arch(write_elf_header(40).path())
+            .unwrap()
+            .unwrap();
+        assert_eq!("arm", armhf.name());
+
+        let mut script = tempfile::NamedTempFile::new().unwrap();
+        script.write_all(b"#!/bin/sh\necho hello\n").unwrap();
+        assert_eq!(None, detect_elf_arch(script.path()).unwrap());
+    }
+
+    #[test]
+    fn test_check_rootfs_arch() {
+        let rootfs = tempfile::tempdir().unwrap();
+        // A rootfs without the known binaries passes.
+        check_rootfs_arch(rootfs.path()).unwrap();
+
+        let other_arch = match Arch::current() {
+            Arch::X86_64 => EM_AARCH64,
+            Arch::Aarch64 => EM_X86_64,
+        };
+        std::fs::create_dir_all(rootfs.path().join("usr/bin")).unwrap();
+        let env = write_elf_header(other_arch);
+        std::fs::copy(env.path(), rootfs.path().join("usr/bin/env")).unwrap();
+        assert!(check_rootfs_arch(rootfs.path()).is_err());
+
+        let env = write_elf_header(Arch::current().elf_machine());
+        std::fs::copy(env.path(), rootfs.path().join("usr/bin/env")).unwrap();
+        check_rootfs_arch(rootfs.path()).unwrap();
+    }
+}
@@ -0,0 +1,69 @@
+use anyhow::{anyhow, bail, Result};
+
+/// The length of the nonce in an Assuan socket file.
+pub const NONCE_LEN: usize = 16;
+
+/// The socket file which libassuan makes on Windows in place of a Unix socket, such as
+/// `S.gpg-agent` of Gpg4win. The server listens on a TCP port of the loopback, and a client
+/// sends the nonce first to prove that it can read the file.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct AssuanSocketFile {
+    pub port: u16,
+    pub nonce: [u8; NONCE_LEN],
+}
+
+impl AssuanSocketFile {
+    /// Parses the content of the file, which is the port in decimal and a newline, followed by
+    /// the nonce.
+    pub fn parse(content: &[u8]) -> Result<AssuanSocketFile> {
+        let newline = content
+            .iter()
+            .position(|&b| b == b'\n')
+            .ok_or_else(|| anyhow!("The Assuan socket file has no port line."))?;
+        let port = std::str::from_utf8(&content[..newline])
+            .ok()
+            .and_then(|port| port.trim().parse::<u16>().ok())
+            .ok_or_else(|| anyhow!("The port of the Assuan socket file is invalid."))?;
+        let nonce = &content[newline + 1..];
+        if nonce.len() != NONCE_LEN {
+            bail!(
+                "The nonce of the Assuan socket file must be {} bytes, but is {} bytes.",
+                NONCE_LEN,
+                nonce.len()
+            );
+        }
+        let mut socket_file = AssuanSocketFile {
+            port,
+            nonce: [0; NONCE_LEN],
+        };
+        socket_file.nonce.copy_from_slice(nonce);
+        Ok(socket_file)
+    }
+}
+
+#[cfg(test)]
+mod test_assuan {
+    use super::*;
+
+    #[test]
+    fn test_parse() {
+        let mut content = b"51234\n".to_vec();
+        // The nonce is binary, and can have a newline.
+        let nonce = *b"\x00\x01\n\x03\x04\x05\x06\x07\x08\x09\x0a\x0b\x0c\x0d\x0e\xff";
+        content.extend_from_slice(&nonce);
+        assert_eq!(
+            AssuanSocketFile { port: 51234, nonce },
+            AssuanSocketFile::parse(&content).unwrap()
+        );
+    }
+
+    #[test]
+    fn test_parse_invalid() {
+        assert!(AssuanSocketFile::parse(b"").is_err());
+        assert!(AssuanSocketFile::parse(b"51234").is_err());
+        assert!(AssuanSocketFile::parse(b"port\n0123456789abcdef").is_err());
+        assert!(AssuanSocketFile::parse(b"70000\n0123456789abcdef").is_err());
+        assert!(AssuanSocketFile::parse(b"51234\n0123456789abcde").is_err());
+        assert!(AssuanSocketFile::parse(b"51234\n0123456789abcdefg").is_err());
+    }
+}
@@ -0,0 +1,222 @@
+use anyhow::{Context, Result};
+use serde::{Deserialize, Serialize};
+use std::fs;
+use std::os::unix::fs::symlink;
+
+use crate::container::{ContainerPath, HostPath};
+use crate::distro_config::AutoUpdateConfig;
+
+/// The timer Distrod mounts on /run/systemd/system, which starts distrod-auto-update.service.
+/// It's enabled in the distro if `auto_update.enabled` of the distro config is true.
+pub const AUTO_UPDATE_TIMER: &str = "distrod-auto-update.timer";
+
+/// The file in the distro where `distrod auto-update run` records the result of the last update.
+const RESULT_PATH: &str = "/var/lib/distrod/auto-update.json";
+const TIMER_WANTS_LINK_PATH: &str =
+    "/etc/systemd/system/timers.target.wants/distrod-auto-update.timer";
+const TIMER_DROPIN_PATH: &str = "/etc/systemd/system/distrod-auto-update.timer.d/distrod.conf";
+
+/// The package managers tried in order, with the commands which update all the packages
+/// without prompts.
+const PACKAGE_UPDATE_COMMANDS: &[(&str, &str)] = &[
+    (
+        "apt-get",
+        "apt-get update && DEBIAN_FRONTEND=noninteractive apt-get upgrade -y",
+    ),
+    ("dnf", "dnf upgrade -y --refresh"),
+    ("yum", "yum update -y"),
+    ("zypper", "zypper --non-interactive update"),
+    ("pacman", "pacman -Syu --noconfirm"),
+    ("apk", "apk upgrade --update-cache"),
+];
+
+const BIN_DIRS: &[&str] = &["/usr/bin", "/bin", "/usr/sbin", "/sbin"];
+
+/// Finds the package manager of the distro. Returns its name and the shell command which updates
+/// all the packages.
+pub fn detect_package_manager(rootfs: &HostPath) -> Result<Option<(&'static str, &'static str)>> {
+    for (name, command) in PACKAGE_UPDATE_COMMANDS {
+        for dir in BIN_DIRS {
+            let path = ContainerPath::new(format!("{}/{}", dir, name))?.to_host_path(rootfs);
+            if path.exists() {
+                return Ok(Some((name, command)));
+            }
+        }
+    }
+    Ok(None)
+}
+
+/// Enables or disables the timer in the rootfs as the config says, and puts the schedule in a
+/// drop-in of the timer. Systemd picks them up on the next boot or daemon-reload.
+pub fn apply_auto_update_config(rootfs: &HostPath, config: &AutoUpdateConfig) -> Result<()> {
+    let link_path = ContainerPath::new(TIMER_WANTS_LINK_PATH)?.to_host_path(rootfs);
+    let dropin_path = ContainerPath::new(TIMER_DROPIN_PATH)?.to_host_path(rootfs);
+    if !config.enabled {
+        for path in &[&link_path, &dropin_path] {
+            if fs::symlink_metadata(path.as_path()).is_ok() {
+                fs::remove_file(path.as_path())
+                    .with_context(|| format!("Failed to remove {:?}.", path))?;
+            }
+        }
+        return Ok(());
+    }
+
+    // OnCalendar= adds up, so clear the one of the timer before setting the new one.
+    let dropin = format!(
+        "[Timer]\nOnCalendar=\nOnCalendar={}\n",
+        config.schedule.trim()
+    );
+    if fs::read_to_string(dropin_path.as_path()).ok().as_deref() != Some(dropin.as_str()) {
+        if let Some(dir) = dropin_path.parent() {
+            fs::create_dir_all(dir).with_context(|| format!("Failed to create {:?}.", dir))?;
+        }
+        fs::write(dropin_path.as_path(), dropin)
+            .with_context(|| format!("Failed to write {:?}.", &dropin_path))?;
+    }
+    if fs::symlink_metadata(link_path.as_path()).is_err() {
+        if let Some(dir) = link_path.parent() {
+            fs::create_dir_all(dir).with_context(|| format!("Failed to create {:?}.", dir))?;
+        }
+        symlink(
+            format!("/run/systemd/system/{}", AUTO_UPDATE_TIMER),
+            &link_path,
+        )
+        .with_context(|| format!("Failed to create {:?}.", &link_path))?;
+    }
+    Ok(())
+}
+
+/// The result of the last automatic update, which `distrod status` shows.
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub struct AutoUpdateResult {
+    pub package_manager: String,
+    /// When the update finished, in RFC 3339.
+    pub finished_at: String,
+    /// The exit code of the update command. None if it was killed by a signal.
+    pub exit_code: Option<i32>,
+}
+
+impl AutoUpdateResult {
+    pub fn load(rootfs: &HostPath) -> Result<Option<AutoUpdateResult>> {
+        let path = ContainerPath::new(RESULT_PATH)?.to_host_path(rootfs);
+        if !path.exists() {
+            return Ok(None);
+        }
+        let cont = fs::read_to_string(path.as_path())
+            .with_context(|| format!("Failed to read {:?}.", &path))?;
+        let result =
+            serde_json::from_str(&cont).with_context(|| format!("Failed to parse {:?}.", &path))?;
+        Ok(Some(result))
+    }
+
+    pub fn save(&self, rootfs: &HostPath) -> Result<()> {
+        let path = ContainerPath::new(RESULT_PATH)?.to_host_path(rootfs);
+        if let Some(dir) = path.parent() {
+            fs::create_dir_all(dir).with_context(|| format!("Failed to create {:?}.", dir))?;
+        }
+        let cont = serde_json::to_string(self)?;
+        fs::write(path.as_path(), cont).with_context(|| format!("Failed to write {:?}.", &path))
+    }
+
+    pub fn succeeded(&self) -> bool {
+        self.exit_code == Some(0)
+    }
+
+    /// Describes the result in a line, such as "succeeded at 2024-01-07T03:12:45+09:00 by apt-get".
+    pub fn describe(&self) -> String {
+        let outcome = match self.exit_code {
+            Some(0) => "succeeded".to_owned(),
+            Some(code) => format!("failed with {}", code),
+            None => "killed".to_owned(),
+        };
+        format!(
+            "{} at {} by {}",
+            outcome, &self.finished_at, &self.package_manager
+        )
+    }
+}
+
+#[cfg(test)]
+mod test_auto_update {
+    use super::*;
+
+    fn make_rootfs() -> (tempfile::TempDir, HostPath) {
+        let tmpdir = tempfile::tempdir().unwrap();
+        let rootfs = HostPath::new(tmpdir.path()).unwrap();
+        (tmpdir, rootfs)
+    }
+
+    #[test]
+    fn test_detect_package_manager() {
+        let (tmpdir, rootfs) = make_rootfs();
+        assert_eq!(None, detect_package_manager(&rootfs).unwrap());
+
+        fs::create_dir_all(tmpdir.path().join("usr/bin")).unwrap();
+        fs::write(tmpdir.path().join("usr/bin/pacman"), "").unwrap();
+        assert_eq!(
+            Some("pacman"),
+            detect_package_manager(&rootfs)
+                .unwrap()
+                .map(|(name, _)| name)
+        );
+        // apt-get takes precedence, such as when pacman is installed as a tool on Debian.
+        fs::write(tmpdir.path().join("usr/bin/apt-get"), "").unwrap();
+        assert_eq!(
+            Some("apt-get"),
+            detect_package_manager(&rootfs)
+                .unwrap()
+                .map(|(name, _)| name)
+        );
+    }
+
+    #[test]
+    fn test_apply_auto_update_config() {
+        let (tmpdir, rootfs) = make_rootfs();
+        let mut config = AutoUpdateConfig {
+            enabled: true,
+            schedule: "Sun 03:00".to_owned(),
+        };
+        apply_auto_update_config(&rootfs, &config).unwrap();
+
+        let link_path = tmpdir
+            .path()
+            .join("etc/systemd/system/timers.target.wants/distrod-auto-update.timer");
+        assert_eq!(
+            std::path::PathBuf::from("/run/systemd/system/distrod-auto-update.timer"),
+            fs::read_link(&link_path).unwrap()
+        );
+        let dropin_path = tmpdir
+            .path()
+            .join("etc/systemd/system/distrod-auto-update.timer.d/distrod.conf");
+        assert_eq!(
+            "[Timer]\nOnCalendar=\nOnCalendar=Sun 03:00\n",
+            fs::read_to_string(&dropin_path).unwrap()
+        );
+        // Applying it again doesn't fail on the existing files.
+        apply_auto_update_config(&rootfs, &config).unwrap();
+
+        config.enabled = false;
+        apply_auto_update_config(&rootfs, &config).unwrap();
+        assert!(fs::symlink_metadata(&link_path).is_err());
+        assert!(!dropin_path.exists());
+    }
+
+    #[test]
+    fn test_save_and_load_result() {
+        let (_tmpdir, rootfs) = make_rootfs();
+        assert_eq!(None, AutoUpdateResult::load(&rootfs).unwrap());
+        let result = AutoUpdateResult {
+            package_manager: "dnf".to_owned(),
+            finished_at: "2024-01-07T03:12:45+09:00".to_owned(),
+            exit_code: Some(1),
+        };
+        result.save(&rootfs).unwrap();
+        let loaded = AutoUpdateResult::load(&rootfs).unwrap().unwrap();
+        assert_eq!(result, loaded);
+        assert!(!loaded.succeeded());
+        assert_eq!(
+            "failed with 1 at 2024-01-07T03:12:45+09:00 by dnf",
+            loaded.describe()
+        );
+    }
+}
@@ -0,0 +1,161 @@
+use anyhow::{bail, Result};
+
+use crate::distro_registry::validate_instance_name;
+use crate::distrod_config::AutostartDistroConfig;
+
+/// Orders the distros of the [autostart] section so that each one comes after the distros in
+/// its `after`. The order in the config is kept as long as it doesn't break a dependency.
+pub fn plan_autostart(distros: &[AutostartDistroConfig]) -> Result<Vec<AutostartDistroConfig>> {
+    plan_start_order(distros, "[autostart]")
+}
+
+/// Orders the distros as `plan_autostart` does. `source` is where the distros are listed, such
+/// as "[autostart]", for the error messages.
+pub fn plan_start_order(
+    distros: &[AutostartDistroConfig],
+    source: &str,
+) -> Result<Vec<AutostartDistroConfig>> {
+    for (i, distro) in distros.iter().enumerate() {
+        validate_instance_name(&distro.name)?;
+        if distros[..i].iter().any(|other| other.name == distro.name) {
+            bail!("'{}' is listed more than once in {}.", &distro.name, source);
+        }
+    }
+    for distro in distros {
+        for dep in &distro.after {
+            if !distros.iter().any(|other| &other.name == dep) {
+                bail!(
+                    "'{}' starts after '{}', which is not in {}.",
+                    &distro.name,
+                    dep,
+                    source
+                );
+            }
+        }
+    }
+
+    let mut plan: Vec<AutostartDistroConfig> = vec![];
+    while plan.len() < distros.len() {
+        let next = distros.iter().find(|distro| {
+            !plan.iter().any(|planned| planned.name == distro.name)
+                && distro
+                    .after
+                    .iter()
+                    .all(|dep| plan.iter().any(|planned| &planned.name == dep))
+        });
+        match next {
+            Some(next) => plan.push(next.clone()),
+            None => {
+                let rest: Vec<&str> = distros
+                    .iter()
+                    .filter(|distro| !plan.iter().any(|planned| planned.name == distro.name))
+                    .map(|distro| distro.name.as_str())
+                    .collect();
+                bail!(
+                    "The distros in {} depend on each other: {:?}.",
+                    source,
+                    rest
+                );
+            }
+        }
+    }
+    Ok(plan)
+}
+
+/// Encodes the plan into the form that the autostart task passes to `distrod autostart`, such as
+/// "db,web:db+cache". Distro names never contain ',', ':' or '+'.
+pub fn encode_plan(plan: &[AutostartDistroConfig]) -> String {
+    plan.iter()
+        .map(|distro| {
+            if distro.after.is_empty() {
+                distro.name.clone()
+            } else {
+                format!("{}:{}", &distro.name, distro.after.join("+"))
+            }
+        })
+        .collect::<Vec<_>>()
+        .join(",")
+}
+
+pub fn parse_plan(encoded: &str) -> Result<Vec<AutostartDistroConfig>> {
+    if encoded.trim().is_empty() {
+        return Ok(vec![]);
+    }
+    let distros = encoded
+        .trim()
+        .split(',')
+        .map(|entry| {
+            let (name, after) = match entry.find(':') {
+                Some(pos) => (&entry[..pos], &entry[pos + 1..]),
+                None => (entry, ""),
+            };
+            AutostartDistroConfig {
+                name: name.to_owned(),
+                after: after
+                    .split('+')
+                    .filter(|dep| !dep.is_empty())
+                    .map(|dep| dep.to_owned())
+                    .collect(),
+            }
+        })
+        .collect::<Vec<_>>();
+    // Check the plan in the same way as the config, since it comes from the environment.
+    plan_autostart(&distros)
+}
+
+#[cfg(test)]
+mod test_autostart {
+    use super::*;
+
+    fn distro(name: &str, after: &[&str]) -> AutostartDistroConfig {
+        AutostartDistroConfig {
+            name: name.to_owned(),
+            after: after.iter().map(|dep| dep.to_string()).collect(),
+        }
+    }
+
+    fn names(plan: &[AutostartDistroConfig]) -> Vec<&str> {
+        plan.iter().map(|distro| distro.name.as_str()).collect()
+    }
+
+    #[test]
+    fn test_plan_keeps_config_order() {
+        let plan = plan_autostart(&[distro("a", &[]), distro("b", &[]), distro("c", &[])]).unwrap();
+        assert_eq!(vec!["a", "b", "c"], names(&plan));
+    }
+
+    #[test]
+    fn test_plan_puts_dependencies_first() {
+        let plan = plan_autostart(&[
+            distro("web", &["db", "cache"]),
+            distro("db", &[]),
+            distro("cache", &["db"]),
+        ])
+        .unwrap();
+        assert_eq!(vec!["db", "cache", "web"], names(&plan));
+    }
+
+    #[test]
+    fn test_plan_rejects_invalid_dependencies() {
+        assert!(plan_autostart(&[distro("a", &["b"]), distro("b", &["a"])]).is_err());
+        assert!(plan_autostart(&[distro("a", &["a"])]).is_err());
+        assert!(plan_autostart(&[distro("a", &["unknown"])]).is_err());
+        assert!(plan_autostart(&[distro("a", &[]), distro("a", &[])]).is_err());
+        assert!(plan_autostart(&[distro("a,b", &[])]).is_err());
+    }
+
+    #[test]
+    fn test_encode_and_parse_plan() {
+        let plan = plan_autostart(&[
+            distro("db", &[]),
+            distro("cache", &[]),
+            distro("web", &["db", "cache"]),
+        ])
+        .unwrap();
+        let encoded = encode_plan(&plan);
+        assert_eq!("db,cache,web:db+cache", encoded);
+        assert_eq!(plan, parse_plan(&encoded).unwrap());
+        assert!(parse_plan("").unwrap().is_empty());
+        assert!(parse_plan("web:db").is_err());
+    }
+}
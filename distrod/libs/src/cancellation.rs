@@ -0,0 +1,145 @@
+#[cfg(target_os = "linux")]
+use std::sync::atomic::AtomicPtr;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+
+#[cfg(target_os = "linux")]
+use anyhow::Context;
+use anyhow::Result;
+#[cfg(target_os = "linux")]
+use nix::libc;
+#[cfg(target_os = "linux")]
+use nix::sys::signal::{self, SaFlags, SigAction, SigHandler, SigSet};
+
+/// The flag of the token which `cancel_on_sigint` cancels.
+#[cfg(target_os = "linux")]
+static SIGINT_CANCELLED: AtomicPtr<AtomicBool> = AtomicPtr::new(std::ptr::null_mut());
+
+/// A token shared between a long-running operation and whoever may want it to stop,
+/// typically a Ctrl-C handler. The operation checks the token at points where it can
+/// stop safely and clean up after itself, instead of being killed in the middle.
+#[derive(Debug, Clone, Default)]
+pub struct CancellationToken {
+    cancelled: Arc<AtomicBool>,
+}
+
+/// The error returned by `CancellationToken::check` when the operation is cancelled.
+/// Callers can tell a cancellation from other errors by `err.downcast_ref::<Cancelled>()`.
+#[derive(Debug, thiserror::Error)]
+#[error("The operation has been cancelled.")]
+pub struct Cancelled;
+
+impl CancellationToken {
+    pub fn new() -> Self {
+        CancellationToken::default()
+    }
+
+    pub fn cancel(&self) {
+        self.cancelled.store(true, Ordering::SeqCst);
+    }
+
+    pub fn is_cancelled(&self) -> bool {
+        self.cancelled.load(Ordering::SeqCst)
+    }
+
+    /// Returns `Err(Cancelled)` if the token has been cancelled.
+    /// Call this at the safe points of a long-running operation.
+    pub fn check(&self) -> Result<()> {
+        if self.is_cancelled() {
+            return Err(Cancelled.into());
+        }
+        Ok(())
+    }
+}
+
+/// Cancels the token on the first Ctrl-C, and exits the process on the second one in case
+/// the operation never reaches a safe point. This must be called within a Tokio runtime.
+/// Call it after the prompts of the command, since they leave nothing to clean up and Ctrl-C
+/// should exit the process there as usual.
+pub fn cancel_on_ctrl_c(token: &CancellationToken) {
+    let token = token.clone();
+    run_on_ctrl_c(move || token.cancel());
+}
+
+/// Cancels the token on the first SIGINT as `cancel_on_ctrl_c` does, but by a signal handler
+/// instead of a task of Tokio. Use this for the operations which fork, such as starting a distro,
+/// since forking a process which has threads isn't safe.
+#[cfg(target_os = "linux")]
+pub fn cancel_on_sigint(token: &CancellationToken) -> Result<()> {
+    // Leaked, since the handler may use it until the process exits.
+    SIGINT_CANCELLED.store(
+        Arc::into_raw(token.cancelled.clone()) as *mut AtomicBool,
+        Ordering::SeqCst,
+    );
+    let action = SigAction::new(
+        SigHandler::Handler(cancel_by_sigint),
+        SaFlags::SA_RESTART,
+        SigSet::empty(),
+    );
+    unsafe { signal::sigaction(signal::SIGINT, &action) }
+        .with_context(|| "Failed to set the handler of SIGINT.")?;
+    Ok(())
+}
+
+#[cfg(target_os = "linux")]
+extern "C" fn cancel_by_sigint(_signal: libc::c_int) {
+    let cancelled = SIGINT_CANCELLED.load(Ordering::SeqCst);
+    if cancelled.is_null() {
+        return;
+    }
+    // Only the atomics, write(2), and _exit(2) are used, which are async-signal-safe.
+    if unsafe { (*cancelled).swap(true, Ordering::SeqCst) } {
+        unsafe { libc::_exit(130) };
+    }
+    let message = b"Cancelling... Hit Ctrl-C again to exit immediately.\n";
+    unsafe {
+        libc::write(
+            libc::STDERR_FILENO,
+            message.as_ptr() as *const libc::c_void,
+            message.len(),
+        );
+    }
+}
+
+/// Calls `cancel` on the first Ctrl-C as `cancel_on_ctrl_c` does, for the tokens other than
+/// `CancellationToken`, such as the one of distrod_core.
+pub fn run_on_ctrl_c<F: FnOnce() + Send + 'static>(cancel: F) {
+    tokio::spawn(async move {
+        if tokio::signal::ctrl_c().await.is_err() {
+            log::debug!("Failed to listen to Ctrl-C.");
+            return;
+        }
+        log::info!("Cancelling... Hit Ctrl-C again to exit immediately.");
+        cancel();
+        if tokio::signal::ctrl_c().await.is_ok() {
+            std::process::exit(130);
+        }
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use anyhow::Context;
+
+    #[test]
+    fn test_cancel() {
+        let token = CancellationToken::new();
+        let cloned = token.clone();
+        assert!(token.check().is_ok());
+        cloned.cancel();
+        assert!(token.is_cancelled());
+        assert!(token.check().is_err());
+    }
+
+    #[test]
+    fn test_cancelled_error_can_be_downcast() {
+        let token = CancellationToken::new();
+        token.cancel();
+        let err = token
+            .check()
+            .with_context(|| "Failed to do something.")
+            .unwrap_err();
+        assert!(err.downcast_ref::<Cancelled>().is_some());
+    }
+}
@@ -0,0 +1,114 @@
+use anyhow::{bail, Result};
+
+/// The Linux capabilities by the numbers in linux/capability.h.
+const CAPABILITIES: &[&str] = &[
+    "CAP_CHOWN",
+    "CAP_DAC_OVERRIDE",
+    "CAP_DAC_READ_SEARCH",
+    "CAP_FOWNER",
+    "CAP_FSETID",
+    "CAP_KILL",
+    "CAP_SETGID",
+    "CAP_SETUID",
+    "CAP_SETPCAP",
+    "CAP_LINUX_IMMUTABLE",
+    "CAP_NET_BIND_SERVICE",
+    "CAP_NET_BROADCAST",
+    "CAP_NET_ADMIN",
+    "CAP_NET_RAW",
+    "CAP_IPC_LOCK",
+    "CAP_IPC_OWNER",
+    "CAP_SYS_MODULE",
+    "CAP_SYS_RAWIO",
+    "CAP_SYS_CHROOT",
+    "CAP_SYS_PTRACE",
+    "CAP_SYS_PACCT",
+    "CAP_SYS_ADMIN",
+    "CAP_SYS_BOOT",
+    "CAP_SYS_NICE",
+    "CAP_SYS_RESOURCE",
+    "CAP_SYS_TIME",
+    "CAP_SYS_TTY_CONFIG",
+    "CAP_MKNOD",
+    "CAP_LEASE",
+    "CAP_AUDIT_WRITE",
+    "CAP_AUDIT_CONTROL",
+    "CAP_SETFCAP",
+    "CAP_MAC_OVERRIDE",
+    "CAP_MAC_ADMIN",
+    "CAP_SYSLOG",
+    "CAP_WAKE_ALARM",
+    "CAP_BLOCK_SUSPEND",
+    "CAP_AUDIT_READ",
+    "CAP_PERFMON",
+    "CAP_BPF",
+    "CAP_CHECKPOINT_RESTORE",
+];
+
+/// Returns the number of a capability such as "CAP_SYS_ADMIN". The prefix and the case are
+/// optional, so "sys_admin" is the same.
+pub fn parse_capability(name: &str) -> Result<u32> {
+    let upper = name.trim().to_ascii_uppercase();
+    let full_name = if upper.starts_with("CAP_") {
+        upper
+    } else {
+        format!("CAP_{}", upper)
+    };
+    match CAPABILITIES.iter().position(|cap| *cap == full_name) {
+        Some(number) => Ok(number as u32),
+        None => bail!("Unknown capability: '{}'.", name),
+    }
+}
+
+pub fn parse_capabilities<S: AsRef<str>>(names: &[S]) -> Result<Vec<u32>> {
+    names
+        .iter()
+        .map(|name| parse_capability(name.as_ref()))
+        .collect()
+}
+
+/// Removes the capabilities from the bounding set of the current process, so that neither this
+/// process after exec nor its children can have them, even by setuid programs. This needs
+/// CAP_SETPCAP, so call this before dropping the privilege. It doesn't allocate, so that it can
+/// be called between fork and exec.
+#[cfg(target_os = "linux")]
+pub fn drop_bounding_set(capabilities: &[u32]) -> std::io::Result<()> {
+    for cap in capabilities {
+        let ret = unsafe {
+            nix::libc::prctl(
+                nix::libc::PR_CAPBSET_DROP,
+                *cap as nix::libc::c_ulong,
+                0,
+                0,
+                0,
+            )
+        };
+        if ret != 0 {
+            let err = std::io::Error::last_os_error();
+            // An older kernel doesn't know the newer capabilities, which it can't give anyway.
+            if err.raw_os_error() == Some(nix::libc::EINVAL) {
+                continue;
+            }
+            return Err(err);
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod test_capability {
+    use super::*;
+
+    #[test]
+    fn test_parse_capability() {
+        assert_eq!(0, parse_capability("CAP_CHOWN").unwrap());
+        assert_eq!(21, parse_capability("CAP_SYS_ADMIN").unwrap());
+        assert_eq!(21, parse_capability("sys_admin").unwrap());
+        assert_eq!(40, parse_capability("cap_checkpoint_restore").unwrap());
+        assert!(parse_capability("CAP_UNKNOWN").is_err());
+        assert_eq!(
+            vec![16, 22],
+            parse_capabilities(&["CAP_SYS_MODULE", "CAP_SYS_BOOT"]).unwrap()
+        );
+    }
+}
@@ -0,0 +1,352 @@
+use anyhow::{anyhow, bail, Context, Result};
+use serde::{Deserialize, Serialize};
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use crate::mount_info::{get_mount_entries, MountEntry};
+
+const CGROUP_PARENT_NAME: &str = "distrod";
+const CGROUP_ROOT: &str = "/sys/fs/cgroup";
+const CPU_PERIOD_US: u64 = 100_000;
+
+/// The limits put on the processes of a distro.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Serialize, Deserialize)]
+pub struct ResourceLimits {
+    pub memory_bytes: Option<u64>,
+    /// The number of CPUs the distro can use up, such as 1.5.
+    pub cpus: Option<f64>,
+    pub pids: Option<u64>,
+}
+
+impl ResourceLimits {
+    pub fn is_empty(&self) -> bool {
+        self.memory_bytes.is_none() && self.cpus.is_none() && self.pids.is_none()
+    }
+
+    /// Returns the limits with the ones set in `other` taking precedence.
+    pub fn overridden_by(&self, other: &ResourceLimits) -> ResourceLimits {
+        ResourceLimits {
+            memory_bytes: other.memory_bytes.or(self.memory_bytes),
+            cpus: other.cpus.or(self.cpus),
+            pids: other.pids.or(self.pids),
+        }
+    }
+}
+
+/// How the cgroup hierarchies are mounted on the host.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CgroupMode {
+    /// Only the v1 hierarchies.
+    Legacy,
+    /// The v1 hierarchies and the v2 one at /sys/fs/cgroup/unified, which WSL sets up by default.
+    Hybrid,
+    /// Only the v2 hierarchy at /sys/fs/cgroup, such as with `cgroup_no_v1=all` of the kernel.
+    Unified,
+}
+
+pub fn detect_cgroup_mode(mount_entries: &[MountEntry]) -> CgroupMode {
+    let is_cgroup2_at = |path: &Path| {
+        mount_entries
+            .iter()
+            .any(|entry| entry.fstype == "cgroup2" && entry.path == path)
+    };
+    let root = Path::new(CGROUP_ROOT);
+    if is_cgroup2_at(root) {
+        CgroupMode::Unified
+    } else if is_cgroup2_at(&root.join("unified")) {
+        CgroupMode::Hybrid
+    } else {
+        CgroupMode::Legacy
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Controller {
+    Memory,
+    Cpu,
+    Pids,
+}
+
+impl Controller {
+    fn name(&self) -> &'static str {
+        match self {
+            Controller::Memory => "memory",
+            Controller::Cpu => "cpu",
+            Controller::Pids => "pids",
+        }
+    }
+}
+
+const CONTROLLERS: [Controller; 3] = [Controller::Memory, Controller::Cpu, Controller::Pids];
+
+/// A cgroup made for a distro. WSL mounts the controllers on cgroup v1, so each controller
+/// is looked up in the v2 hierarchy first, and then in the v1 hierarchies.
+#[derive(Debug, Clone)]
+pub struct Cgroup {
+    dirs: Vec<PathBuf>,
+}
+
+impl Cgroup {
+    /// Creates the cgroup /distrod/<name> with the limits, or updates the limits if it exists.
+    pub fn create(name: &str, limits: &ResourceLimits) -> Result<Cgroup> {
+        let mount_entries =
+            get_mount_entries().with_context(|| "Failed to retrieve mount entries.")?;
+        let mut dirs: Vec<PathBuf> = vec![];
+        for controller in CONTROLLERS.iter() {
+            let (dir, is_v2) = match find_controller_hierarchy(&mount_entries, *controller) {
+                Some(hierarchy) => hierarchy,
+                None => {
+                    if has_limit(limits, *controller) {
+                        bail!(
+                            "The {} cgroup controller is not available.",
+                            controller.name()
+                        );
+                    }
+                    continue;
+                }
+            };
+            let cgroup_dir = if is_v2 {
+                create_v2_cgroup(&dir, name, *controller)?
+            } else {
+                create_dir(&dir.join(CGROUP_PARENT_NAME).join(name))?
+            };
+            write_limit(&cgroup_dir, is_v2, *controller, limits)
+                .with_context(|| format!("Failed to set the limit of {}.", controller.name()))?;
+            if !dirs.contains(&cgroup_dir) {
+                dirs.push(cgroup_dir);
+            }
+        }
+        Ok(Cgroup { dirs })
+    }
+
+    /// Creates the cgroup /distrod/<name> on the unified hierarchy with all the controllers of the
+    /// host enabled, so that systemd in the distro can delegate them to its units by itself.
+    pub fn create_delegated(name: &str, limits: &ResourceLimits) -> Result<Cgroup> {
+        let root = Path::new(CGROUP_ROOT);
+        let controllers_path = root.join("cgroup.controllers");
+        let controllers = fs::read_to_string(&controllers_path)
+            .with_context(|| format!("Failed to read {:?}.", &controllers_path))?;
+        let controllers: Vec<&str> = controllers.split_whitespace().collect();
+
+        let parent = create_dir(&root.join(CGROUP_PARENT_NAME))?;
+        if !controllers.is_empty() {
+            let enable = controllers
+                .iter()
+                .map(|name| format!("+{}", name))
+                .collect::<Vec<_>>()
+                .join(" ");
+            write_file(&root.join("cgroup.subtree_control"), &enable)?;
+            write_file(&parent.join("cgroup.subtree_control"), &enable)?;
+        }
+        let cgroup_dir = create_dir(&parent.join(name))?;
+        for controller in CONTROLLERS.iter() {
+            if !controllers.contains(&controller.name()) {
+                if has_limit(limits, *controller) {
+                    bail!(
+                        "The {} cgroup controller is not available.",
+                        controller.name()
+                    );
+                }
+                continue;
+            }
+            write_limit(&cgroup_dir, true, *controller, limits)
+                .with_context(|| format!("Failed to set the limit of {}.", controller.name()))?;
+        }
+        Ok(Cgroup {
+            dirs: vec![cgroup_dir],
+        })
+    }
+
+    /// Moves the current process into the cgroup, so that its children are in it as well.
+    pub fn add_current_process(&self) -> Result<()> {
+        let pid = nix::unistd::getpid().as_raw().to_string();
+        for dir in &self.dirs {
+            write_file(&dir.join("cgroup.procs"), &pid)?;
+        }
+        Ok(())
+    }
+}
+
+fn has_limit(limits: &ResourceLimits, controller: Controller) -> bool {
+    match controller {
+        Controller::Memory => limits.memory_bytes.is_some(),
+        Controller::Cpu => limits.cpus.is_some(),
+        Controller::Pids => limits.pids.is_some(),
+    }
+}
+
+/// Returns the mount point of the hierarchy which has the controller, and whether it's cgroup v2.
+fn find_controller_hierarchy(
+    mount_entries: &[MountEntry],
+    controller: Controller,
+) -> Option<(PathBuf, bool)> {
+    let v2 = mount_entries.iter().find(|entry| {
+        entry.fstype == "cgroup2"
+            && fs::read_to_string(entry.path.join("cgroup.controllers"))
+                .map(|controllers| {
+                    controllers
+                        .split_whitespace()
+                        .any(|name| name == controller.name())
+                })
+                .unwrap_or(false)
+    });
+    if let Some(v2) = v2 {
+        return Some((v2.path.clone(), true));
+    }
+    mount_entries
+        .iter()
+        .find(|entry| {
+            entry.fstype == "cgroup"
+                && entry
+                    .attributes
+                    .split(',')
+                    .any(|option| option == controller.name())
+        })
+        .map(|entry| (entry.path.clone(), false))
+}
+
+fn create_v2_cgroup(root: &Path, name: &str, controller: Controller) -> Result<PathBuf> {
+    // A controller has to be enabled in all the ancestors to be used in a cgroup.
+    let parent = create_dir(&root.join(CGROUP_PARENT_NAME))?;
+    let enable = format!("+{}", controller.name());
+    write_file(&root.join("cgroup.subtree_control"), &enable)?;
+    write_file(&parent.join("cgroup.subtree_control"), &enable)?;
+    create_dir(&parent.join(name))
+}
+
+fn write_limit(
+    dir: &Path,
+    is_v2: bool,
+    controller: Controller,
+    limits: &ResourceLimits,
+) -> Result<()> {
+    // Reset the limits which are not set, since the cgroup may remain from the last start.
+    match (controller, is_v2) {
+        (Controller::Memory, true) => write_file(
+            &dir.join("memory.max"),
+            &limits
+                .memory_bytes
+                .map_or("max".to_owned(), |bytes| bytes.to_string()),
+        ),
+        (Controller::Memory, false) => write_file(
+            &dir.join("memory.limit_in_bytes"),
+            &limits
+                .memory_bytes
+                .map_or("-1".to_owned(), |bytes| bytes.to_string()),
+        ),
+        (Controller::Cpu, true) => write_file(
+            &dir.join("cpu.max"),
+            &format!(
+                "{} {}",
+                limits
+                    .cpus
+                    .map_or("max".to_owned(), |cpus| cpu_quota_us(cpus).to_string()),
+                CPU_PERIOD_US
+            ),
+        ),
+        (Controller::Cpu, false) => {
+            write_file(&dir.join("cpu.cfs_period_us"), &CPU_PERIOD_US.to_string())?;
+            write_file(
+                &dir.join("cpu.cfs_quota_us"),
+                &limits
+                    .cpus
+                    .map_or("-1".to_owned(), |cpus| cpu_quota_us(cpus).to_string()),
+            )
+        }
+        (Controller::Pids, _) => write_file(
+            &dir.join("pids.max"),
+            &limits
+                .pids
+                .map_or("max".to_owned(), |pids| pids.to_string()),
+        ),
+    }
+}
+
+/// Parses the number of CPUs of `--cpus`, such as 1.5.
+pub fn parse_cpus(cpus: &str) -> Result<f64> {
+    let value: f64 = cpus
+        .parse()
+        .with_context(|| format!("Invalid number of CPUs: '{}'.", cpus))?;
+    if !value.is_finite() || value <= 0.0 {
+        bail!("The number of CPUs should be positive: '{}'.", cpus);
+    }
+    Ok(value)
+}
+
+fn cpu_quota_us(cpus: f64) -> u64 {
+    // The kernel rejects a quota less than 1ms.
+    ((cpus * CPU_PERIOD_US as f64) as u64).max(1000)
+}
+
+fn create_dir(path: &Path) -> Result<PathBuf> {
+    if !path.exists() {
+        fs::create_dir(path).with_context(|| format!("Failed to create {:?}.", path))?;
+    }
+    Ok(path.to_owned())
+}
+
+fn write_file(path: &Path, value: &str) -> Result<()> {
+    fs::write(path, value).map_err(|e| anyhow!("Failed to write '{}' to {:?}. {}", value, path, e))
+}
+
+#[cfg(test)]
+mod test_cgroup_cpus {
+    use super::*;
+
+    #[test]
+    fn test_parse_cpus() {
+        assert_eq!(1.5, parse_cpus("1.5").unwrap());
+        assert_eq!(2.0, parse_cpus("2").unwrap());
+        assert!(parse_cpus("0").is_err());
+        assert!(parse_cpus("-1").is_err());
+        assert!(parse_cpus("NaN").is_err());
+        assert!(parse_cpus("inf").is_err());
+        assert!(parse_cpus("two").is_err());
+    }
+
+    #[test]
+    fn test_cpu_quota_us() {
+        assert_eq!(150_000, cpu_quota_us(1.5));
+        assert_eq!(400_000, cpu_quota_us(4.0));
+        assert_eq!(50_000, cpu_quota_us(0.5));
+        // The quota is raised to the minimum the kernel accepts.
+        assert_eq!(1000, cpu_quota_us(0.001));
+    }
+}
+
+#[cfg(test)]
+mod test_cgroup_mode {
+    use super::*;
+
+    fn entry(path: &str, fstype: &str) -> MountEntry {
+        MountEntry {
+            source: fstype.to_owned(),
+            path: PathBuf::from(path),
+            fstype: fstype.to_owned(),
+            attributes: "rw,nosuid,nodev,noexec,relatime".to_owned(),
+        }
+    }
+
+    #[test]
+    fn test_detect_cgroup_mode() {
+        assert_eq!(
+            CgroupMode::Unified,
+            detect_cgroup_mode(&[entry("/sys", "sysfs"), entry("/sys/fs/cgroup", "cgroup2")])
+        );
+        assert_eq!(
+            CgroupMode::Hybrid,
+            detect_cgroup_mode(&[
+                entry("/sys/fs/cgroup", "tmpfs"),
+                entry("/sys/fs/cgroup/unified", "cgroup2"),
+                entry("/sys/fs/cgroup/memory", "cgroup"),
+            ])
+        );
+        assert_eq!(
+            CgroupMode::Legacy,
+            detect_cgroup_mode(&[
+                entry("/sys/fs/cgroup", "tmpfs"),
+                entry("/sys/fs/cgroup/memory", "cgroup"),
+            ])
+        );
+    }
+}
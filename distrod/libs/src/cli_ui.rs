@@ -1,15 +1,64 @@
 use crate::distro_image::{DefaultImageFetcher, DistroImageFetcher, DistroImageList};
-use anyhow::{bail, Context, Result};
+use crate::error::ImageError;
+use crate::list_chooser::{self, ListItem};
+use crate::log_file::RotatingLogFile;
+use anyhow::{anyhow, bail, Context, Result};
 use colored::*;
+use serde_json::{Map, Value};
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Mutex;
 use std::{ffi::OsString, fmt::Debug, io::Write};
 use tracing::metadata::LevelFilter;
+use tracing_subscriber::filter::Targets;
 use tracing_subscriber::{fmt::FormatEvent, prelude::*};
 
+/// The maximum size of the log file before it's rotated, and the number of the old files kept.
+const LOG_FILE_MAX_BYTES: u64 = 1024 * 1024;
+const LOG_FILE_MAX_OLD_FILES: usize = 5;
+
+/// The format of the log lines. `json` writes an object per line for log collectors.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum LogFormat {
+    Text,
+    Json,
+}
+
+pub const LOG_FORMATS: &[&str] = &["text", "json"];
+
+impl Default for LogFormat {
+    fn default() -> Self {
+        LogFormat::Text
+    }
+}
+
+impl FromStr for LogFormat {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "text" => Ok(LogFormat::Text),
+            "json" => Ok(LogFormat::Json),
+            _ => bail!("Unknown log format: '{}'.", s),
+        }
+    }
+}
+
 #[derive(Default, Debug)]
 pub struct LoggerInitializer {
     logs_kmsg: bool,
     log_level: Option<String>,
     kmsg_log_level: Option<String>,
+    format: LogFormat,
+    log_file: Option<LogFileConfig>,
+}
+
+#[derive(Debug)]
+struct LogFileConfig {
+    path: PathBuf,
+    log_level: String,
+    format: LogFormat,
 }
 
 impl LoggerInitializer {
@@ -28,35 +77,48 @@ impl LoggerInitializer {
         self
     }
 
+    /// Sets the format of the logs to stderr.
+    pub fn with_format(&mut self, format: LogFormat) -> &mut Self {
+        self.format = format;
+        self
+    }
+
+    /// Writes the logs of `log_level` to the rotated file as well, for the runs whose stderr
+    /// nobody sees, such as the autostart on Windows startup.
+    pub fn with_log_file<P: AsRef<Path>>(
+        &mut self,
+        path: P,
+        log_level: String,
+        format: LogFormat,
+    ) -> &mut Self {
+        self.log_file = Some(LogFileConfig {
+            path: path.as_ref().to_owned(),
+            log_level,
+            format,
+        });
+        self
+    }
+
     pub fn init(self, app_name: String) {
         let inner = || -> Result<()> {
-            let terminal_formatter = TerminalLogFormatter::new(app_name.clone());
-            let mut terminal_filter =
-                tracing_subscriber::filter::Targets::new().with_default(LevelFilter::INFO);
-            if let Some(target) = self
-                .log_level
-                .or_else(|| std::env::var("RUST_LOG").ok())
-                .and_then(|level| {
-                    level
-                        .parse()
-                        .map_err(|e| {
-                            eprintln!("Invalid log level format {:?}", e);
-                            e
-                        })
-                        .ok()
-                })
-            {
-                terminal_filter = target;
-            };
+            let terminal_formatter = TerminalLogFormatter::new(app_name.clone(), self.format);
+            let terminal_filter = parse_log_filter(
+                self.log_level.or_else(|| std::env::var("RUST_LOG").ok()),
+                LevelFilter::INFO,
+                "log level",
+            );
             let terminal_fmt_layer = tracing_subscriber::fmt::layer()
                 .with_target(false)
                 .event_format(terminal_formatter)
                 .with_writer(std::io::stderr)
                 .with_filter(terminal_filter);
+            let file_fmt_layer = build_file_fmt_layer(app_name.clone(), self.log_file);
 
             if !self.logs_kmsg {
                 tracing::subscriber::set_global_default(
-                    tracing_subscriber::registry().with(terminal_fmt_layer),
+                    tracing_subscriber::registry()
+                        .with(terminal_fmt_layer)
+                        .with(file_fmt_layer),
                 )
                 .with_context(|| "set_global_default failed.")?;
                 tracing_log::LogTracer::init()
@@ -65,19 +127,8 @@ impl LoggerInitializer {
             }
 
             let kmsg_formatter = KmsgLogFormatter::new(app_name);
-            let mut kmsg_filter =
-                tracing_subscriber::filter::Targets::new().with_default(LevelFilter::ERROR);
-            if let Some(target) = self.kmsg_log_level.and_then(|level| {
-                level
-                    .parse()
-                    .map_err(|e| {
-                        eprintln!("Invalid kmsg log level format {:?}", e);
-                        e
-                    })
-                    .ok()
-            }) {
-                kmsg_filter = target;
-            };
+            let kmsg_filter =
+                parse_log_filter(self.kmsg_log_level, LevelFilter::ERROR, "kmsg log level");
             let kmsg_fmt_layer = tracing_subscriber::fmt::layer()
                 .with_target(false)
                 .event_format(kmsg_formatter)
@@ -90,7 +141,8 @@ impl LoggerInitializer {
             tracing::subscriber::set_global_default(
                 tracing_subscriber::registry()
                     .with(terminal_fmt_layer)
-                    .with(kmsg_fmt_layer),
+                    .with(kmsg_fmt_layer)
+                    .with(file_fmt_layer),
             )
             .with_context(|| "set_global_default for kmsg failed.")?;
             tracing_log::LogTracer::init().with_context(|| {
@@ -105,6 +157,67 @@ impl LoggerInitializer {
     }
 }
 
+/// Parses a filter such as "info" or "info,libs::distro=trace", which sets the level of each
+/// module. An invalid one is reported and `default` is used.
+fn parse_log_filter(filter: Option<String>, default: LevelFilter, name: &str) -> Targets {
+    if let Some(target) = filter.and_then(|level| {
+        level
+            .parse()
+            .map_err(|e| {
+                eprintln!("Invalid {} format {:?}", name, e);
+                e
+            })
+            .ok()
+    }) {
+        return target;
+    }
+    Targets::new().with_default(default)
+}
+
+type LogFileWriter = Mutex<Box<dyn Write + Send>>;
+
+/// Builds the layer of the log file. The layer without the file enables nothing, so that the
+/// subscriber has the same type either way.
+fn build_file_fmt_layer<S>(
+    app_name: String,
+    config: Option<LogFileConfig>,
+) -> tracing_subscriber::filter::Filtered<
+    tracing_subscriber::fmt::Layer<
+        S,
+        tracing_subscriber::fmt::format::DefaultFields,
+        FileLogFormatter,
+        LogFileWriter,
+    >,
+    Targets,
+    S,
+>
+where
+    S: tracing::Subscriber + for<'a> tracing_subscriber::registry::LookupSpan<'a>,
+{
+    let (writer, filter, format): (Box<dyn Write + Send>, _, _) = match config {
+        Some(config) => {
+            match RotatingLogFile::open(&config.path, LOG_FILE_MAX_BYTES, LOG_FILE_MAX_OLD_FILES) {
+                Ok(file) => (
+                    Box::new(file),
+                    parse_log_filter(Some(config.log_level), LevelFilter::INFO, "log file level"),
+                    config.format,
+                ),
+                Err(e) => {
+                    // Such as when a non-root user can't write to /var/log.
+                    eprintln!("Failed to open the log file. {:?}", e);
+                    (Box::new(std::io::sink()), Targets::new(), config.format)
+                }
+            }
+        }
+        None => (Box::new(std::io::sink()), Targets::new(), LogFormat::Text),
+    };
+    tracing_subscriber::fmt::layer()
+        .with_target(false)
+        .event_format(FileLogFormatter { app_name, format })
+        .with_writer(Mutex::new(writer))
+        .with_filter(filter)
+}
+
 pub fn init_logger(app_name: String, log_level: Option<String>) {
     let mut logger_initializer = LoggerInitializer::default();
     if let Some(log_level) = log_level {
@@ -116,17 +229,18 @@ pub fn init_logger(app_name: String, log_level: Option<String>) {
 #[derive(Clone, Debug)]
 struct TerminalLogFormatter {
     app_name: String,
+    format: LogFormat,
 }
 
 impl TerminalLogFormatter {
-    fn new(app_name: String) -> TerminalLogFormatter {
+    fn new(app_name: String, format: LogFormat) -> TerminalLogFormatter {
         #[cfg(target_os = "windows")]
         {
             if let Err(e) = ansi_term::enable_ansi_support() {
                 eprintln!("Warn: ansi_term::enable_ansi_support failed. {:?}", e);
             }
         }
-        TerminalLogFormatter { app_name }
+        TerminalLogFormatter { app_name, format }
     }
 }
 
@@ -141,6 +255,9 @@ where
         mut writer: tracing_subscriber::fmt::format::Writer<'_>,
         event: &tracing::Event<'_>,
     ) -> std::fmt::Result {
+        if self.format == LogFormat::Json {
+            return write_json_event(writer, &self.app_name, event);
+        }
         let level = *event.metadata().level();
         write!(
             writer,
@@ -159,6 +276,101 @@ where
     }
 }
 
+/// Formats the lines of the log file, which has the time and the process of each line since
+/// many processes write to it.
+#[derive(Debug)]
+struct FileLogFormatter {
+    app_name: String,
+    format: LogFormat,
+}
+
+impl<S, N> FormatEvent<S, N> for FileLogFormatter
+where
+    S: tracing::Subscriber + for<'a> tracing_subscriber::registry::LookupSpan<'a>,
+    N: for<'a> tracing_subscriber::fmt::FormatFields<'a> + 'static,
+{
+    fn format_event(
+        &self,
+        ctx: &tracing_subscriber::fmt::FmtContext<'_, S, N>,
+        mut writer: tracing_subscriber::fmt::format::Writer<'_>,
+        event: &tracing::Event<'_>,
+    ) -> std::fmt::Result {
+        if self.format == LogFormat::Json {
+            return write_json_event(writer, &self.app_name, event);
+        }
+        write!(
+            writer.by_ref(),
+            "{} {}[{}]: [{}] {}: ",
+            chrono::Utc::now().to_rfc3339_opts(chrono::SecondsFormat::Millis, true),
+            self.app_name,
+            std::process::id(),
+            event.metadata().level(),
+            event.metadata().target()
+        )?;
+        ctx.field_format().format_fields(writer.by_ref(), event)?;
+        writeln!(writer.by_ref())?;
+        Ok(())
+    }
+}
+
+/// Writes the event as a JSON object in a line, with the fields of the event as its members.
+fn write_json_event(
+    mut writer: tracing_subscriber::fmt::format::Writer<'_>,
+    app_name: &str,
+    event: &tracing::Event<'_>,
+) -> std::fmt::Result {
+    let metadata = event.metadata();
+    let mut object = Map::new();
+    object.insert(
+        "timestamp".to_owned(),
+        Value::from(chrono::Utc::now().to_rfc3339_opts(chrono::SecondsFormat::Millis, true)),
+    );
+    object.insert(
+        "level".to_owned(),
+        Value::from(metadata.level().to_string()),
+    );
+    object.insert("app".to_owned(), Value::from(app_name));
+    object.insert("pid".to_owned(), Value::from(std::process::id()));
+    object.insert("target".to_owned(), Value::from(metadata.target()));
+    event.record(&mut JsonFieldVisitor(&mut object));
+    let line = serde_json::to_string(&Value::Object(object)).map_err(|_| std::fmt::Error)?;
+    writeln!(writer, "{}", line)
+}
+
+struct JsonFieldVisitor<'a>(&'a mut Map<String, Value>);
+
+impl tracing::field::Visit for JsonFieldVisitor<'_> {
+    fn record_debug(&mut self, field: &tracing::field::Field, value: &dyn Debug) {
+        self.insert(field, Value::from(format!("{:?}", value)));
+    }
+
+    fn record_str(&mut self, field: &tracing::field::Field, value: &str) {
+        self.insert(field, Value::from(value));
+    }
+
+    fn record_i64(&mut self, field: &tracing::field::Field, value: i64) {
+        self.insert(field, Value::from(value));
+    }
+
+    fn record_u64(&mut self, field: &tracing::field::Field, value: u64) {
+        self.insert(field, Value::from(value));
+    }
+
+    fn record_bool(&mut self, field: &tracing::field::Field, value: bool) {
+        self.insert(field, Value::from(value));
+    }
+}
+
+impl JsonFieldVisitor<'_> {
+    fn insert(&mut self, field: &tracing::field::Field, value: Value) {
+        // The fields of the records of the log crate, which the target already tells.
+        if field.name().starts_with("log.") {
+            return;
+        }
+        self.0.insert(field.name().to_owned(), value);
+    }
+}
+
 #[derive(Debug)]
 struct KmsgLogFormatter {
     app_name: String,
@@ -210,6 +422,27 @@ where
     }
 }
 
+static NON_INTERACTIVE: AtomicBool = AtomicBool::new(false);
+
+/// Makes the prompts take their defaults, or fail if they have none, instead of asking, such as
+/// in CI pipelines and provisioning scripts.
+pub fn set_non_interactive(non_interactive: bool) {
+    NON_INTERACTIVE.store(non_interactive, Ordering::Relaxed);
+}
+
+/// Whether the prompts can ask the user, which they can't in the non-interactive mode or when
+/// stdin isn't a terminal.
+pub fn is_interactive() -> bool {
+    !NON_INTERACTIVE.load(Ordering::Relaxed) && atty::is(atty::Stream::Stdin)
+}
+
+fn prompt_unavailable(message: &str) -> anyhow::Error {
+    anyhow!(
+        "Can't ask since Distrod runs non-interactively: {} Give the answer by the options.",
+        message
+    )
+}
+
 pub fn choose_from_list(list: DistroImageList) -> Result<Box<dyn DistroImageFetcher>> {
     match list {
         DistroImageList::Fetcher(list_item_kind, fetchers, default) => {
@@ -220,6 +453,21 @@ pub fn choose_from_list(list: DistroImageList) -> Result<Box<dyn DistroImageFetc
                 DefaultImageFetcher::Index(index) => fetchers[index].get_name().to_owned(),
                 DefaultImageFetcher::Name(name) => name,
             };
+            if !is_interactive() {
+                log::info!("Choosing '{}' as {} by default.", &default, &list_item_kind);
+                let index = fetchers
+                    .iter()
+                    .position(|fetcher| fetcher.get_name() == default.as_str())
+                    .ok_or_else(|| ImageError::NotFound {
+                        name: default.clone(),
+                        list_item_kind: list_item_kind.clone(),
+                    })?;
+                return Ok(fetchers.into_iter().nth(index).unwrap());
+            }
+            if list_chooser::is_available() {
+                let index = choose_by_list_chooser(&list_item_kind, &fetchers, &default)?;
+                return Ok(fetchers.into_iter().nth(index).unwrap());
+            }
             for (i, fetcher) in fetchers.iter().enumerate() {
                 println!("{} {}", format!("[{}]", i + 1).cyan(), fetcher.get_name());
             }
@@ -254,7 +502,31 @@ pub fn choose_from_list(list: DistroImageList) -> Result<Box<dyn DistroImageFetc
     }
 }
 
+fn choose_by_list_chooser(
+    list_item_kind: &str,
+    fetchers: &[Box<dyn DistroImageFetcher>],
+    default: &str,
+) -> Result<usize> {
+    let items: Vec<_> = fetchers
+        .iter()
+        .map(|fetcher| ListItem {
+            name: fetcher.get_name(),
+            description: fetcher.get_description(),
+        })
+        .collect();
+    let default = fetchers
+        .iter()
+        .position(|fetcher| fetcher.get_name() == default)
+        .unwrap_or(0);
+    list_chooser::choose(list_item_kind, &items, default)
+}
+
 pub fn prompt_path(message: &str, default: Option<&str>) -> Result<OsString> {
+    if !is_interactive() {
+        return default
+            .map(OsString::from)
+            .ok_or_else(|| prompt_unavailable(message));
+    }
     log::info!("{}", message);
     print!(
         "[Input the path{}]: ",
@@ -272,6 +544,11 @@ pub fn prompt_path(message: &str, default: Option<&str>) -> Result<OsString> {
 }
 
 pub fn prompt_string(message: &str, target_name: &str, default: Option<&str>) -> Result<String> {
+    if !is_interactive() {
+        return default
+            .map(|default| default.to_owned())
+            .ok_or_else(|| prompt_unavailable(message));
+    }
     log::info!("{}", message);
     print!(
         "[Input {}{}]: ",
@@ -289,6 +566,21 @@ pub fn prompt_string(message: &str, target_name: &str, default: Option<&str>) ->
     Ok(choice)
 }
 
+/// Asks a confirmation. It fails in the non-interactive mode rather than assuming either answer.
+pub fn prompt_yes_no(message: &str) -> Result<bool> {
+    if !is_interactive() {
+        return Err(prompt_unavailable(message));
+    }
+    log::info!("{}", message);
+    print!("[y/N]: ");
+    let _ = std::io::stdout().flush();
+    let mut answer = String::new();
+    std::io::stdin()
+        .read_line(&mut answer)
+        .with_context(|| "failed to read from the stdin.")?;
+    Ok(matches!(answer.trim().to_lowercase().as_str(), "y" | "yes"))
+}
+
 pub fn build_progress_bar(total_size: u64) -> indicatif::ProgressBar {
     let bar = indicatif::ProgressBar::new(total_size);
     bar.set_style(indicatif::ProgressStyle::default_bar()
@@ -0,0 +1,209 @@
+use anyhow::{bail, Result};
+use std::str::FromStr;
+
+pub const COMPLETION_SHELLS: &[&str] = &["bash", "zsh", "fish", "powershell"];
+
+/// The shells `completion` prints the scripts for.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CompletionShell {
+    Bash,
+    Zsh,
+    Fish,
+    PowerShell,
+}
+
+impl FromStr for CompletionShell {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "bash" => Ok(CompletionShell::Bash),
+            "zsh" => Ok(CompletionShell::Zsh),
+            "fish" => Ok(CompletionShell::Fish),
+            "powershell" => Ok(CompletionShell::PowerShell),
+            _ => bail!("Unknown shell: '{}'.", s),
+        }
+    }
+}
+
+/// The values of options which are only known when completing, such as the names of the
+/// installed distros, since the generated scripts only know the options and the subcommands.
+pub struct DynamicValues<'a> {
+    /// The options which take the values, such as "--distro" and "-d".
+    pub options: &'a [&'a str],
+    /// The command of the shell which prints the values one per line.
+    pub command: &'a str,
+}
+
+/// Adds the completion of the dynamic values to `script`, the completion script of `bin_name`
+/// generated by clap. The values are completed after the options, and the generated completion
+/// is used otherwise.
+pub fn add_dynamic_values(
+    script: &str,
+    shell: CompletionShell,
+    bin_name: &str,
+    values: &DynamicValues,
+) -> String {
+    let function_name = format!("_{}", bin_name);
+    match shell {
+        CompletionShell::Bash => format!(
+            "{script}\n\
+             {function}_dynamic() {{\n    \
+                 case \"${{COMP_WORDS[COMP_CWORD-1]}}\" in\n    \
+                 {options})\n        \
+                     COMPREPLY=($(compgen -W \"$({command})\" -- \"${{COMP_WORDS[COMP_CWORD]}}\"))\n        \
+                     return 0\n        \
+                     ;;\n    \
+                 esac\n    \
+                 {function} \"$@\"\n\
+             }}\n\
+             complete -F {function}_dynamic -o bashdefault -o default {bin}\n",
+            script = script.trim_end(),
+            function = function_name,
+            options = values.options.join("|"),
+            command = values.command,
+            bin = bin_name,
+        ),
+        CompletionShell::Zsh => {
+            let wrapper = format!(
+                "{function}_dynamic() {{\n    \
+                     case \"${{words[CURRENT-1]}}\" in\n    \
+                     {options})\n        \
+                         compadd -- ${{(f)\"$({command})\"}}\n        \
+                         ;;\n    \
+                     *)\n        \
+                         {function} \"$@\"\n        \
+                         ;;\n    \
+                     esac\n\
+                 }}\n",
+                function = function_name,
+                options = values.options.join("|"),
+                command = values.command,
+            );
+            // The script runs the completion function at its end, since zsh autoloads it as
+            // the body of the function. Run the wrapper instead.
+            let call = format!("{} \"$@\"", function_name);
+            let script = script.trim_end();
+            match script.strip_suffix(&call) {
+                Some(body) => format!("{}{}{}_dynamic \"$@\"\n", body, wrapper, function_name),
+                None => format!(
+                    "{}\n{}compdef {}_dynamic {}\n",
+                    script, wrapper, function_name, bin_name
+                ),
+            }
+        }
+        CompletionShell::Fish => {
+            let mut script = script.trim_end().to_owned();
+            script.push('\n');
+            for option in values.options {
+                let flag = match option.strip_prefix("--") {
+                    Some(long) => format!("-l {}", long),
+                    None => format!("-s {}", option.trim_start_matches('-')),
+                };
+                script.push_str(&format!(
+                    "complete -c {} {} -f -a \"({})\"\n",
+                    bin_name, flag, values.command
+                ));
+            }
+            script
+        }
+        CompletionShell::PowerShell => {
+            // Replace the completions before they are filtered by the word being completed.
+            let filter = match script.find("$completions.Where") {
+                Some(filter) => script[..filter].rfind('\n').map_or(0, |line| line + 1),
+                None => return script.to_owned(),
+            };
+            let options = values
+                .options
+                .iter()
+                .map(|option| format!("'{}'", option))
+                .collect::<Vec<_>>()
+                .join(", ");
+            format!(
+                "{before}    \
+                 $previous = if ($wordToComplete) {{ $commandElements[-2] }} else {{ $commandElements[-1] }}\n    \
+                 if (@({options}) -contains \"$previous\") {{\n        \
+                     $completions = @({command} | ForEach-Object {{\n            \
+                         [CompletionResult]::new($_, $_, [CompletionResultType]::ParameterValue, $_)\n        \
+                     }})\n    \
+                 }}\n\n\
+                 {after}",
+                before = &script[..filter],
+                options = options,
+                command = values.command,
+                after = &script[filter..],
+            )
+        }
+    }
+}
+
+#[cfg(test)]
+mod test_completion {
+    use super::*;
+
+    const VALUES: DynamicValues = DynamicValues {
+        options: &["--distro", "-d"],
+        command: "list-distros",
+    };
+
+    #[test]
+    fn test_add_dynamic_values_bash() {
+        let script = add_dynamic_values(
+            "_distrod() {\n    :\n}\n\ncomplete -F _distrod -o bashdefault -o default distrod\n",
+            CompletionShell::Bash,
+            "distrod",
+            &VALUES,
+        );
+        assert!(script.contains("    --distro|-d)\n"));
+        assert!(script.contains("COMPREPLY=($(compgen -W \"$(list-distros)\""));
+        assert!(
+            script.ends_with("complete -F _distrod_dynamic -o bashdefault -o default distrod\n")
+        );
+    }
+
+    #[test]
+    fn test_add_dynamic_values_zsh() {
+        let script = add_dynamic_values(
+            "#compdef distrod\n\n_distrod() {\n    :\n}\n\n_distrod \"$@\"\n",
+            CompletionShell::Zsh,
+            "distrod",
+            &VALUES,
+        );
+        assert!(script
+            .starts_with("#compdef distrod\n\n_distrod() {\n    :\n}\n\n_distrod_dynamic() {\n"));
+        assert!(script.contains("compadd -- ${(f)\"$(list-distros)\"}"));
+        assert!(script.ends_with("}\n_distrod_dynamic \"$@\"\n"));
+    }
+
+    #[test]
+    fn test_add_dynamic_values_fish() {
+        let script = add_dynamic_values(
+            "complete -c distrod -n \"__fish_use_subcommand\" -s h -l help\n",
+            CompletionShell::Fish,
+            "distrod",
+            &VALUES,
+        );
+        assert!(script.ends_with(
+            "complete -c distrod -l distro -f -a \"(list-distros)\"\n\
+             complete -c distrod -s d -f -a \"(list-distros)\"\n"
+        ));
+    }
+
+    #[test]
+    fn test_add_dynamic_values_powershell() {
+        let generated = "Register-ArgumentCompleter -Native -CommandName 'distrod' -ScriptBlock {\n    \
+                         $completions = @()\n\n    \
+                         $completions.Where{ $_.CompletionText -like \"$wordToComplete*\" } |\n        \
+                         Sort-Object -Property ListItemText\n}\n";
+        let script = add_dynamic_values(generated, CompletionShell::PowerShell, "distrod", &VALUES);
+        assert!(script.contains(
+            "    if (@('--distro', '-d') -contains \"$previous\") {\n        \
+             $completions = @(list-distros | ForEach-Object {\n"
+        ));
+        assert!(script.ends_with(&generated[generated.find("    $completions.Where").unwrap()..]));
+        assert_eq!(
+            "unknown",
+            add_dynamic_values("unknown", CompletionShell::PowerShell, "distrod", &VALUES)
+        );
+    }
+}
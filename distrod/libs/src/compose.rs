@@ -0,0 +1,317 @@
+use anyhow::{bail, Context, Result};
+use serde::Deserialize;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use crate::autostart;
+use crate::container_org_image::parse_image_reference;
+use crate::distro_config::MountConfig;
+use crate::distrod_config::AutostartDistroConfig;
+use crate::port_forward::{PortForwardRule, Protocol};
+use crate::provision::ProvisionSpec;
+
+/// The file `distrod compose` reads if -f is not given, in the current directory.
+pub const DEFAULT_COMPOSE_FILE_NAME: &str = "distrod-compose.toml";
+
+/// The file of `distrod compose`, which declares a set of named distros to bring up and down
+/// together.
+#[derive(Deserialize, Clone, Debug, Default, PartialEq)]
+#[serde(deny_unknown_fields)]
+pub struct ComposeFile {
+    /// How long `up` waits for a distro to finish booting before starting the ones after it.
+    #[serde(default = "default_ready_timeout_sec")]
+    pub ready_timeout_sec: u64,
+    #[serde(default, rename = "distro")]
+    pub distros: Vec<ComposeDistro>,
+}
+
+#[derive(Deserialize, Clone, Debug, Default, PartialEq)]
+#[serde(deny_unknown_fields)]
+pub struct ComposeDistro {
+    /// The name of the named distro, which is created if it doesn't exist.
+    pub name: String,
+    /// The image of linuxcontainers.org to create the distro from, such as debian:12.
+    pub image: String,
+    /// The provisioning spec to apply when the distro is created, relative to the compose file.
+    pub provision: Option<PathBuf>,
+    /// The distros which must finish booting before this one starts.
+    #[serde(default)]
+    pub after: Vec<String>,
+    /// The ports to forward from Windows, such as "5432", "8080:80", or
+    /// "127.0.0.1:8080:80/udp".
+    #[serde(default)]
+    pub ports: Vec<String>,
+    /// The bind mounts such as "/mnt/c/data:/data:ro", as `distrod start --mount` takes.
+    #[serde(default)]
+    pub mounts: Vec<String>,
+    /// The keys of /etc/distrod/distrod.toml of the distro to set on every `up`, such as
+    /// `network.hostname`, as `distrod config set` does.
+    #[serde(default)]
+    pub config: toml::value::Table,
+}
+
+fn default_ready_timeout_sec() -> u64 {
+    300
+}
+
+impl ComposeFile {
+    /// Reads and validates the compose file. The relative paths in it are resolved against the
+    /// directory of the file.
+    pub fn load<P: AsRef<Path>>(path: P) -> Result<ComposeFile> {
+        let path = path.as_ref();
+        let cont =
+            fs::read_to_string(path).with_context(|| format!("Failed to read {:?}.", path))?;
+        let mut compose = ComposeFile::from_toml_str(&cont)
+            .with_context(|| format!("Failed to parse the compose file {:?}.", path))?;
+        let base_dir = path.parent().unwrap_or_else(|| Path::new("."));
+        for distro in &mut compose.distros {
+            if let Some(ref provision) = distro.provision {
+                distro.provision = Some(base_dir.join(provision));
+            }
+        }
+        Ok(compose)
+    }
+
+    pub fn from_toml_str(cont: &str) -> Result<ComposeFile> {
+        let compose: ComposeFile = toml::from_str(cont)?;
+        compose.validate()?;
+        Ok(compose)
+    }
+
+    fn validate(&self) -> Result<()> {
+        self.plan_start_order()?;
+        for distro in &self.distros {
+            distro
+                .validate()
+                .with_context(|| format!("Invalid distro '{}'.", &distro.name))?;
+        }
+        let mut listens = vec![];
+        for distro in &self.distros {
+            for rule in distro.get_port_forward_rules()? {
+                let listen = (rule.listen_address.clone(), rule.listen_port, rule.protocol);
+                if listens.contains(&listen) {
+                    bail!(
+                        "{} is forwarded more than once.",
+                        rule.listen_socket_address()
+                    );
+                }
+                listens.push(listen);
+            }
+        }
+        Ok(())
+    }
+
+    /// Orders the distros so that each one comes after the distros in its `after`. The order in
+    /// the file is kept as long as it doesn't break a dependency.
+    pub fn plan_start_order(&self) -> Result<Vec<&ComposeDistro>> {
+        let deps: Vec<AutostartDistroConfig> = self
+            .distros
+            .iter()
+            .map(|distro| AutostartDistroConfig {
+                name: distro.name.clone(),
+                after: distro.after.clone(),
+            })
+            .collect();
+        let plan = autostart::plan_start_order(&deps, "the compose file")?;
+        Ok(plan
+            .iter()
+            .filter_map(|planned| self.distros.iter().find(|d| d.name == planned.name))
+            .collect())
+    }
+}
+
+impl ComposeDistro {
+    fn validate(&self) -> Result<()> {
+        parse_image_reference(&self.image)?;
+        for mount in &self.mounts {
+            MountConfig::parse(mount)?;
+        }
+        self.get_config_values()?;
+        Ok(())
+    }
+
+    /// Loads the provisioning spec of the distro, if it has one.
+    pub fn load_provision_spec(&self) -> Result<Option<ProvisionSpec>> {
+        self.provision.as_ref().map(ProvisionSpec::load).transpose()
+    }
+
+    pub fn get_port_forward_rules(&self) -> Result<Vec<PortForwardRule>> {
+        self.ports
+            .iter()
+            .map(|port| parse_port_spec(port))
+            .collect()
+    }
+
+    /// Returns the keys of `config` with the values in the form `distrod config set` takes.
+    /// Nested tables are flattened into dotted keys, and arrays are joined by commas.
+    pub fn get_config_values(&self) -> Result<Vec<(String, String)>> {
+        let mut values = vec![];
+        flatten_config_table("", &self.config, &mut values)?;
+        Ok(values)
+    }
+}
+
+fn flatten_config_table(
+    prefix: &str,
+    table: &toml::value::Table,
+    values: &mut Vec<(String, String)>,
+) -> Result<()> {
+    for (key, value) in table {
+        let key = format!("{}{}", prefix, key);
+        match value {
+            toml::Value::Table(table) => flatten_config_table(&format!("{}.", key), table, values)?,
+            toml::Value::Array(items) => {
+                let items = items
+                    .iter()
+                    .map(|item| config_value_to_string(&key, item))
+                    .collect::<Result<Vec<_>>>()?;
+                values.push((key, items.join(",")));
+            }
+            value => {
+                let value = config_value_to_string(&key, value)?;
+                values.push((key, value));
+            }
+        }
+    }
+    Ok(())
+}
+
+fn config_value_to_string(key: &str, value: &toml::Value) -> Result<String> {
+    match value {
+        toml::Value::String(value) => Ok(value.clone()),
+        toml::Value::Integer(value) => Ok(value.to_string()),
+        toml::Value::Float(value) => Ok(value.to_string()),
+        toml::Value::Boolean(value) => Ok(value.to_string()),
+        _ => bail!("The value of '{}' can't be set by the compose file.", key),
+    }
+}
+
+/// Parses a port such as "5432", "8080:80", or "127.0.0.1:8080:80/udp", which is
+/// [LISTEN_ADDRESS:]LISTEN_PORT[:DEST_PORT][/tcp|/udp].
+pub fn parse_port_spec(spec: &str) -> Result<PortForwardRule> {
+    let (ports, protocol) = match spec.rfind('/') {
+        Some(pos) => match &spec[pos + 1..] {
+            "tcp" => (&spec[..pos], Protocol::Tcp),
+            "udp" => (&spec[..pos], Protocol::Udp),
+            _ => bail!("Invalid protocol of the port '{}'.", spec),
+        },
+        None => (spec, Protocol::Tcp),
+    };
+    let fields: Vec<&str> = ports.split(':').collect();
+    let (listen_address, listen_port, dest_port) = match fields.as_slice() {
+        [listen] => (None, listen, None),
+        [listen, dest] => (None, listen, Some(dest)),
+        [address, listen, dest] => (Some(address), listen, Some(dest)),
+        _ => bail!(
+            "Invalid port '{}'. It should be such as 5432, 8080:80, or 127.0.0.1:8080:80/udp.",
+            spec
+        ),
+    };
+    let parse_port = |port: &str| -> Result<u16> {
+        match port.parse::<u16>() {
+            Ok(port) if port != 0 => Ok(port),
+            _ => bail!("Invalid port number in '{}'.", spec),
+        }
+    };
+    Ok(PortForwardRule {
+        listen_port: parse_port(listen_port)?,
+        listen_address: listen_address
+            .map(|address| address.to_string())
+            .unwrap_or_else(|| "0.0.0.0".to_owned()),
+        dest_address: None,
+        dest_port: dest_port.map(|port| parse_port(port)).transpose()?,
+        protocol,
+    })
+}
+
+#[cfg(test)]
+mod test_compose {
+    use super::*;
+
+    const LAB: &str = r#"
+[[distro]]
+name = "app"
+image = "ubuntu:22.04"
+after = ["db"]
+ports = ["8080:80"]
+mounts = ["/mnt/c/src/app:/srv/app:ro"]
+
+[distro.config.env]
+APP_DB = "localhost"
+
+[distro.config.network]
+mdns_names = ["app", "web"]
+
+[[distro]]
+name = "db"
+image = "debian:12"
+provision = "db.toml"
+ports = ["5432", "127.0.0.1:5353:53/udp"]
+"#;
+
+    #[test]
+    fn test_parse_compose_file() {
+        let compose = ComposeFile::from_toml_str(LAB).unwrap();
+        assert_eq!(300, compose.ready_timeout_sec);
+        let names: Vec<&str> = compose
+            .plan_start_order()
+            .unwrap()
+            .iter()
+            .map(|distro| distro.name.as_str())
+            .collect();
+        assert_eq!(vec!["db", "app"], names);
+
+        let app = &compose.distros[0];
+        assert_eq!(
+            vec![
+                ("env.APP_DB".to_owned(), "localhost".to_owned()),
+                ("network.mdns_names".to_owned(), "app,web".to_owned()),
+            ],
+            app.get_config_values().unwrap()
+        );
+        let db = &compose.distros[1];
+        assert_eq!(Some(PathBuf::from("db.toml")), db.provision);
+        assert_eq!(2, db.get_port_forward_rules().unwrap().len());
+    }
+
+    #[test]
+    fn test_parse_port_spec() {
+        let rule = parse_port_spec("5432").unwrap();
+        assert_eq!(5432, rule.listen_port);
+        assert_eq!("0.0.0.0", rule.listen_address);
+        assert_eq!(None, rule.dest_port);
+        assert_eq!(Protocol::Tcp, rule.protocol);
+
+        let rule = parse_port_spec("127.0.0.1:5353:53/udp").unwrap();
+        assert_eq!(5353, rule.listen_port);
+        assert_eq!("127.0.0.1", rule.listen_address);
+        assert_eq!(Some(53), rule.dest_port);
+        assert_eq!(Protocol::Udp, rule.protocol);
+
+        assert!(parse_port_spec("").is_err());
+        assert!(parse_port_spec("0").is_err());
+        assert!(parse_port_spec("8080:http").is_err());
+        assert!(parse_port_spec("8080/sctp").is_err());
+        assert!(parse_port_spec("a:b:8080:80").is_err());
+    }
+
+    #[test]
+    fn test_reject_invalid_compose_file() {
+        let parse = |cont: &str| ComposeFile::from_toml_str(cont);
+        assert!(
+            parse("[[distro]]\nname = \"a\"\nimage = \"debian:12\"\nafter = [\"b\"]\n").is_err()
+        );
+        assert!(parse("[[distro]]\nname = \"a/b\"\nimage = \"debian:12\"\n").is_err());
+        assert!(parse("[[distro]]\nname = \"a\"\nimage = \"../etc\"\n").is_err());
+        assert!(
+            parse("[[distro]]\nname = \"a\"\nimage = \"debian:12\"\nmounts = [\"data\"]\n")
+                .is_err()
+        );
+        assert!(parse("[[distro]]\nname = \"a\"\nimage = \"debian:12\"\nunknown = 1\n").is_err());
+        assert!(parse(
+            "[[distro]]\nname = \"a\"\nimage = \"debian:12\"\nports = [\"80\"]\n\
+             [[distro]]\nname = \"b\"\nimage = \"debian:12\"\nports = [\"80\"]\n"
+        )
+        .is_err());
+    }
+}
@@ -0,0 +1,266 @@
+use anyhow::{Context, Result};
+use serde::{Deserialize, Serialize};
+use std::fs::{File, OpenOptions};
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+use strum::EnumString;
+
+use crate::port_forward::Protocol;
+
+/// Which connections through the forwards portproxy.exe logs.
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq, Eq, EnumString)]
+#[serde(rename_all = "lowercase")]
+#[strum(serialize_all = "lowercase")]
+pub enum ConnectionLogLevel {
+    Off,
+    /// Only the connections which failed, such as the ones to a service that is down.
+    Errors,
+    All,
+}
+
+impl Default for ConnectionLogLevel {
+    fn default() -> Self {
+        ConnectionLogLevel::Off
+    }
+}
+
+/// `[connection_log]` of the rules file of the forwards.
+#[derive(Serialize, Deserialize, Clone, Debug, Default, PartialEq, Eq)]
+pub struct ConnectionLogConfig {
+    #[serde(default)]
+    pub level: ConnectionLogLevel,
+    /// The file to append the connections to as lines of JSON, instead of the journal of
+    /// portproxy.service. A relative path is resolved against the directory of the rules file.
+    pub path: Option<PathBuf>,
+}
+
+impl ConnectionLogConfig {
+    /// Resolves the relative path against `base_dir`.
+    pub fn resolve(&self, base_dir: &Path) -> ConnectionLogConfig {
+        ConnectionLogConfig {
+            path: self.path.as_ref().map(|path| base_dir.join(path)),
+            ..self.clone()
+        }
+    }
+}
+
+#[derive(Serialize, Clone, Copy, Debug, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum CloseReason {
+    /// Either side closed the connection.
+    Closed,
+    /// The UDP service sent nothing back within the session timeout.
+    IdleTimeout,
+    /// The service couldn't be connected to, such as when it's not running.
+    UpstreamUnreachable,
+    /// Relaying failed in the middle, such as by a reset.
+    Failed,
+}
+
+impl CloseReason {
+    pub fn is_error(&self) -> bool {
+        matches!(self, CloseReason::UpstreamUnreachable | CloseReason::Failed)
+    }
+
+    /// The name in the log, such as "idle_timeout".
+    pub fn name(&self) -> &'static str {
+        match self {
+            CloseReason::Closed => "closed",
+            CloseReason::IdleTimeout => "idle_timeout",
+            CloseReason::UpstreamUnreachable => "upstream_unreachable",
+            CloseReason::Failed => "failed",
+        }
+    }
+}
+
+/// A connection through a forward, which is logged when it's closed. "in" is from the client to
+/// the service.
+#[derive(Serialize, Clone, Debug, PartialEq)]
+pub struct ConnectionRecord {
+    pub protocol: Protocol,
+    pub listen: String,
+    pub source: String,
+    pub target: String,
+    #[serde(serialize_with = "serialize_duration_sec", rename = "duration_sec")]
+    pub duration: Duration,
+    pub bytes_in: u64,
+    pub bytes_out: u64,
+    pub close_reason: CloseReason,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+}
+
+fn serialize_duration_sec<S: serde::Serializer>(
+    duration: &Duration,
+    serializer: S,
+) -> std::result::Result<S::Ok, S::Error> {
+    serializer.serialize_f64(duration.as_millis() as f64 / 1000.0)
+}
+
+impl ConnectionRecord {
+    pub fn is_logged(&self, level: ConnectionLogLevel) -> bool {
+        match level {
+            ConnectionLogLevel::Off => false,
+            ConnectionLogLevel::Errors => self.close_reason.is_error(),
+            ConnectionLogLevel::All => true,
+        }
+    }
+
+    /// Formats it as a line of key=value pairs for the journal, such as
+    /// "Connection protocol=tcp listen=0.0.0.0:8080 source=192.168.1.5:51234 ...".
+    pub fn to_logfmt(&self) -> String {
+        let mut line = format!(
+            "Connection protocol={} listen={} source={} target={} duration_sec={:.3} \
+             bytes_in={} bytes_out={} close_reason={}",
+            self.protocol.name().to_ascii_lowercase(),
+            self.listen,
+            self.source,
+            self.target,
+            self.duration.as_secs_f64(),
+            self.bytes_in,
+            self.bytes_out,
+            self.close_reason.name(),
+        );
+        if let Some(ref error) = self.error {
+            line.push_str(&format!(" error={}", quote_logfmt_value(error)));
+        }
+        line
+    }
+
+    fn to_json_line(&self, time: &str) -> Result<String> {
+        let mut value =
+            serde_json::to_value(self).with_context(|| "Failed to serialize the connection.")?;
+        if let Some(object) = value.as_object_mut() {
+            object.insert("time".to_owned(), time.into());
+        }
+        Ok(format!("{}\n", value))
+    }
+}
+
+fn quote_logfmt_value(value: &str) -> String {
+    format!(
+        "\"{}\"",
+        value
+            .replace('\\', "\\\\")
+            .replace('"', "\\\"")
+            .replace('\n', "\\n")
+    )
+}
+
+/// Writes the connection records by a config, to the file if it has a path, or to stdout, which
+/// goes to the journal of portproxy.service.
+#[derive(Debug, Default)]
+pub struct ConnectionLog {
+    config: ConnectionLogConfig,
+    file: Option<File>,
+}
+
+impl ConnectionLog {
+    /// Opens the file of the config in the append mode unless the level is off.
+    pub fn open(config: ConnectionLogConfig) -> Result<ConnectionLog> {
+        let file = match config.path {
+            Some(ref path) if config.level != ConnectionLogLevel::Off => Some(
+                OpenOptions::new()
+                    .create(true)
+                    .append(true)
+                    .open(path)
+                    .with_context(|| format!("Failed to open the connection log {:?}.", path))?,
+            ),
+            _ => None,
+        };
+        Ok(ConnectionLog { config, file })
+    }
+
+    pub fn config(&self) -> &ConnectionLogConfig {
+        &self.config
+    }
+
+    pub fn write(&mut self, record: &ConnectionRecord) -> Result<()> {
+        if !record.is_logged(self.config.level) {
+            return Ok(());
+        }
+        match self.file {
+            Some(ref mut file) => {
+                let time =
+                    chrono::Local::now().to_rfc3339_opts(chrono::SecondsFormat::Millis, true);
+                file.write_all(record.to_json_line(&time)?.as_bytes())
+                    .with_context(|| format!("Failed to write to {:?}.", &self.config.path))
+            }
+            None => {
+                println!("{}", record.to_logfmt());
+                Ok(())
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod test_connection_log {
+    use super::*;
+
+    fn record_for_test(close_reason: CloseReason, error: Option<&str>) -> ConnectionRecord {
+        ConnectionRecord {
+            protocol: Protocol::Tcp,
+            listen: "0.0.0.0:8080".to_owned(),
+            source: "192.168.1.5:51234".to_owned(),
+            target: "172.20.0.2:80".to_owned(),
+            duration: Duration::from_millis(3250),
+            bytes_in: 100,
+            bytes_out: 2000,
+            close_reason,
+            error: error.map(str::to_owned),
+        }
+    }
+
+    #[test]
+    fn test_format_record() {
+        assert_eq!(
+            "Connection protocol=tcp listen=0.0.0.0:8080 source=192.168.1.5:51234 \
+             target=172.20.0.2:80 duration_sec=3.250 bytes_in=100 bytes_out=2000 \
+             close_reason=upstream_unreachable error=\"Connection refused \\\"os error\\\"\"",
+            record_for_test(
+                CloseReason::UpstreamUnreachable,
+                Some("Connection refused \"os error\"")
+            )
+            .to_logfmt()
+        );
+        let line = record_for_test(CloseReason::Closed, None)
+            .to_json_line("2021-11-03T10:00:00.000+09:00")
+            .unwrap();
+        let value: serde_json::Value = serde_json::from_str(&line).unwrap();
+        assert_eq!("closed", value["close_reason"]);
+        assert_eq!(3.25, value["duration_sec"]);
+        assert_eq!("2021-11-03T10:00:00.000+09:00", value["time"]);
+        assert!(value.get("error").is_none());
+    }
+
+    #[test]
+    fn test_is_logged() {
+        let closed = record_for_test(CloseReason::Closed, None);
+        let failed = record_for_test(CloseReason::Failed, Some("reset"));
+        assert!(!failed.is_logged(ConnectionLogLevel::Off));
+        assert!(!closed.is_logged(ConnectionLogLevel::Errors));
+        assert!(failed.is_logged(ConnectionLogLevel::Errors));
+        assert!(closed.is_logged(ConnectionLogLevel::All));
+    }
+
+    #[test]
+    fn test_write_to_file() {
+        let tmpdir = tempfile::TempDir::new().unwrap();
+        let config = ConnectionLogConfig {
+            level: ConnectionLogLevel::Errors,
+            path: Some(PathBuf::from("connections.log")),
+        };
+        let mut log = ConnectionLog::open(config.resolve(tmpdir.path())).unwrap();
+        let path = tmpdir.path().join("connections.log");
+        assert_eq!(Some(&path), log.config().path.as_ref());
+        log.write(&record_for_test(CloseReason::Closed, None))
+            .unwrap();
+        log.write(&record_for_test(CloseReason::Failed, Some("reset")))
+            .unwrap();
+        let content = std::fs::read_to_string(&path).unwrap();
+        assert_eq!(1, content.lines().count());
+        assert!(content.contains("\"close_reason\":\"failed\""));
+    }
+}
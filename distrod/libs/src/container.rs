@@ -1,17 +1,22 @@
-use anyhow::{anyhow, bail, Context, Result};
+use anyhow::{anyhow, Context, Result};
 use nix::sched::CloneFlags;
 use nix::NixPath;
 use passfd::FdPassingExt;
 use std::ffi::{OsStr, OsString};
 use std::fs::{self, File};
 use std::ops::{Deref, DerefMut};
-use std::os::unix::io::AsRawFd;
+use std::os::unix::fs::MetadataExt;
+use std::os::unix::io::{AsRawFd, FromRawFd};
 use std::os::unix::net::UnixStream;
 use std::path::{Path, PathBuf};
 use std::process::Command;
+use std::time::{Duration, Instant};
 
+use crate::capability;
+use crate::cgroup::Cgroup;
+use crate::error::ContainerError;
 use crate::mount_info::{get_mount_entries, MountEntry};
-use crate::multifork::{CommandByMultiFork, Waiter};
+use crate::multifork::{CommandByMultiFork, ProxyProcess, Waiter};
 use crate::passwd::Credential;
 use crate::procfile::ProcFile;
 
@@ -21,6 +26,13 @@ pub struct ContainerLauncher {
     init_envs: Vec<(OsString, OsString)>,
     init_args: Vec<OsString>,
     pre_exec_closures: Vec<Box<dyn FnMut() -> Result<()> + Send + Sync + 'static>>,
+    cgroup: Option<Cgroup>,
+    new_cgroup_namespace: bool,
+    ephemeral_rootfs: bool,
+    read_only_rootfs: bool,
+    dropped_init_capabilities: Vec<u32>,
+    hostname: Option<String>,
+    nested_containers: bool,
 }
 
 #[derive(Debug, Clone)]
@@ -44,6 +56,7 @@ impl ContainerLauncher {
         Ok(Container {
             init_pid: pid,
             init_procfile: procfile,
+            dropped_exec_capabilities: vec![],
         })
     }
 
@@ -81,6 +94,13 @@ impl ContainerLauncher {
         self
     }
 
+    /// Removes the arguments given so far, such as the ones for systemd when the init turns out
+    /// to be another one.
+    pub fn clear_init_args(&mut self) -> &mut Self {
+        self.init_args.clear();
+        self
+    }
+
     pub fn with_init_env<K, V>(&mut self, key: K, value: V) -> &mut Self
     where
         K: AsRef<OsStr>,
@@ -91,6 +111,55 @@ impl ContainerLauncher {
         self
     }
 
+    /// Puts the init process, and so all the processes it starts, in the cgroup.
+    pub fn with_cgroup(&mut self, cgroup: Cgroup) -> &mut Self {
+        self.cgroup = Some(cgroup);
+        self
+    }
+
+    /// Makes the cgroup of the init the root of a new cgroup namespace, so that the init sees
+    /// only its own subtree when it mounts the cgroup hierarchy.
+    pub fn with_new_cgroup_namespace(&mut self) -> &mut Self {
+        self.new_cgroup_namespace = true;
+        self
+    }
+
+    /// Mounts the rootfs under a writable tmpfs layer by overlayfs, so that the changes made in
+    /// the container are discarded when it stops.
+    pub fn with_ephemeral_rootfs(&mut self) -> &mut Self {
+        self.ephemeral_rootfs = true;
+        self
+    }
+
+    /// Mounts the rootfs read-only except for the directories in WRITABLE_STATE_DIRS and the
+    /// tmpfs such as /tmp and /run.
+    pub fn with_read_only_rootfs(&mut self) -> &mut Self {
+        self.read_only_rootfs = true;
+        self
+    }
+
+    /// Removes the capabilities from the bounding set of the init, and so of all the processes
+    /// in the container except for the ones entered from outside.
+    pub fn with_dropped_init_capabilities(&mut self, capabilities: Vec<u32>) -> &mut Self {
+        self.dropped_init_capabilities = capabilities;
+        self
+    }
+
+    /// Sets the hostname in the UTS namespace of the container, which is inherited from the host
+    /// otherwise.
+    pub fn with_hostname(&mut self, hostname: String) -> &mut Self {
+        self.hostname = Some(hostname);
+        self
+    }
+
+    /// Sets up the mounts which container engines such as dockerd and podman in the container
+    /// expect of a real machine, that is, the shared mount propagation of the rootfs and of
+    /// their state directories in NESTED_CONTAINER_STATE_DIRS.
+    pub fn with_nested_container_support(&mut self) -> &mut Self {
+        self.nested_containers = true;
+        self
+    }
+
     /// # Safety
     /// See the notes and safety of https://doc.rust-lang.org/std/os/unix/process/trait.CommandExt.html#tymethod.pre_exec
     /// In addition, note that registered pre_exec closures will run after the rootfs is set up including tmpfs such as /run.
@@ -118,10 +187,26 @@ impl ContainerLauncher {
             command.envs(self.init_envs.iter().map(|(k, v)| (k, v)));
             let mut command = CommandByMultiFork::new(command);
             let fds_to_keep = vec![fd_channel_child.as_raw_fd()];
+            let cgroup = self.cgroup.take();
+            let new_cgroup_namespace = self.new_cgroup_namespace;
+            let hostname = self.hostname.take();
             command.pre_second_fork(move || {
                 daemonize(&fds_to_keep)
                     .with_context(|| "The container failed to be daemonized.")?;
+                if let Some(ref cgroup) = cgroup {
+                    cgroup
+                        .add_current_process()
+                        .with_context(|| "Failed to join the cgroup.")?;
+                }
+                if new_cgroup_namespace {
+                    nix::sched::unshare(CloneFlags::CLONE_NEWCGROUP)
+                        .with_context(|| "Failed to make a cgroup namespace.")?;
+                }
                 enter_new_namespace().with_context(|| "Failed to initialize Linux namespaces.")?;
+                if let Some(ref hostname) = hostname {
+                    nix::unistd::sethostname(hostname)
+                        .with_context(|| format!("Failed to set the hostname to {}.", hostname))?;
+                }
                 Ok(())
             });
             unsafe {
@@ -142,6 +227,8 @@ impl ContainerLauncher {
                                 "a registered pre_exec closure of the init process failed."
                             })?;
                         }
+                        capability::drop_bounding_set(&self.dropped_init_capabilities)
+                            .with_context(|| "Failed to drop the capabilities of the init.")?;
                         Ok(())
                     };
                     if let Err(err) = inner().with_context(|| "Failed to send pidfd.") {
@@ -167,19 +254,33 @@ impl ContainerLauncher {
         Ok(Container {
             init_pid,
             init_procfile,
+            dropped_exec_capabilities: vec![],
         })
     }
 
     fn prepare_filesystem(&self, new_root: &HostPath, old_root: &ContainerPath) -> Result<()> {
         if new_root.as_path() == Path::new("/") {
+            if self.ephemeral_rootfs || self.read_only_rootfs {
+                return Err(ContainerError::EphemeralWslRootfs.into());
+            }
             prepare_host_base_root(old_root)?;
             self.process_mounts(&ContainerPath::new("/")?)?;
         } else {
-            prepare_minimum_root(new_root, old_root)?;
+            prepare_minimum_root(new_root, old_root, self.ephemeral_rootfs)?;
+            if self.read_only_rootfs {
+                bind_writable_state_dirs()?;
+            }
             self.process_mounts(old_root)?;
             let mount_entries =
                 get_mount_entries().with_context(|| "Failed to retrieve mount entries")?;
             umount_host_mountpoints(old_root, &mount_entries)?;
+            if self.nested_containers {
+                share_nested_container_state_dirs()?;
+            }
+            if self.read_only_rootfs {
+                // Do this at last, since the other mounts may make their mount points.
+                remount_read_only("/")?;
+            }
         }
         Ok(())
     }
@@ -218,16 +319,50 @@ pub enum MountSource {
 pub struct Container {
     pub init_pid: u32,
     init_procfile: ProcFile,
+    dropped_exec_capabilities: Vec<u32>,
+}
+
+/// The namespace files of the init of a container, kept open so that a long-running process can
+/// put commands in the container without opening them every time.
+pub struct Namespaces {
+    files: Vec<(&'static str, File)>,
 }
 
 impl Container {
+    /// Removes the capabilities from the bounding set of the commands run by `exec_command`
+    /// from now on, so that they can't get them even by setuid programs.
+    pub fn with_dropped_exec_capabilities(&mut self, capabilities: Vec<u32>) -> &mut Self {
+        self.dropped_exec_capabilities = capabilities;
+        self
+    }
+
     pub fn exec_command(&self, command: Command, cred: Option<&Credential>) -> Result<Waiter> {
         log::debug!("Container::exec_command.");
+        let (proxy, waiter) =
+            ProxyProcess::make_pair().with_context(|| "Failed to make a proxy process.")?;
+        self.exec_command_with_proxy(command, cred, None, proxy)?;
+        Ok(waiter)
+    }
 
+    /// Runs the command in the container and reports its exit code by the proxy.
+    /// The namespaces opened by `open_namespaces` are entered if given.
+    pub fn exec_command_with_proxy(
+        &self,
+        command: Command,
+        cred: Option<&Credential>,
+        namespaces: Option<&Namespaces>,
+        proxy: ProxyProcess,
+    ) -> Result<()> {
         let mut command = CommandByMultiFork::new(command);
         command.pre_second_fork(|| {
-            enter_namespace(&self.init_procfile)
-                .with_context(|| "Failed to enter the init's namespace")?;
+            let entered = match namespaces {
+                Some(namespaces) => namespaces.enter(),
+                None => enter_namespace(&self.init_procfile),
+            };
+            entered.with_context(|| "Failed to enter the init's namespace")?;
+            // Dropping them needs CAP_SETPCAP, which is lost with the privilege.
+            capability::drop_bounding_set(&self.dropped_exec_capabilities)
+                .with_context(|| "Failed to drop the capabilities of the command.")?;
             if let Some(cred) = cred {
                 log::debug!("dropping privilege. kmsg logging in the child ends here.");
                 cred.drop_privilege();
@@ -236,17 +371,42 @@ impl Container {
         });
         // To do a double fork in the new namespace and set the parent of the new child to init.
         command.do_triple_fork(true);
-        let waiter = command
-            .insert_waiter_proxy()
-            .with_context(|| "Failed to request a proxy process.")?;
+        command.insert_proxy(proxy);
         command
             .spawn()
             .with_context(|| "Container::exec_command failed")?;
         log::debug!("Triple fork done.");
-        Ok(waiter)
+        Ok(())
+    }
+
+    pub fn open_namespaces(&self) -> Result<Namespaces> {
+        let mut files = vec![];
+        for ns in NAMESPACES_TO_ENTER {
+            let fd = nix::fcntl::openat(
+                self.init_procfile.as_raw_fd(),
+                *ns,
+                nix::fcntl::OFlag::O_RDONLY | nix::fcntl::OFlag::O_CLOEXEC,
+                nix::sys::stat::Mode::empty(),
+            )
+            .with_context(|| format!("Failed to open {}.", ns))?;
+            files.push((*ns, unsafe { File::from_raw_fd(fd) }));
+        }
+        Ok(Namespaces { files })
+    }
+
+    /// Waits for the init to exit, and returns false if it's still running after the timeout.
+    pub fn wait_for_exit(&mut self, timeout: Duration) -> bool {
+        let deadline = Instant::now() + timeout;
+        while self.init_procfile.is_live() {
+            if Instant::now() >= deadline {
+                return false;
+            }
+            std::thread::sleep(EXIT_POLL_INTERVAL);
+        }
+        true
     }
 
-    pub fn stop(self, sigkill: bool) -> Result<()> {
+    pub fn stop(&self, sigkill: bool) -> Result<()> {
         let signal = if sigkill {
             nix::sys::signal::SIGKILL
         } else {
@@ -272,8 +432,36 @@ fn daemonize(fds_to_keep: &[i32]) -> Result<()> {
     Ok(())
 }
 
+impl Namespaces {
+    fn enter(&self) -> Result<()> {
+        for (ns, file) in &self.files {
+            nix::sched::setns(file.as_raw_fd(), CloneFlags::empty())
+                .with_context(|| format!("Setns({}) failed.", ns))?;
+        }
+        Ok(())
+    }
+}
+
+const EXIT_POLL_INTERVAL: Duration = Duration::from_millis(100);
+
+/// The directories which are kept writable in a read-only rootfs, in addition to the tmpfs.
+const WRITABLE_STATE_DIRS: &[&str] = &["/var", "/home"];
+
+/// The directories where Docker and Podman keep the images and the containers.
+const NESTED_CONTAINER_STATE_DIRS: &[&str] = &["/var/lib/docker", "/var/lib/containers"];
+
+const NAMESPACES_TO_ENTER: &[&str] = &["ns/uts", "ns/pid", "ns/mnt"];
+
 fn enter_namespace(proc: &ProcFile) -> Result<()> {
-    for ns in &["ns/uts", "ns/pid", "ns/mnt"] {
+    // A rootless container has its own user namespace, which has to be entered first to be
+    // allowed to enter the others.
+    let user_ns = proc.open_file_at("ns/user")?;
+    let own_user_ns = fs::metadata("/proc/self/ns/user")?;
+    if user_ns.metadata()?.ino() != own_user_ns.ino() {
+        nix::sched::setns(user_ns.as_raw_fd(), CloneFlags::CLONE_NEWUSER)
+            .with_context(|| "Setns(ns/user) failed.")?;
+    }
+    for ns in NAMESPACES_TO_ENTER {
         let ns_file = proc.open_file_at(ns)?;
         nix::sched::setns(ns_file.as_raw_fd(), CloneFlags::empty())
             .with_context(|| format!("Setns({}) failed.", ns))?;
@@ -303,7 +491,11 @@ fn prepare_host_base_root(old_root: &ContainerPath) -> Result<()> {
         .with_context(|| format!("setup {:?} fail.", old_root.join("proc")))
 }
 
-fn prepare_minimum_root(new_root: &HostPath, old_root: &ContainerPath) -> Result<()> {
+fn prepare_minimum_root(
+    new_root: &HostPath,
+    old_root: &ContainerPath,
+    ephemeral: bool,
+) -> Result<()> {
     let old_root_as_hostpath = old_root.to_host_path(new_root);
     if !old_root_as_hostpath.exists() {
         fs::create_dir_all(&old_root_as_hostpath).with_context(|| {
@@ -313,14 +505,18 @@ fn prepare_minimum_root(new_root: &HostPath, old_root: &ContainerPath) -> Result
             )
         })?;
     }
-    nix::mount::mount::<Path, Path, Path, Path>(
-        Some(new_root.as_ref()),
-        new_root.as_ref(),
-        None,
-        nix::mount::MsFlags::MS_BIND,
-        None,
-    )
-    .with_context(|| "Failed to bind mount the old_root")?;
+    if ephemeral {
+        mount_ephemeral_root(new_root, old_root_as_hostpath.as_path())?;
+    } else {
+        nix::mount::mount::<Path, Path, Path, Path>(
+            Some(new_root.as_ref()),
+            new_root.as_ref(),
+            None,
+            nix::mount::MsFlags::MS_BIND,
+            None,
+        )
+        .with_context(|| "Failed to bind mount the old_root")?;
+    }
     if new_root.as_path() == old_root.as_path() {
         std::env::set_current_dir(new_root.as_path())
             .with_context(|| "Failed to chdir to the new root.")?;
@@ -346,6 +542,105 @@ fn prepare_minimum_root(new_root: &HostPath, old_root: &ContainerPath) -> Result
     Ok(())
 }
 
+/// Mounts an overlayfs on the rootfs whose upper layer is on a tmpfs. The tmpfs is mounted on
+/// the mount point of the old root in the rootfs, which the container never sees since overlayfs
+/// doesn't show the mounts in its lower layer.
+fn mount_ephemeral_root(new_root: &HostPath, scratch: &Path) -> Result<()> {
+    nix::mount::mount::<Path, Path, str, str>(
+        None,
+        scratch,
+        Some("tmpfs"),
+        nix::mount::MsFlags::empty(),
+        Some("mode=0700"),
+    )
+    .with_context(|| format!("Failed to mount a tmpfs on {:?}.", scratch))?;
+    let upper = scratch.join("upper");
+    let work = scratch.join("work");
+    for dir in [&upper, &work].iter() {
+        fs::create_dir(dir).with_context(|| format!("Failed to create {:?}.", dir))?;
+    }
+    let lower = new_root.as_path();
+    // The options of overlayfs are separated by ',' and ':'.
+    if [lower, upper.as_path(), work.as_path()]
+        .iter()
+        .any(|path| path.to_string_lossy().contains(&[',', ':'][..]))
+    {
+        return Err(ContainerError::InvalidOverlayPath {
+            path: lower.to_owned(),
+        }
+        .into());
+    }
+    let options = format!(
+        "lowerdir={},upperdir={},workdir={}",
+        lower.to_string_lossy(),
+        upper.to_string_lossy(),
+        work.to_string_lossy()
+    );
+    nix::mount::mount::<str, Path, str, str>(
+        Some("overlay"),
+        lower,
+        Some("overlay"),
+        nix::mount::MsFlags::empty(),
+        Some(options.as_str()),
+    )
+    .with_context(|| format!("Failed to mount an overlayfs on {:?}.", lower))
+}
+
+/// Makes each of the state directories a mount point of its own, so that they stay writable
+/// after the rootfs is remounted read-only.
+fn bind_writable_state_dirs() -> Result<()> {
+    for dir in WRITABLE_STATE_DIRS {
+        create_mountpoint_unless_exist(dir, false)?;
+        nix::mount::mount::<str, str, str, str>(
+            Some(*dir),
+            *dir,
+            None,
+            nix::mount::MsFlags::MS_BIND | nix::mount::MsFlags::MS_REC,
+            None,
+        )
+        .with_context(|| format!("Failed to bind mount {:?}.", dir))?;
+    }
+    Ok(())
+}
+
+/// Makes the rootfs and the state directories of the container engines shared mounts, since
+/// the engines bind the layers and the volumes there and propagate them into their containers.
+fn share_nested_container_state_dirs() -> Result<()> {
+    for dir in NESTED_CONTAINER_STATE_DIRS {
+        create_mountpoint_unless_exist(dir, false)?;
+        // Only a mount point can have its own propagation type.
+        nix::mount::mount::<str, str, str, str>(
+            Some(*dir),
+            *dir,
+            None,
+            nix::mount::MsFlags::MS_BIND | nix::mount::MsFlags::MS_REC,
+            None,
+        )
+        .with_context(|| format!("Failed to bind mount {:?}.", dir))?;
+    }
+    nix::mount::mount::<str, str, str, str>(
+        None,
+        "/",
+        None,
+        nix::mount::MsFlags::MS_SHARED | nix::mount::MsFlags::MS_REC,
+        None,
+    )
+    .with_context(|| "Failed to make the rootfs a shared mount.")
+}
+
+fn remount_read_only<P: AsRef<Path>>(path: P) -> Result<()> {
+    nix::mount::mount::<Path, Path, Path, Path>(
+        None,
+        path.as_ref(),
+        None,
+        nix::mount::MsFlags::MS_BIND
+            | nix::mount::MsFlags::MS_REMOUNT
+            | nix::mount::MsFlags::MS_RDONLY,
+        None,
+    )
+    .with_context(|| format!("Failed to remount {:?} read-only.", path.as_ref()))
+}
+
 fn mount_nosource_fs<P: AsRef<Path>>(path: P, fstype: &str) -> Result<()> {
     create_mountpoint_unless_exist(path.as_ref(), false)?;
     nix::mount::mount::<Path, Path, Path, Path>(
@@ -428,10 +723,11 @@ pub struct ContainerPath(PathBuf);
 impl ContainerPath {
     pub fn new<P: AsRef<Path>>(path: P) -> Result<Self> {
         if !path.as_ref().has_root() {
-            bail!(
-                "Non-absolute path is given to ContainerPath::new {:?}",
-                path.as_ref()
-            );
+            return Err(ContainerError::RelativePath {
+                kind: "a container path",
+                path: path.as_ref().to_owned(),
+            }
+            .into());
         }
         Ok(ContainerPath(path.as_ref().to_owned()))
     }
@@ -477,10 +773,11 @@ pub struct HostPath(PathBuf);
 impl HostPath {
     pub fn new<P: AsRef<Path>>(path: P) -> Result<Self> {
         if !path.as_ref().has_root() {
-            bail!(
-                "Non-absolute path is given to HostPath::new {:?}",
-                path.as_ref()
-            );
+            return Err(ContainerError::RelativePath {
+                kind: "a host path",
+                path: path.as_ref().to_owned(),
+            }
+            .into());
         }
         Ok(HostPath(path.as_ref().to_owned()))
     }
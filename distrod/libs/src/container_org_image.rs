@@ -1,12 +1,16 @@
+use crate::arch::Arch;
 use crate::distro_image::{
     DefaultImageFetcher, DistroImage, DistroImageFetcher, DistroImageFile, DistroImageList,
     ListChooseFn,
 };
-use anyhow::{anyhow, bail, Context, Result};
+use crate::error::ImageError;
+use crate::simplestreams::{ImageStream, RootfsImage};
+use anyhow::{anyhow, Context, Result};
 use async_trait::async_trait;
 use chrono::NaiveDateTime;
 
 static LINUX_CONTAINERS_ORG_BASE: &str = "https://images.linuxcontainers.org/";
+static IMAGE_STREAM_PATH: &str = "streams/v1/images.json";
 
 pub async fn fetch_container_org_image(choose_from_list: ListChooseFn<'_>) -> Result<DistroImage> {
     let mut distro_image_list = Box::new(ContainerOrgImageList {}) as Box<dyn DistroImageFetcher>;
@@ -33,22 +37,16 @@ impl DistroImageFetcher for ContainerOrgImageList {
     }
 
     async fn fetch(&self) -> Result<DistroImageList> {
-        let distros: Vec<_> = fetch_apache_file_list("images/")
-            .await
-            .map(|links| {
-                links
-                    .into_iter()
-                    .map(|link| {
-                        Box::new(ContainerOrgDistroVersionList {
-                            name: link.name,
-                            version_list_url: format!("images/{}", link.url),
-                        }) as Box<dyn DistroImageFetcher>
-                    })
-                    .collect()
-            })
-            .with_context(|| {
-                "Failed to parse the distro image list of the linuxcontainer.org image server."
-            })?;
+        let distros = match fetch_stream_distros().await {
+            Ok(distros) => distros,
+            Err(e) => {
+                log::debug!(
+                    "Failed to get the image stream. Falling back to the file list. {:?}",
+                    e
+                );
+                fetch_file_list_distros().await?
+            }
+        };
 
         Ok(DistroImageList::Fetcher(
             "a linuxcontainers.org image".to_owned(),
@@ -58,6 +56,204 @@ impl DistroImageFetcher for ContainerOrgImageList {
     }
 }
 
+/// Lists the latest rootfs images of the releases of the distros for the architecture of this
+/// machine by the simplestreams index of linuxcontainers.org.
+pub async fn list_container_org_rootfs_images() -> Result<Vec<RootfsImage>> {
+    let url = format!("{}{}", LINUX_CONTAINERS_ORG_BASE, IMAGE_STREAM_PATH);
+    log::info!("Fetching from linuxcontainers.org...");
+    let download_error = |e: reqwest::Error| ImageError::Download {
+        url: url.clone(),
+        message: e.to_string(),
+    };
+    let json = reqwest::get(&url)
+        .await
+        .map_err(download_error)?
+        .error_for_status()
+        .map_err(download_error)?
+        .text()
+        .await
+        .map_err(download_error)?;
+    let arch = Arch::current().image_arch_name();
+    let images = ImageStream::from_json_str(&json)
+        .map_err(|e| ImageError::InvalidList {
+            url: url.clone(),
+            message: format!("{:#}", e),
+        })?
+        .list_rootfs_images(arch, get_variant);
+    if images.is_empty() {
+        return Err(ImageError::NoImageForArch {
+            arch: arch.to_owned(),
+            url,
+        }
+        .into());
+    }
+    Ok(images)
+}
+
+/// Returns the URL of the rootfs tarball of the image.
+pub fn get_container_org_image_url(image: &RootfsImage) -> String {
+    format!("{}{}", LINUX_CONTAINERS_ORG_BASE, image.path)
+}
+
+/// Returns the path of the image such as "ubuntu/jammy", which `distro_image::choose_by_path`
+/// takes, from a reference in the form of Docker such as "ubuntu:22.04", or of the path itself.
+/// The release can be omitted to take the default one, and the version numbers of Ubuntu and
+/// Debian are translated into the code names, by which linuxcontainers.org names the releases.
+pub fn parse_image_reference(reference: &str) -> Result<String, ImageError> {
+    let invalid = || ImageError::InvalidReference {
+        reference: reference.to_owned(),
+    };
+    let (distro, release) = match reference.find(|c| c == ':' || c == '/') {
+        Some(pos) => (&reference[..pos], Some(&reference[pos + 1..])),
+        None => (reference, None),
+    };
+    let is_valid_name = |name: &str| {
+        !name.is_empty()
+            && !name.starts_with('.')
+            && name
+                .chars()
+                .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_' || c == '.')
+    };
+    if !is_valid_name(distro) || !release.map_or(true, is_valid_name) {
+        return Err(invalid());
+    }
+    let distro = distro.to_ascii_lowercase();
+    Ok(match release {
+        Some(release) => {
+            let release = get_release_code_name(&distro, release).unwrap_or(release);
+            format!("{}/{}", distro, release)
+        }
+        None => distro,
+    })
+}
+
+fn get_release_code_name(distro_name: &str, version: &str) -> Option<&'static str> {
+    let code_name = match (distro_name, version) {
+        ("ubuntu", "18.04") => "bionic",
+        ("ubuntu", "20.04") => "focal",
+        ("ubuntu", "22.04") => "jammy",
+        ("ubuntu", "24.04") => "noble",
+        ("debian", "10") => "buster",
+        ("debian", "11") => "bullseye",
+        ("debian", "12") => "bookworm",
+        ("debian", "13") => "trixie",
+        _ => return None,
+    };
+    Some(code_name)
+}
+
+/// Lists the distros by the simplestreams index of the server, which has every image in a file.
+async fn fetch_stream_distros() -> Result<Vec<Box<dyn DistroImageFetcher>>> {
+    let images = list_container_org_rootfs_images().await?;
+    let mut distros: Vec<ContainerOrgStreamDistro> = vec![];
+    for image in images {
+        match distros.last_mut() {
+            Some(distro) if distro.name == image.distro => distro.releases.push(image),
+            _ => distros.push(ContainerOrgStreamDistro {
+                name: image.distro.clone(),
+                releases: vec![image],
+            }),
+        }
+    }
+    Ok(distros
+        .into_iter()
+        .map(|distro| Box::new(distro) as Box<dyn DistroImageFetcher>)
+        .collect())
+}
+
+/// Lists the distros by the pages of the image directories, for the servers without the index.
+async fn fetch_file_list_distros() -> Result<Vec<Box<dyn DistroImageFetcher>>> {
+    let links = fetch_apache_file_list("images/").await.with_context(|| {
+        "Failed to parse the distro image list of the linuxcontainer.org image server."
+    })?;
+    Ok(links
+        .into_iter()
+        .map(|link| {
+            Box::new(ContainerOrgDistroVersionList {
+                name: link.name,
+                version_list_url: format!("images/{}", link.url),
+            }) as Box<dyn DistroImageFetcher>
+        })
+        .collect())
+}
+
+fn get_variant(distro_name: &str) -> &'static str {
+    match distro_name {
+        "gentoo" => "systemd",
+        _ => "default",
+    }
+}
+
+fn get_default_version(distro_name: &str, versions_len: usize) -> DefaultImageFetcher {
+    match distro_name {
+        "ubuntu" => DefaultImageFetcher::Name("focal".to_owned()),
+        "debian" => DefaultImageFetcher::Name("bullseye".to_owned()),
+        _ => DefaultImageFetcher::Index(versions_len - 1),
+    }
+}
+
+#[derive(Debug)]
+struct ContainerOrgStreamDistro {
+    name: String,
+    releases: Vec<RootfsImage>,
+}
+
+#[async_trait]
+impl DistroImageFetcher for ContainerOrgStreamDistro {
+    fn get_name(&self) -> &str {
+        self.name.as_str()
+    }
+
+    fn get_description(&self) -> Option<String> {
+        Some(
+            self.releases
+                .iter()
+                .map(|image| image.release.as_str())
+                .collect::<Vec<_>>()
+                .join(", "),
+        )
+    }
+
+    async fn fetch(&self) -> Result<DistroImageList> {
+        let versions: Vec<_> = self
+            .releases
+            .iter()
+            .map(|image| Box::new(image.clone()) as Box<dyn DistroImageFetcher>)
+            .collect();
+        let default = get_default_version(&self.name, versions.len());
+        Ok(DistroImageList::Fetcher(
+            "a version".to_owned(),
+            versions,
+            default,
+        ))
+    }
+}
+
+#[async_trait]
+impl DistroImageFetcher for RootfsImage {
+    fn get_name(&self) -> &str {
+        self.release.as_str()
+    }
+
+    fn get_description(&self) -> Option<String> {
+        // The path is like "images/ubuntu/jammy/amd64/default/20231201_07:42/rootfs.tar.xz".
+        let parts: Vec<_> = self.path.split('/').collect();
+        match parts.as_slice() {
+            [.., arch, variant, build, _] => {
+                Some(format!("{} {}, built at {}", arch, variant, build))
+            }
+            _ => None,
+        }
+    }
+
+    async fn fetch(&self) -> Result<DistroImageList> {
+        Ok(DistroImageList::Image(DistroImage {
+            name: format!("{}-{}", &self.distro, &self.release),
+            image: DistroImageFile::Url(get_container_org_image_url(self)),
+        }))
+    }
+}
+
 #[derive(Debug)]
 pub struct ContainerOrgDistroVersionList {
     name: String,
@@ -85,11 +281,7 @@ impl DistroImageFetcher for ContainerOrgDistroVersionList {
                 }) as Box<dyn DistroImageFetcher>
             })
             .collect();
-        let default = match self.get_name() {
-            "ubuntu" => DefaultImageFetcher::Name("focal".to_owned()),
-            "debian" => DefaultImageFetcher::Name("bullseye".to_owned()),
-            _ => DefaultImageFetcher::Index(versions.len() - 1),
-        };
+        let default = get_default_version(self.get_name(), versions.len());
         Ok(DistroImageList::Fetcher(
             "a version".to_owned(),
             versions,
@@ -111,11 +303,20 @@ impl DistroImageFetcher for ContainerOrgDistroVersion {
         self.version_name.as_str()
     }
 
+    fn get_description(&self) -> Option<String> {
+        Some(format!(
+            "{} {}",
+            Arch::current().image_arch_name(),
+            get_variant(&self.distro_name)
+        ))
+    }
+
     async fn fetch(&self) -> Result<DistroImageList> {
-        let variant = match self.distro_name.as_str() {
-            "gentoo" => "amd64/systemd",
-            _ => "amd64/default",
-        };
+        let variant = format!(
+            "{}/{}",
+            Arch::current().image_arch_name(),
+            get_variant(&self.distro_name)
+        );
         let mut dates = fetch_apache_file_list(&format!("{}{}", &self.platform_list_url, variant))
             .await
             .with_context(|| {
@@ -144,12 +345,16 @@ async fn fetch_apache_file_list(relative_url: &str) -> Result<Vec<FileOnApache>>
     let a_link_selector =
         scraper::Selector::parse("body > table > tbody > tr > td:nth-child(2) > a").unwrap();
     log::info!("Fetching from linuxcontainers.org...");
+    let download_error = |e: reqwest::Error| ImageError::Download {
+        url: url.clone(),
+        message: e.to_string(),
+    };
     let apache_file_list_body = reqwest::get(&url)
         .await
-        .with_context(|| format!("Failed to fetch {}", &url))?
+        .map_err(download_error)?
         .text()
         .await
-        .with_context(|| format!("Failed to get the text of {}", &url))?;
+        .map_err(download_error)?;
     let doc = scraper::Html::parse_document(&apache_file_list_body);
     let dates: Vec<_> = doc.select(&date_selector).collect();
     let a_links: Vec<_> = doc.select(&a_link_selector).collect();
@@ -191,9 +396,15 @@ async fn fetch_apache_file_list(relative_url: &str) -> Result<Vec<FileOnApache>>
             })
         })
         .collect::<Result<Vec<_>>>()
-        .with_context(|| "Failed to parse the Apache file list page. Maybe the page is updated?")?;
+        .map_err(|e| ImageError::InvalidList {
+            url: url.clone(),
+            message: format!("{:#}. Maybe the page is updated?", e),
+        })?;
     if links.is_empty() {
-        bail!("{:?} is not available", &relative_url);
+        return Err(ImageError::Unavailable {
+            url: relative_url.to_owned(),
+        }
+        .into());
     }
     Ok(links)
 }
@@ -204,3 +415,30 @@ struct FileOnApache {
     url: String,
     last_modified: NaiveDateTime,
 }
+
+#[cfg(test)]
+mod test_container_org_image {
+    use super::*;
+
+    #[test]
+    fn test_parse_image_reference() {
+        assert_eq!(
+            "ubuntu/jammy",
+            parse_image_reference("ubuntu:22.04").unwrap()
+        );
+        assert_eq!(
+            "ubuntu/jammy",
+            parse_image_reference("ubuntu/jammy").unwrap()
+        );
+        assert_eq!(
+            "debian/bookworm",
+            parse_image_reference("Debian:12").unwrap()
+        );
+        assert_eq!("alpine/3.19", parse_image_reference("alpine:3.19").unwrap());
+        assert_eq!("archlinux", parse_image_reference("archlinux").unwrap());
+        assert!(parse_image_reference("").is_err());
+        assert!(parse_image_reference("ubuntu:").is_err());
+        assert!(parse_image_reference("ubuntu/jammy/amd64").is_err());
+        assert!(parse_image_reference("../ubuntu").is_err());
+    }
+}
@@ -0,0 +1,120 @@
+use serde_json::Value;
+use std::fmt;
+
+/// A version of CUDA, such as 12.2. The patch versions don't matter to the compatibility.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub struct CudaVersion {
+    pub major: u32,
+    pub minor: u32,
+}
+
+impl CudaVersion {
+    /// Parses "12.2", "12.2.140", or "12.2.20230823".
+    pub fn parse(version: &str) -> Option<CudaVersion> {
+        let mut parts = version.trim().split('.');
+        let major = parts.next()?.parse().ok()?;
+        let minor = parts.next()?.parse().ok()?;
+        Some(CudaVersion { major, minor })
+    }
+}
+
+impl fmt::Display for CudaVersion {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}.{}", self.major, self.minor)
+    }
+}
+
+/// Whether a CUDA toolkit runs on a driver.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CudaCompatibility {
+    /// The driver supports the version of the toolkit or a newer one.
+    Full,
+    /// The toolkit is newer in the same major version, which runs except for the features of
+    /// the newer minor version, such as compiling PTX at runtime.
+    MinorVersion,
+    Incompatible,
+}
+
+pub fn get_cuda_compatibility(driver: CudaVersion, toolkit: CudaVersion) -> CudaCompatibility {
+    if toolkit <= driver {
+        CudaCompatibility::Full
+    } else if toolkit.major == driver.major {
+        CudaCompatibility::MinorVersion
+    } else {
+        CudaCompatibility::Incompatible
+    }
+}
+
+/// Finds the newest version of CUDA the driver supports in the header of `nvidia-smi`, such as
+/// "| NVIDIA-SMI 535.104.05   Driver Version: 537.13   CUDA Version: 12.2 |".
+pub fn parse_nvidia_smi_cuda_version(output: &str) -> Option<CudaVersion> {
+    let rest = &output[output.find("CUDA Version:")? + "CUDA Version:".len()..];
+    CudaVersion::parse(rest.split_whitespace().next()?)
+}
+
+/// Reads the version of the toolkit from version.json of CUDA 11.1 or later, or from
+/// version.txt of the older ones, such as "CUDA Version 10.2.89".
+pub fn parse_cuda_toolkit_version(
+    version_json: Option<&str>,
+    version_txt: Option<&str>,
+) -> Option<CudaVersion> {
+    if let Some(json) = version_json {
+        let json: Value = serde_json::from_str(json).ok()?;
+        return CudaVersion::parse(json.get("cuda")?.get("version")?.as_str()?);
+    }
+    let txt = version_txt?;
+    let rest = &txt[txt.find("CUDA Version")? + "CUDA Version".len()..];
+    CudaVersion::parse(rest.split_whitespace().next()?)
+}
+
+#[cfg(test)]
+mod test_cuda_version {
+    use super::*;
+
+    #[test]
+    fn test_parse_versions() {
+        let v12_2 = CudaVersion {
+            major: 12,
+            minor: 2,
+        };
+        assert_eq!(
+            Some(v12_2),
+            parse_nvidia_smi_cuda_version(
+                "| NVIDIA-SMI 535.104.05   Driver Version: 537.13   CUDA Version: 12.2     |"
+            )
+        );
+        assert_eq!(None, parse_nvidia_smi_cuda_version("NVIDIA-SMI has failed"));
+        assert_eq!(
+            Some(v12_2),
+            parse_cuda_toolkit_version(
+                Some(r#"{"cuda": {"name": "CUDA SDK", "version": "12.2.20230823"}}"#),
+                None
+            )
+        );
+        assert_eq!(
+            Some(CudaVersion {
+                major: 10,
+                minor: 2
+            }),
+            parse_cuda_toolkit_version(None, Some("CUDA Version 10.2.89\n"))
+        );
+        assert_eq!(None, parse_cuda_toolkit_version(None, None));
+    }
+
+    #[test]
+    fn test_get_cuda_compatibility() {
+        let v = |major, minor| CudaVersion { major, minor };
+        assert_eq!(
+            CudaCompatibility::Full,
+            get_cuda_compatibility(v(12, 2), v(11, 8))
+        );
+        assert_eq!(
+            CudaCompatibility::MinorVersion,
+            get_cuda_compatibility(v(12, 2), v(12, 4))
+        );
+        assert_eq!(
+            CudaCompatibility::Incompatible,
+            get_cuda_compatibility(v(11, 8), v(12, 0))
+        );
+    }
+}
@@ -0,0 +1,313 @@
+use anyhow::{bail, Context, Result};
+use serde::de::DeserializeOwned;
+use serde::{Deserialize, Serialize};
+use std::io::{self, Read, Write};
+use std::os::unix::net::UnixStream;
+use std::path::PathBuf;
+use std::sync::Mutex;
+
+use crate::distro::get_daemon_socket_path;
+use crate::error;
+
+/// The largest message accepted, which keeps a broken peer from making the other side allocate
+/// as much as the length says.
+const MAX_MESSAGE_LEN: u32 = 16 * 1024 * 1024;
+
+/// A request to `distrod daemon`. A client can send many requests over a connection, and each
+/// one gets a `DaemonResponse`.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum DaemonRequest {
+    /// The states of the distros made by `distrod create`.
+    List,
+    /// Starts the distro, or the default one if the name is None.
+    Start { name: Option<String> },
+    Stop {
+        name: Option<String>,
+        sigkill: bool,
+        timeout_sec: Option<u64>,
+    },
+    /// Turns the connection into the stream of `DaemonResponse::Event`s. It gets no more
+    /// responses to requests.
+    Subscribe,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum DaemonResponse {
+    Distros {
+        distros: Vec<DaemonDistroState>,
+    },
+    Started {
+        name: Option<String>,
+        init_pid: u32,
+    },
+    Stopped {
+        name: Option<String>,
+    },
+    Subscribed,
+    Event {
+        event: DaemonEvent,
+    },
+    /// The request failed. `code` is the one of `crate::error::CodedError` if it has one.
+    Error {
+        code: Option<String>,
+        message: String,
+    },
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct DaemonDistroState {
+    pub name: String,
+    pub rootfs: PathBuf,
+    /// The pid of the init of the distro if it's running.
+    pub init_pid: Option<u32>,
+}
+
+/// A change of the state of a distro, which the daemon sees also when the distro is started or
+/// stopped without it, such as by `distrod start` or a shutdown in the distro.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum DaemonEvent {
+    Started { name: Option<String>, init_pid: u32 },
+    Stopped { name: Option<String> },
+}
+
+/// Writes a message as the length in u32 little endian and the JSON body, as the requests to
+/// the exec broker are.
+pub fn write_message<W: Write, T: Serialize>(writer: &mut W, message: &T) -> Result<()> {
+    let body = serde_json::to_vec(message).with_context(|| "Failed to serialize a message.")?;
+    writer.write_all(&(body.len() as u32).to_le_bytes())?;
+    writer.write_all(&body)?;
+    writer.flush()?;
+    Ok(())
+}
+
+/// Reads a message written by `write_message`. Returns None if the peer has closed the
+/// connection before a message.
+pub fn read_message<R: Read, T: DeserializeOwned>(reader: &mut R) -> Result<Option<T>> {
+    let mut len = [0u8; 4];
+    match reader.read_exact(&mut len) {
+        Ok(_) => {}
+        Err(e) if e.kind() == io::ErrorKind::UnexpectedEof => return Ok(None),
+        Err(e) => return Err(e).with_context(|| "Failed to read the length of a message."),
+    }
+    let len = u32::from_le_bytes(len);
+    if len > MAX_MESSAGE_LEN {
+        bail!("A message of {} bytes is too long.", len);
+    }
+    let mut body = vec![0u8; len as usize];
+    reader
+        .read_exact(&mut body)
+        .with_context(|| "Failed to read the body of a message.")?;
+    let message = serde_json::from_slice(&body).with_context(|| "Failed to parse a message.")?;
+    Ok(Some(message))
+}
+
+/// Answers the requests of a client by `handle` until it closes the connection. A failed request
+/// gets a `DaemonResponse::Error` with the code of its error. A subscribing client is moved to
+/// `subscribers`, which `publish_event` writes to.
+pub fn serve_client<F>(
+    mut stream: UnixStream,
+    subscribers: &Mutex<Vec<UnixStream>>,
+    handle: F,
+) -> Result<()>
+where
+    F: Fn(&DaemonRequest) -> Result<DaemonResponse>,
+{
+    while let Some(request) = read_message::<_, DaemonRequest>(&mut stream)? {
+        log::debug!("Daemon request: {:?}", &request);
+        if request == DaemonRequest::Subscribe {
+            write_message(&mut stream, &DaemonResponse::Subscribed)?;
+            subscribers
+                .lock()
+                .expect("[BUG] the lock is not poisoned.")
+                .push(stream);
+            return Ok(());
+        }
+        let response = handle(&request).unwrap_or_else(|e| {
+            log::warn!("Failed to handle {:?}. {:?}", &request, &e);
+            to_error_response(&e)
+        });
+        write_message(&mut stream, &response)?;
+    }
+    Ok(())
+}
+
+pub fn to_error_response(err: &anyhow::Error) -> DaemonResponse {
+    DaemonResponse::Error {
+        code: error::find_coded_error(err).map(|coded| coded.code().to_owned()),
+        message: format!("{:#}", err),
+    }
+}
+
+/// Writes the event to the subscribers, dropping the ones which have gone or don't read the
+/// events.
+pub fn publish_event(subscribers: &Mutex<Vec<UnixStream>>, event: DaemonEvent) {
+    let response = DaemonResponse::Event { event };
+    subscribers
+        .lock()
+        .expect("[BUG] the lock is not poisoned.")
+        .retain(|subscriber| write_message(&mut &*subscriber, &response).is_ok());
+}
+
+/// A connection to `distrod daemon`.
+pub struct DaemonClient {
+    stream: UnixStream,
+}
+
+impl DaemonClient {
+    /// Connects to the daemon. None is returned if it isn't running.
+    pub fn connect() -> Result<Option<DaemonClient>> {
+        let socket_path = get_daemon_socket_path()?;
+        match UnixStream::connect(socket_path.as_path()) {
+            Ok(stream) => Ok(Some(DaemonClient { stream })),
+            Err(e) => {
+                log::debug!("The daemon is not available. {}", e);
+                Ok(None)
+            }
+        }
+    }
+
+    /// Sends the request and returns the response. A `DaemonResponse::Error` is returned as an
+    /// error with its code in the message.
+    pub fn request(&mut self, request: &DaemonRequest) -> Result<DaemonResponse> {
+        write_message(&mut self.stream, request)
+            .with_context(|| "Failed to send the request to the daemon.")?;
+        match read_message(&mut self.stream)? {
+            Some(DaemonResponse::Error {
+                code: Some(code),
+                message,
+            }) => bail!("[{}] {}", code, message),
+            Some(DaemonResponse::Error {
+                code: None,
+                message,
+            }) => bail!("{}", message),
+            Some(response) => Ok(response),
+            None => bail!("The daemon has closed the connection."),
+        }
+    }
+
+    /// Subscribes to the events, after which only `next_event` is to be called.
+    pub fn subscribe(&mut self) -> Result<()> {
+        match self.request(&DaemonRequest::Subscribe)? {
+            DaemonResponse::Subscribed => Ok(()),
+            response => bail!("Unexpected response to a subscription: {:?}", response),
+        }
+    }
+
+    /// Waits for the next event. Returns None once the daemon exits.
+    pub fn next_event(&mut self) -> Result<Option<DaemonEvent>> {
+        loop {
+            match read_message(&mut self.stream)? {
+                Some(DaemonResponse::Event { event }) => return Ok(Some(event)),
+                Some(response) => log::debug!("Ignoring a response: {:?}", response),
+                None => return Ok(None),
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod test_daemon {
+    use super::*;
+    use crate::error::DistroError;
+    use std::sync::Arc;
+
+    #[test]
+    fn test_message_round_trip() {
+        let mut buf = vec![];
+        let request = DaemonRequest::Stop {
+            name: Some("ubuntu".to_owned()),
+            sigkill: false,
+            timeout_sec: Some(10),
+        };
+        write_message(&mut buf, &request).unwrap();
+        write_message(&mut buf, &DaemonRequest::List).unwrap();
+
+        let mut reader = io::Cursor::new(buf);
+        assert_eq!(
+            Some(request),
+            read_message::<_, DaemonRequest>(&mut reader).unwrap()
+        );
+        assert_eq!(
+            Some(DaemonRequest::List),
+            read_message::<_, DaemonRequest>(&mut reader).unwrap()
+        );
+        assert_eq!(None, read_message::<_, DaemonRequest>(&mut reader).unwrap());
+    }
+
+    #[test]
+    fn test_too_long_message() {
+        let mut reader = io::Cursor::new((MAX_MESSAGE_LEN + 1).to_le_bytes().to_vec());
+        assert!(read_message::<_, DaemonRequest>(&mut reader).is_err());
+    }
+
+    fn spawn_server<F>(handle: F) -> (DaemonClient, Arc<Mutex<Vec<UnixStream>>>)
+    where
+        F: Fn(&DaemonRequest) -> Result<DaemonResponse> + Send + 'static,
+    {
+        let (client, server) = UnixStream::pair().unwrap();
+        let subscribers = Arc::new(Mutex::new(vec![]));
+        let server_subscribers = subscribers.clone();
+        std::thread::spawn(move || serve_client(server, &server_subscribers, handle).unwrap());
+        (DaemonClient { stream: client }, subscribers)
+    }
+
+    #[test]
+    fn test_serve_client() {
+        let (mut client, _) = spawn_server(|request| match request {
+            DaemonRequest::List => Ok(DaemonResponse::Distros { distros: vec![] }),
+            DaemonRequest::Start { name } => {
+                Err(DistroError::AlreadyRunning { name: name.clone() })
+                    .with_context(|| "Failed to start the distro.")
+            }
+            _ => bail!("Unexpected request: {:?}", request),
+        });
+        assert_eq!(
+            DaemonResponse::Distros { distros: vec![] },
+            client.request(&DaemonRequest::List).unwrap()
+        );
+        // The connection is kept after an error.
+        let err = client
+            .request(&DaemonRequest::Start {
+                name: Some("ubuntu".to_owned()),
+            })
+            .unwrap_err();
+        assert_eq!(
+            "[E103] Failed to start the distro.: ubuntu is already running.",
+            err.to_string()
+        );
+        assert!(client
+            .request(&DaemonRequest::Stop {
+                name: None,
+                sigkill: false,
+                timeout_sec: None
+            })
+            .is_err());
+        assert_eq!(
+            DaemonResponse::Distros { distros: vec![] },
+            client.request(&DaemonRequest::List).unwrap()
+        );
+    }
+
+    #[test]
+    fn test_subscribe() {
+        let (mut client, subscribers) =
+            spawn_server(|request| bail!("Unexpected request: {:?}", request));
+        client.subscribe().unwrap();
+        while subscribers.lock().unwrap().is_empty() {
+            std::thread::sleep(std::time::Duration::from_millis(10));
+        }
+        let event = DaemonEvent::Started {
+            name: None,
+            init_pid: 42,
+        };
+        publish_event(&subscribers, event.clone());
+        assert_eq!(Some(event), client.next_event().unwrap());
+
+        drop(client);
+        publish_event(&subscribers, DaemonEvent::Stopped { name: None });
+        assert!(subscribers.lock().unwrap().is_empty());
+    }
+}
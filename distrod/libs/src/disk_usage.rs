@@ -0,0 +1,82 @@
+use anyhow::{Context, Result};
+use std::collections::HashSet;
+use std::fs;
+use std::os::unix::fs::MetadataExt;
+use std::path::{Path, PathBuf};
+
+use crate::mount_info::get_mount_points;
+
+/// Returns the bytes the files under the path take on the disk. The file systems mounted under
+/// it, such as /proc of a running distro, are not counted, and a file with hard links is counted
+/// once. Unlike `du -sx`, the mount points decide what is mounted, so that the btrfs subvolumes
+/// under the path, which have device numbers of their own, are counted. The blocks a btrfs
+/// snapshot shares with its source are counted for each of them.
+pub fn get_disk_usage<P: AsRef<Path>>(path: P) -> Result<u64> {
+    let path = path.as_ref();
+    let root = fs::symlink_metadata(path).with_context(|| format!("Failed to stat {:?}.", path))?;
+    let mount_points = get_mount_points()?;
+    let mut usage = 0;
+    // The subvolumes of btrfs number their inodes on their own.
+    let mut seen_inodes = HashSet::new();
+    let mut dirs: Vec<PathBuf> = vec![];
+    if root.is_dir() {
+        // The mount points are absolute and have no symlink.
+        dirs.push(
+            fs::canonicalize(path)
+                .with_context(|| format!("Failed to canonicalize {:?}.", path))?,
+        );
+    }
+    usage += root.blocks() * 512;
+    while let Some(dir) = dirs.pop() {
+        let entries = match fs::read_dir(&dir) {
+            Ok(entries) => entries,
+            // Such as a directory only root can read in the rootless mode.
+            Err(e) => {
+                log::debug!("Failed to read {:?}. {}", &dir, e);
+                continue;
+            }
+        };
+        for entry in entries {
+            let entry = entry.with_context(|| format!("Failed to read {:?}.", &dir))?;
+            let metadata = match entry.metadata() {
+                Ok(metadata) => metadata,
+                Err(e) => {
+                    log::debug!("Failed to stat {:?}. {}", entry.path(), e);
+                    continue;
+                }
+            };
+            if mount_points.contains(&entry.path()) {
+                continue;
+            }
+            if metadata.nlink() > 1
+                && !metadata.is_dir()
+                && !seen_inodes.insert((metadata.dev(), metadata.ino()))
+            {
+                continue;
+            }
+            usage += metadata.blocks() * 512;
+            if metadata.is_dir() {
+                dirs.push(entry.path());
+            }
+        }
+    }
+    Ok(usage)
+}
+
+#[cfg(test)]
+mod test_disk_usage {
+    use super::*;
+
+    #[test]
+    fn test_hard_links_are_counted_once() {
+        let dir = tempfile::tempdir().unwrap();
+        fs::create_dir(dir.path().join("sub")).unwrap();
+        fs::write(dir.path().join("sub/file"), vec![1u8; 64 * 1024]).unwrap();
+        let with_file = get_disk_usage(dir.path()).unwrap();
+        assert!(with_file >= 64 * 1024);
+
+        fs::hard_link(dir.path().join("sub/file"), dir.path().join("link")).unwrap();
+        assert_eq!(with_file, get_disk_usage(dir.path()).unwrap());
+        assert!(get_disk_usage(dir.path().join("missing")).is_err());
+    }
+}
@@ -3,43 +3,96 @@ use nix::unistd::{Gid, Uid};
 use std::collections::{HashMap, HashSet};
 use std::ffi::{OsStr, OsString};
 use std::fs::{self, File};
-use std::io::{BufReader, BufWriter, Write};
+use std::io::{BufReader, BufWriter, Read, Write};
 use std::os::linux::fs::MetadataExt;
+use std::os::unix::io::FromRawFd;
 use std::os::unix::prelude::{CommandExt, OsStrExt};
 use std::path::{Path, PathBuf};
-use std::process::Command;
-
+use std::process::{Command, Stdio};
+use std::time::{Duration, Instant};
+
+use crate::arch::check_rootfs_arch;
+use crate::auto_update;
+use crate::cancellation::CancellationToken;
+use crate::capability::parse_capabilities;
+use crate::cgroup::{self, Cgroup, CgroupMode, ResourceLimits};
 use crate::container::{Container, ContainerLauncher, ContainerPath, HostPath};
+use crate::distro_config::{
+    parse_memory_size, validate_target_name, DistroConfig, MountConfig, SystemdConfig,
+    DISTRO_CONFIG_PATH,
+};
+use crate::distro_registry::{validate_instance_name, DistroInstance};
 use crate::distrod_config::{self, DistrodConfig};
 use crate::envfile::{EnvFile, EnvShellScript};
+use crate::error::{ContainerError, DistroError};
+use crate::fstab::fix_fstab;
+use crate::hooks::{list_hooks, HookPoint};
+use crate::init_system::InitSystem;
+use crate::locale_sync;
+use crate::machined;
+use crate::managed_file::{ConflictPolicy, ManagedFileUpdater};
 use crate::mount_info::get_mount_entries;
 pub use crate::multifork::Waiter;
 use crate::passwd::{get_real_credential, Credential};
 use crate::procfile::ProcFile;
+use crate::pty;
+use crate::resolved::{self, NameServers};
+use crate::rootfs_image;
+use crate::seccomp::{SeccompFilter, SeccompProfile};
 use crate::systemdunit::{get_existing_systemd_unit, SystemdUnitDisabler, SystemdUnitOverride};
 use crate::template::Template;
-use crate::wsl_interop::{collect_wsl_env_vars, collect_wsl_paths};
+use crate::userns;
+use crate::wsl_interop::{
+    self, collect_wsl_env_vars, collect_wsl_paths, collect_wslenv_shared_vars,
+    collect_wslg_env_vars, get_drive_letter, WSLG_DIR_PATH, WSLG_RUNTIME_DIR_PATH,
+    WSLG_X11_SOCKET_DIR_PATH,
+};
 use serde::{Deserialize, Serialize};
 
 const DISTRO_OLD_ROOT_PATH: &str = "/mnt/distrod_root";
+const DEFAULT_STOP_TIMEOUT: Duration = Duration::from_secs(30);
+pub const DEFAULT_SYSTEMD_TARGET: &str = "multi-user.target";
+/// The device of the GPU paravirtualization of WSL2.
+pub const WSL_GPU_DEVICE_PATH: &str = "/dev/dxg";
+/// The directory where WSL puts the user mode GPU drivers of Windows, such as libcuda.so.
+pub const WSL_GPU_LIB_DIR_PATH: &str = "/usr/lib/wsl/lib";
+const WSL_GPU_LD_CONF_PATH: &str = "/etc/ld.so.conf.d/ld.wsl.conf";
+/// The script which loads the WSL env vars of the user on login.
+const PER_USER_ENVS_PROFILE_PATH: &str = "/etc/profile.d/distrod-user-wsl-envs.sh";
 
 pub struct DistroLauncher {
+    name: Option<String>,
     rootfs: Option<PathBuf>,
     system_envs: HashMap<String, String>,
     system_paths: HashSet<String>,
     per_user_envs: HashMap<String, String>,
     per_user_paths: HashSet<(String, bool)>,
+    resource_limits: ResourceLimits,
+    target: Option<String>,
+    ephemeral: bool,
+    read_only: bool,
+    bind_mounts: Vec<MountConfig>,
+    nameservers: Option<NameServers>,
+    cancel: CancellationToken,
     container_launcher: ContainerLauncher,
 }
 
 impl DistroLauncher {
     pub fn new() -> Result<Self> {
         let mut distro_launcher = DistroLauncher {
+            name: None,
             rootfs: None,
             system_envs: HashMap::new(),
             system_paths: HashSet::new(),
             per_user_envs: HashMap::new(),
             per_user_paths: HashSet::new(),
+            resource_limits: ResourceLimits::default(),
+            target: None,
+            ephemeral: false,
+            read_only: false,
+            bind_mounts: vec![],
+            nameservers: None,
+            cancel: CancellationToken::new(),
             container_launcher: ContainerLauncher::new(),
         };
         set_wsl_interop_envs_in_system_envs(&mut distro_launcher)
@@ -55,8 +108,15 @@ impl DistroLauncher {
         Ok(distro_launcher)
     }
 
+    /// Returns the distro started without a name, which is the one that the shell hook and
+    /// command aliases use.
     pub fn get_running_distro() -> Result<Option<Distro>> {
-        let run_info_file = get_distro_run_info_file(false, false)
+        Self::get_running_distro_by_name(None)
+    }
+
+    /// Returns the distro started with the given name, or the unnamed distro if `name` is None.
+    pub fn get_running_distro_by_name(name: Option<&str>) -> Result<Option<Distro>> {
+        let run_info_file = get_distro_run_info_file(name, false, false)
             .with_context(|| "Failed to open the distro run info file.")?;
         if run_info_file.is_none() {
             return Ok(None);
@@ -67,11 +127,35 @@ impl DistroLauncher {
             return Ok(None);
         }
         Ok(Some(Distro {
+            name: name.map(|name| name.to_owned()),
             rootfs: run_info.rootfs,
+            resource_limits: run_info.resource_limits,
+            target: run_info.target,
+            ephemeral: run_info.ephemeral,
+            read_only: run_info.read_only,
+            bind_mounts: run_info.bind_mounts,
+            nameservers: run_info.nameservers,
+            seccomp_filter: None,
+            terminal: None,
+            clean_env: false,
             container: ContainerLauncher::from_pid(run_info.init_pid)?,
         }))
     }
 
+    /// Sets the name of the distro so that multiple distros can run and be addressed concurrently.
+    pub fn with_name(&mut self, name: &str) -> Result<&mut Self> {
+        validate_instance_name(name)?;
+        self.name = Some(name.to_owned());
+        Ok(self)
+    }
+
+    /// Launches the named distro registered in Distrod, using its name as the name of the distro.
+    pub fn from_named_distro(&mut self, name: &str) -> Result<&mut Self> {
+        let instance = DistroInstance::get(name)?;
+        self.with_name(&instance.name)?;
+        self.with_rootfs(&instance.rootfs)
+    }
+
     pub fn with_rootfs<P: AsRef<Path>>(&mut self, path: P) -> Result<&mut Self> {
         self.rootfs = Some(
             path.as_ref()
@@ -108,6 +192,58 @@ impl DistroLauncher {
         self
     }
 
+    /// Sets the resource limits of the distro, which take precedence over [resources] of the
+    /// distro config.
+    pub fn with_resource_limits(&mut self, limits: ResourceLimits) -> &mut Self {
+        self.resource_limits = limits;
+        self
+    }
+
+    /// Makes `launch` stop with `Cancelled` when the token is cancelled before the container is
+    /// launched. Once it's launched, `launch` finishes starting the distro, so that no container
+    /// is left without its run info.
+    pub fn with_cancellation_token(&mut self, cancel: &CancellationToken) -> &mut Self {
+        self.cancel = cancel.clone();
+        self
+    }
+
+    /// Sets the systemd target to boot into, which takes precedence over the default target of
+    /// the distro.
+    pub fn with_target(&mut self, target: &str) -> Result<&mut Self> {
+        validate_target_name(target)?;
+        self.target = Some(target.to_owned());
+        Ok(self)
+    }
+
+    /// Makes the rootfs writable only on memory, so that all the changes made in the distro are
+    /// discarded when it stops.
+    pub fn with_ephemeral(&mut self) -> &mut Self {
+        self.ephemeral = true;
+        self
+    }
+
+    /// Mounts the rootfs read-only except for /var, /home, and the tmpfs such as /tmp and /run,
+    /// even if [rootfs] of the distro config doesn't say so.
+    pub fn with_read_only_rootfs(&mut self) -> &mut Self {
+        self.read_only = true;
+        self
+    }
+
+    /// Adds a bind mount in addition to the ones in [[mounts]] of the distro config.
+    pub fn with_bind_mount(&mut self, mount: MountConfig) -> Result<&mut Self> {
+        mount.validate()?;
+        self.bind_mounts.push(mount);
+        Ok(self)
+    }
+
+    /// Sets the name servers of the distro in place of the ones of WSL, which take precedence
+    /// over `nameservers` of the distro config.
+    pub fn with_nameservers(&mut self, nameservers: NameServers) -> Result<&mut Self> {
+        nameservers.validate()?;
+        self.nameservers = Some(nameservers);
+        Ok(self)
+    }
+
     pub fn with_init_arg<O: AsRef<OsStr>>(&mut self, arg: O) -> &mut Self {
         self.container_launcher.with_init_arg(arg);
         self
@@ -144,10 +280,188 @@ impl DistroLauncher {
             .ok_or_else(|| anyhow!("rootfs is not initialized."))?
             .clone();
 
+        if rootfs != Path::new("/") {
+            rootfs_image::mount_image(&rootfs)
+                .with_context(|| "Failed to mount the disk image of the rootfs.")?;
+        }
+        let distro_config = get_distro_config(&HostPath::new(&rootfs)?)
+            .with_context(|| "Failed to read the config of the distro.")?;
+        self.cancel.check()?;
+
+        if rootfs == Path::new("/") && userns::is_rootless_mode() {
+            return Err(ContainerError::RootlessWslRootfs.into());
+        }
+        let read_only = self.read_only || distro_config.rootfs.read_only;
+        if rootfs == Path::new("/") && (self.ephemeral || read_only) {
+            return Err(ContainerError::EphemeralWslRootfs.into());
+        }
+        if rootfs != Path::new("/") {
+            check_rootfs_arch(&rootfs)?;
+        }
+        if let Some(ref hostname) = distro_config.network.hostname {
+            if rootfs == Path::new("/") {
+                log::warn!(
+                    "The hostname of the rootfs of WSL is set by WSL. Ignoring {}.",
+                    hostname
+                );
+            } else {
+                set_hostname(&HostPath::new(&rootfs)?, hostname)
+                    .with_context(|| "Failed to set the hostname of the distro.")?;
+                self.container_launcher.with_hostname(hostname.clone());
+            }
+        }
+        // WSL sets the time zone of its own rootfs by `useWindowsTimezone` of wsl.conf.
+        if rootfs != Path::new("/") {
+            locale_sync::sync_from_windows(&HostPath::new(&rootfs)?, &distro_config.locale);
+        }
+        if self.ephemeral {
+            self.container_launcher.with_ephemeral_rootfs();
+        }
+        if read_only {
+            self.container_launcher.with_read_only_rootfs();
+        }
+        self.container_launcher
+            .with_dropped_init_capabilities(parse_capabilities(
+                &distro_config.capabilities.init_drop,
+            )?);
+        let nested_containers = distro_config.nested_containers.enabled;
+        if nested_containers {
+            // Done before the WSL mountpoints are collected, since binfmt_misc may be mounted.
+            prepare_host_for_nested_containers();
+            self.container_launcher.with_nested_container_support();
+        }
+        if !userns::is_rootless_mode() {
+            // Done before the WSL mountpoints are collected, since binfmt_misc may be mounted.
+            match wsl_interop::register_wsl_interop_binfmt() {
+                Ok(true) => log::info!("Registered WSLInterop to binfmt_misc again."),
+                Ok(false) => {}
+                Err(e) => log::warn!("Windows executables may not run in the distro. {:?}", e),
+            }
+        }
+        self.cancel.check()?;
+        let custom_nameservers = self
+            .nameservers
+            .clone()
+            .or_else(|| NameServers::from_config(&distro_config.network));
         if rootfs == Path::new("/") {
+            if custom_nameservers.is_some() {
+                log::warn!(
+                    "The rootfs of WSL takes the name servers of WSL. Ignoring the custom ones."
+                );
+            }
             make_host_mountpoints_shared().with_context(|| "Failed to make mountpoint shared.")?;
         } else {
-            mount_wsl_mountpoints(&mut self).with_context(|| "Failed to mount WSL mountpoints.")?;
+            let host_rootfs = HostPath::new(&rootfs)?;
+            let takes_nameservers = resolved::takes_nameservers_of_wsl(
+                &host_rootfs,
+                &distro_config,
+                custom_nameservers.as_ref(),
+            );
+            if takes_nameservers {
+                if let Err(e) = resolved::write_resolved_dropin(&rootfs) {
+                    log::warn!(
+                        "Failed to pass the name servers to systemd-resolved. {:?}",
+                        e
+                    );
+                }
+            }
+            let syncs_nameservers = resolved::syncs_nameservers(
+                &host_rootfs,
+                &distro_config,
+                custom_nameservers.as_ref(),
+            );
+            if let Some(ref custom_nameservers) = custom_nameservers {
+                set_nameservers(&mut self, &host_rootfs, custom_nameservers)
+                    .with_context(|| "Failed to set the custom name servers.")?;
+            } else if syncs_nameservers && !takes_nameservers {
+                // distrod resolved-sync rewrites it while none of the ones of WSL answers.
+                let wsl_nameservers = resolved::read_wsl_nameservers()?;
+                set_nameservers(&mut self, &host_rootfs, &wsl_nameservers)
+                    .with_context(|| "Failed to set the name servers of WSL.")?;
+            }
+            mount_wsl_mountpoints(
+                &mut self,
+                &distro_config,
+                distro_config.network.share_resolv_conf
+                    && custom_nameservers.is_none()
+                    && !syncs_nameservers,
+            )
+            .with_context(|| "Failed to mount WSL mountpoints.")?;
+        }
+        // WSL sets up the GPU of its own rootfs by itself.
+        let passes_gpu = rootfs != Path::new("/")
+            && distro_config.gpu.passthrough
+            && Path::new(WSL_GPU_DEVICE_PATH).exists();
+        if passes_gpu {
+            mount_wsl_gpu(&mut self, &HostPath::new(&rootfs)?)
+                .with_context(|| "Failed to pass the GPU through.")?;
+        }
+        apply_distro_config(&mut self, &HostPath::new(&rootfs)?, &distro_config)
+            .with_context(|| "Failed to apply the config of the distro.")?;
+        for mount in self.bind_mounts.clone() {
+            add_bind_mount(&mut self, &mount)?;
+        }
+        self.cancel.check()?;
+        let limits =
+            get_configured_resource_limits(&distro_config)?.overridden_by(&self.resource_limits);
+        let cgroup_mode = cgroup::detect_cgroup_mode(
+            &get_mount_entries().with_context(|| "Failed to retrieve mount entries")?,
+        );
+        log::debug!("The cgroup mode of the host: {:?}", cgroup_mode);
+        let cgroup_name = self.name.as_deref().unwrap_or("default");
+        if userns::is_rootless_mode() {
+            // A non-root user can't make cgroups, but systemd still runs without its own one.
+            if !limits.is_empty() {
+                log::warn!("The resource limits are ignored in the rootless mode.");
+            }
+        } else if cgroup_mode == CgroupMode::Unified {
+            // On the v2 only host, give systemd a subtree of its own, since it can't boot
+            // without managing the hierarchy. On the others, systemd mounts what it supports.
+            let cgroup = Cgroup::create_delegated(cgroup_name, &limits)
+                .with_context(|| "Failed to create the cgroup of the distro.")?;
+            self.container_launcher
+                .with_cgroup(cgroup)
+                .with_new_cgroup_namespace()
+                .with_mount(
+                    None,
+                    ContainerPath::new("/sys/fs/cgroup")?,
+                    Some("cgroup2".into()),
+                    nix::mount::MsFlags::MS_NOSUID
+                        | nix::mount::MsFlags::MS_NODEV
+                        | nix::mount::MsFlags::MS_NOEXEC,
+                    None,
+                    false,
+                );
+        } else if !limits.is_empty() || nested_containers {
+            // Container engines make their cgroups under the one of the distro, so give it
+            // a subtree of its own even without the limits.
+            let cgroup = Cgroup::create(cgroup_name, &limits)
+                .with_context(|| "Failed to create the cgroup of the distro.")?;
+            self.container_launcher.with_cgroup(cgroup);
+        }
+
+        let init_system = InitSystem::detect(&HostPath::new(&rootfs)?);
+        log::debug!("The init system of the distro: {}", init_system.name());
+        if init_system == InitSystem::Systemd {
+            if let Err(e) = set_up_user_sessions(&HostPath::new(&rootfs)?, &distro_config) {
+                log::warn!("Failed to set up the systemd user sessions. {:?}", e);
+            }
+        } else {
+            // The other inits don't take the arguments of systemd, such as --setenv, so give the
+            // environment variables to them directly, which their services inherit.
+            self.container_launcher.clear_init_args();
+            for (key, value) in &self.system_envs {
+                self.container_launcher.with_init_env(key, value);
+            }
+        }
+
+        // WSL shares WSLg with its own rootfs by itself.
+        if rootfs != Path::new("/")
+            && distro_config.wslg.enabled
+            && wsl_interop::is_wslg_available()
+        {
+            mount_wslg(&mut self, &HostPath::new(&rootfs)?, init_system)
+                .with_context(|| "Failed to share WSLg with the distro.")?;
         }
 
         self.mount_per_user_envs_script()
@@ -159,9 +473,17 @@ impl DistroLauncher {
         )
         .with_context(|| "Failed to write system env file.")?;
 
+        let target = match self.target {
+            Some(ref target) => target.clone(),
+            None => get_default_target(&HostPath::new(&rootfs)?)
+                .with_context(|| "Failed to get the default target of the distro.")?,
+        };
         self.container_launcher
-            .with_init_env("container", "distrod") // See https://systemd.io/CONTAINER_INTERFACE/
-            .with_init_arg("--unit=multi-user.target");
+            .with_init_env("container", "distrod"); // See https://systemd.io/CONTAINER_INTERFACE/
+        if init_system == InitSystem::Systemd {
+            self.container_launcher
+                .with_init_arg(format!("--unit={}", target));
+        }
         unsafe {
             self.container_launcher.with_init_pre_exec(|| {
                 // Systemd requires the real uid / gid to be the root.
@@ -170,19 +492,66 @@ impl DistroLauncher {
                 Ok(())
             });
         };
+        if distro_config.fstab.auto_fix {
+            report_fstab_fixes(&HostPath::new(&rootfs)?);
+        }
+        run_pre_start_hooks(&HostPath::new(&rootfs)?, self.name.as_deref());
+        self.cancel.check()?;
         let container = self
             .container_launcher
             .launch(
-                "/sbin/init",
+                init_system.init_path(&HostPath::new(&rootfs)?),
                 HostPath::new(&rootfs)?,
                 ContainerPath::new(DISTRO_OLD_ROOT_PATH)?,
             )
             .with_context(|| "Failed to launch a container.")?;
 
-        export_distro_run_info(&rootfs, container.init_pid)
+        let run_info = DistroRunInfo {
+            rootfs: rootfs.clone(),
+            init_pid: container.init_pid,
+            resource_limits: self.resource_limits,
+            target: self.target.clone(),
+            ephemeral: self.ephemeral,
+            read_only: self.read_only,
+            bind_mounts: self.bind_mounts.clone(),
+            nameservers: self.nameservers.clone(),
+        };
+        export_distro_run_info(self.name.as_deref(), &run_info)
             .with_context(|| "Failed to export the Distro running information.")?;
+        if distro_config.machined.register
+            && !userns::is_rootless_mode()
+            && machined::is_available()
+        {
+            let machine_name = machined::get_machine_name(self.name.as_deref());
+            if let Err(e) = machined::register_machine(&machine_name, container.init_pid, &rootfs) {
+                log::warn!("{:?}", e);
+            }
+        }
 
-        let distro = Distro { rootfs, container };
+        let distro = Distro {
+            name: self.name,
+            rootfs,
+            resource_limits: self.resource_limits,
+            target: self.target,
+            ephemeral: self.ephemeral,
+            read_only: self.read_only,
+            bind_mounts: self.bind_mounts,
+            nameservers: self.nameservers,
+            seccomp_filter: None,
+            terminal: None,
+            clean_env: false,
+            container,
+        };
+        if passes_gpu {
+            if let Err(err) = distro.update_ld_cache() {
+                log::warn!(
+                    "Failed to add {} to the library path of the distro. {:?}",
+                    WSL_GPU_LIB_DIR_PATH,
+                    err
+                );
+            }
+        }
+        distro.run_hooks(HookPoint::PostStart);
         Ok(distro)
     }
 
@@ -356,6 +725,11 @@ fn set_per_user_wsl_envs(distro_launcher: &mut DistroLauncher) -> Result<()> {
             value.to_string_lossy().to_string(),
         );
     }
+    // The variables shared by WSLENV are passed to the sessions which don't come from Windows
+    // as well, such as ssh and su, with the values at the start of the distro.
+    for (key, value) in collect_wslenv_shared_vars() {
+        distro_launcher.with_per_user_env(key, value);
+    }
     for path in collect_wsl_paths().with_context(|| "Failed to collect WSL paths.")? {
         distro_launcher.with_per_user_path(path, false);
     }
@@ -398,23 +772,52 @@ fn mount_slash_run_static_files(distro_launcher: &mut DistroLauncher) -> Result<
     Ok(())
 }
 
+/// Lets the distro use the name servers, by the drop-in if it uses systemd-resolved, or else by
+/// binding the resolv.conf with them to /etc/resolv.conf.
+fn set_nameservers(
+    distro_launcher: &mut DistroLauncher,
+    rootfs: &HostPath,
+    nameservers: &NameServers,
+) -> Result<()> {
+    if resolved::uses_resolved(rootfs) {
+        resolved::write_resolved_dropin_of(rootfs.as_path(), nameservers)?;
+        return Ok(());
+    }
+    let resolv_conf = resolved::write_custom_resolv_conf(rootfs, nameservers)?;
+    distro_launcher.with_mount(
+        Some(resolv_conf),
+        ContainerPath::new("/etc/resolv.conf")?,
+        None,
+        nix::mount::MsFlags::MS_BIND,
+        None,
+        true,
+    );
+    Ok(())
+}
+
 fn prepend_distrod_bin_to_path(distro_launcher: &mut DistroLauncher) -> Result<()> {
     distro_launcher.with_system_path(distrod_config::get_distrod_bin_dir_path().to_owned());
     distro_launcher.with_per_user_path(distrod_config::get_distrod_bin_dir_path().to_owned(), true);
     Ok(())
 }
 
-fn mount_wsl_mountpoints(distro_launcher: &mut DistroLauncher) -> Result<()> {
-    let binds = vec![
+fn mount_wsl_mountpoints(
+    distro_launcher: &mut DistroLauncher,
+    distro_config: &DistroConfig,
+    binds_resolv_conf: bool,
+) -> Result<()> {
+    let mut binds = vec![
         ("/init", true),
         ("/sys", false),
         ("/dev", false),
         ("/mnt/wsl", false),
         ("/run/WSL", false),
         ("/etc/wsl.conf", true),
-        ("/etc/resolv.conf", true),
         ("/proc/sys/fs/binfmt_misc", false),
     ];
+    if binds_resolv_conf {
+        binds.push(("/etc/resolv.conf", true));
+    }
     for (bind_file, is_file) in binds {
         if !Path::new(bind_file).exists() {
             log::debug!("WSL path {:?} does not exist.", bind_file);
@@ -441,8 +844,30 @@ fn mount_wsl_mountpoints(distro_launcher: &mut DistroLauncher) -> Result<()> {
             // /init is also mounted by 9p, but we have already mounted it.
             continue;
         }
+        let mut source = HostPath::new(path)?;
+        if let Some(drive_letter) = get_drive_letter(&mount_entry) {
+            if !distro_config.drives.is_included(drive_letter) {
+                log::debug!("Skipping the drive {}: at {:?}.", drive_letter, path);
+                continue;
+            }
+            if let Some(ref options) = distro_config.drives.options {
+                match mount_drive_with_options(
+                    drive_letter,
+                    options,
+                    distro_launcher.name.as_deref(),
+                ) {
+                    Ok(drive_path) => source = drive_path,
+                    Err(err) => log::warn!(
+                        "Failed to mount the drive {}: with '{}'. Sharing the mount of WSL. {:?}",
+                        drive_letter,
+                        options,
+                        err
+                    ),
+                }
+            }
+        }
         distro_launcher.with_mount(
-            Some(HostPath::new(path)?),
+            Some(source),
             ContainerPath::new(path)?,
             None,
             nix::mount::MsFlags::MS_BIND,
@@ -453,6 +878,295 @@ fn mount_wsl_mountpoints(distro_launcher: &mut DistroLauncher) -> Result<()> {
     Ok(())
 }
 
+/// Mounts the Windows drive by drvfs with the options on a directory of the runtime files, since
+/// the options can't be changed on a bind mount of the one WSL has made. A mount left by the
+/// previous start is replaced, so that the current options are used.
+fn mount_drive_with_options(
+    drive_letter: char,
+    options: &str,
+    name: Option<&str>,
+) -> Result<HostPath> {
+    if userns::is_rootless_mode() {
+        return Err(ContainerError::RootRequired {
+            operation: "mount drvfs".to_owned(),
+        }
+        .into());
+    }
+    let mut drive_path = get_distrod_runtime_files_dir_path()?;
+    drive_path.push("drives");
+    drive_path.push(name.unwrap_or("default"));
+    drive_path.push(drive_letter.to_ascii_lowercase().to_string());
+    let is_mounted = get_mount_entries()?
+        .iter()
+        .any(|entry| entry.path == *drive_path);
+    if is_mounted {
+        nix::mount::umount2(drive_path.as_path(), nix::mount::MntFlags::MNT_DETACH)
+            .with_context(|| format!("Failed to unmount {:?}.", &drive_path))?;
+    }
+    fs::create_dir_all(drive_path.as_path())
+        .with_context(|| format!("Failed to create {:?}.", &drive_path))?;
+    // mount.drvfs of WSL knows how to talk to Windows, which the mount syscall alone can't.
+    let status = Command::new("mount")
+        .args(&["-t", "drvfs", "-o", options])
+        .arg(format!("{}:", drive_letter))
+        .arg(drive_path.as_path())
+        .status()
+        .with_context(|| "Failed to run mount.")?;
+    if !status.success() {
+        bail!("mount exited with {}.", status);
+    }
+    Ok(drive_path)
+}
+
+/// The kernel modules dockerd and podman need for the overlay storage and the bridge network.
+/// They are built into the kernel of WSL2, but a custom kernel may have them as modules.
+const NESTED_CONTAINER_KERNEL_MODULES: &[&str] = &[
+    "overlay",
+    "br_netfilter",
+    "veth",
+    "iptable_nat",
+    "xt_conntrack",
+];
+
+/// Loads the kernel modules and mounts binfmt_misc, which multi-arch images run by, on the host.
+/// The container can't do them by itself, so failures are only warned.
+fn prepare_host_for_nested_containers() {
+    for module in NESTED_CONTAINER_KERNEL_MODULES {
+        match Command::new("modprobe").arg(module).status() {
+            Ok(status) if status.success() => {}
+            Ok(status) => log::warn!("modprobe {} exited with {}.", module, status),
+            Err(e) => log::debug!("Failed to run modprobe {}. {:?}", module, e),
+        }
+    }
+    if let Err(e) = wsl_interop::mount_binfmt_misc() {
+        log::warn!("{:?}", e);
+    }
+}
+
+/// Binds /dev/dxg and the drivers in /usr/lib/wsl, and lets the dynamic linker of the distro
+/// find the libraries in /usr/lib/wsl/lib as WSL does for its own rootfs.
+fn mount_wsl_gpu(distro_launcher: &mut DistroLauncher, rootfs: &HostPath) -> Result<()> {
+    // /dev is bound without its submounts, so bind the device explicitly as well.
+    distro_launcher.with_mount(
+        Some(HostPath::new(WSL_GPU_DEVICE_PATH)?),
+        ContainerPath::new(WSL_GPU_DEVICE_PATH)?,
+        None,
+        nix::mount::MsFlags::MS_BIND,
+        None,
+        true,
+    );
+    let wsl_lib_dir = Path::new(WSL_GPU_LIB_DIR_PATH)
+        .parent()
+        .expect("[BUG] WSL_GPU_LIB_DIR_PATH has a parent.");
+    if !wsl_lib_dir.is_dir() {
+        log::debug!("{:?} does not exist.", wsl_lib_dir);
+        return Ok(());
+    }
+    // /usr/lib/wsl/lib and /usr/lib/wsl/drivers are separate mounts.
+    distro_launcher.with_mount(
+        Some(HostPath::new(wsl_lib_dir)?),
+        ContainerPath::new(wsl_lib_dir)?,
+        None,
+        nix::mount::MsFlags::MS_BIND | nix::mount::MsFlags::MS_REC,
+        None,
+        false,
+    );
+    write_wsl_gpu_ld_conf(rootfs)?;
+    Ok(())
+}
+
+/// Adds /usr/lib/wsl/lib to the library path of the dynamic linker of the distro by
+/// /etc/ld.so.conf.d/ld.wsl.conf, as WSL does for its own rootfs. Returns false if the distro
+/// doesn't have ld.so.conf.d, such as the musl based ones, which can't load the drivers anyway.
+pub fn write_wsl_gpu_ld_conf(rootfs: &HostPath) -> Result<bool> {
+    let ld_conf_path = ContainerPath::new(WSL_GPU_LD_CONF_PATH)?.to_host_path(rootfs);
+    let ld_conf_dir = ld_conf_path
+        .parent()
+        .expect("[BUG] WSL_GPU_LD_CONF_PATH has a parent.");
+    if !ld_conf_dir.is_dir() {
+        return Ok(false);
+    }
+    if !ld_conf_path.exists() {
+        fs::write(
+            ld_conf_path.as_path(),
+            format!("{}\n", WSL_GPU_LIB_DIR_PATH),
+        )
+        .with_context(|| format!("Failed to write {:?}.", &ld_conf_path))?;
+    }
+    Ok(true)
+}
+
+/// The path where X11 clients look for the socket of the display.
+const X11_SOCKET_DIR_PATH: &str = "/tmp/.X11-unix";
+
+/// Binds /mnt/wslg, links the X11 socket into /tmp, and sets the environment variables by which
+/// GUI apps find the sockets of WSLg.
+fn mount_wslg(
+    distro_launcher: &mut DistroLauncher,
+    rootfs: &HostPath,
+    init_system: InitSystem,
+) -> Result<()> {
+    // /mnt/wslg/distro and /mnt/wslg/doc are separate mounts.
+    distro_launcher.with_mount(
+        Some(HostPath::new(WSLG_DIR_PATH)?),
+        ContainerPath::new(WSLG_DIR_PATH)?,
+        None,
+        nix::mount::MsFlags::MS_BIND | nix::mount::MsFlags::MS_REC,
+        None,
+        false,
+    );
+    // With systemd, /run/tmpfiles.d/x11.conf makes the link after /tmp is cleaned up.
+    if init_system != InitSystem::Systemd {
+        let x11_socket_dir = ContainerPath::new(X11_SOCKET_DIR_PATH)?.to_host_path(rootfs);
+        match fs::symlink_metadata(x11_socket_dir.as_path()) {
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => {
+                std::os::unix::fs::symlink(WSLG_X11_SOCKET_DIR_PATH, x11_socket_dir.as_path())
+                    .with_context(|| {
+                        format!("Failed to make a symlink at {:?}.", &x11_socket_dir)
+                    })?;
+            }
+            _ => log::debug!("{:?} already exists.", &x11_socket_dir),
+        }
+    }
+    for (key, value) in collect_wslg_env_vars() {
+        distro_launcher.with_per_user_env(key, value);
+    }
+    Ok(())
+}
+
+/// The PAM files which the login sessions by su include, one of which has pam_systemd.so if the
+/// distro is set up for the user sessions.
+const PAM_SESSION_FILES: &[&str] = &[
+    "/etc/pam.d/su",
+    "/etc/pam.d/common-session",
+    "/etc/pam.d/system-auth",
+    "/etc/pam.d/system-login",
+];
+
+/// Lets pam_systemd start `systemd --user` for the logins by su, which `distrod shell` and
+/// `distrod exec --user` use, and enables or disables lingering of the default user.
+fn set_up_user_sessions(rootfs: &HostPath, distro_config: &DistroConfig) -> Result<()> {
+    let mut has_pam_systemd = false;
+    for pam_file in PAM_SESSION_FILES {
+        let path = ContainerPath::new(pam_file)?.to_host_path(rootfs);
+        if !path.exists() {
+            continue;
+        }
+        let cont = fs::read_to_string(path.as_path())
+            .with_context(|| format!("Failed to read {:?}.", &path))?;
+        has_pam_systemd |= cont.contains("pam_systemd.so");
+    }
+    let pam_su_path = ContainerPath::new("/etc/pam.d/su")?.to_host_path(rootfs);
+    if !has_pam_systemd && pam_su_path.exists() {
+        let mut cont = fs::read_to_string(pam_su_path.as_path())
+            .with_context(|| format!("Failed to read {:?}.", &pam_su_path))?;
+        if !cont.is_empty() && !cont.ends_with('\n') {
+            cont.push('\n');
+        }
+        // '-' lets PAM skip the line silently if the module is not installed.
+        cont.push_str("# The following line of pam_systemd.so is inserted by Distrod\n");
+        cont.push_str("-session   optional   pam_systemd.so\n");
+        fs::write(pam_su_path.as_path(), cont)
+            .with_context(|| format!("Failed to update {:?}.", &pam_su_path))?;
+    }
+
+    let user = match distro_config.user.default {
+        Some(ref user) => user,
+        None => return Ok(()),
+    };
+    if user.is_empty() || user.contains('/') {
+        bail!("Invalid user name: '{}'.", user);
+    }
+    // This is what `loginctl enable-linger` and `disable-linger` do, which need logind running.
+    let linger_dir = ContainerPath::new("/var/lib/systemd/linger")?.to_host_path(rootfs);
+    let linger_path = linger_dir.join(user);
+    if distro_config.user.linger {
+        if !linger_path.exists() {
+            fs::create_dir_all(linger_dir.as_path())
+                .with_context(|| format!("Failed to create {:?}.", &linger_dir))?;
+            File::create(&linger_path)
+                .with_context(|| format!("Failed to create {:?}.", &linger_path))?;
+        }
+    } else {
+        match fs::remove_file(&linger_path) {
+            Ok(()) => {}
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => {}
+            Err(e) => {
+                return Err(e).with_context(|| format!("Failed to remove {:?}.", &linger_path))
+            }
+        }
+    }
+    Ok(())
+}
+
+fn add_bind_mount(distro_launcher: &mut DistroLauncher, mount: &MountConfig) -> Result<()> {
+    if !mount.source.exists() {
+        log::warn!("The mount source {:?} does not exist.", &mount.source);
+        return Ok(());
+    }
+    let target = ContainerPath::new(&mount.target)?;
+    distro_launcher.with_mount(
+        Some(HostPath::new(&mount.source)?),
+        target.clone(),
+        None,
+        nix::mount::MsFlags::MS_BIND | nix::mount::MsFlags::MS_REC,
+        None,
+        mount.source.is_file(),
+    );
+    if mount.read_only {
+        // MS_RDONLY is ignored on the first bind mount, so remount it.
+        distro_launcher.with_mount(
+            None,
+            target,
+            None,
+            nix::mount::MsFlags::MS_BIND
+                | nix::mount::MsFlags::MS_REMOUNT
+                | nix::mount::MsFlags::MS_RDONLY,
+            None,
+            mount.source.is_file(),
+        );
+    }
+    Ok(())
+}
+
+fn apply_distro_config(
+    distro_launcher: &mut DistroLauncher,
+    rootfs: &HostPath,
+    distro_config: &DistroConfig,
+) -> Result<()> {
+    for (key, value) in &distro_config.env {
+        distro_launcher.with_system_env(key.clone(), value.clone());
+        distro_launcher
+            .container_launcher
+            .with_init_arg(&env_to_systemd_setenv_arg(key, value));
+    }
+    for mount in &distro_config.mounts {
+        add_bind_mount(distro_launcher, mount)?;
+    }
+    for unit in &distro_config.systemd.masked_units {
+        if let Err(err) = SystemdUnitDisabler::new(&rootfs.as_path(), unit).mask() {
+            log::warn!("Failed to mask {}. Error: {:?}", unit, err);
+        }
+    }
+    if let Err(err) = auto_update::apply_auto_update_config(rootfs, &distro_config.auto_update) {
+        log::warn!("Failed to set up the automatic updates. Error: {:?}", err);
+    }
+    Ok(())
+}
+
+fn get_configured_resource_limits(distro_config: &DistroConfig) -> Result<ResourceLimits> {
+    let resources = &distro_config.resources;
+    Ok(ResourceLimits {
+        memory_bytes: resources
+            .memory
+            .as_deref()
+            .map(parse_memory_size)
+            .transpose()?,
+        cpus: resources.cpus,
+        pids: resources.pids_limit,
+    })
+}
+
 fn make_host_mountpoints_shared() -> Result<()> {
     // Share the mount modification the distro may make with the host mount namespace
     // by MS_SHARED so that WSL's file sharing feature can see them.
@@ -476,7 +1190,17 @@ fn make_host_mountpoints_shared() -> Result<()> {
 }
 
 pub struct Distro {
+    name: Option<String>,
     rootfs: PathBuf,
+    resource_limits: ResourceLimits,
+    target: Option<String>,
+    ephemeral: bool,
+    read_only: bool,
+    bind_mounts: Vec<MountConfig>,
+    nameservers: Option<NameServers>,
+    seccomp_filter: Option<SeccompFilter>,
+    terminal: Option<File>,
+    clean_env: bool,
     container: Container,
 }
 
@@ -484,13 +1208,99 @@ pub struct Distro {
 pub struct DistroRunInfo {
     rootfs: PathBuf,
     init_pid: u32,
+    /// The limits given when the distro started, which override the ones in the distro config.
+    #[serde(default)]
+    resource_limits: ResourceLimits,
+    /// The target given when the distro started.
+    #[serde(default)]
+    target: Option<String>,
+    /// Whether the changes in the rootfs are discarded when the distro stops.
+    #[serde(default)]
+    ephemeral: bool,
+    /// Whether the rootfs was made read-only by the launcher, not by the distro config.
+    #[serde(default)]
+    read_only: bool,
+    /// The bind mounts given when the distro started, not including the ones in the distro config.
+    #[serde(default)]
+    bind_mounts: Vec<MountConfig>,
+    /// The name servers given when the distro started, not the ones in the distro config.
+    #[serde(default)]
+    nameservers: Option<NameServers>,
 }
 
 impl Distro {
+    pub fn get_name(&self) -> Option<&str> {
+        self.name.as_deref()
+    }
+
     pub fn get_rootfs(&self) -> &Path {
         self.rootfs.as_path()
     }
 
+    /// Returns the resource limits given to the launcher, not including the ones in the
+    /// distro config, so that the distro can be started again with the same options.
+    pub fn get_resource_limits(&self) -> &ResourceLimits {
+        &self.resource_limits
+    }
+
+    /// Returns the target given to the launcher, if the distro didn't boot into the default one.
+    pub fn get_target(&self) -> Option<&str> {
+        self.target.as_deref()
+    }
+
+    pub fn is_ephemeral(&self) -> bool {
+        self.ephemeral
+    }
+
+    /// Returns whether the launcher made the rootfs read-only, regardless of the distro config.
+    pub fn is_read_only_by_launcher(&self) -> bool {
+        self.read_only
+    }
+
+    /// Returns the bind mounts given to the launcher, not including the ones in the distro config.
+    pub fn get_bind_mounts(&self) -> &[MountConfig] {
+        &self.bind_mounts
+    }
+
+    /// Returns the name servers given to the launcher, not the ones in the distro config.
+    pub fn get_nameservers(&self) -> Option<&NameServers> {
+        self.nameservers.as_ref()
+    }
+
+    /// Returns the name servers which replace the ones of WSL in the distro, if any.
+    pub fn get_custom_nameservers(&self, distro_config: &DistroConfig) -> Option<NameServers> {
+        self.nameservers
+            .clone()
+            .or_else(|| NameServers::from_config(&distro_config.network))
+    }
+
+    /// Removes the capabilities from the bounding set of the commands run by `exec_command` from
+    /// now on.
+    pub fn with_dropped_exec_capabilities(&mut self, capabilities: Vec<u32>) -> &mut Self {
+        self.container.with_dropped_exec_capabilities(capabilities);
+        self
+    }
+
+    /// Applies the seccomp filter to the commands run by `exec_command` from now on.
+    pub fn with_seccomp_filter(&mut self, filter: SeccompFilter) -> &mut Self {
+        self.seccomp_filter = Some(filter);
+        self
+    }
+
+    /// Runs the commands by `exec_command` from now on with the terminal as their stdio and
+    /// controlling terminal, such as the slave of a pty.
+    pub fn with_terminal(&mut self, terminal: File) -> &mut Self {
+        self.terminal = Some(terminal);
+        self
+    }
+
+    /// Runs the commands by `exec_command` from now on without adding the variables of WSLg, for
+    /// the commands which start from a clean environment.
+    pub fn with_clean_env(&mut self) -> &mut Self {
+        self.clean_env = true;
+        self
+    }
+
     pub fn exec_command<I, S, T1, T2, P>(
         &self,
         command: S,
@@ -515,14 +1325,393 @@ impl Distro {
         if let Some(arg0) = arg0 {
             command.arg0(arg0.as_ref());
         }
+        // Before the seccomp filter, which may not allow setsid.
+        if let Some(ref terminal) = self.terminal {
+            pty::attach_terminal(&mut command, terminal)?;
+        }
+        if let Some(filter) = self.seccomp_filter.clone() {
+            unsafe {
+                command.pre_exec(move || filter.apply());
+            }
+        }
+        let has_wslg = !self.clean_env
+            && self
+                .get_path_in_running_distro(Path::new(WSLG_X11_SOCKET_DIR_PATH))
+                .is_dir();
+        if has_wslg {
+            for (key, value) in collect_wslg_env_vars() {
+                // The ones the caller has, such as by --env, are kept. A name of the Wayland
+                // socket is made its full path though, since XDG_RUNTIME_DIR is changed below.
+                let keeps = match std::env::var_os(&key) {
+                    Some(current) => key != "WAYLAND_DISPLAY" || current.as_bytes().contains(&b'/'),
+                    None => false,
+                };
+                if !keeps {
+                    command.env(key, value);
+                }
+            }
+        }
+        let session_runtime_dir =
+            cred.and_then(|cred| self.get_user_runtime_dir(cred.uid.as_raw()));
+        if let Some(runtime_dir) = session_runtime_dir {
+            // Attach the command to the session of `systemd --user`, such as for the sockets of
+            // gpg-agent and pipewire, in place of XDG_RUNTIME_DIR of WSL.
+            let bus_path = runtime_dir.join("bus");
+            if self.get_path_in_running_distro(&bus_path).exists() {
+                let mut bus_address = OsString::from("unix:path=");
+                bus_address.push(&bus_path);
+                command.env("DBUS_SESSION_BUS_ADDRESS", bus_address);
+            }
+            command.env("XDG_RUNTIME_DIR", runtime_dir);
+        } else if has_wslg {
+            // The one of WSL, which may be /run/user of its own systemd, isn't in the distro.
+            command.env("XDG_RUNTIME_DIR", WSLG_RUNTIME_DIR_PATH);
+        }
         self.container
             .exec_command(command, cred)
             .with_context(|| "Failed to exec command in the container")
     }
 
-    pub fn stop(self, sigkill: bool) -> Result<()> {
-        self.container.stop(sigkill)
+    /// Runs a command in the distro and returns its exit code and stdout.
+    /// This is meant for short-lived commands such as querying the state of systemd.
+    pub fn exec_command_output<I, S, T>(&self, command: S, args: I) -> Result<(u32, String)>
+    where
+        I: IntoIterator<Item = T>,
+        S: AsRef<OsStr>,
+        T: AsRef<OsStr>,
+    {
+        log::debug!("Distro::exec_command_output.");
+        let (stdout_reader, stdout_writer) = nix::unistd::pipe2(nix::fcntl::OFlag::O_CLOEXEC)
+            .with_context(|| "Failed to make a pipe.")?;
+        let mut stdout_reader = unsafe { File::from_raw_fd(stdout_reader) };
+        let stdout_writer = unsafe { File::from_raw_fd(stdout_writer) };
+        let mut command = Command::new(command.as_ref());
+        command
+            .args(args)
+            .stdin(Stdio::null())
+            .stdout(Stdio::from(stdout_writer));
+        // The writer end in this process is closed when the command is dropped after spawn.
+        let mut waiter = self
+            .container
+            .exec_command(command, None)
+            .with_context(|| "Failed to exec command in the container")?;
+        let mut stdout = String::new();
+        stdout_reader
+            .read_to_string(&mut stdout)
+            .with_context(|| "Failed to read the output of the command.")?;
+        Ok((waiter.wait(), stdout))
+    }
+
+    /// Waits until the init finishes booting, and returns false if it doesn't within the timeout.
+    pub fn wait_until_ready(&self, timeout: Duration) -> Result<bool> {
+        let init_system = self.get_init_system()?;
+        let (command, args) = init_system.readiness_command();
+        let start = Instant::now();
+        loop {
+            let (exit_code, output) = self.exec_command_output(command, args)?;
+            if init_system.is_ready(exit_code, &output) {
+                return Ok(true);
+            }
+            if start.elapsed() >= timeout {
+                return Ok(false);
+            }
+            std::thread::sleep(Duration::from_secs(1));
+        }
+    }
+
+    /// Returns /run/user/<uid> of the user if the user session of systemd has made it.
+    pub fn get_user_runtime_dir(&self, uid: u32) -> Option<PathBuf> {
+        if uid == 0 {
+            return None;
+        }
+        let runtime_dir = PathBuf::from(format!("/run/user/{}", uid));
+        if self.get_path_in_running_distro(&runtime_dir).is_dir() {
+            Some(runtime_dir)
+        } else {
+            None
+        }
+    }
+
+    /// Returns the path of a file in the distro seen from outside through the root of the init,
+    /// which includes the mounts made in the distro such as /run.
+    pub fn get_path_in_running_distro(&self, path: &Path) -> PathBuf {
+        Path::new(&format!("/proc/{}/root", self.container.init_pid)).join(
+            path.strip_prefix("/")
+                .expect("[BUG] the path in the distro should be absolute."),
+        )
+    }
+
+    pub fn get_init_system(&self) -> Result<InitSystem> {
+        Ok(InitSystem::detect(&HostPath::new(&self.rootfs)?))
+    }
+
+    pub fn get_init_pid(&self) -> u32 {
+        self.container.init_pid
+    }
+
+    /// Powers off the init cleanly, and kills the processes if it doesn't finish within the timeout.
+    /// The timeout defaults to `systemd.stop_timeout_sec` of the distro config.
+    pub fn stop(self, sigkill: bool, timeout: Option<Duration>) -> Result<()> {
+        if sigkill {
+            let result = self.container.stop(true);
+            unregister_from_machined(self.name.as_deref());
+            return result;
+        }
+        self.run_hooks(HookPoint::PreStop);
+        let timeout = match timeout {
+            Some(timeout) => timeout,
+            None => get_distro_config(&HostPath::new(&self.rootfs)?)
+                .with_context(|| "Failed to read the config of the distro.")?
+                .systemd
+                .stop_timeout_sec
+                .map_or(DEFAULT_STOP_TIMEOUT, Duration::from_secs),
+        };
+
+        log::info!("Powering off the distro.");
+        let (command, args) = self
+            .get_init_system()?
+            .poweroff_command(&HostPath::new(&self.rootfs)?);
+        match self.exec_command_output(command, args) {
+            Ok((0, _)) => {}
+            // The init may not be the detected one. Then ask it to stop by a signal as before.
+            Ok((code, _)) => {
+                log::debug!("{} failed with {}. Sending SIGINT.", command, code);
+                self.container.stop(false)?;
+            }
+            Err(e) => {
+                log::debug!("Failed to run {}. Sending SIGINT. {:?}", command, e);
+                self.container.stop(false)?;
+            }
+        }
+        let name = self.name;
+        let mut container = self.container;
+        let result = if container.wait_for_exit(timeout) {
+            Ok(())
+        } else {
+            log::warn!(
+                "The distro didn't stop in {} seconds. Killing it.",
+                timeout.as_secs()
+            );
+            container.stop(true)
+        };
+        unregister_from_machined(name.as_deref());
+        result
+    }
+
+    /// Rebuilds /etc/ld.so.cache of the distro, so that it has the GPU drivers mounted on
+    /// /usr/lib/wsl/lib, which were not there when the cache was last built.
+    pub fn update_ld_cache(&self) -> Result<()> {
+        let rootfs = HostPath::new(&self.rootfs)?;
+        if !ContainerPath::new(WSL_GPU_LD_CONF_PATH)?
+            .to_host_path(&rootfs)
+            .exists()
+        {
+            return Ok(());
+        }
+        let (exit_code, _) = self.exec_command_output("/sbin/ldconfig", &[] as &[&str])?;
+        if exit_code != 0 {
+            bail!("ldconfig exited with {}.", exit_code);
+        }
+        Ok(())
+    }
+
+    /// Runs the hooks inside the distro. A failing hook doesn't stop the others.
+    fn run_hooks(&self, point: HookPoint) {
+        let hooks = match HostPath::new(&self.rootfs).and_then(|rootfs| list_hooks(&rootfs, point))
+        {
+            Ok(hooks) => hooks,
+            Err(e) => {
+                log::warn!("Failed to list the {} hooks. {:?}", point.name(), e);
+                return;
+            }
+        };
+        for hook in hooks {
+            log::debug!("Running the {} hook {:?}.", point.name(), &hook);
+            let result = self
+                .exec_command(
+                    hook.as_path(),
+                    &[point.name()],
+                    Some("/"),
+                    None::<&str>,
+                    None,
+                )
+                .map(|mut waiter| waiter.wait());
+            match result {
+                Ok(0) => {}
+                Ok(exit_code) => log::warn!("The hook {:?} exited with {}.", &hook, exit_code),
+                Err(e) => log::warn!("Failed to run the hook {:?}. {:?}", &hook, e),
+            }
+        }
+    }
+}
+
+/// Comments out the lines of /etc/fstab that would make systemd fail to boot or mount on WSL,
+/// and tells the user which lines have been changed.
+fn report_fstab_fixes(rootfs: &HostPath) {
+    match fix_fstab(rootfs) {
+        Ok(fixes) => {
+            for fix in fixes {
+                log::warn!(
+                    "Disabled line {} of /etc/fstab, since {}: '{}'",
+                    fix.line_number,
+                    fix.problem,
+                    fix.line
+                );
+            }
+        }
+        Err(e) => log::warn!("Failed to check /etc/fstab. {:?}", e),
+    }
+}
+
+/// Removes the machine of the stopped distro from systemd-machined, which registered it on the
+/// start.
+fn unregister_from_machined(name: Option<&str>) {
+    if userns::is_rootless_mode() || !machined::is_available() {
+        return;
+    }
+    // machined may have dropped the machine by itself when the init exited.
+    if let Err(e) = machined::unregister_machine(&machined::get_machine_name(name)) {
+        log::debug!("{:?}", e);
+    }
+}
+
+/// Runs the pre-start hooks outside the container. They get the rootfs and the name of the distro
+/// by the environment variables, since they cannot see the distro itself yet.
+fn run_pre_start_hooks(rootfs: &HostPath, name: Option<&str>) {
+    let hooks = match list_hooks(rootfs, HookPoint::PreStart) {
+        Ok(hooks) => hooks,
+        Err(e) => {
+            log::warn!("Failed to list the pre-start hooks. {:?}", e);
+            return;
+        }
+    };
+    for hook in hooks {
+        let host_path = hook.to_host_path(rootfs);
+        log::debug!("Running the pre-start hook {:?}.", &host_path);
+        let mut command = Command::new(host_path.as_path());
+        command
+            .arg(HookPoint::PreStart.name())
+            .env("DISTROD_ROOTFS", rootfs.as_path())
+            .stdin(Stdio::null());
+        if let Some(name) = name {
+            command.env("DISTROD_DISTRO_NAME", name);
+        }
+        match command.status() {
+            Ok(status) if status.success() => {}
+            Ok(status) => log::warn!("The hook {:?} exited with {}.", &host_path, status),
+            Err(e) => log::warn!("Failed to run the hook {:?}. {:?}", &host_path, e),
+        }
+    }
+}
+
+/// Returns the systemd target the distro boots into, which is `systemd.default_target` of the
+/// distro config, or multi-user.target if it's not set.
+pub fn get_default_target(rootfs: &HostPath) -> Result<String> {
+    let configured_target = get_distro_config(rootfs)
+        .with_context(|| "Failed to read the config of the distro.")?
+        .systemd
+        .default_target;
+    Ok(configured_target.unwrap_or_else(|| DEFAULT_SYSTEMD_TARGET.to_owned()))
+}
+
+/// Reads /etc/distrod/distrod.toml of the distro. The default config is returned if it doesn't exist.
+pub fn get_distro_config(rootfs: &HostPath) -> Result<DistroConfig> {
+    let config_path = ContainerPath::new(DISTRO_CONFIG_PATH)?.to_host_path(rootfs);
+    if !config_path.exists() {
+        return Ok(DistroConfig::default());
+    }
+    // distrod-exec reads this with the setuid bit set, and the config can mount any directories.
+    // Whoever can write its directory can replace it as well.
+    let config_dir = config_path
+        .parent()
+        .expect("[BUG] the config path has the parent.");
+    for path in &[config_path.as_path(), config_dir] {
+        let metadata = fs::metadata(path)
+            .with_context(|| format!("Failed to get the metadata of {:?}.", path))?;
+        if !is_owned_by_trusted_user(&metadata) || is_writable_by_others(&metadata) {
+            return Err(DistroError::UnsafeFile {
+                path: path.to_path_buf(),
+            }
+            .into());
+        }
+    }
+    let cont = fs::read_to_string(config_path.as_path())
+        .with_context(|| format!("Failed to read {:?}.", &config_path))?;
+    DistroConfig::from_toml_str(&cont)
+        .with_context(|| format!("Invalid config file. {:?}", &config_path))
+}
+
+/// Returns the filter of the seccomp profile in [seccomp] of the distro config, if it's set.
+pub fn get_configured_seccomp_filter(rootfs: &HostPath) -> Result<Option<SeccompFilter>> {
+    let profile = match get_distro_config(rootfs)?.seccomp.profile {
+        Some(profile) => profile,
+        None => return Ok(None),
+    };
+    let profile_path = ContainerPath::new(&profile)?.to_host_path(rootfs);
+    // The profile restricts the users in the distro, who must not be able to replace it.
+    let metadata = fs::symlink_metadata(profile_path.as_path())
+        .with_context(|| format!("Failed to get the metadata of {:?}.", &profile_path))?;
+    if metadata.file_type().is_symlink() || !is_owned_by_trusted_user(&metadata) {
+        return Err(DistroError::UnsafeFile {
+            path: profile_path.to_path_buf(),
+        }
+        .into());
     }
+    let filter = SeccompProfile::load(profile_path.as_path())?
+        .compile()
+        .with_context(|| format!("Failed to compile the seccomp profile {:?}.", &profile))?;
+    Ok(Some(filter))
+}
+
+/// Returns the capabilities in `capabilities.exec_drop` of the distro config.
+pub fn get_configured_exec_capability_drops(rootfs: &HostPath) -> Result<Vec<u32>> {
+    parse_capabilities(&get_distro_config(rootfs)?.capabilities.exec_drop)
+}
+
+fn is_owned_by_trusted_user(metadata: &fs::Metadata) -> bool {
+    let is_owned_by_rootless_user =
+        userns::get_rootless_owner().map_or(false, |owner| owner.uid == metadata.st_uid());
+    metadata.st_uid() == 0 || is_owned_by_rootless_user
+}
+
+/// Whether the group or the others can write the file.
+fn is_writable_by_others(metadata: &fs::Metadata) -> bool {
+    metadata.st_mode() & 0o022 != 0
+}
+
+pub fn set_distro_config(rootfs: &HostPath, config: &DistroConfig) -> Result<()> {
+    let config_path = ContainerPath::new(DISTRO_CONFIG_PATH)?.to_host_path(rootfs);
+    if let Some(dir) = config_path.parent() {
+        fs::create_dir_all(dir).with_context(|| format!("Failed to create {:?}.", dir))?;
+    }
+    fs::write(config_path.as_path(), config.to_toml_string()?)
+        .with_context(|| format!("Failed to write {:?}.", &config_path))?;
+    Ok(())
+}
+
+/// Returns the rootfs of the distro with the given name, or of the default distro if `name` is None.
+/// The rootfs of the running distro takes precedence, since it may be started with another rootfs.
+pub fn get_distro_rootfs(name: Option<&str>) -> Result<HostPath> {
+    let running_distro = DistroLauncher::get_running_distro_by_name(name)
+        .with_context(|| "Failed to get the running distro.")?;
+    let rootfs: PathBuf = match (running_distro, name) {
+        (Some(distro), _) => distro.get_rootfs().to_owned(),
+        (None, Some(name)) => DistroInstance::get(name)?.rootfs,
+        (None, None) => DistrodConfig::get()
+            .with_context(|| "Failed to get the Distrod config.")?
+            .distrod
+            .default_distro_image
+            .clone(),
+    };
+    HostPath::new(rootfs)
+}
+
+/// Saves the target as `systemd.default_target` of the distro config.
+pub fn set_default_target(rootfs: &HostPath, target: &str) -> Result<()> {
+    validate_target_name(target)?;
+    let mut config = get_distro_config(rootfs)?;
+    config.systemd.default_target = Some(target.to_owned());
+    set_distro_config(rootfs, &config)
 }
 
 pub fn is_inside_running_distro() -> bool {
@@ -608,8 +1797,16 @@ fn disable_incompatible_systemd_network_configuration(
 
 fn remove_systemd_resolv_conf(rootfs: &HostPath) -> Result<()> {
     let resolv_conf_path = ContainerPath::new("/etc/resolv.conf")?.to_host_path(rootfs);
-    let metadata = fs::symlink_metadata(&resolv_conf_path)
-        .with_context(|| format!("Failed to get the symlink_metadata {:?}", &resolv_conf_path))?;
+    let metadata = match fs::symlink_metadata(&resolv_conf_path) {
+        Ok(metadata) => metadata,
+        // Images without systemd, such as Alpine, may not have the file at all.
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(()),
+        Err(e) => {
+            return Err(e).with_context(|| {
+                format!("Failed to get the symlink_metadata {:?}", &resolv_conf_path)
+            })
+        }
+    };
     if !metadata.file_type().is_symlink() {
         return Ok(());
     }
@@ -662,18 +1859,59 @@ fn update_etc_hosts(rootfs: &HostPath, hostname: &str) -> Result<()> {
     Ok(())
 }
 
+/// Writes the hostname in /etc/hostname, which systemd sets on boot, and points 127.0.1.1 in
+/// /etc/hosts to it, so that the distro can resolve its own name.
+pub fn set_hostname(rootfs: &HostPath, hostname: &str) -> Result<()> {
+    update_etc_hostname(rootfs, hostname).with_context(|| "Failed to update /etc/hostname.")?;
+    let hosts_path = ContainerPath::new("/etc/hosts")?.to_host_path(rootfs);
+    let current_hosts = if hosts_path.exists() {
+        fs::read_to_string(hosts_path.as_path())
+            .with_context(|| format!("Failed to read hosts file '{:?}'.", &hosts_path))?
+    } else {
+        String::new()
+    };
+    let new_hosts = set_hostname_in_hosts(&current_hosts, hostname);
+    if new_hosts != current_hosts {
+        fs::write(hosts_path.as_path(), new_hosts.as_bytes())
+            .with_context(|| format!("Failed to write hostname to '{:?}'.", &hosts_path))?;
+    }
+    Ok(())
+}
+
+fn set_hostname_in_hosts(hosts: &str, hostname: &str) -> String {
+    let hostname_line = format!("127.0.1.1\t{}", hostname);
+    let mut replaced = false;
+    let mut lines: Vec<String> = hosts
+        .lines()
+        .map(|line| {
+            if line.split_whitespace().next() == Some("127.0.1.1") {
+                replaced = true;
+                hostname_line.clone()
+            } else {
+                line.to_owned()
+            }
+        })
+        .collect();
+    if !replaced {
+        lines.push(hostname_line);
+    }
+    let mut new_hosts = lines.join("\n");
+    new_hosts.push('\n');
+    new_hosts
+}
+
 fn disable_incompatible_systemd_services(rootfs: &HostPath) {
-    let to_be_disabled = [
-        "dhcpcd.service",
-        "NetworkManager.service",
-        "multipathd.service",
-        "systemd-networkd.service",
-        "systemd-resolved.service",
-        "networking.service",
-        "fwupd-refresh.service",
-        "fwupd-refresh.timer",
-    ];
-    for unit in &to_be_disabled {
+    let systemd_config = match get_distro_config(rootfs) {
+        Ok(distro_config) => distro_config.systemd,
+        Err(err) => {
+            log::warn!(
+                "Failed to read the config of the distro. Using the default units. {:?}",
+                err
+            );
+            SystemdConfig::default()
+        }
+    };
+    for unit in &systemd_config.disabled_units {
         let disabler = SystemdUnitDisabler::new(&rootfs.as_path(), unit);
         if matches!(disabler.is_masked(), Ok(true)) {
             continue;
@@ -682,14 +1920,7 @@ fn disable_incompatible_systemd_services(rootfs: &HostPath) {
             log::warn!("Faled to disable {}. Error: {:?}", unit, err);
         }
     }
-    let to_be_masked = [
-        "systemd-remount-fs.service",
-        "systemd-modules-load.service",
-        "getty@tty1.service",
-        "serial-getty@ttyS0.service",
-        "console-getty.service",
-    ];
-    for unit in &to_be_masked {
+    for unit in &systemd_config.masked_units {
         if let Err(err) = SystemdUnitDisabler::new(&rootfs.as_path(), unit).mask() {
             log::warn!("Faled to mask {}. Error: {:?}", unit, err);
         }
@@ -749,16 +1980,16 @@ fn create_per_user_envs_init_loader_script(rootfs: &HostPath) -> Result<()> {
             anyhow!("Failed to get the path to the per-user WSL env init script for root.")
         })?,
     );
-    let profile_dot_d_path =
-        ContainerPath::new("/etc/profile.d/distrod-user-wsl-envs.sh")?.to_host_path(rootfs);
-    let mut profile_dot_d = BufWriter::new(
-        File::create(&profile_dot_d_path)
-            .with_context(|| format!("Failed to create {:?}", &profile_dot_d_path))?,
-    );
-    profile_dot_d
-        .write_all(load_script.render().as_bytes())
-        .with_context(|| format!("Failed to write to {:?}", rootfs))?;
-    Ok(())
+    let profile_dot_d_path = ContainerPath::new(PER_USER_ENVS_PROFILE_PATH)?.to_host_path(rootfs);
+    // The user's changes to the script are kept, since this runs on every start, where nobody
+    // can be asked.
+    ManagedFileUpdater::for_rootfs(rootfs, ConflictPolicy::Ours)?
+        .update_content(
+            Path::new(PER_USER_ENVS_PROFILE_PATH.trim_start_matches('/')),
+            load_script.render().as_bytes(),
+            profile_dot_d_path.as_path(),
+        )
+        .with_context(|| format!("Failed to update {:?}.", &profile_dot_d_path))
 }
 
 fn get_per_user_envs_init_script_shellexp() -> Result<String> {
@@ -953,26 +2184,22 @@ where
     Ok(())
 }
 
-fn export_distro_run_info(rootfs: &Path, init_pid: u32) -> Result<()> {
-    if let Ok(Some(_)) = get_distro_run_info_file(false, false) {
-        fs::remove_file(&get_distro_run_info_path()?)
+fn export_distro_run_info(name: Option<&str>, run_info: &DistroRunInfo) -> Result<()> {
+    if let Ok(Some(_)) = get_distro_run_info_file(name, false, false) {
+        fs::remove_file(&get_distro_run_info_path(name)?)
             .with_context(|| "Failed to remove the existing run info file.")?;
     }
     let mut file = BufWriter::new(
-        get_distro_run_info_file(true, true)
+        get_distro_run_info_file(name, true, true)
             .with_context(|| "Failed to create a run info file.")?
             .expect("[BUG] get_distro_run_info_file shuold return Some when create:true"),
     );
-    let run_info = DistroRunInfo {
-        rootfs: rootfs.to_owned(),
-        init_pid,
-    };
-    file.write_all(&serde_json::to_vec(&run_info)?)
+    file.write_all(&serde_json::to_vec(run_info)?)
         .with_context(|| "Failed to write to a distro run info file.")?;
     Ok(())
 }
 
-fn get_distro_run_info_file(create: bool, write: bool) -> Result<Option<File>> {
+fn get_distro_run_info_file(name: Option<&str>, create: bool, write: bool) -> Result<Option<File>> {
     let mut json = fs::OpenOptions::new();
     json.read(true);
     if create {
@@ -981,7 +2208,8 @@ fn get_distro_run_info_file(create: bool, write: bool) -> Result<Option<File>> {
     if write {
         json.write(true);
     }
-    let json = json.open(get_distro_run_info_path()?);
+    let path = get_distro_run_info_path(name)?;
+    let json = json.open(path.as_path());
     if let Err(ref error) = json {
         if error.raw_os_error() == Some(nix::errno::Errno::ENOENT as i32) {
             return Ok(None);
@@ -989,22 +2217,59 @@ fn get_distro_run_info_file(create: bool, write: bool) -> Result<Option<File>> {
     }
     let json = json.with_context(|| "Failed to open the run info file of the distro.")?;
     let metadata = json.metadata()?;
-    if metadata.st_uid() != 0 || metadata.st_gid() != 0 {
-        bail!(
-            "The run info file of the distrod is unsafe, which is owned by a non-root user/group."
-        );
+    if !userns::is_trusted_owner(metadata.st_uid(), metadata.st_gid()) {
+        return Err(DistroError::UnsafeFile {
+            path: path.to_path_buf(),
+        }
+        .into());
     }
     Ok(Some(json))
 }
 
-fn get_distro_run_info_path() -> Result<HostPath> {
-    get_distrod_runtime_files_dir_path().map(|mut path| {
-        path.push("distrod_run_info.json");
-        path
-    })
+fn get_distro_run_info_path(name: Option<&str>) -> Result<HostPath> {
+    let mut path = get_distrod_runtime_files_dir_path()?;
+    match name {
+        None => path.push("distrod_run_info.json"),
+        Some(name) => {
+            path.push("instances");
+            if !path.exists() {
+                fs::create_dir(path.as_path())
+                    .with_context(|| format!("Failed to create {:?} directory.", &path))?;
+            }
+            path.push(format!("{}.json", name));
+        }
+    }
+    Ok(path)
+}
+
+/// The path to the socket where `distrod daemon` listens.
+pub fn get_daemon_socket_path() -> Result<HostPath> {
+    let mut path = get_distrod_runtime_files_dir_path()?;
+    path.push("daemon.sock");
+    Ok(path)
+}
+
+/// The path to the socket where the exec broker of the distro listens.
+pub fn get_exec_broker_socket_path(name: Option<&str>) -> Result<HostPath> {
+    let mut path = get_distrod_runtime_files_dir_path()?;
+    match name {
+        None => path.push("exec_broker.sock"),
+        Some(name) => {
+            path.push("instances");
+            if !path.exists() {
+                fs::create_dir(path.as_path())
+                    .with_context(|| format!("Failed to create {:?} directory.", &path))?;
+            }
+            path.push(format!("{}.sock", name));
+        }
+    }
+    Ok(path)
 }
 
 fn get_distrod_runtime_files_dir_path() -> Result<HostPath> {
+    if let Some(owner) = userns::get_rootless_owner() {
+        return HostPath::new(userns::get_rootless_runtime_dir(&owner)?);
+    }
     let path = "/run/distrod";
     if !Path::new(&path).exists() {
         fs::create_dir(&path)
@@ -1013,6 +2278,27 @@ fn get_distrod_runtime_files_dir_path() -> Result<HostPath> {
     HostPath::new(path)
 }
 
+#[cfg(test)]
+mod test_hostname {
+    use super::*;
+
+    #[test]
+    fn test_set_hostname_in_hosts() {
+        assert_eq!(
+            "127.0.0.1\tlocalhost\n127.0.1.1\tdev-box\n::1\tip6-localhost\n",
+            set_hostname_in_hosts(
+                "127.0.0.1\tlocalhost\n127.0.1.1 DESKTOP-1234.localdomain DESKTOP-1234\n::1\tip6-localhost\n",
+                "dev-box"
+            )
+        );
+        assert_eq!(
+            "127.0.0.1\tlocalhost\n127.0.1.1\tdev-box\n",
+            set_hostname_in_hosts("127.0.0.1\tlocalhost", "dev-box")
+        );
+        assert_eq!("127.0.1.1\tdev-box\n", set_hostname_in_hosts("", "dev-box"));
+    }
+}
+
 #[cfg(test)]
 mod test_sanity_check {
     use super::*;
@@ -1120,3 +2406,67 @@ mod test_update_etc_hosts {
         );
     }
 }
+
+#[cfg(test)]
+mod test_default_target {
+    use super::*;
+    use tempfile::TempDir;
+
+    #[test]
+    fn test_set_and_get_default_target() {
+        let tmpdir = TempDir::new().unwrap();
+        let rootfs = HostPath::new(tmpdir.path()).expect("Failed to create HostPath.");
+        assert_eq!(DEFAULT_SYSTEMD_TARGET, get_default_target(&rootfs).unwrap());
+
+        set_default_target(&rootfs, "distrod-session.target").unwrap();
+        // Read the file directly, since get_distro_config rejects the one not owned by root.
+        let config_path = tmpdir.path().join("etc/distrod/distrod.toml");
+        let config =
+            DistroConfig::from_toml_str(&fs::read_to_string(config_path).unwrap()).unwrap();
+        assert_eq!(
+            Some("distrod-session.target"),
+            config.systemd.default_target.as_deref()
+        );
+    }
+
+    #[test]
+    fn test_invalid_target_is_rejected() {
+        let tmpdir = TempDir::new().unwrap();
+        let rootfs = HostPath::new(tmpdir.path()).expect("Failed to create HostPath.");
+        assert!(set_default_target(&rootfs, "sshd.service").is_err());
+        assert!(set_default_target(&rootfs, "multi-user.target --foo").is_err());
+        assert!(set_default_target(&rootfs, "../../multi-user.target").is_err());
+    }
+}
+
+#[cfg(test)]
+mod test_distro_config {
+    use super::*;
+    use std::os::unix::fs::PermissionsExt;
+    use tempfile::TempDir;
+
+    #[test]
+    fn test_writable_config_is_rejected() {
+        // The files the test makes are owned by root only when the test runs as root.
+        if !nix::unistd::geteuid().is_root() {
+            return;
+        }
+        let rootfs = TempDir::new().unwrap();
+        let rootfs_path = HostPath::new(rootfs.path()).unwrap();
+        let config_dir = rootfs.path().join("etc/distrod");
+        fs::create_dir_all(&config_dir).unwrap();
+        fs::set_permissions(&config_dir, fs::Permissions::from_mode(0o755)).unwrap();
+        let config_path = config_dir.join("distrod.toml");
+        fs::write(&config_path, "").unwrap();
+        fs::set_permissions(&config_path, fs::Permissions::from_mode(0o644)).unwrap();
+        assert!(get_distro_config(&rootfs_path).is_ok());
+
+        fs::set_permissions(&config_path, fs::Permissions::from_mode(0o664)).unwrap();
+        assert!(get_distro_config(&rootfs_path).is_err());
+        fs::set_permissions(&config_path, fs::Permissions::from_mode(0o644)).unwrap();
+
+        fs::set_permissions(&config_dir, fs::Permissions::from_mode(0o777)).unwrap();
+        let err = get_distro_config(&rootfs_path).unwrap_err();
+        assert_eq!("E105", crate::error::find_coded_error(&err).unwrap().code());
+    }
+}
@@ -0,0 +1,1003 @@
+use anyhow::{anyhow, bail, Context, Result};
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::net::IpAddr;
+use std::path::PathBuf;
+use toml::Value;
+
+use crate::capability::parse_capabilities;
+
+/// The path of the per-distro config file in the distro.
+pub const DISTRO_CONFIG_PATH: &str = "/etc/distrod/distrod.toml";
+
+/// The configuration of a distro, which is read from /etc/distrod/distrod.toml of the distro
+/// every time it starts. Unlike DistrodConfig, which is shared by all the distros, this lives in
+/// the rootfs of each distro.
+// Keep the fields which can be empty arrays first, since TOML doesn't allow values after tables.
+#[derive(Serialize, Deserialize, Clone, Debug, Default, PartialEq)]
+pub struct DistroConfig {
+    /// The additional bind mounts into the distro.
+    #[serde(default)]
+    pub mounts: Vec<MountConfig>,
+    /// The environment variables set for all the users and the systemd services.
+    #[serde(default)]
+    pub env: BTreeMap<String, String>,
+    #[serde(default)]
+    pub autostart: AutostartConfig,
+    #[serde(default)]
+    pub systemd: SystemdConfig,
+    #[serde(default)]
+    pub network: NetworkConfig,
+    #[serde(default)]
+    pub fstab: FstabConfig,
+    #[serde(default)]
+    pub resources: ResourcesConfig,
+    #[serde(default)]
+    pub user: UserConfig,
+    #[serde(default)]
+    pub rootfs: RootfsConfig,
+    #[serde(default)]
+    pub seccomp: SeccompConfig,
+    #[serde(default)]
+    pub capabilities: CapabilitiesConfig,
+    #[serde(default)]
+    pub gpu: GpuConfig,
+    #[serde(default)]
+    pub drives: DrivesConfig,
+    #[serde(default)]
+    pub nested_containers: NestedContainersConfig,
+    #[serde(default)]
+    pub machined: MachinedConfig,
+    #[serde(default)]
+    pub wslg: WslgConfig,
+    #[serde(default)]
+    pub locale: LocaleConfig,
+    #[serde(default)]
+    pub auto_update: AutoUpdateConfig,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, Default, PartialEq)]
+pub struct AutostartConfig {
+    /// The seconds to wait before starting systemd when the distro is started on Windows boot.
+    pub delay_sec: Option<u32>,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub struct MountConfig {
+    /// The path outside the distro, such as /mnt/c/Users/you/projects.
+    pub source: PathBuf,
+    /// The path in the distro.
+    pub target: PathBuf,
+    #[serde(default)]
+    pub read_only: bool,
+}
+
+impl MountConfig {
+    /// Parses a mount such as "/mnt/c/data:/data:ro", which `distrod start --mount` takes.
+    /// The last field is optional and is either "ro" or "rw".
+    pub fn parse(spec: &str) -> Result<MountConfig> {
+        let fields: Vec<&str> = spec.split(':').collect();
+        let (source, target, read_only) = match fields.as_slice() {
+            [source, target] => (source, target, false),
+            [source, target, "ro"] => (source, target, true),
+            [source, target, "rw"] => (source, target, false),
+            _ => bail!(
+                "Invalid mount '{}'. It should be SOURCE:TARGET, optionally followed by :ro or :rw.",
+                spec
+            ),
+        };
+        let mount = MountConfig {
+            source: PathBuf::from(source),
+            target: PathBuf::from(target),
+            read_only,
+        };
+        mount.validate()?;
+        Ok(mount)
+    }
+
+    pub fn validate(&self) -> Result<()> {
+        // Don't use Path::is_absolute, since this is validated on the Windows side as well.
+        let is_absolute = |path: &PathBuf| path.to_string_lossy().starts_with('/');
+        if !is_absolute(&self.source) || !is_absolute(&self.target) {
+            bail!(
+                "The paths of a mount should be absolute. source: {:?}, target: {:?}",
+                &self.source,
+                &self.target
+            );
+        }
+        Ok(())
+    }
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub struct SystemdConfig {
+    /// The units masked every time the distro starts. The defaults are the ones which can't
+    /// work in WSL, such as the gettys of the consoles.
+    #[serde(default = "default_masked_units")]
+    pub masked_units: Vec<String>,
+    /// The units disabled when the distro is created, which conflict with the network of WSL.
+    /// Unlike the masked ones, they can be enabled again in the distro.
+    #[serde(default = "default_disabled_units")]
+    pub disabled_units: Vec<String>,
+    /// The seconds `distrod stop` waits for systemd to power off before it kills the processes.
+    pub stop_timeout_sec: Option<u64>,
+    /// The target systemd boots into, such as multi-user.target or graphical.target.
+    /// `distrod target set` changes this.
+    pub default_target: Option<String>,
+}
+
+impl Default for SystemdConfig {
+    fn default() -> Self {
+        SystemdConfig {
+            masked_units: default_masked_units(),
+            disabled_units: default_disabled_units(),
+            stop_timeout_sec: None,
+            default_target: None,
+        }
+    }
+}
+
+fn default_masked_units() -> Vec<String> {
+    [
+        "systemd-remount-fs.service",
+        "getty@tty1.service",
+        "serial-getty@ttyS0.service",
+        "console-getty.service",
+    ]
+    .iter()
+    .map(|unit| unit.to_string())
+    .collect()
+}
+
+fn default_disabled_units() -> Vec<String> {
+    [
+        "dhcpcd.service",
+        "NetworkManager.service",
+        "multipathd.service",
+        "systemd-networkd.service",
+        "systemd-resolved.service",
+        "networking.service",
+        "fwupd-refresh.service",
+        "fwupd-refresh.timer",
+    ]
+    .iter()
+    .map(|unit| unit.to_string())
+    .collect()
+}
+
+/// Returns an error if the name can't be a unit in /etc/systemd/system.
+pub fn validate_unit_name(unit: &str) -> Result<()> {
+    if unit.is_empty() || unit.contains('/') {
+        bail!("Invalid unit name: '{}'.", unit);
+    }
+    Ok(())
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub struct NetworkConfig {
+    /// Whether to bind-mount /etc/resolv.conf generated by WSL, or to give its name servers to
+    /// systemd-resolved if the distro uses it. Set it false to manage DNS in the distro by yourself.
+    #[serde(default = "default_share_resolv_conf")]
+    pub share_resolv_conf: bool,
+    /// The hostname of the distro. Defaults to the one of WSL, which is the name of the Windows
+    /// machine.
+    pub hostname: Option<String>,
+    /// The names which distrod-mdns.service answers for as <name>.local. Defaults to the
+    /// hostname of the distro if empty.
+    #[serde(default)]
+    pub mdns_names: Vec<String>,
+    /// The IP addresses of the name servers to use in place of the ones of WSL, such as the
+    /// ones of a corporate network. The ones of WSL are used if empty.
+    #[serde(default)]
+    pub nameservers: Vec<String>,
+    /// The search domains with the custom name servers.
+    #[serde(default)]
+    pub search_domains: Vec<String>,
+    /// Whether to take the DNS servers of Windows while none of the name servers of WSL answers,
+    /// such as when a VPN client has taken the route to them.
+    #[serde(default)]
+    pub repair_vpn_dns: bool,
+}
+
+impl Default for NetworkConfig {
+    fn default() -> Self {
+        NetworkConfig {
+            share_resolv_conf: default_share_resolv_conf(),
+            hostname: None,
+            mdns_names: vec![],
+            nameservers: vec![],
+            search_domains: vec![],
+            repair_vpn_dns: false,
+        }
+    }
+}
+
+fn default_share_resolv_conf() -> bool {
+    true
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub struct FstabConfig {
+    /// Whether to comment out the lines of /etc/fstab which don't work on WSL, such as swap
+    /// entries and disks that don't exist, every time the distro starts.
+    #[serde(default = "default_auto_fix")]
+    pub auto_fix: bool,
+}
+
+impl Default for FstabConfig {
+    fn default() -> Self {
+        FstabConfig {
+            auto_fix: default_auto_fix(),
+        }
+    }
+}
+
+fn default_auto_fix() -> bool {
+    true
+}
+
+/// The limits put on the cgroup of the distro, so that it cannot use up the resources of the WSL VM.
+#[derive(Serialize, Deserialize, Clone, Debug, Default, PartialEq)]
+pub struct ResourcesConfig {
+    /// The memory limit such as "4G". K, M, G, and T are the powers of 1024.
+    pub memory: Option<String>,
+    /// The number of CPUs the distro can use up, such as 1.5.
+    pub cpus: Option<f64>,
+    /// The maximum number of processes and threads.
+    pub pids_limit: Option<u64>,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub struct UserConfig {
+    /// The user `distrod shell` logs in as by default.
+    pub default: Option<String>,
+    /// Whether to enable lingering of the default user, so that `systemd --user` of the user
+    /// runs from the boot while nobody is logged in.
+    #[serde(default = "default_linger")]
+    pub linger: bool,
+}
+
+impl Default for UserConfig {
+    fn default() -> Self {
+        UserConfig {
+            default: None,
+            linger: default_linger(),
+        }
+    }
+}
+
+fn default_linger() -> bool {
+    true
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, Default, PartialEq)]
+pub struct RootfsConfig {
+    /// Whether to mount the rootfs read-only. /var and /home stay writable, and /tmp and /run
+    /// are tmpfs.
+    #[serde(default)]
+    pub read_only: bool,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, Default, PartialEq)]
+pub struct SeccompConfig {
+    /// The path in the distro of a seccomp profile in the Docker format, which is applied to the
+    /// commands run by `distrod exec` and the command aliases.
+    pub profile: Option<PathBuf>,
+}
+
+/// The capabilities removed from the bounding sets, such as "CAP_SYS_ADMIN".
+#[derive(Serialize, Deserialize, Clone, Debug, Default, PartialEq)]
+pub struct CapabilitiesConfig {
+    /// Removed from systemd and all the processes it starts.
+    #[serde(default)]
+    pub init_drop: Vec<String>,
+    /// Removed from the commands run by `distrod exec` and the command aliases.
+    #[serde(default)]
+    pub exec_drop: Vec<String>,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub struct GpuConfig {
+    /// Whether to bring /dev/dxg and the GPU drivers of Windows in /usr/lib/wsl into the distro,
+    /// so that CUDA and DirectML work in it.
+    #[serde(default = "default_passthrough")]
+    pub passthrough: bool,
+}
+
+impl Default for GpuConfig {
+    fn default() -> Self {
+        GpuConfig {
+            passthrough: default_passthrough(),
+        }
+    }
+}
+
+fn default_passthrough() -> bool {
+    true
+}
+
+/// Which Windows drives mounted by WSL, such as /mnt/c, are mounted into the distro.
+#[derive(Serialize, Deserialize, Clone, Debug, Default, PartialEq)]
+pub struct DrivesConfig {
+    /// The drive letters to mount, such as ["C", "D"]. All the drives are mounted if empty.
+    #[serde(default)]
+    pub include: Vec<String>,
+    /// The drive letters not to mount, such as slow network drives.
+    #[serde(default)]
+    pub exclude: Vec<String>,
+    /// The drvfs mount options, such as "metadata,case=dir". If set, the drives are mounted
+    /// afresh with them instead of sharing the mounts of WSL.
+    pub options: Option<String>,
+}
+
+impl DrivesConfig {
+    pub fn is_included(&self, drive_letter: char) -> bool {
+        let matches = |letter: &String| letter.eq_ignore_ascii_case(&drive_letter.to_string());
+        (self.include.is_empty() || self.include.iter().any(matches))
+            && !self.exclude.iter().any(matches)
+    }
+
+    fn validate(&self) -> Result<()> {
+        for letter in self.include.iter().chain(self.exclude.iter()) {
+            let mut chars = letter.chars();
+            let is_letter = chars.next().map_or(false, |c| c.is_ascii_alphabetic());
+            if !is_letter || chars.next().is_some() {
+                bail!("'{}' is not a drive letter.", letter);
+            }
+        }
+        if let Some(ref options) = self.options {
+            if options.is_empty() || options.contains(char::is_whitespace) {
+                bail!("Invalid drvfs mount options: '{}'.", options);
+            }
+        }
+        Ok(())
+    }
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, Default, PartialEq)]
+pub struct NestedContainersConfig {
+    /// Whether to set up the distro for dockerd and podman, which need the shared mount
+    /// propagation, a cgroup subtree of the distro, and some kernel modules.
+    #[serde(default)]
+    pub enabled: bool,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub struct MachinedConfig {
+    /// Whether to register the distro with systemd-machined of WSL while it runs, if WSL runs
+    /// systemd, so that machinectl can manage it.
+    #[serde(default = "default_register")]
+    pub register: bool,
+}
+
+impl Default for MachinedConfig {
+    fn default() -> Self {
+        MachinedConfig {
+            register: default_register(),
+        }
+    }
+}
+
+fn default_register() -> bool {
+    true
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub struct WslgConfig {
+    /// Whether to share the sockets of WSLg with the distro so that GUI apps run in it.
+    #[serde(default = "default_wslg_enabled")]
+    pub enabled: bool,
+}
+
+impl Default for WslgConfig {
+    fn default() -> Self {
+        WslgConfig {
+            enabled: default_wslg_enabled(),
+        }
+    }
+}
+
+fn default_wslg_enabled() -> bool {
+    true
+}
+
+/// Whether to make the distro follow Windows on every start, which WSL does for its own distro.
+#[derive(Serialize, Deserialize, Clone, Debug, Default, PartialEq)]
+pub struct LocaleConfig {
+    /// Set /etc/localtime of the distro to the time zone of Windows.
+    #[serde(default)]
+    pub sync_windows_timezone: bool,
+    /// Set LANG of the distro to the display language of Windows, such as ja_JP.UTF-8.
+    #[serde(default)]
+    pub sync_windows_locale: bool,
+}
+
+/// The packages of the distro updated by distrod-auto-update.timer, which is off by default.
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub struct AutoUpdateConfig {
+    #[serde(default)]
+    pub enabled: bool,
+    /// When to update, in the OnCalendar= format of systemd.timer, such as "weekly" or
+    /// "Sun 03:00". An update missed while the distro is stopped runs on the next start.
+    #[serde(default = "default_auto_update_schedule")]
+    pub schedule: String,
+}
+
+impl Default for AutoUpdateConfig {
+    fn default() -> Self {
+        AutoUpdateConfig {
+            enabled: false,
+            schedule: default_auto_update_schedule(),
+        }
+    }
+}
+
+fn default_auto_update_schedule() -> String {
+    "weekly".to_owned()
+}
+
+/// Parses a size such as "512M" or "4G" into bytes.
+pub fn parse_memory_size(size: &str) -> Result<u64> {
+    let size = size.trim();
+    let (number, unit) = match size.find(|c: char| !c.is_ascii_digit()) {
+        Some(pos) => size.split_at(pos),
+        None => (size, ""),
+    };
+    let shift = match unit.to_ascii_uppercase().trim_end_matches('B') {
+        "" => 0,
+        "K" => 10,
+        "M" => 20,
+        "G" => 30,
+        "T" => 40,
+        _ => bail!("Invalid unit of size: '{}'.", size),
+    };
+    let number = number
+        .parse::<u64>()
+        .with_context(|| format!("Invalid size: '{}'.", size))?;
+    number
+        .checked_mul(1 << shift)
+        .ok_or_else(|| anyhow!("Too large size: '{}'.", size))
+}
+
+impl DistroConfig {
+    pub fn from_toml_str(cont: &str) -> Result<DistroConfig> {
+        let config: DistroConfig =
+            toml::from_str(cont).with_context(|| "Failed to parse the distro config.")?;
+        config.validate()?;
+        Ok(config)
+    }
+
+    pub fn to_toml_string(&self) -> Result<String> {
+        toml::to_string(self).with_context(|| "Failed to serialize the distro config.")
+    }
+
+    /// Returns the value of a key such as "network.share_resolv_conf" or "mounts.0.source".
+    pub fn get_value(&self, key: &str) -> Result<Option<Value>> {
+        let root = Value::try_from(self).with_context(|| "Failed to serialize the config.")?;
+        Ok(lookup(&root, key).cloned())
+    }
+
+    /// Sets the value of a key. The value is converted to the type of the key, so "false" is
+    /// a boolean for "network.share_resolv_conf" but a string for "env.FOO".
+    pub fn set_value(&mut self, key: &str, value: &str) -> Result<()> {
+        let root = Value::try_from(&*self).with_context(|| "Failed to serialize the config.")?;
+        let current = lookup(&root, key);
+        let mut last_error = anyhow!("Unknown key: '{}'.", key);
+        for candidate in candidate_values(current, value, key)? {
+            match set_in_value(root.clone(), key, candidate) {
+                Ok(new_config) => {
+                    *self = new_config;
+                    return Ok(());
+                }
+                Err(e) => last_error = e,
+            }
+        }
+        Err(last_error.context(format!("Failed to set '{}' to '{}'.", key, value)))
+    }
+
+    fn validate(&self) -> Result<()> {
+        for mount in &self.mounts {
+            mount.validate()?;
+        }
+        self.drives.validate()?;
+        parse_capabilities(&self.capabilities.init_drop)?;
+        parse_capabilities(&self.capabilities.exec_drop)?;
+        if let Some(ref profile) = self.seccomp.profile {
+            if !profile.to_string_lossy().starts_with('/') {
+                bail!(
+                    "The path of the seccomp profile should be absolute: {:?}.",
+                    profile
+                );
+            }
+        }
+        for key in self.env.keys() {
+            if key.is_empty() || key.contains('=') || key.contains(char::is_whitespace) {
+                bail!("Invalid environment variable name: '{}'.", key);
+            }
+        }
+        if let Some(ref memory) = self.resources.memory {
+            parse_memory_size(memory)?;
+        }
+        if let Some(cpus) = self.resources.cpus {
+            if !cpus.is_finite() || cpus <= 0.0 {
+                bail!("resources.cpus should be positive: {}.", cpus);
+            }
+        }
+        for unit in self
+            .systemd
+            .masked_units
+            .iter()
+            .chain(&self.systemd.disabled_units)
+        {
+            validate_unit_name(unit)?;
+        }
+        if let Some(ref target) = self.systemd.default_target {
+            validate_target_name(target)?;
+        }
+        if let Some(ref hostname) = self.network.hostname {
+            validate_hostname(hostname)?;
+        }
+        for name in self
+            .network
+            .mdns_names
+            .iter()
+            .chain(&self.network.search_domains)
+        {
+            validate_hostname(name)?;
+        }
+        for nameserver in &self.network.nameservers {
+            validate_nameserver(nameserver)?;
+        }
+        validate_timer_schedule(&self.auto_update.schedule)?;
+        Ok(())
+    }
+}
+
+/// Rejects a schedule which can't be a value of OnCalendar=. The calendar syntax itself is
+/// checked by systemd, which logs an invalid one and doesn't start the timer.
+pub fn validate_timer_schedule(schedule: &str) -> Result<()> {
+    if schedule.trim().is_empty() || schedule.contains(|c: char| c.is_control()) {
+        bail!("Invalid schedule: '{}'.", schedule);
+    }
+    Ok(())
+}
+
+/// Checks the name server is an IP address, since a name server can't be given by a name.
+pub fn validate_nameserver(nameserver: &str) -> Result<()> {
+    nameserver
+        .parse::<IpAddr>()
+        .map(|_| ())
+        .map_err(|_| anyhow!("'{}' is not an IP address of a name server.", nameserver))
+}
+
+/// Checks the name is a valid hostname of dot-separated labels of letters, digits, and hyphens.
+pub fn validate_hostname(hostname: &str) -> Result<()> {
+    let label_pattern = regex::Regex::new(r"^[a-zA-Z0-9]([a-zA-Z0-9\-]{0,61}[a-zA-Z0-9])?$")
+        .expect("the hostname label pattern should be valid");
+    // HOST_NAME_MAX of Linux.
+    if hostname.len() > 64
+        || !hostname
+            .split('.')
+            .all(|label| label_pattern.is_match(label))
+    {
+        bail!("'{}' is not a valid hostname.", hostname);
+    }
+    Ok(())
+}
+
+/// Checks the name is of a systemd target, since it's passed to systemd as a command line option.
+pub fn validate_target_name(target: &str) -> Result<()> {
+    let target_pattern = regex::Regex::new(r"^[a-zA-Z0-9:_.@\-]+\.target$")
+        .expect("the target name pattern should be valid");
+    if !target_pattern.is_match(target) {
+        bail!("'{}' is not a valid name of a systemd target.", target);
+    }
+    Ok(())
+}
+
+/// Checks the name is a user name which useradd of every distro accepts.
+pub fn validate_user_name(name: &str) -> Result<()> {
+    let is_valid_char =
+        |c: char| c.is_ascii_lowercase() || c.is_ascii_digit() || c == '_' || c == '-';
+    if name.is_empty() || name.starts_with('-') || !name.chars().all(is_valid_char) {
+        bail!(
+            "Invalid user name: '{}'. A name can contain only lowercase alphanumerics, '_', and '-'.",
+            name
+        );
+    }
+    Ok(())
+}
+
+fn lookup<'a>(root: &'a Value, key: &str) -> Option<&'a Value> {
+    key.split('.').try_fold(root, |value, name| match value {
+        Value::Table(table) => table.get(name),
+        Value::Array(array) => array.get(name.parse::<usize>().ok()?),
+        _ => None,
+    })
+}
+
+fn lookup_mut<'a>(root: &'a mut Value, key: &str) -> Option<&'a mut Value> {
+    key.split('.').try_fold(root, |value, name| match value {
+        Value::Table(table) => table.get_mut(name),
+        Value::Array(array) => array.get_mut(name.parse::<usize>().ok()?),
+        _ => None,
+    })
+}
+
+fn set_in_value(mut root: Value, key: &str, new_value: Value) -> Result<DistroConfig> {
+    let (parent_key, last_key) = match key.rfind('.') {
+        Some(pos) => (&key[..pos], &key[pos + 1..]),
+        None => ("", key),
+    };
+    let parent = if parent_key.is_empty() {
+        Some(&mut root)
+    } else {
+        lookup_mut(&mut root, parent_key)
+    };
+    match parent {
+        Some(Value::Table(table)) => {
+            table.insert(last_key.to_owned(), new_value);
+        }
+        Some(Value::Array(array)) => {
+            let elem = last_key
+                .parse::<usize>()
+                .ok()
+                .and_then(|index| array.get_mut(index))
+                .ok_or_else(|| anyhow!("Unknown key: '{}'.", key))?;
+            *elem = new_value;
+        }
+        _ => bail!("Unknown key: '{}'.", key),
+    }
+
+    let new_config: DistroConfig = root.try_into().with_context(|| "Invalid type of value.")?;
+    new_config.validate()?;
+    // Unknown keys are silently ignored by the deserialization.
+    if new_config.get_value(key)?.is_none() {
+        bail!("Unknown key: '{}'.", key);
+    }
+    Ok(new_config)
+}
+
+/// Returns the values that the given string can mean for the key, in the order of preference.
+fn candidate_values(current: Option<&Value>, value: &str, key: &str) -> Result<Vec<Value>> {
+    let candidates = match current {
+        Some(Value::String(_)) => vec![Value::String(value.to_owned())],
+        Some(Value::Boolean(_)) => vec![value
+            .parse::<bool>()
+            .map(Value::Boolean)
+            .with_context(|| format!("'{}' should be true or false.", key))?],
+        Some(Value::Integer(_)) => vec![value
+            .parse::<i64>()
+            .map(Value::Integer)
+            .with_context(|| format!("'{}' should be an integer.", key))?],
+        Some(Value::Table(_)) => bail!("'{}' is a section. Set the keys in it instead.", key),
+        Some(Value::Array(_)) => vec![parse_toml_literal(value).unwrap_or_else(|| {
+            // Take "a,b" as ["a", "b"] so that users don't have to quote the elements.
+            Value::Array(
+                value
+                    .split(',')
+                    .map(|elem| Value::String(elem.trim().to_owned()))
+                    .filter(|elem| elem.as_str() != Some(""))
+                    .collect(),
+            )
+        })],
+        // The type of an unset key is unknown, so try the literal first and then a string.
+        _ => parse_toml_literal(value)
+            .into_iter()
+            .chain(std::iter::once(Value::String(value.to_owned())))
+            .collect(),
+    };
+    Ok(candidates)
+}
+
+fn parse_toml_literal(value: &str) -> Option<Value> {
+    if value.contains('\n') {
+        return None;
+    }
+    match format!("value = {}", value).parse::<Value>() {
+        Ok(Value::Table(mut table)) => table.remove("value"),
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod test_distro_config {
+    use super::*;
+
+    #[test]
+    fn test_parse_full_config() {
+        let config = DistroConfig::from_toml_str(
+            r#"
+            [[mounts]]
+            source = "/mnt/c/Users/you/projects"
+            target = "/home/you/projects"
+
+            [[mounts]]
+            source = "/mnt/c/data"
+            target = "/data"
+            read_only = true
+
+            [env]
+            EDITOR = "vim"
+
+            [autostart]
+            delay_sec = 30
+
+            [systemd]
+            masked_units = ["snapd.service"]
+            disabled_units = []
+            default_target = "graphical.target"
+
+            [network]
+            share_resolv_conf = false
+            hostname = "dev-box"
+            mdns_names = ["dev-box", "api"]
+            nameservers = ["10.0.0.53", "fd00::53"]
+            search_domains = ["corp.example.com"]
+            repair_vpn_dns = true
+
+            [fstab]
+            auto_fix = false
+
+            [resources]
+            memory = "4G"
+            cpus = 1.5
+
+            [rootfs]
+            read_only = true
+
+            [seccomp]
+            profile = "/etc/distrod/seccomp.json"
+
+            [capabilities]
+            init_drop = ["CAP_SYS_MODULE"]
+            exec_drop = ["CAP_SYS_ADMIN", "CAP_NET_ADMIN"]
+
+            [gpu]
+            passthrough = false
+
+            [drives]
+            exclude = ["Z"]
+            options = "metadata,case=dir"
+
+            [nested_containers]
+            enabled = true
+
+            [machined]
+            register = false
+
+            [wslg]
+            enabled = false
+
+            [locale]
+            sync_windows_timezone = true
+            "#,
+        )
+        .unwrap();
+        assert_eq!(2, config.mounts.len());
+        assert!(!config.mounts[0].read_only);
+        assert!(config.mounts[1].read_only);
+        assert_eq!(Some(&"vim".to_owned()), config.env.get("EDITOR"));
+        assert_eq!(Some(30), config.autostart.delay_sec);
+        assert_eq!(
+            vec!["snapd.service".to_owned()],
+            config.systemd.masked_units
+        );
+        assert!(config.systemd.disabled_units.is_empty());
+        assert_eq!(
+            Some("graphical.target".to_owned()),
+            config.systemd.default_target
+        );
+        assert!(!config.network.share_resolv_conf);
+        assert_eq!(Some("dev-box".to_owned()), config.network.hostname);
+        assert_eq!(2, config.network.mdns_names.len());
+        assert_eq!(
+            vec!["10.0.0.53".to_owned(), "fd00::53".to_owned()],
+            config.network.nameservers
+        );
+        assert_eq!(
+            vec!["corp.example.com".to_owned()],
+            config.network.search_domains
+        );
+        assert!(config.network.repair_vpn_dns);
+        assert!(!config.fstab.auto_fix);
+        assert_eq!(Some(1.5), config.resources.cpus);
+        assert!(config.rootfs.read_only);
+        assert_eq!(
+            Some(PathBuf::from("/etc/distrod/seccomp.json")),
+            config.seccomp.profile
+        );
+        assert_eq!(2, config.capabilities.exec_drop.len());
+        assert!(!config.gpu.passthrough);
+        assert_eq!(Some("metadata,case=dir".to_owned()), config.drives.options);
+        assert!(config.nested_containers.enabled);
+        assert!(!config.machined.register);
+        assert!(!config.wslg.enabled);
+        assert!(config.locale.sync_windows_timezone);
+        assert!(!config.locale.sync_windows_locale);
+
+        assert_eq!(
+            config,
+            DistroConfig::from_toml_str(&config.to_toml_string().unwrap()).unwrap()
+        );
+    }
+
+    #[test]
+    fn test_validate_hostname() {
+        assert!(validate_hostname("ubuntu").is_ok());
+        assert!(validate_hostname("dev-box.local").is_ok());
+        assert!(validate_hostname("-dev").is_err());
+        assert!(validate_hostname("dev box").is_err());
+        assert!(validate_hostname("dev..box").is_err());
+        assert!(validate_hostname(&"a".repeat(65)).is_err());
+    }
+
+    #[test]
+    fn test_validate_user_name() {
+        assert!(validate_user_name("alice").is_ok());
+        assert!(validate_user_name("dev_user-2").is_ok());
+        assert!(validate_user_name("Alice").is_err());
+        assert!(validate_user_name("-alice").is_err());
+        assert!(validate_user_name("").is_err());
+    }
+
+    #[test]
+    fn test_parse_memory_size() {
+        assert_eq!(1024, parse_memory_size("1024").unwrap());
+        assert_eq!(512 << 20, parse_memory_size("512M").unwrap());
+        assert_eq!(4 << 30, parse_memory_size("4GB").unwrap());
+        assert_eq!(2 << 30, parse_memory_size("2g").unwrap());
+        assert!(parse_memory_size("4X").is_err());
+        assert!(parse_memory_size("G").is_err());
+    }
+
+    #[test]
+    fn test_parse_mount() {
+        assert_eq!(
+            MountConfig {
+                source: PathBuf::from("/mnt/c/data"),
+                target: PathBuf::from("/data"),
+                read_only: false,
+            },
+            MountConfig::parse("/mnt/c/data:/data").unwrap()
+        );
+        let read_only = MountConfig::parse("/mnt/c/data:/data:ro").unwrap();
+        assert!(read_only.read_only);
+        let read_write = MountConfig::parse("/mnt/c/data:/data:rw").unwrap();
+        assert!(!read_write.read_only);
+        assert!(MountConfig::parse("/mnt/c/data").is_err());
+        assert!(MountConfig::parse("/mnt/c/data:/data:rx").is_err());
+        assert!(MountConfig::parse("data:/data").is_err());
+    }
+
+    #[test]
+    fn test_drives_is_included() {
+        let all = DrivesConfig::default();
+        assert!(all.is_included('c'));
+        let excluded = DrivesConfig {
+            exclude: vec!["z".to_owned()],
+            ..DrivesConfig::default()
+        };
+        assert!(excluded.is_included('C'));
+        assert!(!excluded.is_included('Z'));
+        let included = DrivesConfig {
+            include: vec!["C".to_owned(), "D".to_owned()],
+            exclude: vec!["D".to_owned()],
+            options: None,
+        };
+        assert!(included.is_included('c'));
+        assert!(!included.is_included('d'));
+        assert!(!included.is_included('e'));
+
+        let mut config = DistroConfig::default();
+        assert!(config.set_value("drives.exclude", "CD").is_err());
+        config.set_value("drives.exclude", "Z").unwrap();
+        assert_eq!(vec!["Z".to_owned()], config.drives.exclude);
+    }
+
+    #[test]
+    fn test_empty_config_is_default() {
+        let config = DistroConfig::from_toml_str("").unwrap();
+        assert_eq!(DistroConfig::default(), config);
+        assert!(config.network.share_resolv_conf);
+        assert!(config
+            .systemd
+            .masked_units
+            .contains(&"console-getty.service".to_owned()));
+        assert!(config
+            .systemd
+            .disabled_units
+            .contains(&"NetworkManager.service".to_owned()));
+        assert_eq!(
+            config,
+            DistroConfig::from_toml_str(&config.to_toml_string().unwrap()).unwrap()
+        );
+    }
+
+    #[test]
+    fn test_get_and_set_value() {
+        let mut config = DistroConfig::default();
+        assert_eq!(
+            Some(Value::Boolean(true)),
+            config.get_value("network.share_resolv_conf").unwrap()
+        );
+        assert_eq!(None, config.get_value("autostart.delay_sec").unwrap());
+
+        config
+            .set_value("network.share_resolv_conf", "false")
+            .unwrap();
+        config.set_value("autostart.delay_sec", "30").unwrap();
+        config.set_value("env.EDITOR", "vim").unwrap();
+        config.set_value("env.FLAG", "true").unwrap();
+        config
+            .set_value("systemd.masked_units", "snapd.service, cups.service")
+            .unwrap();
+        config
+            .set_value("systemd.default_target", "graphical.target")
+            .unwrap();
+        assert!(!config.network.share_resolv_conf);
+        assert_eq!(Some(30), config.autostart.delay_sec);
+        assert_eq!(Some(&"vim".to_owned()), config.env.get("EDITOR"));
+        assert_eq!(Some(&"true".to_owned()), config.env.get("FLAG"));
+        assert_eq!(
+            vec!["snapd.service".to_owned(), "cups.service".to_owned()],
+            config.systemd.masked_units
+        );
+
+        config.mounts.push(MountConfig {
+            source: PathBuf::from("/mnt/c/data"),
+            target: PathBuf::from("/data"),
+            read_only: false,
+        });
+        config.set_value("mounts.0.read_only", "true").unwrap();
+        assert!(config.mounts[0].read_only);
+    }
+
+    #[test]
+    fn test_set_invalid_value() {
+        let mut config = DistroConfig::default();
+        assert!(config.set_value("network.share_resolv_conf", "1").is_err());
+        assert!(config.set_value("network.no_such_key", "true").is_err());
+        assert!(config.set_value("network", "true").is_err());
+        assert!(config.set_value("mounts.0.source", "/data").is_err());
+        assert!(config.set_value("autostart.delay_sec", "-1").is_err());
+        assert!(config
+            .set_value("systemd.default_target", "sshd.service")
+            .is_err());
+        assert!(config.set_value("auto_update.schedule", "").is_err());
+        assert_eq!(DistroConfig::default(), config);
+    }
+
+    #[test]
+    fn test_invalid_config() {
+        assert!(DistroConfig::from_toml_str(
+            r#"
+            [[mounts]]
+            source = "relative/path"
+            target = "/data"
+            "#
+        )
+        .is_err());
+        assert!(DistroConfig::from_toml_str(
+            r#"
+            [env]
+            "A=B" = "C"
+            "#
+        )
+        .is_err());
+        assert!(DistroConfig::from_toml_str(
+            r#"
+            [capabilities]
+            exec_drop = ["CAP_NO_SUCH_THING"]
+            "#
+        )
+        .is_err());
+        assert!(DistroConfig::from_toml_str(
+            r#"
+            [network]
+            nameservers = ["dns.example.com"]
+            "#
+        )
+        .is_err());
+    }
+}
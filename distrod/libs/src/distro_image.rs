@@ -1,7 +1,12 @@
 use std::ffi::OsString;
+use std::sync::{Arc, Mutex};
 
-use anyhow::{Context, Result};
+use anyhow::{bail, Result};
 use async_trait::async_trait;
+use once_cell::sync::Lazy;
+
+use crate::cancellation::CancellationToken;
+use crate::error::ImageError;
 
 pub type ListChooseFn<'a> =
     &'a (dyn Fn(DistroImageList) -> Result<Box<dyn DistroImageFetcher>> + Send + Sync);
@@ -10,6 +15,10 @@ pub type PromptPath<'a> = &'a (dyn Fn(&str, Option<&str>) -> Result<OsString> +
 #[async_trait]
 pub trait DistroImageFetcher {
     fn get_name(&self) -> &str;
+    /// A line shown beside the name when choosing from the list, such as the architecture.
+    fn get_description(&self) -> Option<String> {
+        None
+    }
     async fn fetch(&self) -> Result<DistroImageList>;
 }
 
@@ -42,6 +51,38 @@ pub enum DistroImageFile {
 
 pub type DistroImageFetcherGen = Box<dyn Fn() -> Result<Box<dyn DistroImageFetcher>> + Sync>;
 
+type SharedDistroImageFetcherGen =
+    Arc<dyn Fn() -> Result<Box<dyn DistroImageFetcher>> + Send + Sync>;
+
+static REGISTERED_FETCHERS: Lazy<Mutex<Vec<SharedDistroImageFetcherGen>>> =
+    Lazy::new(|| Mutex::new(vec![]));
+
+/// Adds a source of images to the list of `with_registered_fetchers`, so that a program using
+/// this library can offer its own images, such as the ones of a company mirror, in the prompts.
+pub fn register_image_fetcher<F>(fetcher_gen: F)
+where
+    F: Fn() -> Result<Box<dyn DistroImageFetcher>> + Send + Sync + 'static,
+{
+    REGISTERED_FETCHERS
+        .lock()
+        .expect("[BUG] the lock is not poisoned.")
+        .push(Arc::new(fetcher_gen));
+}
+
+/// Returns the built-in fetchers followed by the ones given to `register_image_fetcher`.
+pub fn with_registered_fetchers(
+    mut fetchers: Vec<DistroImageFetcherGen>,
+) -> Vec<DistroImageFetcherGen> {
+    let registered = REGISTERED_FETCHERS
+        .lock()
+        .expect("[BUG] the lock is not poisoned.");
+    for fetcher_gen in registered.iter() {
+        let fetcher_gen = Arc::clone(fetcher_gen);
+        fetchers.push(Box::new(move || fetcher_gen()));
+    }
+    fetchers
+}
+
 pub async fn fetch_image(
     fetchers: Vec<DistroImageFetcherGen>,
     choose_from_list: ListChooseFn<'_>,
@@ -64,6 +105,44 @@ pub async fn fetch_image(
     }
 }
 
+/// Returns a `ListChooseFn` which chooses the images by the names of `path` without prompts,
+/// such as "ubuntu/jammy", one name for each list from the top. The default of a list is chosen
+/// once the names run out.
+pub fn choose_by_path(
+    path: &str,
+) -> impl Fn(DistroImageList) -> Result<Box<dyn DistroImageFetcher>> + Send + Sync {
+    let names = Mutex::new(
+        path.split('/')
+            .filter(|name| !name.is_empty())
+            .map(|name| name.to_owned())
+            .collect::<Vec<_>>()
+            .into_iter(),
+    );
+    move |list| match list {
+        DistroImageList::Fetcher(list_item_kind, fetchers, default) => {
+            let name = names
+                .lock()
+                .expect("[BUG] the lock is not poisoned.")
+                .next();
+            let position = match (name, default) {
+                (Some(ref name), _) | (None, DefaultImageFetcher::Name(ref name)) => fetchers
+                    .iter()
+                    .position(|fetcher| fetcher.get_name() == name)
+                    .ok_or_else(|| ImageError::NotFound {
+                        name: name.clone(),
+                        list_item_kind: list_item_kind.clone(),
+                    })?,
+                (None, DefaultImageFetcher::Index(index)) => index,
+            };
+            fetchers
+                .into_iter()
+                .nth(position)
+                .ok_or_else(|| ImageError::EmptyList { list_item_kind }.into())
+        }
+        DistroImageList::Image(_) => bail!("[BUG] an image is not a list to choose from."),
+    }
+}
+
 struct DistroImageFetchersList {
     fetchers: Vec<DistroImageFetcherGen>,
     default_index: usize,
@@ -89,30 +168,133 @@ pub async fn download_file_with_progress<F, W>(
     url: &str,
     progress_bar_builder: F,
     out: &mut W,
+    cancel: &CancellationToken,
 ) -> Result<()>
 where
     F: FnOnce(u64) -> indicatif::ProgressBar,
     W: std::io::Write,
 {
+    let mut progress_bar_builder = Some(progress_bar_builder);
+    let mut progress_bar = None;
+    let result = download_file_with_callback(
+        url,
+        |downloaded_size, total_size| {
+            let progress_bar = progress_bar.get_or_insert_with(|| {
+                let build = progress_bar_builder
+                    .take()
+                    .expect("the progress bar is built only once");
+                build(total_size)
+            });
+            progress_bar.set_position(downloaded_size);
+        },
+        out,
+        cancel,
+    )
+    .await;
+    if let Some(progress_bar) = progress_bar {
+        if result.is_ok() {
+            progress_bar.finish();
+        } else {
+            progress_bar.abandon();
+        }
+    }
+    result
+}
+
+/// Downloads a file, calling `on_progress` with the downloaded size and the total size.
+async fn download_file_with_callback<F, W>(
+    url: &str,
+    mut on_progress: F,
+    out: &mut W,
+    cancel: &CancellationToken,
+) -> Result<()>
+where
+    F: FnMut(u64, u64),
+    W: std::io::Write,
+{
+    let download_error = |message: String| ImageError::Download {
+        url: url.to_owned(),
+        message,
+    };
     let client = reqwest::Client::builder().build()?;
     let mut response = client
         .get(url)
         .send()
         .await
-        .with_context(|| format!("Failed to download {}.", &url))?;
+        .map_err(|e| download_error(e.to_string()))?;
     let total_size = response
         .content_length()
-        .with_context(|| format!("Failed to get the content length of {}.", &url))?;
+        .ok_or_else(|| download_error("The content length is unknown.".to_owned()))?;
 
-    let progress_bar = progress_bar_builder(total_size);
     let mut downloaded_size = 0;
-
-    while let Some(bytes) = response.chunk().await? {
+    on_progress(downloaded_size, total_size);
+    while let Some(bytes) = response
+        .chunk()
+        .await
+        .map_err(|e| download_error(e.to_string()))?
+    {
+        cancel.check()?;
         out.write_all(&bytes)?;
-        downloaded_size = std::cmp::min(downloaded_size + bytes.len(), total_size as usize);
-        progress_bar.set_position(downloaded_size as u64);
+        downloaded_size = std::cmp::min(downloaded_size + bytes.len() as u64, total_size);
+        on_progress(downloaded_size, total_size);
     }
-
-    progress_bar.finish();
     Ok(())
 }
+
+#[cfg(test)]
+mod test_distro_image {
+    use super::*;
+
+    struct NamedFetcher(&'static str);
+
+    #[async_trait]
+    impl DistroImageFetcher for NamedFetcher {
+        fn get_name(&self) -> &str {
+            self.0
+        }
+
+        async fn fetch(&self) -> Result<DistroImageList> {
+            bail!("not fetched in the tests")
+        }
+    }
+
+    fn build_list(default: DefaultImageFetcher) -> DistroImageList {
+        DistroImageList::Fetcher(
+            "a distro".to_owned(),
+            vec![
+                Box::new(NamedFetcher("debian")) as Box<dyn DistroImageFetcher>,
+                Box::new(NamedFetcher("ubuntu")),
+            ],
+            default,
+        )
+    }
+
+    #[test]
+    fn test_choose_by_path() {
+        let choose = choose_by_path("ubuntu/");
+        let chosen = choose(build_list(DefaultImageFetcher::Index(0))).unwrap();
+        assert_eq!("ubuntu", chosen.get_name());
+        // The names have run out.
+        let chosen = choose(build_list(DefaultImageFetcher::Index(0))).unwrap();
+        assert_eq!("debian", chosen.get_name());
+        let chosen = choose(build_list(DefaultImageFetcher::Name("ubuntu".to_owned()))).unwrap();
+        assert_eq!("ubuntu", chosen.get_name());
+
+        let choose = choose_by_path("arch");
+        assert!(choose(build_list(DefaultImageFetcher::Index(0))).is_err());
+    }
+
+    #[test]
+    fn test_with_registered_fetchers() {
+        register_image_fetcher(|| {
+            Ok(Box::new(NamedFetcher("mirror")) as Box<dyn DistroImageFetcher>)
+        });
+        let builtin = || Ok(Box::new(NamedFetcher("debian")) as Box<dyn DistroImageFetcher>);
+        let fetchers = with_registered_fetchers(vec![Box::new(builtin) as DistroImageFetcherGen]);
+        let names: Vec<String> = fetchers
+            .iter()
+            .map(|fetcher_gen| fetcher_gen().unwrap().get_name().to_owned())
+            .collect();
+        assert_eq!(vec!["debian", "mirror"], names);
+    }
+}
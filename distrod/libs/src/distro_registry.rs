@@ -0,0 +1,102 @@
+use anyhow::{Context, Result};
+use std::path::PathBuf;
+
+use crate::distrod_config::DistrodConfig;
+use crate::error::DistroError;
+use crate::userns;
+
+/// A named distro managed by Distrod. Named distros are the rootfs directories under
+/// `distro_images_dir` of the Distrod config, which is where `distrod create` installs images,
+/// and the name is the name of the directory.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct DistroInstance {
+    pub name: String,
+    pub rootfs: PathBuf,
+}
+
+impl DistroInstance {
+    pub fn get(name: &str) -> Result<DistroInstance> {
+        validate_instance_name(name)?;
+        let rootfs = get_instances_dir()?.join(name);
+        if !rootfs.is_dir() {
+            return Err(DistroError::NotFound {
+                name: name.to_owned(),
+                rootfs,
+            }
+            .into());
+        }
+        Ok(DistroInstance {
+            name: name.to_owned(),
+            rootfs,
+        })
+    }
+}
+
+/// Lists the named distros sorted by name.
+pub fn list_instances() -> Result<Vec<DistroInstance>> {
+    let instances_dir = get_instances_dir()?;
+    if !instances_dir.exists() {
+        return Ok(vec![]);
+    }
+    let mut instances = vec![];
+    for entry in std::fs::read_dir(&instances_dir)
+        .with_context(|| format!("Failed to read {:?}.", &instances_dir))?
+    {
+        let entry = entry.with_context(|| format!("Failed to read {:?}.", &instances_dir))?;
+        if !entry.path().is_dir() {
+            continue;
+        }
+        let name = entry.file_name().to_string_lossy().to_string();
+        if validate_instance_name(&name).is_err() {
+            continue;
+        }
+        instances.push(DistroInstance {
+            name,
+            rootfs: entry.path(),
+        });
+    }
+    instances.sort_by(|a, b| a.name.cmp(&b.name));
+    Ok(instances)
+}
+
+/// The name is used as a file name, so it must not contain a path separator or be a special name.
+pub fn validate_instance_name(name: &str) -> std::result::Result<(), DistroError> {
+    let is_valid_char = |c: char| c.is_ascii_alphanumeric() || c == '-' || c == '_' || c == '.';
+    if name.is_empty() || name.starts_with('.') || !name.chars().all(is_valid_char) {
+        return Err(DistroError::InvalidName {
+            name: name.to_owned(),
+        });
+    }
+    Ok(())
+}
+
+/// Returns the directory of the named distros, which is in the home directory of the user in
+/// the rootless mode.
+pub fn get_instances_dir() -> Result<PathBuf> {
+    if userns::is_rootless_mode() {
+        return userns::get_rootless_distro_images_dir();
+    }
+    let config = DistrodConfig::get().with_context(|| "Failed to get the Distrod config.")?;
+    Ok(config.distrod.distro_images_dir.clone())
+}
+
+#[cfg(test)]
+mod test_validate_instance_name {
+    use super::*;
+
+    #[test]
+    fn test_valid_names() {
+        assert!(validate_instance_name("ubuntu").is_ok());
+        assert!(validate_instance_name("local-ubuntu-21.04_amd64").is_ok());
+    }
+
+    #[test]
+    fn test_invalid_names() {
+        assert!(validate_instance_name("").is_err());
+        assert!(validate_instance_name(".").is_err());
+        assert!(validate_instance_name("..").is_err());
+        assert!(validate_instance_name("../etc").is_err());
+        assert!(validate_instance_name("foo/bar").is_err());
+        assert!(validate_instance_name("foo bar").is_err());
+    }
+}
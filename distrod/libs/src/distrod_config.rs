@@ -16,6 +16,22 @@ use serde::{Deserialize, Serialize};
 #[derive(Serialize, Deserialize, Clone, Debug)]
 pub struct DistrodConfig {
     pub distrod: DistrodGlobalConfig,
+    #[serde(default)]
+    pub etc_guard: EtcGuardConfig,
+    #[serde(default)]
+    pub extract: ExtractConfig,
+    #[serde(default)]
+    pub monitor: MonitorConfig,
+    #[serde(default)]
+    pub autostart: DistrosAutostartConfig,
+    #[serde(default)]
+    pub event_log: EventLogConfig,
+    #[serde(default)]
+    pub port_discovery: PortDiscoveryConfig,
+    #[serde(default)]
+    pub snapshot: SnapshotConfig,
+    #[serde(default)]
+    pub time_sync: TimeSyncConfig,
 }
 
 #[derive(Serialize, Deserialize, Clone, Debug)]
@@ -24,9 +40,270 @@ pub struct DistrodGlobalConfig {
     pub distro_images_dir: PathBuf,
     pub log_level: Option<String>,
     pub kmsg_log_level: Option<String>,
+    /// The level of the logs written to the log file, such as "info,libs::distro=debug".
+    /// The file is off without it, except for the autostart, which writes "info" by default.
+    pub log_file_level: Option<String>,
+    /// The format of the log file, "text" or "json".
+    pub log_file_format: Option<String>,
+}
+
+/// The paths skipped when `distrod create` extracts an image, to make the rootfs smaller.
+/// Both are glob patterns such as "/usr/share/doc/**". `include` takes precedence over `exclude`.
+#[derive(Serialize, Deserialize, Clone, Debug, Default)]
+pub struct ExtractConfig {
+    #[serde(default)]
+    pub exclude: Vec<String>,
+    #[serde(default)]
+    pub include: Vec<String>,
+}
+
+/// The files in /etc that `distrod etc-guard` keeps from being overwritten by WSL or other tools.
+#[derive(Serialize, Deserialize, Clone, Debug, Default)]
+pub struct EtcGuardConfig {
+    #[serde(default)]
+    pub files: Vec<GuardedFileConfig>,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct GuardedFileConfig {
+    /// The guarded file, such as /etc/resolv.conf.
+    pub path: PathBuf,
+    /// The file which has the contents that the guarded file should have.
+    pub source: PathBuf,
+    #[serde(default)]
+    pub policy: GuardPolicy,
+}
+
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq, Eq)]
+#[serde(rename_all = "kebab-case")]
+pub enum GuardPolicy {
+    /// Write the contents of the source back to the guarded file.
+    Reapply,
+    /// Only log the overwrite and leave the new contents.
+    BackOff,
+}
+
+impl Default for GuardPolicy {
+    fn default() -> Self {
+        GuardPolicy::Reapply
+    }
+}
+
+/// The heuristics with which `distrod monitor` flags the processes that look like malware or
+/// cryptominers.
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct MonitorConfig {
+    /// The directories where legitimate programs are rarely installed.
+    #[serde(default = "default_monitor_watched_dirs")]
+    pub watched_dirs: Vec<PathBuf>,
+    /// Alert whenever a program in the watched directories is executed.
+    #[serde(default)]
+    pub alert_on_exec: bool,
+    /// Alert when a program in the watched directories, or whose file has been deleted, uses
+    /// this percentage of a CPU for `cpu_duration_sec`.
+    #[serde(default = "default_monitor_cpu_percent")]
+    pub cpu_percent: f64,
+    #[serde(default = "default_monitor_cpu_duration_sec")]
+    pub cpu_duration_sec: u64,
+    /// Alert when a process has this many established outbound TCP connections. 0 disables it.
+    #[serde(default = "default_monitor_max_outbound_connections")]
+    pub max_outbound_connections: usize,
+    /// The executables which are never flagged.
+    #[serde(default)]
+    pub allowed_exes: Vec<PathBuf>,
+    /// Show the alerts as Windows notifications in addition to the journal.
+    #[serde(default = "default_monitor_notify_windows")]
+    pub notify_windows: bool,
+    #[serde(default = "default_monitor_interval_sec")]
+    pub interval_sec: u64,
+}
+
+impl Default for MonitorConfig {
+    fn default() -> Self {
+        MonitorConfig {
+            watched_dirs: default_monitor_watched_dirs(),
+            alert_on_exec: false,
+            cpu_percent: default_monitor_cpu_percent(),
+            cpu_duration_sec: default_monitor_cpu_duration_sec(),
+            max_outbound_connections: default_monitor_max_outbound_connections(),
+            allowed_exes: vec![],
+            notify_windows: default_monitor_notify_windows(),
+            interval_sec: default_monitor_interval_sec(),
+        }
+    }
+}
+
+fn default_monitor_watched_dirs() -> Vec<PathBuf> {
+    vec![
+        PathBuf::from("/tmp"),
+        PathBuf::from("/var/tmp"),
+        PathBuf::from("/dev/shm"),
+    ]
+}
+
+fn default_monitor_cpu_percent() -> f64 {
+    80.0
+}
+
+fn default_monitor_cpu_duration_sec() -> u64 {
+    60
+}
+
+fn default_monitor_max_outbound_connections() -> usize {
+    200
+}
+
+fn default_monitor_notify_windows() -> bool {
+    true
+}
+
+fn default_monitor_interval_sec() -> u64 {
+    5
+}
+
+/// The named distros which start after the default distro on Windows startup, in this order.
+/// `distrod enable --start-on-windows-boot` writes the order into the autostart task.
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct DistrosAutostartConfig {
+    #[serde(default)]
+    pub distros: Vec<AutostartDistroConfig>,
+    /// How long to wait for a distro in `after` to finish booting.
+    #[serde(default = "default_autostart_ready_timeout_sec")]
+    pub ready_timeout_sec: u64,
+    /// Whether the autostart task keeps its WSL session open by `distrod autostart --keep-alive`,
+    /// so that WSL doesn't shut down the VM without any terminal open.
+    #[serde(default)]
+    pub keep_alive: bool,
+}
+
+impl Default for DistrosAutostartConfig {
+    fn default() -> Self {
+        DistrosAutostartConfig {
+            distros: vec![],
+            ready_timeout_sec: default_autostart_ready_timeout_sec(),
+            keep_alive: false,
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Eq)]
+pub struct AutostartDistroConfig {
+    pub name: String,
+    /// The distros which should have finished booting before this one starts.
+    #[serde(default)]
+    pub after: Vec<String>,
+}
+
+fn default_autostart_ready_timeout_sec() -> u64 {
+    120
+}
+
+/// The journal entries that `distrod event-log` forwards to the Windows Event Log.
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct EventLogConfig {
+    /// The least important priority to forward, from 0 (emerg) to 7 (debug). Defaults to 3 (err).
+    #[serde(default = "default_event_log_max_priority")]
+    pub max_priority: u8,
+    /// The units whose entries are forwarded. All the units if empty.
+    #[serde(default)]
+    pub units: Vec<String>,
+    /// The source of the events in the Application log.
+    #[serde(default = "default_event_log_source")]
+    pub source: String,
+}
+
+impl Default for EventLogConfig {
+    fn default() -> Self {
+        EventLogConfig {
+            max_priority: default_event_log_max_priority(),
+            units: vec![],
+            source: default_event_log_source(),
+        }
+    }
+}
+
+fn default_event_log_max_priority() -> u8 {
+    3
+}
+
+fn default_event_log_source() -> String {
+    "Distrod".to_owned()
+}
+
+/// The listening ports that `distrod port watch` forwards from Windows automatically.
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct PortDiscoveryConfig {
+    /// The ports which are never forwarded automatically, in addition to the ones in tcp4_ports.
+    #[serde(default)]
+    pub excluded_ports: Vec<u16>,
+    #[serde(default = "default_port_discovery_interval_sec")]
+    pub interval_sec: u64,
+    /// How long portproxy.exe keeps running without any connection through the forwards. 0 keeps
+    /// it running.
+    #[serde(default)]
+    pub idle_exit_sec: u64,
+    /// The address on Windows the forwards listen on. Only Windows itself reaches them by
+    /// default, since the ports are found without being asked for. "0.0.0.0" exposes them to
+    /// the LAN as well.
+    #[serde(default = "default_port_discovery_listen_address")]
+    pub listen_address: String,
+}
+
+impl Default for PortDiscoveryConfig {
+    fn default() -> Self {
+        PortDiscoveryConfig {
+            excluded_ports: vec![],
+            interval_sec: default_port_discovery_interval_sec(),
+            idle_exit_sec: 0,
+            listen_address: default_port_discovery_listen_address(),
+        }
+    }
+}
+
+fn default_port_discovery_interval_sec() -> u64 {
+    2
+}
+
+fn default_port_discovery_listen_address() -> String {
+    "127.0.0.1".to_owned()
+}
+
+/// How many snapshots of each distro `distrod compact` keeps.
+#[derive(Serialize, Deserialize, Clone, Debug, Default)]
+pub struct SnapshotConfig {
+    /// The number of the newest snapshots to keep. All of them are kept without it.
+    pub keep: Option<usize>,
+}
+
+/// When `distrod time-sync` corrects the clock, which drifts after Windows sleeps.
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct TimeSyncConfig {
+    /// The clock is corrected when it's off by more seconds than this.
+    #[serde(default = "default_time_sync_max_offset_sec")]
+    pub max_offset_sec: f64,
+    #[serde(default = "default_time_sync_interval_sec")]
+    pub interval_sec: u64,
+}
+
+impl Default for TimeSyncConfig {
+    fn default() -> Self {
+        TimeSyncConfig {
+            max_offset_sec: default_time_sync_max_offset_sec(),
+            interval_sec: default_time_sync_interval_sec(),
+        }
+    }
+}
+
+fn default_time_sync_max_offset_sec() -> f64 {
+    5.0
+}
+
+fn default_time_sync_interval_sec() -> u64 {
+    30
 }
 
 static DISTROD_ROOT_DIR: &str = "/opt/distrod";
+static LOG_FILE_PATH: &str = "/var/log/distrod/distrod.log";
 
 static DISTROD_CONFIG: Lazy<Result<RwLock<Arc<DistrodConfig>>>> = Lazy::new(|| {
     Ok(RwLock::new(Arc::new(read_distrod_config().with_context(
@@ -64,6 +341,11 @@ impl DistrodConfig {
     }
 }
 
+/// The directory where Distrod is installed.
+pub fn get_distrod_root_dir() -> &'static str {
+    DISTROD_ROOT_DIR
+}
+
 static DISTROD_ALIAS_DIR: Lazy<String> = Lazy::new(|| format!("{}/{}", DISTROD_ROOT_DIR, "alias"));
 
 /// The directory where the alias commands are stored.
@@ -71,6 +353,19 @@ pub fn get_alias_dir() -> &'static str {
     DISTROD_ALIAS_DIR.as_str()
 }
 
+/// The file where Distrod writes the logs, which is rotated at 1 MiB.
+pub fn get_log_file_path() -> &'static str {
+    LOG_FILE_PATH
+}
+
+static IMAGE_FETCHER_PLUGINS_DIR: Lazy<String> =
+    Lazy::new(|| format!("{}/{}", DISTROD_ROOT_DIR, "image-fetchers"));
+
+/// The directory of the executables which list more images for `distrod create`.
+pub fn get_image_fetcher_plugins_dir() -> &'static str {
+    IMAGE_FETCHER_PLUGINS_DIR.as_str()
+}
+
 static DISTROD_BIN_DIR: Lazy<String> = Lazy::new(|| format!("{}/{}", DISTROD_ROOT_DIR, "bin"));
 
 /// The path to the distrod binary.
@@ -113,6 +408,47 @@ pub fn get_distrod_conf_dir() -> &'static str {
     DISTROD_CONF_DIR_PAH.as_str()
 }
 
+static DISTROD_VAR_DIR_PATH: Lazy<String> = Lazy::new(|| format!("{}/{}", DISTROD_ROOT_DIR, "var"));
+
+/// The path to the directory where Distrod stores the state that should survive reboots,
+/// such as statistics.
+pub fn get_distrod_var_dir() -> &'static str {
+    DISTROD_VAR_DIR_PATH.as_str()
+}
+
+static PORT_USAGE_STATS_PATH: Lazy<String> =
+    Lazy::new(|| format!("{}/{}", DISTROD_VAR_DIR_PATH.as_str(), "port_usage.json"));
+
+/// The path to the file where portproxy records the bandwidth usage of the forwarded ports.
+pub fn get_port_usage_stats_path() -> &'static str {
+    PORT_USAGE_STATS_PATH.as_str()
+}
+
+static TCP4_PORTS_PATH: Lazy<String> =
+    Lazy::new(|| format!("{}/{}", DISTROD_CONF_DIR_PAH.as_str(), "tcp4_ports"));
+
+/// The path to the file with the ports which portproxy.service forwards.
+pub fn get_tcp4_ports_path() -> &'static str {
+    TCP4_PORTS_PATH.as_str()
+}
+
+static PORT_FORWARD_RULES_PATH: Lazy<String> =
+    Lazy::new(|| format!("{}/{}", DISTROD_CONF_DIR_PAH.as_str(), "port_forwards.toml"));
+
+/// The path to the rules file of the forwards which portproxy.service reloads on change.
+pub fn get_port_forward_rules_path() -> &'static str {
+    PORT_FORWARD_RULES_PATH.as_str()
+}
+
+static WSL_CONF_BACKUP_PATH: Lazy<String> =
+    Lazy::new(|| format!("{}/{}", DISTROD_CONF_DIR_PAH.as_str(), "wsl.conf.orig"));
+
+/// The path to the copy of /etc/wsl.conf taken when Distrod was enabled, which
+/// `distrod disable --purge` restores the boot settings from.
+pub fn get_wsl_conf_backup_path() -> &'static str {
+    WSL_CONF_BACKUP_PATH.as_str()
+}
+
 #[cfg(target_os = "linux")]
 fn read_distrod_config() -> Result<DistrodConfig> {
     let config_path = Path::new(&*DISTROD_CONF_DIR_PAH).join("distrod.toml");
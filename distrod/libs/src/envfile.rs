@@ -19,7 +19,7 @@ use std::{
     path::{Path, PathBuf},
 };
 
-use anyhow::{anyhow, Context, Result};
+use anyhow::{anyhow, bail, Context, Result};
 
 #[derive(Debug, Clone, Default)]
 pub struct EnvShellScript {
@@ -492,7 +492,7 @@ impl<'a> PathVariable<'a> {
     }
 }
 
-fn single_quote_str_for_shell(s: &str) -> String {
+pub(crate) fn single_quote_str_for_shell(s: &str) -> String {
     format!("'{}'", s.replace("'", "'\"'\"'"))
 }
 
@@ -505,6 +505,51 @@ fn unquote_path(s: &str) -> &str {
     s
 }
 
+/// An environment variable given by `distrod exec --env` or a line of `--env-file`, which is either
+/// `KEY=VALUE` or `KEY` to pass the variable of the caller through. Unlike /etc/environment, the
+/// value is taken literally without unquoting it.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum EnvArg {
+    Set(String, String),
+    PassThrough(String),
+}
+
+impl EnvArg {
+    pub fn parse(arg: &str) -> Result<EnvArg> {
+        let (key, value) = match arg.find('=') {
+            Some(i) => (&arg[..i], Some(&arg[i + 1..])),
+            None => (arg, None),
+        };
+        if key.is_empty() || key.chars().any(|c| c.is_whitespace()) {
+            bail!("Invalid environment variable name: '{}'.", key);
+        }
+        Ok(match value {
+            Some(value) => EnvArg::Set(key.to_owned(), value.to_owned()),
+            None => EnvArg::PassThrough(key.to_owned()),
+        })
+    }
+}
+
+/// Reads the file of `KEY=VALUE` or `KEY` lines. Empty lines and lines starting with # are skipped.
+pub fn read_env_arg_file<P: AsRef<Path>>(path: P) -> Result<Vec<EnvArg>> {
+    let mut contents = String::new();
+    File::open(path.as_ref())
+        .and_then(|mut file| file.read_to_string(&mut contents))
+        .with_context(|| format!("Failed to read {:?}.", path.as_ref()))?;
+    parse_env_arg_lines(&contents)
+}
+
+fn parse_env_arg_lines(contents: &str) -> Result<Vec<EnvArg>> {
+    contents
+        .lines()
+        .enumerate()
+        .filter(|(_, line)| !line.trim().is_empty() && !line.trim_start().starts_with('#'))
+        .map(|(i, line)| {
+            EnvArg::parse(line.trim_start()).with_context(|| format!("Invalid line {}.", i + 1))
+        })
+        .collect()
+}
+
 #[cfg(test)]
 mod test_env_shell_script {
     use super::*;
@@ -1181,3 +1226,39 @@ mod test_env_file {
         assert_eq!(new_cont, expected);
     }
 }
+
+#[cfg(test)]
+mod test_env_arg {
+    use super::*;
+
+    #[test]
+    fn test_parse_env_arg() {
+        assert_eq!(
+            EnvArg::Set("FOO".to_owned(), "a=b c".to_owned()),
+            EnvArg::parse("FOO=a=b c").unwrap()
+        );
+        assert_eq!(
+            EnvArg::Set("EMPTY".to_owned(), "".to_owned()),
+            EnvArg::parse("EMPTY=").unwrap()
+        );
+        assert_eq!(
+            EnvArg::PassThrough("HOME".to_owned()),
+            EnvArg::parse("HOME").unwrap()
+        );
+        assert!(EnvArg::parse("=value").is_err());
+        assert!(EnvArg::parse("A B=c").is_err());
+    }
+
+    #[test]
+    fn test_parse_env_arg_lines() {
+        let contents = "# comment\nFOO='quoted'\n\n  BAR\n";
+        assert_eq!(
+            vec![
+                EnvArg::Set("FOO".to_owned(), "'quoted'".to_owned()),
+                EnvArg::PassThrough("BAR".to_owned()),
+            ],
+            parse_env_arg_lines(contents).unwrap()
+        );
+        assert!(parse_env_arg_lines("OK=1\n=broken\n").is_err());
+    }
+}
@@ -0,0 +1,264 @@
+use std::path::PathBuf;
+use thiserror::Error;
+
+use crate::cancellation::Cancelled;
+
+/// The exit codes of Distrod and the launcher on the failures scripts tell apart, in one table so
+/// that a code means one failure whichever of them exits with it. The others exit with 1.
+pub mod exit_code {
+    /// An option of the launcher is invalid, such as a malformed distro name.
+    pub const INVALID_OPTION: i32 = 2;
+    /// A distro of the name is already registered to WSL.
+    pub const ALREADY_REGISTERED: i32 = 3;
+    /// The launcher couldn't choose, download, or unpack the image.
+    pub const LAUNCHER_IMAGE: i32 = 4;
+    /// WSL failed to register the distro.
+    pub const REGISTRATION: i32 = 5;
+    /// The launcher failed to add the user or enable Distrod in the registered distro.
+    pub const SET_UP: i32 = 6;
+    /// `crate::error::DistroError`.
+    pub const DISTRO: i32 = 10;
+    /// `crate::error::ImageError`.
+    pub const IMAGE: i32 = 11;
+    /// `crate::error::ContainerError`.
+    pub const CONTAINER: i32 = 12;
+    /// Ctrl-C, the same as the shells exiting by SIGINT.
+    pub const CANCELLED: i32 = 130;
+}
+
+/// An error which has a stable code listed in the docs, and the exit status of the command
+/// failing by it. The other errors are only for the messages, and the command exits with 1.
+/// Callers find it in the chain of an `anyhow::Error` by `find_coded_error`.
+pub trait CodedError: std::error::Error {
+    fn code(&self) -> &'static str;
+    fn exit_code(&self) -> i32;
+}
+
+/// The errors of managing the named distros.
+#[derive(Debug, Error)]
+#[non_exhaustive]
+pub enum DistroError {
+    /// No distro of the name has been made by `distrod create`.
+    #[error("No distro named '{name}' is found at {rootfs:?}.")]
+    NotFound { name: String, rootfs: PathBuf },
+    /// The distro of the name, or any distro if the name is None, is not running.
+    #[error("{}", describe_not_running(.name))]
+    NotRunning { name: Option<String> },
+    /// The distro of the name, or another distro if the name is None, is already running.
+    #[error("{}", describe_already_running(.name))]
+    AlreadyRunning { name: Option<String> },
+    #[error(
+        "Invalid distro name: '{name}'. A name can contain only alphanumerics, '-', '_', \
+         and '.', and must not start with '.'."
+    )]
+    InvalidName { name: String },
+    /// A file of the distro which root trusts, such as its config, can be changed by others.
+    #[error("{path:?} must be owned by root, and only root must be able to write it.")]
+    UnsafeFile { path: PathBuf },
+}
+
+fn describe_not_running(name: &Option<String>) -> String {
+    match name {
+        Some(name) => format!("{} is not running.", name),
+        None => "No distro is currently running.".to_owned(),
+    }
+}
+
+fn describe_already_running(name: &Option<String>) -> String {
+    match name {
+        Some(name) => format!("{} is already running.", name),
+        None => "There is already a running distro.".to_owned(),
+    }
+}
+
+impl CodedError for DistroError {
+    fn code(&self) -> &'static str {
+        match self {
+            DistroError::NotFound { .. } => "E101",
+            DistroError::NotRunning { .. } => "E102",
+            DistroError::AlreadyRunning { .. } => "E103",
+            DistroError::InvalidName { .. } => "E104",
+            DistroError::UnsafeFile { .. } => "E105",
+        }
+    }
+
+    fn exit_code(&self) -> i32 {
+        exit_code::DISTRO
+    }
+}
+
+/// The errors of fetching the distro images.
+#[derive(Debug, Error)]
+#[non_exhaustive]
+pub enum ImageError {
+    /// No image of the name is in the list of the kind, such as "a distro" or "a version".
+    #[error("'{name}' is not found in {list_item_kind}.")]
+    NotFound {
+        name: String,
+        list_item_kind: String,
+    },
+    #[error("Empty list of {list_item_kind}.")]
+    EmptyList { list_item_kind: String },
+    /// The image server has no rootfs image for the architecture of this machine.
+    #[error("No rootfs image for {arch} is in {url}.")]
+    NoImageForArch { arch: String, url: String },
+    /// The page of the image server which lists the images has no link.
+    #[error("{url:?} is not available")]
+    Unavailable { url: String },
+    #[error("Failed to download {url}. {message}")]
+    Download { url: String, message: String },
+    /// The image reference isn't like "ubuntu", "ubuntu:22.04", or "ubuntu/jammy".
+    #[error("'{reference}' is not an image such as ubuntu, ubuntu:22.04, or debian/bookworm.")]
+    InvalidReference { reference: String },
+    /// An image fetcher plugin failed or broke the contract of its output.
+    #[error("The image fetcher plugin '{plugin}' failed. {message}")]
+    Plugin { plugin: String, message: String },
+    /// The page of the image server which lists the images can't be read.
+    #[error("The list of the images at {url} is broken. {message}")]
+    InvalidList { url: String, message: String },
+}
+
+impl CodedError for ImageError {
+    fn code(&self) -> &'static str {
+        match self {
+            ImageError::NotFound { .. } => "E201",
+            ImageError::EmptyList { .. } => "E202",
+            ImageError::NoImageForArch { .. } => "E203",
+            ImageError::Unavailable { .. } => "E204",
+            ImageError::Download { .. } => "E205",
+            ImageError::InvalidReference { .. } => "E206",
+            ImageError::Plugin { .. } => "E207",
+            ImageError::InvalidList { .. } => "E208",
+        }
+    }
+
+    fn exit_code(&self) -> i32 {
+        exit_code::IMAGE
+    }
+}
+
+/// The errors of setting up the container of a distro.
+#[derive(Debug, Error)]
+#[non_exhaustive]
+pub enum ContainerError {
+    /// The rootfs has the binaries for another architecture.
+    #[error(
+        "The rootfs {rootfs:?} is for {rootfs_arch}, but this machine runs {machine_arch} \
+         binaries. Choose an image for {image_arch}."
+    )]
+    ArchMismatch {
+        rootfs: PathBuf,
+        rootfs_arch: String,
+        machine_arch: String,
+        image_arch: String,
+    },
+    /// The rootfs of WSL is asked to be ephemeral or read-only, which would hide the changes
+    /// from WSL or break it.
+    #[error(
+        "The rootfs of WSL can't be ephemeral or read-only. \
+         Start a distro made by `distrod create`."
+    )]
+    EphemeralWslRootfs,
+    /// The rootless mode can't write the rootfs of WSL, which root owns.
+    #[error(
+        "The rootless mode can't start the rootfs of WSL. \
+         Create a distro by `distrod create` without sudo and start it by `--distro`."
+    )]
+    RootlessWslRootfs,
+    /// The operation needs the root on the host, not the one of the rootless mode.
+    #[error("Only root can {operation}.")]
+    RootRequired { operation: String },
+    /// The rootfs path has a character which separates the options of overlayfs.
+    #[error("The rootfs path {path:?} can't contain ',' or ':' to be ephemeral.")]
+    InvalidOverlayPath { path: PathBuf },
+    /// A path given to the container isn't absolute.
+    #[error("Non-absolute path is given as {kind}: {path:?}")]
+    RelativePath { kind: &'static str, path: PathBuf },
+}
+
+impl CodedError for ContainerError {
+    fn code(&self) -> &'static str {
+        match self {
+            ContainerError::ArchMismatch { .. } => "E301",
+            ContainerError::EphemeralWslRootfs => "E302",
+            ContainerError::RootlessWslRootfs => "E303",
+            ContainerError::RootRequired { .. } => "E304",
+            ContainerError::InvalidOverlayPath { .. } => "E305",
+            ContainerError::RelativePath { .. } => "E306",
+        }
+    }
+
+    fn exit_code(&self) -> i32 {
+        exit_code::CONTAINER
+    }
+}
+
+impl CodedError for Cancelled {
+    fn code(&self) -> &'static str {
+        "E001"
+    }
+
+    fn exit_code(&self) -> i32 {
+        exit_code::CANCELLED
+    }
+}
+
+/// Finds the outermost coded error in the chain of `err`.
+pub fn find_coded_error(err: &anyhow::Error) -> Option<&dyn CodedError> {
+    err.chain().find_map(|cause| {
+        if let Some(e) = cause.downcast_ref::<DistroError>() {
+            return Some(e as &dyn CodedError);
+        }
+        if let Some(e) = cause.downcast_ref::<ImageError>() {
+            return Some(e as &dyn CodedError);
+        }
+        if let Some(e) = cause.downcast_ref::<ContainerError>() {
+            return Some(e as &dyn CodedError);
+        }
+        cause
+            .downcast_ref::<Cancelled>()
+            .map(|e| e as &dyn CodedError)
+    })
+}
+
+/// Logs `err` and its code, and returns the exit status of the command failing by it. The
+/// binaries call this when main fails.
+pub fn report(err: &anyhow::Error) -> i32 {
+    match find_coded_error(err) {
+        Some(coded) => {
+            log::error!("[{}] {:?}", coded.code(), err);
+            coded.exit_code()
+        }
+        None => {
+            log::error!("{:?}", err);
+            1
+        }
+    }
+}
+
+#[cfg(test)]
+mod test_error {
+    use super::*;
+    use anyhow::Context;
+
+    #[test]
+    fn test_find_coded_error() {
+        let err = Err::<(), _>(DistroError::NotRunning {
+            name: Some("ubuntu".to_owned()),
+        })
+        .with_context(|| "Failed to stop the distro.")
+        .unwrap_err();
+        let coded = find_coded_error(&err).unwrap();
+        assert_eq!("E102", coded.code());
+        assert_eq!(exit_code::DISTRO, coded.exit_code());
+        assert_eq!("ubuntu is not running.", coded.to_string());
+
+        let err = Err::<(), _>(Cancelled)
+            .with_context(|| "Failed to create the distro.")
+            .unwrap_err();
+        assert_eq!(130, find_coded_error(&err).unwrap().exit_code());
+
+        let err = anyhow::anyhow!("Failed to do something.");
+        assert!(find_coded_error(&err).is_none());
+    }
+}
@@ -0,0 +1,242 @@
+use anyhow::{Context, Result};
+use nix::sys::inotify::{AddWatchFlags, InitFlags, Inotify, WatchDescriptor};
+use std::collections::{HashMap, VecDeque};
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, Instant};
+
+use crate::distrod_config::{GuardPolicy, GuardedFileConfig};
+
+/// If a file has to be reapplied this many times within REAPPLY_WINDOW, someone else is
+/// fighting over it. Then the guard backs off from the file instead of fighting forever.
+const MAX_REAPPLIES_IN_WINDOW: usize = 5;
+const REAPPLY_WINDOW: Duration = Duration::from_secs(60);
+
+/// Watches the guarded files with inotify and enforces their policies when they are overwritten.
+pub struct EtcGuard {
+    inotify: Inotify,
+    guarded_files: Vec<GuardedFile>,
+    watched_dirs: HashMap<WatchDescriptor, PathBuf>,
+}
+
+struct GuardedFile {
+    config: GuardedFileConfig,
+    reapplied_at: VecDeque<Instant>,
+    gave_up: bool,
+}
+
+impl EtcGuard {
+    pub fn new(configs: Vec<GuardedFileConfig>) -> Result<EtcGuard> {
+        let inotify =
+            Inotify::init(InitFlags::IN_CLOEXEC).with_context(|| "Failed to init inotify.")?;
+        let mut watched_dirs = HashMap::new();
+        for config in &configs {
+            // Watch the parent directory, since the file may be replaced by a new file or a symlink.
+            let dir = config
+                .path
+                .parent()
+                .unwrap_or_else(|| Path::new("/"))
+                .to_owned();
+            if watched_dirs.values().any(|watched| watched == &dir) {
+                continue;
+            }
+            let wd = inotify
+                .add_watch(
+                    &dir,
+                    AddWatchFlags::IN_CLOSE_WRITE
+                        | AddWatchFlags::IN_MOVED_TO
+                        | AddWatchFlags::IN_CREATE
+                        | AddWatchFlags::IN_DELETE,
+                )
+                .with_context(|| format!("Failed to watch {:?}.", &dir))?;
+            watched_dirs.insert(wd, dir);
+        }
+        Ok(EtcGuard {
+            inotify,
+            guarded_files: configs
+                .into_iter()
+                .map(|config| GuardedFile {
+                    config,
+                    reapplied_at: VecDeque::new(),
+                    gave_up: false,
+                })
+                .collect(),
+            watched_dirs,
+        })
+    }
+
+    /// Enforces the policies of all the files once, and keeps enforcing them when they change.
+    /// This never returns unless reading from inotify fails.
+    pub fn run(&mut self) -> Result<()> {
+        for file in &mut self.guarded_files {
+            file.enforce();
+        }
+        loop {
+            let events = self
+                .inotify
+                .read_events()
+                .with_context(|| "Failed to read inotify events.")?;
+            for event in events {
+                let (dir, name) = match (self.watched_dirs.get(&event.wd), event.name) {
+                    (Some(dir), Some(name)) => (dir, name),
+                    _ => continue,
+                };
+                let path = dir.join(name);
+                for file in &mut self.guarded_files {
+                    if file.config.path == path {
+                        file.enforce();
+                    }
+                }
+            }
+        }
+    }
+}
+
+impl GuardedFile {
+    fn enforce(&mut self) {
+        if self.gave_up {
+            return;
+        }
+        let path = &self.config.path;
+        match is_overwritten(path, &self.config.source) {
+            Ok(false) => return,
+            Ok(true) => {}
+            Err(e) => {
+                log::warn!("Failed to check {:?}. {:?}", path, e);
+                return;
+            }
+        }
+        if self.config.policy == GuardPolicy::BackOff {
+            log::info!(
+                "{:?} has been overwritten. Leaving it as is by the back-off policy.",
+                path
+            );
+            return;
+        }
+
+        let now = Instant::now();
+        while let Some(oldest) = self.reapplied_at.front() {
+            if now.duration_since(*oldest) <= REAPPLY_WINDOW {
+                break;
+            }
+            self.reapplied_at.pop_front();
+        }
+        if self.reapplied_at.len() >= MAX_REAPPLIES_IN_WINDOW {
+            log::warn!(
+                "{:?} keeps being overwritten by something else. Backing off from it.",
+                path
+            );
+            self.gave_up = true;
+            return;
+        }
+
+        match reapply(path, &self.config.source) {
+            Ok(_) => {
+                log::info!(
+                    "{:?} has been overwritten. Reapplied {:?}.",
+                    path,
+                    &self.config.source
+                );
+                self.reapplied_at.push_back(now);
+            }
+            Err(e) => log::warn!("Failed to reapply {:?}. {:?}", path, e),
+        }
+    }
+}
+
+fn is_overwritten(path: &Path, source: &Path) -> Result<bool> {
+    let expected =
+        fs::read(source).with_context(|| format!("Failed to read the source {:?}.", source))?;
+    if fs::symlink_metadata(path).map_or(true, |metadata| metadata.file_type().is_symlink()) {
+        return Ok(true);
+    }
+    let current = fs::read(path).with_context(|| format!("Failed to read {:?}.", path))?;
+    Ok(current != expected)
+}
+
+fn reapply(path: &Path, source: &Path) -> Result<()> {
+    let contents =
+        fs::read(source).with_context(|| format!("Failed to read the source {:?}.", source))?;
+    // Don't write through a symlink, which points to a file of the tool that made it.
+    if let Ok(metadata) = fs::symlink_metadata(path) {
+        if metadata.file_type().is_symlink() {
+            fs::remove_file(path).with_context(|| format!("Failed to remove {:?}.", path))?;
+        }
+    }
+    fs::write(path, contents).with_context(|| format!("Failed to write {:?}.", path))?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod test_etc_guard {
+    use super::*;
+
+    fn new_guarded_file(dir: &Path, policy: GuardPolicy) -> GuardedFile {
+        let source = dir.join("source");
+        fs::write(&source, "nameserver 1.1.1.1\n").unwrap();
+        let path = dir.join("resolv.conf");
+        fs::write(&path, "nameserver 172.17.0.1\n").unwrap();
+        GuardedFile {
+            config: GuardedFileConfig {
+                path,
+                source,
+                policy,
+            },
+            reapplied_at: VecDeque::new(),
+            gave_up: false,
+        }
+    }
+
+    #[test]
+    fn test_reapply_policy() {
+        let tmp_dir = tempfile::tempdir().unwrap();
+        let mut file = new_guarded_file(tmp_dir.path(), GuardPolicy::Reapply);
+        file.enforce();
+        assert_eq!(
+            "nameserver 1.1.1.1\n",
+            fs::read_to_string(&file.config.path).unwrap()
+        );
+
+        // A symlink is replaced with a regular file.
+        fs::remove_file(&file.config.path).unwrap();
+        std::os::unix::fs::symlink(tmp_dir.path().join("other"), &file.config.path).unwrap();
+        file.enforce();
+        assert!(!fs::symlink_metadata(&file.config.path)
+            .unwrap()
+            .file_type()
+            .is_symlink());
+        assert_eq!(
+            "nameserver 1.1.1.1\n",
+            fs::read_to_string(&file.config.path).unwrap()
+        );
+    }
+
+    #[test]
+    fn test_back_off_policy() {
+        let tmp_dir = tempfile::tempdir().unwrap();
+        let mut file = new_guarded_file(tmp_dir.path(), GuardPolicy::BackOff);
+        file.enforce();
+        assert_eq!(
+            "nameserver 172.17.0.1\n",
+            fs::read_to_string(&file.config.path).unwrap()
+        );
+    }
+
+    #[test]
+    fn test_guard_backs_off_from_fight() {
+        let tmp_dir = tempfile::tempdir().unwrap();
+        let mut file = new_guarded_file(tmp_dir.path(), GuardPolicy::Reapply);
+        for _ in 0..MAX_REAPPLIES_IN_WINDOW {
+            fs::write(&file.config.path, "nameserver 172.17.0.1\n").unwrap();
+            file.enforce();
+        }
+        assert!(!file.gave_up);
+        fs::write(&file.config.path, "nameserver 172.17.0.1\n").unwrap();
+        file.enforce();
+        assert!(file.gave_up);
+        assert_eq!(
+            "nameserver 172.17.0.1\n",
+            fs::read_to_string(&file.config.path).unwrap()
+        );
+    }
+}
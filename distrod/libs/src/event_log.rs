@@ -0,0 +1,180 @@
+use anyhow::{Context, Result};
+use serde::{Deserialize, Serialize};
+
+/// The longest message written to an event. eventcreate rejects longer descriptions.
+const MAX_MESSAGE_LEN: usize = 4096;
+
+/// The level of an event in the Windows Event Log.
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum EventLevel {
+    Error,
+    Warning,
+    Information,
+}
+
+impl EventLevel {
+    /// Maps the syslog priority of a journal entry, from 0 (emerg) to 7 (debug).
+    pub fn from_priority(priority: u8) -> EventLevel {
+        match priority {
+            0..=3 => EventLevel::Error,
+            4 => EventLevel::Warning,
+            _ => EventLevel::Information,
+        }
+    }
+
+    /// The value of `/T` of eventcreate.
+    pub fn eventcreate_type(&self) -> &'static str {
+        match self {
+            EventLevel::Error => "ERROR",
+            EventLevel::Warning => "WARNING",
+            EventLevel::Information => "INFORMATION",
+        }
+    }
+
+    /// The event id, which is fixed for each level, so that Windows tools can filter and alert
+    /// on the errors by it.
+    pub fn event_id(&self) -> u16 {
+        match self {
+            EventLevel::Error => 1,
+            EventLevel::Warning => 2,
+            EventLevel::Information => 3,
+        }
+    }
+}
+
+/// An entry of the journal of a distro, which distrod passes to portproxy.exe on Windows as a
+/// line of JSON to be written to the Event Log.
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Eq)]
+pub struct EventLogEntry {
+    pub level: EventLevel,
+    pub distro: String,
+    pub unit: Option<String>,
+    pub message: String,
+}
+
+impl EventLogEntry {
+    /// Converts a line of `journalctl -o json`. Returns None if it has no message.
+    pub fn from_journal_json(line: &str, distro: &str) -> Option<EventLogEntry> {
+        let entry: serde_json::Value = serde_json::from_str(line).ok()?;
+        let message = get_journal_field(&entry, "MESSAGE")?;
+        let priority = get_journal_field(&entry, "PRIORITY")
+            .and_then(|priority| priority.parse::<u8>().ok())
+            .unwrap_or(6);
+        let unit = get_journal_field(&entry, "_SYSTEMD_UNIT")
+            .or_else(|| get_journal_field(&entry, "SYSLOG_IDENTIFIER"));
+        Some(EventLogEntry {
+            level: EventLevel::from_priority(priority),
+            distro: distro.to_owned(),
+            unit,
+            message,
+        })
+    }
+
+    /// Parses a line written by `to_json_line`.
+    pub fn from_json_line(line: &str) -> Result<EventLogEntry> {
+        serde_json::from_str(line).with_context(|| format!("Malformed entry: {}", line))
+    }
+
+    pub fn to_json_line(&self) -> Result<String> {
+        let mut line =
+            serde_json::to_string(self).with_context(|| "Failed to serialize the entry.")?;
+        line.push('\n');
+        Ok(line)
+    }
+
+    /// The description of the event, such as "[Ubuntu] nginx.service: Failed to start.".
+    pub fn description(&self) -> String {
+        let mut description = match self.unit {
+            Some(ref unit) => format!("[{}] {}: {}", self.distro, unit, self.message),
+            None => format!("[{}] {}", self.distro, self.message),
+        };
+        if description.len() > MAX_MESSAGE_LEN {
+            let mut end = MAX_MESSAGE_LEN;
+            while !description.is_char_boundary(end) {
+                end -= 1;
+            }
+            description.truncate(end);
+        }
+        description
+    }
+}
+
+/// Returns a field of a journal entry. journalctl writes the fields which aren't valid UTF-8 as
+/// arrays of bytes.
+fn get_journal_field(entry: &serde_json::Value, name: &str) -> Option<String> {
+    match entry.get(name)? {
+        serde_json::Value::String(value) => Some(value.clone()),
+        serde_json::Value::Array(bytes) => {
+            let bytes: Vec<u8> = bytes
+                .iter()
+                .filter_map(|byte| byte.as_u64().map(|byte| byte as u8))
+                .collect();
+            Some(String::from_utf8_lossy(&bytes).into_owned())
+        }
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod test_event_log {
+    use super::*;
+
+    #[test]
+    fn test_from_journal_json() {
+        let entry = EventLogEntry::from_journal_json(
+            r#"{"MESSAGE":"Failed to start A high performance web server.","PRIORITY":"3","_SYSTEMD_UNIT":"init.scope","SYSLOG_IDENTIFIER":"systemd"}"#,
+            "Ubuntu",
+        )
+        .unwrap();
+        assert_eq!(EventLevel::Error, entry.level);
+        assert_eq!(
+            "[Ubuntu] init.scope: Failed to start A high performance web server.",
+            entry.description()
+        );
+
+        let entry = EventLogEntry::from_journal_json(
+            r#"{"MESSAGE":[104,105,255],"PRIORITY":"4","SYSLOG_IDENTIFIER":"kernel"}"#,
+            "Ubuntu",
+        )
+        .unwrap();
+        assert_eq!(EventLevel::Warning, entry.level);
+        assert_eq!(Some("kernel".to_owned()), entry.unit);
+        assert_eq!("hi\u{fffd}", entry.message);
+
+        assert_eq!(
+            None,
+            EventLogEntry::from_journal_json(r#"{"PRIORITY":"3"}"#, "Ubuntu")
+        );
+        assert_eq!(None, EventLogEntry::from_journal_json("not json", "Ubuntu"));
+    }
+
+    #[test]
+    fn test_json_line() {
+        let entry = EventLogEntry {
+            level: EventLevel::Warning,
+            distro: "Ubuntu".to_owned(),
+            unit: Some("ssh.service".to_owned()),
+            message: "line1\nline2".to_owned(),
+        };
+        let line = entry.to_json_line().unwrap();
+        assert_eq!(1, line.lines().count());
+        assert_eq!(
+            entry,
+            EventLogEntry::from_json_line(line.trim_end()).unwrap()
+        );
+    }
+
+    #[test]
+    fn test_description_is_truncated() {
+        let entry = EventLogEntry {
+            level: EventLevel::Information,
+            distro: "Ubuntu".to_owned(),
+            unit: None,
+            message: "あ".repeat(MAX_MESSAGE_LEN),
+        };
+        let description = entry.description();
+        assert!(description.len() <= MAX_MESSAGE_LEN);
+        assert!(description.starts_with("[Ubuntu] あ"));
+    }
+}
@@ -0,0 +1,238 @@
+use anyhow::{anyhow, bail, Context, Result};
+use nix::poll::{PollFd, PollFlags};
+use nix::sys::socket::sockopt::PeerCredentials;
+use passfd::FdPassingExt;
+use serde::{Deserialize, Serialize};
+use std::ffi::OsString;
+use std::fs::{self, File};
+use std::io::{Read, Write};
+use std::os::unix::fs::PermissionsExt;
+use std::os::unix::io::{AsRawFd, FromRawFd, IntoRawFd};
+use std::os::unix::net::{UnixListener, UnixStream};
+use std::os::unix::prelude::CommandExt;
+use std::path::PathBuf;
+use std::process::{Command, Stdio};
+use std::time::{Duration, Instant};
+
+use crate::container::{Container, ContainerLauncher, ContainerPath, HostPath, Namespaces};
+use crate::distro::{
+    get_configured_exec_capability_drops, get_configured_seccomp_filter,
+    get_exec_broker_socket_path, Distro,
+};
+use crate::multifork::{reap_children, ProxyProcess, Waiter};
+use crate::passwd::{get_credential_from_passwd_file, Credential};
+use crate::procfile::ProcFile;
+use crate::seccomp::SeccompFilter;
+
+/// How often the broker checks if the distro is still running while no request comes.
+const INIT_CHECK_INTERVAL_MSEC: i32 = 1000;
+
+/// The command which `distrod exec` asks the broker to run.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct ExecRequest {
+    pub command: OsString,
+    pub args: Vec<OsString>,
+    pub arg0: Option<OsString>,
+    pub working_directory: Option<PathBuf>,
+    pub envs: Vec<(OsString, OsString)>,
+    pub uid: Option<u32>,
+}
+
+/// A long-running process which keeps the namespaces of a running distro open, and runs the
+/// commands requested by `distrod exec` in them. This saves `distrod exec` from looking up the
+/// distro and entering its namespaces by itself, which matters when tools run many short commands.
+pub struct ExecBroker {
+    listener: UnixListener,
+    socket_path: HostPath,
+    container: Container,
+    init_procfile: ProcFile,
+    namespaces: Namespaces,
+    rootfs: HostPath,
+    seccomp_filter: Option<SeccompFilter>,
+}
+
+impl ExecBroker {
+    pub fn bind(distro: &Distro) -> Result<ExecBroker> {
+        let socket_path = get_exec_broker_socket_path(distro.get_name())?;
+        if socket_path.exists() {
+            if UnixStream::connect(socket_path.as_path()).is_ok() {
+                bail!("The exec broker of the distro is already running.");
+            }
+            fs::remove_file(socket_path.as_path())
+                .with_context(|| format!("Failed to remove the stale {:?}.", &socket_path))?;
+        }
+        let listener = UnixListener::bind(socket_path.as_path())
+            .with_context(|| format!("Failed to bind {:?}.", &socket_path))?;
+        fs::set_permissions(socket_path.as_path(), fs::Permissions::from_mode(0o600))
+            .with_context(|| format!("Failed to set the permission of {:?}.", &socket_path))?;
+
+        let init_pid = distro.get_init_pid();
+        let rootfs = HostPath::new(distro.get_rootfs())?;
+        let mut container = ContainerLauncher::from_pid(init_pid)?;
+        container.with_dropped_exec_capabilities(
+            get_configured_exec_capability_drops(&rootfs)
+                .with_context(|| "Failed to get the capabilities to drop.")?,
+        );
+        let namespaces = container
+            .open_namespaces()
+            .with_context(|| "Failed to open the namespaces of the distro.")?;
+        let init_procfile =
+            ProcFile::from_pid(init_pid)?.with_context(|| "The init of the distro has exited.")?;
+        let seccomp_filter = get_configured_seccomp_filter(&rootfs)
+            .with_context(|| "Failed to get the seccomp profile of the distro.")?;
+        Ok(ExecBroker {
+            listener,
+            socket_path,
+            container,
+            init_procfile,
+            namespaces,
+            rootfs,
+            seccomp_filter,
+        })
+    }
+
+    /// Serves the requests until the distro stops.
+    pub fn run(&mut self) -> Result<()> {
+        log::info!("The exec broker is listening on {:?}.", &self.socket_path);
+        loop {
+            reap_children();
+            let mut fds = [PollFd::new(self.listener.as_raw_fd(), PollFlags::POLLIN)];
+            match nix::poll::poll(&mut fds, INIT_CHECK_INTERVAL_MSEC) {
+                Ok(0) => {
+                    if !self.init_procfile.is_live() {
+                        log::info!("The distro has stopped. The exec broker exits.");
+                        return Ok(());
+                    }
+                    continue;
+                }
+                Ok(_) => {}
+                Err(nix::Error::Sys(nix::errno::Errno::EINTR)) => continue,
+                Err(e) => return Err(e).with_context(|| "Failed to poll the socket."),
+            }
+            let (stream, _) = match self.listener.accept() {
+                Ok(accepted) => accepted,
+                Err(e) => {
+                    log::warn!("Failed to accept a connection. {:?}", e);
+                    continue;
+                }
+            };
+            if let Err(e) = self.serve(stream) {
+                log::warn!("Failed to serve an exec request. {:?}", e);
+            }
+        }
+    }
+
+    fn serve(&self, mut stream: UnixStream) -> Result<()> {
+        let peer = nix::sys::socket::getsockopt(stream.as_raw_fd(), PeerCredentials)
+            .with_context(|| "Failed to get the credential of the peer.")?;
+        if peer.uid() != 0 {
+            bail!("A non-root user (uid {}) sent a request.", peer.uid());
+        }
+
+        // The stdio of the client comes first, and then the length and the body of the request.
+        let mut stdio = vec![];
+        for _ in 0..3 {
+            let fd = stream
+                .recv_fd()
+                .with_context(|| "Failed to receive the stdio of the client.")?;
+            // Keep the command from inheriting the fds other than as its stdio.
+            nix::fcntl::fcntl(
+                fd,
+                nix::fcntl::FcntlArg::F_SETFD(nix::fcntl::FdFlag::FD_CLOEXEC),
+            )?;
+            stdio.push(unsafe { File::from_raw_fd(fd) });
+        }
+        let mut len = [0u8; 4];
+        stream.read_exact(&mut len)?;
+        let mut body = vec![0u8; u32::from_le_bytes(len) as usize];
+        stream.read_exact(&mut body)?;
+        let request: ExecRequest =
+            serde_json::from_slice(&body).with_context(|| "Failed to parse the request.")?;
+        log::debug!("Exec request: {:?}", &request.command);
+
+        let cred = request
+            .uid
+            .map(|uid| self.get_credential(uid))
+            .transpose()?;
+        let mut stdio = stdio.into_iter();
+        let mut command = Command::new(&request.command);
+        command
+            .args(&request.args)
+            .env_clear()
+            .envs(request.envs)
+            .stdin(Stdio::from(stdio.next().expect("three fds are received")))
+            .stdout(Stdio::from(stdio.next().expect("three fds are received")))
+            .stderr(Stdio::from(stdio.next().expect("three fds are received")));
+        if let Some(ref wd) = request.working_directory {
+            command.current_dir(wd);
+        }
+        if let Some(ref arg0) = request.arg0 {
+            command.arg0(arg0);
+        }
+        if let Some(filter) = self.seccomp_filter.clone() {
+            unsafe {
+                command.pre_exec(move || filter.apply());
+            }
+        }
+        let socket = unsafe { File::from_raw_fd(stream.into_raw_fd()) };
+        self.container.exec_command_with_proxy(
+            command,
+            cred.as_ref(),
+            Some(&self.namespaces),
+            ProxyProcess::from_exit_code_socket(socket),
+        )
+    }
+
+    /// The groups are taken from /etc/passwd of the distro. A uid which isn't there is refused
+    /// rather than given a made-up group, which may belong to someone else.
+    fn get_credential(&self, uid: u32) -> Result<Credential> {
+        let passwd_path = ContainerPath::new("/etc/passwd")?.to_host_path(&self.rootfs);
+        get_credential_from_passwd_file(None, Some(uid), passwd_path.as_path())
+            .with_context(|| format!("Failed to open the passwd file. {:?}", &passwd_path))?
+            .ok_or_else(|| anyhow!("The uid {} is not in /etc/passwd of the distro.", uid))
+    }
+}
+
+impl Drop for ExecBroker {
+    fn drop(&mut self) {
+        let _ = fs::remove_file(self.socket_path.as_path());
+    }
+}
+
+/// Sends the request to the broker of the distro with the stdio of this process, and returns
+/// the waiter of the command. None is returned if the broker isn't running.
+pub fn request_exec(name: Option<&str>, request: &ExecRequest) -> Result<Option<Waiter>> {
+    let socket_path = get_exec_broker_socket_path(name)?;
+    let mut stream = match UnixStream::connect(socket_path.as_path()) {
+        Ok(stream) => stream,
+        Err(e) => {
+            log::debug!("The exec broker is not available. {}", e);
+            return Ok(None);
+        }
+    };
+    for fd in 0..3 {
+        stream
+            .send_fd(fd)
+            .with_context(|| "Failed to send the stdio to the broker.")?;
+    }
+    let body = serde_json::to_vec(request)?;
+    stream.write_all(&(body.len() as u32).to_le_bytes())?;
+    stream.write_all(&body)?;
+    Ok(Some(Waiter::from_exit_code_reader(unsafe {
+        File::from_raw_fd(stream.into_raw_fd())
+    })))
+}
+
+/// Waits for the broker of a stopped distro to exit and remove its socket, so that a new broker
+/// can bind it. Returns false if the socket is still there after the timeout.
+pub fn wait_for_broker_exit(name: Option<&str>, timeout: Duration) -> Result<bool> {
+    let socket_path = get_exec_broker_socket_path(name)?;
+    let start = Instant::now();
+    while socket_path.exists() {
+        if start.elapsed() >= timeout {
+            return Ok(false);
+        }
+        std::thread::sleep(Duration::from_millis(100));
+    }
+    Ok(true)
+}
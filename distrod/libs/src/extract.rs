@@ -0,0 +1,361 @@
+use std::fs::{self, File};
+use std::io::{self, BufWriter, Read, Write};
+use std::path::{Component, Path, PathBuf};
+use std::sync::mpsc;
+use std::thread;
+
+use crate::cancellation::CancellationToken;
+use crate::container::{ContainerPath, HostPath};
+use anyhow::{Context, Result};
+use xz2::read::XzDecoder;
+
+/// The path of the list of the paths skipped by ExtractFilter in the rootfs, so that later
+/// checks of the rootfs can tell the skipped files from the broken ones.
+pub const SKIPPED_PATHS_MANIFEST_PATH: &str = "/etc/distrod/skipped_paths";
+
+/// The size of the decompressed chunks passed from the decoder thread.
+const DECODED_CHUNK_SIZE: usize = 1024 * 1024;
+/// The number of the decompressed chunks decoded ahead of the unpacking, which bounds the
+/// memory used when writing the files is slower than decoding.
+const DECODED_CHUNKS_AHEAD: usize = 16;
+
+/// Decides which paths of an image are extracted.
+#[derive(Debug, Default)]
+pub struct ExtractFilter {
+    exclude: Vec<glob::Pattern>,
+    include: Vec<glob::Pattern>,
+}
+
+impl ExtractFilter {
+    pub fn new<S: AsRef<str>>(exclude: &[S], include: &[S]) -> Result<ExtractFilter> {
+        let compile = |patterns: &[S]| -> Result<Vec<glob::Pattern>> {
+            patterns
+                .iter()
+                .map(|pattern| {
+                    glob::Pattern::new(pattern.as_ref())
+                        .with_context(|| format!("Invalid pattern: '{}'.", pattern.as_ref()))
+                })
+                .collect()
+        };
+        Ok(ExtractFilter {
+            exclude: compile(exclude)?,
+            include: compile(include)?,
+        })
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.exclude.is_empty()
+    }
+
+    /// Whether the path in the image, such as "./usr/share/doc/bash", is skipped.
+    /// A path is skipped if it matches an exclude pattern and none of the include patterns.
+    pub fn skips(&self, path: &Path) -> bool {
+        let path = to_absolute_path(path);
+        let options = glob::MatchOptions {
+            require_literal_separator: true,
+            ..Default::default()
+        };
+        let matches = |pattern: &glob::Pattern| pattern.matches_path_with(&path, options);
+        self.exclude.iter().any(matches) && !self.include.iter().any(matches)
+    }
+}
+
+/// Makes the path in the image absolute, such as "./usr/share/doc/" into "/usr/share/doc", so that
+/// the patterns match the directories, whose paths end with `/` in the image, as well.
+fn to_absolute_path(path: &Path) -> PathBuf {
+    let mut absolute = PathBuf::from("/");
+    absolute.extend(
+        path.components()
+            .filter(|component| *component != Component::CurDir),
+    );
+    absolute
+}
+
+/// Unpacks a .tar.xz distro image into `install_dir`, checking `cancel` between the entries.
+/// Returns the paths skipped by `filter`. The image is read and decompressed on another thread
+/// while the files are written on this one.
+pub fn unpack_image<R: Read + Send + 'static>(
+    tar_xz: R,
+    install_dir: &Path,
+    filter: &ExtractFilter,
+    cancel: &CancellationToken,
+) -> Result<Vec<PathBuf>> {
+    let tar = BackgroundXzDecoder::new(tar_xz);
+    let mut archive = tar::Archive::new(tar);
+    archive.set_preserve_permissions(true);
+    archive.set_unpack_xattrs(true);
+
+    // Unpack the directories last as tar::Archive::unpack does, so that their permissions
+    // don't prevent the files in them from being unpacked.
+    let mut directories = vec![];
+    let mut skipped_directories = vec![];
+    let mut skipped_paths = vec![];
+    for entry in archive
+        .entries()
+        .with_context(|| "Failed to read the entries of the image.")?
+    {
+        cancel.check()?;
+        let mut entry = entry.with_context(|| "An entry of the image is broken.")?;
+        let path = entry.path()?.into_owned();
+        if entry.header().entry_type() == tar::EntryType::Directory {
+            if filter.skips(&path) {
+                skipped_directories.push((path, entry));
+            } else {
+                directories.push(entry);
+            }
+            continue;
+        }
+        // A hard link to a skipped file cannot be made, so skip it as well.
+        let links_to_skipped = entry.header().entry_type() == tar::EntryType::Link
+            && entry
+                .link_name()?
+                .map_or(false, |link_name| filter.skips(&link_name));
+        if filter.skips(&path) || links_to_skipped {
+            skipped_paths.push(to_absolute_path(&path));
+            continue;
+        }
+        entry
+            .unpack_in(install_dir)
+            .with_context(|| format!("Failed to unpack {:?}.", &path))?;
+    }
+    for mut directory in directories {
+        cancel.check()?;
+        directory
+            .unpack_in(install_dir)
+            .with_context(|| format!("Failed to unpack {:?}.", directory.path()))?;
+    }
+    // A skipped directory which has an included entry has been made for the entry, so it gets its
+    // permissions from the image as well.
+    for (path, mut directory) in skipped_directories {
+        cancel.check()?;
+        if !install_dir.join(&path).is_dir() {
+            skipped_paths.push(to_absolute_path(&path));
+            continue;
+        }
+        directory
+            .unpack_in(install_dir)
+            .with_context(|| format!("Failed to unpack {:?}.", &path))?;
+    }
+    Ok(skipped_paths)
+}
+
+/// Decompresses an xz stream on a thread of its own, so that decoding the next entries of an
+/// image overlaps writing the files of the current one. The decoding itself is still done by a
+/// single thread, since xz2 doesn't provide the multi-threaded decoder of liblzma.
+struct BackgroundXzDecoder {
+    receiver: mpsc::Receiver<io::Result<Vec<u8>>>,
+    chunk: Vec<u8>,
+    pos: usize,
+    is_done: bool,
+}
+
+impl BackgroundXzDecoder {
+    fn new<R: Read + Send + 'static>(xz: R) -> BackgroundXzDecoder {
+        let (sender, receiver) = mpsc::sync_channel(DECODED_CHUNKS_AHEAD);
+        thread::spawn(move || {
+            let mut decoder = XzDecoder::new(xz);
+            loop {
+                let mut chunk = vec![0; DECODED_CHUNK_SIZE];
+                let result = read_full(&mut decoder, &mut chunk).map(|len| {
+                    chunk.truncate(len);
+                    chunk
+                });
+                let is_last = !matches!(result, Ok(ref chunk) if !chunk.is_empty());
+                // Sending fails once the unpacking has stopped, such as by a cancellation.
+                if sender.send(result).is_err() || is_last {
+                    return;
+                }
+            }
+        });
+        BackgroundXzDecoder {
+            receiver,
+            chunk: vec![],
+            pos: 0,
+            is_done: false,
+        }
+    }
+}
+
+impl Read for BackgroundXzDecoder {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        while self.pos == self.chunk.len() {
+            if self.is_done {
+                return Ok(0);
+            }
+            match self.receiver.recv() {
+                Ok(Ok(chunk)) => {
+                    self.is_done = chunk.is_empty();
+                    self.chunk = chunk;
+                    self.pos = 0;
+                }
+                Ok(Err(e)) => {
+                    self.is_done = true;
+                    return Err(e);
+                }
+                Err(_) => {
+                    self.is_done = true;
+                    return Err(io::Error::new(
+                        io::ErrorKind::Other,
+                        "The decoder thread of the image has stopped.",
+                    ));
+                }
+            }
+        }
+        let len = std::cmp::min(buf.len(), self.chunk.len() - self.pos);
+        buf[..len].copy_from_slice(&self.chunk[self.pos..self.pos + len]);
+        self.pos += len;
+        Ok(len)
+    }
+}
+
+/// Reads until `buf` is full or the end of `reader`, returning the length read.
+fn read_full<R: Read>(reader: &mut R, buf: &mut [u8]) -> io::Result<usize> {
+    let mut len = 0;
+    while len < buf.len() {
+        match reader.read(&mut buf[len..]) {
+            Ok(0) => break,
+            Ok(n) => len += n,
+            Err(e) if e.kind() == io::ErrorKind::Interrupted => continue,
+            Err(e) => return Err(e),
+        }
+    }
+    Ok(len)
+}
+
+/// Writes the list of the skipped paths in the rootfs.
+pub fn write_skipped_paths_manifest(rootfs: &HostPath, skipped_paths: &[PathBuf]) -> Result<()> {
+    let manifest_path = ContainerPath::new(SKIPPED_PATHS_MANIFEST_PATH)?.to_host_path(rootfs);
+    if let Some(dir) = manifest_path.parent() {
+        fs::create_dir_all(dir).with_context(|| format!("Failed to create {:?}.", dir))?;
+    }
+    let mut manifest = BufWriter::new(
+        File::create(manifest_path.as_path())
+            .with_context(|| format!("Failed to create {:?}.", &manifest_path))?,
+    );
+    writeln!(
+        manifest,
+        "# The paths skipped by the [extract] config when this distro was created."
+    )?;
+    for path in skipped_paths {
+        writeln!(manifest, "{}", path.to_string_lossy())?;
+    }
+    manifest
+        .flush()
+        .with_context(|| format!("Failed to write {:?}.", &manifest_path))?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod test_extract {
+    use super::*;
+    use std::os::unix::fs::PermissionsExt;
+    use xz2::write::XzEncoder;
+
+    #[test]
+    fn test_extract_filter() {
+        let filter = ExtractFilter::new(
+            &["/usr/share/doc/**", "/usr/share/locale/**"],
+            &["/usr/share/locale/en*/**"],
+        )
+        .unwrap();
+        assert!(!filter.is_empty());
+        assert!(filter.skips(Path::new("./usr/share/doc/bash/README")));
+        assert!(filter.skips(Path::new("./usr/share/doc/bash/")));
+        assert!(filter.skips(Path::new("./usr/share/locale/de/LC_MESSAGES/bash.mo")));
+        assert!(!filter.skips(Path::new("./usr/share/locale/en_GB/LC_MESSAGES/bash.mo")));
+        assert!(!filter.skips(Path::new("./usr/bin/bash")));
+        // `*` doesn't match `/`.
+        let filter = ExtractFilter::new(&["/usr/share/doc/*"], &[]).unwrap();
+        assert!(filter.skips(Path::new("./usr/share/doc/bash")));
+        assert!(!filter.skips(Path::new("./usr/share/doc/bash/README")));
+
+        assert!(ExtractFilter::new(&[] as &[&str], &[]).unwrap().is_empty());
+        assert!(ExtractFilter::new(&["/usr/[share"], &[]).is_err());
+    }
+
+    #[test]
+    fn test_unpack_image_with_filter() {
+        let mut builder = tar::Builder::new(vec![]);
+        let mut append = |path: &str, mode: u32, data: Option<&[u8]>| {
+            let mut header = tar::Header::new_gnu();
+            header.set_mode(mode);
+            match data {
+                Some(data) => {
+                    header.set_entry_type(tar::EntryType::Regular);
+                    header.set_size(data.len() as u64);
+                    builder.append_data(&mut header, path, data).unwrap();
+                }
+                None => {
+                    header.set_entry_type(tar::EntryType::Directory);
+                    header.set_size(0);
+                    builder.append_data(&mut header, path, io::empty()).unwrap();
+                }
+            }
+        };
+        append("usr/", 0o755, None);
+        append("usr/bin/", 0o755, None);
+        append("usr/bin/bash", 0o755, Some(b"bash"));
+        append("usr/share/", 0o755, None);
+        append("usr/share/doc/", 0o755, None);
+        append("usr/share/doc/bash/", 0o755, None);
+        append("usr/share/doc/bash/README", 0o644, Some(b"readme"));
+        append("usr/share/locale/", 0o755, None);
+        append("usr/share/locale/de/", 0o755, None);
+        append("usr/share/locale/en_GB/", 0o750, None);
+        append("usr/share/locale/en_GB/bash.mo", 0o644, Some(b"mo"));
+        let tar = builder.into_inner().unwrap();
+        let mut encoder = XzEncoder::new(vec![], 1);
+        encoder.write_all(&tar).unwrap();
+        let xz = encoder.finish().unwrap();
+
+        let install_dir = tempfile::tempdir().unwrap();
+        let filter = ExtractFilter::new(
+            &["/usr/share/doc/**", "/usr/share/locale/**"],
+            &["/usr/share/locale/en*/**"],
+        )
+        .unwrap();
+        let skipped_paths = unpack_image(
+            io::Cursor::new(xz),
+            install_dir.path(),
+            &filter,
+            &CancellationToken::new(),
+        )
+        .unwrap();
+
+        let root = install_dir.path();
+        assert!(root.join("usr/bin/bash").is_file());
+        // The excluded directories are not made empty.
+        assert!(!root.join("usr/share/doc/bash").exists());
+        assert!(!root.join("usr/share/locale/de").exists());
+        assert!(root.join("usr/share/locale/en_GB/bash.mo").is_file());
+        assert_eq!(
+            0o750,
+            fs::metadata(root.join("usr/share/locale/en_GB"))
+                .unwrap()
+                .permissions()
+                .mode()
+                & 0o777
+        );
+        assert!(skipped_paths.contains(&PathBuf::from("/usr/share/doc/bash")));
+        assert!(skipped_paths.contains(&PathBuf::from("/usr/share/doc/bash/README")));
+        assert!(skipped_paths.contains(&PathBuf::from("/usr/share/locale/de")));
+        assert!(!skipped_paths.contains(&PathBuf::from("/usr/share/locale/en_GB/bash.mo")));
+    }
+
+    #[test]
+    fn test_background_xz_decoder() {
+        // Over two chunks, so that the data goes across the chunks.
+        let data: Vec<u8> = (0..DECODED_CHUNK_SIZE * 2 + 12345)
+            .map(|i| (i * 7 % 251) as u8)
+            .collect();
+        let mut encoder = XzEncoder::new(vec![], 1);
+        encoder.write_all(&data).unwrap();
+        let xz = encoder.finish().unwrap();
+
+        let mut decoded = vec![];
+        BackgroundXzDecoder::new(io::Cursor::new(xz))
+            .read_to_end(&mut decoded)
+            .unwrap();
+        assert_eq!(data, decoded);
+    }
+}
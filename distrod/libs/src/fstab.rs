@@ -0,0 +1,212 @@
+use anyhow::{Context, Result};
+use std::collections::{HashMap, HashSet};
+use std::fmt;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use crate::container::{ContainerPath, HostPath};
+use crate::mount_info::get_mount_entries;
+
+const FSTAB_PATH: &str = "/etc/fstab";
+const FSTAB_BACKUP_PATH: &str = "/etc/fstab.distrod-backup";
+const DISABLED_LINE_MARK: &str = "# Disabled by Distrod: ";
+
+/// A line of /etc/fstab which breaks the boot or the mounts of WSL.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct FstabFix {
+    /// The 1-based line number in /etc/fstab.
+    pub line_number: usize,
+    pub line: String,
+    pub problem: FstabProblem,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum FstabProblem {
+    /// WSL manages the swap by itself.
+    Swap,
+    /// The device doesn't exist on WSL, such as a disk of the machine the fstab is copied from.
+    MissingDevice(String),
+    /// WSL has already mounted something there, such as /mnt/c.
+    MountedByWsl(PathBuf),
+    /// Another line mounts the same path.
+    DuplicateTarget { first_line_number: usize },
+}
+
+impl fmt::Display for FstabProblem {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            FstabProblem::Swap => write!(f, "swap is managed by WSL"),
+            FstabProblem::MissingDevice(device) => write!(f, "{} does not exist", device),
+            FstabProblem::MountedByWsl(path) => write!(f, "{:?} is mounted by WSL", path),
+            FstabProblem::DuplicateTarget { first_line_number } => {
+                write!(f, "the path is also mounted by line {}", first_line_number)
+            }
+        }
+    }
+}
+
+/// Comments out the lines of /etc/fstab of the distro which don't work on WSL, and returns them.
+/// The original file is backed up to /etc/fstab.distrod-backup the first time it's changed.
+pub fn fix_fstab(rootfs: &HostPath) -> Result<Vec<FstabFix>> {
+    let fstab_path = ContainerPath::new(FSTAB_PATH)?.to_host_path(rootfs);
+    if !fstab_path.exists() {
+        return Ok(vec![]);
+    }
+    let fstab = fs::read_to_string(fstab_path.as_path())
+        .with_context(|| format!("Failed to read {:?}.", &fstab_path))?;
+    let wsl_mounts = get_mount_entries()
+        .with_context(|| "Failed to get the mount entries.")?
+        .into_iter()
+        .filter(|entry| entry.fstype == "9p" || entry.fstype == "drvfs")
+        .map(|entry| entry.path)
+        .collect();
+    let fixes = find_problems(&fstab, &wsl_mounts, device_exists);
+    if fixes.is_empty() {
+        return Ok(fixes);
+    }
+
+    let backup_path = ContainerPath::new(FSTAB_BACKUP_PATH)?.to_host_path(rootfs);
+    if !backup_path.exists() {
+        fs::copy(fstab_path.as_path(), backup_path.as_path())
+            .with_context(|| format!("Failed to back up {:?}.", &fstab_path))?;
+    }
+    fs::write(fstab_path.as_path(), comment_out_lines(&fstab, &fixes))
+        .with_context(|| format!("Failed to write {:?}.", &fstab_path))?;
+    Ok(fixes)
+}
+
+fn find_problems<F>(fstab: &str, wsl_mounts: &HashSet<PathBuf>, device_exists: F) -> Vec<FstabFix>
+where
+    F: Fn(&str) -> bool,
+{
+    let mut fixes = vec![];
+    let mut targets = HashMap::new();
+    for (i, line) in fstab.lines().enumerate() {
+        let line_number = i + 1;
+        let fields: Vec<&str> = line.split_whitespace().collect();
+        if fields.len() < 3 || fields[0].starts_with('#') {
+            continue;
+        }
+        let (source, target, fstype) = (fields[0], Path::new(fields[1]), fields[2]);
+        let options: Vec<&str> = fields.get(3).map_or(vec![], |o| o.split(',').collect());
+        let problem = if fstype == "swap" {
+            Some(FstabProblem::Swap)
+        } else if !options.contains(&"nofail")
+            && !options.contains(&"noauto")
+            && is_device(source)
+            && !device_exists(source)
+        {
+            Some(FstabProblem::MissingDevice(source.to_owned()))
+        } else if wsl_mounts.contains(target) {
+            Some(FstabProblem::MountedByWsl(target.to_owned()))
+        } else if let Some(first_line_number) = targets.get(target) {
+            Some(FstabProblem::DuplicateTarget {
+                first_line_number: *first_line_number,
+            })
+        } else {
+            targets.insert(target.to_owned(), line_number);
+            None
+        };
+        if let Some(problem) = problem {
+            fixes.push(FstabFix {
+                line_number,
+                line: line.to_owned(),
+                problem,
+            });
+        }
+    }
+    fixes
+}
+
+fn is_device(source: &str) -> bool {
+    source.starts_with("/dev/") || get_device_link_path(source).is_some()
+}
+
+fn get_device_link_path(source: &str) -> Option<PathBuf> {
+    let (tag, value) = source.split_once('=')?;
+    let dir = match tag {
+        "UUID" => "/dev/disk/by-uuid",
+        "LABEL" => "/dev/disk/by-label",
+        "PARTUUID" => "/dev/disk/by-partuuid",
+        "PARTLABEL" => "/dev/disk/by-partlabel",
+        _ => return None,
+    };
+    Some(Path::new(dir).join(value.trim_matches('"')))
+}
+
+fn device_exists(source: &str) -> bool {
+    match get_device_link_path(source) {
+        Some(path) => path.exists(),
+        None => Path::new(source).exists(),
+    }
+}
+
+fn comment_out_lines(fstab: &str, fixes: &[FstabFix]) -> String {
+    let fixes: HashMap<_, _> = fixes.iter().map(|fix| (fix.line_number, fix)).collect();
+    let mut result = String::new();
+    for (i, line) in fstab.lines().enumerate() {
+        match fixes.get(&(i + 1)) {
+            Some(fix) => result.push_str(&format!(
+                "{}{}\n#{}\n",
+                DISABLED_LINE_MARK, fix.problem, line
+            )),
+            None => result.push_str(&format!("{}\n", line)),
+        }
+    }
+    result
+}
+
+#[cfg(test)]
+mod test_fstab {
+    use super::*;
+
+    const FSTAB: &str = "\
+# <file system> <mount point> <type> <options> <dump> <pass>
+UUID=1234-abcd / ext4 errors=remount-ro 0 1
+/dev/sdb1 /data ext4 defaults 0 2
+/dev/sdc1 /backup ext4 defaults,nofail 0 2
+/swapfile none swap sw 0 0
+C: /mnt/c drvfs defaults 0 0
+tmpfs /tmp tmpfs defaults 0 0
+tmpfs /tmp tmpfs defaults 0 0
+";
+
+    #[test]
+    fn test_find_problems() {
+        let wsl_mounts = vec![PathBuf::from("/mnt/c")].into_iter().collect();
+        let fixes = find_problems(FSTAB, &wsl_mounts, |source| source == "/dev/sdb1");
+        let problems: Vec<_> = fixes
+            .iter()
+            .map(|fix| (fix.line_number, fix.problem.clone()))
+            .collect();
+        assert_eq!(
+            vec![
+                (2, FstabProblem::MissingDevice("UUID=1234-abcd".to_owned())),
+                (5, FstabProblem::Swap),
+                (6, FstabProblem::MountedByWsl(PathBuf::from("/mnt/c"))),
+                (
+                    8,
+                    FstabProblem::DuplicateTarget {
+                        first_line_number: 7
+                    }
+                ),
+            ],
+            problems
+        );
+    }
+
+    #[test]
+    fn test_comment_out_lines() {
+        let fixes = find_problems(FSTAB, &HashSet::new(), |_| true);
+        let fixed = comment_out_lines(FSTAB, &fixes);
+        assert!(
+            fixed.contains("# Disabled by Distrod: swap is managed by WSL\n#/swapfile none swap")
+        );
+        assert!(fixed.contains("\n/dev/sdb1 /data ext4 defaults 0 2\n"));
+        // The fixed lines are not detected again.
+        assert_eq!(
+            Vec::<FstabFix>::new(),
+            find_problems(&fixed, &HashSet::new(), |_| true)
+        );
+    }
+}
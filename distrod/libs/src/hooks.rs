@@ -0,0 +1,110 @@
+use anyhow::{Context, Result};
+use std::fs;
+use std::os::linux::fs::MetadataExt;
+use std::path::{Path, PathBuf};
+
+use crate::container::{ContainerPath, HostPath};
+
+const HOOKS_DIR_PATH: &str = "/etc/distrod/hooks";
+
+/// The points of the lifecycle of a distro where the executables in
+/// /etc/distrod/hooks/<point>.d/ are run.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum HookPoint {
+    /// Run outside the container before the distro starts, such as to mount network shares.
+    PreStart,
+    /// Run inside the distro after it has started, such as to start VPN clients.
+    PostStart,
+    /// Run inside the distro before it stops.
+    PreStop,
+}
+
+impl HookPoint {
+    pub fn name(&self) -> &'static str {
+        match self {
+            HookPoint::PreStart => "pre-start",
+            HookPoint::PostStart => "post-start",
+            HookPoint::PreStop => "pre-stop",
+        }
+    }
+
+    pub fn get_dir_path(&self) -> Result<ContainerPath> {
+        ContainerPath::new(Path::new(HOOKS_DIR_PATH).join(format!("{}.d", self.name())))
+    }
+}
+
+/// Returns the hooks of the distro at the point, in the order of their names.
+pub fn list_hooks(rootfs: &HostPath, point: HookPoint) -> Result<Vec<ContainerPath>> {
+    let dir = point.get_dir_path()?;
+    let hooks = list_executables(dir.to_host_path(rootfs).as_path())?;
+    hooks
+        .into_iter()
+        .map(|hook| {
+            ContainerPath::new(
+                dir.as_path()
+                    .join(hook.file_name().expect("read_dir returns named entries")),
+            )
+        })
+        .collect()
+}
+
+/// Lists the executables in the directory which only root or the effective user can modify.
+/// The other files are skipped with a warning, since distrod-exec runs the hooks with the setuid bit.
+fn list_executables(dir: &Path) -> Result<Vec<PathBuf>> {
+    if !dir.exists() {
+        return Ok(vec![]);
+    }
+    let euid = nix::unistd::geteuid().as_raw();
+    let mut executables = vec![];
+    for entry in fs::read_dir(dir).with_context(|| format!("Failed to read {:?}.", dir))? {
+        let entry = entry?;
+        let path = entry.path();
+        let metadata = fs::metadata(&path)
+            .with_context(|| format!("Failed to get the metadata of {:?}.", &path))?;
+        if !metadata.is_file() || metadata.st_mode() & 0o111 == 0 {
+            log::debug!("{:?} is not an executable. Skipping it.", &path);
+            continue;
+        }
+        if (metadata.st_uid() != 0 && metadata.st_uid() != euid) || metadata.st_mode() & 0o022 != 0
+        {
+            log::warn!(
+                "{:?} can be modified by other users. Skipping it for safety.",
+                &path
+            );
+            continue;
+        }
+        executables.push(path);
+    }
+    executables.sort();
+    Ok(executables)
+}
+
+#[cfg(test)]
+mod test_hooks {
+    use super::*;
+    use std::os::unix::fs::PermissionsExt;
+
+    fn create_file(path: &Path, mode: u32) {
+        fs::write(path, "#!/bin/sh\n").unwrap();
+        fs::set_permissions(path, fs::Permissions::from_mode(mode)).unwrap();
+    }
+
+    #[test]
+    fn test_list_executables() {
+        let tmp_dir = tempfile::tempdir().unwrap();
+        let dir = tmp_dir.path();
+        create_file(&dir.join("20-vpn"), 0o755);
+        create_file(&dir.join("10-mount"), 0o700);
+        create_file(&dir.join("README"), 0o644);
+        create_file(&dir.join("30-writable"), 0o777);
+        fs::create_dir(dir.join("40-dir")).unwrap();
+
+        assert_eq!(
+            vec![dir.join("10-mount"), dir.join("20-vpn")],
+            list_executables(dir).unwrap()
+        );
+        assert!(list_executables(&dir.join("nonexistent"))
+            .unwrap()
+            .is_empty());
+    }
+}
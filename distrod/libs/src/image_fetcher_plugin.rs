@@ -0,0 +1,373 @@
+use anyhow::{Context, Result};
+use async_trait::async_trait;
+use serde::{Deserialize, Serialize};
+use std::io::Write;
+use std::os::unix::fs::PermissionsExt;
+use std::os::unix::prelude::MetadataExt;
+use std::path::{Path, PathBuf};
+use std::process::{Command, Stdio};
+use std::sync::Arc;
+
+use crate::distro_image::{
+    DefaultImageFetcher, DistroImage, DistroImageFetcher, DistroImageFetcherGen, DistroImageFile,
+    DistroImageList,
+};
+use crate::error::ImageError;
+
+/// The version of the contract between Distrod and the plugins, which is sent in every request.
+pub const PLUGIN_PROTOCOL_VERSION: u32 = 1;
+
+/// An executable which lists images for `distrod create`, such as the ones of a company mirror.
+/// Distrod runs it once per request, writes the request in JSON on its stdin, and reads the
+/// response in JSON from its stdout. The stderr is shown to the user as it is.
+#[derive(Clone, Debug)]
+pub struct ImageFetcherPlugin {
+    /// The file name of the executable, by which `distrod create --image-plugin` chooses it.
+    pub name: String,
+    pub path: PathBuf,
+}
+
+#[derive(Serialize, Debug, PartialEq)]
+#[serde(tag = "request", rename_all = "snake_case")]
+pub enum PluginRequest {
+    /// Asks the name and the description shown in the list of the ways to get an image.
+    Describe { version: u32 },
+    /// Asks the list under the path of the names chosen so far, or the image at the path.
+    List { version: u32, path: Vec<String> },
+}
+
+#[derive(Deserialize, Debug, PartialEq)]
+#[serde(deny_unknown_fields)]
+pub struct PluginDescription {
+    pub name: String,
+    pub description: Option<String>,
+}
+
+#[derive(Deserialize, Debug, PartialEq)]
+#[serde(rename_all = "snake_case", deny_unknown_fields)]
+pub enum PluginListResponse {
+    List(PluginImageList),
+    Image(PluginImage),
+}
+
+#[derive(Deserialize, Debug, PartialEq)]
+#[serde(deny_unknown_fields)]
+pub struct PluginImageList {
+    /// What the items are, such as "a release of Ubuntu", shown in the prompt.
+    pub kind: String,
+    pub items: Vec<PluginListItem>,
+    /// The name of the item chosen by default. Defaults to the first one.
+    pub default: Option<String>,
+}
+
+#[derive(Deserialize, Debug, PartialEq)]
+#[serde(deny_unknown_fields)]
+pub struct PluginListItem {
+    pub name: String,
+    pub description: Option<String>,
+}
+
+/// A rootfs image in .tar.xz, given by either `url` or `file`.
+#[derive(Deserialize, Debug, PartialEq)]
+#[serde(deny_unknown_fields)]
+pub struct PluginImage {
+    /// The name of the image, which is the default name of the new distro.
+    pub name: String,
+    pub url: Option<String>,
+    /// The absolute path to a local file, such as the one the plugin has downloaded from S3.
+    pub file: Option<PathBuf>,
+}
+
+impl ImageFetcherPlugin {
+    pub fn new<P: AsRef<Path>>(path: P) -> ImageFetcherPlugin {
+        let path = path.as_ref().to_owned();
+        ImageFetcherPlugin {
+            name: path
+                .file_name()
+                .map(|name| name.to_string_lossy().into_owned())
+                .unwrap_or_default(),
+            path,
+        }
+    }
+
+    pub fn describe(&self) -> Result<PluginDescription> {
+        let response = self.request(&PluginRequest::Describe {
+            version: PLUGIN_PROTOCOL_VERSION,
+        })?;
+        serde_json::from_slice(&response).map_err(|e| self.error(format!("{}", e)).into())
+    }
+
+    pub fn list(&self, path: &[String]) -> Result<PluginListResponse> {
+        let response = self.request(&PluginRequest::List {
+            version: PLUGIN_PROTOCOL_VERSION,
+            path: path.to_vec(),
+        })?;
+        let response: PluginListResponse =
+            serde_json::from_slice(&response).map_err(|e| self.error(format!("{}", e)))?;
+        if let PluginListResponse::Image(ref image) = response {
+            image.validate().map_err(|message| self.error(message))?;
+        }
+        Ok(response)
+    }
+
+    fn request(&self, request: &PluginRequest) -> Result<Vec<u8>> {
+        log::debug!("Requesting {:?} to {:?}.", request, &self.path);
+        let mut child = Command::new(&self.path)
+            .stdin(Stdio::piped())
+            .stdout(Stdio::piped())
+            .spawn()
+            .with_context(|| format!("Failed to run the plugin {:?}.", &self.path))?;
+        let mut stdin = child.stdin.take().expect("[BUG] stdin is piped.");
+        serde_json::to_writer(&mut stdin, request)?;
+        // The plugin may exit without reading the request, so the error is left to the status.
+        let _ = stdin.write_all(b"\n");
+        drop(stdin);
+        let output = child
+            .wait_with_output()
+            .with_context(|| format!("Failed to wait for the plugin {:?}.", &self.path))?;
+        if !output.status.success() {
+            return Err(self
+                .error(format!("It exited with {}.", output.status))
+                .into());
+        }
+        Ok(output.stdout)
+    }
+
+    fn error(&self, message: String) -> ImageError {
+        ImageError::Plugin {
+            plugin: self.name.clone(),
+            message,
+        }
+    }
+}
+
+impl PluginImage {
+    fn validate(&self) -> std::result::Result<(), String> {
+        match (&self.url, &self.file) {
+            (Some(_), None) => Ok(()),
+            (None, Some(file)) if file.is_absolute() => Ok(()),
+            (None, Some(file)) => Err(format!(
+                "The file of the image is not absolute: {:?}.",
+                file
+            )),
+            _ => Err("An image should have either url or file.".to_owned()),
+        }
+    }
+}
+
+/// Lists the plugins in the directory, which are the executables owned by root that only root can
+/// write, since Distrod runs them as root. The others are skipped with warnings.
+pub fn list_plugins<P: AsRef<Path>>(dir: P) -> Result<Vec<ImageFetcherPlugin>> {
+    let dir = dir.as_ref();
+    let entries = match std::fs::read_dir(dir) {
+        Ok(entries) => entries,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(vec![]),
+        Err(e) => return Err(e).with_context(|| format!("Failed to read {:?}.", dir)),
+    };
+    let mut plugins = vec![];
+    for entry in entries {
+        let path = entry
+            .with_context(|| format!("Failed to read {:?}.", dir))?
+            .path();
+        let metadata = std::fs::metadata(&path)
+            .with_context(|| format!("Failed to get the metadata of {:?}.", &path))?;
+        let mode = metadata.permissions().mode();
+        if !metadata.is_file() || mode & 0o111 == 0 {
+            continue;
+        }
+        if metadata.uid() != 0 || mode & 0o022 != 0 {
+            log::warn!(
+                "Skipping the image fetcher plugin {:?}, which is not owned by root or is \
+                 writable by others.",
+                &path
+            );
+            continue;
+        }
+        plugins.push(ImageFetcherPlugin::new(path));
+    }
+    plugins.sort_by(|a, b| a.name.cmp(&b.name));
+    Ok(plugins)
+}
+
+/// Returns the fetchers of the plugins in the directory for `distro_image::fetch_image`. The
+/// plugins which fail to describe themselves are skipped with warnings, so that a broken plugin
+/// doesn't keep the others from being used.
+pub fn get_plugin_fetchers<P: AsRef<Path>>(dir: P) -> Result<Vec<DistroImageFetcherGen>> {
+    let mut fetchers = vec![];
+    for plugin in list_plugins(dir)? {
+        let fetcher = match PluginImageFetcher::new(plugin) {
+            Ok(fetcher) => fetcher,
+            Err(e) => {
+                log::warn!("Skipping an image fetcher plugin. {:?}", e);
+                continue;
+            }
+        };
+        fetchers.push(
+            Box::new(move || Ok(Box::new(fetcher.clone()) as Box<dyn DistroImageFetcher>))
+                as DistroImageFetcherGen,
+        );
+    }
+    Ok(fetchers)
+}
+
+/// Fetches the image at the path of the plugin without prompts, such as "ubuntu/jammy". The
+/// defaults of the lists are taken once the names of the path run out.
+pub async fn fetch_plugin_image(plugin: ImageFetcherPlugin, path: &str) -> Result<DistroImage> {
+    let choose = crate::distro_image::choose_by_path(path);
+    let mut fetcher = Box::new(PluginImageFetcher::new(plugin)?) as Box<dyn DistroImageFetcher>;
+    loop {
+        match fetcher.fetch().await? {
+            list @ DistroImageList::Fetcher(_, _, _) => fetcher = choose(list)?,
+            DistroImageList::Image(image) => return Ok(image),
+        }
+    }
+}
+
+/// A node of the tree of the images which a plugin lists, at the path of the names chosen so far.
+#[derive(Clone)]
+struct PluginImageFetcher {
+    plugin: Arc<ImageFetcherPlugin>,
+    name: String,
+    description: Option<String>,
+    path: Vec<String>,
+}
+
+impl PluginImageFetcher {
+    fn new(plugin: ImageFetcherPlugin) -> Result<PluginImageFetcher> {
+        let description = plugin.describe()?;
+        Ok(PluginImageFetcher {
+            plugin: Arc::new(plugin),
+            name: description.name,
+            description: description.description,
+            path: vec![],
+        })
+    }
+}
+
+#[async_trait]
+impl DistroImageFetcher for PluginImageFetcher {
+    fn get_name(&self) -> &str {
+        &self.name
+    }
+
+    fn get_description(&self) -> Option<String> {
+        self.description.clone()
+    }
+
+    async fn fetch(&self) -> Result<DistroImageList> {
+        match self.plugin.list(&self.path)? {
+            PluginListResponse::List(list) => {
+                let default = match list.default {
+                    Some(name) => DefaultImageFetcher::Name(name),
+                    None => DefaultImageFetcher::Index(0),
+                };
+                let items = list
+                    .items
+                    .into_iter()
+                    .map(|item| {
+                        let mut path = self.path.clone();
+                        path.push(item.name.clone());
+                        Box::new(PluginImageFetcher {
+                            plugin: Arc::clone(&self.plugin),
+                            name: item.name,
+                            description: item.description,
+                            path,
+                        }) as Box<dyn DistroImageFetcher>
+                    })
+                    .collect();
+                Ok(DistroImageList::Fetcher(list.kind, items, default))
+            }
+            PluginListResponse::Image(image) => Ok(DistroImageList::Image(DistroImage {
+                name: image.name,
+                image: match (image.url, image.file) {
+                    (Some(url), _) => DistroImageFile::Url(url),
+                    (None, Some(file)) => DistroImageFile::Local(file.into_os_string()),
+                    (None, None) => unreachable!("[BUG] the image has been validated."),
+                },
+            })),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test_image_fetcher_plugin {
+    use super::*;
+
+    /// A plugin which lists "ubuntu" with the releases "focal" and "jammy" by the request.
+    const PLUGIN_SCRIPT: &str = r#"#!/bin/sh
+read -r request
+case "$request" in
+  *'"describe"'*) echo '{"name": "Test mirror", "description": "For the tests"}' ;;
+  *'"path":[]'*) echo '{"list": {"kind": "a distro", "items": [{"name": "ubuntu"}]}}' ;;
+  *'"path":["ubuntu"]'*) echo '{"list": {"kind": "a release", "items": [{"name": "focal"}, {"name": "jammy"}], "default": "jammy"}}' ;;
+  *'"path":["ubuntu","focal"]'*) echo '{"image": {"name": "ubuntu-focal", "url": "https://example.com/focal.tar.xz"}}' ;;
+  *'"path":["ubuntu","jammy"]'*) echo '{"image": {"name": "ubuntu-jammy", "file": "/tmp/jammy.tar.xz"}}' ;;
+  *) exit 1 ;;
+esac
+"#;
+
+    fn put_plugin(dir: &Path) -> ImageFetcherPlugin {
+        let path = dir.join("test-mirror");
+        std::fs::write(&path, PLUGIN_SCRIPT).unwrap();
+        std::fs::set_permissions(&path, std::fs::Permissions::from_mode(0o755)).unwrap();
+        ImageFetcherPlugin::new(path)
+    }
+
+    #[test]
+    fn test_serialize_request() {
+        let request = PluginRequest::List {
+            version: 1,
+            path: vec!["ubuntu".to_owned()],
+        };
+        assert_eq!(
+            r#"{"request":"list","version":1,"path":["ubuntu"]}"#,
+            serde_json::to_string(&request).unwrap()
+        );
+    }
+
+    #[test]
+    fn test_parse_response() {
+        let response: PluginListResponse =
+            serde_json::from_str(r#"{"image": {"name": "a", "url": "https://example.com/a"}}"#)
+                .unwrap();
+        assert!(matches!(response, PluginListResponse::Image(_)));
+        assert!(serde_json::from_str::<PluginListResponse>(r#"{"unknown": {}}"#).is_err());
+
+        let image = |url: Option<&str>, file: Option<&str>| PluginImage {
+            name: "a".to_owned(),
+            url: url.map(|url| url.to_owned()),
+            file: file.map(PathBuf::from),
+        };
+        assert!(image(Some("https://example.com/a"), None)
+            .validate()
+            .is_ok());
+        assert!(image(None, Some("/tmp/a.tar.xz")).validate().is_ok());
+        assert!(image(None, Some("a.tar.xz")).validate().is_err());
+        assert!(image(None, None).validate().is_err());
+        assert!(image(Some("https://example.com/a"), Some("/tmp/a.tar.xz"))
+            .validate()
+            .is_err());
+    }
+
+    #[tokio::test]
+    async fn test_fetch_plugin_image() {
+        let dir = tempfile::tempdir().unwrap();
+        let plugin = put_plugin(dir.path());
+        assert_eq!("Test mirror", plugin.describe().unwrap().name.as_str());
+
+        let image = fetch_plugin_image(plugin.clone(), "ubuntu/focal")
+            .await
+            .unwrap();
+        assert_eq!("ubuntu-focal", image.name);
+        assert!(
+            matches!(image.image, DistroImageFile::Url(ref url) if url == "https://example.com/focal.tar.xz")
+        );
+
+        // The default of the list is taken once the path runs out.
+        let image = fetch_plugin_image(plugin.clone(), "ubuntu").await.unwrap();
+        assert_eq!("ubuntu-jammy", image.name);
+
+        let err = fetch_plugin_image(plugin, "debian").await.unwrap_err();
+        assert!(err.downcast_ref::<ImageError>().is_some());
+    }
+}
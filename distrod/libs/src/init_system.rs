@@ -0,0 +1,155 @@
+use std::fs;
+
+use crate::container::{ContainerPath, HostPath};
+
+/// The init system of a distro, which runs as PID 1 of the container. Most images have systemd,
+/// but the ones such as Alpine and Void have OpenRC and runit.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum InitSystem {
+    Systemd,
+    OpenRc,
+    Runit,
+}
+
+impl InitSystem {
+    /// Finds the init system of the rootfs by what /sbin/init is, and by the programs of
+    /// OpenRC and runit if /sbin/init is a program such as busybox. Defaults to systemd.
+    pub fn detect(rootfs: &HostPath) -> InitSystem {
+        if let Some(target) = fs::read_link(host_path(rootfs, "/sbin/init").as_path())
+            .ok()
+            .and_then(|target| target.file_name().map(|name| name.to_owned()))
+        {
+            match target.to_string_lossy().as_ref() {
+                "systemd" => return InitSystem::Systemd,
+                "openrc-init" => return InitSystem::OpenRc,
+                "runit-init" | "runit" => return InitSystem::Runit,
+                _ => {}
+            }
+        }
+        if exists(rootfs, "/lib/systemd/systemd") || exists(rootfs, "/usr/lib/systemd/systemd") {
+            InitSystem::Systemd
+        } else if exists(rootfs, "/sbin/openrc") || exists(rootfs, "/sbin/openrc-init") {
+            InitSystem::OpenRc
+        } else if exists(rootfs, "/sbin/runit") {
+            InitSystem::Runit
+        } else {
+            InitSystem::Systemd
+        }
+    }
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            InitSystem::Systemd => "systemd",
+            InitSystem::OpenRc => "OpenRC",
+            InitSystem::Runit => "runit",
+        }
+    }
+
+    /// The program to run as PID 1. Without openrc-init, busybox init starts OpenRC by
+    /// /etc/inittab as on Alpine.
+    pub fn init_path(&self, rootfs: &HostPath) -> &'static str {
+        match self {
+            InitSystem::OpenRc if exists(rootfs, "/sbin/openrc-init") => "/sbin/openrc-init",
+            InitSystem::Runit if exists(rootfs, "/sbin/runit") => "/sbin/runit",
+            _ => "/sbin/init",
+        }
+    }
+
+    /// The command which asks the init to power off, and returns without waiting for it.
+    pub fn poweroff_command(&self, rootfs: &HostPath) -> (&'static str, &'static [&'static str]) {
+        match self {
+            InitSystem::Systemd => ("systemctl", &["poweroff", "--no-block"]),
+            InitSystem::OpenRc if exists(rootfs, "/sbin/openrc-init") => {
+                ("openrc-shutdown", &["--poweroff", "now"])
+            }
+            // busybox poweroff signals busybox init, which runs the shutdown of /etc/inittab.
+            InitSystem::OpenRc => ("poweroff", &[]),
+            InitSystem::Runit => ("runit-init", &["0"]),
+        }
+    }
+
+    /// The command which tells whether the init has started the services for the normal use.
+    /// Pass its result to `is_ready`.
+    pub fn readiness_command(&self) -> (&'static str, &'static [&'static str]) {
+        match self {
+            InitSystem::Systemd => ("systemctl", &["is-system-running"]),
+            // The default runlevel has been entered and no services are still starting.
+            InitSystem::OpenRc => (
+                "/bin/sh",
+                &[
+                    "-c",
+                    r#"[ "$(rc-status --runlevel)" = default ] && [ -z "$(ls -A /run/openrc/starting 2>/dev/null)" ]"#,
+                ],
+            ),
+            // runit has no state of the whole system, but runsvdir runs once the stage 2 starts.
+            InitSystem::Runit => ("/bin/sh", &["-c", "pidof runsvdir >/dev/null"]),
+        }
+    }
+
+    pub fn is_ready(&self, exit_code: u32, output: &str) -> bool {
+        match self {
+            // "degraded" is ready as well, since the services which have started are usable.
+            InitSystem::Systemd => matches!(output.trim(), "running" | "degraded"),
+            InitSystem::OpenRc | InitSystem::Runit => exit_code == 0,
+        }
+    }
+}
+
+fn host_path(rootfs: &HostPath, path: &str) -> HostPath {
+    ContainerPath::new(path)
+        .expect("[BUG] the path should be absolute.")
+        .to_host_path(rootfs)
+}
+
+fn exists(rootfs: &HostPath, path: &str) -> bool {
+    host_path(rootfs, path).exists()
+}
+
+#[cfg(test)]
+mod test_init_system {
+    use super::*;
+    use tempfile::TempDir;
+
+    fn make_rootfs(files: &[&str], init_link: Option<&str>) -> (TempDir, HostPath) {
+        let tmpdir = TempDir::new().unwrap();
+        let rootfs = HostPath::new(tmpdir.path()).unwrap();
+        fs::create_dir_all(tmpdir.path().join("sbin")).unwrap();
+        for file in files {
+            let path = host_path(&rootfs, file);
+            fs::create_dir_all(path.parent().unwrap()).unwrap();
+            fs::write(path.as_path(), "").unwrap();
+        }
+        if let Some(target) = init_link {
+            std::os::unix::fs::symlink(target, tmpdir.path().join("sbin/init")).unwrap();
+        }
+        (tmpdir, rootfs)
+    }
+
+    #[test]
+    fn test_detect() {
+        let (_tmpdir, rootfs) = make_rootfs(&[], Some("/lib/systemd/systemd"));
+        assert_eq!(InitSystem::Systemd, InitSystem::detect(&rootfs));
+
+        // Alpine
+        let (_tmpdir, rootfs) =
+            make_rootfs(&["/sbin/openrc", "/bin/busybox"], Some("/bin/busybox"));
+        assert_eq!(InitSystem::OpenRc, InitSystem::detect(&rootfs));
+        assert_eq!("/sbin/init", InitSystem::OpenRc.init_path(&rootfs));
+
+        // Void
+        let (_tmpdir, rootfs) = make_rootfs(&["/sbin/runit"], Some("runit-init"));
+        assert_eq!(InitSystem::Runit, InitSystem::detect(&rootfs));
+        assert_eq!("/sbin/runit", InitSystem::Runit.init_path(&rootfs));
+
+        let (_tmpdir, rootfs) = make_rootfs(&[], None);
+        assert_eq!(InitSystem::Systemd, InitSystem::detect(&rootfs));
+    }
+
+    #[test]
+    fn test_is_ready() {
+        assert!(InitSystem::Systemd.is_ready(1, "degraded\n"));
+        assert!(!InitSystem::Systemd.is_ready(1, "starting\n"));
+        assert!(InitSystem::OpenRc.is_ready(0, ""));
+        assert!(!InitSystem::Runit.is_ready(1, ""));
+    }
+}
@@ -1,28 +1,110 @@
+pub mod arch;
+pub mod assuan;
+pub mod cancellation;
+pub mod capability;
 pub mod cli_ui;
+pub mod completion;
+pub mod connection_log;
 pub mod container_org_image;
+pub mod cuda_version;
+pub mod distro_config;
 pub mod distro_image;
 pub mod distrod_config;
+pub mod error;
+pub mod event_log;
+pub mod list_chooser;
 pub mod local_image;
+pub mod log_file;
+pub mod mdns;
+pub mod port_forward;
+pub mod port_metrics;
+pub mod port_usage;
+pub mod simplestreams;
+pub mod terminal_profile;
+pub mod windows_alias;
+pub mod wslenv;
 
+#[cfg(target_os = "linux")]
+pub mod auto_update;
+#[cfg(target_os = "linux")]
+pub mod autostart;
+#[cfg(target_os = "linux")]
+pub mod cgroup;
 #[cfg(target_os = "linux")]
 pub mod command_alias;
 #[cfg(target_os = "linux")]
+pub mod compose;
+#[cfg(target_os = "linux")]
 pub mod container;
 #[cfg(target_os = "linux")]
+pub mod daemon;
+#[cfg(target_os = "linux")]
+pub mod disk_usage;
+#[cfg(target_os = "linux")]
 pub mod distro;
 #[cfg(target_os = "linux")]
+pub mod distro_registry;
+#[cfg(target_os = "linux")]
 pub mod envfile;
 #[cfg(target_os = "linux")]
+pub mod etc_guard;
+#[cfg(target_os = "linux")]
+pub mod exec_broker;
+#[cfg(target_os = "linux")]
+pub mod extract;
+#[cfg(target_os = "linux")]
+pub mod fstab;
+#[cfg(target_os = "linux")]
+pub mod hooks;
+#[cfg(target_os = "linux")]
+pub mod image_fetcher_plugin;
+#[cfg(target_os = "linux")]
+pub mod init_system;
+#[cfg(target_os = "linux")]
+pub mod locale_sync;
+#[cfg(target_os = "linux")]
+pub mod machined;
+#[cfg(target_os = "linux")]
+pub mod managed_file;
+#[cfg(target_os = "linux")]
 pub mod mount_info;
 #[cfg(target_os = "linux")]
 pub mod multifork;
 #[cfg(target_os = "linux")]
 pub mod passwd;
 #[cfg(target_os = "linux")]
+pub mod process_list;
+#[cfg(target_os = "linux")]
 pub mod procfile;
 #[cfg(target_os = "linux")]
+pub mod provision;
+#[cfg(target_os = "linux")]
+pub mod pty;
+#[cfg(target_os = "linux")]
+pub mod resolved;
+#[cfg(target_os = "linux")]
+pub mod rootfs_dir;
+#[cfg(target_os = "linux")]
+pub mod rootfs_image;
+#[cfg(target_os = "linux")]
+pub mod rootfs_storage;
+#[cfg(target_os = "linux")]
+pub mod seccomp;
+#[cfg(target_os = "linux")]
+pub mod socket_relay;
+#[cfg(target_os = "linux")]
+pub mod syscall_table;
+#[cfg(target_os = "linux")]
 pub mod systemdunit;
 #[cfg(target_os = "linux")]
+pub mod threat_monitor;
+#[cfg(target_os = "linux")]
+pub mod time_sync;
+#[cfg(target_os = "linux")]
+pub mod userns;
+#[cfg(target_os = "linux")]
+pub mod wsl_conf;
+#[cfg(target_os = "linux")]
 pub mod wsl_interop;
 
 #[cfg(target_os = "linux")]
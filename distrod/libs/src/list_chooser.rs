@@ -0,0 +1,283 @@
+use anyhow::{bail, Context, Result};
+use console::{Key, Term};
+
+/// The number of the items shown at once, which is smaller on a short terminal.
+const MAX_VISIBLE_ITEMS: usize = 10;
+
+/// An item of the list, with a line shown beside its name such as the release and the
+/// architecture of an image.
+pub struct ListItem<'a> {
+    pub name: &'a str,
+    pub description: Option<String>,
+}
+
+/// The items matching the filter typed so far, and the one under the cursor.
+pub struct ListFilter<'a> {
+    items: &'a [ListItem<'a>],
+    query: String,
+    matches: Vec<usize>,
+    cursor: usize,
+}
+
+impl<'a> ListFilter<'a> {
+    pub fn new(items: &'a [ListItem<'a>], default: usize) -> ListFilter<'a> {
+        ListFilter {
+            items,
+            query: String::new(),
+            matches: (0..items.len()).collect(),
+            cursor: std::cmp::min(default, items.len().saturating_sub(1)),
+        }
+    }
+
+    pub fn get_query(&self) -> &str {
+        &self.query
+    }
+
+    pub fn push_char(&mut self, c: char) {
+        self.query.push(c);
+        self.update_matches();
+    }
+
+    pub fn pop_char(&mut self) {
+        self.query.pop();
+        self.update_matches();
+    }
+
+    pub fn move_up(&mut self) {
+        self.cursor = self.cursor.saturating_sub(1);
+    }
+
+    pub fn move_down(&mut self) {
+        if self.cursor + 1 < self.matches.len() {
+            self.cursor += 1;
+        }
+    }
+
+    /// The index in the items of the one under the cursor, or None if nothing matches.
+    pub fn get_selected(&self) -> Option<usize> {
+        self.matches.get(self.cursor).copied()
+    }
+
+    pub fn get_match_count(&self) -> usize {
+        self.matches.len()
+    }
+
+    /// The indices of the matching items shown in `height` lines, scrolled to the cursor.
+    pub fn get_visible(&self, height: usize) -> &[usize] {
+        let start = (self.cursor + 1).saturating_sub(height);
+        let end = std::cmp::min(start + height, self.matches.len());
+        &self.matches[start..end]
+    }
+
+    /// Each word of the query matches a part of the name or the description, regardless of
+    /// the case, such as "ubu 22" for "ubuntu" of "22.04".
+    fn update_matches(&mut self) {
+        let words: Vec<String> = self
+            .query
+            .split_whitespace()
+            .map(|word| word.to_lowercase())
+            .collect();
+        let selected = self.get_selected();
+        self.matches = self
+            .items
+            .iter()
+            .enumerate()
+            .filter(|(_, item)| {
+                let text = format!(
+                    "{} {}",
+                    item.name,
+                    item.description.as_deref().unwrap_or_default()
+                )
+                .to_lowercase();
+                words.iter().all(|word| text.contains(word.as_str()))
+            })
+            .map(|(i, _)| i)
+            .collect();
+        // Keep the cursor on the same item if it still matches.
+        self.cursor = selected
+            .and_then(|selected| self.matches.iter().position(|&i| i == selected))
+            .unwrap_or(0);
+    }
+}
+
+/// Whether the chooser can run, which needs a terminal that understands the cursor movements.
+pub fn is_available() -> bool {
+    let term = Term::stdout();
+    term.is_term()
+        && Term::stderr().is_term()
+        && std::env::var("TERM").map_or(true, |term| term != "dumb")
+}
+
+/// Lets the user choose one of `items` by the arrow keys, narrowing them by typing. Returns the
+/// index of the chosen one. Escape cancels the choice.
+pub fn choose(list_item_kind: &str, items: &[ListItem], default: usize) -> Result<usize> {
+    if items.is_empty() {
+        bail!("Empty list of {}.", list_item_kind);
+    }
+    let term = Term::stdout();
+    let mut filter = ListFilter::new(items, default);
+    let _ = term.hide_cursor();
+    let result = run_chooser(&term, list_item_kind, &mut filter);
+    let _ = term.show_cursor();
+    result
+}
+
+fn run_chooser(term: &Term, list_item_kind: &str, filter: &mut ListFilter) -> Result<usize> {
+    let mut drawn_lines = 0;
+    loop {
+        clear_lines(term, drawn_lines)?;
+        drawn_lines = draw(term, list_item_kind, filter)?;
+        let key = term
+            .read_key()
+            .with_context(|| "Failed to read a key from the terminal.")?;
+        match key {
+            Key::ArrowUp => filter.move_up(),
+            Key::ArrowDown | Key::Tab => filter.move_down(),
+            Key::Backspace => filter.pop_char(),
+            Key::Char(c) if !c.is_control() => filter.push_char(c),
+            Key::Enter => {
+                if let Some(selected) = filter.get_selected() {
+                    clear_lines(term, drawn_lines)?;
+                    let _ = term.write_line(&format!(
+                        "{} {}",
+                        console::style(format!("Chose {}:", list_item_kind)).green(),
+                        filter.items[selected].name
+                    ));
+                    return Ok(selected);
+                }
+            }
+            Key::Escape => {
+                clear_lines(term, drawn_lines)?;
+                bail!("Canceled choosing {}.", list_item_kind);
+            }
+            _ => {}
+        }
+    }
+}
+
+fn clear_lines(term: &Term, lines: usize) -> Result<()> {
+    // Some terminals move the cursor by one line for zero.
+    if lines == 0 {
+        return Ok(());
+    }
+    term.clear_last_lines(lines)
+        .with_context(|| "Failed to clear the terminal.")
+}
+
+/// Draws the filter, the visible items, and the help, returning the number of the lines.
+fn draw(term: &Term, list_item_kind: &str, filter: &ListFilter) -> Result<usize> {
+    let (rows, columns) = term.size();
+    let width = columns as usize;
+    let height = std::cmp::max(
+        1,
+        std::cmp::min(MAX_VISIBLE_ITEMS, (rows as usize).saturating_sub(3)),
+    );
+
+    let mut lines = vec![format!(
+        "{} {}",
+        console::style(format!("Choose {}:", list_item_kind)).cyan(),
+        filter.get_query()
+    )];
+    let selected = filter.get_selected();
+    for &i in filter.get_visible(height) {
+        let item = &filter.items[i];
+        let line = match item.description {
+            Some(ref description) => {
+                format!("{}  {}", item.name, console::style(description).dim())
+            }
+            None => item.name.to_owned(),
+        };
+        lines.push(if Some(i) == selected {
+            format!("{} {}", console::style(">").cyan().bold(), line)
+        } else {
+            format!("  {}", line)
+        });
+    }
+    if filter.get_match_count() == 0 {
+        lines.push(format!("  {}", console::style("No match.").dim()));
+    }
+    lines.push(
+        console::style(format!(
+            "[{}/{}] Up/Down: move, type: filter, Enter: choose, Esc: cancel",
+            filter.get_match_count(),
+            filter.items.len()
+        ))
+        .dim()
+        .to_string(),
+    );
+    for line in &lines {
+        term.write_line(&console::truncate_str(line, width, "…"))
+            .with_context(|| "Failed to write to the terminal.")?;
+    }
+    Ok(lines.len())
+}
+
+#[cfg(test)]
+mod test_list_chooser {
+    use super::*;
+
+    fn build_items() -> Vec<ListItem<'static>> {
+        vec![
+            ListItem {
+                name: "ubuntu",
+                description: Some("jammy, focal".to_owned()),
+            },
+            ListItem {
+                name: "debian",
+                description: Some("bookworm".to_owned()),
+            },
+            ListItem {
+                name: "Kali",
+                description: None,
+            },
+        ]
+    }
+
+    #[test]
+    fn test_filter() {
+        let items = build_items();
+        let mut filter = ListFilter::new(&items, 1);
+        assert_eq!(Some(1), filter.get_selected());
+
+        filter.push_char('a');
+        assert_eq!(3, filter.get_match_count());
+        filter.push_char('n');
+        // "debian" still matches, so the cursor stays on it.
+        assert_eq!(Some(1), filter.get_selected());
+        assert_eq!(&[1], filter.get_visible(10));
+
+        filter.pop_char();
+        filter.pop_char();
+        for c in "KAL".chars() {
+            filter.push_char(c);
+        }
+        assert_eq!(Some(2), filter.get_selected());
+
+        // Every word matches the name or the description.
+        for _ in 0..3 {
+            filter.pop_char();
+        }
+        for c in "u jammy".chars() {
+            filter.push_char(c);
+        }
+        assert_eq!(&[0], filter.get_visible(10));
+        filter.push_char('x');
+        assert_eq!(None, filter.get_selected());
+    }
+
+    #[test]
+    fn test_move_and_scroll() {
+        let items = build_items();
+        let mut filter = ListFilter::new(&items, 0);
+        filter.move_up();
+        assert_eq!(Some(0), filter.get_selected());
+        filter.move_down();
+        filter.move_down();
+        filter.move_down();
+        assert_eq!(Some(2), filter.get_selected());
+        assert_eq!(&[1, 2], filter.get_visible(2));
+        filter.move_up();
+        filter.move_up();
+        assert_eq!(&[0, 1], filter.get_visible(2));
+    }
+}
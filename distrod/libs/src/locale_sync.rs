@@ -0,0 +1,203 @@
+use anyhow::{anyhow, bail, Context, Result};
+use std::fs;
+use std::os::unix::fs::symlink;
+use std::path::{Component, Path};
+
+use crate::container::{ContainerPath, HostPath};
+use crate::distro_config::LocaleConfig;
+use crate::envfile::EnvFile;
+use crate::wsl_interop;
+
+const ZONEINFO_DIR: &str = "/usr/share/zoneinfo";
+
+/// The IANA time zones of the Windows time zones, for when WSL doesn't set its own
+/// /etc/localtime by `useWindowsTimezone`. These are the common ones of the windowsZones of
+/// CLDR.
+const WINDOWS_TIMEZONES: &[(&str, &str)] = &[
+    ("Dateline Standard Time", "Etc/GMT+12"),
+    ("Hawaiian Standard Time", "Pacific/Honolulu"),
+    ("Alaskan Standard Time", "America/Anchorage"),
+    ("Pacific Standard Time", "America/Los_Angeles"),
+    ("US Mountain Standard Time", "America/Phoenix"),
+    ("Mountain Standard Time", "America/Denver"),
+    ("Central Standard Time", "America/Chicago"),
+    ("Eastern Standard Time", "America/New_York"),
+    ("Atlantic Standard Time", "America/Halifax"),
+    ("E. South America Standard Time", "America/Sao_Paulo"),
+    ("Argentina Standard Time", "America/Argentina/Buenos_Aires"),
+    ("UTC", "Etc/UTC"),
+    ("GMT Standard Time", "Europe/London"),
+    ("W. Europe Standard Time", "Europe/Berlin"),
+    ("Romance Standard Time", "Europe/Paris"),
+    ("Central Europe Standard Time", "Europe/Budapest"),
+    ("Central European Standard Time", "Europe/Warsaw"),
+    ("GTB Standard Time", "Europe/Bucharest"),
+    ("FLE Standard Time", "Europe/Kiev"),
+    ("Turkey Standard Time", "Europe/Istanbul"),
+    ("Israel Standard Time", "Asia/Jerusalem"),
+    ("Egypt Standard Time", "Africa/Cairo"),
+    ("South Africa Standard Time", "Africa/Johannesburg"),
+    ("Russian Standard Time", "Europe/Moscow"),
+    ("Arabian Standard Time", "Asia/Dubai"),
+    ("India Standard Time", "Asia/Kolkata"),
+    ("SE Asia Standard Time", "Asia/Bangkok"),
+    ("China Standard Time", "Asia/Shanghai"),
+    ("Singapore Standard Time", "Asia/Singapore"),
+    ("Taipei Standard Time", "Asia/Taipei"),
+    ("Tokyo Standard Time", "Asia/Tokyo"),
+    ("Korea Standard Time", "Asia/Seoul"),
+    ("AUS Eastern Standard Time", "Australia/Sydney"),
+    ("New Zealand Standard Time", "Pacific/Auckland"),
+];
+
+/// Sets the time zone and the locale of the distro to the ones of Windows as the config says.
+/// This runs on every start, so a failure is only warned.
+pub fn sync_from_windows(rootfs: &HostPath, config: &LocaleConfig) {
+    if config.sync_windows_timezone {
+        if let Err(e) = get_windows_timezone().and_then(|tz| set_timezone(rootfs, &tz)) {
+            log::warn!(
+                "Failed to set the time zone of Windows to the distro. {:?}",
+                e
+            );
+        }
+    }
+    if config.sync_windows_locale {
+        let result = wsl_interop::get_windows_culture_name()
+            .and_then(|culture| culture_to_locale(&culture))
+            .and_then(|locale| set_locale(rootfs, &locale));
+        if let Err(e) = result {
+            log::warn!("Failed to set the locale of Windows to the distro. {:?}", e);
+        }
+    }
+}
+
+/// Returns the IANA name of the time zone of Windows, such as "Asia/Tokyo". It's the one WSL
+/// links its /etc/localtime to if it can, which saves running Powershell.
+pub fn get_windows_timezone() -> Result<String> {
+    if let Ok(target) = fs::read_link("/etc/localtime") {
+        if let Some(tz) = parse_zoneinfo_link(&target) {
+            return Ok(tz);
+        }
+    }
+    let id = wsl_interop::get_windows_timezone_id()?;
+    WINDOWS_TIMEZONES
+        .iter()
+        .find(|(windows_id, _)| *windows_id == id)
+        .map(|(_, tz)| (*tz).to_owned())
+        .ok_or_else(|| anyhow!("Unknown time zone of Windows: '{}'.", id))
+}
+
+fn parse_zoneinfo_link(target: &Path) -> Option<String> {
+    let target = target.to_str()?;
+    let pos = target.find("zoneinfo/")?;
+    let tz = &target[pos + "zoneinfo/".len()..];
+    if tz.is_empty() {
+        return None;
+    }
+    Some(tz.to_owned())
+}
+
+/// Points /etc/localtime of the distro at the zoneinfo of the time zone. The zoneinfo of WSL
+/// is copied if the distro doesn't have tzdata.
+fn set_timezone(rootfs: &HostPath, tz: &str) -> Result<()> {
+    if Path::new(tz)
+        .components()
+        .any(|component| !matches!(component, Component::Normal(_)))
+    {
+        bail!("Invalid time zone: '{}'.", tz);
+    }
+    let zoneinfo = format!("{}/{}", ZONEINFO_DIR, tz);
+    let localtime = ContainerPath::new("/etc/localtime")?.to_host_path(rootfs);
+    if fs::read_link(localtime.as_path())
+        .ok()
+        .and_then(|target| parse_zoneinfo_link(&target))
+        .as_deref()
+        == Some(tz)
+    {
+        return Ok(());
+    }
+    log::debug!("Setting the time zone of the distro to {}.", tz);
+    if localtime.symlink_metadata().is_ok() {
+        fs::remove_file(localtime.as_path())
+            .with_context(|| format!("Failed to remove {:?}.", &localtime))?;
+    }
+    if ContainerPath::new(&zoneinfo)?.to_host_path(rootfs).exists() {
+        symlink(&zoneinfo, localtime.as_path())
+            .with_context(|| format!("Failed to link {:?} to {}.", &localtime, &zoneinfo))?;
+    } else {
+        fs::copy(&zoneinfo, localtime.as_path())
+            .with_context(|| format!("Failed to copy {} to {:?}.", &zoneinfo, &localtime))?;
+    }
+    // Debian and Ubuntu also read the name from /etc/timezone.
+    let timezone = ContainerPath::new("/etc/timezone")?.to_host_path(rootfs);
+    if timezone.exists() {
+        fs::write(timezone.as_path(), format!("{}\n", tz))
+            .with_context(|| format!("Failed to write {:?}.", &timezone))?;
+    }
+    Ok(())
+}
+
+/// Converts a culture name of Windows such as "ja-JP" to a locale such as "ja_JP.UTF-8".
+fn culture_to_locale(culture: &str) -> Result<String> {
+    let mut parts = culture.split('-');
+    let language = parts.next().unwrap_or_default();
+    // The last part is the region, such as "CN" of "zh-Hans-CN".
+    let region = parts.last();
+    let is_valid = |part: &str| !part.is_empty() && part.chars().all(|c| c.is_ascii_alphabetic());
+    match region {
+        Some(region) if is_valid(language) && is_valid(region) => Ok(format!(
+            "{}_{}.UTF-8",
+            language.to_ascii_lowercase(),
+            region.to_ascii_uppercase()
+        )),
+        _ => bail!("Unsupported culture of Windows: '{}'.", culture),
+    }
+}
+
+/// Sets LANG in /etc/locale.conf, which systemd reads, and in /etc/default/locale of Debian
+/// and Ubuntu if it's there. The other variables in them are kept.
+fn set_locale(rootfs: &HostPath, locale: &str) -> Result<()> {
+    let mut paths = vec![ContainerPath::new("/etc/locale.conf")?.to_host_path(rootfs)];
+    let default_locale = ContainerPath::new("/etc/default/locale")?.to_host_path(rootfs);
+    if default_locale.exists() {
+        paths.push(default_locale);
+    }
+    for path in paths {
+        let mut env_file = EnvFile::open(path.as_path())?;
+        if env_file.get_env("LANG") == Some(locale) {
+            continue;
+        }
+        log::debug!("Setting LANG={} in {:?}.", locale, &path);
+        env_file.put_env("LANG".to_owned(), locale.to_owned());
+        env_file
+            .write()
+            .with_context(|| format!("Failed to write {:?}.", &path))?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod test_locale_sync {
+    use super::*;
+
+    #[test]
+    fn test_culture_to_locale() {
+        assert_eq!("ja_JP.UTF-8", culture_to_locale("ja-JP").unwrap());
+        assert_eq!("zh_CN.UTF-8", culture_to_locale("zh-Hans-CN").unwrap());
+        assert!(culture_to_locale("en").is_err());
+        assert!(culture_to_locale("").is_err());
+    }
+
+    #[test]
+    fn test_parse_zoneinfo_link() {
+        assert_eq!(
+            Some("Asia/Tokyo".to_owned()),
+            parse_zoneinfo_link(Path::new("/usr/share/zoneinfo/Asia/Tokyo"))
+        );
+        assert_eq!(
+            Some("Etc/UTC".to_owned()),
+            parse_zoneinfo_link(Path::new("../usr/share/zoneinfo/Etc/UTC"))
+        );
+        assert_eq!(None, parse_zoneinfo_link(Path::new("/etc/localtime.bak")));
+    }
+}
@@ -0,0 +1,112 @@
+use anyhow::{Context, Result};
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+
+/// A log file which is renamed to `<name>.1` when it grows over the size, shifting the older
+/// ones up to `<name>.<max_old_files>`. Each process counts the size by itself, so the file of
+/// many processes writing at once can grow over the size by their last lines.
+pub struct RotatingLogFile {
+    path: PathBuf,
+    max_bytes: u64,
+    max_old_files: usize,
+    file: File,
+    size: u64,
+}
+
+impl RotatingLogFile {
+    pub fn open<P: AsRef<Path>>(
+        path: P,
+        max_bytes: u64,
+        max_old_files: usize,
+    ) -> Result<RotatingLogFile> {
+        let path = path.as_ref().to_owned();
+        if let Some(dir) = path.parent() {
+            fs::create_dir_all(dir).with_context(|| format!("Failed to create {:?}.", dir))?;
+        }
+        let file = open_append(&path).with_context(|| format!("Failed to open {:?}.", &path))?;
+        let size = file
+            .metadata()
+            .with_context(|| format!("Failed to get the metadata of {:?}.", &path))?
+            .len();
+        Ok(RotatingLogFile {
+            path,
+            max_bytes,
+            max_old_files,
+            file,
+            size,
+        })
+    }
+
+    fn rotate(&mut self) -> io::Result<()> {
+        for i in (1..self.max_old_files).rev() {
+            let old = self.get_old_file_path(i);
+            if old.exists() {
+                fs::rename(&old, self.get_old_file_path(i + 1))?;
+            }
+        }
+        if self.max_old_files > 0 {
+            fs::rename(&self.path, self.get_old_file_path(1))?;
+        } else {
+            fs::remove_file(&self.path)?;
+        }
+        self.file = open_append(&self.path)?;
+        self.size = 0;
+        Ok(())
+    }
+
+    fn get_old_file_path(&self, i: usize) -> PathBuf {
+        let mut name = self.path.as_os_str().to_owned();
+        name.push(format!(".{}", i));
+        PathBuf::from(name)
+    }
+}
+
+impl Write for RotatingLogFile {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        if self.size > 0 && self.size + buf.len() as u64 > self.max_bytes {
+            self.rotate()?;
+        }
+        let written = self.file.write(buf)?;
+        self.size += written as u64;
+        Ok(written)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.file.flush()
+    }
+}
+
+fn open_append(path: &Path) -> io::Result<File> {
+    OpenOptions::new().append(true).create(true).open(path)
+}
+
+#[cfg(test)]
+mod test_log_file {
+    use super::*;
+
+    #[test]
+    fn test_rotate() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("logs/distrod.log");
+        let mut log_file = RotatingLogFile::open(&path, 10, 2).unwrap();
+        for line in &["first\n", "second\n", "third\n", "fourth\n"] {
+            log_file.write_all(line.as_bytes()).unwrap();
+        }
+        assert_eq!("fourth\n", fs::read_to_string(&path).unwrap());
+        assert_eq!(
+            "third\n",
+            fs::read_to_string(dir.path().join("logs/distrod.log.1")).unwrap()
+        );
+        assert_eq!(
+            "second\n",
+            fs::read_to_string(dir.path().join("logs/distrod.log.2")).unwrap()
+        );
+        assert!(!dir.path().join("logs/distrod.log.3").exists());
+
+        // The size of the existing file counts.
+        let mut log_file = RotatingLogFile::open(&path, 10, 2).unwrap();
+        log_file.write_all(b"fifth\n").unwrap();
+        assert_eq!("fifth\n", fs::read_to_string(&path).unwrap());
+    }
+}
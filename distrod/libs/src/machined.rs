@@ -0,0 +1,71 @@
+use anyhow::{bail, Context, Result};
+use std::ffi::OsString;
+use std::path::Path;
+use std::process::{Command, Stdio};
+
+const MACHINED_SERVICE: &str = "org.freedesktop.machine1";
+const MACHINED_OBJECT: &str = "/org/freedesktop/machine1";
+const MACHINED_INTERFACE: &str = "org.freedesktop.machine1.Manager";
+
+/// Returns whether WSL itself runs systemd, whose systemd-machined the distros can be
+/// registered with, such as by `systemd=true` of /etc/wsl.conf.
+pub fn is_available() -> bool {
+    // The same check as sd_booted(3).
+    Path::new("/run/systemd/system").is_dir() && has_busctl()
+}
+
+/// The name of the distro in `machinectl list`.
+pub fn get_machine_name(distro_name: Option<&str>) -> String {
+    distro_name.unwrap_or("distrod").to_owned()
+}
+
+/// Registers the container whose init is `leader` as a machine, so that `machinectl shell`,
+/// `machinectl status`, and `journalctl -M` work for it.
+pub fn register_machine(name: &str, leader: u32, root_directory: &Path) -> Result<()> {
+    let args: Vec<OsString> = vec![
+        "RegisterMachine".into(),
+        "sayssus".into(),
+        name.into(),
+        // An empty id, since systemd in the distro may not have made its machine-id yet.
+        "0".into(),
+        "distrod".into(),
+        "container".into(),
+        leader.to_string().into(),
+        root_directory.as_os_str().to_owned(),
+    ];
+    call_machined(&args).with_context(|| format!("Failed to register {} with machined.", name))
+}
+
+/// Removes the machine from machined without killing its processes.
+pub fn unregister_machine(name: &str) -> Result<()> {
+    let args: Vec<OsString> = vec!["UnregisterMachine".into(), "s".into(), name.into()];
+    call_machined(&args).with_context(|| format!("Failed to unregister {} from machined.", name))
+}
+
+fn call_machined(args: &[OsString]) -> Result<()> {
+    let output = Command::new("busctl")
+        .args(&[
+            "call",
+            MACHINED_SERVICE,
+            MACHINED_OBJECT,
+            MACHINED_INTERFACE,
+        ])
+        .args(args)
+        .stdin(Stdio::null())
+        .output()
+        .with_context(|| "Failed to run busctl.")?;
+    if !output.status.success() {
+        bail!(
+            "busctl exited with {}: {}",
+            output.status,
+            String::from_utf8_lossy(&output.stderr).trim()
+        );
+    }
+    Ok(())
+}
+
+fn has_busctl() -> bool {
+    ["/usr/bin/busctl", "/bin/busctl"]
+        .iter()
+        .any(|path| Path::new(path).exists())
+}
@@ -0,0 +1,376 @@
+use anyhow::{bail, Context, Result};
+use std::fs;
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::process::Command;
+
+use crate::cli_ui::{self, prompt_string};
+use crate::container::{ContainerPath, HostPath};
+use crate::distrod_config;
+
+/// The directory in a rootfs which has the baselines of the files Distrod puts in the rootfs,
+/// such as the scripts in /etc/profile.d.
+pub const ROOTFS_BASELINE_DIR: &str = "/etc/distrod/baseline";
+
+/// How to resolve a file both the user and the update have changed.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ConflictPolicy {
+    /// Ask the user. The user's version is kept if stdin is not a terminal.
+    Ask,
+    /// Keep the user's version, and save the new one beside it.
+    Ours,
+    /// Take the new version, and save the user's one beside it.
+    Theirs,
+}
+
+enum Resolution {
+    Keep,
+    Accept,
+    Merged(Vec<u8>),
+}
+
+/// Updates the files which Distrod ships but users may edit, such as the files in conf/, the
+/// units, and the scripts in /etc/profile.d. The shipped version of each file is kept as its
+/// baseline to tell whether the user or the update has changed the file, and to do a three-way
+/// merge. /etc/wsl.conf isn't managed by this, since Distrod edits it key by key by WslConf,
+/// which keeps the other keys and the comments of the user.
+pub struct ManagedFileUpdater {
+    baseline_dir: PathBuf,
+    policy: ConflictPolicy,
+}
+
+impl ManagedFileUpdater {
+    /// For the files of Distrod itself, whose baselines are kept in var/baseline/.
+    pub fn new(policy: ConflictPolicy) -> ManagedFileUpdater {
+        ManagedFileUpdater::with_baseline_dir(
+            Path::new(distrod_config::get_distrod_var_dir()).join("baseline"),
+            policy,
+        )
+    }
+
+    /// For the files Distrod puts in the rootfs, whose baselines are kept in the rootfs, so that
+    /// they go along with the distro.
+    pub fn for_rootfs(rootfs: &HostPath, policy: ConflictPolicy) -> Result<ManagedFileUpdater> {
+        Ok(ManagedFileUpdater::with_baseline_dir(
+            ContainerPath::new(ROOTFS_BASELINE_DIR)?
+                .to_host_path(rootfs)
+                .as_path()
+                .to_owned(),
+            policy,
+        ))
+    }
+
+    pub fn with_baseline_dir(baseline_dir: PathBuf, policy: ConflictPolicy) -> ManagedFileUpdater {
+        ManagedFileUpdater {
+            baseline_dir,
+            policy,
+        }
+    }
+
+    /// Installs the new version of the file at `rel_path` from `new_path`.
+    pub fn update(&self, rel_path: &Path, new_path: &Path, dest_path: &Path) -> Result<()> {
+        let new = fs::read(new_path).with_context(|| format!("Failed to read {:?}.", new_path))?;
+        self.update_content(rel_path, &new, dest_path)
+    }
+
+    /// Installs `new` as the new version of the file at `rel_path`, such as
+    /// "conf/distrod.toml", which names its baseline.
+    pub fn update_content(&self, rel_path: &Path, new: &[u8], dest_path: &Path) -> Result<()> {
+        let baseline_path = self.baseline_dir.join(rel_path);
+        let base = fs::read(&baseline_path).ok();
+        if !dest_path.exists() {
+            if base.is_some() {
+                log::info!(
+                    "{:?} has been deleted. Leaving it deleted in spite of the update.",
+                    dest_path
+                );
+            } else {
+                install(dest_path, new)?;
+            }
+            return save_file(&baseline_path, new);
+        }
+        let ours =
+            fs::read(dest_path).with_context(|| format!("Failed to read {:?}.", dest_path))?;
+        if ours == new || base.as_deref() == Some(new) {
+            // The update doesn't change the file, so keep the user's one.
+            return save_file(&baseline_path, new);
+        }
+        if base.as_ref() == Some(&ours) {
+            log::debug!("{:?} is not modified by the user. Updating it.", dest_path);
+            install(dest_path, new)?;
+            return save_file(&baseline_path, new);
+        }
+
+        // diff3 and diff take the new version as a file.
+        let mut new_file = tempfile::Builder::new()
+            .prefix("distrod-new")
+            .tempfile()
+            .with_context(|| "Failed to create a temporary file.")?;
+        new_file.write_all(new)?;
+        new_file.flush()?;
+        let new_path = new_file.path();
+        if base.is_some() {
+            if let (true, merged) = diff3(dest_path, &baseline_path, new_path)? {
+                install(dest_path, &merged)?;
+                log::info!("Merged the update into your {:?}.", dest_path);
+                return save_file(&baseline_path, new);
+            }
+        }
+        let resolution = match self.policy {
+            ConflictPolicy::Ours => Resolution::Keep,
+            ConflictPolicy::Theirs => Resolution::Accept,
+            ConflictPolicy::Ask if !cli_ui::is_interactive() => Resolution::Keep,
+            ConflictPolicy::Ask => ask_resolution(dest_path, new_path, &baseline_path)?,
+        };
+        match resolution {
+            Resolution::Keep => {
+                let saved = with_suffix(dest_path, ".distrod-new");
+                install(&saved, new)?;
+                log::warn!(
+                    "Kept your {:?}. The new version is saved as {:?}.",
+                    dest_path,
+                    &saved
+                );
+            }
+            Resolution::Accept => {
+                let saved = with_suffix(dest_path, ".distrod-old");
+                install(&saved, &ours)?;
+                install(dest_path, new)?;
+                log::warn!(
+                    "Updated {:?}. Your version is saved as {:?}.",
+                    dest_path,
+                    &saved
+                );
+            }
+            Resolution::Merged(merged) => {
+                install(dest_path, &merged)?;
+                log::info!("Merged the new version into {:?}.", dest_path);
+            }
+        }
+        save_file(&baseline_path, new)
+    }
+}
+
+fn ask_resolution(dest_path: &Path, new_path: &Path, baseline_path: &Path) -> Result<Resolution> {
+    // Without the baseline, such as for the files installed by an old version, show the whole
+    // difference between the two versions.
+    let base_path = if baseline_path.exists() {
+        baseline_path
+    } else {
+        Path::new("/dev/null")
+    };
+    println!("Both you and the update have changed {:?}.", dest_path);
+    println!("--- Your changes ---");
+    show_diff(base_path, dest_path)?;
+    println!("--- The changes of the update ---");
+    show_diff(base_path, new_path)?;
+    loop {
+        let choice = prompt_string(
+            "[k]eep yours, [a]ccept the new one, or [m]erge them?",
+            "k, a, or m",
+            Some("k"),
+        )?;
+        match choice.as_str() {
+            "" | "k" => return Ok(Resolution::Keep),
+            "a" => return Ok(Resolution::Accept),
+            "m" => {
+                if let Some(merged) = merge(dest_path, base_path, new_path)? {
+                    return Ok(Resolution::Merged(merged));
+                }
+            }
+            _ => log::info!("Choose k, a, or m."),
+        }
+    }
+}
+
+fn show_diff(from: &Path, to: &Path) -> Result<()> {
+    // diff exits with 1 when the files differ.
+    Command::new("diff")
+        .arg("-u")
+        .arg(from)
+        .arg(to)
+        .status()
+        .with_context(|| "Failed to run diff.")?;
+    Ok(())
+}
+
+/// Merges the two versions by diff3. Returns whether they are merged without conflicts, and the
+/// merged content, which has the conflict markers otherwise.
+fn diff3(ours: &Path, base: &Path, theirs: &Path) -> Result<(bool, Vec<u8>)> {
+    let output = Command::new("diff3")
+        .arg("-m")
+        .args(&["-L", "yours", "-L", "base", "-L", "new"])
+        .arg(ours)
+        .arg(base)
+        .arg(theirs)
+        .output()
+        .with_context(|| "Failed to run diff3.")?;
+    match output.status.code() {
+        Some(0) => Ok((true, output.stdout)),
+        Some(1) => Ok((false, output.stdout)),
+        _ => bail!(
+            "diff3 failed. {}",
+            String::from_utf8_lossy(&output.stderr).trim()
+        ),
+    }
+}
+
+/// Merges the two versions by diff3, and lets the user resolve the conflicts by $EDITOR.
+/// Returns None if the user gives up the merge.
+fn merge(ours: &Path, base: &Path, theirs: &Path) -> Result<Option<Vec<u8>>> {
+    let (is_clean, merged_content) = diff3(ours, base, theirs)?;
+    if is_clean {
+        return Ok(Some(merged_content));
+    }
+
+    let mut merged = tempfile::Builder::new()
+        .prefix("distrod-merge")
+        .tempfile()
+        .with_context(|| "Failed to create a temporary file.")?;
+    merged.write_all(&merged_content)?;
+    merged.flush()?;
+    let editor = std::env::var("EDITOR").unwrap_or_else(|_| "vi".to_owned());
+    log::info!(
+        "Resolve the conflicts between <<<<<<< and >>>>>>> by {}.",
+        &editor
+    );
+    let status = Command::new(&editor)
+        .arg(merged.path())
+        .status()
+        .with_context(|| format!("Failed to run {}.", &editor))?;
+    let result = fs::read(merged.path())?;
+    if !status.success() || String::from_utf8_lossy(&result).contains("<<<<<<<") {
+        log::info!("The conflicts are not resolved.");
+        return Ok(None);
+    }
+    Ok(Some(result))
+}
+
+fn install(path: &Path, cont: &[u8]) -> Result<()> {
+    fs::write(path, cont).with_context(|| format!("Failed to write {:?}.", path))
+}
+
+fn save_file(path: &Path, cont: &[u8]) -> Result<()> {
+    if let Some(dir) = path.parent() {
+        fs::create_dir_all(dir).with_context(|| format!("Failed to create {:?}.", dir))?;
+    }
+    install(path, cont)
+}
+
+fn with_suffix(path: &Path, suffix: &str) -> PathBuf {
+    let mut name = path.as_os_str().to_owned();
+    name.push(suffix);
+    PathBuf::from(name)
+}
+
+#[cfg(test)]
+mod test_managed_file {
+    use super::*;
+
+    fn set_up(base: Option<&str>, ours: Option<&str>) -> (tempfile::TempDir, ManagedFileUpdater) {
+        let dir = tempfile::tempdir().unwrap();
+        let updater = ManagedFileUpdater::with_baseline_dir(
+            dir.path().join("baseline"),
+            ConflictPolicy::Ours,
+        );
+        if let Some(base) = base {
+            save_file(&dir.path().join("baseline/conf/test.conf"), base.as_bytes()).unwrap();
+        }
+        if let Some(ours) = ours {
+            fs::write(dir.path().join("test.conf"), ours).unwrap();
+        }
+        (dir, updater)
+    }
+
+    fn update(dir: &tempfile::TempDir, updater: &ManagedFileUpdater, new: &str) {
+        updater
+            .update_content(
+                Path::new("conf/test.conf"),
+                new.as_bytes(),
+                &dir.path().join("test.conf"),
+            )
+            .unwrap();
+    }
+
+    fn read(path: PathBuf) -> Option<String> {
+        fs::read_to_string(path).ok()
+    }
+
+    #[test]
+    fn test_update_unmodified_file() {
+        let (dir, updater) = set_up(Some("a\nb\n"), Some("a\nb\n"));
+        update(&dir, &updater, "a\nB\n");
+        assert_eq!(
+            Some("a\nB\n".to_owned()),
+            read(dir.path().join("test.conf"))
+        );
+        assert_eq!(
+            Some("a\nB\n".to_owned()),
+            read(dir.path().join("baseline/conf/test.conf"))
+        );
+    }
+
+    #[test]
+    fn test_clean_merge() {
+        let (dir, updater) = set_up(Some("a\nb\nc\nd\ne\n"), Some("A\nb\nc\nd\ne\n"));
+        update(&dir, &updater, "a\nb\nc\nd\nE\n");
+        assert_eq!(
+            Some("A\nb\nc\nd\nE\n".to_owned()),
+            read(dir.path().join("test.conf"))
+        );
+        assert!(!dir.path().join("test.conf.distrod-new").exists());
+        assert_eq!(
+            Some("a\nb\nc\nd\nE\n".to_owned()),
+            read(dir.path().join("baseline/conf/test.conf"))
+        );
+    }
+
+    #[test]
+    fn test_conflict() {
+        let (dir, updater) = set_up(Some("a\nb\n"), Some("a\nours\n"));
+        update(&dir, &updater, "a\ntheirs\n");
+        assert_eq!(
+            Some("a\nours\n".to_owned()),
+            read(dir.path().join("test.conf"))
+        );
+        assert_eq!(
+            Some("a\ntheirs\n".to_owned()),
+            read(dir.path().join("test.conf.distrod-new"))
+        );
+        assert_eq!(
+            Some("a\ntheirs\n".to_owned()),
+            read(dir.path().join("baseline/conf/test.conf"))
+        );
+
+        let (dir, _) = set_up(Some("a\nb\n"), Some("a\nours\n"));
+        let updater = ManagedFileUpdater::with_baseline_dir(
+            dir.path().join("baseline"),
+            ConflictPolicy::Theirs,
+        );
+        update(&dir, &updater, "a\ntheirs\n");
+        assert_eq!(
+            Some("a\ntheirs\n".to_owned()),
+            read(dir.path().join("test.conf"))
+        );
+        assert_eq!(
+            Some("a\nours\n".to_owned()),
+            read(dir.path().join("test.conf.distrod-old"))
+        );
+    }
+
+    #[test]
+    fn test_deleted_file() {
+        let (dir, updater) = set_up(Some("a\n"), None);
+        update(&dir, &updater, "b\n");
+        assert!(!dir.path().join("test.conf").exists());
+        assert_eq!(
+            Some("b\n".to_owned()),
+            read(dir.path().join("baseline/conf/test.conf"))
+        );
+
+        // A new file is installed.
+        let (dir, updater) = set_up(None, None);
+        update(&dir, &updater, "b\n");
+        assert_eq!(Some("b\n".to_owned()), read(dir.path().join("test.conf")));
+    }
+}
@@ -0,0 +1,236 @@
+use std::net::Ipv4Addr;
+
+pub const MDNS_PORT: u16 = 5353;
+pub const MDNS_GROUP: Ipv4Addr = Ipv4Addr::new(224, 0, 0, 251);
+/// The TTL of the answers, which RFC 6762 recommends for the records with a hostname.
+pub const MDNS_TTL_SEC: u32 = 120;
+
+const HEADER_LEN: usize = 12;
+const FLAG_RESPONSE: u16 = 0x8000;
+const FLAG_AUTHORITATIVE: u16 = 0x0400;
+const TYPE_A: u16 = 1;
+const TYPE_ANY: u16 = 255;
+const CLASS_IN: u16 = 1;
+/// The top bit of the class, which is the unicast-response bit in a question and the
+/// cache-flush bit in an answer.
+const CLASS_TOP_BIT: u16 = 0x8000;
+const MAX_POINTER_JUMPS: usize = 16;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct MdnsQuestion {
+    /// The name without the trailing dot, such as "ubuntu.local".
+    pub name: String,
+    pub qtype: u16,
+    /// Whether the querier asks to answer it by unicast.
+    pub unicast_response: bool,
+}
+
+impl MdnsQuestion {
+    /// Returns whether it asks the IPv4 address of `name`, which is compared case-insensitively.
+    pub fn asks_ipv4_of(&self, name: &str) -> bool {
+        (self.qtype == TYPE_A || self.qtype == TYPE_ANY) && self.name.eq_ignore_ascii_case(name)
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct MdnsQuery {
+    pub id: u16,
+    pub questions: Vec<MdnsQuestion>,
+}
+
+impl MdnsQuery {
+    /// Parses a query. Returns None for the responses of other hosts and malformed packets.
+    pub fn parse(packet: &[u8]) -> Option<MdnsQuery> {
+        if packet.len() < HEADER_LEN {
+            return None;
+        }
+        let id = read_u16(packet, 0)?;
+        if read_u16(packet, 2)? & FLAG_RESPONSE != 0 {
+            return None;
+        }
+        let question_count = read_u16(packet, 4)?;
+        let mut offset = HEADER_LEN;
+        let mut questions = vec![];
+        for _ in 0..question_count {
+            let (name, next) = read_name(packet, offset)?;
+            let qtype = read_u16(packet, next)?;
+            let qclass = read_u16(packet, next + 2)?;
+            offset = next + 4;
+            if qclass & !CLASS_TOP_BIT != CLASS_IN {
+                continue;
+            }
+            questions.push(MdnsQuestion {
+                name,
+                qtype,
+                unicast_response: qclass & CLASS_TOP_BIT != 0,
+            });
+        }
+        Some(MdnsQuery { id, questions })
+    }
+}
+
+/// Builds the response with the A record of `name`. A legacy unicast query, which is sent
+/// from a port other than 5353, is answered with its id and question as a plain DNS response.
+pub fn build_a_response(
+    name: &str,
+    addr: Ipv4Addr,
+    legacy_query: Option<(u16, &MdnsQuestion)>,
+) -> Vec<u8> {
+    let mut packet = vec![];
+    let id = legacy_query.map_or(0, |(id, _)| id);
+    push_u16(&mut packet, id);
+    push_u16(&mut packet, FLAG_RESPONSE | FLAG_AUTHORITATIVE);
+    push_u16(&mut packet, legacy_query.map_or(0, |_| 1));
+    push_u16(&mut packet, 1);
+    push_u16(&mut packet, 0);
+    push_u16(&mut packet, 0);
+    if let Some((_, question)) = legacy_query {
+        push_name(&mut packet, &question.name);
+        push_u16(&mut packet, question.qtype);
+        push_u16(&mut packet, CLASS_IN);
+    }
+    push_name(&mut packet, name);
+    push_u16(&mut packet, TYPE_A);
+    // Legacy resolvers don't know the cache-flush bit.
+    let class = if legacy_query.is_some() {
+        CLASS_IN
+    } else {
+        CLASS_IN | CLASS_TOP_BIT
+    };
+    push_u16(&mut packet, class);
+    let ttl = if legacy_query.is_some() {
+        // RFC 6762 6.7: at most 10 seconds for legacy unicast responses.
+        10
+    } else {
+        MDNS_TTL_SEC
+    };
+    packet.extend_from_slice(&ttl.to_be_bytes());
+    push_u16(&mut packet, 4);
+    packet.extend_from_slice(&addr.octets());
+    packet
+}
+
+fn read_u16(packet: &[u8], offset: usize) -> Option<u16> {
+    let bytes = packet.get(offset..offset + 2)?;
+    Some(u16::from_be_bytes([bytes[0], bytes[1]]))
+}
+
+/// Reads a name, following the compression pointers. Returns the name and the offset next to it.
+fn read_name(packet: &[u8], offset: usize) -> Option<(String, usize)> {
+    let mut labels = vec![];
+    let mut offset = offset;
+    let mut next = None;
+    let mut jumps = 0;
+    loop {
+        let len = *packet.get(offset)? as usize;
+        if len == 0 {
+            return Some((labels.join("."), next.unwrap_or(offset + 1)));
+        }
+        if len & 0xc0 == 0xc0 {
+            jumps += 1;
+            if jumps > MAX_POINTER_JUMPS {
+                return None;
+            }
+            let pointer = (read_u16(packet, offset)? & 0x3fff) as usize;
+            next.get_or_insert(offset + 2);
+            offset = pointer;
+            continue;
+        }
+        let label = packet.get(offset + 1..offset + 1 + len)?;
+        labels.push(String::from_utf8_lossy(label).into_owned());
+        offset += 1 + len;
+    }
+}
+
+fn push_u16(packet: &mut Vec<u8>, value: u16) {
+    packet.extend_from_slice(&value.to_be_bytes());
+}
+
+fn push_name(packet: &mut Vec<u8>, name: &str) {
+    for label in name.split('.').filter(|label| !label.is_empty()) {
+        packet.push(label.len() as u8);
+        packet.extend_from_slice(label.as_bytes());
+    }
+    packet.push(0);
+}
+
+#[cfg(test)]
+mod test_mdns {
+    use super::*;
+
+    fn build_query(id: u16, names: &[(&str, u16, u16)]) -> Vec<u8> {
+        let mut packet = vec![];
+        push_u16(&mut packet, id);
+        push_u16(&mut packet, 0);
+        push_u16(&mut packet, names.len() as u16);
+        packet.extend_from_slice(&[0; 6]);
+        for (name, qtype, qclass) in names {
+            push_name(&mut packet, name);
+            push_u16(&mut packet, *qtype);
+            push_u16(&mut packet, *qclass);
+        }
+        packet
+    }
+
+    #[test]
+    fn test_parse_query() {
+        let query = MdnsQuery::parse(&build_query(
+            7,
+            &[
+                ("Ubuntu.local", TYPE_A, CLASS_IN | CLASS_TOP_BIT),
+                ("x.local", 28, CLASS_IN),
+            ],
+        ))
+        .unwrap();
+        assert_eq!(7, query.id);
+        assert_eq!(2, query.questions.len());
+        assert!(query.questions[0].asks_ipv4_of("ubuntu.local"));
+        assert!(query.questions[0].unicast_response);
+        assert!(!query.questions[1].asks_ipv4_of("x.local"));
+
+        let mut response = build_query(0, &[]);
+        response[2] = 0x84;
+        assert_eq!(None, MdnsQuery::parse(&response));
+        assert_eq!(None, MdnsQuery::parse(&[0; 4]));
+        let mut truncated = build_query(0, &[("ubuntu.local", TYPE_A, CLASS_IN)]);
+        truncated.truncate(truncated.len() - 3);
+        assert_eq!(None, MdnsQuery::parse(&truncated));
+    }
+
+    #[test]
+    fn test_read_compressed_name() {
+        let mut packet = build_query(0, &[("ubuntu.local", TYPE_A, CLASS_IN)]);
+        let second = packet.len();
+        // "api" followed by a pointer to "local" of the first name.
+        packet.extend_from_slice(&[3, b'a', b'p', b'i', 0xc0, (HEADER_LEN + 7) as u8]);
+        assert_eq!(
+            Some(("api.local".to_owned(), second + 6)),
+            read_name(&packet, second)
+        );
+        // A pointer to itself.
+        let looped = vec![0xc0, 0];
+        assert_eq!(None, read_name(&looped, 0));
+    }
+
+    #[test]
+    fn test_build_a_response() {
+        let response = build_a_response("ubuntu.local", Ipv4Addr::new(172, 20, 0, 2), None);
+        let mut expected = vec![0, 0, 0x84, 0, 0, 0, 0, 1, 0, 0, 0, 0];
+        expected.extend_from_slice(b"\x06ubuntu\x05local\x00");
+        expected.extend_from_slice(&[0, 1, 0x80, 1, 0, 0, 0, 120, 0, 4, 172, 20, 0, 2]);
+        assert_eq!(expected, response);
+
+        let question = MdnsQuestion {
+            name: "ubuntu.local".to_owned(),
+            qtype: TYPE_A,
+            unicast_response: false,
+        };
+        let response = build_a_response(
+            "ubuntu.local",
+            Ipv4Addr::new(172, 20, 0, 2),
+            Some((9, &question)),
+        );
+        assert_eq!(&[0, 9], &response[0..2]);
+        assert_eq!(&[0, 1, 0, 1], &response[4..8]);
+    }
+}
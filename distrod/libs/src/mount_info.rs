@@ -1,6 +1,9 @@
 use std::{
-    fs::File,
+    collections::HashSet,
+    ffi::OsStr,
+    fs::{self, File},
     io::{BufRead, BufReader},
+    os::unix::ffi::OsStrExt,
     path::PathBuf,
 };
 
@@ -37,3 +40,64 @@ pub fn get_mount_entries() -> Result<Vec<MountEntry>> {
 
     Ok(mount_entries)
 }
+
+/// Returns the mount points in the mount namespace of this process. Unlike /proc/mounts, the
+/// mountinfo lists the bind mounts of a subdirectory and of the files as well.
+pub fn get_mount_points() -> Result<HashSet<PathBuf>> {
+    let mountinfo = fs::read_to_string("/proc/self/mountinfo")
+        .with_context(|| "Failed to read '/proc/self/mountinfo'")?;
+    Ok(parse_mount_points(&mountinfo))
+}
+
+fn parse_mount_points(mountinfo: &str) -> HashSet<PathBuf> {
+    mountinfo
+        .lines()
+        .filter_map(|line| line.split(' ').nth(4))
+        .map(unescape_mount_path)
+        .collect()
+}
+
+/// The kernel escapes the space, tab, newline, and backslash in the paths by the octal, such as
+/// `\040` for a space.
+fn unescape_mount_path(path: &str) -> PathBuf {
+    let bytes = path.as_bytes();
+    let mut unescaped = Vec::with_capacity(bytes.len());
+    let mut i = 0;
+    while i < bytes.len() {
+        if bytes[i] == b'\\' && i + 4 <= bytes.len() {
+            let octal = std::str::from_utf8(&bytes[i + 1..i + 4]).ok();
+            if let Some(byte) = octal.and_then(|octal| u8::from_str_radix(octal, 8).ok()) {
+                unescaped.push(byte);
+                i += 4;
+                continue;
+            }
+        }
+        unescaped.push(bytes[i]);
+        i += 1;
+    }
+    PathBuf::from(OsStr::from_bytes(&unescaped))
+}
+
+#[cfg(test)]
+mod test_mount_info {
+    use super::*;
+
+    #[test]
+    fn test_parse_mount_points() {
+        let mountinfo = "\
+22 1 8:2 / / rw,relatime shared:1 - ext4 /dev/sda2 rw
+35 22 0:30 /@home /home rw,relatime shared:2 - btrfs /dev/sdb1 rw,subvol=/@home
+41 22 0:5 /data/My\\040Files /mnt/My\\040Files rw - ext4 /dev/sda2 rw
+42 22 0:5 /etc/hosts /srv/hosts rw - ext4 /dev/sda2 rw
+";
+        let mount_points = parse_mount_points(mountinfo);
+        assert_eq!(4, mount_points.len());
+        assert!(mount_points.contains(&PathBuf::from("/home")));
+        assert!(mount_points.contains(&PathBuf::from("/mnt/My Files")));
+        assert!(mount_points.contains(&PathBuf::from("/srv/hosts")));
+        assert_eq!(
+            PathBuf::from("/a\\b\\0x"),
+            unescape_mount_path("/a\\134b\\0x")
+        );
+    }
+}
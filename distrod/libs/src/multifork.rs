@@ -1,14 +1,32 @@
 use anyhow::{anyhow, Context, Result};
-use nix::fcntl::OFlag;
-use nix::libc::c_int;
-use nix::sys::signal;
-use std::convert::From;
+use nix::libc::{self, c_int, c_void};
+use nix::sys::signal::{self, SaFlags, SigAction, SigHandler, SigSet};
+use nix::sys::wait::{WaitPidFlag, WaitStatus};
+use std::convert::{From, TryFrom};
 use std::fs::File;
-use std::io::{Read, Write};
+use std::io::{ErrorKind, Read, Write};
 use std::ops::Deref;
-use std::os::unix::io::FromRawFd;
-use std::os::unix::prelude::CommandExt;
-use std::process::Command;
+use std::os::unix::io::{AsRawFd, FromRawFd, IntoRawFd};
+use std::os::unix::net::UnixStream;
+use std::os::unix::prelude::{CommandExt, ExitStatusExt};
+use std::process::{Command, ExitStatus};
+use std::sync::atomic::{AtomicBool, AtomicI32, Ordering};
+
+/// The signals which the waiters forward to the commands.
+const FORWARDED_SIGNALS: &[signal::Signal] = &[
+    signal::SIGHUP,
+    signal::SIGINT,
+    signal::SIGQUIT,
+    signal::SIGTERM,
+    signal::SIGUSR1,
+    signal::SIGUSR2,
+];
+
+/// The socket to the proxy process which the handler of the forwarded signals writes to, or -1.
+static SIGNAL_FORWARDING_SOCKET: AtomicI32 = AtomicI32::new(-1);
+
+/// Whether the handler of the forwarded signals forwards the ones sent by the kernel as well.
+static FORWARDS_KERNEL_SIGNALS: AtomicBool = AtomicBool::new(false);
 
 pub struct CommandByMultiFork<'a> {
     command: Command,
@@ -52,10 +70,15 @@ impl<'a> CommandByMultiFork<'a> {
     pub fn insert_waiter_proxy(&mut self) -> Result<Waiter> {
         let (proxy, waiter) =
             ProxyProcess::make_pair().with_context(|| "Failed to make a proxy process.")?;
-        self.proxy_process = Some(proxy);
+        self.insert_proxy(proxy);
         Ok(waiter)
     }
 
+    pub fn insert_proxy(&mut self, proxy: ProxyProcess) -> &mut CommandByMultiFork<'a> {
+        self.proxy_process = Some(proxy);
+        self
+    }
+
     pub fn spawn(mut self) -> Result<()> {
         if unsafe { nix::unistd::fork().with_context(|| "The first fork failed")? }.is_child() {
             let inner = || -> Result<()> {
@@ -122,6 +145,34 @@ pub struct Waiter {
 }
 
 impl Waiter {
+    /// Makes a waiter which reads the exit code from the file, such as a socket connected to
+    /// the proxy process of another process.
+    pub fn from_exit_code_reader(reader: File) -> Waiter {
+        Waiter {
+            pipe_for_exitcode: reader,
+        }
+    }
+
+    /// Forwards the signals this process gets, such as SIGTERM on the close of the session,
+    /// to the command through its proxy process until the waiter is dropped.
+    /// The signals the terminal sends to its foreground process group, such as SIGINT by Ctrl-C,
+    /// are forwarded only if `includes_terminal_signals`, since the command gets them by itself
+    /// when it's in the process group of this process.
+    pub fn forward_signals(&self, includes_terminal_signals: bool) -> Result<()> {
+        FORWARDS_KERNEL_SIGNALS.store(includes_terminal_signals, Ordering::SeqCst);
+        SIGNAL_FORWARDING_SOCKET.store(self.pipe_for_exitcode.as_raw_fd(), Ordering::SeqCst);
+        let action = SigAction::new(
+            SigHandler::SigAction(forward_signal),
+            SaFlags::SA_RESTART,
+            SigSet::empty(),
+        );
+        for sig in FORWARDED_SIGNALS {
+            unsafe { signal::sigaction(*sig, &action) }
+                .with_context(|| format!("Failed to set the handler of {:?}.", sig))?;
+        }
+        Ok(())
+    }
+
     pub fn wait(&mut self) -> u32 {
         let mut exit_code = vec![137]; // The exit code for SIGKILL
         let res = self
@@ -137,21 +188,63 @@ impl Waiter {
     }
 }
 
+impl Drop for Waiter {
+    fn drop(&mut self) {
+        let _ = SIGNAL_FORWARDING_SOCKET.compare_exchange(
+            self.pipe_for_exitcode.as_raw_fd(),
+            -1,
+            Ordering::SeqCst,
+            Ordering::SeqCst,
+        );
+    }
+}
+
+extern "C" fn forward_signal(sig: c_int, info: *mut libc::siginfo_t, _context: *mut c_void) {
+    if !info.is_null()
+        && unsafe { (*info).si_code } == libc::SI_KERNEL
+        && !FORWARDS_KERNEL_SIGNALS.load(Ordering::SeqCst)
+    {
+        return;
+    }
+    let socket = SIGNAL_FORWARDING_SOCKET.load(Ordering::SeqCst);
+    if socket >= 0 {
+        // write(2) is async-signal-safe.
+        unsafe {
+            libc::write(socket, &(sig as u8) as *const u8 as *const c_void, 1);
+        }
+    }
+}
+
 pub struct ProxyProcess {
     pipe_for_exitcode: File,
+    hangs_up_command: bool,
 }
 
 impl ProxyProcess {
+    /// Makes a proxy which writes the exit code to the socket connected to the waiter.
+    /// The command gets SIGHUP when the peer closes the socket before the command exits,
+    /// as it would when its terminal is closed.
+    pub fn from_exit_code_socket(socket: File) -> ProxyProcess {
+        ProxyProcess {
+            pipe_for_exitcode: socket,
+            hangs_up_command: true,
+        }
+    }
+
+    /// Makes a proxy and its waiter connected by a socket, which carries the exit code to the
+    /// waiter and the signals forwarded by the waiter to the proxy.
     pub fn make_pair() -> Result<(ProxyProcess, Waiter)> {
-        let (waiter_pipe_host, waiter_pipe_child) =
-            nix::unistd::pipe2(OFlag::O_CLOEXEC).with_context(|| "Failed to make a pipe.")?;
+        // UnixStream::pair sets FD_CLOEXEC to the both ends.
+        let (waiter_socket, proxy_socket) =
+            UnixStream::pair().with_context(|| "Failed to make a socket pair.")?;
         unsafe {
             Ok((
                 ProxyProcess {
-                    pipe_for_exitcode: File::from_raw_fd(waiter_pipe_child),
+                    pipe_for_exitcode: File::from_raw_fd(proxy_socket.into_raw_fd()),
+                    hangs_up_command: false,
                 },
                 Waiter {
-                    pipe_for_exitcode: File::from_raw_fd(waiter_pipe_host),
+                    pipe_for_exitcode: File::from_raw_fd(waiter_socket.into_raw_fd()),
                 },
             ))
         }
@@ -165,14 +258,35 @@ impl ProxyProcess {
             let mut child = command
                 .spawn()
                 .with_context(|| "Failed to run a command.")?;
+            let mut peer = self
+                .pipe_for_exitcode
+                .try_clone()
+                .with_context(|| "Failed to clone the socket.")?;
+            let pid = nix::unistd::Pid::from_raw(child.id() as i32);
+            let hangs_up_command = self.hangs_up_command;
+            std::thread::spawn(move || {
+                // The peer writes only the numbers of the signals to forward to the command.
+                let mut buf = [0u8; 1];
+                loop {
+                    match peer.read(&mut buf) {
+                        Ok(1) => match signal::Signal::try_from(buf[0] as c_int) {
+                            Ok(sig) => {
+                                let _ = signal::kill(pid, sig);
+                            }
+                            Err(_) => log::debug!("Unknown signal is forwarded: {}", buf[0]),
+                        },
+                        Err(e) if e.kind() == ErrorKind::Interrupted => continue,
+                        _ => break,
+                    }
+                }
+                if hangs_up_command {
+                    let _ = signal::kill(pid, signal::SIGHUP);
+                }
+            });
             let status = child
                 .wait()
                 .with_context(|| "Failed to wait wthe command.")?;
-            let exit_code = status
-                .code()
-                .ok_or_else(|| anyhow!("status.code() is None unexpectedly."))?
-                as u8;
-            let exit_code = vec![exit_code];
+            let exit_code = vec![get_exit_code(status)?];
             if let Err(e) = self.pipe_for_exitcode.write_all(&exit_code) {
                 log::debug!("Failed to write the exit code to the pipe. {}", e);
             }
@@ -182,6 +296,29 @@ impl ProxyProcess {
     }
 }
 
+/// Returns the exit code of the command as a shell reports it, which is 128 + N for the commands
+/// killed by the signal N.
+fn get_exit_code(status: ExitStatus) -> Result<u8> {
+    if let Some(code) = status.code() {
+        return Ok(code as u8);
+    }
+    let signal = status
+        .signal()
+        .ok_or_else(|| anyhow!("The command has neither an exit code nor a signal."))?;
+    Ok((128 + signal) as u8)
+}
+
+/// Reaps the exited children without blocking, such as the first children of the triple forks,
+/// which exit right after they fork.
+pub fn reap_children() {
+    loop {
+        match nix::sys::wait::waitpid(None, Some(WaitPidFlag::WNOHANG)) {
+            Ok(WaitStatus::StillAlive) | Err(_) => break,
+            Ok(_) => continue,
+        }
+    }
+}
+
 pub fn set_noninheritable_sig_ign() {
     for signal in signal::Signal::iterator() {
         // Ignore signals by a function instead of SIG_IGN so that the child doesn't inherit it.
@@ -223,4 +360,33 @@ mod tests {
         let exit_code = waiter.wait();
         assert_eq!(42, exit_code);
     }
+
+    #[test]
+    fn test_exit_code_by_signal() {
+        let mut command = Command::new("/bin/bash");
+        command.args(&["-c", "kill -SIGTERM $$"]);
+        let mut doublefork = CommandByMultiFork::new(command);
+        let mut waiter = doublefork.insert_waiter_proxy().unwrap();
+        let _ = doublefork.spawn().unwrap();
+        let exit_code = waiter.wait();
+        assert_eq!(128 + signal::SIGTERM as u32, exit_code);
+    }
+
+    #[test]
+    fn test_forward_signals() {
+        let mut command = Command::new("/bin/bash");
+        command.args(&["-c", "trap 'exit 42' SIGTERM; sleep 10 & wait"]);
+        let mut doublefork = CommandByMultiFork::new(command);
+        let mut waiter = doublefork.insert_waiter_proxy().unwrap();
+        let _ = doublefork.spawn().unwrap();
+        // Write the signal as the handler does, since signaling the test process would be
+        // handled by the other tests running in it as well.
+        std::thread::sleep(std::time::Duration::from_millis(500));
+        waiter
+            .pipe_for_exitcode
+            .write_all(&[signal::SIGTERM as u8])
+            .unwrap();
+        let exit_code = waiter.wait();
+        assert_eq!(42, exit_code);
+    }
 }
@@ -0,0 +1,550 @@
+use anyhow::{bail, Context, Result};
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeSet, HashSet};
+use std::net::{IpAddr, SocketAddr};
+use std::path::Path;
+
+use crate::connection_log::ConnectionLogConfig;
+
+#[cfg(target_os = "linux")]
+use procfs::net::TcpState;
+
+/// A change of the forwarded ports, which `distrod port watch` passes to portproxy.exe on Windows
+/// as a line on its stdin, such as "add 8080".
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum PortForwardCommand {
+    Add(u16),
+    Remove(u16),
+}
+
+impl PortForwardCommand {
+    pub fn parse_line(line: &str) -> Result<PortForwardCommand> {
+        let mut fields = line.split_whitespace();
+        let (command, port) = match (fields.next(), fields.next(), fields.next()) {
+            (Some(command), Some(port), None) => (command, port),
+            _ => bail!("Malformed command: {}", line),
+        };
+        let port = port
+            .parse::<u16>()
+            .with_context(|| format!("Invalid port: {}", port))?;
+        match command {
+            "add" => Ok(PortForwardCommand::Add(port)),
+            "remove" => Ok(PortForwardCommand::Remove(port)),
+            _ => bail!("Unknown command: {}", command),
+        }
+    }
+
+    pub fn to_line(&self) -> String {
+        match self {
+            PortForwardCommand::Add(port) => format!("add {}\n", port),
+            PortForwardCommand::Remove(port) => format!("remove {}\n", port),
+        }
+    }
+}
+
+/// Returns the commands which change the forwards of `current` into the ones of `next`.
+pub fn diff_ports(current: &BTreeSet<u16>, next: &BTreeSet<u16>) -> Vec<PortForwardCommand> {
+    let removed = current
+        .difference(next)
+        .map(|port| PortForwardCommand::Remove(*port));
+    let added = next
+        .difference(current)
+        .map(|port| PortForwardCommand::Add(*port));
+    removed.chain(added).collect()
+}
+
+/// The rules file of the forwards which portproxy.service keeps, in addition to tcp4_ports.
+/// portproxy.exe reloads it when it changes.
+#[derive(Serialize, Deserialize, Clone, Debug, Default, PartialEq, Eq)]
+pub struct PortForwardRules {
+    /// Takes precedence over `--firewall` of portproxy.exe if given.
+    pub firewall: Option<FirewallConfig>,
+    /// Takes precedence over `--metrics-address` of portproxy.exe if given.
+    pub metrics: Option<MetricsConfig>,
+    /// Takes precedence over `--connection-log` and `--connection-log-file` of portproxy.exe if
+    /// given.
+    pub connection_log: Option<ConnectionLogConfig>,
+    #[serde(default, rename = "forward")]
+    pub forwards: Vec<PortForwardRule>,
+}
+
+/// The inbound rules of Windows Firewall which portproxy.exe adds for the forwards while they are
+/// open, so that the other machines reach them.
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Eq)]
+pub struct FirewallConfig {
+    #[serde(default)]
+    pub enabled: bool,
+    /// The remote addresses the rules allow, such as "LocalSubnet", "192.168.1.0/24", or "Any".
+    #[serde(default = "default_firewall_remote_addresses")]
+    pub remote_addresses: Vec<String>,
+}
+
+impl FirewallConfig {
+    /// The remote addresses to allow if it's enabled.
+    pub fn get_enabled_remote_addresses(&self) -> Option<Vec<String>> {
+        if !self.enabled {
+            return None;
+        }
+        if self.remote_addresses.is_empty() {
+            return Some(default_firewall_remote_addresses());
+        }
+        Some(self.remote_addresses.clone())
+    }
+}
+
+pub fn default_firewall_remote_addresses() -> Vec<String> {
+    vec!["LocalSubnet".to_owned()]
+}
+
+/// The HTTP endpoint of the Prometheus metrics of the forwards, which portproxy.exe serves on
+/// Windows at /metrics.
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Eq)]
+pub struct MetricsConfig {
+    /// The address to listen on, such as "0.0.0.0:9464" for a Prometheus on another machine.
+    #[serde(default = "default_metrics_listen_address")]
+    pub listen_address: String,
+}
+
+pub fn default_metrics_listen_address() -> String {
+    "127.0.0.1:9464".to_owned()
+}
+
+const RULES_FILE_HEADER: &str =
+    "# The forwards from Windows which portproxy.service keeps, in addition to tcp4_ports.\n\
+     # Written by `distrod port add` and `distrod port remove`, which don't keep the comments.\n\n";
+
+/// The group of the firewall rules, by which they can be deleted at once.
+pub const FIREWALL_RULE_GROUP: &str = "Distrod";
+
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Eq, Hash)]
+pub struct PortForwardRule {
+    /// The port on Windows.
+    pub listen_port: u16,
+    /// The address of the interface on Windows to listen on, such as 127.0.0.1 to accept only
+    /// the connections from Windows itself.
+    #[serde(default = "default_listen_address")]
+    pub listen_address: String,
+    /// The address to forward to. Defaults to the address of eth0 of WSL.
+    pub dest_address: Option<String>,
+    /// The port to forward to. Defaults to `listen_port`.
+    pub dest_port: Option<u16>,
+    #[serde(default)]
+    pub protocol: Protocol,
+}
+
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq, Eq, Hash)]
+#[serde(rename_all = "lowercase")]
+pub enum Protocol {
+    Tcp,
+    Udp,
+}
+
+impl Protocol {
+    /// The name in upper case, such as "TCP".
+    pub fn name(&self) -> &'static str {
+        match self {
+            Protocol::Tcp => "TCP",
+            Protocol::Udp => "UDP",
+        }
+    }
+}
+
+impl Default for Protocol {
+    fn default() -> Self {
+        Protocol::Tcp
+    }
+}
+
+fn default_listen_address() -> String {
+    "0.0.0.0".to_owned()
+}
+
+impl PortForwardRules {
+    pub fn parse(content: &str) -> Result<PortForwardRules> {
+        let rules: PortForwardRules =
+            toml::from_str(content).with_context(|| "Failed to parse the rules.")?;
+        rules.validate()?;
+        Ok(rules)
+    }
+
+    /// Reads the rules file. No rules if it doesn't exist yet.
+    pub fn open<P: AsRef<Path>>(path: P) -> Result<PortForwardRules> {
+        let path = path.as_ref();
+        match std::fs::read_to_string(path) {
+            Ok(content) => PortForwardRules::parse(&content)
+                .with_context(|| format!("Invalid port forwarding rules in {:?}.", path)),
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(PortForwardRules::default()),
+            Err(e) => Err(e).with_context(|| format!("Failed to read {:?}.", path)),
+        }
+    }
+
+    /// Saves the rules. The file is replaced atomically so that portproxy.exe never reads a
+    /// partial file. The comments in the file are not kept.
+    pub fn save<P: AsRef<Path>>(&self, path: P) -> Result<()> {
+        let path = path.as_ref();
+        let mut content = String::from(RULES_FILE_HEADER);
+        content.push_str(&toml::to_string(self).with_context(|| "Failed to serialize the rules.")?);
+        let tmp_path = path.with_extension("tmp");
+        std::fs::write(&tmp_path, content)
+            .with_context(|| format!("Failed to write {:?}.", &tmp_path))?;
+        std::fs::rename(&tmp_path, path)
+            .with_context(|| format!("Failed to rename {:?} to {:?}.", &tmp_path, path))?;
+        Ok(())
+    }
+
+    /// Adds the rule unless it conflicts with the existing ones.
+    pub fn add(&mut self, rule: PortForwardRule) -> Result<()> {
+        self.forwards.push(rule);
+        if let Err(e) = self.validate() {
+            self.forwards.pop();
+            return Err(e);
+        }
+        Ok(())
+    }
+
+    /// Removes the rules of the listen port, only the ones on `listen_address` if given, and
+    /// returns them.
+    pub fn remove(
+        &mut self,
+        listen_port: u16,
+        listen_address: Option<&str>,
+        protocol: Protocol,
+    ) -> Vec<PortForwardRule> {
+        let (removed, kept) = self.forwards.drain(..).partition(|rule| {
+            rule.listen_port == listen_port
+                && rule.protocol == protocol
+                && listen_address.map_or(true, |address| rule.listen_address == address)
+        });
+        self.forwards = kept;
+        removed
+    }
+
+    fn validate(&self) -> Result<()> {
+        if let Some(ref metrics) = self.metrics {
+            if metrics.listen_address.parse::<SocketAddr>().is_err() {
+                bail!(
+                    "Invalid listen_address of [metrics]: {}. It should be such as 0.0.0.0:9464.",
+                    metrics.listen_address
+                );
+            }
+        }
+        let mut listens = HashSet::new();
+        for rule in &self.forwards {
+            if rule.listen_port == 0 || rule.dest_port == Some(0) {
+                bail!("The port 0 can't be forwarded.");
+            }
+            if !listens.insert((&rule.listen_address, rule.listen_port, rule.protocol)) {
+                bail!(
+                    "{} is forwarded more than once.",
+                    rule.listen_socket_address()
+                );
+            }
+        }
+        Ok(())
+    }
+}
+
+impl PortForwardRule {
+    /// The address to bind, such as "0.0.0.0:8080" or "[::]:8080".
+    pub fn listen_socket_address(&self) -> String {
+        to_socket_address(&self.listen_address, self.listen_port)
+    }
+
+    /// Whether it needs a firewall rule to be reached from the other machines, that is, it doesn't
+    /// listen only on the loopback.
+    pub fn is_exposed(&self) -> bool {
+        self.listen_address
+            .trim_start_matches('[')
+            .trim_end_matches(']')
+            .parse::<IpAddr>()
+            .map_or(true, |ip| !ip.is_loopback())
+    }
+
+    /// The display name of the firewall rule of this forward.
+    pub fn firewall_rule_name(&self) -> String {
+        format!(
+            "Distrod portproxy {} {}",
+            self.protocol.name(),
+            self.listen_socket_address()
+        )
+    }
+
+    /// The Powershell command which replaces the firewall rule of this forward with the one that
+    /// allows the inbound connections from `remote_addresses` to the listen port.
+    pub fn build_add_firewall_rule_command(&self, remote_addresses: &[String]) -> String {
+        let remote_addresses: Vec<String> = remote_addresses
+            .iter()
+            .map(|address| quote_posh_string(address))
+            .collect();
+        let mut command = format!(
+            "{}; New-NetFirewallRule -DisplayName {} -Group {} -Direction Inbound -Action Allow \
+             -Protocol {} -LocalPort {} -RemoteAddress {}",
+            self.build_delete_firewall_rule_command(),
+            quote_posh_string(&self.firewall_rule_name()),
+            quote_posh_string(FIREWALL_RULE_GROUP),
+            self.protocol.name(),
+            self.listen_port,
+            remote_addresses.join(","),
+        );
+        let unspecified = self
+            .listen_address
+            .parse::<IpAddr>()
+            .map_or(false, |ip| ip.is_unspecified());
+        if !unspecified {
+            command.push_str(&format!(
+                " -LocalAddress {}",
+                quote_posh_string(&self.listen_address)
+            ));
+        }
+        command.push_str(" | Out-Null");
+        command
+    }
+
+    pub fn build_delete_firewall_rule_command(&self) -> String {
+        format!(
+            "Remove-NetFirewallRule -DisplayName {} -ErrorAction SilentlyContinue",
+            quote_posh_string(&self.firewall_rule_name())
+        )
+    }
+
+    /// The address to forward to, taking `default_dest_address` if the rule has none.
+    pub fn dest_socket_address(&self, default_dest_address: &str) -> String {
+        to_socket_address(
+            self.dest_address.as_deref().unwrap_or(default_dest_address),
+            self.dest_port.unwrap_or(self.listen_port),
+        )
+    }
+}
+
+fn quote_posh_string(value: &str) -> String {
+    format!("'{}'", value.replace('\'', "''"))
+}
+
+fn to_socket_address(address: &str, port: u16) -> String {
+    if address.contains(':') && !address.starts_with('[') {
+        format!("[{}]:{}", address, port)
+    } else {
+        format!("{}:{}", address, port)
+    }
+}
+
+/// Returns the TCP ports listened on in the network namespace, which the distros share with WSL.
+/// The ones listened only on the loopback are left out, since portproxy.exe connects to them by
+/// the address of eth0.
+#[cfg(target_os = "linux")]
+pub fn collect_listening_tcp_ports() -> Result<BTreeSet<u16>> {
+    let mut entries = procfs::net::tcp().with_context(|| "Failed to read /proc/net/tcp.")?;
+    entries.extend(procfs::net::tcp6().with_context(|| "Failed to read /proc/net/tcp6.")?);
+    Ok(entries
+        .into_iter()
+        .filter(|entry| is_reachable_listener(&entry.state, &entry.local_address))
+        .map(|entry| entry.local_address.port())
+        .collect())
+}
+
+#[cfg(target_os = "linux")]
+fn is_reachable_listener(state: &TcpState, local_address: &SocketAddr) -> bool {
+    if *state != TcpState::Listen {
+        return false;
+    }
+    match local_address.ip() {
+        IpAddr::V4(ip) => !ip.is_loopback(),
+        // IPv4 clients reach a socket on [::] unless it's IPv6 only, and the mapped addresses.
+        IpAddr::V6(ip) => {
+            ip.is_unspecified()
+                || (!ip.is_loopback() && ip.to_ipv4().map_or(false, |ip| !ip.is_loopback()))
+        }
+    }
+}
+
+#[cfg(test)]
+mod test_port_forward {
+    use super::*;
+
+    #[test]
+    fn test_command_line() {
+        for command in &[
+            PortForwardCommand::Add(8080),
+            PortForwardCommand::Remove(22),
+        ] {
+            assert_eq!(
+                *command,
+                PortForwardCommand::parse_line(command.to_line().trim_end()).unwrap()
+            );
+        }
+        assert!(PortForwardCommand::parse_line("add").is_err());
+        assert!(PortForwardCommand::parse_line("add 70000").is_err());
+        assert!(PortForwardCommand::parse_line("open 80").is_err());
+        assert!(PortForwardCommand::parse_line("add 80 81").is_err());
+    }
+
+    #[test]
+    fn test_diff_ports() {
+        let current: BTreeSet<u16> = vec![22, 80, 3000].into_iter().collect();
+        let next: BTreeSet<u16> = vec![22, 443, 8080].into_iter().collect();
+        assert_eq!(
+            vec![
+                PortForwardCommand::Remove(80),
+                PortForwardCommand::Remove(3000),
+                PortForwardCommand::Add(443),
+                PortForwardCommand::Add(8080),
+            ],
+            diff_ports(&current, &next)
+        );
+        assert!(diff_ports(&next, &next).is_empty());
+    }
+
+    #[test]
+    fn test_parse_rules() {
+        let rules = PortForwardRules::parse(
+            r#"
+            [[forward]]
+            listen_port = 8080
+            dest_port = 80
+
+            [[forward]]
+            listen_port = 53
+            listen_address = "::1"
+            dest_address = "172.20.0.3"
+            protocol = "udp"
+            "#,
+        )
+        .unwrap();
+        assert_eq!(2, rules.forwards.len());
+        let web = &rules.forwards[0];
+        assert_eq!(Protocol::Tcp, web.protocol);
+        assert_eq!("0.0.0.0:8080", web.listen_socket_address());
+        assert_eq!("172.20.0.2:80", web.dest_socket_address("172.20.0.2"));
+        let dns = &rules.forwards[1];
+        assert_eq!(Protocol::Udp, dns.protocol);
+        assert_eq!("[::1]:53", dns.listen_socket_address());
+        assert_eq!("172.20.0.3:53", dns.dest_socket_address("172.20.0.2"));
+
+        assert_eq!(
+            PortForwardRules::default(),
+            PortForwardRules::parse("").unwrap()
+        );
+        assert!(PortForwardRules::parse("[[forward]]\nlisten_port = 0\n").is_err());
+        assert_eq!(
+            default_metrics_listen_address(),
+            PortForwardRules::parse("[metrics]\n")
+                .unwrap()
+                .metrics
+                .unwrap()
+                .listen_address
+        );
+        assert!(PortForwardRules::parse("[metrics]\nlisten_address = \"9464\"\n").is_err());
+        assert!(PortForwardRules::parse(
+            "[[forward]]\nlisten_port = 22\n[[forward]]\nlisten_port = 22\ndest_port = 2222\n"
+        )
+        .is_err());
+        // The same port can be forwarded for TCP and UDP.
+        assert!(PortForwardRules::parse(
+            "[[forward]]\nlisten_port = 53\n[[forward]]\nlisten_port = 53\nprotocol = \"udp\"\n"
+        )
+        .is_ok());
+    }
+
+    #[test]
+    fn test_edit_rules() {
+        let tmpdir = tempfile::TempDir::new().unwrap();
+        let path = tmpdir.path().join("port_forwards.toml");
+        let mut rules = PortForwardRules::open(&path).unwrap();
+        rules.add(tcp_rule_for_test(8080)).unwrap();
+        assert!(rules.add(tcp_rule_for_test(8080)).is_err());
+        let mut dns = tcp_rule_for_test(53);
+        dns.protocol = Protocol::Udp;
+        dns.dest_port = Some(5353);
+        rules.add(dns.clone()).unwrap();
+        rules.firewall = Some(FirewallConfig {
+            enabled: true,
+            remote_addresses: default_firewall_remote_addresses(),
+        });
+        rules.metrics = Some(MetricsConfig {
+            listen_address: default_metrics_listen_address(),
+        });
+        rules.save(&path).unwrap();
+        assert_eq!(rules, PortForwardRules::open(&path).unwrap());
+
+        assert!(rules.remove(53, None, Protocol::Tcp).is_empty());
+        assert!(rules
+            .remove(8080, Some("127.0.0.1"), Protocol::Tcp)
+            .is_empty());
+        assert_eq!(vec![dns], rules.remove(53, None, Protocol::Udp));
+        assert_eq!(vec![tcp_rule_for_test(8080)], rules.forwards);
+    }
+
+    #[test]
+    fn test_firewall_rule_command() {
+        let rules = PortForwardRules::parse(
+            r#"
+            [firewall]
+            enabled = true
+
+            [[forward]]
+            listen_port = 8080
+
+            [[forward]]
+            listen_port = 53
+            listen_address = "127.0.0.1"
+            protocol = "udp"
+            "#,
+        )
+        .unwrap();
+        let remote_addresses = rules
+            .firewall
+            .as_ref()
+            .unwrap()
+            .get_enabled_remote_addresses()
+            .unwrap();
+        assert_eq!(vec!["LocalSubnet".to_owned()], remote_addresses);
+        let web = &rules.forwards[0];
+        assert!(web.is_exposed());
+        assert_eq!(
+            "Remove-NetFirewallRule -DisplayName 'Distrod portproxy TCP 0.0.0.0:8080' \
+             -ErrorAction SilentlyContinue; \
+             New-NetFirewallRule -DisplayName 'Distrod portproxy TCP 0.0.0.0:8080' -Group 'Distrod' \
+             -Direction Inbound -Action Allow -Protocol TCP -LocalPort 8080 \
+             -RemoteAddress 'LocalSubnet','10.0.0.0/8' | Out-Null",
+            web.build_add_firewall_rule_command(&[
+                "LocalSubnet".to_owned(),
+                "10.0.0.0/8".to_owned()
+            ])
+        );
+        assert!(!rules.forwards[1].is_exposed());
+
+        let mut quoted = tcp_rule_for_test(22);
+        quoted.listen_address = "1.2.3.4'; rm".to_owned();
+        assert!(quoted
+            .build_add_firewall_rule_command(&["Any".to_owned()])
+            .contains("-LocalAddress '1.2.3.4''; rm'"));
+        assert_eq!(None, PortForwardRules::parse("").unwrap().firewall);
+    }
+
+    fn tcp_rule_for_test(port: u16) -> PortForwardRule {
+        PortForwardRule {
+            listen_port: port,
+            listen_address: "0.0.0.0".to_owned(),
+            dest_address: None,
+            dest_port: None,
+            protocol: Protocol::Tcp,
+        }
+    }
+
+    #[cfg(target_os = "linux")]
+    #[test]
+    fn test_is_reachable_listener() {
+        let listener =
+            |addr: &str| is_reachable_listener(&TcpState::Listen, &addr.parse().unwrap());
+        assert!(listener("0.0.0.0:8080"));
+        assert!(listener("172.20.0.2:8080"));
+        assert!(listener("[::]:8080"));
+        assert!(!listener("127.0.0.1:8080"));
+        assert!(!listener("[::1]:8080"));
+        assert!(!listener("[::ffff:127.0.0.1]:8080"));
+        assert!(!is_reachable_listener(
+            &TcpState::Established,
+            &"0.0.0.0:8080".parse().unwrap()
+        ));
+    }
+}
@@ -0,0 +1,142 @@
+use std::fmt::Write;
+
+/// The counters of a forwarded port since portproxy.exe started, which it serves to Prometheus.
+/// The forwards of TCP and UDP on the same port are counted together.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct PortMetrics {
+    pub port: u16,
+    /// The open TCP connections and the UDP clients seen within the session timeout.
+    pub active_connections: u64,
+    pub connections: u64,
+    /// From the clients to the service.
+    pub bytes_in: u64,
+    /// From the service to the clients.
+    pub bytes_out: u64,
+    /// The connections and the datagrams failed to be relayed, such as when the service is down.
+    pub errors: u64,
+}
+
+/// The path portproxy.exe serves the metrics at.
+pub const METRICS_PATH: &str = "/metrics";
+
+/// The Content-Type of the text format of Prometheus.
+pub const METRICS_CONTENT_TYPE: &str = "text/plain; version=0.0.4; charset=utf-8";
+
+/// Formats the metrics in the text format of Prometheus, sorted by the ports.
+pub fn format_prometheus_metrics(metrics: &[PortMetrics]) -> String {
+    let mut metrics = metrics.to_vec();
+    metrics.sort_by_key(|port_metrics| port_metrics.port);
+
+    let mut text = String::new();
+    let mut write_metric = |name: &str, kind: &str, help: &str, samples: Vec<(String, u64)>| {
+        // Writing to a String never fails.
+        let _ = writeln!(text, "# HELP {} {}", name, help);
+        let _ = writeln!(text, "# TYPE {} {}", name, kind);
+        for (labels, value) in samples {
+            let _ = writeln!(text, "{}{{{}}} {}", name, labels, value);
+        }
+    };
+    let port_label = |port_metrics: &PortMetrics| format!("port=\"{}\"", port_metrics.port);
+    write_metric(
+        "distrod_portproxy_active_connections",
+        "gauge",
+        "The connections open through the forward.",
+        metrics
+            .iter()
+            .map(|m| (port_label(m), m.active_connections))
+            .collect(),
+    );
+    write_metric(
+        "distrod_portproxy_connections_total",
+        "counter",
+        "The connections accepted by the forward.",
+        metrics
+            .iter()
+            .map(|m| (port_label(m), m.connections))
+            .collect(),
+    );
+    write_metric(
+        "distrod_portproxy_transferred_bytes_total",
+        "counter",
+        "The bytes transferred through the forward. in is from the clients to the service.",
+        metrics
+            .iter()
+            .flat_map(|m| {
+                vec![
+                    (format!("{},direction=\"in\"", port_label(m)), m.bytes_in),
+                    (format!("{},direction=\"out\"", port_label(m)), m.bytes_out),
+                ]
+            })
+            .collect(),
+    );
+    write_metric(
+        "distrod_portproxy_errors_total",
+        "counter",
+        "The connections and the datagrams the forward failed to relay.",
+        metrics.iter().map(|m| (port_label(m), m.errors)).collect(),
+    );
+    text
+}
+
+/// Returns the path of the request line of an HTTP request, such as "/metrics" of
+/// "GET /metrics HTTP/1.1". None unless it's a GET.
+pub fn parse_http_get_path(request: &str) -> Option<&str> {
+    let mut fields = request.lines().next()?.split_whitespace();
+    match (fields.next(), fields.next(), fields.next()) {
+        (Some("GET"), Some(target), Some(version)) if version.starts_with("HTTP/") => {
+            // Prometheus doesn't add a query, but ignore it in case.
+            Some(target.split('?').next().unwrap_or(target))
+        }
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod test_port_metrics {
+    use super::*;
+
+    #[test]
+    fn test_format_prometheus_metrics() {
+        let ssh = PortMetrics {
+            port: 22,
+            active_connections: 1,
+            connections: 3,
+            bytes_in: 100,
+            bytes_out: 2000,
+            errors: 0,
+        };
+        let web = PortMetrics {
+            port: 8080,
+            errors: 2,
+            ..PortMetrics::default()
+        };
+        let text = format_prometheus_metrics(&[web, ssh]);
+        assert!(text.starts_with(
+            "# HELP distrod_portproxy_active_connections The connections open through the forward.\n\
+             # TYPE distrod_portproxy_active_connections gauge\n\
+             distrod_portproxy_active_connections{port=\"22\"} 1\n\
+             distrod_portproxy_active_connections{port=\"8080\"} 0\n"
+        ));
+        assert!(text.contains(
+            "distrod_portproxy_transferred_bytes_total{port=\"22\",direction=\"in\"} 100\n\
+             distrod_portproxy_transferred_bytes_total{port=\"22\",direction=\"out\"} 2000\n"
+        ));
+        assert!(text.contains("distrod_portproxy_errors_total{port=\"8080\"} 2\n"));
+        assert!(text.ends_with('\n'));
+    }
+
+    #[test]
+    fn test_parse_http_get_path() {
+        assert_eq!(
+            Some("/metrics"),
+            parse_http_get_path("GET /metrics HTTP/1.1\r\nHost: localhost:9464\r\n\r\n")
+        );
+        assert_eq!(
+            Some("/metrics"),
+            parse_http_get_path("GET /metrics?name[]=x HTTP/1.0\r\n")
+        );
+        assert_eq!(None, parse_http_get_path("POST /metrics HTTP/1.1\r\n"));
+        assert_eq!(None, parse_http_get_path("GET /metrics\r\n"));
+        assert_eq!(None, parse_http_get_path(""));
+    }
+}
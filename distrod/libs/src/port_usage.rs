@@ -0,0 +1,143 @@
+use anyhow::{Context, Result};
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::fs::File;
+use std::io::{BufReader, BufWriter};
+use std::path::Path;
+
+/// The bytes transferred through a forwarded port.
+/// `bytes_in` is from the clients to the service, and `bytes_out` is from the service to the clients.
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct PortUsage {
+    pub bytes_in: u64,
+    pub bytes_out: u64,
+}
+
+impl PortUsage {
+    pub fn total(&self) -> u64 {
+        self.bytes_in.saturating_add(self.bytes_out)
+    }
+
+    fn add(&mut self, other: &PortUsage) {
+        self.bytes_in = self.bytes_in.saturating_add(other.bytes_in);
+        self.bytes_out = self.bytes_out.saturating_add(other.bytes_out);
+    }
+}
+
+/// The bandwidth usage of the forwarded ports aggregated by month.
+/// The keys of `months` are in the "YYYY-MM" format, so they are sorted chronologically.
+#[derive(Serialize, Deserialize, Clone, Debug, Default)]
+pub struct PortUsageStats {
+    pub months: BTreeMap<String, BTreeMap<u16, PortUsage>>,
+}
+
+impl PortUsageStats {
+    /// Opens the stats file. An empty stats is returned if the file doesn't exist yet.
+    pub fn open<P: AsRef<Path>>(path: P) -> Result<PortUsageStats> {
+        let path = path.as_ref();
+        if !path.exists() {
+            return Ok(PortUsageStats::default());
+        }
+        let file = File::open(path)
+            .with_context(|| format!("Failed to open the port usage stats {:?}.", path))?;
+        serde_json::from_reader(BufReader::new(file))
+            .with_context(|| format!("Failed to parse the port usage stats {:?}.", path))
+    }
+
+    /// Saves the stats. The file is replaced atomically so that readers never see a partial file.
+    pub fn save<P: AsRef<Path>>(&self, path: P) -> Result<()> {
+        let path = path.as_ref();
+        if let Some(dir) = path.parent() {
+            std::fs::create_dir_all(dir)
+                .with_context(|| format!("Failed to create the directory {:?}.", dir))?;
+        }
+        let tmp_path = path.with_extension("tmp");
+        let tmp_file = File::create(&tmp_path)
+            .with_context(|| format!("Failed to create {:?}.", &tmp_path))?;
+        serde_json::to_writer(BufWriter::new(tmp_file), self)
+            .with_context(|| format!("Failed to write the port usage stats to {:?}.", &tmp_path))?;
+        std::fs::rename(&tmp_path, path)
+            .with_context(|| format!("Failed to rename {:?} to {:?}.", &tmp_path, path))?;
+        Ok(())
+    }
+
+    pub fn add(&mut self, month: &str, port: u16, usage: &PortUsage) {
+        self.months
+            .entry(month.to_owned())
+            .or_default()
+            .entry(port)
+            .or_default()
+            .add(usage);
+    }
+
+    pub fn get_month(&self, month: &str) -> Option<&BTreeMap<u16, PortUsage>> {
+        self.months.get(month)
+    }
+
+    pub fn get_month_total(&self, month: &str) -> PortUsage {
+        let mut total = PortUsage::default();
+        if let Some(ports) = self.months.get(month) {
+            for usage in ports.values() {
+                total.add(usage);
+            }
+        }
+        total
+    }
+}
+
+/// Returns the current month in the "YYYY-MM" format, which is the key of `PortUsageStats::months`.
+pub fn get_current_month() -> String {
+    chrono::Local::now().format("%Y-%m").to_string()
+}
+
+#[cfg(test)]
+mod test_port_usage_stats {
+    use super::*;
+
+    #[test]
+    fn test_add_accumulates_by_month_and_port() {
+        let mut stats = PortUsageStats::default();
+        let usage = PortUsage {
+            bytes_in: 10,
+            bytes_out: 100,
+        };
+        stats.add("2021-10", 80, &usage);
+        stats.add("2021-10", 80, &usage);
+        stats.add("2021-10", 443, &usage);
+        stats.add("2021-11", 80, &usage);
+
+        let october = stats.get_month("2021-10").unwrap();
+        assert_eq!(
+            PortUsage {
+                bytes_in: 20,
+                bytes_out: 200
+            },
+            october[&80]
+        );
+        assert_eq!(usage, october[&443]);
+        assert_eq!(330, stats.get_month_total("2021-10").total());
+        assert_eq!(110, stats.get_month_total("2021-11").total());
+        assert_eq!(0, stats.get_month_total("2021-12").total());
+    }
+
+    #[test]
+    fn test_save_and_open() {
+        let tmp_dir = tempfile::tempdir().unwrap();
+        let path = tmp_dir.path().join("stats/port_usage.json");
+        assert!(PortUsageStats::open(&path).unwrap().months.is_empty());
+
+        let mut stats = PortUsageStats::default();
+        stats.add(
+            "2021-10",
+            8080,
+            &PortUsage {
+                bytes_in: 1,
+                bytes_out: 2,
+            },
+        );
+        stats.save(&path).unwrap();
+
+        let stats = PortUsageStats::open(&path).unwrap();
+        assert_eq!(3, stats.get_month_total("2021-10").total());
+    }
+}
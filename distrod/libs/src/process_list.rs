@@ -0,0 +1,228 @@
+use anyhow::{Context, Result};
+use std::collections::HashMap;
+use std::fs;
+use std::io::ErrorKind;
+use std::path::Path;
+
+use crate::passwd::PasswdFile;
+
+/// A process of a distro, read from the /proc of the distro.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ProcessEntry {
+    /// The PID in the PID namespace of the distro.
+    pub pid: u32,
+    pub uid: u32,
+    /// The systemd unit the process belongs to, such as "nginx.service" or "session-1.scope".
+    pub unit: Option<String>,
+    /// The command line, or the name in brackets as in ps if the process has none, such as a
+    /// zombie.
+    pub command: String,
+}
+
+/// Lists the processes in a /proc, such as the one mounted in a distro, sorted by the PID.
+/// The processes which exit while they are read are left out.
+pub fn list_processes<P: AsRef<Path>>(proc_dir: P) -> Result<Vec<ProcessEntry>> {
+    let proc_dir = proc_dir.as_ref();
+    let mut processes = vec![];
+    for entry in
+        fs::read_dir(proc_dir).with_context(|| format!("Failed to read {:?}.", proc_dir))?
+    {
+        let entry = entry?;
+        let pid = match entry
+            .file_name()
+            .to_str()
+            .and_then(|name| name.parse().ok())
+        {
+            Some(pid) => pid,
+            None => continue,
+        };
+        match read_process(&entry.path(), pid) {
+            Ok(process) => processes.push(process),
+            Err(e) if is_gone(&e) => continue,
+            Err(e) => {
+                return Err(e).with_context(|| format!("Failed to read the process {}.", pid))
+            }
+        }
+    }
+    processes.sort_by_key(|process| process.pid);
+    Ok(processes)
+}
+
+fn read_process(pid_dir: &Path, pid: u32) -> std::io::Result<ProcessEntry> {
+    let status = fs::read_to_string(pid_dir.join("status"))?;
+    let uid = parse_uid(&status)
+        .ok_or_else(|| std::io::Error::new(ErrorKind::InvalidData, "No Uid in the status file."))?;
+    // The cgroup file can't be read if the cgroup filesystem isn't mounted.
+    let unit = fs::read_to_string(pid_dir.join("cgroup"))
+        .ok()
+        .and_then(|cgroup| parse_unit(&cgroup));
+    let cmdline = fs::read(pid_dir.join("cmdline"))?;
+    let command = if cmdline.is_empty() {
+        let comm = fs::read_to_string(pid_dir.join("comm"))?;
+        format!("[{}]", comm.trim_end())
+    } else {
+        cmdline
+            .split(|c| *c == 0)
+            .filter(|arg| !arg.is_empty())
+            .map(String::from_utf8_lossy)
+            .collect::<Vec<_>>()
+            .join(" ")
+    };
+    Ok(ProcessEntry {
+        pid,
+        uid,
+        unit,
+        command,
+    })
+}
+
+fn is_gone(error: &std::io::Error) -> bool {
+    // The files of an exited process fail by ESRCH after its directory is listed.
+    error.kind() == ErrorKind::NotFound || error.raw_os_error() == Some(nix::libc::ESRCH)
+}
+
+/// Takes the real uid from the "Uid:" line of /proc/PID/status.
+fn parse_uid(status: &str) -> Option<u32> {
+    status
+        .lines()
+        .find_map(|line| line.strip_prefix("Uid:"))
+        .and_then(|uids| uids.split_whitespace().next())
+        .and_then(|uid| uid.parse().ok())
+}
+
+/// Finds the innermost unit in the systemd hierarchy of /proc/PID/cgroup, which is the unified one
+/// on cgroup v2 and "name=systemd" on v1. The path may have the cgroup of the distro on top when
+/// the distro doesn't have its own cgroup namespace, so any prefix is skipped.
+fn parse_unit(cgroup: &str) -> Option<String> {
+    let path = cgroup
+        .lines()
+        .find_map(|line| line.strip_prefix("0::"))
+        .or_else(|| {
+            cgroup
+                .lines()
+                .find_map(|line| line.splitn(2, ":name=systemd:").nth(1))
+        })?;
+    path.rsplit('/')
+        .find(|name| name.ends_with(".service") || name.ends_with(".scope"))
+        .map(|name| name.to_owned())
+}
+
+/// Maps the uids to the user names by /etc/passwd of the distro.
+pub fn get_user_names<P: AsRef<Path>>(passwd_path: P) -> Result<HashMap<u32, String>> {
+    let mut passwd_file = PasswdFile::open(passwd_path)?;
+    let mut names = HashMap::new();
+    for entry in passwd_file.entries() {
+        let entry = match entry {
+            Ok(entry) => entry,
+            // Such as the empty last line.
+            Err(_) => continue,
+        };
+        names
+            .entry(entry.uid)
+            .or_insert_with(|| entry.name.to_owned());
+    }
+    Ok(names)
+}
+
+#[cfg(test)]
+mod test_process_list {
+    use super::*;
+
+    fn put_process(proc_dir: &Path, pid: u32, uid: u32, cgroup: &str, cmdline: &[u8], comm: &str) {
+        let pid_dir = proc_dir.join(pid.to_string());
+        fs::create_dir_all(&pid_dir).unwrap();
+        fs::write(
+            pid_dir.join("status"),
+            format!(
+                "Name:\t{}\nUid:\t{}\t{}\t{}\t{}\n",
+                comm, uid, uid, uid, uid
+            ),
+        )
+        .unwrap();
+        fs::write(pid_dir.join("cgroup"), cgroup).unwrap();
+        fs::write(pid_dir.join("cmdline"), cmdline).unwrap();
+        fs::write(pid_dir.join("comm"), format!("{}\n", comm)).unwrap();
+    }
+
+    #[test]
+    fn test_list_processes() {
+        let tmpdir = tempfile::tempdir().unwrap();
+        put_process(
+            tmpdir.path(),
+            120,
+            33,
+            "0::/system.slice/nginx.service\n",
+            b"nginx: worker process\0",
+            "nginx",
+        );
+        put_process(
+            tmpdir.path(),
+            1,
+            0,
+            "0::/init.scope\n",
+            b"/sbin/init\0",
+            "systemd",
+        );
+        put_process(tmpdir.path(), 7, 1000, "", b"", "defunct");
+        // Not a process.
+        fs::create_dir_all(tmpdir.path().join("sys")).unwrap();
+
+        let processes = list_processes(tmpdir.path()).unwrap();
+        assert_eq!(
+            vec![
+                ProcessEntry {
+                    pid: 1,
+                    uid: 0,
+                    unit: Some("init.scope".to_owned()),
+                    command: "/sbin/init".to_owned(),
+                },
+                ProcessEntry {
+                    pid: 7,
+                    uid: 1000,
+                    unit: None,
+                    command: "[defunct]".to_owned(),
+                },
+                ProcessEntry {
+                    pid: 120,
+                    uid: 33,
+                    unit: Some("nginx.service".to_owned()),
+                    command: "nginx: worker process".to_owned(),
+                },
+            ],
+            processes
+        );
+    }
+
+    #[test]
+    fn test_parse_unit() {
+        assert_eq!(
+            Some("bash.service".to_owned()),
+            parse_unit("0::/user.slice/user-1000.slice/user@1000.service/app.slice/bash.service\n")
+        );
+        // The cgroup of the distro seen from outside of its cgroup namespace.
+        assert_eq!(
+            Some("session-1.scope".to_owned()),
+            parse_unit("0::/distrod/ubuntu/user.slice/user-1000.slice/session-1.scope\n")
+        );
+        assert_eq!(
+            Some("cron.service".to_owned()),
+            parse_unit("12:cpu,cpuacct:/\n1:name=systemd:/system.slice/cron.service\n")
+        );
+        assert_eq!(None, parse_unit("0::/\n"));
+    }
+
+    #[test]
+    fn test_get_user_names() {
+        let tmpdir = tempfile::tempdir().unwrap();
+        let passwd_path = tmpdir.path().join("passwd");
+        fs::write(
+            &passwd_path,
+            "root:x:0:0:root:/root:/bin/bash\nalice:x:1000:1000::/home/alice:/bin/zsh\n",
+        )
+        .unwrap();
+        let names = get_user_names(&passwd_path).unwrap();
+        assert_eq!(Some("root"), names.get(&0).map(|name| name.as_str()));
+        assert_eq!(Some("alice"), names.get(&1000).map(|name| name.as_str()));
+        assert_eq!(None, names.get(&33));
+    }
+}
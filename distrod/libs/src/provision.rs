@@ -0,0 +1,392 @@
+use anyhow::{bail, Context, Result};
+use serde::Deserialize;
+use std::fs;
+use std::io::Write;
+use std::os::unix::fs::PermissionsExt;
+use std::path::{Component, Path};
+
+use crate::container::{ContainerPath, HostPath};
+use crate::distro_config::validate_user_name;
+use crate::envfile::single_quote_str_for_shell;
+use crate::hooks::HookPoint;
+use crate::passwd::PasswdFile;
+use crate::rootfs_dir::RootfsDir;
+
+/// The name of the post-start hook which provisions the distro on its first boot. It removes
+/// itself once it succeeds, and runs again on the next start if it fails.
+const FIRST_BOOT_HOOK_NAME: &str = "00-distrod-provision";
+
+/// The package managers the first boot script tries in order, with the commands which install
+/// the packages given after them.
+const PACKAGE_INSTALL_COMMANDS: &[(&str, &str)] = &[
+    (
+        "apt-get",
+        "apt-get update && DEBIAN_FRONTEND=noninteractive apt-get install -y",
+    ),
+    ("dnf", "dnf install -y"),
+    ("yum", "yum install -y"),
+    ("zypper", "zypper --non-interactive install"),
+    ("pacman", "pacman -Sy --noconfirm --needed"),
+    ("apk", "apk add"),
+];
+
+/// The spec of `distrod create --provision`, which makes the same distro from an image every time.
+/// The users and the files are made when the distro is created, and the packages, the services,
+/// and the commands are set up by a post-start hook on the first boot, since they need the
+/// network and systemd.
+// Keep the fields which can be empty arrays first, since TOML doesn't allow values after tables.
+#[derive(Deserialize, Clone, Debug, Default, PartialEq)]
+#[serde(deny_unknown_fields)]
+pub struct ProvisionSpec {
+    /// The packages to install by the package manager of the distro.
+    #[serde(default)]
+    pub packages: Vec<String>,
+    /// The systemd units to enable and start, after the packages are installed.
+    #[serde(default)]
+    pub services: Vec<String>,
+    /// The shell commands to run as root at last, in order.
+    #[serde(default)]
+    pub commands: Vec<String>,
+    #[serde(default)]
+    pub users: Vec<UserSpec>,
+    #[serde(default)]
+    pub files: Vec<FileSpec>,
+}
+
+#[derive(Deserialize, Clone, Debug, Default, PartialEq)]
+#[serde(deny_unknown_fields)]
+pub struct UserSpec {
+    pub name: String,
+    pub uid: Option<u32>,
+    /// The groups to add the user to, besides the admin group.
+    #[serde(default)]
+    pub groups: Vec<String>,
+    /// Adds the user to the admin group, such as sudo or wheel.
+    #[serde(default)]
+    pub admin: bool,
+    /// Lets the user use sudo without the password. It implies `admin`.
+    #[serde(default)]
+    pub passwordless_sudo: bool,
+    /// Makes the user the one `distrod shell` and WSL log in as. It implies `admin`.
+    #[serde(default)]
+    pub default: bool,
+}
+
+#[derive(Deserialize, Clone, Debug, Default, PartialEq)]
+#[serde(deny_unknown_fields)]
+pub struct FileSpec {
+    /// The absolute path in the distro. The missing parent directories are made.
+    pub path: String,
+    pub content: String,
+    /// The permission in octal, such as "0644".
+    pub mode: Option<String>,
+    /// The user who owns the file, which can be one of the users of the spec. Defaults to root.
+    pub owner: Option<String>,
+}
+
+impl ProvisionSpec {
+    /// Reads and validates the spec in TOML.
+    pub fn load<P: AsRef<Path>>(path: P) -> Result<ProvisionSpec> {
+        let path = path.as_ref();
+        let cont =
+            fs::read_to_string(path).with_context(|| format!("Failed to read {:?}.", path))?;
+        let spec = ProvisionSpec::from_toml_str(&cont)
+            .with_context(|| format!("Failed to parse the provisioning spec {:?}.", path))?;
+        Ok(spec)
+    }
+
+    pub fn from_toml_str(cont: &str) -> Result<ProvisionSpec> {
+        let spec: ProvisionSpec = toml::from_str(cont)?;
+        spec.validate()?;
+        Ok(spec)
+    }
+
+    fn validate(&self) -> Result<()> {
+        for user in &self.users {
+            validate_user_name(&user.name)?;
+            for group in &user.groups {
+                validate_user_name(group)
+                    .with_context(|| format!("Invalid group of {}.", &user.name))?;
+            }
+        }
+        if self.users.iter().filter(|user| user.default).count() > 1 {
+            bail!("Only one user can be the default user.");
+        }
+        for file in &self.files {
+            file.get_container_path()?;
+            file.get_mode()?;
+        }
+        for unit in &self.services {
+            if unit.is_empty() || unit.contains('/') {
+                bail!("Invalid unit name: {:?}", unit);
+            }
+        }
+        Ok(())
+    }
+
+    /// Writes the files of the spec into the rootfs. The users must have been created.
+    pub fn write_files(&self, rootfs: &HostPath) -> Result<()> {
+        for file in &self.files {
+            file.write(rootfs)
+                .with_context(|| format!("Failed to write {}.", &file.path))?;
+        }
+        Ok(())
+    }
+
+    /// Puts the post-start hook which sets up the packages, the services, and the commands on
+    /// the first boot. Nothing is put if the spec has none of them.
+    pub fn put_first_boot_hook(&self, rootfs: &HostPath) -> Result<()> {
+        let script = match self.build_first_boot_script() {
+            Some(script) => script,
+            None => return Ok(()),
+        };
+        let hook_path = ContainerPath::new(
+            HookPoint::PostStart
+                .get_dir_path()?
+                .as_path()
+                .join(FIRST_BOOT_HOOK_NAME),
+        )?
+        .to_host_path(rootfs);
+        let hook_dir = hook_path.parent().expect("the hook has a directory");
+        fs::create_dir_all(hook_dir)
+            .with_context(|| format!("Failed to create {:?}.", hook_dir))?;
+        fs::write(hook_path.as_path(), script)
+            .with_context(|| format!("Failed to write {:?}.", &hook_path))?;
+        // Hooks which others can modify are skipped.
+        fs::set_permissions(hook_path.as_path(), fs::Permissions::from_mode(0o755))
+            .with_context(|| format!("Failed to set the permission of {:?}.", &hook_path))?;
+        Ok(())
+    }
+
+    /// Returns the shell script of the first boot hook, or None if it has nothing to do.
+    pub fn build_first_boot_script(&self) -> Option<String> {
+        if self.packages.is_empty() && self.services.is_empty() && self.commands.is_empty() {
+            return None;
+        }
+        let mut script = String::from(
+            "#!/bin/sh\n\
+             # Generated by `distrod create --provision`. This runs on the first boot of the distro,\n\
+             # and removes itself once it succeeds.\n\
+             set -e\n",
+        );
+        if !self.services.is_empty() || !self.packages.is_empty() {
+            // The packages need the network, which systemd brings up.
+            script.push_str(
+                "if command -v systemctl >/dev/null 2>&1; then\n    \
+                     systemctl is-system-running --wait >/dev/null 2>&1 || true\n\
+                 fi\n",
+            );
+        }
+        if !self.packages.is_empty() {
+            let packages = quote_all(&self.packages);
+            for (i, (program, install_command)) in PACKAGE_INSTALL_COMMANDS.iter().enumerate() {
+                let keyword = if i == 0 { "if" } else { "elif" };
+                script.push_str(&format!(
+                    "{} command -v {} >/dev/null 2>&1; then\n    {} {}\n",
+                    keyword, program, install_command, packages
+                ));
+            }
+            script.push_str(
+                "else\n    \
+                     echo 'No package manager is found to install the packages.' >&2\n    \
+                     exit 1\n\
+                 fi\n",
+            );
+        }
+        if !self.services.is_empty() {
+            script.push_str(&format!(
+                "systemctl enable --now {}\n",
+                quote_all(&self.services)
+            ));
+        }
+        for command in &self.commands {
+            script.push_str(command.trim_end());
+            script.push('\n');
+        }
+        script.push_str("rm -f -- \"$0\"\n");
+        Some(script)
+    }
+}
+
+impl FileSpec {
+    fn get_mode(&self) -> Result<Option<u32>> {
+        self.mode
+            .as_ref()
+            .map(|mode| {
+                u32::from_str_radix(mode, 8)
+                    .ok()
+                    .filter(|mode| *mode <= 0o7777)
+                    .with_context(|| format!("Invalid mode of {}: {:?}", &self.path, mode))
+            })
+            .transpose()
+    }
+
+    /// Returns the path, which must be absolute and have no '..', so that the file stays in the
+    /// rootfs.
+    fn get_container_path(&self) -> Result<ContainerPath> {
+        let path = Path::new(&self.path);
+        if !path.has_root() {
+            bail!("The path of a file should be absolute: {:?}", &self.path);
+        }
+        if path.file_name().is_none()
+            || path
+                .components()
+                .any(|component| !matches!(component, Component::RootDir | Component::Normal(_)))
+        {
+            bail!("The path of a file must not have '..': {:?}", &self.path);
+        }
+        ContainerPath::new(path)
+    }
+
+    fn write(&self, rootfs: &HostPath) -> Result<()> {
+        let path = self.get_container_path()?;
+        let name = path.file_name().expect("[BUG] the path is validated.");
+        // Neither the parent directories nor the file are followed if they are symlinks, which
+        // would redirect the write and the chown out of the rootfs.
+        let dir = RootfsDir::create_dir_all(
+            rootfs,
+            &ContainerPath::new(path.parent().expect("[BUG] the path is validated."))?,
+        )?;
+        let mut file = dir.create_file(name, 0o644)?;
+        file.write_all(self.content.as_bytes())
+            .with_context(|| format!("Failed to write {:?}.", &path))?;
+        if let Some(mode) = self.get_mode()? {
+            file.set_permissions(fs::Permissions::from_mode(mode))
+                .with_context(|| format!("Failed to set the permission of {:?}.", &path))?;
+        }
+        if let Some(ref owner) = self.owner {
+            let passwd_path = ContainerPath::new("/etc/passwd")?.to_host_path(rootfs);
+            let mut passwd_file = PasswdFile::open(passwd_path.as_path())
+                .with_context(|| format!("Failed to open {:?}.", &passwd_path))?;
+            let (uid, gid) = match passwd_file.get_ent_by_name(owner)? {
+                Some(entry) => (entry.uid, entry.gid),
+                None => bail!("The owner {} doesn't exist in the distro.", owner),
+            };
+            dir.chown(name, uid, gid)?;
+        }
+        Ok(())
+    }
+}
+
+fn quote_all(words: &[String]) -> String {
+    words
+        .iter()
+        .map(|word| single_quote_str_for_shell(word))
+        .collect::<Vec<_>>()
+        .join(" ")
+}
+
+#[cfg(test)]
+mod test_provision {
+    use super::*;
+
+    #[test]
+    fn test_parse_spec() {
+        let spec = ProvisionSpec::from_toml_str(
+            r#"
+            packages = ["git", "build-essential"]
+            services = ["docker.service"]
+            commands = ["echo done > /root/done"]
+
+            [[users]]
+            name = "alice"
+            default = true
+            groups = ["docker"]
+
+            [[files]]
+            path = "/etc/motd"
+            content = "Welcome\n"
+            mode = "0644"
+            "#,
+        )
+        .unwrap();
+        assert_eq!(vec!["git", "build-essential"], spec.packages);
+        assert!(spec.users[0].default);
+        assert_eq!(Some(0o644), spec.files[0].get_mode().unwrap());
+
+        assert!(ProvisionSpec::from_toml_str("pakages = [\"git\"]").is_err());
+        assert!(
+            ProvisionSpec::from_toml_str("[[files]]\npath = \"etc/motd\"\ncontent = \"\"\n")
+                .is_err()
+        );
+        assert!(ProvisionSpec::from_toml_str(
+            "[[files]]\npath = \"/etc/motd\"\ncontent = \"\"\nmode = \"0999\"\n"
+        )
+        .is_err());
+        assert!(ProvisionSpec::from_toml_str(
+            "[[files]]\npath = \"/etc/../root/.bashrc\"\ncontent = \"\"\n"
+        )
+        .is_err());
+        assert!(ProvisionSpec::from_toml_str(
+            "[[users]]\nname = \"a\"\ndefault = true\n[[users]]\nname = \"b\"\ndefault = true\n"
+        )
+        .is_err());
+    }
+
+    #[test]
+    fn test_build_first_boot_script() {
+        let spec = ProvisionSpec {
+            packages: vec!["git".to_owned(), "it's".to_owned()],
+            services: vec!["nginx.service".to_owned()],
+            commands: vec!["echo hello\n".to_owned()],
+            ..ProvisionSpec::default()
+        };
+        let script = spec.build_first_boot_script().unwrap();
+        assert!(script.starts_with("#!/bin/sh\n"));
+        assert!(script.contains(
+            "if command -v apt-get >/dev/null 2>&1; then\n    apt-get update && \
+             DEBIAN_FRONTEND=noninteractive apt-get install -y 'git' 'it'\"'\"'s'\n"
+        ));
+        assert!(script.contains("elif command -v apk >/dev/null 2>&1; then\n    apk add 'git'"));
+        assert!(script.contains("systemctl enable --now 'nginx.service'\n"));
+        assert!(script.ends_with("echo hello\nrm -f -- \"$0\"\n"));
+
+        assert_eq!(None, ProvisionSpec::default().build_first_boot_script());
+    }
+
+    #[test]
+    fn test_write_files() {
+        let tempdir = tempfile::tempdir().unwrap();
+        let rootfs = HostPath::new(tempdir.path()).unwrap();
+        let spec = ProvisionSpec {
+            files: vec![FileSpec {
+                path: "/etc/profile.d/team.sh".to_owned(),
+                content: "export TEAM=1\n".to_owned(),
+                mode: Some("0600".to_owned()),
+                owner: None,
+            }],
+            ..ProvisionSpec::default()
+        };
+        spec.write_files(&rootfs).unwrap();
+        let path = tempdir.path().join("etc/profile.d/team.sh");
+        assert_eq!("export TEAM=1\n", fs::read_to_string(&path).unwrap());
+        assert_eq!(
+            0o600,
+            fs::metadata(&path).unwrap().permissions().mode() & 0o7777
+        );
+    }
+
+    #[test]
+    fn test_write_files_not_following_symlinks() {
+        let tempdir = tempfile::tempdir().unwrap();
+        let rootfs = HostPath::new(tempdir.path().join("rootfs")).unwrap();
+        let outside = tempdir.path().join("outside");
+        fs::create_dir_all(rootfs.join("etc")).unwrap();
+        fs::create_dir_all(&outside).unwrap();
+        std::os::unix::fs::symlink(&outside, rootfs.join("etc/profile.d")).unwrap();
+        std::os::unix::fs::symlink(outside.join("motd"), rootfs.join("etc/motd")).unwrap();
+
+        for path in &["/etc/profile.d/team.sh", "/etc/motd"] {
+            let spec = ProvisionSpec {
+                files: vec![FileSpec {
+                    path: path.to_string(),
+                    content: "export TEAM=1\n".to_owned(),
+                    ..FileSpec::default()
+                }],
+                ..ProvisionSpec::default()
+            };
+            assert!(spec.write_files(&rootfs).is_err());
+        }
+        assert_eq!(0, fs::read_dir(&outside).unwrap().count());
+    }
+}
@@ -0,0 +1,241 @@
+use anyhow::{Context, Result};
+use nix::fcntl::{fcntl, FcntlArg, FdFlag};
+use nix::libc;
+use nix::pty::Winsize;
+use nix::sys::signal::{self, SaFlags, SigAction, SigHandler, SigSet};
+use nix::sys::termios::{self, SetArg, Termios};
+use std::fs::File;
+use std::io::{self, Read, Write};
+use std::os::unix::io::{AsRawFd, FromRawFd, RawFd};
+use std::os::unix::process::CommandExt;
+use std::process::{Command, Stdio};
+use std::sync::atomic::{AtomicI32, Ordering};
+use std::sync::mpsc;
+use std::time::Duration;
+
+use crate::multifork::Waiter;
+
+/// How long the output left in the pty is relayed after the command exits. The background
+/// processes of the command may keep the pty open.
+const OUTPUT_DRAIN_TIMEOUT: Duration = Duration::from_millis(200);
+
+/// The write end of the pipe which the handler of SIGWINCH writes to, or -1.
+static WINCH_PIPE_WRITER: AtomicI32 = AtomicI32::new(-1);
+
+/// A pseudo terminal for a command, which this process relays to its own terminal.
+pub struct Pty {
+    master: File,
+    slave: File,
+}
+
+impl Pty {
+    /// Opens a pty which has the window size of the terminal of this process.
+    pub fn open() -> Result<Pty> {
+        let window_size =
+            get_window_size(libc::STDIN_FILENO).or_else(|| get_window_size(libc::STDOUT_FILENO));
+        let pty = nix::pty::openpty(window_size.as_ref(), None::<&Termios>)
+            .with_context(|| "Failed to open a pty.")?;
+        // The command shouldn't inherit the master, or the pty is never closed.
+        for fd in &[pty.master, pty.slave] {
+            fcntl(*fd, FcntlArg::F_SETFD(FdFlag::FD_CLOEXEC))
+                .with_context(|| "Failed to set FD_CLOEXEC to the pty.")?;
+        }
+        unsafe {
+            Ok(Pty {
+                master: File::from_raw_fd(pty.master),
+                slave: File::from_raw_fd(pty.slave),
+            })
+        }
+    }
+
+    /// Returns the terminal for the command, which `attach_terminal` gives to it.
+    pub fn try_clone_slave(&self) -> Result<File> {
+        self.slave
+            .try_clone()
+            .with_context(|| "Failed to duplicate the pty.")
+    }
+
+    /// Relays the terminal of this process and the pty until the command exits, and returns its
+    /// exit code. The terminal is put in the raw mode meanwhile, so that the keys such as Ctrl-C
+    /// and the resizes of the window go to the command.
+    pub fn relay(self, waiter: &mut Waiter) -> Result<u32> {
+        // The command has its own copies of the slave.
+        drop(self.slave);
+        let _raw_mode = RawModeGuard::enter(libc::STDIN_FILENO)?;
+        let mut winch_reader = watch_window_size()?;
+
+        let master_fd = self.master.as_raw_fd();
+        std::thread::spawn(move || {
+            let mut buf = [0u8; 1];
+            while let Ok(1) = winch_reader.read(&mut buf) {
+                if let Some(window_size) = get_window_size(libc::STDIN_FILENO) {
+                    let _ = set_window_size(master_fd, &window_size);
+                }
+            }
+        });
+        let mut master_writer = self
+            .master
+            .try_clone()
+            .with_context(|| "Failed to duplicate the pty.")?;
+        std::thread::spawn(move || {
+            // This thread ends with the process, since reading stdin can't be interrupted.
+            let _ = io::copy(&mut io::stdin(), &mut master_writer);
+            // Tell the command the end of the input, as Ctrl-D would.
+            let _ = master_writer.write_all(b"\x04");
+        });
+        let (output_done_sender, output_done) = mpsc::channel();
+        let mut master_reader = self.master;
+        std::thread::spawn(move || {
+            // Reading the master fails by EIO once all of the slaves are closed.
+            let _ = copy_flushing(&mut master_reader, &mut io::stdout());
+            let _ = output_done_sender.send(());
+        });
+
+        let exit_code = waiter.wait();
+        let _ = output_done.recv_timeout(OUTPUT_DRAIN_TIMEOUT);
+        let _ = io::stdout().flush();
+        Ok(exit_code)
+    }
+}
+
+/// Copies like io::copy, but flushes each write, since the output of a command on a terminal
+/// such as a prompt may not end with a newline.
+fn copy_flushing<R: Read, W: Write>(reader: &mut R, writer: &mut W) -> io::Result<()> {
+    let mut buf = vec![0; 1 << 14];
+    loop {
+        let n = reader.read(&mut buf)?;
+        if n == 0 {
+            return Ok(());
+        }
+        writer.write_all(&buf[..n])?;
+        writer.flush()?;
+    }
+}
+
+/// Makes the terminal the stdio and the controlling terminal of the command, in a session of
+/// its own.
+pub fn attach_terminal(command: &mut Command, terminal: &File) -> Result<()> {
+    let clone = || {
+        terminal
+            .try_clone()
+            .with_context(|| "Failed to duplicate the terminal.")
+    };
+    command
+        .stdin(Stdio::from(clone()?))
+        .stdout(Stdio::from(clone()?))
+        .stderr(Stdio::from(clone()?));
+    unsafe {
+        command.pre_exec(|| {
+            if libc::setsid() < 0 {
+                return Err(io::Error::last_os_error());
+            }
+            // The stdio has been replaced with the terminal by now.
+            if libc::ioctl(libc::STDIN_FILENO, libc::TIOCSCTTY, 0) != 0 {
+                return Err(io::Error::last_os_error());
+            }
+            Ok(())
+        });
+    }
+    Ok(())
+}
+
+pub fn get_window_size(fd: RawFd) -> Option<Winsize> {
+    let mut window_size = Winsize {
+        ws_row: 0,
+        ws_col: 0,
+        ws_xpixel: 0,
+        ws_ypixel: 0,
+    };
+    if unsafe { libc::ioctl(fd, libc::TIOCGWINSZ, &mut window_size) } != 0 {
+        return None;
+    }
+    Some(window_size)
+}
+
+pub fn set_window_size(fd: RawFd, window_size: &Winsize) -> Result<()> {
+    if unsafe { libc::ioctl(fd, libc::TIOCSWINSZ, window_size) } != 0 {
+        return Err(io::Error::last_os_error()).with_context(|| "Failed to set the window size.");
+    }
+    Ok(())
+}
+
+/// Puts the terminal in the raw mode, and restores the original mode when it's dropped.
+/// It does nothing if the fd isn't a terminal.
+struct RawModeGuard {
+    fd: RawFd,
+    original: Option<Termios>,
+}
+
+impl RawModeGuard {
+    fn enter(fd: RawFd) -> Result<RawModeGuard> {
+        if !nix::unistd::isatty(fd).unwrap_or(false) {
+            return Ok(RawModeGuard { fd, original: None });
+        }
+        let original =
+            termios::tcgetattr(fd).with_context(|| "Failed to get the mode of the terminal.")?;
+        let mut raw = original.clone();
+        termios::cfmakeraw(&mut raw);
+        termios::tcsetattr(fd, SetArg::TCSANOW, &raw)
+            .with_context(|| "Failed to put the terminal in the raw mode.")?;
+        Ok(RawModeGuard {
+            fd,
+            original: Some(original),
+        })
+    }
+}
+
+impl Drop for RawModeGuard {
+    fn drop(&mut self) {
+        if let Some(ref original) = self.original {
+            if let Err(e) = termios::tcsetattr(self.fd, SetArg::TCSANOW, original) {
+                log::warn!("Failed to restore the mode of the terminal. {}", e);
+            }
+        }
+    }
+}
+
+/// Returns a pipe which gets a byte each time the window of the terminal is resized.
+fn watch_window_size() -> Result<File> {
+    let (reader, writer) = nix::unistd::pipe2(nix::fcntl::OFlag::O_CLOEXEC)
+        .with_context(|| "Failed to make a pipe.")?;
+    WINCH_PIPE_WRITER.store(writer, Ordering::SeqCst);
+    let action = SigAction::new(
+        SigHandler::Handler(notify_window_size_change),
+        SaFlags::SA_RESTART,
+        SigSet::empty(),
+    );
+    unsafe { signal::sigaction(signal::SIGWINCH, &action) }
+        .with_context(|| "Failed to set the handler of SIGWINCH.")?;
+    Ok(unsafe { File::from_raw_fd(reader) })
+}
+
+extern "C" fn notify_window_size_change(_signal: libc::c_int) {
+    let writer = WINCH_PIPE_WRITER.load(Ordering::SeqCst);
+    if writer >= 0 {
+        // write(2) is async-signal-safe.
+        unsafe {
+            libc::write(writer, b"\0".as_ptr() as *const libc::c_void, 1);
+        }
+    }
+}
+
+#[cfg(test)]
+mod test_pty {
+    use super::*;
+
+    #[test]
+    fn test_window_size() {
+        let pty = Pty::open().unwrap();
+        let window_size = Winsize {
+            ws_row: 40,
+            ws_col: 120,
+            ws_xpixel: 0,
+            ws_ypixel: 0,
+        };
+        set_window_size(pty.master.as_raw_fd(), &window_size).unwrap();
+        let slave = pty.try_clone_slave().unwrap();
+        let got = get_window_size(slave.as_raw_fd()).unwrap();
+        assert_eq!((40, 120), (got.ws_row, got.ws_col));
+        assert!(nix::unistd::isatty(slave.as_raw_fd()).unwrap());
+    }
+}
@@ -0,0 +1,520 @@
+use anyhow::{Context, Result};
+use serde::{Deserialize, Serialize};
+use std::fs;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr, UdpSocket};
+use std::path::{Path, PathBuf};
+use std::time::{Duration, Instant};
+
+use crate::container::{ContainerPath, HostPath};
+use crate::distro::{Distro, DistroLauncher};
+use crate::distro_config::{validate_hostname, validate_nameserver, DistroConfig, NetworkConfig};
+use crate::systemdunit::SystemdUnitDisabler;
+use crate::wsl_interop;
+
+/// /etc/resolv.conf generated by WSL, from which the name servers are taken.
+pub const WSL_RESOLV_CONF_PATH: &str = "/etc/resolv.conf";
+/// The drop-in of systemd-resolved in the distro which Distrod owns.
+pub const RESOLVED_DROPIN_PATH: &str = "/etc/systemd/resolved.conf.d/distrod-wsl.conf";
+/// The resolv.conf with the custom name servers or the repaired ones of WSL, which is bound to
+/// /etc/resolv.conf of the distro if it doesn't use systemd-resolved.
+pub const CUSTOM_RESOLV_CONF_PATH: &str = "/etc/distrod/resolv.conf";
+
+const RESOLVED_SERVICE: &str = "systemd-resolved.service";
+/// The stub listener of systemd-resolved, which is not a name server to forward to.
+const RESOLVED_STUB_ADDRESS: &str = "127.0.0.53";
+const SYNC_INTERVAL: Duration = Duration::from_secs(5);
+const PROBE_TIMEOUT: Duration = Duration::from_secs(2);
+/// How long the name servers taken from Windows are kept without asking Windows again while
+/// they don't answer either, since Powershell takes a while to start.
+const REPAIR_RECHECK_INTERVAL: Duration = Duration::from_secs(60);
+/// A DNS query with the recursion desired of the root name servers, which any name server answers.
+const PROBE_QUERY: [u8; 17] = [
+    0x64, 0x69, 0x01, 0x00, 0, 1, 0, 0, 0, 0, 0, 0, 0, 0, 2, 0, 1,
+];
+
+/// Returns whether the distro resolves names by systemd-resolved, that is, /etc/resolv.conf of
+/// the rootfs links to the one resolved generates and the service is not masked.
+pub fn uses_resolved(rootfs: &HostPath) -> bool {
+    let resolv_conf = ContainerPath::new("/etc/resolv.conf")
+        .expect("[BUG] the path should be absolute.")
+        .to_host_path(rootfs);
+    let links_to_resolved = fs::read_link(resolv_conf.as_path())
+        .map(|target| target.to_string_lossy().contains("systemd/resolve/"))
+        .unwrap_or(false);
+    let masked = SystemdUnitDisabler::new(rootfs.as_path(), RESOLVED_SERVICE)
+        .is_masked()
+        .unwrap_or(false);
+    links_to_resolved && !masked
+}
+
+/// Returns whether systemd-resolved of the distro takes the name servers of WSL by the drop-in,
+/// in place of the bind mount of /etc/resolv.conf, which would replace the link to resolved.
+/// `custom` is the name servers which replace the ones of WSL, if any.
+pub fn takes_nameservers_of_wsl(
+    rootfs: &HostPath,
+    distro_config: &DistroConfig,
+    custom: Option<&NameServers>,
+) -> bool {
+    distro_config.network.share_resolv_conf
+        && custom.is_none()
+        && rootfs.as_path() != Path::new("/")
+        && uses_resolved(rootfs)
+}
+
+/// Returns whether `distrod resolved-sync` keeps the name servers of the distro up to date, that
+/// is, systemd-resolved takes the ones of WSL, or `network.repair_vpn_dns` is on.
+pub fn syncs_nameservers(
+    rootfs: &HostPath,
+    distro_config: &DistroConfig,
+    custom: Option<&NameServers>,
+) -> bool {
+    takes_nameservers_of_wsl(rootfs, distro_config, custom)
+        || (distro_config.network.share_resolv_conf
+            && distro_config.network.repair_vpn_dns
+            && custom.is_none()
+            && rootfs.as_path() != Path::new("/"))
+}
+
+/// The name servers and the search domains of a resolv.conf.
+#[derive(Serialize, Deserialize, Debug, Clone, Default, PartialEq, Eq)]
+pub struct NameServers {
+    pub nameservers: Vec<String>,
+    pub search_domains: Vec<String>,
+}
+
+impl NameServers {
+    /// The custom name servers in the [network] section, if any.
+    pub fn from_config(network_config: &NetworkConfig) -> Option<NameServers> {
+        if network_config.nameservers.is_empty() {
+            return None;
+        }
+        Some(NameServers {
+            nameservers: network_config.nameservers.clone(),
+            search_domains: network_config.search_domains.clone(),
+        })
+    }
+
+    pub fn validate(&self) -> Result<()> {
+        for server in &self.nameservers {
+            validate_nameserver(server)?;
+        }
+        for domain in &self.search_domains {
+            validate_hostname(domain)?;
+        }
+        Ok(())
+    }
+
+    pub fn parse_resolv_conf(content: &str) -> NameServers {
+        let mut name_servers = NameServers::default();
+        for line in content.lines() {
+            let mut fields = line.split_whitespace();
+            match fields.next() {
+                Some("nameserver") => name_servers.nameservers.extend(
+                    fields
+                        .next()
+                        .filter(|server| *server != RESOLVED_STUB_ADDRESS)
+                        .map(|server| server.to_owned()),
+                ),
+                // The last one wins as in resolv.conf(5).
+                Some("search") | Some("domain") => {
+                    name_servers.search_domains = fields.map(|domain| domain.to_owned()).collect()
+                }
+                _ => {}
+            }
+        }
+        name_servers
+    }
+
+    /// The drop-in of resolved.conf which sets these as the global ones.
+    pub fn to_resolved_dropin(&self) -> String {
+        let mut dropin = String::from("# Generated by Distrod. Don't edit this file.\n[Resolve]\n");
+        dropin.push_str(&format!("DNS={}\n", self.nameservers.join(" ")));
+        if !self.search_domains.is_empty() {
+            dropin.push_str(&format!("Domains={}\n", self.search_domains.join(" ")));
+        }
+        dropin
+    }
+
+    pub fn to_resolv_conf(&self) -> String {
+        let mut resolv_conf = String::from("# Generated by Distrod. Don't edit this file.\n");
+        for server in &self.nameservers {
+            resolv_conf.push_str(&format!("nameserver {}\n", server));
+        }
+        if !self.search_domains.is_empty() {
+            resolv_conf.push_str(&format!("search {}\n", self.search_domains.join(" ")));
+        }
+        resolv_conf
+    }
+}
+
+/// Writes the drop-in with the name servers of WSL into /etc of `root`, which is the rootfs or
+/// the root of the running init. Returns whether it has changed.
+pub fn write_resolved_dropin(root: &Path) -> Result<bool> {
+    write_resolved_dropin_of(root, &read_wsl_nameservers()?)
+}
+
+pub fn read_wsl_nameservers() -> Result<NameServers> {
+    let resolv_conf = fs::read_to_string(WSL_RESOLV_CONF_PATH)
+        .with_context(|| format!("Failed to read {}.", WSL_RESOLV_CONF_PATH))?;
+    Ok(NameServers::parse_resolv_conf(&resolv_conf))
+}
+
+/// Writes the drop-in with the given name servers into /etc of `root`. Returns whether it has
+/// changed.
+pub fn write_resolved_dropin_of(root: &Path, name_servers: &NameServers) -> Result<bool> {
+    let dropin = name_servers.to_resolved_dropin();
+    let dropin_path = get_in_root(root, RESOLVED_DROPIN_PATH);
+    if fs::read_to_string(&dropin_path).ok().as_deref() == Some(dropin.as_str()) {
+        return Ok(false);
+    }
+    let dropin_dir = dropin_path
+        .parent()
+        .expect("[BUG] RESOLVED_DROPIN_PATH has a parent.");
+    fs::create_dir_all(dropin_dir)
+        .with_context(|| format!("Failed to create {:?}.", dropin_dir))?;
+    fs::write(&dropin_path, dropin)
+        .with_context(|| format!("Failed to write {:?}.", &dropin_path))?;
+    Ok(true)
+}
+
+/// Keeps the name servers of the running distro up to date with /etc/resolv.conf of WSL, which
+/// changes such as when a VPN connects. They are written in the drop-in, which systemd-resolved
+/// reloads, or in the resolv.conf bound to /etc/resolv.conf of the distro. If `repairs_vpn_dns`,
+/// the ones of Windows are taken while none of the ones of WSL answers. Returns when the distro
+/// stops.
+pub fn sync_nameservers(distro: &Distro, repairs_vpn_dns: bool) -> Result<()> {
+    let init_pid = distro.get_init_pid();
+    let root = PathBuf::from(format!("/proc/{}/root", init_pid));
+    let via_resolved = uses_resolved(&HostPath::new(distro.get_rootfs())?);
+    let mut repairer = if repairs_vpn_dns {
+        Some(VpnDnsRepairer::default())
+    } else {
+        None
+    };
+    loop {
+        match update_nameservers(&root, via_resolved, repairer.as_mut()) {
+            Ok(true) if !via_resolved => log::info!("The name servers have changed."),
+            Ok(true) => {
+                log::info!("The name servers have changed. Reloading systemd-resolved.");
+                match distro
+                    .exec_command_output("systemctl", &["try-reload-or-restart", RESOLVED_SERVICE])
+                {
+                    Ok((0, _)) => {}
+                    Ok((code, _)) => log::warn!("Reloading systemd-resolved failed with {}.", code),
+                    Err(e) => log::warn!("Failed to reload systemd-resolved. {:?}", e),
+                }
+            }
+            Ok(false) => {}
+            Err(e) => log::warn!("Failed to update the name servers. {:?}", e),
+        }
+        std::thread::sleep(SYNC_INTERVAL);
+        // The distro may have been restarted with another init, which has its own sync.
+        let running = DistroLauncher::get_running_distro_by_name(distro.get_name())
+            .with_context(|| "Failed to get the running distro.")?;
+        if running.map(|running| running.get_init_pid()) != Some(init_pid) {
+            return Ok(());
+        }
+    }
+}
+
+fn update_nameservers(
+    root: &Path,
+    via_resolved: bool,
+    repairer: Option<&mut VpnDnsRepairer>,
+) -> Result<bool> {
+    let mut name_servers = read_wsl_nameservers()?;
+    if let Some(repairer) = repairer {
+        name_servers = repairer.repair(name_servers);
+    }
+    if via_resolved {
+        write_resolved_dropin_of(root, &name_servers)
+    } else {
+        write_resolv_conf_of(root, &name_servers)
+    }
+}
+
+/// Replaces the name servers of WSL with the ones of Windows while none of them answers, such as
+/// when a VPN client has taken the route to the one WSL points at.
+#[derive(Debug, Default)]
+pub struct VpnDnsRepairer {
+    repair: Option<Repair>,
+}
+
+#[derive(Debug)]
+struct Repair {
+    broken: NameServers,
+    repaired: NameServers,
+    checked_at: Instant,
+}
+
+impl VpnDnsRepairer {
+    /// Returns the name servers to use in place of `wsl`, the ones of /etc/resolv.conf of WSL.
+    pub fn repair(&mut self, wsl: NameServers) -> NameServers {
+        self.repair_by(
+            wsl,
+            is_nameserver_reachable,
+            wsl_interop::get_windows_nameservers,
+        )
+    }
+
+    /// Does `repair` with the probe of the name servers and the query of the ones of Windows.
+    /// While a repair is kept, the ones of WSL are probed again every REPAIR_RECHECK_INTERVAL, so
+    /// that they are used again once they answer, such as when the VPN is disconnected.
+    fn repair_by<P, W>(
+        &mut self,
+        wsl: NameServers,
+        is_reachable: P,
+        get_windows_nameservers: W,
+    ) -> NameServers
+    where
+        P: Fn(&str) -> bool,
+        W: FnOnce() -> Result<Vec<String>>,
+    {
+        if let Some(ref repair) = self.repair {
+            if repair.broken == wsl && repair.checked_at.elapsed() < REPAIR_RECHECK_INTERVAL {
+                return repair.repaired.clone();
+            }
+        }
+        let last_repair = self.repair.take();
+        if wsl.nameservers.is_empty() || wsl.nameservers.iter().any(|server| is_reachable(server)) {
+            if last_repair.is_some() {
+                log::info!("The name servers of WSL answer again. Using them.");
+            }
+            return wsl;
+        }
+        if let Some(mut repair) = last_repair {
+            if repair.broken == wsl
+                && repair
+                    .repaired
+                    .nameservers
+                    .iter()
+                    .any(|server| is_reachable(server))
+            {
+                repair.checked_at = Instant::now();
+                let repaired = repair.repaired.clone();
+                self.repair = Some(repair);
+                return repaired;
+            }
+        }
+        let windows_nameservers = match get_windows_nameservers() {
+            Ok(servers) if !servers.is_empty() => servers,
+            Ok(_) => {
+                log::warn!("None of the name servers of WSL answers, and Windows has none.");
+                return wsl;
+            }
+            Err(e) => {
+                log::warn!("Failed to get the name servers of Windows. {:?}", e);
+                return wsl;
+            }
+        };
+        log::info!(
+            "None of the name servers of WSL answers. Using the ones of Windows, {}.",
+            windows_nameservers.join(", ")
+        );
+        let repaired = NameServers {
+            nameservers: windows_nameservers,
+            search_domains: wsl.search_domains.clone(),
+        };
+        self.repair = Some(Repair {
+            broken: wsl,
+            repaired: repaired.clone(),
+            checked_at: Instant::now(),
+        });
+        repaired
+    }
+}
+
+/// Returns whether the name server answers a query within PROBE_TIMEOUT.
+pub fn is_nameserver_reachable(server: &str) -> bool {
+    let ip = match server.parse::<IpAddr>() {
+        Ok(ip) => ip,
+        Err(_) => return false,
+    };
+    let bind_addr = match ip {
+        IpAddr::V4(_) => SocketAddr::from((Ipv4Addr::UNSPECIFIED, 0)),
+        IpAddr::V6(_) => SocketAddr::from((Ipv6Addr::UNSPECIFIED, 0)),
+    };
+    let probe = || -> std::io::Result<bool> {
+        let socket = UdpSocket::bind(bind_addr)?;
+        socket.set_read_timeout(Some(PROBE_TIMEOUT))?;
+        socket.connect((ip, 53))?;
+        socket.send(&PROBE_QUERY)?;
+        let mut buf = [0; 512];
+        let n = socket.recv(&mut buf)?;
+        Ok(is_probe_response(&buf[..n]))
+    };
+    probe().unwrap_or(false)
+}
+
+fn is_probe_response(packet: &[u8]) -> bool {
+    // Any response counts, even an error, since the name server has answered.
+    packet.len() >= 12 && packet[..2] == PROBE_QUERY[..2] && packet[2] & 0x80 != 0
+}
+
+/// Writes the resolv.conf with the custom name servers into the rootfs, and returns its path.
+pub fn write_custom_resolv_conf(rootfs: &HostPath, name_servers: &NameServers) -> Result<HostPath> {
+    write_resolv_conf_of(rootfs.as_path(), name_servers)?;
+    HostPath::new(get_in_root(rootfs.as_path(), CUSTOM_RESOLV_CONF_PATH))
+}
+
+/// Writes the resolv.conf with the given name servers into /etc/distrod of `root`. Returns
+/// whether it has changed. It's written in place, since it may be bound to /etc/resolv.conf.
+fn write_resolv_conf_of(root: &Path, name_servers: &NameServers) -> Result<bool> {
+    let resolv_conf = name_servers.to_resolv_conf();
+    let path = get_in_root(root, CUSTOM_RESOLV_CONF_PATH);
+    if fs::read_to_string(&path).ok().as_deref() == Some(resolv_conf.as_str()) {
+        return Ok(false);
+    }
+    let dir = path
+        .parent()
+        .expect("[BUG] CUSTOM_RESOLV_CONF_PATH has a parent.");
+    fs::create_dir_all(dir).with_context(|| format!("Failed to create {:?}.", dir))?;
+    fs::write(&path, resolv_conf).with_context(|| format!("Failed to write {:?}.", &path))?;
+    Ok(true)
+}
+
+fn get_in_root(root: &Path, path: &str) -> PathBuf {
+    root.join(path.strip_prefix('/').expect("[BUG] the path is absolute."))
+}
+
+#[cfg(test)]
+mod test_resolved {
+    use super::*;
+
+    #[test]
+    fn test_parse_resolv_conf() {
+        let name_servers = NameServers::parse_resolv_conf(
+            "# This file was automatically generated by WSL.\n\
+             nameserver 172.20.0.1\n\
+             nameserver 127.0.0.53\n\
+             nameserver 10.0.0.2\n\
+             search corp.example.com example.com\n",
+        );
+        assert_eq!(
+            vec!["172.20.0.1".to_owned(), "10.0.0.2".to_owned()],
+            name_servers.nameservers
+        );
+        assert_eq!(
+            "# Generated by Distrod. Don't edit this file.\n\
+             [Resolve]\n\
+             DNS=172.20.0.1 10.0.0.2\n\
+             Domains=corp.example.com example.com\n",
+            name_servers.to_resolved_dropin()
+        );
+    }
+
+    #[test]
+    fn test_custom_nameservers() {
+        let mut network_config = NetworkConfig::default();
+        assert_eq!(None, NameServers::from_config(&network_config));
+        network_config.nameservers = vec!["1.1.1.1".to_owned(), "2606:4700::1111".to_owned()];
+        network_config.search_domains = vec!["corp.example.com".to_owned()];
+        let name_servers = NameServers::from_config(&network_config).unwrap();
+        assert!(name_servers.validate().is_ok());
+        assert_eq!(
+            "# Generated by Distrod. Don't edit this file.\n\
+             nameserver 1.1.1.1\n\
+             nameserver 2606:4700::1111\n\
+             search corp.example.com\n",
+            name_servers.to_resolv_conf()
+        );
+        assert_eq!(
+            name_servers,
+            NameServers::parse_resolv_conf(&name_servers.to_resolv_conf())
+        );
+
+        let invalid = NameServers {
+            nameservers: vec!["dns.google".to_owned()],
+            search_domains: vec![],
+        };
+        assert!(invalid.validate().is_err());
+    }
+
+    #[test]
+    fn test_probe_response() {
+        let mut response = PROBE_QUERY.to_vec();
+        response[2] |= 0x80;
+        assert!(is_probe_response(&response));
+        assert!(!is_probe_response(&PROBE_QUERY));
+        response[0] = 0;
+        assert!(!is_probe_response(&response));
+        assert!(!is_nameserver_reachable("dns.google"));
+    }
+
+    #[test]
+    fn test_repair_vpn_dns() {
+        let wsl = NameServers {
+            nameservers: vec!["172.20.0.1".to_owned()],
+            search_domains: vec!["corp.example.com".to_owned()],
+        };
+        let windows = || Ok(vec!["10.0.0.2".to_owned()]);
+        let repaired = NameServers {
+            nameservers: vec!["10.0.0.2".to_owned()],
+            search_domains: vec!["corp.example.com".to_owned()],
+        };
+        let mut repairer = VpnDnsRepairer::default();
+
+        // The ones of WSL are used while they answer.
+        assert_eq!(wsl, repairer.repair_by(wsl.clone(), |_| true, windows));
+        assert_eq!(
+            repaired,
+            repairer.repair_by(wsl.clone(), |server| server == "10.0.0.2", windows)
+        );
+        // The repair is kept without probing again until the recheck.
+        assert_eq!(
+            repaired,
+            repairer.repair_by(wsl.clone(), |_| panic!("probed"), windows)
+        );
+
+        // The ones of WSL are probed again on the recheck, and used once they answer.
+        let expire = |repairer: &mut VpnDnsRepairer| {
+            repairer.repair.as_mut().unwrap().checked_at = Instant::now() - REPAIR_RECHECK_INTERVAL;
+        };
+        expire(&mut repairer);
+        assert_eq!(
+            repaired,
+            repairer.repair_by(
+                wsl.clone(),
+                |server| server == "10.0.0.2",
+                || { panic!("asked Windows") }
+            )
+        );
+        expire(&mut repairer);
+        assert_eq!(wsl, repairer.repair_by(wsl.clone(), |_| true, windows));
+        assert!(repairer.repair.is_none());
+    }
+
+    #[test]
+    fn test_syncs_nameservers() {
+        let tmpdir = tempfile::TempDir::new().unwrap();
+        let rootfs = HostPath::new(tmpdir.path()).unwrap();
+        let mut distro_config = DistroConfig::default();
+        assert!(!syncs_nameservers(&rootfs, &distro_config, None));
+        distro_config.network.repair_vpn_dns = true;
+        assert!(syncs_nameservers(&rootfs, &distro_config, None));
+        let custom = NameServers {
+            nameservers: vec!["1.1.1.1".to_owned()],
+            search_domains: vec![],
+        };
+        assert!(!syncs_nameservers(&rootfs, &distro_config, Some(&custom)));
+        assert!(!syncs_nameservers(
+            &HostPath::new("/").unwrap(),
+            &distro_config,
+            None
+        ));
+    }
+
+    #[test]
+    fn test_uses_resolved() {
+        let tmpdir = tempfile::TempDir::new().unwrap();
+        let rootfs = HostPath::new(tmpdir.path()).unwrap();
+        fs::create_dir_all(tmpdir.path().join("etc")).unwrap();
+        assert!(!uses_resolved(&rootfs));
+        std::os::unix::fs::symlink(
+            "../run/systemd/resolve/stub-resolv.conf",
+            tmpdir.path().join("etc/resolv.conf"),
+        )
+        .unwrap();
+        assert!(uses_resolved(&rootfs));
+    }
+}
@@ -0,0 +1,321 @@
+use anyhow::{bail, Context, Result};
+use nix::errno::Errno;
+use nix::fcntl::OFlag;
+use nix::sys::stat::{AtFlags, Mode, SFlag};
+use nix::unistd::{FchownatFlags, Gid, Uid, UnlinkatFlags};
+use std::collections::VecDeque;
+use std::ffi::{OsStr, OsString};
+use std::fs::{self, File};
+use std::os::unix::ffi::OsStrExt;
+use std::os::unix::io::{AsRawFd, FromRawFd};
+use std::path::{Component, Path, PathBuf};
+
+use crate::container::{ContainerPath, HostPath};
+
+/// A directory in the rootfs of a distro which isn't running, opened without following any
+/// symlink on the way. The files of a rootfs are made by its users, so a symlink such as
+/// /var/cache -> /etc would point the operations of the root at the files of the host if the
+/// paths were joined to the rootfs and followed.
+pub struct RootfsDir {
+    dir: File,
+    /// The path on the host, only for the messages.
+    host_path: HostPath,
+}
+
+impl RootfsDir {
+    /// Opens the directory at `path` in the rootfs. Returns None if it doesn't exist, and fails
+    /// if any of its components is a symlink or "..".
+    pub fn open(rootfs: &HostPath, path: &ContainerPath) -> Result<Option<RootfsDir>> {
+        RootfsDir::open_or_create(rootfs, path, false)
+    }
+
+    /// Opens the directory at `path` in the rootfs as `open` does, making the missing ones with
+    /// the mode 0755.
+    pub fn create_dir_all(rootfs: &HostPath, path: &ContainerPath) -> Result<RootfsDir> {
+        Ok(RootfsDir::open_or_create(rootfs, path, true)?
+            .expect("[BUG] the directories are created."))
+    }
+
+    fn open_or_create(
+        rootfs: &HostPath,
+        path: &ContainerPath,
+        creates: bool,
+    ) -> Result<Option<RootfsDir>> {
+        let mut dir = RootfsDir {
+            dir: File::open(rootfs.as_path())
+                .with_context(|| format!("Failed to open {:?}.", rootfs))?,
+            host_path: rootfs.clone(),
+        };
+        for component in path.as_path().components() {
+            let name = match component {
+                Component::RootDir => continue,
+                Component::Normal(name) => name,
+                _ => bail!("{:?} must not have '..' or '.'.", path),
+            };
+            if creates {
+                match nix::sys::stat::mkdirat(
+                    dir.dir.as_raw_fd(),
+                    name,
+                    Mode::from_bits_truncate(0o755),
+                ) {
+                    Ok(_) | Err(nix::Error::Sys(Errno::EEXIST)) => {}
+                    Err(e) => {
+                        return Err(e).with_context(|| {
+                            format!("Failed to create {:?}.", dir.host_path.join(name))
+                        })
+                    }
+                }
+            }
+            dir = match dir.open_dir(name)? {
+                Some(child) => child,
+                None => return Ok(None),
+            };
+        }
+        Ok(Some(dir))
+    }
+
+    /// Opens the directory `name` in this directory. Returns None if it doesn't exist, and fails
+    /// if it's a symlink or not a directory.
+    pub fn open_dir(&self, name: &OsStr) -> Result<Option<RootfsDir>> {
+        let host_path = HostPath::new(self.host_path.join(name))?;
+        let fd = match nix::fcntl::openat(
+            self.dir.as_raw_fd(),
+            name,
+            OFlag::O_RDONLY | OFlag::O_DIRECTORY | OFlag::O_NOFOLLOW | OFlag::O_CLOEXEC,
+            Mode::empty(),
+        ) {
+            Ok(fd) => fd,
+            Err(nix::Error::Sys(Errno::ENOENT)) => return Ok(None),
+            Err(nix::Error::Sys(Errno::ELOOP)) => {
+                bail!("{:?} is a symlink, which is not followed.", &host_path)
+            }
+            Err(e) => return Err(e).with_context(|| format!("Failed to open {:?}.", &host_path)),
+        };
+        Ok(Some(RootfsDir {
+            dir: unsafe { File::from_raw_fd(fd) },
+            host_path,
+        }))
+    }
+
+    /// Lists the entries with whether each of them is a directory, not following symlinks.
+    pub fn entries(&self) -> Result<Vec<(OsString, bool)>> {
+        let mut dir = nix::dir::Dir::openat(
+            self.dir.as_raw_fd(),
+            ".",
+            OFlag::O_RDONLY | OFlag::O_DIRECTORY | OFlag::O_CLOEXEC,
+            Mode::empty(),
+        )
+        .with_context(|| format!("Failed to read {:?}.", &self.host_path))?;
+        let mut entries = vec![];
+        for entry in dir.iter() {
+            let entry = entry.with_context(|| format!("Failed to read {:?}.", &self.host_path))?;
+            let name = OsStr::from_bytes(entry.file_name().to_bytes());
+            if name == "." || name == ".." {
+                continue;
+            }
+            let stat =
+                nix::sys::stat::fstatat(self.dir.as_raw_fd(), name, AtFlags::AT_SYMLINK_NOFOLLOW)
+                    .with_context(|| format!("Failed to stat {:?}.", self.host_path.join(name)))?;
+            let is_dir = SFlag::from_bits_truncate(stat.st_mode) & SFlag::S_IFMT == SFlag::S_IFDIR;
+            entries.push((name.to_owned(), is_dir));
+        }
+        Ok(entries)
+    }
+
+    /// Removes the file `name`, or the symlink itself if it's a symlink.
+    pub fn remove_file(&self, name: &OsStr) -> Result<()> {
+        nix::unistd::unlinkat(Some(self.dir.as_raw_fd()), name, UnlinkatFlags::NoRemoveDir)
+            .with_context(|| format!("Failed to remove {:?}.", self.host_path.join(name)))
+    }
+
+    /// Creates or truncates the file `name` with the mode, which fails if it's a symlink.
+    pub fn create_file(&self, name: &OsStr, mode: u32) -> Result<File> {
+        let fd = nix::fcntl::openat(
+            self.dir.as_raw_fd(),
+            name,
+            OFlag::O_WRONLY
+                | OFlag::O_CREAT
+                | OFlag::O_TRUNC
+                | OFlag::O_NOFOLLOW
+                | OFlag::O_CLOEXEC,
+            Mode::from_bits_truncate(mode),
+        )
+        .with_context(|| format!("Failed to create {:?}.", self.host_path.join(name)))?;
+        Ok(unsafe { File::from_raw_fd(fd) })
+    }
+
+    /// Changes the owner of the file `name`, or the symlink itself if it's a symlink.
+    pub fn chown(&self, name: &OsStr, uid: u32, gid: u32) -> Result<()> {
+        nix::unistd::fchownat(
+            Some(self.dir.as_raw_fd()),
+            name,
+            Some(Uid::from_raw(uid)),
+            Some(Gid::from_raw(gid)),
+            FchownatFlags::NoFollowSymlink,
+        )
+        .with_context(|| {
+            format!(
+                "Failed to change the owner of {:?}.",
+                self.host_path.join(name)
+            )
+        })
+    }
+}
+
+/// The most symlinks `resolve_path` follows, as many as Linux does.
+const MAX_SYMLINKS: usize = 40;
+
+/// Returns the path on the host of `path` in the rootfs, following the symlinks on the way as
+/// the distro sees them, where an absolute target is in the rootfs and ".." stops at its root.
+/// The missing components are left as they are. This is for reading the files of a distro which
+/// isn't running, such as /usr/local/cuda, which is usually a symlink to /usr/local/cuda-<version>.
+pub fn resolve_path(rootfs: &HostPath, path: &ContainerPath) -> Result<HostPath> {
+    let mut resolved = PathBuf::from("/");
+    let mut pending: VecDeque<OsString> = to_names(path.as_path());
+    let mut n_symlinks = 0;
+    while let Some(name) = pending.pop_front() {
+        if name == ".." {
+            resolved.pop();
+            continue;
+        }
+        let candidate = resolved.join(&name);
+        let host_path = ContainerPath::new(&candidate)?.to_host_path(rootfs);
+        match fs::symlink_metadata(host_path.as_path()) {
+            Ok(metadata) if metadata.file_type().is_symlink() => {
+                n_symlinks += 1;
+                if n_symlinks > MAX_SYMLINKS {
+                    bail!("Too many levels of symlinks in {:?}.", path);
+                }
+                let target = fs::read_link(host_path.as_path())
+                    .with_context(|| format!("Failed to read the link {:?}.", &host_path))?;
+                if target.has_root() {
+                    resolved = PathBuf::from("/");
+                }
+                for name in to_names(&target).into_iter().rev() {
+                    pending.push_front(name);
+                }
+            }
+            _ => resolved = candidate,
+        }
+    }
+    Ok(ContainerPath::new(resolved)?.to_host_path(rootfs))
+}
+
+/// Splits the path into the names of its components and "..", leaving out the root and ".".
+fn to_names(path: &Path) -> VecDeque<OsString> {
+    path.components()
+        .filter_map(|component| match component {
+            Component::Normal(name) => Some(name.to_owned()),
+            Component::ParentDir => Some(OsString::from("..")),
+            _ => None,
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod test_rootfs_dir {
+    use super::*;
+    use std::fs;
+    use std::io::Write;
+    use std::os::unix::fs::symlink;
+
+    #[test]
+    fn test_refuses_symlinks() {
+        let tempdir = tempfile::tempdir().unwrap();
+        let rootfs = HostPath::new(tempdir.path().join("rootfs")).unwrap();
+        let outside = tempdir.path().join("outside");
+        fs::create_dir_all(rootfs.join("var/cache")).unwrap();
+        fs::create_dir_all(&outside).unwrap();
+        symlink(&outside, rootfs.join("var/cache/apt")).unwrap();
+
+        assert!(RootfsDir::open(
+            &rootfs,
+            &ContainerPath::new("/var/cache/apt/archives").unwrap()
+        )
+        .is_err());
+        assert!(RootfsDir::create_dir_all(
+            &rootfs,
+            &ContainerPath::new("/var/cache/apt/archives").unwrap()
+        )
+        .is_err());
+        assert!(RootfsDir::open(&rootfs, &ContainerPath::new("/var/../etc").unwrap()).is_err());
+        assert!(!outside.join("archives").exists());
+    }
+
+    #[test]
+    fn test_create_and_list() {
+        let tempdir = tempfile::tempdir().unwrap();
+        let rootfs = HostPath::new(tempdir.path()).unwrap();
+        assert!(
+            RootfsDir::open(&rootfs, &ContainerPath::new("/etc/team").unwrap())
+                .unwrap()
+                .is_none()
+        );
+
+        let dir =
+            RootfsDir::create_dir_all(&rootfs, &ContainerPath::new("/etc/team").unwrap()).unwrap();
+        dir.create_file(OsStr::new("a"), 0o600)
+            .unwrap()
+            .write_all(b"a\n")
+            .unwrap();
+        fs::create_dir(tempdir.path().join("etc/team/sub")).unwrap();
+        symlink("/etc/passwd", tempdir.path().join("etc/team/link")).unwrap();
+        assert!(dir.create_file(OsStr::new("link"), 0o600).is_err());
+
+        let mut entries = dir.entries().unwrap();
+        entries.sort();
+        assert_eq!(
+            vec![
+                (OsString::from("a"), false),
+                (OsString::from("link"), false),
+                (OsString::from("sub"), true),
+            ],
+            entries
+        );
+        dir.remove_file(OsStr::new("link")).unwrap();
+        assert!(fs::symlink_metadata(tempdir.path().join("etc/team/link")).is_err());
+        assert_eq!(
+            "a\n",
+            fs::read_to_string(tempdir.path().join("etc/team/a")).unwrap()
+        );
+    }
+
+    #[test]
+    fn test_resolve_path() {
+        let tempdir = tempfile::tempdir().unwrap();
+        let rootfs = HostPath::new(tempdir.path()).unwrap();
+        fs::create_dir_all(tempdir.path().join("usr/local/cuda-12.2")).unwrap();
+        fs::create_dir_all(tempdir.path().join("etc/alternatives")).unwrap();
+        symlink(
+            "/usr/local/cuda-12.2",
+            tempdir.path().join("etc/alternatives/cuda"),
+        )
+        .unwrap();
+        symlink(
+            "/etc/alternatives/cuda",
+            tempdir.path().join("usr/local/cuda"),
+        )
+        .unwrap();
+        symlink(
+            "../../../../../etc",
+            tempdir.path().join("usr/local/escape"),
+        )
+        .unwrap();
+        symlink("loop", tempdir.path().join("loop")).unwrap();
+
+        let resolve = |path: &str| resolve_path(&rootfs, &ContainerPath::new(path).unwrap());
+        assert_eq!(
+            tempdir.path().join("usr/local/cuda-12.2/version.json"),
+            resolve("/usr/local/cuda/version.json").unwrap().as_path()
+        );
+        assert_eq!(
+            tempdir.path().join("etc/passwd"),
+            resolve("/usr/local/escape/passwd").unwrap().as_path()
+        );
+        assert_eq!(
+            tempdir.path().join("opt/rocm"),
+            resolve("/opt/../opt/rocm").unwrap().as_path()
+        );
+        assert!(resolve("/loop").is_err());
+    }
+}
@@ -0,0 +1,141 @@
+use anyhow::{anyhow, bail, Context, Result};
+use std::ffi::OsStr;
+use std::fs::{self, OpenOptions};
+use std::path::{Path, PathBuf};
+use std::process::Command;
+
+use crate::error::ContainerError;
+use crate::mount_info::get_mount_entries;
+use crate::userns;
+
+/// The smallest image `create_image` makes, which is about what mkfs.ext4 needs for the
+/// journal and a minimal rootfs.
+const MIN_IMAGE_SIZE: u64 = 64 << 20;
+
+/// Returns the path of the disk image of the rootfs, which is a hidden file next to it, such as
+/// `.ubuntu.ext4` for `ubuntu`. The rootfs directory is the mount point of the image.
+pub fn get_image_path<P: AsRef<Path>>(rootfs: P) -> Result<PathBuf> {
+    let rootfs = rootfs.as_ref();
+    let name = rootfs
+        .file_name()
+        .ok_or_else(|| anyhow!("{:?} has no file name.", rootfs))?;
+    Ok(rootfs.with_file_name(format!(".{}.ext4", name.to_string_lossy())))
+}
+
+/// Whether the rootfs is stored in a disk image rather than in the directory itself.
+pub fn has_image<P: AsRef<Path>>(rootfs: P) -> Result<bool> {
+    Ok(get_image_path(rootfs)?.is_file())
+}
+
+/// Makes a sparse ext4 image of the size for the rootfs and mounts it on the empty directory
+/// of the rootfs. The files written to the rootfs can't take more than the size.
+pub fn create_image<P: AsRef<Path>>(rootfs: P, size: u64) -> Result<()> {
+    let rootfs = rootfs.as_ref();
+    check_root()?;
+    if size < MIN_IMAGE_SIZE {
+        bail!(
+            "The disk image of the rootfs must be {} MiB or larger.",
+            MIN_IMAGE_SIZE >> 20
+        );
+    }
+    let image = get_image_path(rootfs)?;
+    let file = OpenOptions::new()
+        .write(true)
+        .create_new(true)
+        .open(&image)
+        .with_context(|| format!("Failed to create {:?}.", &image))?;
+    let result = file
+        .set_len(size)
+        .with_context(|| format!("Failed to resize {:?}.", &image))
+        .and_then(|_| {
+            drop(file);
+            run("mkfs.ext4", &["-q", "-F"], &[image.as_os_str()])
+        })
+        .and_then(|_| mount_image_if_unmounted(rootfs, &image));
+    if result.is_err() {
+        if let Err(e) = fs::remove_file(&image) {
+            log::warn!("Failed to remove {:?}. {:?}", &image, e);
+        }
+    }
+    result
+}
+
+/// Mounts the disk image of the rootfs on it if it has one and it's not mounted yet. The image
+/// stays mounted after the distro stops, so that the rootfs can be read and changed from the
+/// host, until WSL shuts down.
+pub fn mount_image<P: AsRef<Path>>(rootfs: P) -> Result<()> {
+    let rootfs = rootfs.as_ref();
+    let image = get_image_path(rootfs)?;
+    if !image.is_file() {
+        return Ok(());
+    }
+    check_root()?;
+    mount_image_if_unmounted(rootfs, &image)
+}
+
+/// Unmounts the disk image from the rootfs if it's mounted, such as before moving the image.
+pub fn unmount_image<P: AsRef<Path>>(rootfs: P) -> Result<()> {
+    let rootfs = rootfs.as_ref();
+    if is_mounted(rootfs)? {
+        run("umount", &[], &[rootfs.as_os_str()])?;
+    }
+    Ok(())
+}
+
+fn mount_image_if_unmounted(rootfs: &Path, image: &Path) -> Result<()> {
+    if is_mounted(rootfs)? {
+        return Ok(());
+    }
+    log::debug!("Mounting {:?} on {:?}.", image, rootfs);
+    // The loop device is detached by the kernel when the image is unmounted.
+    run(
+        "mount",
+        &["-t", "ext4", "-o", "loop"],
+        &[image.as_os_str(), rootfs.as_os_str()],
+    )
+}
+
+fn is_mounted(rootfs: &Path) -> Result<bool> {
+    let rootfs = rootfs
+        .canonicalize()
+        .with_context(|| format!("Failed to canonicalize {:?}.", rootfs))?;
+    Ok(get_mount_entries()?
+        .iter()
+        .any(|entry| entry.path == rootfs))
+}
+
+fn check_root() -> Result<()> {
+    if userns::is_rootless_mode() {
+        return Err(ContainerError::RootRequired {
+            operation: "mount the disk image of a rootfs".to_owned(),
+        }
+        .into());
+    }
+    Ok(())
+}
+
+fn run(command: &str, options: &[&str], args: &[&OsStr]) -> Result<()> {
+    let status = Command::new(command)
+        .args(options)
+        .args(args)
+        .status()
+        .with_context(|| format!("Failed to run {}.", command))?;
+    if !status.success() {
+        bail!("{} exited with {}.", command, status);
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod test_rootfs_image {
+    use super::*;
+
+    #[test]
+    fn test_get_image_path() {
+        assert_eq!(
+            PathBuf::from("/var/lib/distrod/distros/.ubuntu.ext4"),
+            get_image_path("/var/lib/distrod/distros/ubuntu").unwrap()
+        );
+        assert!(get_image_path("/").is_err());
+    }
+}
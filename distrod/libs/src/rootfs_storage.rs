@@ -0,0 +1,232 @@
+use anyhow::{bail, Context, Result};
+use nix::sys::statfs::{statfs, BTRFS_SUPER_MAGIC};
+use std::ffi::OsStr;
+use std::fs::{self, File};
+use std::os::unix::ffi::OsStrExt;
+use std::os::unix::fs::MetadataExt;
+use std::os::unix::io::AsRawFd;
+use std::path::Path;
+use std::process::Command;
+
+/// The inode number of the root directory of every btrfs subvolume.
+const BTRFS_FIRST_FREE_OBJECTID: u64 = 256;
+const BTRFS_PATH_NAME_MAX: usize = 4087;
+
+/// struct btrfs_ioctl_vol_args of linux/btrfs.h. Only the kernel reads the fields.
+#[repr(C)]
+#[allow(dead_code)]
+struct BtrfsIoctlVolArgs {
+    fd: i64,
+    name: [u8; BTRFS_PATH_NAME_MAX + 1],
+}
+
+mod ioctl {
+    use super::BtrfsIoctlVolArgs;
+
+    const BTRFS_IOCTL_MAGIC: u8 = 0x94;
+    nix::ioctl_write_ptr!(btrfs_snap_create, BTRFS_IOCTL_MAGIC, 1, BtrfsIoctlVolArgs);
+    nix::ioctl_write_ptr!(
+        btrfs_subvol_create,
+        BTRFS_IOCTL_MAGIC,
+        14,
+        BtrfsIoctlVolArgs
+    );
+    nix::ioctl_write_ptr!(btrfs_snap_destroy, BTRFS_IOCTL_MAGIC, 15, BtrfsIoctlVolArgs);
+}
+
+/// How the rootfs of a distro is stored.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RootfsStorage {
+    /// A btrfs subvolume, which is snapshotted by copy-on-write in an instant.
+    BtrfsSubvolume,
+    /// A plain directory, which is copied file by file.
+    Directory,
+}
+
+impl RootfsStorage {
+    pub fn of<P: AsRef<Path>>(rootfs: P) -> Result<RootfsStorage> {
+        let rootfs = rootfs.as_ref();
+        let metadata =
+            fs::metadata(rootfs).with_context(|| format!("Failed to stat {:?}.", rootfs))?;
+        if metadata.ino() == BTRFS_FIRST_FREE_OBJECTID && is_on_btrfs(rootfs)? {
+            return Ok(RootfsStorage::BtrfsSubvolume);
+        }
+        Ok(RootfsStorage::Directory)
+    }
+}
+
+/// Whether the path is on btrfs.
+pub fn is_on_btrfs<P: AsRef<Path>>(path: P) -> Result<bool> {
+    let path = path.as_ref();
+    let stat = statfs(path).with_context(|| format!("Failed to statfs {:?}.", path))?;
+    Ok(stat.filesystem_type() == BTRFS_SUPER_MAGIC)
+}
+
+/// Makes the directory of a new rootfs, which is a btrfs subvolume if the parent is on btrfs,
+/// or a plain directory otherwise, such as on ext4 of the virtual disk of WSL. The parent
+/// directories are made as `fs::create_dir_all` does.
+pub fn create_rootfs_dir<P: AsRef<Path>>(rootfs: P) -> Result<RootfsStorage> {
+    let rootfs = rootfs.as_ref();
+    let (parent, name) = split_parent(rootfs)?;
+    fs::create_dir_all(parent).with_context(|| format!("Failed to create {:?}.", parent))?;
+    if is_on_btrfs(parent)? {
+        match create_subvolume(parent, name) {
+            Ok(()) => {
+                log::debug!("Created a btrfs subvolume at {:?}.", rootfs);
+                return Ok(RootfsStorage::BtrfsSubvolume);
+            }
+            // Such as in the rootless mode, which may not be allowed to make a subvolume.
+            Err(e) => log::debug!(
+                "Failed to create a subvolume at {:?}. Falling back to a directory. {:?}",
+                rootfs,
+                e
+            ),
+        }
+    }
+    fs::create_dir(rootfs).with_context(|| format!("Failed to create {:?}.", rootfs))?;
+    Ok(RootfsStorage::Directory)
+}
+
+/// Copies the rootfs to `dest`, which must not exist. A subvolume is snapshotted into a
+/// subvolume on the same btrfs, and the others are copied by `cp`, with reflinks if the file
+/// system supports them.
+pub fn copy_rootfs<P1: AsRef<Path>, P2: AsRef<Path>>(src: P1, dest: P2) -> Result<RootfsStorage> {
+    let (src, dest) = (src.as_ref(), dest.as_ref());
+    if dest.exists() {
+        bail!("{:?} already exists.", dest);
+    }
+    let (parent, name) = split_parent(dest)?;
+    fs::create_dir_all(parent).with_context(|| format!("Failed to create {:?}.", parent))?;
+    if RootfsStorage::of(src)? == RootfsStorage::BtrfsSubvolume && is_on_btrfs(parent)? {
+        match snapshot_subvolume(src, parent, name) {
+            Ok(()) => {
+                log::debug!("Snapshotted {:?} to {:?}.", src, dest);
+                return Ok(RootfsStorage::BtrfsSubvolume);
+            }
+            // Such as when `dest` is on another btrfs.
+            Err(e) => log::debug!(
+                "Failed to snapshot {:?}. Falling back to copying. {:?}",
+                src,
+                e
+            ),
+        }
+    }
+    let storage = create_rootfs_dir(dest)?;
+    // Copy the contents by "src/." so that they go into the directory made above.
+    let mut src_contents = src.as_os_str().to_owned();
+    src_contents.push("/.");
+    let status = Command::new("cp")
+        .args(&["-a", "--reflink=auto"])
+        .arg(&src_contents)
+        .arg(dest)
+        .status()
+        .with_context(|| "Failed to run cp.")?;
+    if !status.success() {
+        if let Err(e) = remove_rootfs_dir(dest) {
+            log::warn!(
+                "Failed to remove the incomplete copy at {:?}. {:?}",
+                dest,
+                e
+            );
+        }
+        bail!("cp exited with {}.", status);
+    }
+    Ok(storage)
+}
+
+/// Removes the rootfs, deleting the subvolume if it's one.
+pub fn remove_rootfs_dir<P: AsRef<Path>>(rootfs: P) -> Result<()> {
+    let rootfs = rootfs.as_ref();
+    if RootfsStorage::of(rootfs)? == RootfsStorage::BtrfsSubvolume {
+        let (parent, name) = split_parent(rootfs)?;
+        match delete_subvolume(parent, name) {
+            Ok(()) => return Ok(()),
+            // Such as in the rootless mode. Removing the files still works, since rmdir deletes
+            // an empty subvolume on Linux 4.18 or later.
+            Err(e) => log::debug!("Failed to delete the subvolume {:?}. {:?}", rootfs, e),
+        }
+    }
+    fs::remove_dir_all(rootfs).with_context(|| format!("Failed to remove {:?}.", rootfs))
+}
+
+fn split_parent(path: &Path) -> Result<(&Path, &OsStr)> {
+    match (path.parent(), path.file_name()) {
+        (Some(parent), Some(name)) => Ok((parent, name)),
+        _ => bail!("{:?} has no parent directory.", path),
+    }
+}
+
+fn build_vol_args(fd: i64, name: &OsStr) -> Result<BtrfsIoctlVolArgs> {
+    let name = name.as_bytes();
+    if name.len() > BTRFS_PATH_NAME_MAX {
+        bail!(
+            "The name of a subvolume can't be longer than {} bytes.",
+            BTRFS_PATH_NAME_MAX
+        );
+    }
+    let mut args = BtrfsIoctlVolArgs {
+        fd,
+        name: [0; BTRFS_PATH_NAME_MAX + 1],
+    };
+    args.name[..name.len()].copy_from_slice(name);
+    Ok(args)
+}
+
+fn open_dir(path: &Path) -> Result<File> {
+    File::open(path).with_context(|| format!("Failed to open {:?}.", path))
+}
+
+fn create_subvolume(parent: &Path, name: &OsStr) -> Result<()> {
+    let parent_dir = open_dir(parent)?;
+    let args = build_vol_args(0, name)?;
+    unsafe { ioctl::btrfs_subvol_create(parent_dir.as_raw_fd(), &args) }
+        .with_context(|| format!("BTRFS_IOC_SUBVOL_CREATE of {:?} failed.", name))?;
+    Ok(())
+}
+
+fn snapshot_subvolume(src: &Path, parent: &Path, name: &OsStr) -> Result<()> {
+    let src_dir = open_dir(src)?;
+    let parent_dir = open_dir(parent)?;
+    let args = build_vol_args(src_dir.as_raw_fd() as i64, name)?;
+    unsafe { ioctl::btrfs_snap_create(parent_dir.as_raw_fd(), &args) }
+        .with_context(|| format!("BTRFS_IOC_SNAP_CREATE of {:?} failed.", name))?;
+    Ok(())
+}
+
+fn delete_subvolume(parent: &Path, name: &OsStr) -> Result<()> {
+    let parent_dir = open_dir(parent)?;
+    let args = build_vol_args(0, name)?;
+    unsafe { ioctl::btrfs_snap_destroy(parent_dir.as_raw_fd(), &args) }
+        .with_context(|| format!("BTRFS_IOC_SNAP_DESTROY of {:?} failed.", name))?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod test_rootfs_storage {
+    use super::*;
+
+    #[test]
+    fn test_vol_args_layout() {
+        // The size is a part of the ioctl numbers, so it must be the one of the kernel.
+        assert_eq!(4096, std::mem::size_of::<BtrfsIoctlVolArgs>());
+        let args = build_vol_args(3, OsStr::new("ubuntu")).unwrap();
+        assert_eq!(b"ubuntu\0", &args.name[..7]);
+        assert!(build_vol_args(3, OsStr::new(&"a".repeat(4088))).is_err());
+    }
+
+    #[test]
+    fn test_directory_fallback() {
+        let dir = tempfile::tempdir().unwrap();
+        let rootfs = dir.path().join("distros/ubuntu");
+        create_rootfs_dir(&rootfs).unwrap();
+        fs::write(rootfs.join("hello"), "world").unwrap();
+
+        let copy = dir.path().join("distros/ubuntu-copy");
+        copy_rootfs(&rootfs, &copy).unwrap();
+        assert_eq!("world", fs::read_to_string(copy.join("hello")).unwrap());
+        assert!(copy_rootfs(&rootfs, &copy).is_err());
+
+        remove_rootfs_dir(&copy).unwrap();
+        assert!(!copy.exists());
+    }
+}
@@ -0,0 +1,542 @@
+use anyhow::{bail, Context, Result};
+use serde::Deserialize;
+use std::path::Path;
+use std::sync::Arc;
+
+use crate::syscall_table::get_syscall_number;
+
+/// A seccomp profile in the format of Docker, such as
+/// https://github.com/moby/moby/blob/master/profiles/seccomp/default.json.
+/// The processes in the distro have all their capabilities, so the rules which include
+/// capabilities are applied and the ones which exclude them are not. minKernel is not checked.
+#[derive(Deserialize, Clone, Debug, PartialEq)]
+#[serde(rename_all = "camelCase")]
+pub struct SeccompProfile {
+    pub default_action: String,
+    #[serde(default)]
+    pub default_errno_ret: Option<u32>,
+    #[serde(default)]
+    pub syscalls: Vec<SyscallRule>,
+}
+
+#[derive(Deserialize, Clone, Debug, PartialEq)]
+#[serde(rename_all = "camelCase")]
+pub struct SyscallRule {
+    #[serde(default)]
+    pub names: Vec<String>,
+    /// The single name used by the old format of the profile.
+    #[serde(default)]
+    pub name: Option<String>,
+    pub action: String,
+    #[serde(default)]
+    pub errno_ret: Option<u32>,
+    #[serde(default)]
+    pub args: Vec<SyscallArg>,
+    #[serde(default)]
+    pub includes: SyscallRuleFilter,
+    #[serde(default)]
+    pub excludes: SyscallRuleFilter,
+}
+
+#[derive(Deserialize, Clone, Debug, PartialEq)]
+#[serde(rename_all = "camelCase")]
+pub struct SyscallArg {
+    pub index: u32,
+    pub value: u64,
+    #[serde(default)]
+    pub value_two: u64,
+    pub op: String,
+}
+
+#[derive(Deserialize, Clone, Debug, Default, PartialEq)]
+#[serde(rename_all = "camelCase")]
+pub struct SyscallRuleFilter {
+    #[serde(default)]
+    pub arches: Vec<String>,
+    #[serde(default)]
+    pub caps: Vec<String>,
+}
+
+/// A compiled seccomp filter, which can be shared by the processes spawned from this process.
+#[derive(Clone, Debug)]
+pub struct SeccompFilter {
+    program: Arc<Vec<SockFilter>>,
+}
+
+#[repr(C)]
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+struct SockFilter {
+    code: u16,
+    jt: u8,
+    jf: u8,
+    k: u32,
+}
+
+#[repr(C)]
+struct SockFprog {
+    len: u16,
+    filter: *const SockFilter,
+}
+
+const BPF_LD_W_ABS: u16 = 0x20;
+const BPF_ALU_AND_K: u16 = 0x54;
+const BPF_JMP_JEQ_K: u16 = 0x15;
+const BPF_JMP_JGT_K: u16 = 0x25;
+const BPF_JMP_JGE_K: u16 = 0x35;
+const BPF_RET_K: u16 = 0x06;
+const BPF_MAXINSNS: usize = 4096;
+
+const SECCOMP_MODE_FILTER: nix::libc::c_ulong = 2;
+
+const SECCOMP_RET_KILL_PROCESS: u32 = 0x8000_0000;
+const SECCOMP_RET_KILL_THREAD: u32 = 0x0000_0000;
+const SECCOMP_RET_TRAP: u32 = 0x0003_0000;
+const SECCOMP_RET_ERRNO: u32 = 0x0005_0000;
+const SECCOMP_RET_LOG: u32 = 0x7ffc_0000;
+const SECCOMP_RET_ALLOW: u32 = 0x7fff_0000;
+
+// The offsets in struct seccomp_data.
+const SECCOMP_DATA_NR: u32 = 0;
+const SECCOMP_DATA_ARCH: u32 = 4;
+const SECCOMP_DATA_ARGS: u32 = 16;
+
+#[cfg(target_arch = "x86_64")]
+const AUDIT_ARCH: u32 = 0xc000_003e;
+#[cfg(target_arch = "aarch64")]
+const AUDIT_ARCH: u32 = 0xc000_00b7;
+#[cfg(target_arch = "x86_64")]
+const ARCH_NAMES: &[&str] = &["amd64", "x86_64", "SCMP_ARCH_X86_64"];
+#[cfg(target_arch = "aarch64")]
+const ARCH_NAMES: &[&str] = &["arm64", "aarch64", "SCMP_ARCH_AARCH64"];
+
+/// The x32 ABI shares the arch of x86_64, and is told by this bit of the syscall number.
+#[cfg(target_arch = "x86_64")]
+const X32_SYSCALL_BIT: u32 = 0x4000_0000;
+
+const EPERM: u32 = 1;
+
+impl SeccompProfile {
+    pub fn from_json_str(cont: &str) -> Result<SeccompProfile> {
+        serde_json::from_str(cont).with_context(|| "Failed to parse the seccomp profile.")
+    }
+
+    pub fn load<P: AsRef<Path>>(path: P) -> Result<SeccompProfile> {
+        let cont = std::fs::read_to_string(path.as_ref())
+            .with_context(|| format!("Failed to read {:?}.", path.as_ref()))?;
+        Self::from_json_str(&cont)
+            .with_context(|| format!("Invalid seccomp profile {:?}.", path.as_ref()))
+    }
+
+    /// Compiles the profile into a BPF program. The rules are checked in the order of the
+    /// profile, and the first rule which matches the syscall decides the action.
+    pub fn compile(&self) -> Result<SeccompFilter> {
+        let default_errno = self.default_errno_ret.unwrap_or(EPERM);
+        let default_action = parse_action(&self.default_action, default_errno)?;
+
+        let mut program = vec![
+            stmt(BPF_LD_W_ABS, SECCOMP_DATA_ARCH),
+            jump(BPF_JMP_JEQ_K, AUDIT_ARCH, 1, 0),
+            stmt(BPF_RET_K, SECCOMP_RET_KILL_PROCESS),
+            stmt(BPF_LD_W_ABS, SECCOMP_DATA_NR),
+        ];
+        #[cfg(target_arch = "x86_64")]
+        program.extend_from_slice(&[
+            jump(BPF_JMP_JGE_K, X32_SYSCALL_BIT, 0, 1),
+            stmt(BPF_RET_K, SECCOMP_RET_KILL_PROCESS),
+        ]);
+
+        for rule in &self.syscalls {
+            if !rule.applies_to_this_system() {
+                continue;
+            }
+            let action = parse_action(&rule.action, rule.errno_ret.unwrap_or(default_errno))?;
+            let names = rule.names.iter().chain(rule.name.iter());
+            for name in names {
+                let nr = match get_syscall_number(name) {
+                    Some(nr) => nr,
+                    None => {
+                        // Profiles list the syscalls of the other architectures and newer kernels.
+                        log::debug!("Skipping the unknown syscall '{}'.", name);
+                        continue;
+                    }
+                };
+                program.extend(compile_rule(nr, &rule.args, action)?);
+            }
+        }
+        program.push(stmt(BPF_RET_K, default_action));
+
+        if program.len() > BPF_MAXINSNS {
+            bail!(
+                "The seccomp profile is too large. It has {} instructions while the kernel \
+                 accepts {}.",
+                program.len(),
+                BPF_MAXINSNS
+            );
+        }
+        Ok(SeccompFilter {
+            program: Arc::new(program),
+        })
+    }
+}
+
+impl SyscallRule {
+    fn applies_to_this_system(&self) -> bool {
+        let is_this_arch = |arches: &[String]| {
+            arches
+                .iter()
+                .any(|arch| ARCH_NAMES.contains(&arch.as_str()))
+        };
+        if !self.includes.arches.is_empty() && !is_this_arch(&self.includes.arches) {
+            return false;
+        }
+        if is_this_arch(&self.excludes.arches) || !self.excludes.caps.is_empty() {
+            return false;
+        }
+        true
+    }
+}
+
+impl SeccompFilter {
+    /// Installs the filter into the current process. This is meant to be called right before
+    /// exec, such as in a pre_exec closure, so it doesn't allocate.
+    /// A non-root process has no_new_privs set as the kernel requires, so setuid programs such
+    /// as sudo don't gain the privilege after this.
+    pub fn apply(&self) -> std::io::Result<()> {
+        if !nix::unistd::geteuid().is_root() {
+            let ret = unsafe { nix::libc::prctl(nix::libc::PR_SET_NO_NEW_PRIVS, 1, 0, 0, 0) };
+            if ret != 0 {
+                return Err(std::io::Error::last_os_error());
+            }
+        }
+        let prog = SockFprog {
+            len: self.program.len() as u16,
+            filter: self.program.as_ptr(),
+        };
+        let ret = unsafe {
+            nix::libc::prctl(
+                nix::libc::PR_SET_SECCOMP,
+                SECCOMP_MODE_FILTER,
+                &prog as *const SockFprog,
+            )
+        };
+        if ret != 0 {
+            return Err(std::io::Error::last_os_error());
+        }
+        Ok(())
+    }
+}
+
+fn parse_action(action: &str, errno: u32) -> Result<u32> {
+    Ok(match action {
+        "SCMP_ACT_ALLOW" => SECCOMP_RET_ALLOW,
+        "SCMP_ACT_ERRNO" => SECCOMP_RET_ERRNO | (errno & 0xffff),
+        "SCMP_ACT_KILL" | "SCMP_ACT_KILL_THREAD" => SECCOMP_RET_KILL_THREAD,
+        "SCMP_ACT_KILL_PROCESS" => SECCOMP_RET_KILL_PROCESS,
+        "SCMP_ACT_TRAP" => SECCOMP_RET_TRAP,
+        "SCMP_ACT_LOG" => SECCOMP_RET_LOG,
+        _ => bail!("Unsupported seccomp action: '{}'.", action),
+    })
+}
+
+/// The destination of a jump in a rule, which is resolved when the rule is assembled.
+#[derive(Clone, Copy)]
+enum Jump {
+    /// Skips the given number of instructions.
+    Skip(u8),
+    /// Goes to the end of the rule, where the next rule starts.
+    Fail,
+}
+
+struct Insn {
+    code: u16,
+    k: u32,
+    jt: Jump,
+    jf: Jump,
+}
+
+fn insn(code: u16, k: u32, jt: Jump, jf: Jump) -> Insn {
+    Insn { code, k, jt, jf }
+}
+
+fn load(offset: u32) -> Insn {
+    insn(BPF_LD_W_ABS, offset, Jump::Skip(0), Jump::Skip(0))
+}
+
+/// Returns the instructions which check the syscall number and the arguments, and return the
+/// action if they match. The accumulator holds the syscall number before and after them.
+fn compile_rule(nr: u32, args: &[SyscallArg], action: u32) -> Result<Vec<SockFilter>> {
+    let mut insns = vec![insn(BPF_JMP_JEQ_K, nr, Jump::Skip(0), Jump::Fail)];
+    for arg in args {
+        insns.extend(compile_arg(arg)?);
+    }
+    insns.push(insn(BPF_RET_K, action, Jump::Skip(0), Jump::Skip(0)));
+    let fail = insns.len();
+    // The arguments have been loaded into the accumulator, so load the number again.
+    insns.push(load(SECCOMP_DATA_NR));
+
+    let resolve = |i: usize, jump: Jump| -> Result<u8> {
+        match jump {
+            Jump::Skip(n) => Ok(n),
+            Jump::Fail => {
+                let offset = fail - (i + 1);
+                if offset > u8::MAX as usize {
+                    bail!("A rule of the seccomp profile has too many arguments.");
+                }
+                Ok(offset as u8)
+            }
+        }
+    };
+    insns
+        .iter()
+        .enumerate()
+        .map(|(i, insn)| {
+            Ok(SockFilter {
+                code: insn.code,
+                jt: resolve(i, insn.jt)?,
+                jf: resolve(i, insn.jf)?,
+                k: insn.k,
+            })
+        })
+        .collect()
+}
+
+/// Compares a 64-bit argument by its upper and lower halves, since BPF handles 32-bit words.
+fn compile_arg(arg: &SyscallArg) -> Result<Vec<Insn>> {
+    if arg.index >= 6 {
+        bail!("Invalid argument index of seccomp: {}.", arg.index);
+    }
+    // Both x86_64 and aarch64 are little endian.
+    let lo_offset = SECCOMP_DATA_ARGS + 8 * arg.index;
+    let hi_offset = lo_offset + 4;
+    let hi = |v: u64| (v >> 32) as u32;
+    let lo = |v: u64| v as u32;
+    let (next, fail) = (Jump::Skip(0), Jump::Fail);
+    let v = arg.value;
+    Ok(match arg.op.as_str() {
+        "SCMP_CMP_EQ" => vec![
+            load(hi_offset),
+            insn(BPF_JMP_JEQ_K, hi(v), next, fail),
+            load(lo_offset),
+            insn(BPF_JMP_JEQ_K, lo(v), next, fail),
+        ],
+        "SCMP_CMP_NE" => vec![
+            load(hi_offset),
+            insn(BPF_JMP_JEQ_K, hi(v), next, Jump::Skip(2)),
+            load(lo_offset),
+            insn(BPF_JMP_JEQ_K, lo(v), fail, next),
+        ],
+        // value is the mask, and valueTwo is the value to compare with.
+        "SCMP_CMP_MASKED_EQ" => vec![
+            load(hi_offset),
+            insn(BPF_ALU_AND_K, hi(v), next, next),
+            insn(BPF_JMP_JEQ_K, hi(arg.value_two), next, fail),
+            load(lo_offset),
+            insn(BPF_ALU_AND_K, lo(v), next, next),
+            insn(BPF_JMP_JEQ_K, lo(arg.value_two), next, fail),
+        ],
+        "SCMP_CMP_GT" | "SCMP_CMP_GE" => vec![
+            load(hi_offset),
+            insn(BPF_JMP_JGT_K, hi(v), Jump::Skip(3), next),
+            insn(BPF_JMP_JEQ_K, hi(v), next, fail),
+            load(lo_offset),
+            insn(
+                if arg.op == "SCMP_CMP_GT" {
+                    BPF_JMP_JGT_K
+                } else {
+                    BPF_JMP_JGE_K
+                },
+                lo(v),
+                next,
+                fail,
+            ),
+        ],
+        "SCMP_CMP_LT" | "SCMP_CMP_LE" => vec![
+            load(hi_offset),
+            insn(BPF_JMP_JGT_K, hi(v), fail, next),
+            insn(BPF_JMP_JEQ_K, hi(v), next, Jump::Skip(2)),
+            load(lo_offset),
+            insn(
+                if arg.op == "SCMP_CMP_LT" {
+                    BPF_JMP_JGE_K
+                } else {
+                    BPF_JMP_JGT_K
+                },
+                lo(v),
+                fail,
+                next,
+            ),
+        ],
+        _ => bail!("Unsupported seccomp operator: '{}'.", &arg.op),
+    })
+}
+
+fn stmt(code: u16, k: u32) -> SockFilter {
+    SockFilter {
+        code,
+        jt: 0,
+        jf: 0,
+        k,
+    }
+}
+
+fn jump(code: u16, k: u32, jt: u8, jf: u8) -> SockFilter {
+    SockFilter { code, jt, jf, k }
+}
+
+#[cfg(test)]
+mod test_seccomp {
+    use super::*;
+
+    /// Runs the program for a syscall in the way the kernel does.
+    fn run(filter: &SeccompFilter, name: &str, args: [u64; 6]) -> u32 {
+        let nr = get_syscall_number(name).unwrap();
+        let load_word = |offset: u32| -> u32 {
+            match offset {
+                SECCOMP_DATA_NR => nr,
+                SECCOMP_DATA_ARCH => AUDIT_ARCH,
+                _ => {
+                    let i = ((offset - SECCOMP_DATA_ARGS) / 8) as usize;
+                    if (offset - SECCOMP_DATA_ARGS) % 8 == 0 {
+                        args[i] as u32
+                    } else {
+                        (args[i] >> 32) as u32
+                    }
+                }
+            }
+        };
+        let mut acc = 0;
+        let mut pc = 0;
+        loop {
+            let insn = filter.program[pc];
+            pc += 1;
+            let taken = match insn.code {
+                BPF_LD_W_ABS => {
+                    acc = load_word(insn.k);
+                    continue;
+                }
+                BPF_ALU_AND_K => {
+                    acc &= insn.k;
+                    continue;
+                }
+                BPF_RET_K => return insn.k,
+                BPF_JMP_JEQ_K => acc == insn.k,
+                BPF_JMP_JGT_K => acc > insn.k,
+                BPF_JMP_JGE_K => acc >= insn.k,
+                _ => panic!("unknown instruction {:?}", insn),
+            };
+            let offset = if taken { insn.jt } else { insn.jf };
+            pc += offset as usize;
+        }
+    }
+
+    const PROFILE: &str = r#"{
+        "defaultAction": "SCMP_ACT_ERRNO",
+        "defaultErrnoRet": 38,
+        "syscalls": [
+            {
+                "names": ["read", "write", "no_such_syscall"],
+                "action": "SCMP_ACT_ALLOW"
+            },
+            {
+                "names": ["personality"],
+                "action": "SCMP_ACT_ALLOW",
+                "args": [{"index": 0, "value": 8, "op": "SCMP_CMP_EQ"}]
+            },
+            {
+                "names": ["clone"],
+                "action": "SCMP_ACT_ALLOW",
+                "args": [{"index": 0, "value": 2114060288, "valueTwo": 0, "op": "SCMP_CMP_MASKED_EQ"}]
+            },
+            {
+                "names": ["socket"],
+                "action": "SCMP_ACT_ALLOW",
+                "args": [{"index": 0, "value": 40, "op": "SCMP_CMP_LT"}]
+            },
+            {
+                "names": ["kill"],
+                "action": "SCMP_ACT_ERRNO",
+                "errnoRet": 1,
+                "args": [{"index": 1, "value": 4294967297, "op": "SCMP_CMP_GE"}]
+            },
+            {
+                "names": ["kill"],
+                "action": "SCMP_ACT_ALLOW"
+            },
+            {
+                "names": ["mount"],
+                "action": "SCMP_ACT_ALLOW",
+                "excludes": {"caps": ["CAP_SYS_ADMIN"]}
+            },
+            {
+                "names": ["reboot"],
+                "action": "SCMP_ACT_ALLOW",
+                "includes": {"arches": ["s390x"]}
+            }
+        ]
+    }"#;
+
+    #[test]
+    fn test_compiled_filter() {
+        let filter = SeccompProfile::from_json_str(PROFILE)
+            .unwrap()
+            .compile()
+            .unwrap();
+        let errno = |errno: u32| SECCOMP_RET_ERRNO | errno;
+        assert_eq!(SECCOMP_RET_ALLOW, run(&filter, "read", [0; 6]));
+        assert_eq!(SECCOMP_RET_ALLOW, run(&filter, "write", [0; 6]));
+        assert_eq!(errno(38), run(&filter, "close", [0; 6]));
+
+        assert_eq!(
+            SECCOMP_RET_ALLOW,
+            run(&filter, "personality", [8, 0, 0, 0, 0, 0])
+        );
+        assert_eq!(errno(38), run(&filter, "personality", [9, 0, 0, 0, 0, 0]));
+        assert_eq!(
+            errno(38),
+            run(&filter, "personality", [8 | 1 << 32, 0, 0, 0, 0, 0])
+        );
+
+        // clone without the namespace flags.
+        assert_eq!(
+            SECCOMP_RET_ALLOW,
+            run(&filter, "clone", [0x11, 0, 0, 0, 0, 0])
+        );
+        assert_eq!(
+            errno(38),
+            run(&filter, "clone", [0x1000_0000, 0, 0, 0, 0, 0])
+        );
+
+        assert_eq!(
+            SECCOMP_RET_ALLOW,
+            run(&filter, "socket", [39, 0, 0, 0, 0, 0])
+        );
+        assert_eq!(errno(38), run(&filter, "socket", [40, 0, 0, 0, 0, 0]));
+        assert_eq!(errno(38), run(&filter, "socket", [1 << 32, 0, 0, 0, 0, 0]));
+
+        assert_eq!(
+            SECCOMP_RET_ALLOW,
+            run(&filter, "kill", [0, 1 << 32, 0, 0, 0, 0])
+        );
+        assert_eq!(
+            errno(1),
+            run(&filter, "kill", [0, (1 << 32) + 1, 0, 0, 0, 0])
+        );
+        assert_eq!(errno(1), run(&filter, "kill", [0, 2 << 32, 0, 0, 0, 0]));
+
+        assert_eq!(errno(38), run(&filter, "mount", [0; 6]));
+        assert_eq!(errno(38), run(&filter, "reboot", [0; 6]));
+    }
+
+    #[test]
+    fn test_invalid_profile() {
+        let compile = |json: &str| SeccompProfile::from_json_str(json)?.compile();
+        assert!(compile(r#"{"defaultAction": "SCMP_ACT_UNKNOWN"}"#).is_err());
+        assert!(compile(
+            r#"{"defaultAction": "SCMP_ACT_ALLOW", "syscalls": [
+                {"names": ["read"], "action": "SCMP_ACT_ERRNO",
+                 "args": [{"index": 6, "value": 0, "op": "SCMP_CMP_EQ"}]}]}"#
+        )
+        .is_err());
+        assert!(compile(r#"{"syscalls": []}"#).is_err());
+    }
+}
@@ -0,0 +1,152 @@
+use anyhow::{Context, Result};
+use serde::Deserialize;
+use std::collections::BTreeMap;
+
+/// The index of the images on an image server of the simplestreams format, such as
+/// https://images.linuxcontainers.org/streams/v1/images.json, which LXD uses.
+#[derive(Debug, Deserialize)]
+pub struct ImageStream {
+    products: BTreeMap<String, Product>,
+}
+
+#[derive(Debug, Deserialize)]
+struct Product {
+    arch: String,
+    release: String,
+    variant: String,
+    versions: BTreeMap<String, ProductVersion>,
+}
+
+#[derive(Debug, Deserialize)]
+struct ProductVersion {
+    items: BTreeMap<String, ProductItem>,
+}
+
+#[derive(Debug, Deserialize)]
+struct ProductItem {
+    ftype: String,
+    path: String,
+}
+
+/// The latest rootfs tarball of a release of a distro.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RootfsImage {
+    /// The name of the distro, such as "ubuntu".
+    pub distro: String,
+    /// The release, such as "jammy".
+    pub release: String,
+    /// The path of the rootfs.tar.xz relative to the server.
+    pub path: String,
+}
+
+impl ImageStream {
+    pub fn from_json_str(json: &str) -> Result<ImageStream> {
+        serde_json::from_str(json).with_context(|| "Failed to parse the image stream.")
+    }
+
+    /// Returns the latest rootfs tarballs for `arch`, such as "amd64", sorted by the distro and the
+    /// release. `variant_of` returns the variant to choose for a distro, such as "default".
+    pub fn list_rootfs_images<F>(&self, arch: &str, variant_of: F) -> Vec<RootfsImage>
+    where
+        F: Fn(&str) -> &'static str,
+    {
+        let mut images = vec![];
+        for (product_name, product) in &self.products {
+            // The names are like "ubuntu:jammy:amd64:default".
+            let distro = product_name.split(':').next().unwrap_or_default();
+            if product.arch != arch || product.variant != variant_of(distro) {
+                continue;
+            }
+            // The versions are the dates of the builds such as "20231201_07:42", which sort in
+            // the order of the builds.
+            let latest_rootfs = product.versions.values().rev().find_map(|version| {
+                version
+                    .items
+                    .values()
+                    .find(|item| item.ftype == "root.tar.xz")
+            });
+            if let Some(rootfs) = latest_rootfs {
+                images.push(RootfsImage {
+                    distro: distro.to_owned(),
+                    release: product.release.clone(),
+                    path: rootfs.path.clone(),
+                });
+            }
+        }
+        images.sort_by(|a, b| (&a.distro, &a.release).cmp(&(&b.distro, &b.release)));
+        images
+    }
+}
+
+#[cfg(test)]
+mod test_simplestreams {
+    use super::*;
+
+    #[test]
+    fn test_list_rootfs_images() {
+        let stream = ImageStream::from_json_str(
+            r#"{
+                "format": "products:1.0",
+                "products": {
+                    "ubuntu:jammy:amd64:default": {
+                        "arch": "amd64", "os": "Ubuntu", "release": "jammy", "variant": "default",
+                        "versions": {
+                            "20231130_07:42": {"items": {
+                                "root.tar.xz": {"ftype": "root.tar.xz", "path": "images/ubuntu/jammy/amd64/default/20231130_07:42/rootfs.tar.xz", "size": 1}
+                            }},
+                            "20231201_07:42": {"items": {
+                                "lxd.tar.xz": {"ftype": "lxd.tar.xz", "path": "images/ubuntu/jammy/amd64/default/20231201_07:42/lxd.tar.xz"},
+                                "root.tar.xz": {"ftype": "root.tar.xz", "path": "images/ubuntu/jammy/amd64/default/20231201_07:42/rootfs.tar.xz"}
+                            }}
+                        }
+                    },
+                    "ubuntu:jammy:arm64:default": {
+                        "arch": "arm64", "release": "jammy", "variant": "default",
+                        "versions": {"20231201_07:42": {"items": {
+                            "root.tar.xz": {"ftype": "root.tar.xz", "path": "arm64.tar.xz"}
+                        }}}
+                    },
+                    "gentoo:current:amd64:systemd": {
+                        "arch": "amd64", "release": "current", "variant": "systemd",
+                        "versions": {"20231201_16:07": {"items": {
+                            "root.tar.xz": {"ftype": "root.tar.xz", "path": "gentoo.tar.xz"}
+                        }}}
+                    },
+                    "gentoo:current:amd64:openrc": {
+                        "arch": "amd64", "release": "current", "variant": "openrc",
+                        "versions": {"20231201_16:07": {"items": {
+                            "root.tar.xz": {"ftype": "root.tar.xz", "path": "openrc.tar.xz"}
+                        }}}
+                    },
+                    "alpine:edge:amd64:default": {
+                        "arch": "amd64", "release": "edge", "variant": "default",
+                        "versions": {"20231201_13:00": {"items": {
+                            "root.squashfs": {"ftype": "squashfs", "path": "alpine.squashfs"}
+                        }}}
+                    }
+                }
+            }"#,
+        )
+        .unwrap();
+        let images = stream.list_rootfs_images("amd64", |distro| match distro {
+            "gentoo" => "systemd",
+            _ => "default",
+        });
+        assert_eq!(
+            vec![
+                RootfsImage {
+                    distro: "gentoo".to_owned(),
+                    release: "current".to_owned(),
+                    path: "gentoo.tar.xz".to_owned(),
+                },
+                RootfsImage {
+                    distro: "ubuntu".to_owned(),
+                    release: "jammy".to_owned(),
+                    path: "images/ubuntu/jammy/amd64/default/20231201_07:42/rootfs.tar.xz"
+                        .to_owned(),
+                },
+            ],
+            images
+        );
+    }
+}
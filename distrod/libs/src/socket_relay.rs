@@ -0,0 +1,116 @@
+use anyhow::{bail, Context, Result};
+use std::fs;
+use std::io::{Read, Write};
+use std::net::Shutdown;
+use std::os::unix::fs::FileTypeExt;
+use std::os::unix::net::UnixStream;
+use std::path::Path;
+use std::sync::mpsc;
+
+/// Removes the socket left by the last run, which makes bind fail.
+pub fn remove_stale_socket(path: &Path) -> Result<()> {
+    match fs::symlink_metadata(path) {
+        Ok(metadata) if metadata.file_type().is_socket() => {
+            fs::remove_file(path).with_context(|| format!("Failed to remove {:?}.", path))
+        }
+        Ok(_) => bail!("{:?} exists and is not a socket.", path),
+        Err(_) => Ok(()),
+    }
+}
+
+/// Copies the data from the stream to `writer`, and from `reader` to the stream, until either of
+/// them ends. Named pipes can't be half-closed, so the stream is closed then, and the other copy
+/// ends when its reader sees it.
+pub fn relay_streams<W, R>(stream: UnixStream, mut writer: W, mut reader: R) -> Result<()>
+where
+    W: Write + Send + 'static,
+    R: Read + Send + 'static,
+{
+    let mut stream_reader = stream
+        .try_clone()
+        .with_context(|| "Failed to clone the socket.")?;
+    let mut stream_writer = stream
+        .try_clone()
+        .with_context(|| "Failed to clone the socket.")?;
+    let (done_sender, done_receiver) = mpsc::channel();
+    let from_stream_done = done_sender.clone();
+    std::thread::spawn(move || {
+        let result = std::io::copy(&mut stream_reader, &mut writer)
+            .with_context(|| "Failed to relay from the socket.");
+        // Let the other side see EOF.
+        drop(writer);
+        let _ = from_stream_done.send(result);
+    });
+    std::thread::spawn(move || {
+        let result = std::io::copy(&mut reader, &mut stream_writer)
+            .with_context(|| "Failed to relay to the socket.");
+        let _ = done_sender.send(result);
+    });
+    let result = done_receiver
+        .recv()
+        .expect("[BUG] the relay threads send the result.");
+    let _ = stream.shutdown(Shutdown::Both);
+    result.map(|_| ())
+}
+
+#[cfg(test)]
+mod test_socket_relay {
+    use super::*;
+    use std::os::unix::net::UnixListener;
+
+    #[test]
+    fn test_relay_streams() {
+        let (mut client, stream) = UnixStream::pair().unwrap();
+        let (writer, mut written) = UnixStream::pair().unwrap();
+        let (reader, mut to_read) = UnixStream::pair().unwrap();
+        let relay = std::thread::spawn(move || relay_streams(stream, writer, reader));
+
+        client.write_all(b"request").unwrap();
+        let mut buf = [0; 7];
+        written.read_exact(&mut buf).unwrap();
+        assert_eq!(b"request", &buf);
+
+        to_read.write_all(b"response").unwrap();
+        let mut buf = [0; 8];
+        client.read_exact(&mut buf).unwrap();
+        assert_eq!(b"response", &buf);
+
+        // The relay ends when the client closes the stream, and closes the writer.
+        drop(client);
+        relay.join().unwrap().unwrap();
+        let mut rest = vec![];
+        written.read_to_end(&mut rest).unwrap();
+        assert!(rest.is_empty());
+    }
+
+    #[test]
+    fn test_relay_streams_ends_with_reader() {
+        let (mut client, stream) = UnixStream::pair().unwrap();
+        let (writer, _written) = UnixStream::pair().unwrap();
+        let (reader, to_read) = UnixStream::pair().unwrap();
+        let relay = std::thread::spawn(move || relay_streams(stream, writer, reader));
+
+        // The stream is closed when the reader ends, since a named pipe can't be half-closed.
+        drop(to_read);
+        relay.join().unwrap().unwrap();
+        let mut rest = vec![];
+        client.read_to_end(&mut rest).unwrap();
+        assert!(rest.is_empty());
+    }
+
+    #[test]
+    fn test_remove_stale_socket() {
+        let dir = tempfile::tempdir().unwrap();
+        let socket = dir.path().join("test.sock");
+        drop(UnixListener::bind(&socket).unwrap());
+        remove_stale_socket(&socket).unwrap();
+        assert!(!socket.exists());
+        // A missing socket is fine.
+        remove_stale_socket(&socket).unwrap();
+
+        let file = dir.path().join("file");
+        fs::write(&file, "").unwrap();
+        assert!(remove_stale_socket(&file).is_err());
+        assert!(file.exists());
+    }
+}
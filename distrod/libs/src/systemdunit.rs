@@ -39,6 +39,16 @@ impl SystemdUnitDisabler {
         self.make_masked_unit_symlink()
     }
 
+    /// Removes the mask made by `mask`. Does nothing if the unit isn't masked.
+    pub fn unmask(&self) -> Result<()> {
+        if !self.is_masked()? {
+            return Ok(());
+        }
+        let local_unit_path = self.get_local_unit_path();
+        fs::remove_file(&local_unit_path)
+            .with_context(|| format!("Failed to remove {:?}", &local_unit_path))
+    }
+
     pub fn is_masked(&self) -> Result<bool> {
         let local_unit_path = self.get_local_unit_path();
 
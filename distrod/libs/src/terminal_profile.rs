@@ -0,0 +1,150 @@
+use anyhow::{Context, Result};
+use serde::Serialize;
+
+/// The subdirectory of %LocalAppData%\Microsoft\Windows Terminal\Fragments for Distrod.
+/// Windows Terminal lists the profiles of the fragments in it under this name.
+pub static FRAGMENT_APP_NAME: &str = "Distrod";
+
+/// A profile of Windows Terminal which logs in to a distro by `distrod exec`.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct TerminalProfile {
+    pub name: String,
+    pub commandline: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub icon: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub starting_directory: Option<String>,
+}
+
+#[derive(Debug, Serialize)]
+struct TerminalFragment<'a> {
+    profiles: Vec<&'a TerminalProfile>,
+}
+
+impl TerminalProfile {
+    /// Builds the profile which runs the login shell of `user` in `distro_name` by `distrod exec`,
+    /// starting in the home directory.
+    pub fn new(
+        distro_name: &str,
+        distrod_bin_path: &str,
+        user: &str,
+        home: &str,
+        shell: &str,
+    ) -> TerminalProfile {
+        let args = [
+            "wsl.exe",
+            "-d",
+            distro_name,
+            "-u",
+            "root",
+            "-e",
+            distrod_bin_path,
+            "exec",
+            "--user",
+            user,
+            "--",
+            shell,
+            "-l",
+        ];
+        let commandline = args
+            .iter()
+            .map(|arg| quote_windows_arg(arg))
+            .collect::<Vec<_>>()
+            .join(" ");
+        TerminalProfile {
+            name: distro_name.to_owned(),
+            commandline,
+            icon: None,
+            starting_directory: Some(format!(
+                r"\\wsl$\{}{}",
+                distro_name,
+                home.replace('/', r"\")
+            )),
+        }
+    }
+
+    pub fn with_icon(&mut self, icon: String) -> &mut Self {
+        self.icon = Some(icon);
+        self
+    }
+
+    /// Returns the JSON of the fragment file which has only this profile.
+    pub fn to_fragment_json(&self) -> Result<String> {
+        let fragment = TerminalFragment {
+            profiles: vec![self],
+        };
+        serde_json::to_string_pretty(&fragment)
+            .with_context(|| "Failed to serialize the Windows Terminal fragment.")
+    }
+}
+
+/// Quotes an argument by the rules of CommandLineToArgvW, which wsl.exe parses its command line by.
+pub(crate) fn quote_windows_arg(arg: &str) -> String {
+    if !arg.is_empty() && !arg.contains(|c: char| c == ' ' || c == '\t' || c == '"') {
+        return arg.to_owned();
+    }
+    let mut quoted = String::from("\"");
+    let mut backslashes = 0;
+    for c in arg.chars() {
+        match c {
+            '\\' => backslashes += 1,
+            '"' => {
+                quoted.push_str(&"\\".repeat(backslashes * 2 + 1));
+                quoted.push('"');
+                backslashes = 0;
+            }
+            _ => {
+                quoted.push_str(&"\\".repeat(backslashes));
+                quoted.push(c);
+                backslashes = 0;
+            }
+        }
+    }
+    quoted.push_str(&"\\".repeat(backslashes * 2));
+    quoted.push('"');
+    quoted
+}
+
+#[cfg(test)]
+mod test_terminal_profile {
+    use super::*;
+
+    #[test]
+    fn test_to_fragment_json() {
+        let mut profile = TerminalProfile::new(
+            "Distrod",
+            "/opt/distrod/bin/distrod",
+            "alice",
+            "/home/alice",
+            "/bin/bash",
+        );
+        profile.with_icon(r"C:\Distrod\distrod_wsl_launcher.exe".to_owned());
+        assert_eq!(
+            "wsl.exe -d Distrod -u root -e /opt/distrod/bin/distrod exec --user alice -- /bin/bash -l",
+            profile.commandline
+        );
+        let json: serde_json::Value =
+            serde_json::from_str(&profile.to_fragment_json().unwrap()).unwrap();
+        assert_eq!(
+            serde_json::json!({
+                "profiles": [{
+                    "name": "Distrod",
+                    "commandline": profile.commandline,
+                    "icon": r"C:\Distrod\distrod_wsl_launcher.exe",
+                    "startingDirectory": r"\\wsl$\Distrod\home\alice",
+                }]
+            }),
+            json
+        );
+    }
+
+    #[test]
+    fn test_quote_windows_arg() {
+        assert_eq!("plain", quote_windows_arg("plain"));
+        assert_eq!("\"\"", quote_windows_arg(""));
+        assert_eq!("\"My Distro\"", quote_windows_arg("My Distro"));
+        assert_eq!(r#""a\"b""#, quote_windows_arg(r#"a"b"#));
+        assert_eq!(r#""a b\\""#, quote_windows_arg(r"a b\"));
+    }
+}
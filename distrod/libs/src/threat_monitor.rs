@@ -0,0 +1,460 @@
+use anyhow::{bail, Context, Result};
+use nix::libc;
+use std::collections::{HashMap, HashSet};
+use std::ffi::CString;
+use std::fmt;
+use std::net::SocketAddr;
+use std::os::unix::ffi::OsStrExt;
+use std::os::unix::io::RawFd;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, Instant};
+
+use procfs::net::TcpState;
+use procfs::process::{FDTarget, Process};
+
+use crate::distrod_config::MonitorConfig;
+
+// The fanotify constants, which are not defined in the libc crate we use.
+const FAN_CLASS_NOTIF: libc::c_uint = 0x0;
+const FAN_CLOEXEC: libc::c_uint = 0x1;
+const FAN_NONBLOCK: libc::c_uint = 0x2;
+const FAN_MARK_ADD: libc::c_uint = 0x1;
+const FAN_MARK_MOUNT: libc::c_uint = 0x10;
+const FAN_OPEN_EXEC: u64 = 0x1000;
+const FANOTIFY_METADATA_VERSION: u8 = 3;
+
+#[repr(C)]
+#[derive(Clone, Copy)]
+struct FanotifyEventMetadata {
+    event_len: u32,
+    vers: u8,
+    reserved: u8,
+    metadata_len: u16,
+    mask: u64,
+    fd: i32,
+    pid: i32,
+}
+
+/// The reason why a process looks suspicious.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Suspicion {
+    ExecFromWatchedDir,
+    HighCpu { percent: f64, duration: Duration },
+    ManyConnections { count: usize },
+}
+
+impl Suspicion {
+    fn kind(&self) -> &'static str {
+        match self {
+            Suspicion::ExecFromWatchedDir => "exec",
+            Suspicion::HighCpu { .. } => "cpu",
+            Suspicion::ManyConnections { .. } => "connections",
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct Alert {
+    pub pid: i32,
+    pub exe: PathBuf,
+    pub suspicion: Suspicion,
+}
+
+impl fmt::Display for Alert {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match &self.suspicion {
+            Suspicion::ExecFromWatchedDir => {
+                write!(f, "{:?} (pid {}) has been executed.", &self.exe, self.pid)
+            }
+            Suspicion::HighCpu { percent, duration } => write!(
+                f,
+                "{:?} (pid {}) has been using {:.0}% CPU for {} seconds.",
+                &self.exe,
+                self.pid,
+                percent,
+                duration.as_secs()
+            ),
+            Suspicion::ManyConnections { count } => write!(
+                f,
+                "{:?} (pid {}) has {} outbound connections.",
+                &self.exe, self.pid, count
+            ),
+        }
+    }
+}
+
+/// Watches the processes of the distro and flags the ones that match the heuristics.
+/// With `alert_on_exec`, programs executed from the watched directories are caught by fanotify
+/// even if they exit before the next sampling. CPU and connections are sampled from procfs
+/// periodically.
+pub struct ThreatMonitor {
+    config: MonitorConfig,
+    fanotify: Option<RawFd>,
+    cpu_tracker: CpuTracker,
+    alerted: HashSet<(i32, &'static str)>,
+}
+
+impl ThreatMonitor {
+    pub fn new(config: MonitorConfig) -> Result<ThreatMonitor> {
+        // The exec marks make the kernel report every exec on the mounts, so they are added only
+        // when the exec alerts are enabled.
+        let fanotify = if !config.alert_on_exec {
+            None
+        } else {
+            match init_fanotify(&config.watched_dirs) {
+                Ok(fd) => Some(fd),
+                Err(e) => {
+                    log::warn!(
+                        "Failed to watch the executions with fanotify. Only sampling the processes. {:?}",
+                        e
+                    );
+                    None
+                }
+            }
+        };
+        Ok(ThreatMonitor {
+            config,
+            fanotify,
+            cpu_tracker: CpuTracker::default(),
+            alerted: HashSet::new(),
+        })
+    }
+
+    /// Keeps monitoring the distro and calls `on_alert` once for each suspicious process.
+    /// This never returns unless listing the processes fails.
+    pub fn run<F: FnMut(&Alert)>(&mut self, mut on_alert: F) -> Result<()> {
+        let ticks_per_second =
+            procfs::ticks_per_second().with_context(|| "Failed to get the clock ticks.")? as f64;
+        let interval = Duration::from_secs(self.config.interval_sec.max(1));
+        loop {
+            let mut alerts = vec![];
+            if let Some(fd) = self.fanotify {
+                match read_exec_events(fd) {
+                    Ok(execs) => alerts.extend(execs.into_iter().filter_map(|(pid, exe)| {
+                        if !self.is_unknown_binary(&exe) {
+                            return None;
+                        }
+                        Some(Alert {
+                            pid,
+                            exe,
+                            suspicion: Suspicion::ExecFromWatchedDir,
+                        })
+                    })),
+                    Err(e) => log::warn!("Failed to read fanotify events. {:?}", e),
+                }
+            }
+            alerts.extend(self.sample_processes(ticks_per_second)?);
+            for alert in alerts {
+                if self.alerted.insert((alert.pid, alert.suspicion.kind())) {
+                    on_alert(&alert);
+                }
+            }
+            std::thread::sleep(interval);
+        }
+    }
+
+    fn sample_processes(&mut self, ticks_per_second: f64) -> Result<Vec<Alert>> {
+        let processes =
+            procfs::process::all_processes().with_context(|| "Failed to list the processes.")?;
+        let outbound_sockets = if self.config.max_outbound_connections > 0 {
+            get_outbound_socket_inodes().unwrap_or_else(|e| {
+                log::warn!("Failed to get the TCP connections. {:?}", e);
+                HashSet::new()
+            })
+        } else {
+            HashSet::new()
+        };
+        let now = Instant::now();
+        let mut alerts = vec![];
+        let mut alive = HashSet::new();
+        for process in processes {
+            let pid = process.pid;
+            // Kernel threads and the processes which have exited don't have exe.
+            let exe = match process.exe() {
+                Ok(exe) => exe,
+                Err(_) => continue,
+            };
+            alive.insert(pid);
+            if self.is_allowed(&exe) {
+                continue;
+            }
+            if self.is_unknown_binary(&exe) {
+                let stat = match process.stat() {
+                    Ok(stat) => stat,
+                    Err(_) => continue,
+                };
+                let ticks = stat.utime + stat.stime;
+                if let Some((percent, duration)) = self.cpu_tracker.update(
+                    pid,
+                    ticks,
+                    now,
+                    ticks_per_second,
+                    self.config.cpu_percent,
+                ) {
+                    if duration >= Duration::from_secs(self.config.cpu_duration_sec) {
+                        alerts.push(Alert {
+                            pid,
+                            exe: exe.clone(),
+                            suspicion: Suspicion::HighCpu { percent, duration },
+                        });
+                    }
+                }
+            }
+            if !outbound_sockets.is_empty() {
+                let count = count_sockets(&process, &outbound_sockets);
+                if count >= self.config.max_outbound_connections {
+                    alerts.push(Alert {
+                        pid,
+                        exe,
+                        suspicion: Suspicion::ManyConnections { count },
+                    });
+                }
+            }
+        }
+        self.cpu_tracker.retain(&alive);
+        self.alerted.retain(|(pid, _)| alive.contains(pid));
+        Ok(alerts)
+    }
+
+    fn is_allowed(&self, exe: &Path) -> bool {
+        self.config
+            .allowed_exes
+            .iter()
+            .any(|allowed| allowed == exe)
+    }
+
+    fn is_unknown_binary(&self, exe: &Path) -> bool {
+        !self.is_allowed(exe) && is_unknown_binary(exe, &self.config.watched_dirs)
+    }
+}
+
+impl Drop for ThreatMonitor {
+    fn drop(&mut self) {
+        if let Some(fd) = self.fanotify.take() {
+            let _ = nix::unistd::close(fd);
+        }
+    }
+}
+
+/// Whether the executable is in one of the watched directories, or has been deleted after it
+/// started, which is a common trick to hide a dropped binary.
+fn is_unknown_binary(exe: &Path, watched_dirs: &[PathBuf]) -> bool {
+    if exe.as_os_str().as_bytes().ends_with(b" (deleted)") {
+        return true;
+    }
+    watched_dirs.iter().any(|dir| exe.starts_with(dir))
+}
+
+/// Tracks how long each process has been using more CPU than the threshold.
+#[derive(Default)]
+struct CpuTracker {
+    samples: HashMap<i32, CpuSample>,
+}
+
+struct CpuSample {
+    ticks: u64,
+    sampled_at: Instant,
+    busy_since: Option<Instant>,
+}
+
+impl CpuTracker {
+    /// Records the CPU time of the process, and returns its CPU usage since the last sample and
+    /// how long it has been above the threshold, if it's above the threshold.
+    fn update(
+        &mut self,
+        pid: i32,
+        ticks: u64,
+        now: Instant,
+        ticks_per_second: f64,
+        threshold_percent: f64,
+    ) -> Option<(f64, Duration)> {
+        let previous = match self.samples.insert(
+            pid,
+            CpuSample {
+                ticks,
+                sampled_at: now,
+                busy_since: None,
+            },
+        ) {
+            Some(previous) => previous,
+            None => return None,
+        };
+        let elapsed = now.duration_since(previous.sampled_at).as_secs_f64();
+        if elapsed <= 0.0 || ticks < previous.ticks {
+            return None;
+        }
+        let percent = (ticks - previous.ticks) as f64 * 100.0 / ticks_per_second / elapsed;
+        if percent < threshold_percent {
+            return None;
+        }
+        let busy_since = previous.busy_since.unwrap_or(previous.sampled_at);
+        if let Some(sample) = self.samples.get_mut(&pid) {
+            sample.busy_since = Some(busy_since);
+        }
+        Some((percent, now.duration_since(busy_since)))
+    }
+
+    fn retain(&mut self, alive: &HashSet<i32>) {
+        self.samples.retain(|pid, _| alive.contains(pid));
+    }
+}
+
+fn init_fanotify(watched_dirs: &[PathBuf]) -> Result<RawFd> {
+    let fd = unsafe {
+        libc::fanotify_init(
+            FAN_CLASS_NOTIF | FAN_CLOEXEC | FAN_NONBLOCK,
+            (libc::O_RDONLY | libc::O_CLOEXEC | libc::O_LARGEFILE) as libc::c_uint,
+        )
+    };
+    if fd < 0 {
+        bail!("fanotify_init failed. {}", std::io::Error::last_os_error());
+    }
+    for dir in watched_dirs {
+        if !dir.exists() {
+            continue;
+        }
+        let path = CString::new(dir.as_os_str().as_bytes())
+            .with_context(|| format!("Invalid path {:?}.", dir))?;
+        // Marks are put on the whole mounts, so the events are filtered by the paths later.
+        let res = unsafe {
+            libc::fanotify_mark(
+                fd,
+                FAN_MARK_ADD | FAN_MARK_MOUNT,
+                FAN_OPEN_EXEC,
+                libc::AT_FDCWD,
+                path.as_ptr(),
+            )
+        };
+        if res < 0 {
+            let e = std::io::Error::last_os_error();
+            let _ = nix::unistd::close(fd);
+            bail!("fanotify_mark failed on {:?}. {}", dir, e);
+        }
+    }
+    Ok(fd)
+}
+
+/// Reads the pending exec events, and returns the pids and the paths of the executed files.
+fn read_exec_events(fd: RawFd) -> Result<Vec<(i32, PathBuf)>> {
+    let mut execs = vec![];
+    let mut buf = vec![0u8; 4096];
+    loop {
+        let n = match nix::unistd::read(fd, &mut buf) {
+            Ok(n) => n,
+            Err(nix::Error::Sys(nix::errno::Errno::EAGAIN)) => return Ok(execs),
+            Err(e) => return Err(e).with_context(|| "Failed to read from fanotify."),
+        };
+        let mut offset = 0;
+        while offset + std::mem::size_of::<FanotifyEventMetadata>() <= n {
+            let metadata: FanotifyEventMetadata = unsafe {
+                std::ptr::read_unaligned(buf[offset..].as_ptr() as *const FanotifyEventMetadata)
+            };
+            if metadata.vers != FANOTIFY_METADATA_VERSION || metadata.event_len == 0 {
+                bail!("Unsupported fanotify metadata version {}.", metadata.vers);
+            }
+            offset += metadata.event_len as usize;
+            if metadata.fd < 0 {
+                continue;
+            }
+            let path = std::fs::read_link(format!("/proc/self/fd/{}", metadata.fd));
+            let _ = nix::unistd::close(metadata.fd);
+            if metadata.mask & FAN_OPEN_EXEC == 0 {
+                continue;
+            }
+            if let Ok(path) = path {
+                execs.push((metadata.pid, path));
+            }
+        }
+    }
+}
+
+/// Returns the inodes of the established TCP sockets connected to other hosts.
+fn get_outbound_socket_inodes() -> Result<HashSet<u64>> {
+    let mut entries = procfs::net::tcp().with_context(|| "Failed to read /proc/net/tcp.")?;
+    entries.extend(procfs::net::tcp6().with_context(|| "Failed to read /proc/net/tcp6.")?);
+    Ok(entries
+        .into_iter()
+        .filter(|entry| is_outbound(&entry.state, &entry.remote_address))
+        .map(|entry| u64::from(entry.inode))
+        .collect())
+}
+
+fn is_outbound(state: &TcpState, remote_address: &SocketAddr) -> bool {
+    *state == TcpState::Established && !remote_address.ip().is_loopback()
+}
+
+fn count_sockets(process: &Process, sockets: &HashSet<u64>) -> usize {
+    let fds = match process.fd() {
+        Ok(fds) => fds,
+        Err(_) => return 0,
+    };
+    fds.into_iter()
+        .filter(|fd| match fd.target {
+            FDTarget::Socket(inode) => sockets.contains(&u64::from(inode)),
+            _ => false,
+        })
+        .count()
+}
+
+#[cfg(test)]
+mod test_threat_monitor {
+    use super::*;
+
+    #[test]
+    fn test_is_unknown_binary() {
+        let watched_dirs = vec![PathBuf::from("/tmp"), PathBuf::from("/dev/shm")];
+        assert!(is_unknown_binary(Path::new("/tmp/.x/xmrig"), &watched_dirs));
+        assert!(is_unknown_binary(Path::new("/dev/shm/a"), &watched_dirs));
+        assert!(is_unknown_binary(
+            Path::new("/usr/bin/miner (deleted)"),
+            &watched_dirs
+        ));
+        assert!(!is_unknown_binary(
+            Path::new("/usr/bin/bash"),
+            &watched_dirs
+        ));
+        assert!(!is_unknown_binary(Path::new("/tmpfoo/bin"), &watched_dirs));
+    }
+
+    #[test]
+    fn test_cpu_tracker() {
+        let mut tracker = CpuTracker::default();
+        let start = Instant::now();
+        let at = |sec: u64| start + Duration::from_secs(sec);
+
+        assert_eq!(None, tracker.update(1, 0, at(0), 100.0, 80.0));
+        assert_eq!(
+            Some((100.0, Duration::from_secs(5))),
+            tracker.update(1, 500, at(5), 100.0, 80.0)
+        );
+        assert_eq!(
+            Some((90.0, Duration::from_secs(15))),
+            tracker.update(1, 1400, at(15), 100.0, 80.0)
+        );
+        // The duration is reset when the usage goes below the threshold.
+        assert_eq!(None, tracker.update(1, 1500, at(20), 100.0, 80.0));
+        assert_eq!(
+            Some((100.0, Duration::from_secs(5))),
+            tracker.update(1, 2000, at(25), 100.0, 80.0)
+        );
+
+        tracker.retain(&HashSet::new());
+        assert_eq!(None, tracker.update(1, 3000, at(30), 100.0, 80.0));
+    }
+
+    #[test]
+    fn test_is_outbound() {
+        assert!(is_outbound(
+            &TcpState::Established,
+            &"203.0.113.1:3333".parse().unwrap()
+        ));
+        assert!(!is_outbound(
+            &TcpState::Established,
+            &"127.0.0.1:3333".parse().unwrap()
+        ));
+        assert!(!is_outbound(
+            &TcpState::TimeWait,
+            &"203.0.113.1:3333".parse().unwrap()
+        ));
+    }
+}
@@ -0,0 +1,166 @@
+use anyhow::{bail, Context, Result};
+use nix::libc;
+use std::fmt;
+use std::process::{Command, Stdio};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use crate::wsl_interop;
+
+/// The RTC of the VM of WSL, which Hyper-V keeps at the time of Windows even while the VM is
+/// suspended with Windows.
+const RTC_SINCE_EPOCH_PATH: &str = "/sys/class/rtc/rtc0/since_epoch";
+
+/// The clock which the clock of the system is compared with.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ReferenceClock {
+    /// The RTC of the VM, whose resolution is a second.
+    Rtc,
+    /// The clock of Windows read by Powershell, for when the RTC can't be read.
+    Windows,
+}
+
+impl fmt::Display for ReferenceClock {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ReferenceClock::Rtc => write!(f, "RTC"),
+            ReferenceClock::Windows => write!(f, "clock of Windows"),
+        }
+    }
+}
+
+/// The NTP clients which correct the clock by themselves once they are told to.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TimeService {
+    Chrony,
+    Timesyncd,
+}
+
+impl fmt::Display for TimeService {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            TimeService::Chrony => write!(f, "chrony"),
+            TimeService::Timesyncd => write!(f, "systemd-timesyncd"),
+        }
+    }
+}
+
+const TIME_SERVICE_UNITS: &[(&str, TimeService)] = &[
+    ("chronyd.service", TimeService::Chrony),
+    ("chrony.service", TimeService::Chrony),
+    ("systemd-timesyncd.service", TimeService::Timesyncd),
+];
+
+/// How far the clock of the system is behind the reference clock.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct ClockOffset {
+    pub reference: ReferenceClock,
+    /// The seconds to add to the clock of the system. Negative if it's ahead.
+    pub seconds: f64,
+}
+
+/// Compares the clock of the system with the RTC, or with the clock of Windows if the VM has
+/// no RTC.
+pub fn get_clock_offset() -> Result<ClockOffset> {
+    match get_rtc_offset() {
+        Ok(seconds) => Ok(ClockOffset {
+            reference: ReferenceClock::Rtc,
+            seconds,
+        }),
+        Err(e) => {
+            log::debug!("Failed to read the RTC. {:?}", e);
+            Ok(ClockOffset {
+                reference: ReferenceClock::Windows,
+                seconds: get_windows_offset()
+                    .with_context(|| "Failed to read the clock of Windows.")?,
+            })
+        }
+    }
+}
+
+fn get_rtc_offset() -> Result<f64> {
+    let since_epoch = std::fs::read_to_string(RTC_SINCE_EPOCH_PATH)
+        .with_context(|| format!("Failed to read {}.", RTC_SINCE_EPOCH_PATH))?;
+    let rtc = parse_rtc_since_epoch(&since_epoch)?;
+    // The RTC truncates the time to seconds, so it's half a second behind on average.
+    Ok(rtc as f64 + 0.5 - get_unix_time()?)
+}
+
+fn parse_rtc_since_epoch(since_epoch: &str) -> Result<u64> {
+    since_epoch
+        .trim()
+        .parse()
+        .with_context(|| format!("Invalid time of the RTC: '{}'.", since_epoch.trim()))
+}
+
+fn get_windows_offset() -> Result<f64> {
+    let before = get_unix_time()?;
+    let windows = wsl_interop::get_windows_unix_time_millis()? as f64 / 1000.0;
+    let after = get_unix_time()?;
+    // Powershell takes a while to start, so compare it with the middle of the run.
+    Ok(windows - (before + after) / 2.0)
+}
+
+fn get_unix_time() -> Result<f64> {
+    Ok(SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .with_context(|| "The clock of the system is before 1970.")?
+        .as_secs_f64())
+}
+
+/// Returns the NTP client running in the distro, if any.
+pub fn find_active_time_service() -> Option<TimeService> {
+    TIME_SERVICE_UNITS
+        .iter()
+        .find(|(unit, _)| {
+            Command::new("systemctl")
+                .args(&["is-active", "--quiet", unit])
+                .stdin(Stdio::null())
+                .status()
+                .map(|status| status.success())
+                .unwrap_or(false)
+        })
+        .map(|(_, service)| *service)
+}
+
+/// Tells the NTP client to step the clock now instead of slewing it for hours.
+pub fn trigger_time_service(service: TimeService) -> Result<()> {
+    let (program, args): (&str, &[&str]) = match service {
+        TimeService::Chrony => ("chronyc", &["makestep"]),
+        // timesyncd steps the clock on the first synchronization after it starts.
+        TimeService::Timesyncd => ("systemctl", &["restart", "systemd-timesyncd.service"]),
+    };
+    let status = Command::new(program)
+        .args(args)
+        .stdin(Stdio::null())
+        .status()
+        .with_context(|| format!("Failed to run {}.", program))?;
+    if !status.success() {
+        bail!("{} exited with {}.", program, status);
+    }
+    Ok(())
+}
+
+/// Adds the seconds to the clock of the system. The clock is shared by WSL and all the distros.
+pub fn step_clock(seconds: f64) -> Result<()> {
+    let time = get_unix_time()? + seconds;
+    let timespec = libc::timespec {
+        tv_sec: time.trunc() as libc::time_t,
+        tv_nsec: (time.fract() * 1e9) as libc::c_long,
+    };
+    if unsafe { libc::clock_settime(libc::CLOCK_REALTIME, &timespec) } < 0 {
+        bail!("clock_settime failed. {}", std::io::Error::last_os_error());
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod test_time_sync {
+    use super::*;
+
+    #[test]
+    fn test_parse_rtc_since_epoch() {
+        assert_eq!(1700000000, parse_rtc_since_epoch("1700000000\n").unwrap());
+        assert!(parse_rtc_since_epoch("").is_err());
+        assert!(parse_rtc_since_epoch("-1").is_err());
+    }
+}
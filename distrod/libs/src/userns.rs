@@ -0,0 +1,318 @@
+use anyhow::{anyhow, bail, Context, Result};
+use nix::sched::CloneFlags;
+use nix::unistd::{Gid, Uid};
+use once_cell::sync::OnceCell;
+use std::fs;
+use std::io::Write;
+use std::os::unix::fs::{DirBuilderExt, MetadataExt};
+use std::path::{Path, PathBuf};
+use std::process::{Command, Stdio};
+use std::sync::atomic::{AtomicBool, Ordering};
+
+use crate::passwd::PasswdFile;
+
+/// The user who runs Distrod without the root permission. Set once the rootless mode is enabled,
+/// and kept after entering the user namespace, where the uid becomes 0.
+static ROOTLESS_OWNER: OnceCell<RootlessOwner> = OnceCell::new();
+static HAS_ENTERED_NAMESPACES: AtomicBool = AtomicBool::new(false);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct RootlessOwner {
+    pub uid: u32,
+    pub gid: u32,
+}
+
+/// A range of the ids in /etc/subuid or /etc/subgid.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct SubIdRange {
+    pub start: u32,
+    pub count: u32,
+}
+
+/// Makes Distrod keep its files in the directories of the current user instead of the system
+/// ones. Call this before anything reads the runtime files.
+pub fn enable_rootless_mode() {
+    let _ = ROOTLESS_OWNER.set(RootlessOwner {
+        uid: nix::unistd::getuid().as_raw(),
+        gid: nix::unistd::getgid().as_raw(),
+    });
+}
+
+pub fn get_rootless_owner() -> Option<RootlessOwner> {
+    ROOTLESS_OWNER.get().copied()
+}
+
+pub fn is_rootless_mode() -> bool {
+    ROOTLESS_OWNER.get().is_some()
+}
+
+/// Returns whether the files owned by the uid can be trusted as much as the ones owned by root,
+/// which is the case for the files of the user in the rootless mode.
+pub fn is_trusted_owner(uid: u32, gid: u32) -> bool {
+    match get_rootless_owner() {
+        Some(owner) => (uid == 0 && gid == 0) || (uid == owner.uid && gid == owner.gid),
+        None => uid == 0 && gid == 0,
+    }
+}
+
+/// Tells why the kernel doesn't let the current user make a user namespace, if it doesn't.
+pub fn check_userns_support() -> Result<()> {
+    // Debian and its derivatives have this knob in addition to the upstream one.
+    if let Ok(value) = fs::read_to_string("/proc/sys/kernel/unprivileged_userns_clone") {
+        if value.trim() == "0" {
+            bail!("Unprivileged user namespaces are disabled by kernel.unprivileged_userns_clone.");
+        }
+    }
+    if let Ok(value) = fs::read_to_string("/proc/sys/user/max_user_namespaces") {
+        if value.trim() == "0" {
+            bail!("User namespaces are disabled by user.max_user_namespaces.");
+        }
+    }
+    Ok(())
+}
+
+/// Moves the current process into new user and mount namespaces where the current user is root.
+/// The ranges in /etc/subuid and /etc/subgid are mapped as well by newuidmap and newgidmap if they
+/// are available, so that the distro can have other users. Otherwise, only root is mapped.
+/// This must be called while the process has only one thread. It does nothing on the second call.
+pub fn enter_rootless_namespaces() -> Result<()> {
+    if HAS_ENTERED_NAMESPACES.load(Ordering::SeqCst) {
+        return Ok(());
+    }
+    let owner =
+        get_rootless_owner().ok_or_else(|| anyhow!("[BUG] The rootless mode is not enabled."))?;
+    check_userns_support()?;
+    let user_name = get_user_name(owner.uid);
+    let sub_ranges = user_name.as_deref().and_then(|name| {
+        let subuid = read_subid_range(Path::new("/etc/subuid"), name, owner.uid).ok()??;
+        let subgid = read_subid_range(Path::new("/etc/subgid"), name, owner.uid).ok()??;
+        Some((subuid, subgid))
+    });
+
+    // newuidmap is a setuid program, which doesn't work from inside the new namespace. So start
+    // it beforehand, and let it write the maps once the namespace has been made.
+    let mut mapper = match sub_ranges {
+        Some((subuid, subgid)) if has_command("newuidmap") && has_command("newgidmap") => {
+            Some(spawn_id_mapper(owner, subuid, subgid)?)
+        }
+        _ => None,
+    };
+
+    nix::sched::unshare(CloneFlags::CLONE_NEWUSER | CloneFlags::CLONE_NEWNS).with_context(
+        || "Failed to make a user namespace. The kernel may not allow it for a non-root user.",
+    )?;
+
+    let mapped_by_helper = match mapper.take() {
+        Some(mut mapper) => {
+            // The helper starts mapping when its stdin is closed.
+            drop(mapper.stdin.take());
+            match mapper.wait() {
+                Ok(status) if status.success() => true,
+                Ok(status) => {
+                    log::warn!(
+                        "newuidmap or newgidmap exited with {}. Mapping only root.",
+                        status
+                    );
+                    false
+                }
+                Err(e) => {
+                    log::warn!("Failed to wait for newuidmap. Mapping only root. {:?}", e);
+                    false
+                }
+            }
+        }
+        None => false,
+    };
+    if !mapped_by_helper {
+        // An unprivileged process can map only its own ids, and only after denying setgroups.
+        write_proc_self("setgroups", "deny")?;
+        write_proc_self("uid_map", &format!("0 {} 1", owner.uid))?;
+        write_proc_self("gid_map", &format!("0 {} 1", owner.gid))?;
+    }
+    let root = (Uid::from_raw(0), Gid::from_raw(0));
+    nix::unistd::setresuid(root.0, root.0, root.0)
+        .with_context(|| "Failed to become root in the user namespace.")?;
+    nix::unistd::setresgid(root.1, root.1, root.1)
+        .with_context(|| "Failed to become root in the user namespace.")?;
+    HAS_ENTERED_NAMESPACES.store(true, Ordering::SeqCst);
+    Ok(())
+}
+
+/// The directory of the runtime files in the rootless mode, such as /run/user/1000/distrod. It's
+/// created if missing, and must be a directory only the user can access, since /tmp/distrod-<uid>
+/// can be made by anyone beforehand to take the sockets in it.
+pub fn get_rootless_runtime_dir(owner: &RootlessOwner) -> Result<PathBuf> {
+    let user_runtime_dir = std::env::var_os("XDG_RUNTIME_DIR")
+        .map(PathBuf::from)
+        .filter(|dir| dir.is_absolute() && dir.is_dir())
+        .unwrap_or_else(|| PathBuf::from(format!("/run/user/{}", owner.uid)));
+    let path = if user_runtime_dir.is_dir() {
+        user_runtime_dir.join("distrod")
+    } else {
+        PathBuf::from(format!("/tmp/distrod-{}", owner.uid))
+    };
+    create_private_dir(&path)?;
+    Ok(path)
+}
+
+/// Creates the directory with the mode 0700 if it's missing, and checks that it's a directory
+/// owned by the current user, which others can't access. The user is root in the user namespace,
+/// where the files of the owner are owned by root.
+fn create_private_dir(path: &Path) -> Result<()> {
+    match fs::DirBuilder::new().mode(0o700).create(path) {
+        Ok(_) => {}
+        Err(e) if e.kind() == std::io::ErrorKind::AlreadyExists => {}
+        Err(e) => return Err(e).with_context(|| format!("Failed to create {:?}.", path)),
+    }
+    let metadata =
+        fs::symlink_metadata(path).with_context(|| format!("Failed to stat {:?}.", path))?;
+    if !metadata.file_type().is_dir() {
+        bail!("{:?} is not a directory.", path);
+    }
+    if metadata.uid() != nix::unistd::geteuid().as_raw() {
+        bail!("{:?} is owned by another user.", path);
+    }
+    if metadata.mode() & 0o077 != 0 {
+        bail!(
+            "{:?} can be accessed by other users. Its mode is {:o}.",
+            path,
+            metadata.mode() & 0o7777
+        );
+    }
+    Ok(())
+}
+
+/// The directory where `distrod create` installs the distros in the rootless mode.
+pub fn get_rootless_distro_images_dir() -> Result<PathBuf> {
+    if let Some(data_home) = std::env::var_os("XDG_DATA_HOME") {
+        return Ok(PathBuf::from(data_home).join("distrod/distros"));
+    }
+    let home = std::env::var_os("HOME")
+        .ok_or_else(|| anyhow!("HOME is not set. Failed to find the directory of the distros."))?;
+    Ok(PathBuf::from(home).join(".local/share/distrod/distros"))
+}
+
+/// Reads the range of the sub ids given to the user, whose lines are "name:start:count" or
+/// "uid:start:count".
+pub fn read_subid_range(path: &Path, user_name: &str, uid: u32) -> Result<Option<SubIdRange>> {
+    if !path.exists() {
+        return Ok(None);
+    }
+    let cont = fs::read_to_string(path).with_context(|| format!("Failed to read {:?}.", path))?;
+    Ok(parse_subid_range(&cont, user_name, uid))
+}
+
+fn parse_subid_range(cont: &str, user_name: &str, uid: u32) -> Option<SubIdRange> {
+    let uid = uid.to_string();
+    cont.lines().find_map(|line| {
+        let fields: Vec<&str> = line.trim().split(':').collect();
+        if fields.len() != 3 || (fields[0] != user_name && fields[0] != uid) {
+            return None;
+        }
+        Some(SubIdRange {
+            start: fields[1].parse().ok()?,
+            count: fields[2].parse().ok()?,
+        })
+    })
+}
+
+fn spawn_id_mapper(
+    owner: RootlessOwner,
+    subuid: SubIdRange,
+    subgid: SubIdRange,
+) -> Result<std::process::Child> {
+    let pid = nix::unistd::getpid().as_raw().to_string();
+    // Map root to the user, and 1.. to the sub ids.
+    let script =
+        r#"read -r _; newuidmap "$1" 0 "$2" 1 1 "$3" "$4" && newgidmap "$1" 0 "$5" 1 1 "$6" "$7""#;
+    Command::new("/bin/sh")
+        .arg("-c")
+        .arg(script)
+        .arg("distrod-id-mapper")
+        .args(&[
+            pid,
+            owner.uid.to_string(),
+            subuid.start.to_string(),
+            subuid.count.to_string(),
+            owner.gid.to_string(),
+            subgid.start.to_string(),
+            subgid.count.to_string(),
+        ])
+        .stdin(Stdio::piped())
+        .spawn()
+        .with_context(|| "Failed to spawn newuidmap.")
+}
+
+fn get_user_name(uid: u32) -> Option<String> {
+    let mut passwd_file = PasswdFile::open("/etc/passwd").ok()?;
+    let entry = passwd_file.get_ent_by_uid(uid).ok()??;
+    Some(entry.name.to_owned())
+}
+
+fn has_command(name: &str) -> bool {
+    ["/usr/bin", "/bin", "/usr/sbin", "/sbin"]
+        .iter()
+        .any(|dir| Path::new(dir).join(name).exists())
+}
+
+fn write_proc_self(name: &str, value: &str) -> Result<()> {
+    let path = Path::new("/proc/self").join(name);
+    let mut file = fs::OpenOptions::new()
+        .write(true)
+        .open(&path)
+        .with_context(|| format!("Failed to open {:?}.", &path))?;
+    file.write_all(value.as_bytes())
+        .with_context(|| format!("Failed to write '{}' to {:?}.", value, &path))
+}
+
+#[cfg(test)]
+mod test_subid {
+    use super::*;
+
+    #[test]
+    fn test_parse_subid_range() {
+        let cont = "alice:100000:65536\n1001:165536:65536\nbroken line\n";
+        assert_eq!(
+            Some(SubIdRange {
+                start: 100000,
+                count: 65536
+            }),
+            parse_subid_range(cont, "alice", 1000)
+        );
+        assert_eq!(
+            Some(SubIdRange {
+                start: 165536,
+                count: 65536
+            }),
+            parse_subid_range(cont, "bob", 1001)
+        );
+        assert_eq!(None, parse_subid_range(cont, "carol", 1002));
+    }
+}
+
+#[cfg(test)]
+mod test_runtime_dir {
+    use super::*;
+    use std::os::unix::fs::PermissionsExt;
+
+    #[test]
+    fn test_create_private_dir() {
+        let tempdir = tempfile::tempdir().unwrap();
+        let path = tempdir.path().join("distrod-1000");
+        create_private_dir(&path).unwrap();
+        assert_eq!(0o700, fs::metadata(&path).unwrap().mode() & 0o7777);
+        // The existing one is kept.
+        create_private_dir(&path).unwrap();
+
+        fs::set_permissions(&path, fs::Permissions::from_mode(0o777)).unwrap();
+        assert!(create_private_dir(&path).is_err());
+
+        let link = tempdir.path().join("link");
+        std::os::unix::fs::symlink(&path, &link).unwrap();
+        assert!(create_private_dir(&link).is_err());
+
+        let file = tempdir.path().join("file");
+        fs::write(&file, "").unwrap();
+        assert!(create_private_dir(&file).is_err());
+    }
+}
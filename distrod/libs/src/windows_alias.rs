@@ -0,0 +1,218 @@
+use anyhow::{bail, Context, Result};
+use serde::{Deserialize, Serialize};
+
+use crate::terminal_profile::quote_windows_arg;
+
+/// The comment on the second line of a shim, which tells the shims Distrod made from the other
+/// files in the alias directory, followed by the alias in JSON.
+const SHIM_MARKER: &str = ":: distrod-alias ";
+
+/// A command of Windows, such as `code.cmd`, which runs a Linux program in a distro by
+/// `distrod exec`, so that PowerShell and the shortcuts of Windows can start it.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub struct WindowsAlias {
+    /// The name of the command on Windows, without `.cmd`.
+    #[serde(skip)]
+    pub name: String,
+    /// The WSL distro which Distrod is installed in.
+    pub wsl_distro: String,
+    /// The distro of `distrod create` to run the program in. None means the default one.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub distro: Option<String>,
+    /// The user to run the program as. None means root.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub user: Option<String>,
+    /// The program, which is looked up in the PATH of the user if it's not absolute.
+    pub command: String,
+    /// The arguments given before the ones of the shim.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub args: Vec<String>,
+    /// Don't keep the console of the shim open, for the programs with windows, such as the ones
+    /// shown by WSLg.
+    #[serde(default)]
+    pub gui: bool,
+}
+
+impl WindowsAlias {
+    /// Returns the content of the `.cmd` file. The shim passes its working directory and
+    /// arguments to the program.
+    pub fn to_cmd_script(&self, distrod_bin_path: &str) -> Result<String> {
+        let metadata = serde_json::to_string(self)
+            .with_context(|| format!("Failed to serialize the alias '{}'.", &self.name))?;
+        let mut args = vec![
+            "wsl.exe",
+            "-d",
+            self.wsl_distro.as_str(),
+            "-u",
+            "root",
+            "-e",
+            distrod_bin_path,
+            "exec",
+        ];
+        if let Some(ref distro) = self.distro {
+            args.extend(&["--distro", distro.as_str()]);
+        }
+        if let Some(ref user) = self.user {
+            args.extend(&["--user", user.as_str()]);
+        }
+        args.push("--working-directory");
+        let mut commandline = args
+            .iter()
+            .map(|arg| escape_cmd_arg(&quote_windows_arg(arg)))
+            .collect::<Vec<_>>();
+        // exec translates the Windows path of the working directory.
+        commandline.push("\"%CD%\"".to_owned());
+        commandline.push("--".to_owned());
+        commandline.extend(
+            std::iter::once(&self.command)
+                .chain(self.args.iter())
+                .map(|arg| escape_cmd_arg(&quote_windows_arg(arg))),
+        );
+        commandline.push("%*".to_owned());
+        let launch = if self.gui {
+            format!("start \"\" /min {}", commandline.join(" "))
+        } else {
+            commandline.join(" ")
+        };
+        Ok(format!(
+            "@echo off\r\n{}{}\r\n{}\r\n",
+            SHIM_MARKER, metadata, launch
+        ))
+    }
+
+    /// Reads the alias back from a shim. None is returned if Distrod didn't make the file.
+    pub fn from_cmd_script(name: &str, script: &str) -> Result<Option<WindowsAlias>> {
+        let metadata = match script
+            .lines()
+            .find_map(|line| line.trim_end().strip_prefix(SHIM_MARKER))
+        {
+            Some(metadata) => metadata,
+            None => return Ok(None),
+        };
+        let mut alias: WindowsAlias = serde_json::from_str(metadata)
+            .with_context(|| format!("The shim of the alias '{}' is broken.", name))?;
+        alias.name = name.to_owned();
+        Ok(Some(alias))
+    }
+}
+
+/// Fails if `name` can't be the name of a command of Windows.
+pub fn validate_alias_name(name: &str) -> Result<()> {
+    if name.is_empty() || name.starts_with('.') {
+        bail!("An alias name must not be empty or start with a dot.");
+    }
+    if !name
+        .chars()
+        .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_' || c == '.')
+    {
+        bail!(
+            "An alias name must consist of alphanumerics, '-', '_', and '.': '{}'.",
+            name
+        );
+    }
+    Ok(())
+}
+
+/// Escapes a quoted argument for a line of a batch file, so that cmd passes it to the program as
+/// it is. The metacharacters of cmd, including the quotes, are escaped by `^`, so that cmd sees
+/// no quoted part where they would be taken as they are. `%` starts a variable even in quotes,
+/// and is doubled instead.
+fn escape_cmd_arg(arg: &str) -> String {
+    let mut escaped = String::with_capacity(arg.len());
+    for c in arg.chars() {
+        match c {
+            '%' => escaped.push_str("%%"),
+            '^' | '&' | '|' | '<' | '>' | '(' | ')' | '"' => {
+                escaped.push('^');
+                escaped.push(c);
+            }
+            _ => escaped.push(c),
+        }
+    }
+    escaped
+}
+
+#[cfg(test)]
+mod test_windows_alias {
+    use super::*;
+
+    #[test]
+    fn test_cmd_script() {
+        let alias = WindowsAlias {
+            name: "code".to_owned(),
+            wsl_distro: "Distrod".to_owned(),
+            distro: None,
+            user: Some("alice".to_owned()),
+            command: "code".to_owned(),
+            args: vec!["--profile".to_owned(), "100%".to_owned()],
+            gui: false,
+        };
+        let script = alias.to_cmd_script("/opt/distrod/bin/distrod").unwrap();
+        assert_eq!(
+            "wsl.exe -d Distrod -u root -e /opt/distrod/bin/distrod exec --user alice \
+             --working-directory \"%CD%\" -- code --profile 100%% %*",
+            script.lines().nth(2).unwrap()
+        );
+        assert_eq!(
+            Some(alias),
+            WindowsAlias::from_cmd_script("code", &script).unwrap()
+        );
+        assert_eq!(
+            None,
+            WindowsAlias::from_cmd_script("other", "@echo off\r\nnotepad.exe\r\n").unwrap()
+        );
+    }
+
+    #[test]
+    fn test_gui_cmd_script() {
+        let alias = WindowsAlias {
+            name: "gedit".to_owned(),
+            wsl_distro: "My Distro".to_owned(),
+            distro: Some("fedora".to_owned()),
+            user: None,
+            command: "/usr/bin/gedit".to_owned(),
+            args: vec![],
+            gui: true,
+        };
+        let script = alias.to_cmd_script("/opt/distrod/bin/distrod").unwrap();
+        assert_eq!(
+            "start \"\" /min wsl.exe -d ^\"My Distro^\" -u root -e /opt/distrod/bin/distrod exec \
+             --distro fedora --working-directory \"%CD%\" -- /usr/bin/gedit %*",
+            script.lines().nth(2).unwrap()
+        );
+    }
+
+    #[test]
+    fn test_cmd_script_metacharacters() {
+        let alias = WindowsAlias {
+            name: "run".to_owned(),
+            wsl_distro: "Distrod".to_owned(),
+            distro: None,
+            user: None,
+            command: "sh".to_owned(),
+            args: vec![
+                "-c".to_owned(),
+                "echo a & calc.exe | more > out (x) ^ \"q\"".to_owned(),
+            ],
+            gui: false,
+        };
+        let script = alias.to_cmd_script("/opt/distrod/bin/distrod").unwrap();
+        assert_eq!(
+            "wsl.exe -d Distrod -u root -e /opt/distrod/bin/distrod exec \
+             --working-directory \"%CD%\" -- sh -c \
+             ^\"echo a ^& calc.exe ^| more ^> out ^(x^) ^^ \\^\"q\\^\"^\" %*",
+            script.lines().nth(2).unwrap()
+        );
+    }
+
+    #[test]
+    fn test_validate_alias_name() {
+        assert!(validate_alias_name("code").is_ok());
+        assert!(validate_alias_name("python3.11").is_ok());
+        assert!(validate_alias_name("").is_err());
+        assert!(validate_alias_name(".hidden").is_err());
+        assert!(validate_alias_name("a b").is_err());
+        assert!(validate_alias_name("..\\evil").is_err());
+    }
+}
@@ -0,0 +1,382 @@
+use anyhow::{bail, Context, Result};
+use std::os::unix::fs::PermissionsExt;
+use std::path::{Path, PathBuf};
+
+/// The wsl.conf which WSL reads when it starts.
+pub const WSL_CONF_PATH: &str = "/etc/wsl.conf";
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum ValueKind {
+    Bool,
+    String,
+}
+
+/// The keys WSL reads from wsl.conf. WSL ignores the others silently.
+const KNOWN_KEYS: &[(&str, &str, ValueKind)] = &[
+    ("automount", "enabled", ValueKind::Bool),
+    ("automount", "mountFsTab", ValueKind::Bool),
+    ("automount", "root", ValueKind::String),
+    ("automount", "options", ValueKind::String),
+    ("network", "generateHosts", ValueKind::Bool),
+    ("network", "generateResolvConf", ValueKind::Bool),
+    ("network", "hostname", ValueKind::String),
+    ("interop", "enabled", ValueKind::Bool),
+    ("interop", "appendWindowsPath", ValueKind::Bool),
+    ("user", "default", ValueKind::String),
+    ("boot", "systemd", ValueKind::Bool),
+    ("boot", "command", ValueKind::String),
+    ("gpu", "enabled", ValueKind::Bool),
+    ("time", "useWindowsTimezone", ValueKind::Bool),
+];
+
+/// Whether WSL reads the key.
+pub fn is_known_key(section: &str, key: &str) -> bool {
+    find_known_key(section, key).is_some()
+}
+
+/// Checks the value is one WSL accepts for the key, such as "true" or "false" for a boolean key.
+/// Any value is accepted for an unknown key.
+pub fn validate_value(section: &str, key: &str, value: &str) -> Result<()> {
+    if find_known_key(section, key) == Some(ValueKind::Bool) && value != "true" && value != "false"
+    {
+        bail!(
+            "{}.{} must be true or false, but it's '{}'.",
+            section,
+            key,
+            value
+        );
+    }
+    if value.contains('\n') {
+        bail!("A value of wsl.conf can't contain a newline.");
+    }
+    Ok(())
+}
+
+/// Checks the section and the key can be written to wsl.conf and read back, whether WSL knows
+/// them or not. They can't contain the characters which make a section header, a key-value
+/// separator, a comment, or a new line.
+pub fn validate_key(section: &str, key: &str) -> Result<()> {
+    for name in &[section, key] {
+        if name.trim() != *name || name.chars().any(|c| c.is_control() || "=[]#;".contains(c)) {
+            bail!(
+                "'{}.{}' can't be a key of wsl.conf. A section or a key can't contain '=', '[', \
+                 ']', '#', ';', or a control character, or start or end with a space.",
+                section,
+                key
+            );
+        }
+    }
+    Ok(())
+}
+
+fn find_known_key(section: &str, key: &str) -> Option<ValueKind> {
+    KNOWN_KEYS
+        .iter()
+        .find(|(s, k, _)| *s == section && *k == key)
+        .map(|(_, _, kind)| *kind)
+}
+
+/// /etc/wsl.conf, which is an INI file. Only the given keys are modified and the other lines,
+/// including comments, are kept as they are.
+pub struct WslConf {
+    path: PathBuf,
+    lines: Vec<String>,
+}
+
+impl WslConf {
+    pub fn open<P: AsRef<Path>>(path: P) -> Result<WslConf> {
+        let path = path.as_ref();
+        let lines = if path.exists() {
+            std::fs::read_to_string(path)
+                .with_context(|| format!("Failed to read {:?}.", path))?
+                .lines()
+                .map(|line| line.to_owned())
+                .collect()
+        } else {
+            vec![]
+        };
+        Ok(WslConf {
+            path: path.to_owned(),
+            lines,
+        })
+    }
+
+    pub fn get(&self, section: &str, key: &str) -> Option<String> {
+        let (start, end) = self.find_section(section)?;
+        self.lines[start..end]
+            .iter()
+            .find_map(|line| parse_key_value(line).filter(|(k, _)| *k == key))
+            .map(|(_, value)| value.to_owned())
+    }
+
+    pub fn set(&mut self, section: &str, key: &str, value: &str) {
+        let new_line = format!("{} = {}", key, value);
+        let (start, end) = match self.find_section(section) {
+            Some(range) => range,
+            None => {
+                if self
+                    .lines
+                    .last()
+                    .map_or(false, |line| !line.trim().is_empty())
+                {
+                    self.lines.push(String::new());
+                }
+                self.lines.push(format!("[{}]", section));
+                self.lines.push(new_line);
+                return;
+            }
+        };
+        for line in &mut self.lines[start..end] {
+            if parse_key_value(line).map_or(false, |(k, _)| k == key) {
+                *line = new_line;
+                return;
+            }
+        }
+        // Insert after the last non-empty line of the section, not after the blank lines
+        // separating it from the next section.
+        let mut insert_at = end;
+        while insert_at > start && self.lines[insert_at - 1].trim().is_empty() {
+            insert_at -= 1;
+        }
+        self.lines.insert(insert_at, new_line);
+    }
+
+    /// Removes the key from the section. Returns false if it isn't there.
+    pub fn unset(&mut self, section: &str, key: &str) -> bool {
+        let (start, end) = match self.find_section(section) {
+            Some(range) => range,
+            None => return false,
+        };
+        let matched: Vec<_> = (start..end)
+            .filter(|&i| parse_key_value(&self.lines[i]).map_or(false, |(k, _)| k == key))
+            .collect();
+        for &i in matched.iter().rev() {
+            self.lines.remove(i);
+        }
+        !matched.is_empty()
+    }
+
+    /// Returns the problems which make WSL ignore a line or fail to start, with the line
+    /// numbers, such as a key outside of the sections or a boolean value which is not
+    /// "true" or "false".
+    pub fn find_problems(&self) -> Vec<String> {
+        let mut problems = vec![];
+        let mut section = None;
+        let mut seen_keys = vec![];
+        for (i, line) in self.lines.iter().enumerate() {
+            let line_number = i + 1;
+            let trimmed = line.trim();
+            if trimmed.is_empty() || trimmed.starts_with('#') || trimmed.starts_with(';') {
+                continue;
+            }
+            if let Some(header) = parse_section_header(line) {
+                if !KNOWN_KEYS.iter().any(|(s, _, _)| *s == header) {
+                    problems.push(format!(
+                        "line {}: unknown section [{}].",
+                        line_number, header
+                    ));
+                }
+                section = Some(header);
+                continue;
+            }
+            let (key, value) = match parse_key_value(line) {
+                Some(key_value) => key_value,
+                None => {
+                    problems.push(format!(
+                        "line {}: neither a section nor `key = value`: {}",
+                        line_number, trimmed
+                    ));
+                    continue;
+                }
+            };
+            let section = match section {
+                Some(section) => section,
+                None => {
+                    problems.push(format!(
+                        "line {}: {} is outside of the sections.",
+                        line_number, key
+                    ));
+                    continue;
+                }
+            };
+            if seen_keys.contains(&(section, key)) {
+                problems.push(format!(
+                    "line {}: {}.{} is set again.",
+                    line_number, section, key
+                ));
+            }
+            seen_keys.push((section, key));
+            if KNOWN_KEYS.iter().any(|(s, _, _)| *s == section) && !is_known_key(section, key) {
+                problems.push(format!(
+                    "line {}: unknown key {}.{}.",
+                    line_number, section, key
+                ));
+            }
+            if let Err(e) = validate_value(section, key, value) {
+                problems.push(format!("line {}: {}", line_number, e));
+            }
+        }
+        problems
+    }
+
+    /// Writes the file by replacing it with a new one, so that WSL never reads a half-written
+    /// wsl.conf.
+    pub fn write(&self) -> Result<()> {
+        let mut cont = self.lines.join("\n");
+        cont.push('\n');
+        let file_name = self
+            .path
+            .file_name()
+            .map_or_else(|| "wsl.conf".into(), |name| name.to_string_lossy());
+        let tmp_path = self
+            .path
+            .with_file_name(format!(".{}.distrod-tmp", file_name));
+        std::fs::write(&tmp_path, cont)
+            .with_context(|| format!("Failed to write {:?}.", &tmp_path))?;
+        std::fs::set_permissions(&tmp_path, std::fs::Permissions::from_mode(0o644))
+            .with_context(|| format!("Failed to set the permission of {:?}.", &tmp_path))?;
+        std::fs::rename(&tmp_path, &self.path)
+            .with_context(|| format!("Failed to replace {:?}.", &self.path))
+    }
+
+    /// Returns the range of the lines in the section, excluding the section header.
+    fn find_section(&self, section: &str) -> Option<(usize, usize)> {
+        let header = self
+            .lines
+            .iter()
+            .position(|line| parse_section_header(line) == Some(section))?;
+        let end = self.lines[header + 1..]
+            .iter()
+            .position(|line| parse_section_header(line).is_some())
+            .map_or(self.lines.len(), |pos| header + 1 + pos);
+        Some((header + 1, end))
+    }
+}
+
+fn parse_section_header(line: &str) -> Option<&str> {
+    let line = line.trim();
+    if line.starts_with('[') && line.ends_with(']') {
+        return Some(line[1..line.len() - 1].trim());
+    }
+    None
+}
+
+fn parse_key_value(line: &str) -> Option<(&str, &str)> {
+    let line = line.trim();
+    if line.starts_with('#') || line.starts_with(';') {
+        return None;
+    }
+    let mut key_value = line.splitn(2, '=');
+    let key = key_value.next()?.trim();
+    let value = key_value.next()?.trim();
+    Some((key, value))
+}
+
+#[cfg(test)]
+mod test_wsl_conf {
+    use super::*;
+    use tempfile::TempDir;
+
+    #[test]
+    fn test_set_keeps_other_lines() {
+        let tmpdir = TempDir::new().unwrap();
+        let path = tmpdir.path().join("wsl.conf");
+        std::fs::write(
+            &path,
+            "# my settings\n\
+             [automount]\n\
+             enabled = true\n\
+             \n\
+             [boot]\n\
+             command = service ssh start\n\
+             \n\
+             [network]\n\
+             hostname = foo\n",
+        )
+        .unwrap();
+
+        let mut wsl_conf = WslConf::open(&path).unwrap();
+        assert_eq!(Some("foo".to_owned()), wsl_conf.get("network", "hostname"));
+        assert_eq!(None, wsl_conf.get("boot", "systemd"));
+        wsl_conf.set("boot", "systemd", "true");
+        wsl_conf.set("network", "hostname", "bar");
+        wsl_conf.write().unwrap();
+
+        assert_eq!(
+            "# my settings\n\
+             [automount]\n\
+             enabled = true\n\
+             \n\
+             [boot]\n\
+             command = service ssh start\n\
+             systemd = true\n\
+             \n\
+             [network]\n\
+             hostname = bar\n",
+            std::fs::read_to_string(&path).unwrap()
+        );
+    }
+
+    #[test]
+    fn test_unset_and_find_problems() {
+        let tmpdir = TempDir::new().unwrap();
+        let path = tmpdir.path().join("wsl.conf");
+        std::fs::write(
+            &path,
+            "default = root\n\
+             [boot]\n\
+             systemd = yes\n\
+             command = service ssh start\n\
+             systemd = true\n\
+             [interop]\n\
+             appendWindowsPaths = false\n\
+             oops\n",
+        )
+        .unwrap();
+
+        let mut wsl_conf = WslConf::open(&path).unwrap();
+        assert_eq!(
+            vec![
+                "line 1: default is outside of the sections.",
+                "line 3: boot.systemd must be true or false, but it's 'yes'.",
+                "line 5: boot.systemd is set again.",
+                "line 7: unknown key interop.appendWindowsPaths.",
+                "line 8: neither a section nor `key = value`: oops",
+            ],
+            wsl_conf.find_problems()
+        );
+
+        assert!(wsl_conf.unset("boot", "systemd"));
+        assert!(!wsl_conf.unset("boot", "systemd"));
+        assert!(!wsl_conf.unset("gpu", "enabled"));
+        assert_eq!(
+            Some("service ssh start".to_owned()),
+            wsl_conf.get("boot", "command")
+        );
+        assert_eq!(None, wsl_conf.get("boot", "systemd"));
+    }
+
+    #[test]
+    fn test_validate_key() {
+        assert!(validate_key("boot", "systemd").is_ok());
+        assert!(validate_key("custom.section", "someKey").is_ok());
+        assert!(validate_key("boot", "systemd=true").is_err());
+        assert!(validate_key("boot]\n[network", "hostname").is_err());
+        assert!(validate_key("boot", "command\nsystemd").is_err());
+        assert!(validate_key("boot", "#systemd").is_err());
+        assert!(validate_key("boot", " systemd").is_err());
+    }
+
+    #[test]
+    fn test_set_creates_new_file() {
+        let tmpdir = TempDir::new().unwrap();
+        let path = tmpdir.path().join("wsl.conf");
+        let mut wsl_conf = WslConf::open(&path).unwrap();
+        wsl_conf.set("boot", "systemd", "true");
+        wsl_conf.write().unwrap();
+        assert_eq!(
+            "[boot]\nsystemd = true\n",
+            std::fs::read_to_string(&path).unwrap()
+        );
+    }
+}
@@ -2,37 +2,83 @@ use std::{
     collections::{HashMap, HashSet},
     ffi::OsString,
     iter::FromIterator,
-    path::PathBuf,
+    net::IpAddr,
+    path::{Path, PathBuf},
+    process::Command,
 };
 
 use anyhow::{anyhow, bail, Context, Result};
 use procfs::process;
 
-use crate::{envfile::PathVariable, mount_info::get_mount_entries};
+use crate::{
+    envfile::PathVariable,
+    mount_info::{get_mount_entries, MountEntry},
+    wslenv,
+};
 
 pub fn get_wsl_drive_path(drive_letter: &str) -> Result<Option<PathBuf>> {
     let entries = get_mount_entries().with_context(|| "Failed to get the mount entries.")?;
     Ok(entries.into_iter().find_map(|e| {
-        if e.fstype != "9p" {
+        let letter = get_drive_letter(&e)?;
+        if !drive_letter.eq_ignore_ascii_case(&letter.to_string()) {
             return None;
         }
-        // Windows 10
-        if e.source
-            .starts_with(&format!("{}:\\", drive_letter.to_uppercase()))
-        {
-            return Some(e.path);
-        }
-        // Windows 11
-        if e.source == "drvfs"
-            && e.attributes
-                .contains(&format!("path={}:\\", drive_letter.to_uppercase()))
-        {
-            return Some(e.path);
-        }
-        None
+        Some(e.path)
     }))
 }
 
+/// Returns the upper case letter of the Windows drive if the entry is a drive mounted by WSL.
+pub fn get_drive_letter(e: &MountEntry) -> Option<char> {
+    if e.fstype != "9p" {
+        return None;
+    }
+    let path = if e.source == "drvfs" {
+        // Windows 11
+        let start = e.attributes.find("path=")? + "path=".len();
+        &e.attributes[start..]
+    } else {
+        // Windows 10
+        e.source.as_str()
+    };
+    let mut chars = path.chars();
+    let letter = chars.next().filter(|c| c.is_ascii_alphabetic())?;
+    if chars.next() != Some(':') || chars.next() != Some('\\') {
+        return None;
+    }
+    Some(letter.to_ascii_uppercase())
+}
+
+/// Translates a Windows path such as `C:\src\proj` into the path where it's mounted by WSL,
+/// such as /mnt/c/src/proj. None is returned if the path is not a Windows path.
+pub fn windows_path_to_wsl_path(path: &str) -> Result<Option<PathBuf>> {
+    let (drive_letter, components) = match split_windows_path(path) {
+        Some(split) => split,
+        None => return Ok(None),
+    };
+    let mut wsl_path = get_wsl_drive_path(&drive_letter.to_string())?
+        .ok_or_else(|| anyhow!("{}: drive is not mounted by WSL.", drive_letter))?;
+    wsl_path.extend(components);
+    Ok(Some(wsl_path))
+}
+
+/// Splits a Windows path with a drive letter into the letter and the components after it.
+fn split_windows_path(path: &str) -> Option<(char, Vec<&str>)> {
+    let mut chars = path.chars();
+    let drive_letter = chars.next().filter(|c| c.is_ascii_alphabetic())?;
+    if chars.next() != Some(':') {
+        return None;
+    }
+    let rest = chars.as_str();
+    if !rest.is_empty() && !rest.starts_with(|c| c == '\\' || c == '/') {
+        return None;
+    }
+    let components = rest
+        .split(|c| c == '\\' || c == '/')
+        .filter(|component| !component.is_empty() && *component != ".")
+        .collect();
+    Some((drive_letter, components))
+}
+
 fn get_wsl_drive_mount_point() -> Result<Option<PathBuf>> {
     let c_drive = get_wsl_drive_path("c")
         .with_context(|| "Failed to get the path where C drive is mounted.")?;
@@ -47,6 +93,44 @@ fn get_wsl_drive_mount_point() -> Result<Option<PathBuf>> {
     ))
 }
 
+/// Converts a path of WSL to the one in the Windows format by wslpath, such as
+/// \\wsl$\Ubuntu\opt\distrod\var, for the processes run on Windows.
+pub fn wsl_path_to_windows_path(path: &Path) -> Result<String> {
+    let output = std::process::Command::new("/bin/wslpath")
+        .args(&["-w".as_ref(), path.as_os_str()])
+        .output()
+        .with_context(|| format!("Failed to execute wslpath -w {:?}", path))?;
+    if !output.status.success() {
+        bail!(
+            "wslpath -w {:?} exited with error. stderr: {}",
+            path,
+            String::from_utf8_lossy(&output.stderr)
+        );
+    }
+    Ok(String::from_utf8_lossy(&output.stdout).trim().to_owned())
+}
+
+/// Returns an environment variable of Windows, such as LOCALAPPDATA, which WSL doesn't pass
+/// unless WSLENV has it.
+pub fn get_windows_env_var(name: &str) -> Result<String> {
+    let c = get_wsl_drive_path("C")?.ok_or_else(|| anyhow!("C drive not found."))?;
+    // cmd.exe refuses a working directory under \\wsl$.
+    let output = Command::new(c.join("Windows/System32/cmd.exe"))
+        .args(&["/C", &format!("echo %{}%", name)])
+        .current_dir(&c)
+        .output()
+        .with_context(|| "Failed to execute cmd.exe.")?;
+    if !output.status.success() {
+        bail!("cmd.exe failed. {}", output.status);
+    }
+    let value = String::from_utf8_lossy(&output.stdout).trim().to_owned();
+    // cmd.exe echoes the name as is if the variable is not defined.
+    if value.is_empty() || value == format!("%{}%", name) {
+        bail!("%{}% is not set on Windows.", name);
+    }
+    Ok(value)
+}
+
 pub fn get_distro_name() -> Result<String> {
     let envs = collect_wsl_env_vars().with_context(|| "Failed to collect wsl envs.")?;
     Ok(envs
@@ -100,6 +184,29 @@ pub fn collect_wsl_env_vars() -> Result<HashMap<OsString, OsString>> {
     bail!("Couldn't find WSL envs");
 }
 
+/// Returns the variables which WSL has passed from Windows by WSLENV, such as the ones
+/// `distrod env share` has added.
+pub fn collect_wslenv_shared_vars() -> Vec<(String, String)> {
+    let wslenv = match std::env::var("WSLENV") {
+        Ok(wslenv) => wslenv,
+        Err(_) => return vec![],
+    };
+    let wsl_env_names = get_wsl_interop_env_names();
+    wslenv::parse_wslenv(&wslenv)
+        .into_iter()
+        .filter(|entry| entry.is_passed_to_wsl())
+        // PATH is set up from the WSL paths separately.
+        .filter(|entry| {
+            entry.name != "PATH" && !wsl_env_names.contains(&OsString::from(&entry.name))
+        })
+        .filter_map(|entry| {
+            std::env::var(&entry.name)
+                .ok()
+                .map(|value| (entry.name, value))
+        })
+        .collect()
+}
+
 fn get_wsl_interop_env_names() -> Vec<OsString> {
     ["WSL_INTEROP", "WSLENV", "WSL_DISTRO_NAME"]
         .iter()
@@ -125,3 +232,258 @@ pub fn collect_wsl_paths() -> Result<Vec<String>> {
         .collect();
     Ok(wsl_paths)
 }
+
+/// The directory which WSLg shares its sockets of X11, Wayland, and PulseAudio in.
+pub const WSLG_DIR_PATH: &str = "/mnt/wslg";
+/// The runtime directory of WSLg, which WSL sets in XDG_RUNTIME_DIR.
+pub const WSLG_RUNTIME_DIR_PATH: &str = "/mnt/wslg/runtime-dir";
+pub const WSLG_X11_SOCKET_DIR_PATH: &str = "/mnt/wslg/.X11-unix";
+
+/// Returns whether WSLg runs for this WSL instance.
+pub fn is_wslg_available() -> bool {
+    Path::new(WSLG_X11_SOCKET_DIR_PATH).is_dir()
+}
+
+/// Returns the environment variables with which GUI apps find the sockets of WSLg.
+/// WAYLAND_DISPLAY is an absolute path so that it's found regardless of XDG_RUNTIME_DIR, which
+/// is the one of the user session of systemd in the distro.
+pub fn collect_wslg_env_vars() -> Vec<(String, String)> {
+    get_wslg_env_vars(
+        std::env::var("DISPLAY").ok().as_deref(),
+        std::env::var("WAYLAND_DISPLAY").ok().as_deref(),
+    )
+}
+
+fn get_wslg_env_vars(
+    display: Option<&str>,
+    wayland_display: Option<&str>,
+) -> Vec<(String, String)> {
+    // sudo may drop them, and the values are written to the profile of every user, so use the
+    // defaults of WSLg unless they are the plain names.
+    let display = display
+        .filter(|display| is_name_with_number(display, ":"))
+        .unwrap_or(":0");
+    let wayland_display = wayland_display
+        .filter(|display| is_name_with_number(display, "wayland-"))
+        .unwrap_or("wayland-0");
+    vec![
+        ("DISPLAY".to_owned(), display.to_owned()),
+        (
+            "WAYLAND_DISPLAY".to_owned(),
+            format!("{}/{}", WSLG_RUNTIME_DIR_PATH, wayland_display),
+        ),
+        (
+            "PULSE_SERVER".to_owned(),
+            format!("unix:{}/PulseServer", WSLG_DIR_PATH),
+        ),
+    ]
+}
+
+fn is_name_with_number(name: &str, prefix: &str) -> bool {
+    match name.strip_prefix(prefix) {
+        Some(number) => !number.is_empty() && number.chars().all(|c| c.is_ascii_digit()),
+        None => false,
+    }
+}
+
+pub const BINFMT_MISC_DIR_PATH: &str = "/proc/sys/fs/binfmt_misc";
+/// The binfmt_misc entry by which WSL runs Windows executables with its /init. The same rule is
+/// in /run/binfmt.d of the distros, so that systemd-binfmt registers it again after resetting.
+pub const WSL_INTEROP_BINFMT_RULE: &str = ":WSLInterop:M::MZ::/init:PF";
+const WSL_INTEROP_BINFMT_NAME: &str = "WSLInterop";
+
+/// Mounts binfmt_misc on WSL unless it's mounted. binfmt_misc is not namespaced, so the entries
+/// registered in it are shared by WSL and all the distros.
+pub fn mount_binfmt_misc() -> Result<()> {
+    let binfmt_misc_path = Path::new(BINFMT_MISC_DIR_PATH);
+    if binfmt_misc_path.join("register").exists() {
+        return Ok(());
+    }
+    nix::mount::mount::<str, Path, str, str>(
+        Some("binfmt_misc"),
+        binfmt_misc_path,
+        Some("binfmt_misc"),
+        nix::mount::MsFlags::empty(),
+        None,
+    )
+    .with_context(|| format!("Failed to mount binfmt_misc on {}.", BINFMT_MISC_DIR_PATH))
+}
+
+/// Registers the WSLInterop entry if it's missing, such as after systemd-binfmt of a distro has
+/// reset binfmt_misc. Returns whether it has been registered.
+pub fn register_wsl_interop_binfmt() -> Result<bool> {
+    mount_binfmt_misc()?;
+    let binfmt_misc_path = Path::new(BINFMT_MISC_DIR_PATH);
+    if binfmt_misc_path.join(WSL_INTEROP_BINFMT_NAME).exists() {
+        return Ok(false);
+    }
+    std::fs::write(binfmt_misc_path.join("register"), WSL_INTEROP_BINFMT_RULE)
+        .with_context(|| "Failed to register WSLInterop to binfmt_misc.")?;
+    Ok(true)
+}
+
+/// Lists the IPv4 DNS servers of the interfaces of Windows in the order of the interface
+/// metrics, so that the ones of a connected VPN come first. The WSL interfaces are left out.
+const WINDOWS_NAMESERVERS_POSH_COMMAND: &str = "Get-DnsClientServerAddress -AddressFamily IPv4 \
+    | Where-Object { $_.InterfaceAlias -notlike 'vEthernet (WSL*' } \
+    | Sort-Object { (Get-NetIPInterface -InterfaceIndex $_.InterfaceIndex -AddressFamily IPv4).InterfaceMetric } \
+    | ForEach-Object { $_.ServerAddresses }";
+
+/// Asks Windows for its DNS servers by Powershell, such as the ones a VPN client has set, which
+/// WSL doesn't always pass to /etc/resolv.conf.
+pub fn get_windows_nameservers() -> Result<Vec<String>> {
+    Ok(parse_windows_nameservers(&run_powershell(
+        WINDOWS_NAMESERVERS_POSH_COMMAND,
+    )?))
+}
+
+/// Returns the name of the culture of Windows, such as "ja-JP", which follows the display
+/// language and the region of the user.
+pub fn get_windows_culture_name() -> Result<String> {
+    let name = run_powershell("(Get-Culture).Name")?.trim().to_owned();
+    if name.is_empty() {
+        bail!("Windows uses the invariant culture.");
+    }
+    Ok(name)
+}
+
+/// Returns the ID of the time zone of Windows, such as "Tokyo Standard Time".
+pub fn get_windows_timezone_id() -> Result<String> {
+    Ok(run_powershell("(Get-TimeZone).Id")?.trim().to_owned())
+}
+
+/// Returns the time of Windows in milliseconds since the Unix epoch.
+pub fn get_windows_unix_time_millis() -> Result<u64> {
+    let output = run_powershell("[DateTimeOffset]::UtcNow.ToUnixTimeMilliseconds()")?;
+    output
+        .trim()
+        .parse()
+        .with_context(|| format!("Invalid time of Windows: '{}'.", output.trim()))
+}
+
+/// Returns the user environment variable of Windows in the registry, which the processes
+/// started after it's set get, unlike the one `get_windows_env_var` gets from a process.
+pub fn get_windows_user_env_var(name: &str) -> Result<Option<String>> {
+    let value = run_powershell(&format!(
+        "[Environment]::GetEnvironmentVariable({}, 'User')",
+        quote_posh_string(name)
+    ))?;
+    let value = value.trim_end_matches(&['\r', '\n'][..]);
+    Ok(if value.is_empty() {
+        None
+    } else {
+        Some(value.to_owned())
+    })
+}
+
+/// Sets the user environment variable of Windows in the registry, or deletes it by None.
+/// Windows tells the running apps such as Explorer about it, but the running terminals and
+/// WSL keep the old one.
+pub fn set_windows_user_env_var(name: &str, value: Option<&str>) -> Result<()> {
+    run_powershell(&format!(
+        "[Environment]::SetEnvironmentVariable({}, {}, 'User')",
+        quote_posh_string(name),
+        value.map_or_else(|| "$null".to_owned(), quote_posh_string)
+    ))?;
+    Ok(())
+}
+
+fn quote_posh_string(s: &str) -> String {
+    format!("'{}'", s.replace('\'', "''"))
+}
+
+fn run_powershell(command: &str) -> Result<String> {
+    let c = get_wsl_drive_path("C")?.ok_or_else(|| anyhow!("C drive not found."))?;
+    let output = Command::new(c.join("Windows/System32/WindowsPowerShell/v1.0/powershell.exe"))
+        .args(&["-NoProfile", "-NonInteractive", "-Command"])
+        .arg(command)
+        .output()
+        .with_context(|| "Failed to execute Powershell.")?;
+    if !output.status.success() {
+        bail!("Powershell failed. {}", output.status);
+    }
+    Ok(String::from_utf8_lossy(&output.stdout).into_owned())
+}
+
+fn parse_windows_nameservers(output: &str) -> Vec<String> {
+    let mut nameservers: Vec<String> = vec![];
+    for line in output.lines() {
+        let server = line.trim();
+        if server.parse::<IpAddr>().is_err() || nameservers.iter().any(|known| known == server) {
+            continue;
+        }
+        nameservers.push(server.to_owned());
+    }
+    nameservers
+}
+
+#[cfg(test)]
+mod test_wsl_interop {
+    use super::*;
+
+    #[test]
+    fn test_split_windows_path() {
+        assert_eq!(
+            Some(('C', vec!["src", "proj"])),
+            split_windows_path("C:\\src\\proj")
+        );
+        assert_eq!(
+            Some(('d', vec!["work", "a b"])),
+            split_windows_path("d:/work/./a b/")
+        );
+        assert_eq!(Some(('C', vec![])), split_windows_path("C:"));
+        assert_eq!(Some(('C', vec![])), split_windows_path("C:\\"));
+        assert_eq!(None, split_windows_path("/home/user"));
+        assert_eq!(None, split_windows_path("C:src"));
+        assert_eq!(None, split_windows_path("relative/path"));
+    }
+
+    #[test]
+    fn test_parse_windows_nameservers() {
+        assert_eq!(
+            vec!["10.8.0.1".to_owned(), "192.168.1.1".to_owned()],
+            parse_windows_nameservers("10.8.0.1\r\n192.168.1.1\r\n10.8.0.1\r\nWARNING: x\r\n\r\n")
+        );
+        assert!(parse_windows_nameservers("").is_empty());
+    }
+
+    #[test]
+    fn test_get_drive_letter() {
+        let entry = |source: &str, path: &str, attributes: &str| MountEntry {
+            source: source.to_owned(),
+            path: PathBuf::from(path),
+            fstype: "9p".to_owned(),
+            attributes: attributes.to_owned(),
+        };
+        let windows10 = entry("C:\\", "/mnt/c", "rw,noatime,dirsync,aname=drvfs;path=C:\\");
+        assert_eq!(Some('C'), get_drive_letter(&windows10));
+        let windows11 = entry("drvfs", "/mnt/d", "rw,noatime,aname=drvfs;path=d:\\;uid=0");
+        assert_eq!(Some('D'), get_drive_letter(&windows11));
+        let drivers = entry("drivers", "/usr/lib/wsl/drivers", "ro,aname=drivers");
+        assert_eq!(None, get_drive_letter(&drivers));
+    }
+
+    #[test]
+    fn test_get_wslg_env_vars() {
+        let envs = get_wslg_env_vars(Some(":1"), Some("wayland-1"));
+        assert_eq!(("DISPLAY".to_owned(), ":1".to_owned()), envs[0]);
+        assert_eq!(
+            (
+                "WAYLAND_DISPLAY".to_owned(),
+                "/mnt/wslg/runtime-dir/wayland-1".to_owned()
+            ),
+            envs[1]
+        );
+        assert_eq!(
+            (
+                "PULSE_SERVER".to_owned(),
+                "unix:/mnt/wslg/PulseServer".to_owned()
+            ),
+            envs[2]
+        );
+
+        let envs = get_wslg_env_vars(None, Some("wayland-0; rm -rf /"));
+        assert_eq!(":0", envs[0].1);
+        assert_eq!("/mnt/wslg/runtime-dir/wayland-0", envs[1].1);
+    }
+}
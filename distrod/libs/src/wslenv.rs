@@ -0,0 +1,194 @@
+use anyhow::{bail, Result};
+use std::fmt;
+
+/// An entry of WSLENV, such as "GOPATH/l", which tells WSL to pass the variable between Windows
+/// and WSL.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct WslenvEntry {
+    pub name: String,
+    /// The letters after '/', such as "pu".
+    pub flags: String,
+}
+
+impl WslenvEntry {
+    /// Parses "NAME[/FLAGS]", validating the name and the flags.
+    pub fn parse(entry: &str) -> Result<WslenvEntry> {
+        let (name, flags) = match entry.split_once('/') {
+            Some((name, flags)) => (name, flags),
+            None => (entry, ""),
+        };
+        validate_name(name)?;
+        validate_flags(flags)?;
+        Ok(WslenvEntry {
+            name: name.to_owned(),
+            flags: flags.to_owned(),
+        })
+    }
+
+    /// Whether WSL passes the variable from Windows to WSL. /w passes it only the other way.
+    pub fn is_passed_to_wsl(&self) -> bool {
+        !self.flags.contains('w')
+    }
+
+    /// Describes the flags, such as "a path list, Windows to WSL only".
+    pub fn describe_flags(&self) -> String {
+        let mut descriptions = vec![];
+        if self.flags.contains('p') {
+            descriptions.push("a path");
+        }
+        if self.flags.contains('l') {
+            descriptions.push("a path list");
+        }
+        if self.flags.contains('u') {
+            descriptions.push("Windows to WSL only");
+        }
+        if self.flags.contains('w') {
+            descriptions.push("WSL to Windows only");
+        }
+        descriptions.join(", ")
+    }
+}
+
+impl fmt::Display for WslenvEntry {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if self.flags.is_empty() {
+            write!(f, "{}", self.name)
+        } else {
+            write!(f, "{}/{}", self.name, self.flags)
+        }
+    }
+}
+
+/// Splits a value of WSLENV into the entries. The broken entries, which WSL ignores as well,
+/// are kept as they are so that they are written back unchanged.
+pub fn parse_wslenv(value: &str) -> Vec<WslenvEntry> {
+    value
+        .split(':')
+        .filter(|entry| !entry.is_empty())
+        .map(|entry| {
+            WslenvEntry::parse(entry).unwrap_or_else(|_| {
+                let (name, flags) = entry.split_once('/').unwrap_or((entry, ""));
+                WslenvEntry {
+                    name: name.to_owned(),
+                    flags: flags.to_owned(),
+                }
+            })
+        })
+        .collect()
+}
+
+pub fn format_wslenv(entries: &[WslenvEntry]) -> String {
+    entries
+        .iter()
+        .map(|entry| entry.to_string())
+        .collect::<Vec<_>>()
+        .join(":")
+}
+
+/// Adds the entry, or replaces the one of the same name. Returns false if it's already there
+/// as it is.
+pub fn share(entries: &mut Vec<WslenvEntry>, entry: WslenvEntry) -> bool {
+    match entries
+        .iter_mut()
+        .find(|known| known.name.eq_ignore_ascii_case(&entry.name))
+    {
+        Some(known) if *known == entry => false,
+        Some(known) => {
+            *known = entry;
+            true
+        }
+        None => {
+            entries.push(entry);
+            true
+        }
+    }
+}
+
+/// Removes the entry of the name. Returns false if there's none.
+pub fn unshare(entries: &mut Vec<WslenvEntry>, name: &str) -> bool {
+    let len = entries.len();
+    // The names of the environment variables of Windows are case-insensitive.
+    entries.retain(|entry| !entry.name.eq_ignore_ascii_case(name));
+    entries.len() != len
+}
+
+fn validate_name(name: &str) -> Result<()> {
+    let mut chars = name.chars();
+    let is_valid = match chars.next() {
+        Some(c) if c.is_ascii_alphabetic() || c == '_' => {
+            chars.all(|c| c.is_ascii_alphanumeric() || c == '_')
+        }
+        _ => false,
+    };
+    if !is_valid {
+        bail!(
+            "Invalid name of a variable: '{}'. It should be letters, digits, and '_'.",
+            name
+        );
+    }
+    if name == "WSLENV" {
+        bail!("WSLENV itself can't be shared.");
+    }
+    Ok(())
+}
+
+fn validate_flags(flags: &str) -> Result<()> {
+    if let Some(c) = flags.chars().find(|c| !"plwu".contains(*c)) {
+        bail!(
+            "Unknown flag of WSLENV: '{}'. It should be p, l, u, or w.",
+            c
+        );
+    }
+    if flags.contains('p') && flags.contains('l') {
+        bail!("The flags p and l can't be given together.");
+    }
+    if flags.contains('u') && flags.contains('w') {
+        bail!("The flags u and w can't be given together.");
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod test_wslenv {
+    use super::*;
+
+    #[test]
+    fn test_parse_entry() {
+        let entry = WslenvEntry::parse("GOPATH/lu").unwrap();
+        assert_eq!("GOPATH", entry.name);
+        assert_eq!("lu", entry.flags);
+        assert_eq!("a path list, Windows to WSL only", entry.describe_flags());
+        assert_eq!("", WslenvEntry::parse("TERM").unwrap().flags);
+        assert!(WslenvEntry::parse("GOPATH/x").is_err());
+        assert!(WslenvEntry::parse("GOPATH/pl").is_err());
+        assert!(WslenvEntry::parse("GOPATH/uw").is_err());
+        assert!(WslenvEntry::parse("1ABC").is_err());
+        assert!(WslenvEntry::parse("A:B").is_err());
+        assert!(WslenvEntry::parse("WSLENV").is_err());
+    }
+
+    #[test]
+    fn test_share_and_unshare() {
+        let mut entries = parse_wslenv("WT_SESSION::WT_PROFILE_ID:USERPROFILE/pu");
+        assert_eq!(3, entries.len());
+        assert!(!share(
+            &mut entries,
+            WslenvEntry::parse("USERPROFILE/pu").unwrap()
+        ));
+        assert!(share(
+            &mut entries,
+            WslenvEntry::parse("userprofile/p").unwrap()
+        ));
+        assert!(share(&mut entries, WslenvEntry::parse("GOPATH/l").unwrap()));
+        assert_eq!(
+            "WT_SESSION:WT_PROFILE_ID:userprofile/p:GOPATH/l",
+            format_wslenv(&entries)
+        );
+        assert!(unshare(&mut entries, "wt_session"));
+        assert!(!unshare(&mut entries, "WT_SESSION"));
+        assert_eq!(
+            "WT_PROFILE_ID:userprofile/p:GOPATH/l",
+            format_wslenv(&entries)
+        );
+    }
+}
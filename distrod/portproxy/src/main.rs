@@ -1,10 +1,38 @@
-use anyhow::{Context, Result};
+use anyhow::{anyhow, bail, Context, Result};
 use libs::cli_ui::init_logger;
+use libs::connection_log::{
+    CloseReason, ConnectionLog, ConnectionLogConfig, ConnectionLogLevel, ConnectionRecord,
+};
+use libs::port_forward::{
+    FirewallConfig, MetricsConfig, PortForwardCommand, PortForwardRule, PortForwardRules, Protocol,
+};
+use libs::port_metrics::{self, PortMetrics};
+use libs::port_usage::{self, PortUsage, PortUsageStats};
+use std::collections::HashMap;
+use std::net::{Ipv4Addr, Ipv6Addr, SocketAddr};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex, RwLock};
+use std::time::{Duration, Instant};
 use structopt::StructOpt;
 use strum::{EnumString, EnumVariantNames};
-use tokio::io::AsyncWriteExt;
-use tokio::io::{self, BufReader};
-use tokio::net::{TcpListener, TcpStream};
+use tokio::io::{self, AsyncBufReadExt, AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+use tokio::net::{TcpListener, TcpStream, UdpSocket};
+use tokio::sync::{mpsc, Notify};
+use tokio::task::JoinHandle;
+
+const USAGE_STATS_FLUSH_INTERVAL: Duration = Duration::from_secs(60);
+const RULES_RELOAD_INTERVAL: Duration = Duration::from_secs(2);
+/// How long a UDP client is remembered without any datagram, since UDP has no close.
+const UDP_SESSION_TIMEOUT: Duration = Duration::from_secs(60);
+const METRICS_REQUEST_TIMEOUT: Duration = Duration::from_secs(10);
+const MAX_METRICS_REQUEST_SIZE: usize = 8192;
+const IDLE_CHECK_INTERVAL: Duration = Duration::from_secs(1);
+
+/// Notified when a connection is closed, so that its bytes are saved to the usage stats at once.
+/// The final flush on the shutdown never runs when portproxy.service stops, which kills the
+/// process through the interop.
+static CONNECTION_CLOSED: Notify = Notify::const_new();
 
 #[derive(Debug, StructOpt)]
 #[structopt(name = "portproxy", rename_all = "kebab")]
@@ -20,6 +48,14 @@ pub struct Opts {
 pub enum Subcommand {
     Proxy(ProxyOpts),
     Show(ShowOpts),
+    /// Write the journal entries given by `distrod event-log` as lines of JSON on stdin to the Windows Event Log.
+    EventLog(EventLogOpts),
+    /// Relay stdin and stdout to a named pipe, such as \\.\pipe\docker_engine. This is run by `distrod relay pipe-to-socket` for each client of the socket.
+    PipeConnect(PipeConnectOpts),
+    /// Create a named pipe, and relay each of its clients to the stdin and stdout of a new process of the command. This is run by `distrod relay socket-to-pipe`.
+    PipeServe(PipeServeOpts),
+    /// Relay stdin and stdout to the server of an Assuan socket file, such as the one of gpg-agent, which listens on a TCP port of the loopback. This is run by `distrod relay assuan-to-socket` for each client of the socket.
+    AssuanConnect(AssuanConnectOpts),
 }
 
 #[derive(Debug, StructOpt)]
@@ -28,6 +64,47 @@ pub struct ProxyOpts {
     pub dest_addr: String,
     #[structopt(short, long)]
     pub tcp4: Vec<u16>,
+    /// Record the bytes transferred through each port to this file.
+    #[structopt(long)]
+    pub usage_stats: Option<PathBuf>,
+    /// Also add and remove the forwards by the commands such as "add 8080" and "remove 8080" on
+    /// stdin, which `distrod port watch` gives. Exits when stdin is closed.
+    #[structopt(long)]
+    pub dynamic: bool,
+    /// The address the forwards added on stdin listen on. Defaults to the loopback, so that
+    /// only Windows itself reaches them. Give 0.0.0.0 to expose them to the LAN.
+    #[structopt(long, default_value = "127.0.0.1")]
+    pub dynamic_listen_address: String,
+    /// Also keep the forwards in this rules file, reloading it when it changes. The forwards of
+    /// the changed rules are replaced, and their connections are kept until they are closed.
+    #[structopt(long)]
+    pub rules: Option<PathBuf>,
+    /// Add an inbound rule of Windows Firewall for each forward while it's open, so that the
+    /// other machines can reach it. This needs the administrator. `[firewall]` of the rules
+    /// file takes precedence.
+    #[structopt(long)]
+    pub firewall: bool,
+    /// The remote addresses the firewall rules allow, such as LocalSubnet, 192.168.1.0/24, or
+    /// Any. Defaults to LocalSubnet.
+    #[structopt(long, number_of_values = 1)]
+    pub firewall_remote_address: Vec<String>,
+    /// Serve the Prometheus metrics of the forwards at /metrics on this address, such as
+    /// 127.0.0.1:9464. `[metrics]` of the rules file takes precedence.
+    #[structopt(long)]
+    pub metrics_address: Option<SocketAddr>,
+    /// Log each connection through the forwards when it's closed, with its source, target,
+    /// duration, bytes, and close reason: off(default), errors, or all. `[connection_log]` of the
+    /// rules file takes precedence.
+    #[structopt(long, possible_values = &["off", "errors", "all"])]
+    pub connection_log: Option<ConnectionLogLevel>,
+    /// Append the logged connections to this file as lines of JSON, instead of stdout.
+    #[structopt(long)]
+    pub connection_log_file: Option<PathBuf>,
+    /// Exit when no connection has been open through the forwards for this many seconds, so
+    /// that nothing runs on Windows while they aren't used. `distrod port watch` starts it again
+    /// when a port is listened on. 0 keeps it running.
+    #[structopt(long, default_value = "0")]
+    pub idle_exit_sec: u64,
 }
 
 #[derive(Debug, StructOpt)]
@@ -36,6 +113,34 @@ pub struct ShowOpts {
     pub show: ShowItem,
 }
 
+#[derive(Debug, StructOpt)]
+#[structopt(rename_all = "kebab")]
+pub struct EventLogOpts {
+    /// The source of the events in the Application log.
+    #[structopt(long, default_value = "Distrod")]
+    pub source: String,
+}
+
+#[derive(Debug, StructOpt)]
+#[structopt(rename_all = "kebab")]
+pub struct PipeConnectOpts {
+    pub pipe: String,
+}
+
+#[derive(Debug, StructOpt)]
+#[structopt(rename_all = "kebab")]
+pub struct PipeServeOpts {
+    pub pipe: String,
+    #[structopt(required = true, last = true)]
+    pub command: Vec<String>,
+}
+
+#[derive(Debug, StructOpt)]
+#[structopt(rename_all = "kebab")]
+pub struct AssuanConnectOpts {
+    pub socket_file: PathBuf,
+}
+
 #[derive(Clone, Debug, EnumString, EnumVariantNames)]
 #[strum(serialize_all = "kebab-case")]
 pub enum ShowItem {
@@ -59,6 +164,12 @@ async fn run(opts: Opts) -> Result<()> {
     match opts.command {
         Subcommand::Proxy(proxy_opts) => run_proxy(proxy_opts).await,
         Subcommand::Show(show_opts) => run_show(show_opts)?,
+        Subcommand::EventLog(event_log_opts) => run_event_log(event_log_opts)?,
+        Subcommand::PipeConnect(pipe_connect_opts) => run_pipe_connect(pipe_connect_opts).await?,
+        Subcommand::PipeServe(pipe_serve_opts) => run_pipe_serve(pipe_serve_opts).await?,
+        Subcommand::AssuanConnect(assuan_connect_opts) => {
+            run_assuan_connect(assuan_connect_opts).await?
+        }
     };
     log::trace!("Exiting run.");
     Ok(())
@@ -66,7 +177,6 @@ async fn run(opts: Opts) -> Result<()> {
 
 #[cfg(target_os = "linux")]
 fn run_show(_opts: ShowOpts) -> Result<()> {
-    use anyhow::anyhow;
     use nix::sys::socket::{InetAddr, SockAddr};
 
     let mut addrs = nix::ifaddrs::getifaddrs()?;
@@ -88,65 +198,1209 @@ fn run_show(_opts: ShowOpts) -> Result<()> {
 
 #[cfg(target_os = "windows")]
 fn run_show(_opts: ShowOpts) -> Result<()> {
-    use anyhow::bail;
-
     bail!("Show command is not implemented on Windows.");
 }
 
+#[cfg(target_os = "windows")]
+fn run_event_log(opts: EventLogOpts) -> Result<()> {
+    use libs::event_log::EventLogEntry;
+    use std::io::BufRead;
+    use std::process::Command;
+
+    for line in std::io::stdin().lock().lines() {
+        let line = line.with_context(|| "Failed to read stdin.")?;
+        let entry = match EventLogEntry::from_json_line(&line) {
+            Ok(entry) => entry,
+            Err(e) => {
+                log::warn!("Skipping the entry. {:?}", e);
+                continue;
+            }
+        };
+        // eventcreate registers the source in the Application log the first time, which needs
+        // the administrator.
+        let output = Command::new("eventcreate")
+            .args(&["/L", "APPLICATION", "/SO", &opts.source])
+            .args(&["/T", entry.level.eventcreate_type()])
+            .args(&["/ID", &entry.level.event_id().to_string()])
+            .args(&["/D", &entry.description()])
+            .output()
+            .with_context(|| "Failed to run eventcreate.")?;
+        if !output.status.success() {
+            log::error!(
+                "eventcreate failed. {}",
+                String::from_utf8_lossy(&output.stderr).trim()
+            );
+        }
+    }
+    Ok(())
+}
+
+#[cfg(target_os = "linux")]
+fn run_event_log(_opts: EventLogOpts) -> Result<()> {
+    bail!("EventLog command is only available on Windows.");
+}
+
+#[cfg(target_os = "windows")]
+async fn run_pipe_connect(opts: PipeConnectOpts) -> Result<()> {
+    use tokio::net::windows::named_pipe::ClientOptions;
+
+    // ERROR_PIPE_BUSY, which means all the instances of the pipe are connected for now.
+    const ERROR_PIPE_BUSY: i32 = 231;
+    let pipe = loop {
+        match ClientOptions::new().open(&opts.pipe) {
+            Ok(pipe) => break pipe,
+            Err(e) if e.raw_os_error() == Some(ERROR_PIPE_BUSY) => {}
+            Err(e) => return Err(e).with_context(|| format!("Failed to open {}.", &opts.pipe)),
+        }
+        tokio::time::sleep(Duration::from_millis(50)).await;
+    };
+    let (pipe_read, pipe_write) = io::split(pipe);
+    relay_until_either_ends(io::stdin(), pipe_write, pipe_read, io::stdout()).await
+}
+
+#[cfg(target_os = "windows")]
+async fn run_pipe_serve(opts: PipeServeOpts) -> Result<()> {
+    use tokio::net::windows::named_pipe::ServerOptions;
+
+    let mut server = ServerOptions::new()
+        .first_pipe_instance(true)
+        .create(&opts.pipe)
+        .with_context(|| format!("Failed to create {}.", &opts.pipe))?;
+    println!("Serving {}", &opts.pipe);
+    loop {
+        server
+            .connect()
+            .await
+            .with_context(|| format!("Failed to wait for a client of {}.", &opts.pipe))?;
+        let client = server;
+        // Create the next instance before serving the client, so that the pipe keeps existing.
+        server = ServerOptions::new()
+            .create(&opts.pipe)
+            .with_context(|| format!("Failed to create {}.", &opts.pipe))?;
+        let command = opts.command.clone();
+        tokio::spawn(async move {
+            if let Err(e) = serve_pipe_client(client, &command).await {
+                log::error!("{:?}", e);
+            }
+        });
+    }
+}
+
+#[cfg(target_os = "windows")]
+async fn serve_pipe_client(
+    client: tokio::net::windows::named_pipe::NamedPipeServer,
+    command: &[String],
+) -> Result<()> {
+    use std::process::Stdio;
+
+    let mut child = tokio::process::Command::new(&command[0])
+        .args(&command[1..])
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .kill_on_drop(true)
+        .spawn()
+        .with_context(|| format!("Failed to run {}.", &command[0]))?;
+    let child_stdin = child
+        .stdin
+        .take()
+        .expect("[BUG] the stdin of the child is piped.");
+    let child_stdout = child
+        .stdout
+        .take()
+        .expect("[BUG] the stdout of the child is piped.");
+    let (client_read, client_write) = io::split(client);
+    relay_until_either_ends(client_read, child_stdin, child_stdout, client_write).await?;
+    child
+        .wait()
+        .await
+        .with_context(|| format!("Failed to wait for {}.", &command[0]))?;
+    Ok(())
+}
+
+/// Relays the two directions until either ends, since a named pipe can't be half-closed. The
+/// other direction is dropped then, which lets its peer see EOF.
+#[cfg(target_os = "windows")]
+async fn relay_until_either_ends<R1, W1, R2, W2>(
+    mut reader1: R1,
+    mut writer1: W1,
+    mut reader2: R2,
+    mut writer2: W2,
+) -> Result<()>
+where
+    R1: AsyncRead + Unpin,
+    W1: AsyncWrite + Unpin,
+    R2: AsyncRead + Unpin,
+    W2: AsyncWrite + Unpin,
+{
+    tokio::select! {
+        result = io::copy(&mut reader1, &mut writer1) => {
+            result.with_context(|| "Failed to relay to the pipe.")?;
+        }
+        result = io::copy(&mut reader2, &mut writer2) => {
+            result.with_context(|| "Failed to relay from the pipe.")?;
+        }
+    }
+    Ok(())
+}
+
+/// Connects to the server of the Assuan socket file, and sends the nonce in the file before
+/// relaying, as libassuan does in place of a Unix socket on Windows.
+#[cfg(target_os = "windows")]
+async fn run_assuan_connect(opts: AssuanConnectOpts) -> Result<()> {
+    use libs::assuan::AssuanSocketFile;
+
+    let content = tokio::fs::read(&opts.socket_file)
+        .await
+        .with_context(|| format!("Failed to read {:?}.", &opts.socket_file))?;
+    let socket_file = AssuanSocketFile::parse(&content)
+        .with_context(|| format!("{:?} is not an Assuan socket file.", &opts.socket_file))?;
+    let mut stream = TcpStream::connect(("127.0.0.1", socket_file.port))
+        .await
+        .with_context(|| format!("Failed to connect to the port {}.", socket_file.port))?;
+    stream
+        .write_all(&socket_file.nonce)
+        .await
+        .with_context(|| "Failed to send the nonce.")?;
+    let (stream_reader, stream_writer) = stream.into_split();
+    relay_until_either_ends(io::stdin(), stream_writer, stream_reader, io::stdout()).await
+}
+
+#[cfg(target_os = "linux")]
+async fn run_pipe_connect(_opts: PipeConnectOpts) -> Result<()> {
+    bail!("PipeConnect command is only available on Windows.");
+}
+
+#[cfg(target_os = "linux")]
+async fn run_pipe_serve(_opts: PipeServeOpts) -> Result<()> {
+    bail!("PipeServe command is only available on Windows.");
+}
+
+#[cfg(target_os = "linux")]
+async fn run_assuan_connect(_opts: AssuanConnectOpts) -> Result<()> {
+    bail!("AssuanConnect command is only available on Windows.");
+}
+
+/// The counters of a forwarded port. `bytes_in` and `bytes_out` are reset by each flush of the
+/// usage stats, and the others are kept since the start for the metrics.
+#[derive(Default)]
+struct UsageCounter {
+    bytes_in: AtomicU64,
+    bytes_out: AtomicU64,
+    total_bytes_in: AtomicU64,
+    total_bytes_out: AtomicU64,
+    active_connections: AtomicU64,
+    connections: AtomicU64,
+    errors: AtomicU64,
+}
+
+impl UsageCounter {
+    fn add_bytes_in(&self, n: u64) {
+        self.bytes_in.fetch_add(n, Ordering::Relaxed);
+        self.total_bytes_in.fetch_add(n, Ordering::Relaxed);
+    }
+
+    fn add_bytes_out(&self, n: u64) {
+        self.bytes_out.fetch_add(n, Ordering::Relaxed);
+        self.total_bytes_out.fetch_add(n, Ordering::Relaxed);
+    }
+
+    fn add_error(&self) {
+        self.errors.fetch_add(1, Ordering::Relaxed);
+    }
+
+    fn get_metrics(&self, port: u16) -> PortMetrics {
+        PortMetrics {
+            port,
+            active_connections: self.active_connections.load(Ordering::Relaxed),
+            connections: self.connections.load(Ordering::Relaxed),
+            bytes_in: self.total_bytes_in.load(Ordering::Relaxed),
+            bytes_out: self.total_bytes_out.load(Ordering::Relaxed),
+            errors: self.errors.load(Ordering::Relaxed),
+        }
+    }
+
+    fn take(&self) -> PortUsage {
+        PortUsage {
+            bytes_in: self.bytes_in.swap(0, Ordering::Relaxed),
+            bytes_out: self.bytes_out.swap(0, Ordering::Relaxed),
+        }
+    }
+
+    fn put_back(&self, usage: &PortUsage) {
+        self.bytes_in.fetch_add(usage.bytes_in, Ordering::Relaxed);
+        self.bytes_out.fetch_add(usage.bytes_out, Ordering::Relaxed);
+    }
+}
+
+/// A forward listening on a port, which its connections share.
+struct Forward {
+    protocol: Protocol,
+    listen_addr: String,
+    dest_addr: String,
+    counter: Arc<UsageCounter>,
+    logger: ConnectionLogger,
+}
+
+/// A connection through a forward, or a client of a UDP forward, which is counted as active
+/// until it's dropped.
+struct Connection {
+    forward: Arc<Forward>,
+    source: SocketAddr,
+    started: Instant,
+    bytes_in: AtomicU64,
+    bytes_out: AtomicU64,
+}
+
+impl Connection {
+    fn open(forward: &Arc<Forward>, source: SocketAddr) -> Connection {
+        let counter = &forward.counter;
+        counter.connections.fetch_add(1, Ordering::Relaxed);
+        counter.active_connections.fetch_add(1, Ordering::Relaxed);
+        Connection {
+            forward: forward.clone(),
+            source,
+            started: Instant::now(),
+            bytes_in: AtomicU64::new(0),
+            bytes_out: AtomicU64::new(0),
+        }
+    }
+
+    fn add_bytes_in(&self, n: u64) {
+        self.forward.counter.add_bytes_in(n);
+        self.bytes_in.fetch_add(n, Ordering::Relaxed);
+    }
+
+    fn add_bytes_out(&self, n: u64) {
+        self.forward.counter.add_bytes_out(n);
+        self.bytes_out.fetch_add(n, Ordering::Relaxed);
+    }
+
+    /// Logs the connection by the connection log. The failures are counted as the errors.
+    fn close(&self, close_reason: CloseReason, error: Option<&anyhow::Error>) {
+        if close_reason.is_error() {
+            self.forward.counter.add_error();
+        }
+        self.forward.logger.log(&ConnectionRecord {
+            protocol: self.forward.protocol,
+            listen: self.forward.listen_addr.clone(),
+            source: self.source.to_string(),
+            target: self.forward.dest_addr.clone(),
+            duration: self.started.elapsed(),
+            bytes_in: self.bytes_in.load(Ordering::Relaxed),
+            bytes_out: self.bytes_out.load(Ordering::Relaxed),
+            close_reason,
+            error: error.map(|e| format!("{:#}", e)),
+        });
+    }
+}
+
+impl Drop for Connection {
+    fn drop(&mut self) {
+        self.forward
+            .counter
+            .active_connections
+            .fetch_sub(1, Ordering::Relaxed);
+        // The closes in a burst are saved together by one flush.
+        CONNECTION_CLOSED.notify_one();
+    }
+}
+
+/// Logs the connections by `[connection_log]` of the rules file, or by the options if it has
+/// none.
+#[derive(Clone)]
+struct ConnectionLogger {
+    log: Arc<Mutex<ConnectionLog>>,
+    default_config: Arc<ConnectionLogConfig>,
+}
+
+impl ConnectionLogger {
+    fn new(default_config: ConnectionLogConfig) -> ConnectionLogger {
+        let log = ConnectionLog::open(default_config.clone()).unwrap_or_else(|e| {
+            log::error!("{:?}", e);
+            ConnectionLog::default()
+        });
+        ConnectionLogger {
+            log: Arc::new(Mutex::new(log)),
+            default_config: Arc::new(default_config),
+        }
+    }
+
+    /// Applies `[connection_log]` of the rules file in `rules_dir`. A config which fails to be
+    /// opened is reported and the current one is kept.
+    fn apply_config(&self, config: Option<&ConnectionLogConfig>, rules_dir: &Path) {
+        let config = match config {
+            Some(config) => config.resolve(rules_dir),
+            None => (*self.default_config).clone(),
+        };
+        let mut log = self
+            .log
+            .lock()
+            .expect("[BUG] the connection log is never poisoned.");
+        if *log.config() == config {
+            return;
+        }
+        match ConnectionLog::open(config) {
+            Ok(next) => *log = next,
+            Err(e) => log::error!("Keeping the current connection log. {:?}", e),
+        }
+    }
+
+    fn log(&self, record: &ConnectionRecord) {
+        let result = self
+            .log
+            .lock()
+            .expect("[BUG] the connection log is never poisoned.")
+            .write(record);
+        if let Err(e) = result {
+            log::error!("Failed to log the connection. {:?}", e);
+        }
+    }
+}
+
+/// The counters are kept after the forward of the port is removed, so that the last bytes are
+/// flushed.
+type UsageCounters = Arc<RwLock<HashMap<u16, Arc<UsageCounter>>>>;
+
 async fn run_proxy(opts: ProxyOpts) {
-    let mut handles = vec![];
+    let counters: UsageCounters = Arc::new(RwLock::new(HashMap::new()));
+    let usage_stats_path = opts.usage_stats.clone();
+    if let Some(usage_stats_path) = usage_stats_path.clone() {
+        let counters = counters.clone();
+        tokio::spawn(async move {
+            let mut interval = tokio::time::interval(USAGE_STATS_FLUSH_INTERVAL);
+            loop {
+                tokio::select! {
+                    _ = interval.tick() => {}
+                    _ = CONNECTION_CLOSED.notified() => {}
+                }
+                if let Err(e) = flush_usage_stats(&counters, &usage_stats_path) {
+                    log::error!("{:?}", e);
+                }
+            }
+        });
+    }
+
+    let idle_exit_sec = opts.idle_exit_sec;
+    tokio::select! {
+        _ = serve_forwards(opts, counters.clone()) => {}
+        _ = wait_for_shutdown() => log::info!("Shutting down."),
+        _ = wait_for_idle(&counters, idle_exit_sec), if idle_exit_sec != 0 => {
+            log::info!(
+                "Exiting since no connection has been made for {} seconds.",
+                idle_exit_sec
+            );
+        }
+    }
+    // Save the bytes of the connections still open, which would be lost otherwise.
+    if let Some(ref usage_stats_path) = usage_stats_path {
+        if let Err(e) = flush_usage_stats(&counters, usage_stats_path) {
+            log::error!("{:?}", e);
+        }
+    }
+}
+
+/// Waits until no connection has been open through the forwards for `idle_exit_sec`. The
+/// connections opened and closed between the checks are found by the count of the connections.
+async fn wait_for_idle(counters: &UsageCounters, idle_exit_sec: u64) {
+    let idle_exit = Duration::from_secs(idle_exit_sec);
+    let mut idle_since = Instant::now();
+    let mut last_connections = 0;
+    let mut interval = tokio::time::interval(IDLE_CHECK_INTERVAL);
+    loop {
+        interval.tick().await;
+        let (active_connections, connections) = count_connections(counters);
+        if active_connections > 0 || connections != last_connections {
+            last_connections = connections;
+            idle_since = Instant::now();
+        } else if idle_since.elapsed() >= idle_exit {
+            return;
+        }
+    }
+}
+
+/// Returns the numbers of the open connections and of all the connections since the start.
+fn count_connections(counters: &UsageCounters) -> (u64, u64) {
+    counters
+        .read()
+        .expect("[BUG] the usage counters are never poisoned.")
+        .values()
+        .fold((0, 0), |(active, all), counter| {
+            (
+                active + counter.active_connections.load(Ordering::Relaxed),
+                all + counter.connections.load(Ordering::Relaxed),
+            )
+        })
+}
+
+/// Waits for Ctrl-C, or SIGTERM such as from `systemctl stop`.
+#[cfg(target_os = "linux")]
+async fn wait_for_shutdown() {
+    use tokio::signal::unix::{signal, SignalKind};
+
+    let mut terminate = match signal(SignalKind::terminate()) {
+        Ok(terminate) => terminate,
+        Err(e) => {
+            log::warn!("Failed to listen to SIGTERM. {:?}", e);
+            return wait_for_ctrl_c().await;
+        }
+    };
+    tokio::select! {
+        _ = terminate.recv() => {}
+        _ = wait_for_ctrl_c() => {}
+    }
+}
+
+/// Waits for Ctrl-C or Ctrl-Break, which is what Windows has instead of SIGTERM.
+#[cfg(target_os = "windows")]
+async fn wait_for_shutdown() {
+    let mut ctrl_break = match tokio::signal::windows::ctrl_break() {
+        Ok(ctrl_break) => ctrl_break,
+        Err(e) => {
+            log::warn!("Failed to listen to Ctrl-Break. {:?}", e);
+            return wait_for_ctrl_c().await;
+        }
+    };
+    tokio::select! {
+        _ = ctrl_break.recv() => {}
+        _ = wait_for_ctrl_c() => {}
+    }
+}
+
+async fn wait_for_ctrl_c() {
+    if let Err(e) = tokio::signal::ctrl_c().await {
+        log::warn!("Failed to listen to Ctrl-C. {:?}", e);
+        std::future::pending::<()>().await;
+    }
+}
+
+async fn serve_forwards(opts: ProxyOpts, counters: UsageCounters) {
+    let firewall = Firewall::spawn(if opts.firewall {
+        let config = FirewallConfig {
+            enabled: true,
+            remote_addresses: opts.firewall_remote_address.clone(),
+        };
+        config.get_enabled_remote_addresses()
+    } else {
+        None
+    });
+    let logger = ConnectionLogger::new(ConnectionLogConfig {
+        level: opts.connection_log.unwrap_or_default(),
+        path: opts.connection_log_file.clone(),
+    });
+    let mut metrics_server =
+        MetricsServer::new(counters.clone(), firewall.clone(), opts.metrics_address);
+    if opts.rules.is_none() {
+        metrics_server.apply_config(None).await;
+    }
+    let rules_handle = opts.rules.clone().map(|rules_path| {
+        let dest_addr = opts.dest_addr.clone();
+        let counters = counters.clone();
+        let firewall = firewall.clone();
+        let logger = logger.clone();
+        tokio::spawn(async move {
+            watch_rules(
+                rules_path,
+                dest_addr,
+                counters,
+                firewall,
+                logger,
+                metrics_server,
+            )
+            .await
+        })
+    });
+    let mut forwards = HashMap::new();
     for tcp_port in opts.tcp4 {
         if tcp_port == 0 {
             log::info!("Skipping port 0");
             continue;
         }
-        let dest_addr = format!("{}:{}", &opts.dest_addr, tcp_port);
-        handles.push(tokio::spawn(async move {
-            if let Err(e) = proxy_tcp_port(tcp_port, dest_addr).await {
+        forwards.insert(
+            tcp_port,
+            spawn_forward(
+                &tcp_rule("0.0.0.0", tcp_port),
+                &opts.dest_addr,
+                &counters,
+                &firewall,
+                &logger,
+            ),
+        );
+    }
+    if opts.dynamic {
+        if let Err(e) = apply_forward_commands(
+            &mut forwards,
+            &opts.dynamic_listen_address,
+            &opts.dest_addr,
+            &counters,
+            &firewall,
+            &logger,
+        )
+        .await
+        {
+            log::error!("{:?}", e);
+        }
+        return;
+    }
+    for (_, handle) in forwards {
+        let _ = handle.await;
+    }
+    if let Some(rules_handle) = rules_handle {
+        let _ = rules_handle.await;
+    }
+}
+
+/// The rule of a port given by `--tcp4` or `add`, which is forwarded to the same port.
+fn tcp_rule(listen_address: &str, port: u16) -> PortForwardRule {
+    PortForwardRule {
+        listen_port: port,
+        listen_address: listen_address.to_owned(),
+        dest_address: None,
+        dest_port: None,
+        protocol: Protocol::Tcp,
+    }
+}
+
+fn spawn_forward(
+    rule: &PortForwardRule,
+    default_dest_addr: &str,
+    counters: &UsageCounters,
+    firewall: &Firewall,
+    logger: &ConnectionLogger,
+) -> JoinHandle<()> {
+    let counter = counters
+        .write()
+        .expect("[BUG] the usage counters are never poisoned.")
+        .entry(rule.listen_port)
+        .or_default()
+        .clone();
+    let forward = Arc::new(Forward {
+        protocol: rule.protocol,
+        listen_addr: rule.listen_socket_address(),
+        dest_addr: rule.dest_socket_address(default_dest_addr),
+        counter,
+        logger: logger.clone(),
+    });
+    let firewall = firewall.clone();
+    let rule = rule.clone();
+    tokio::spawn(async move {
+        // Dropped when the forward fails or is aborted, which deletes the firewall rule.
+        let _firewall_rule = firewall.open(&rule);
+        let result = match forward.protocol {
+            Protocol::Tcp => proxy_tcp_port(forward).await,
+            Protocol::Udp => proxy_udp_port(forward).await,
+        };
+        if let Err(e) = result {
+            log::error!("{:?}", e);
+        }
+    })
+}
+
+/// Polls the rules file and applies it whenever it changes. A broken rules file is reported and
+/// the current forwards are kept.
+async fn watch_rules(
+    rules_path: PathBuf,
+    default_dest_addr: String,
+    counters: UsageCounters,
+    firewall: Firewall,
+    logger: ConnectionLogger,
+    mut metrics_server: MetricsServer,
+) {
+    let mut forwards: HashMap<PortForwardRule, JoinHandle<()>> = HashMap::new();
+    let mut last_content = None;
+    let mut interval = tokio::time::interval(RULES_RELOAD_INTERVAL);
+    loop {
+        interval.tick().await;
+        let content = match tokio::fs::read_to_string(&rules_path).await {
+            Ok(content) => content,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => String::new(),
+            Err(e) => {
+                log::error!("Failed to read {:?}. {:?}", &rules_path, e);
+                continue;
+            }
+        };
+        if last_content.as_ref() == Some(&content) {
+            continue;
+        }
+        let rules = PortForwardRules::parse(&content);
+        last_content = Some(content);
+        let rules = match rules {
+            Ok(rules) => rules,
+            Err(e) => {
+                log::error!(
+                    "Keeping the current forwards since {:?} is invalid. {:?}",
+                    &rules_path,
+                    e
+                );
+                continue;
+            }
+        };
+        firewall.apply_config(rules.firewall.as_ref());
+        metrics_server.apply_config(rules.metrics.as_ref()).await;
+        logger.apply_config(
+            rules.connection_log.as_ref(),
+            rules_path.parent().unwrap_or_else(|| Path::new(".")),
+        );
+        let (kept, removed): (HashMap<_, _>, HashMap<_, _>) = forwards
+            .drain()
+            .partition(|(rule, _)| rules.forwards.contains(rule));
+        forwards = kept;
+        for (rule, handle) in removed {
+            handle.abort();
+            // Wait for the listener to be closed, since a changed rule may bind the same port.
+            let _ = handle.await;
+            println!("Stopped forwarding {}", rule.listen_socket_address());
+        }
+        for rule in rules.forwards {
+            if !forwards.contains_key(&rule) {
+                let handle =
+                    spawn_forward(&rule, &default_dest_addr, &counters, &firewall, &logger);
+                forwards.insert(rule, handle);
+            }
+        }
+    }
+}
+
+/// Adds and removes the forwards by the commands on stdin until it's closed. The connections
+/// accepted by a removed forward are kept until they are closed.
+async fn apply_forward_commands(
+    forwards: &mut HashMap<u16, JoinHandle<()>>,
+    listen_address: &str,
+    dest_addr: &str,
+    counters: &UsageCounters,
+    firewall: &Firewall,
+    logger: &ConnectionLogger,
+) -> Result<()> {
+    let mut lines = io::BufReader::new(io::stdin()).lines();
+    while let Some(line) = lines
+        .next_line()
+        .await
+        .with_context(|| "Failed to read stdin.")?
+    {
+        let command = match PortForwardCommand::parse_line(&line) {
+            Ok(command) => command,
+            Err(e) => {
+                log::warn!("Skipping the command. {:?}", e);
+                continue;
+            }
+        };
+        match command {
+            PortForwardCommand::Add(port) => {
+                if port == 0 || forwards.contains_key(&port) {
+                    continue;
+                }
+                forwards.insert(
+                    port,
+                    spawn_forward(
+                        &tcp_rule(listen_address, port),
+                        dest_addr,
+                        counters,
+                        firewall,
+                        logger,
+                    ),
+                );
+            }
+            PortForwardCommand::Remove(port) => {
+                if let Some(handle) = forwards.remove(&port) {
+                    handle.abort();
+                    println!(
+                        "Stopped forwarding {}",
+                        tcp_rule(listen_address, port).listen_socket_address()
+                    );
+                }
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Serves the metrics of the forwards on the address of `[metrics]` of the rules file, or the one
+/// of the options if it has none, and moves to the new address when it changes.
+struct MetricsServer {
+    counters: UsageCounters,
+    firewall: Firewall,
+    default_address: Option<SocketAddr>,
+    address: Option<SocketAddr>,
+    handle: Option<JoinHandle<()>>,
+}
+
+impl MetricsServer {
+    fn new(
+        counters: UsageCounters,
+        firewall: Firewall,
+        default_address: Option<SocketAddr>,
+    ) -> MetricsServer {
+        MetricsServer {
+            counters,
+            firewall,
+            default_address,
+            address: None,
+            handle: None,
+        }
+    }
+
+    async fn apply_config(&mut self, config: Option<&MetricsConfig>) {
+        let address = match config {
+            // The rules file has validated it.
+            Some(config) => config.listen_address.parse().ok(),
+            None => self.default_address,
+        };
+        if address == self.address {
+            return;
+        }
+        if let Some(handle) = self.handle.take() {
+            handle.abort();
+            // Wait for the listener to be closed, since the new address may have the same port.
+            let _ = handle.await;
+        }
+        self.address = address;
+        let address = match address {
+            Some(address) => address,
+            None => return,
+        };
+        let counters = self.counters.clone();
+        let firewall = self.firewall.clone();
+        self.handle = Some(tokio::spawn(async move {
+            let _firewall_rule =
+                firewall.open(&tcp_rule(&address.ip().to_string(), address.port()));
+            if let Err(e) = serve_metrics(address, counters).await {
                 log::error!("{:?}", e);
             }
         }));
     }
-    for handle in handles {
-        let _ = handle.await;
-    }
 }
 
-async fn proxy_tcp_port(port: u16, dest_addr: String) -> Result<()> {
-    let listen_addr = format!("0.0.0.0:{}", port);
-    let listener = TcpListener::bind(&listen_addr)
+async fn serve_metrics(address: SocketAddr, counters: UsageCounters) -> Result<()> {
+    let listener = TcpListener::bind(address)
         .await
-        .with_context(|| format!("Failed to bind {}.", &listen_addr))?;
-    println!("Forwarding {} to {}", &listen_addr, &dest_addr);
+        .with_context(|| format!("Failed to bind {} for the metrics.", address))?;
+    println!(
+        "Serving the metrics at http://{}{}",
+        address,
+        port_metrics::METRICS_PATH
+    );
     loop {
         let (stream, _) = listener
             .accept()
             .await
-            .with_context(|| format!("Failed to accept on the port {}.", port))?;
-        let dest = dest_addr.clone();
+            .with_context(|| format!("Failed to accept on {}.", address))?;
+        let counters = counters.clone();
+        tokio::spawn(async move {
+            if let Err(e) = respond_metrics(stream, &counters).await {
+                log::debug!("Failed to respond the metrics. {:?}", e);
+            }
+        });
+    }
+}
+
+/// Responds a request to the metrics by HTTP/1.1, closing the connection after each response,
+/// which is enough for the scrapes of Prometheus.
+async fn respond_metrics(mut stream: TcpStream, counters: &UsageCounters) -> Result<()> {
+    let mut request = vec![];
+    let mut buf = [0; 1024];
+    // A GET has no body, so only the head is read.
+    while !request.windows(4).any(|window| window == b"\r\n\r\n") {
+        let n = tokio::time::timeout(METRICS_REQUEST_TIMEOUT, stream.read(&mut buf))
+            .await
+            .with_context(|| "Timed out reading the request.")?
+            .with_context(|| "Failed to read the request.")?;
+        if n == 0 {
+            break;
+        }
+        request.extend_from_slice(&buf[..n]);
+        if request.len() > MAX_METRICS_REQUEST_SIZE {
+            bail!("The request is too large.");
+        }
+    }
+    let response =
+        match port_metrics::parse_http_get_path(&String::from_utf8_lossy(&request)) {
+            Some(port_metrics::METRICS_PATH) => {
+                let body = port_metrics::format_prometheus_metrics(&collect_port_metrics(counters));
+                format!(
+                    "HTTP/1.1 200 OK\r\nContent-Type: {}\r\nContent-Length: {}\r\n\
+                 Connection: close\r\n\r\n{}",
+                    port_metrics::METRICS_CONTENT_TYPE,
+                    body.len(),
+                    body
+                )
+            }
+            Some(_) => "HTTP/1.1 404 Not Found\r\nContent-Length: 0\r\nConnection: close\r\n\r\n"
+                .to_owned(),
+            None => "HTTP/1.1 400 Bad Request\r\nContent-Length: 0\r\nConnection: close\r\n\r\n"
+                .to_owned(),
+        };
+    stream
+        .write_all(response.as_bytes())
+        .await
+        .with_context(|| "Failed to write the response.")?;
+    stream
+        .shutdown()
+        .await
+        .with_context(|| "Failed to shut down the connection.")?;
+    Ok(())
+}
+
+fn collect_port_metrics(counters: &UsageCounters) -> Vec<PortMetrics> {
+    counters
+        .read()
+        .expect("[BUG] the usage counters are never poisoned.")
+        .iter()
+        .map(|(port, counter)| counter.get_metrics(*port))
+        .collect()
+}
+
+enum FirewallCommand {
+    Open(PortForwardRule),
+    Close(PortForwardRule),
+    SetRemoteAddresses(Option<Vec<String>>),
+}
+
+/// Adds and deletes the firewall rules of the open forwards one by one on a task of its own,
+/// since a forward is closed by aborting its task.
+#[derive(Clone)]
+struct Firewall {
+    commands: mpsc::UnboundedSender<FirewallCommand>,
+    /// The remote addresses given by the options, or None if they don't enable the rules.
+    default_remote_addresses: Option<Vec<String>>,
+}
+
+/// Keeps the firewall rule of a forward until it's dropped.
+struct FirewallRuleGuard {
+    rule: PortForwardRule,
+    commands: mpsc::UnboundedSender<FirewallCommand>,
+}
+
+impl Firewall {
+    fn spawn(default_remote_addresses: Option<Vec<String>>) -> Firewall {
+        let (commands, receiver) = mpsc::unbounded_channel();
+        tokio::spawn(manage_firewall_rules(
+            receiver,
+            default_remote_addresses.clone(),
+        ));
+        Firewall {
+            commands,
+            default_remote_addresses,
+        }
+    }
+
+    fn open(&self, rule: &PortForwardRule) -> FirewallRuleGuard {
+        // The task lives as long as the process.
+        let _ = self.commands.send(FirewallCommand::Open(rule.clone()));
+        FirewallRuleGuard {
+            rule: rule.clone(),
+            commands: self.commands.clone(),
+        }
+    }
+
+    /// Applies `[firewall]` of the rules file, or the options if it has none.
+    fn apply_config(&self, config: Option<&FirewallConfig>) {
+        let remote_addresses = match config {
+            Some(config) => config.get_enabled_remote_addresses(),
+            None => self.default_remote_addresses.clone(),
+        };
+        let _ = self
+            .commands
+            .send(FirewallCommand::SetRemoteAddresses(remote_addresses));
+    }
+}
+
+impl Drop for FirewallRuleGuard {
+    fn drop(&mut self) {
+        let _ = self
+            .commands
+            .send(FirewallCommand::Close(self.rule.clone()));
+    }
+}
+
+async fn manage_firewall_rules(
+    mut commands: mpsc::UnboundedReceiver<FirewallCommand>,
+    mut remote_addresses: Option<Vec<String>>,
+) {
+    let mut open_rules: Vec<PortForwardRule> = vec![];
+    while let Some(command) = commands.recv().await {
+        match command {
+            FirewallCommand::Open(rule) => {
+                if let Some(ref remote_addresses) = remote_addresses {
+                    add_firewall_rule(&rule, remote_addresses).await;
+                }
+                open_rules.push(rule);
+            }
+            FirewallCommand::Close(rule) => {
+                if let Some(position) = open_rules.iter().position(|open| *open == rule) {
+                    open_rules.remove(position);
+                    if remote_addresses.is_some() {
+                        delete_firewall_rule(&rule).await;
+                    }
+                }
+            }
+            FirewallCommand::SetRemoteAddresses(next) => {
+                if next == remote_addresses {
+                    continue;
+                }
+                for rule in &open_rules {
+                    match next {
+                        Some(ref next) => add_firewall_rule(rule, next).await,
+                        None => delete_firewall_rule(rule).await,
+                    }
+                }
+                remote_addresses = next;
+            }
+        }
+    }
+}
+
+async fn add_firewall_rule(rule: &PortForwardRule, remote_addresses: &[String]) {
+    if !rule.is_exposed() {
+        return;
+    }
+    match run_powershell(&rule.build_add_firewall_rule_command(remote_addresses)).await {
+        Ok(_) => println!(
+            "Allowed {} from {} by Windows Firewall",
+            rule.listen_socket_address(),
+            remote_addresses.join(", ")
+        ),
+        Err(e) => log::error!(
+            "Failed to add the firewall rule of {}. {:?}",
+            rule.listen_socket_address(),
+            e
+        ),
+    }
+}
+
+async fn delete_firewall_rule(rule: &PortForwardRule) {
+    if !rule.is_exposed() {
+        return;
+    }
+    if let Err(e) = run_powershell(&rule.build_delete_firewall_rule_command()).await {
+        log::error!(
+            "Failed to delete the firewall rule of {}. {:?}",
+            rule.listen_socket_address(),
+            e
+        );
+    }
+}
+
+async fn run_powershell(command: &str) -> Result<()> {
+    log::trace!("powershell command:\n{}", command);
+    let output = tokio::process::Command::new("powershell.exe")
+        .args(&["-NoProfile", "-NonInteractive", "-Command", command])
+        .output()
+        .await
+        .with_context(|| "Failed to execute Powershell.")?;
+    if !output.status.success() {
+        bail!(
+            "Powershell failed. {}",
+            String::from_utf8_lossy(&output.stderr).trim()
+        );
+    }
+    Ok(())
+}
+
+fn flush_usage_stats(counters: &UsageCounters, path: &Path) -> Result<()> {
+    let counters = counters
+        .read()
+        .expect("[BUG] the usage counters are never poisoned.");
+    let usages: Vec<_> = counters
+        .iter()
+        .map(|(port, counter)| (*port, counter.take()))
+        .filter(|(_, usage)| usage.total() != 0)
+        .collect();
+    if usages.is_empty() {
+        return Ok(());
+    }
+    let result = (|| -> Result<()> {
+        let mut stats = PortUsageStats::open(path)?;
+        let month = port_usage::get_current_month();
+        for (port, usage) in &usages {
+            stats.add(&month, *port, usage);
+        }
+        stats.save(path)
+    })();
+    if result.is_err() {
+        // Keep the counts so that they are saved in the next flush.
+        for (port, usage) in &usages {
+            counters[port].put_back(usage);
+        }
+    }
+    result.with_context(|| format!("Failed to record the port usage stats to {:?}.", path))
+}
+
+async fn proxy_tcp_port(forward: Arc<Forward>) -> Result<()> {
+    let listen_addr = &forward.listen_addr;
+    let listener = TcpListener::bind(listen_addr)
+        .await
+        .with_context(|| format!("Failed to bind {}.", listen_addr))?;
+    println!("Forwarding {} to {}", listen_addr, &forward.dest_addr);
+    loop {
+        let (stream, source) = listener
+            .accept()
+            .await
+            .with_context(|| format!("Failed to accept on {}.", listen_addr))?;
+        let connection = Connection::open(&forward, source);
         tokio::spawn(async move {
-            if let Err(e) = proxy_tcp_stream(stream, dest).await {
+            let (close_reason, result) = proxy_tcp_stream(stream, &connection).await;
+            if let Err(ref e) = result {
                 log::error!("{:?}", e);
             }
+            connection.close(close_reason, result.err().as_ref());
         });
     }
 }
 
-async fn proxy_tcp_stream(mut client: TcpStream, upstream_addr: String) -> Result<()> {
-    let buf_size = 1 << 16;
+/// Relays the datagrams of each client through a socket of its own, so that the replies from the
+/// upstream go back to the client.
+async fn proxy_udp_port(forward: Arc<Forward>) -> Result<()> {
+    let listen_addr = &forward.listen_addr;
+    let dest_addr = &forward.dest_addr;
+    let socket = Arc::new(
+        UdpSocket::bind(listen_addr)
+            .await
+            .with_context(|| format!("Failed to bind {}.", listen_addr))?,
+    );
+    println!("Forwarding {} to {} (udp)", listen_addr, dest_addr);
+    let sessions: UdpSessions = Arc::default();
+    let mut buf = vec![0; 1 << 16];
+    loop {
+        let (n, client) = socket
+            .recv_from(&mut buf)
+            .await
+            .with_context(|| format!("Failed to receive on {}.", listen_addr))?;
+        let session = sessions
+            .lock()
+            .expect("[BUG] the UDP sessions are never poisoned.")
+            .get(&client)
+            .cloned();
+        let session = match session {
+            Some(session) => session,
+            None => {
+                let connection = Connection::open(&forward, client);
+                let upstream = match connect_udp_upstream(dest_addr).await {
+                    Ok(upstream) => upstream,
+                    Err(e) => {
+                        log::error!("{:?}", e);
+                        connection.close(CloseReason::UpstreamUnreachable, Some(&e));
+                        continue;
+                    }
+                };
+                let session = Arc::new(UdpSession {
+                    upstream,
+                    connection,
+                });
+                sessions
+                    .lock()
+                    .expect("[BUG] the UDP sessions are never poisoned.")
+                    .insert(client, session.clone());
+                tokio::spawn(relay_udp_replies(
+                    socket.clone(),
+                    session.clone(),
+                    sessions.clone(),
+                ));
+                session
+            }
+        };
+        match session.upstream.send(&buf[..n]).await {
+            Ok(_) => session.connection.add_bytes_in(n as u64),
+            Err(e) => {
+                forward.counter.add_error();
+                log::error!("Failed to send to the upstream {}. {:?}", dest_addr, e);
+            }
+        }
+    }
+}
 
-    let mut upstream = TcpStream::connect(upstream_addr)
+/// Makes a socket connected to the upstream. It's bound to the unspecified address of the
+/// upstream's address family, since an IPv4 socket can't send to an IPv6 upstream.
+async fn connect_udp_upstream(dest_addr: &str) -> Result<UdpSocket> {
+    let dest = tokio::net::lookup_host(dest_addr)
+        .await
+        .with_context(|| format!("Failed to resolve the upstream {}.", dest_addr))?
+        .next()
+        .ok_or_else(|| anyhow!("The upstream {} has no address.", dest_addr))?;
+    let local_addr: SocketAddr = if dest.is_ipv6() {
+        (Ipv6Addr::UNSPECIFIED, 0).into()
+    } else {
+        (Ipv4Addr::UNSPECIFIED, 0).into()
+    };
+    let upstream = UdpSocket::bind(local_addr)
         .await
-        .with_context(|| "Failed to connect to the upstream.")?;
+        .with_context(|| "Failed to bind a socket to the upstream.")?;
+    upstream
+        .connect(dest)
+        .await
+        .with_context(|| format!("Failed to connect to the upstream {}.", dest_addr))?;
+    Ok(upstream)
+}
 
-    let (client_read, mut client_write) = client.split();
-    let (upstream_read, mut upstream_write) = upstream.split();
+/// A client of a UDP forward, which has a socket of its own to the upstream.
+struct UdpSession {
+    upstream: UdpSocket,
+    connection: Connection,
+}
 
-    let client_to_upstream = async {
-        let mut buf_read = BufReader::with_capacity(buf_size, client_read);
-        io::copy_buf(&mut buf_read, &mut upstream_write)
+type UdpSessions = Arc<Mutex<HashMap<SocketAddr, Arc<UdpSession>>>>;
+
+async fn relay_udp_replies(
+    socket: Arc<UdpSocket>,
+    session: Arc<UdpSession>,
+    sessions: UdpSessions,
+) {
+    let client = session.connection.source;
+    let mut buf = vec![0; 1 << 16];
+    let (close_reason, error) = loop {
+        let n = match tokio::time::timeout(UDP_SESSION_TIMEOUT, session.upstream.recv(&mut buf))
             .await
-            .with_context(|| "Copy to the upstream failed.")?;
+        {
+            Ok(Ok(n)) => n,
+            Ok(Err(e)) => {
+                break (
+                    CloseReason::Failed,
+                    Some(anyhow::Error::new(e).context("Failed to receive from the upstream.")),
+                )
+            }
+            Err(_) => break (CloseReason::IdleTimeout, None),
+        };
+        if let Err(e) = socket.send_to(&buf[..n], client).await {
+            log::error!("Failed to send to the client {}. {:?}", client, e);
+            break (
+                CloseReason::Failed,
+                Some(
+                    anyhow::Error::new(e)
+                        .context(format!("Failed to send to the client {}.", client)),
+                ),
+            );
+        }
+        session.connection.add_bytes_out(n as u64);
+    };
+    sessions
+        .lock()
+        .expect("[BUG] the UDP sessions are never poisoned.")
+        .remove(&client);
+    session.connection.close(close_reason, error.as_ref());
+}
+
+/// Relays a TCP connection until both sides close it, and returns how it was closed.
+async fn proxy_tcp_stream(
+    mut client: TcpStream,
+    connection: &Connection,
+) -> (CloseReason, Result<()>) {
+    let mut upstream = match TcpStream::connect(&connection.forward.dest_addr).await {
+        Ok(upstream) => upstream,
+        Err(e) => {
+            return (
+                CloseReason::UpstreamUnreachable,
+                Err(anyhow::Error::new(e).context("Failed to connect to the upstream.")),
+            )
+        }
+    };
+
+    let (mut client_read, mut client_write) = client.split();
+    let (mut upstream_read, mut upstream_write) = upstream.split();
+
+    let client_to_upstream = async {
+        copy_counting(&mut client_read, &mut upstream_write, |n| {
+            connection.add_bytes_in(n)
+        })
+        .await
+        .with_context(|| "Copy to the upstream failed.")?;
         upstream_write
             .shutdown()
             .await
@@ -155,10 +1409,11 @@ async fn proxy_tcp_stream(mut client: TcpStream, upstream_addr: String) -> Resul
     };
 
     let upstream_to_client = async {
-        let mut buf_read = BufReader::with_capacity(buf_size, upstream_read);
-        io::copy(&mut buf_read, &mut client_write)
-            .await
-            .with_context(|| "Copy to the client failed.")?;
+        copy_counting(&mut upstream_read, &mut client_write, |n| {
+            connection.add_bytes_out(n)
+        })
+        .await
+        .with_context(|| "Copy to the client failed.")?;
         client_write
             .shutdown()
             .await
@@ -166,7 +1421,27 @@ async fn proxy_tcp_stream(mut client: TcpStream, upstream_addr: String) -> Resul
         Ok(())
     };
 
-    tokio::try_join!(client_to_upstream, upstream_to_client)?;
+    match tokio::try_join!(client_to_upstream, upstream_to_client) {
+        Ok(_) => (CloseReason::Closed, Ok(())),
+        Err(e) => (CloseReason::Failed, Err(e)),
+    }
+}
 
-    Ok(())
+/// Copies the data like tokio::io::copy, but counts the bytes as they are transferred
+/// so that long-lived connections are accounted before they are closed.
+async fn copy_counting<R, W, F>(reader: &mut R, writer: &mut W, count: F) -> io::Result<()>
+where
+    R: AsyncRead + Unpin,
+    W: AsyncWrite + Unpin,
+    F: Fn(u64),
+{
+    let mut buf = vec![0; 1 << 16];
+    loop {
+        let n = reader.read(&mut buf).await?;
+        if n == 0 {
+            return Ok(());
+        }
+        writer.write_all(&buf[..n]).await?;
+        count(n as u64);
+    }
 }
@@ -2,7 +2,19 @@
 
 set -e
 
-LATEST_RELEASE_URL="https://github.com/nullpo-head/wsl-distrod/releases/latest/download/opt_distrod.tar.gz"
+case "$(uname -m)" in
+x86_64)
+    RELEASE_ASSET_NAME="opt_distrod.tar.gz"
+    ;;
+aarch64)
+    RELEASE_ASSET_NAME="opt_distrod-aarch64.tar.gz"
+    ;;
+*)
+    echo "Distrod doesn't support $(uname -m)." >&2
+    exit 1
+    ;;
+esac
+LATEST_RELEASE_URL="https://github.com/nullpo-head/wsl-distrod/releases/latest/download/${RELEASE_ASSET_NAME}"
 
 HELP_STR="Usage: $0 <command>
 
@@ -67,7 +79,7 @@ get_release_file() {
     if [ -n "$RELEASE_FILE" ]; then
         cp "$RELEASE_FILE" opt_distrod.tar.gz
     else
-        curl -L -O "${LATEST_RELEASE_URL}"
+        curl -L -o opt_distrod.tar.gz "${LATEST_RELEASE_URL}"
     fi
 }
 
@@ -1,5 +1,13 @@
 ROOTFS_PATH ?= distrod/distrod_wsl_launcher/resources/distrod_root.tar.gz
-OUTPUT_PORT_PROXY_EXE_PATH ?= distrod/target/release/portproxy.exe
+# Set this to cross-compile, e.g. CARGO_TARGET=aarch64-pc-windows-msvc for ARM64 Windows.
+CARGO_TARGET ?=
+ifneq ($(CARGO_TARGET),)
+CARGO_TARGET_OPT = --target $(CARGO_TARGET)
+RELEASE_DIR = distrod/target/$(CARGO_TARGET)/release
+else
+RELEASE_DIR = distrod/target/release
+endif
+OUTPUT_PORT_PROXY_EXE_PATH ?= $(RELEASE_DIR)/portproxy.exe
 
 build: distro_launcher/x64/distrod_wsl_launcher.exe
 
@@ -16,13 +24,13 @@ distrod/distrod_wsl_launcher/resources/distrod_root.tar.gz: $(ROOTFS_PATH)
 	fi
 
 distrod_wsl_launcher: distrod/distrod_wsl_launcher/resources/distrod_root.tar.gz
-	cd distrod; cargo.exe build --release -p distrod_wsl_launcher
+	cd distrod; cargo.exe build --release $(CARGO_TARGET_OPT) -p distrod_wsl_launcher
 
 distrod/target/release/portproxy.exe: portproxy.exe
 portproxy.exe:
-	cd distrod; cargo.exe build --release -p portproxy
-	if [ "$$(realpath "$(OUTPUT_PORT_PROXY_EXE_PATH)" )" != "$$(realpath ./distrod/target/release/portproxy.exe)" ]; then \
-		cp target/release/port_proxy.exe $(OUTPUT_PORT_PROXY_EXE_PATH); \
+	cd distrod; cargo.exe build --release $(CARGO_TARGET_OPT) -p portproxy
+	if [ "$$(realpath "$(OUTPUT_PORT_PROXY_EXE_PATH)" )" != "$$(realpath ./$(RELEASE_DIR)/portproxy.exe)" ]; then \
+		cp $(RELEASE_DIR)/portproxy.exe $(OUTPUT_PORT_PROXY_EXE_PATH); \
 	fi
 
 test-win: distrod/distrod_wsl_launcher/resources/distrod_root.tar.gz